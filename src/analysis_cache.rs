@@ -0,0 +1,73 @@
+use crate::spotify_data::AudioAnalysis;
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const DEFAULT_ANALYSIS_CACHE_DIR: &str = "audio_analysis_cache";
+
+/// Audio analyses on disk, one JSON file per track id. An analysis never
+/// changes, so one fetched is kept for good. Only the parts that are used
+/// are stored, the response is hundreds of kilobytes.
+pub struct AnalysisCache {
+    dir: PathBuf,
+}
+
+impl AnalysisCache {
+    pub fn new(dir: impl Into<PathBuf>) -> AnalysisCache {
+        AnalysisCache { dir: dir.into() }
+    }
+
+    fn path(&self, track_id: &str) -> PathBuf {
+        self.dir.join(format!("{track_id}.json"))
+    }
+
+    /// None when the track's analysis was never stored.
+    pub fn get(&self, track_id: &str) -> Result<Option<AudioAnalysis>> {
+        let path = self.path(track_id);
+        match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data)
+                .map(Some)
+                .with_context(|| format!("{} is not an audio analysis", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn put(&self, track_id: &str, analysis: &AudioAnalysis) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Could not create {}", self.dir.display()))?;
+        fs::write(self.path(track_id), serde_json::to_string(analysis)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::load_sample;
+
+    #[test]
+    fn test_stored_analysis_is_trimmed() {
+        let dir = std::env::temp_dir().join(format!("analysis-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = AnalysisCache::new(&dir);
+        assert!(cache.get("3BovdzfaX4jb5KFQwoPfAw").unwrap().is_none());
+
+        let response = load_sample("audio_analysis.json");
+        let analysis: AudioAnalysis = serde_json::from_str(&response).unwrap();
+        cache.put("3BovdzfaX4jb5KFQwoPfAw", &analysis).unwrap();
+        assert_eq!(
+            cache.get("3BovdzfaX4jb5KFQwoPfAw").unwrap().as_ref(),
+            Some(&analysis)
+        );
+        let stored = fs::read_to_string(dir.join("3BovdzfaX4jb5KFQwoPfAw.json")).unwrap();
+        assert!(stored.len() < response.len() / 2);
+        assert!(!stored.contains("beats"));
+
+        fs::write(dir.join("broken.json"), "[]").unwrap();
+        assert!(cache.get("broken").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use crate::spotify_data::ArtistFull;
+use crate::state_file::{unchanged, StateFormat};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// v2 wrapped v1 in the state file envelope.
+const ARTIST_CACHE_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+
+/// How long fetched artist details are served before being fetched again.
+pub const DEFAULT_ARTIST_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The artist cache next to a history file, e.g. `history.artists.json` for
+/// `history.jsonl`.
+pub fn artist_cache_path_for(history: &Path) -> PathBuf {
+    history.with_extension("artists.json")
+}
+
+/// The details of an artist as they were when fetched.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedArtist {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub popularity: Option<u32>,
+    pub followers: Option<u64>,
+    pub fetched_at: SystemTime,
+}
+
+impl CachedArtist {
+    pub fn new(artist: &ArtistFull, fetched_at: SystemTime) -> CachedArtist {
+        CachedArtist {
+            id: artist.id.clone(),
+            name: artist.name.clone(),
+            genres: artist.genres.clone(),
+            popularity: artist.popularity,
+            followers: artist.followers.as_ref().map(|f| f.total),
+            fetched_at,
+        }
+    }
+}
+
+/// How many lookups the cache could and couldn't answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheCounters {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Artist details by id, kept in a JSON file so genre stats and reports
+/// don't fetch the same artists over and over.
+pub struct ArtistCache {
+    path: PathBuf,
+    ttl: Duration,
+    artists: HashMap<String, CachedArtist>,
+    counters: CacheCounters,
+}
+
+impl ArtistCache {
+    /// Reads the cache at `path`, a missing file is an empty cache.
+    pub fn load(path: impl Into<PathBuf>) -> Result<ArtistCache> {
+        let path = path.into();
+        let artists = match fs::read_to_string(&path) {
+            Ok(data) => ARTIST_CACHE_FORMAT.parse(&data, &path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(ArtistCache {
+            path,
+            ttl: DEFAULT_ARTIST_CACHE_TTL,
+            artists,
+            counters: CacheCounters::default(),
+        })
+    }
+
+    /// How long an artist is served before it is stale.
+    pub fn ttl(mut self, ttl: Duration) -> ArtistCache {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn counters(&self) -> CacheCounters {
+        self.counters
+    }
+
+    /// The artist when it was fetched less than the TTL before `now`,
+    /// counted as a hit or a miss.
+    pub fn fresh(&mut self, id: &str, now: SystemTime) -> Option<&CachedArtist> {
+        let ttl = self.ttl;
+        let artist = self.artists.get(id).filter(|artist| {
+            now.duration_since(artist.fetched_at)
+                .is_ok_and(|age| age < ttl)
+        });
+        match artist {
+            Some(_) => self.counters.hits += 1,
+            None => self.counters.misses += 1,
+        }
+        artist
+    }
+
+    /// The artist whatever its age, without counting the lookup.
+    pub fn get(&self, id: &str) -> Option<&CachedArtist> {
+        self.artists.get(id)
+    }
+
+    pub fn insert(&mut self, artist: &ArtistFull, fetched_at: SystemTime) {
+        self.artists
+            .insert(artist.id.clone(), CachedArtist::new(artist, fetched_at));
+    }
+
+    /// Writes the cache out, replacing the file.
+    pub fn save(&self) -> Result<()> {
+        let data = ARTIST_CACHE_FORMAT.to_string(&self.artists)?;
+        fs::write(&self.path, data)
+            .with_context(|| format!("Could not write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spotify_data::FollowedArtists;
+
+    fn followed() -> Vec<ArtistFull> {
+        let data = fs::read_to_string("sample_data/followed_artists.json").unwrap();
+        let followed: FollowedArtists = serde_json::from_str(&data).unwrap();
+        followed.artists.items
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_727_000_000 + secs)
+    }
+
+    #[test]
+    fn test_fresh_artists_and_counters() {
+        let path = std::env::temp_dir().join(format!("artists-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut cache = ArtistCache::load(&path)
+            .unwrap()
+            .ttl(Duration::from_secs(60));
+        let artists = followed();
+        cache.insert(&artists[0], at(0));
+        cache.insert(&artists[1], at(30));
+
+        assert_eq!(
+            cache.fresh(&artists[0].id, at(59)).unwrap().name,
+            "The Beatles"
+        );
+        // Stale, but still there
+        assert!(cache.fresh(&artists[0].id, at(60)).is_none());
+        assert!(cache.get(&artists[0].id).is_some());
+        assert!(cache.fresh("unknown", at(0)).is_none());
+        assert_eq!(cache.counters(), CacheCounters { hits: 1, misses: 2 });
+
+        cache.save().unwrap();
+        let mut loaded = ArtistCache::load(&path).unwrap();
+        let bowie = loaded.fresh(&artists[1].id, at(30)).unwrap();
+        assert_eq!(bowie.genres.len(), 3);
+        assert_eq!(bowie.fetched_at, at(30));
+        assert_eq!(
+            bowie.followers,
+            artists[1].followers.as_ref().map(|f| f.total)
+        );
+        // Counters start over with every load
+        assert_eq!(loaded.counters(), CacheCounters { hits: 1, misses: 0 });
+
+        // Caches from before the state file versions
+        let cached = HashMap::from([(
+            artists[1].id.clone(),
+            CachedArtist::new(&artists[1], at(30)),
+        )]);
+        fs::write(&path, serde_json::to_string(&cached).unwrap()).unwrap();
+        assert!(ArtistCache::load(&path)
+            .unwrap()
+            .get(&artists[1].id)
+            .is_some());
+
+        fs::write(&path, "[]").unwrap();
+        assert!(ArtistCache::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
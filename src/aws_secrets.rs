@@ -0,0 +1,438 @@
+use crate::error::SecretNotFound;
+use crate::secrets::SecretProvider;
+
+use anyhow::{anyhow, bail, Result};
+use aws_sdk_secretsmanager::types::{Filter, FilterNameStringType};
+use aws_sdk_secretsmanager::Client;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tracing::debug;
+
+/// Notes are kept in a secret of their own under this path, Secrets Manager
+/// only has a plain text description otherwise
+const NOTES_PATH: &str = "notes/";
+/// Waits before writing a secret again that AWS still reports as missing or
+/// as existing, right after it was created or deleted
+const AWS_WRITE_RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_millis(250),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+
+/// What went wrong with a Secrets Manager call.
+#[derive(Debug)]
+pub enum AwsError {
+    /// There is no secret with the name, or AWS doesn't know it yet
+    NotFound,
+    /// A secret with the name already exists or is still being deleted
+    Conflict,
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for AwsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AwsError::NotFound => f.write_str("The secret does not exist"),
+            AwsError::Conflict => f.write_str("The secret exists or is being deleted"),
+            AwsError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for AwsError {}
+
+/// The Secrets Manager calls [AwsSecretsProvider] makes, by secret name.
+/// Implemented for the SDK's [Client], implement it on a fake to test
+/// without AWS.
+pub trait SecretsManagerApi {
+    fn get_value(&self, name: &str) -> impl Future<Output = Result<String, AwsError>>;
+
+    fn create(&self, name: &str, value: &str) -> impl Future<Output = Result<(), AwsError>>;
+
+    fn put_value(&self, name: &str, value: &str) -> impl Future<Output = Result<(), AwsError>>;
+
+    /// Names of the secrets starting with `prefix`.
+    fn list_names(&self, prefix: &str) -> impl Future<Output = Result<Vec<String>, AwsError>>;
+
+    fn delete(&self, name: &str) -> impl Future<Output = Result<(), AwsError>>;
+}
+
+fn other(e: impl std::error::Error + Send + Sync + 'static) -> AwsError {
+    AwsError::Other(e.into())
+}
+
+impl SecretsManagerApi for Client {
+    async fn get_value(&self, name: &str) -> Result<String, AwsError> {
+        let output = self
+            .get_secret_value()
+            .secret_id(name)
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                e if e.is_resource_not_found_exception() => AwsError::NotFound,
+                e => other(e),
+            })?;
+        match output.secret_string() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(AwsError::Other(anyhow!(
+                "{name} holds binary data, not text"
+            ))),
+        }
+    }
+
+    async fn create(&self, name: &str, value: &str) -> Result<(), AwsError> {
+        self.create_secret()
+            .name(name)
+            .secret_string(value)
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                e if e.is_resource_exists_exception() || e.is_invalid_request_exception() => {
+                    AwsError::Conflict
+                }
+                e => other(e),
+            })?;
+        Ok(())
+    }
+
+    async fn put_value(&self, name: &str, value: &str) -> Result<(), AwsError> {
+        self.put_secret_value()
+            .secret_id(name)
+            .secret_string(value)
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                e if e.is_resource_not_found_exception() => AwsError::NotFound,
+                e => other(e),
+            })?;
+        Ok(())
+    }
+
+    async fn list_names(&self, prefix: &str) -> Result<Vec<String>, AwsError> {
+        let mut names = Vec::new();
+        let mut next_token = None;
+        loop {
+            let mut request = self.list_secrets().set_next_token(next_token);
+            if !prefix.is_empty() {
+                request = request.filters(
+                    Filter::builder()
+                        .key(FilterNameStringType::Name)
+                        .values(prefix)
+                        .build(),
+                );
+            }
+            let output = request
+                .send()
+                .await
+                .map_err(|e| other(e.into_service_error()))?;
+            names.extend(
+                output
+                    .secret_list()
+                    .iter()
+                    .filter_map(|secret| secret.name())
+                    // The name filter ignores case
+                    .filter(|name| name.starts_with(prefix))
+                    .map(str::to_string),
+            );
+            match output.next_token() {
+                Some(token) => next_token = Some(token.to_string()),
+                None => return Ok(names),
+            }
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<(), AwsError> {
+        // Without a recovery window, so the name can be used again right away
+        self.delete_secret()
+            .secret_id(name)
+            .force_delete_without_recovery(true)
+            .send()
+            .await
+            .map_err(|e| match e.into_service_error() {
+                e if e.is_resource_not_found_exception() => AwsError::NotFound,
+                e => other(e),
+            })?;
+        Ok(())
+    }
+}
+
+/// Secrets in AWS Secrets Manager. A key is used as the secret name after
+/// `prefix`, e.g. `spotify-rs/spotify_refresh_token_jorge`, and its note
+/// goes into `{prefix}notes/{key}`.
+pub struct AwsSecretsProvider<C: SecretsManagerApi = Client> {
+    client: C,
+    prefix: String,
+    retry_delays: Vec<Duration>,
+}
+
+impl AwsSecretsProvider<Client> {
+    /// Uses the AWS credentials and region of the environment, found the
+    /// same way as the AWS CLI does.
+    pub async fn from_env(prefix: &str) -> AwsSecretsProvider {
+        let config = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        AwsSecretsProvider::new(Client::new(&config), prefix)
+    }
+}
+
+impl<C: SecretsManagerApi> AwsSecretsProvider<C> {
+    pub fn new(client: C, prefix: &str) -> AwsSecretsProvider<C> {
+        AwsSecretsProvider {
+            client,
+            prefix: prefix.to_string(),
+            retry_delays: AWS_WRITE_RETRY_DELAYS.to_vec(),
+        }
+    }
+
+    /// Waits between writes of a secret AWS hasn't caught up with yet.
+    pub fn with_retry_delays(mut self, delays: Vec<Duration>) -> AwsSecretsProvider<C> {
+        self.retry_delays = delays;
+        self
+    }
+
+    fn secret_name(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+
+    fn note_name(&self, key: &str) -> String {
+        format!("{}{NOTES_PATH}{key}", self.prefix)
+    }
+
+    /// Updates the secret or creates it. Secrets Manager is eventually
+    /// consistent: a secret created a moment ago can still be missing for
+    /// an update while already existing for a create, so those are retried.
+    async fn write(&self, name: &str, value: &str) -> Result<()> {
+        let mut delays = self.retry_delays.iter();
+        loop {
+            let written = match self.client.put_value(name, value).await {
+                Err(AwsError::NotFound) => self.client.create(name, value).await,
+                written => written,
+            };
+            match written {
+                Ok(()) => return Ok(()),
+                Err(AwsError::Other(e)) => return Err(e.context(format!("Could not write {name}"))),
+                Err(e) => match delays.next() {
+                    None => bail!("Could not write {name}, AWS kept answering: {e}"),
+                    Some(delay) => {
+                        debug!("Writing {name} again in {delay:?}: {e}");
+                        tokio::time::sleep(*delay).await;
+                    }
+                },
+            }
+        }
+    }
+
+    async fn remove(&self, name: &str) -> Result<()> {
+        match self.client.delete(name).await {
+            Ok(()) | Err(AwsError::NotFound) => Ok(()),
+            Err(e) => Err(anyhow!("Could not delete {name}: {e}")),
+        }
+    }
+}
+
+impl<C: SecretsManagerApi> SecretProvider for AwsSecretsProvider<C> {
+    async fn list(&self) -> Result<Vec<String>> {
+        let names = self.client.list_names(&self.prefix).await?;
+        Ok(names
+            .iter()
+            .filter_map(|name| name.strip_prefix(&self.prefix))
+            .filter(|key| !key.starts_with(NOTES_PATH))
+            .map(str::to_string)
+            .collect())
+    }
+
+    async fn get(&self, key: &str) -> Result<(String, String)> {
+        let value = match self.client.get_value(&self.secret_name(key)).await {
+            Ok(value) => value,
+            Err(AwsError::NotFound) => return Err(SecretNotFound::new(key).into()),
+            Err(e) => return Err(e.into()),
+        };
+        let note = match self.client.get_value(&self.note_name(key)).await {
+            Ok(note) => note,
+            Err(AwsError::NotFound) => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok((value, note))
+    }
+
+    async fn put(&self, key: &str, value: &str, note: Option<String>) -> Result<()> {
+        self.write(&self.secret_name(key), value).await?;
+        let note_name = self.note_name(key);
+        match note.filter(|note| !note.is_empty()) {
+            Some(note) => self.write(&note_name, &note).await,
+            // Most secrets never had a note, only an old one is deleted
+            None => match self.client.get_value(&note_name).await {
+                Ok(_) => self.remove(&note_name).await,
+                Err(AwsError::NotFound) => Ok(()),
+                Err(e) => Err(anyhow!("Could not read {note_name}: {e}")),
+            },
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.remove(&self.secret_name(key)).await?;
+        self.remove(&self.note_name(key)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    /// Secrets Manager in memory. `stale_updates` updates of an existing
+    /// secret fail as if AWS didn't know it yet.
+    #[derive(Default)]
+    struct FakeSecretsManager {
+        secrets: Mutex<HashMap<String, String>>,
+        stale_updates: Mutex<u32>,
+        writes: Mutex<u32>,
+        deletes: Mutex<u32>,
+    }
+
+    impl FakeSecretsManager {
+        fn with_stale_updates(stale_updates: u32) -> FakeSecretsManager {
+            FakeSecretsManager {
+                stale_updates: Mutex::new(stale_updates),
+                ..FakeSecretsManager::default()
+            }
+        }
+    }
+
+    impl SecretsManagerApi for FakeSecretsManager {
+        async fn get_value(&self, name: &str) -> Result<String, AwsError> {
+            let secrets = self.secrets.lock().unwrap();
+            secrets.get(name).cloned().ok_or(AwsError::NotFound)
+        }
+
+        async fn create(&self, name: &str, value: &str) -> Result<(), AwsError> {
+            *self.writes.lock().unwrap() += 1;
+            let mut secrets = self.secrets.lock().unwrap();
+            if secrets.contains_key(name) {
+                return Err(AwsError::Conflict);
+            }
+            secrets.insert(name.to_string(), value.to_string());
+            Ok(())
+        }
+
+        async fn put_value(&self, name: &str, value: &str) -> Result<(), AwsError> {
+            *self.writes.lock().unwrap() += 1;
+            let mut stale_updates = self.stale_updates.lock().unwrap();
+            let mut secrets = self.secrets.lock().unwrap();
+            if !secrets.contains_key(name) {
+                return Err(AwsError::NotFound);
+            }
+            if *stale_updates > 0 {
+                *stale_updates -= 1;
+                return Err(AwsError::NotFound);
+            }
+            secrets.insert(name.to_string(), value.to_string());
+            Ok(())
+        }
+
+        async fn list_names(&self, prefix: &str) -> Result<Vec<String>, AwsError> {
+            let secrets = self.secrets.lock().unwrap();
+            let mut names: Vec<String> = secrets
+                .keys()
+                .filter(|name| name.starts_with(prefix))
+                .cloned()
+                .collect();
+            names.sort();
+            Ok(names)
+        }
+
+        async fn delete(&self, name: &str) -> Result<(), AwsError> {
+            *self.deletes.lock().unwrap() += 1;
+            let mut secrets = self.secrets.lock().unwrap();
+            secrets.remove(name).map(|_| ()).ok_or(AwsError::NotFound)
+        }
+    }
+
+    fn provider(fake: FakeSecretsManager) -> AwsSecretsProvider<FakeSecretsManager> {
+        AwsSecretsProvider::new(fake, "spotify-rs/").with_retry_delays(vec![Duration::ZERO; 3])
+    }
+
+    #[tokio::test]
+    async fn test_spotify_keys_round_trip() {
+        let fake = FakeSecretsManager::default();
+        fake.create("other-app/token", "not ours").await.unwrap();
+        let secrets = provider(fake);
+
+        let note = r#"{"expires_in":3600,"last_refresh":null}"#;
+        secrets
+            .put(
+                "spotify_refresh_token_jorge",
+                "refresh",
+                Some(note.to_string()),
+            )
+            .await
+            .unwrap();
+        secrets
+            .put("spotify_access_token_jorge", "access", None)
+            .await
+            .unwrap();
+        // There was no note to delete
+        assert_eq!(*secrets.client.deletes.lock().unwrap(), 0);
+        secrets
+            .put(
+                "spotify_refresh_token_jorge",
+                "refreshed",
+                Some(note.to_string()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            secrets.get("spotify_refresh_token_jorge").await.unwrap(),
+            ("refreshed".to_string(), note.to_string())
+        );
+        assert_eq!(
+            secrets.get("spotify_access_token_jorge").await.unwrap(),
+            ("access".to_string(), String::new())
+        );
+        assert!(secrets.get("spotify_client_id").await.is_err());
+        assert_eq!(
+            secrets.list().await.unwrap(),
+            ["spotify_access_token_jorge", "spotify_refresh_token_jorge"]
+        );
+        let names = secrets.client.list_names("").await.unwrap();
+        assert!(names.contains(&"spotify-rs/notes/spotify_refresh_token_jorge".to_string()));
+
+        // Without a note the old one goes
+        secrets
+            .put("spotify_refresh_token_jorge", "refreshed", None)
+            .await
+            .unwrap();
+        assert_eq!(*secrets.client.deletes.lock().unwrap(), 1);
+        assert_eq!(
+            secrets.get("spotify_refresh_token_jorge").await.unwrap(),
+            ("refreshed".to_string(), String::new())
+        );
+
+        secrets.delete("spotify_refresh_token_jorge").await.unwrap();
+        assert_eq!(
+            secrets.client.list_names("spotify-rs/").await.unwrap(),
+            ["spotify-rs/spotify_access_token_jorge"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writes_wait_for_aws_to_catch_up() {
+        let secrets = provider(FakeSecretsManager::with_stale_updates(2));
+        secrets.put("spotify_client_id", "old", None).await.unwrap();
+        // Update and create fail twice, the third update goes through
+        secrets.put("spotify_client_id", "new", None).await.unwrap();
+        assert_eq!(secrets.get("spotify_client_id").await.unwrap().0, "new");
+        assert_eq!(*secrets.client.writes.lock().unwrap(), 2 + 5);
+
+        let secrets = provider(FakeSecretsManager::with_stale_updates(10));
+        secrets.put("spotify_client_id", "old", None).await.unwrap();
+        let err = secrets
+            .put("spotify_client_id", "new", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("AWS kept answering"));
+        assert_eq!(secrets.get("spotify_client_id").await.unwrap().0, "old");
+    }
+}
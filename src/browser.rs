@@ -0,0 +1,28 @@
+use anyhow::Result;
+use std::sync::Arc;
+
+/// Shows a URL to the user, e.g. the page to authorize the app on.
+pub trait UrlOpener: Send + Sync {
+    fn open(&self, url: &str) -> Result<()>;
+}
+
+/// The default browser of the desktop.
+#[cfg(feature = "open")]
+pub struct SystemBrowser;
+
+#[cfg(feature = "open")]
+impl UrlOpener for SystemBrowser {
+    fn open(&self, url: &str) -> Result<()> {
+        open::that_detached(url)?;
+        Ok(())
+    }
+}
+
+/// The opener of a client nobody gave one, the default browser when built
+/// with the `open` feature. None means the URL is only printed.
+pub fn default_opener() -> Option<Arc<dyn UrlOpener>> {
+    #[cfg(feature = "open")]
+    return Some(Arc::new(SystemBrowser));
+    #[cfg(not(feature = "open"))]
+    None
+}
@@ -0,0 +1,161 @@
+use crate::redact;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+pub const DEFAULT_MAX_CAPTURES: usize = 50;
+const CAPTURE_EXTENSION: &str = ".json";
+
+/// Debug option to dump raw Spotify responses to disk.
+/// Bodies that fail to parse are always captured, successful bodies only
+/// for the endpoints listed in `endpoints` (handy to collect fixtures).
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    pub endpoints: HashSet<String>,
+    pub max_captures: usize,
+}
+
+#[derive(Serialize)]
+struct CaptureRecord<'a> {
+    endpoint: &'a str,
+    url: &'a str,
+    status: u16,
+    captured_at: SystemTime,
+    parse_error: Option<&'a str>,
+    body: serde_json::Value,
+}
+
+impl CaptureConfig {
+    pub fn new(dir: PathBuf) -> CaptureConfig {
+        CaptureConfig {
+            dir,
+            endpoints: HashSet::new(),
+            max_captures: DEFAULT_MAX_CAPTURES,
+        }
+    }
+
+    pub fn captures_successes_for(&self, endpoint: &str) -> bool {
+        self.endpoints.contains(endpoint)
+    }
+
+    /// Writes the redacted body, with its url and status, to a new timestamped
+    /// file and prunes old captures. Returns the path of the new file.
+    pub fn capture(
+        &self,
+        endpoint: &str,
+        url: &str,
+        status: u16,
+        body: &str,
+        parse_error: Option<&str>,
+    ) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)?;
+
+        let redacted = redact::redact_body(body);
+        let body = serde_json::from_str(&redacted).unwrap_or(serde_json::Value::String(redacted));
+        let captured_at = SystemTime::now();
+        let record = CaptureRecord {
+            endpoint,
+            url,
+            status,
+            captured_at,
+            parse_error,
+            body,
+        };
+
+        // Zero padded so file names sort in capture order
+        let nanos = captured_at.duration_since(UNIX_EPOCH)?.as_nanos();
+        let path = self
+            .dir
+            .join(format!("{nanos:020}-{endpoint}{CAPTURE_EXTENSION}"));
+        fs::write(&path, serde_json::to_string_pretty(&record)?)?;
+        debug!("Captured {endpoint} response into {}", path.display());
+
+        if let Err(e) = self.prune() {
+            warn!("Failed pruning old captures in {}: {e}", self.dir.display());
+        }
+
+        Ok(path)
+    }
+
+    /// Deletes the oldest captures so at most `max_captures` remain.
+    /// Returns how many files were removed.
+    pub fn prune(&self) -> Result<usize> {
+        let mut captures: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| is_capture_file(path))
+            .collect();
+        if captures.len() <= self.max_captures {
+            return Ok(0);
+        }
+
+        captures.sort();
+        let excess = captures.len() - self.max_captures;
+        for path in &captures[..excess] {
+            fs::remove_file(path)?;
+        }
+
+        Ok(excess)
+    }
+}
+
+fn is_capture_file(path: &std::path::Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            name.ends_with(CAPTURE_EXTENSION) && name.starts_with(|c: char| c.is_ascii_digit())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("capture-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_capture_is_redacted() {
+        let config = CaptureConfig::new(test_dir("redacted"));
+        let path = config
+            .capture(
+                "token",
+                "https://accounts.spotify.com/api/token",
+                200,
+                r#"{"access_token":"secret-token","expires_in":"soon"}"#,
+                Some("invalid type: string \"soon\", expected i64"),
+            )
+            .unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(!written.contains("secret-token"));
+        assert!(written.contains("https://accounts.spotify.com/api/token"));
+        assert!(written.contains("expected i64"));
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_old_captures_are_pruned() {
+        let mut config = CaptureConfig::new(test_dir("pruned"));
+        config.max_captures = 2;
+        let mut paths = Vec::new();
+        for _ in 0..4 {
+            paths.push(config.capture("devices", "url", 500, "oops", None).unwrap());
+        }
+
+        assert!(!paths[0].exists());
+        assert!(!paths[1].exists());
+        assert!(paths[2].exists());
+        assert!(paths[3].exists());
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use crate::stats::{ArtistStats, HourlyListening};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDate;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use plotters::style::text_anchor::{HPos, Pos, VPos};
+use std::path::Path;
+
+pub const CHART_SIZE: (u32, u32) = (1024, 600);
+/// Every data element is drawn in this color, the heatmap only varies its
+/// opacity
+const BAR_COLOR: RGBColor = RGBColor(0x1D, 0xB9, 0x54);
+/// Playlist covers are square, Spotify scales them down for display
+pub const COVER_SIZE: u32 = 640;
+/// Keeps a generated cover well under Spotify's upload limit
+const COVER_QUALITY: u8 = 85;
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// A chart of one of the stats queries. Takes their output as is, so the
+/// charts show the same numbers as the text reports.
+pub enum Chart<'a> {
+    PlaysPerDay(&'a [(NaiveDate, usize)]),
+    ListeningByHour(&'a HourlyListening),
+    TopArtists(&'a [ArtistStats]),
+}
+
+impl Chart<'_> {
+    fn title(&self) -> &'static str {
+        match self {
+            Chart::PlaysPerDay(_) => "Plays per day",
+            Chart::ListeningByHour(_) => "Listening by hour",
+            Chart::TopArtists(_) => "Top artists",
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Chart::PlaysPerDay(days) => days.iter().all(|(_, plays)| *plays == 0),
+            Chart::ListeningByHour(listening) => listening.is_empty(),
+            Chart::TopArtists(artists) => artists.is_empty(),
+        }
+    }
+
+    /// Writes the chart to `out`, as SVG or PNG going by its extension.
+    pub fn render(&self, out: &Path) -> Result<()> {
+        let extension = out
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("svg") => self.draw_on(SVGBackend::new(out, CHART_SIZE).into_drawing_area()),
+            Some("png") => self.draw_on(BitMapBackend::new(out, CHART_SIZE).into_drawing_area()),
+            _ => bail!("Can only write .svg or .png charts, not {}", out.display()),
+        }
+    }
+
+    /// The chart as an SVG document.
+    pub fn to_svg(&self) -> Result<String> {
+        let mut svg = String::new();
+        self.draw_on(SVGBackend::with_string(&mut svg, CHART_SIZE).into_drawing_area())?;
+        Ok(svg)
+    }
+
+    fn draw_on<DB: DrawingBackend>(&self, root: DrawingArea<DB, Shift>) -> Result<()> {
+        let drawn = root.fill(&WHITE).and_then(|_| {
+            if self.is_empty() {
+                return draw_placeholder(&root, self.title());
+            }
+            match self {
+                Chart::PlaysPerDay(days) => draw_plays_per_day(&root, days),
+                Chart::ListeningByHour(listening) => draw_listening_by_hour(&root, listening),
+                Chart::TopArtists(artists) => draw_top_artists(&root, artists),
+            }
+        });
+        drawn
+            .and_then(|_| root.present())
+            .map_err(|e| anyhow!("Could not draw the {} chart: {e}", self.title()))
+    }
+}
+
+/// A playlist cover as a JPEG, `title` in white on the chart color, one
+/// line of it per line of `title`.
+pub fn playlist_cover(title: &str) -> Result<Vec<u8>> {
+    let mut pixels = vec![0; (COVER_SIZE * COVER_SIZE * 3) as usize];
+    let root =
+        BitMapBackend::with_buffer(&mut pixels, (COVER_SIZE, COVER_SIZE)).into_drawing_area();
+    root.fill(&BAR_COLOR)
+        .and_then(|_| draw_cover_title(&root, title))
+        .and_then(|_| root.present())
+        .map_err(|e| anyhow!("Could not draw the playlist cover: {e}"))?;
+    drop(root);
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, COVER_QUALITY).encode(
+        &pixels,
+        COVER_SIZE,
+        COVER_SIZE,
+        ColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+fn draw_cover_title<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    title: &str,
+) -> DrawResult<DB> {
+    let lines: Vec<&str> = title.lines().collect();
+    let line_height = 80;
+    let style = TextStyle::from(("sans-serif", 64))
+        .color(&WHITE)
+        .pos(Pos::new(HPos::Center, VPos::Center));
+    let center = COVER_SIZE as i32 / 2;
+    let top = center - line_height * (lines.len() as i32 - 1) / 2;
+    for (i, line) in lines.iter().enumerate() {
+        root.draw_text(line, &style, (center, top + line_height * i as i32))?;
+    }
+    Ok(())
+}
+
+type DrawResult<DB> = Result<(), DrawingAreaErrorKind<<DB as DrawingBackend>::ErrorType>>;
+
+fn draw_placeholder<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    title: &str,
+) -> DrawResult<DB> {
+    let (width, height) = root.dim_in_pixel();
+    let style = TextStyle::from(("sans-serif", 28).into_font())
+        .color(&RGBColor(0x70, 0x70, 0x70))
+        .pos(Pos::new(HPos::Center, VPos::Center));
+    root.draw_text(
+        &format!("{title}: no data for this range"),
+        &style,
+        (width as i32 / 2, height as i32 / 2),
+    )
+}
+
+fn draw_plays_per_day<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    days: &[(NaiveDate, usize)],
+) -> DrawResult<DB> {
+    let most = days.iter().map(|(_, plays)| *plays).max().unwrap_or(0) as u32;
+    let mut chart = ChartBuilder::on(root)
+        .caption("Plays per day", ("sans-serif", 28))
+        .margin(16)
+        .x_label_area_size(40)
+        .y_label_area_size(48)
+        // Segmented ranges include their end
+        .build_cartesian_2d((0..days.len() as u32 - 1).into_segmented(), 0..most + 1)?;
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .x_labels(days.len().min(12))
+        .x_label_formatter(&|value| match value {
+            SegmentValue::CenterOf(day) => days
+                .get(*day as usize)
+                .map(|(date, _)| date.format("%m-%d").to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        })
+        .y_desc("Plays")
+        .draw()?;
+    chart.draw_series(days.iter().enumerate().map(|(day, (_, plays))| {
+        let day = day as u32;
+        let mut bar = Rectangle::new(
+            [
+                (SegmentValue::Exact(day), 0),
+                (SegmentValue::Exact(day + 1), *plays as u32),
+            ],
+            BAR_COLOR.filled(),
+        );
+        bar.set_margin(0, 0, 2, 2);
+        bar
+    }))?;
+    Ok(())
+}
+
+fn draw_listening_by_hour<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    listening: &HourlyListening,
+) -> DrawResult<DB> {
+    let most = listening.max().as_secs_f64();
+    let mut chart = ChartBuilder::on(root)
+        .caption("Listening by hour", ("sans-serif", 28))
+        .margin(16)
+        .x_label_area_size(40)
+        .y_label_area_size(48)
+        .build_cartesian_2d((0u32..23).into_segmented(), (0u32..6).into_segmented())?;
+    chart
+        .configure_mesh()
+        .disable_mesh()
+        .x_labels(24)
+        .y_labels(7)
+        .x_label_formatter(&|value| match value {
+            SegmentValue::CenterOf(hour) => hour.to_string(),
+            _ => String::new(),
+        })
+        // Monday on top
+        .y_label_formatter(&|value| match value {
+            SegmentValue::CenterOf(row) => WEEKDAYS
+                .get(6usize.saturating_sub(*row as usize))
+                .map(|day| day.to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        })
+        .x_desc("Hour")
+        .draw()?;
+    chart.draw_series(
+        listening
+            .cells
+            .iter()
+            .enumerate()
+            .flat_map(|(weekday, hours)| {
+                let row = 6 - weekday as u32;
+                hours.iter().enumerate().map(move |(hour, listened)| {
+                    let hour = hour as u32;
+                    // Empty hours stay faintly visible so the grid reads as one
+                    let share = 0.05 + 0.95 * listened.as_secs_f64() / most;
+                    let mut cell = Rectangle::new(
+                        [
+                            (SegmentValue::Exact(hour), SegmentValue::Exact(row)),
+                            (SegmentValue::Exact(hour + 1), SegmentValue::Exact(row + 1)),
+                        ],
+                        BAR_COLOR.mix(share).filled(),
+                    );
+                    cell.set_margin(1, 1, 1, 1);
+                    cell
+                })
+            }),
+    )?;
+    Ok(())
+}
+
+fn draw_top_artists<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    artists: &[ArtistStats],
+) -> DrawResult<DB> {
+    let most = artists.iter().map(|artist| artist.plays).max().unwrap_or(0) as u32;
+    let rows = artists.len() as u32;
+    let mut chart = ChartBuilder::on(root)
+        .caption("Top artists", ("sans-serif", 28))
+        .margin(16)
+        .x_label_area_size(40)
+        .y_label_area_size(200)
+        .build_cartesian_2d(0..most + 1, (0..rows - 1).into_segmented())?;
+    chart
+        .configure_mesh()
+        .disable_y_mesh()
+        .y_labels(artists.len())
+        // Most played on top
+        .y_label_formatter(&|value| match value {
+            SegmentValue::CenterOf(row) => artists
+                .get((rows - 1).saturating_sub(*row) as usize)
+                .map(|artist| artist.name.clone())
+                .unwrap_or_default(),
+            _ => String::new(),
+        })
+        .x_desc("Plays")
+        .draw()?;
+    chart.draw_series(artists.iter().enumerate().map(|(rank, artist)| {
+        let row = rows - 1 - rank as u32;
+        let mut bar = Rectangle::new(
+            [
+                (0, SegmentValue::Exact(row)),
+                (artist.plays as u32, SegmentValue::Exact(row + 1)),
+            ],
+            BAR_COLOR.filled(),
+        );
+        bar.set_margin(2, 2, 0, 0);
+        bar
+    }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const BAR_FILL: &str = "fill=\"#1DB954\"";
+
+    fn data_elements(svg: &str) -> usize {
+        svg.matches(BAR_FILL).count()
+    }
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 9, day).unwrap()
+    }
+
+    #[test]
+    fn test_plays_per_day_chart() {
+        let days = [(day(23), 2), (day(24), 0), (day(25), 5)];
+        let svg = Chart::PlaysPerDay(&days).to_svg().unwrap();
+        assert_eq!(data_elements(&svg), days.len());
+        assert!(svg.contains("09-25"));
+    }
+
+    #[test]
+    fn test_listening_by_hour_chart() {
+        let mut listening = HourlyListening::default();
+        listening.cells[0][8] = Duration::from_secs(600);
+        listening.cells[6][23] = Duration::from_secs(60);
+        let svg = Chart::ListeningByHour(&listening).to_svg().unwrap();
+        assert_eq!(data_elements(&svg), 7 * 24);
+        assert!(svg.contains("Mon"));
+    }
+
+    #[test]
+    fn test_top_artists_chart() {
+        let artists: Vec<ArtistStats> = ["Ratatat", "Daft Punk"]
+            .iter()
+            .zip([12, 7])
+            .map(|(name, plays)| ArtistStats {
+                name: name.to_string(),
+                plays,
+                listened: Duration::from_secs(plays as u64 * 180),
+            })
+            .collect();
+        let svg = Chart::TopArtists(&artists).to_svg().unwrap();
+        assert_eq!(data_elements(&svg), artists.len());
+        assert!(svg.contains("Daft Punk"));
+    }
+
+    #[test]
+    fn test_empty_charts_show_placeholder() {
+        let charts = [
+            Chart::PlaysPerDay(&[]),
+            Chart::PlaysPerDay(&[(day(23), 0)]),
+            Chart::ListeningByHour(&HourlyListening::default()),
+            Chart::TopArtists(&[]),
+        ];
+        for chart in charts {
+            let svg = chart.to_svg().unwrap();
+            assert_eq!(data_elements(&svg), 0);
+            assert!(svg.contains("no data for this range"));
+        }
+    }
+
+    #[test]
+    fn test_playlist_cover() {
+        let jpeg = playlist_cover("September\n2024").unwrap();
+        // JPEG start and end of image markers
+        assert!(jpeg.starts_with(&[0xFF, 0xD8, 0xFF]));
+        assert!(jpeg.ends_with(&[0xFF, 0xD9]));
+        // Has to fit Spotify's upload limit once base64 encoded
+        assert!(jpeg.len() * 4 / 3 < 256 * 1024);
+    }
+
+    #[test]
+    fn test_render_picks_format_from_extension() {
+        let dir = std::env::temp_dir().join(format!("spotify-rs-charts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let days = [(day(23), 3)];
+        let chart = Chart::PlaysPerDay(&days);
+
+        let svg = dir.join("plays.svg");
+        chart.render(&svg).unwrap();
+        assert!(std::fs::read_to_string(&svg).unwrap().starts_with("<svg"));
+        assert!(chart.render(&dir.join("plays.txt")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
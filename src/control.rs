@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+pub const DEFAULT_CONTROL_DIR: &str = "control";
+/// Commands older than this when the daemon gets to them are dropped, they
+/// were meant for whatever was playing back then.
+pub const DEFAULT_COMMAND_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Something the CLI asks the running daemon to do.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlCommand {
+    /// Label the play in progress
+    Tag {
+        label: String,
+    },
+    /// Report what the daemon is doing, without asking Spotify
+    Status,
+    /// Keep polling but stop recording plays
+    PauseTracking,
+    ResumeTracking,
+    /// Load the credentials again, e.g. after re-running `auth`
+    ReloadConfig,
+    /// Record the play in progress and exit
+    Shutdown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ControlMessage {
+    sent_at: SystemTime,
+    #[serde(flatten)]
+    command: ControlCommand,
+}
+
+/// CLI to daemon commands through a directory both can reach.
+///
+/// Each command is one JSON file, written under a temporary name and renamed
+/// so the daemon never reads half a command. The daemon drains the directory
+/// on every poll, oldest command first.
+pub struct ControlChannel {
+    dir: PathBuf,
+}
+
+impl ControlChannel {
+    pub fn new(dir: impl Into<PathBuf>) -> ControlChannel {
+        ControlChannel { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn send(&self, command: ControlCommand) -> Result<PathBuf> {
+        self.send_at(command, SystemTime::now())
+    }
+
+    fn send_at(&self, command: ControlCommand, sent_at: SystemTime) -> Result<PathBuf> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Could not create {}", self.dir.display()))?;
+        let nanos = sent_at.duration_since(UNIX_EPOCH)?.as_nanos();
+        let name = format!("{nanos:020}-{}", std::process::id());
+        let tmp = self.dir.join(format!("{name}.tmp"));
+        let path = self.dir.join(format!("{name}.json"));
+
+        let message = ControlMessage { sent_at, command };
+        fs::write(&tmp, serde_json::to_string(&message)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+
+    /// Takes every pending command out of the channel, in the order they were
+    /// sent. Commands older than `max_age` and unreadable files are dropped.
+    pub fn drain(&self, now: SystemTime, max_age: Duration) -> Result<Vec<ControlCommand>> {
+        if !fs::exists(&self.dir)? {
+            return Ok(Vec::new());
+        }
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+
+        let mut commands = Vec::new();
+        for path in paths {
+            let message = fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_str::<ControlMessage>(&data)?));
+            fs::remove_file(&path)?;
+            match message {
+                Err(e) => warn!("Dropping unreadable command {}: {e}", path.display()),
+                Ok(message) => {
+                    let age = now.duration_since(message.sent_at).unwrap_or_default();
+                    if age > max_age {
+                        warn!("Dropping stale command {:?}", message.command);
+                    } else {
+                        commands.push(message.command);
+                    }
+                }
+            }
+        }
+        Ok(commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_channel(name: &str) -> ControlChannel {
+        let dir = std::env::temp_dir().join(format!("control-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ControlChannel::new(dir)
+    }
+
+    fn tag(label: &str) -> ControlCommand {
+        ControlCommand::Tag {
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_send_and_drain_in_order() {
+        let channel = temp_channel("order");
+        let now = SystemTime::now();
+        assert!(channel
+            .drain(now, DEFAULT_COMMAND_MAX_AGE)
+            .unwrap()
+            .is_empty());
+
+        channel
+            .send_at(tag("gym"), now - Duration::from_secs(2))
+            .unwrap();
+        channel
+            .send_at(tag("focus"), now - Duration::from_secs(5))
+            .unwrap();
+        fs::write(channel.dir().join("garbage.json"), "{").unwrap();
+
+        let commands = channel.drain(now, DEFAULT_COMMAND_MAX_AGE).unwrap();
+        assert_eq!(commands, vec![tag("focus"), tag("gym")]);
+        assert!(channel
+            .drain(now, DEFAULT_COMMAND_MAX_AGE)
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(channel.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_stale_commands_are_dropped() {
+        let channel = temp_channel("stale");
+        let now = SystemTime::now();
+        channel
+            .send_at(tag("old"), now - Duration::from_secs(600))
+            .unwrap();
+        assert!(channel
+            .drain(now, DEFAULT_COMMAND_MAX_AGE)
+            .unwrap()
+            .is_empty());
+        assert_eq!(fs::read_dir(channel.dir()).unwrap().count(), 0);
+        fs::remove_dir_all(channel.dir()).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+use crate::control::ControlCommand;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Bumped whenever requests or responses change incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+const SOCKET_NAME: &str = "control.sock";
+
+/// One line of newline delimited JSON sent to the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Request {
+    pub version: u32,
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+/// The daemon's answer, one line per request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Response {
+    pub version: u32,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<DaemonStatus>,
+}
+
+impl Response {
+    pub fn ok() -> Response {
+        Response {
+            version: PROTOCOL_VERSION,
+            ok: true,
+            error: None,
+            status: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Response {
+        Response {
+            ok: false,
+            error: Some(message.into()),
+            ..Response::ok()
+        }
+    }
+
+    pub fn with_status(status: DaemonStatus) -> Response {
+        Response {
+            status: Some(status),
+            ..Response::ok()
+        }
+    }
+}
+
+/// What `status` reports, answered by the daemon without asking Spotify.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started_at: SystemTime,
+    /// False while tracking is paused
+    pub tracking: bool,
+    pub current_track: Option<String>,
+    pub plays_recorded: usize,
+    /// Repeated warnings left out of the log since the start
+    #[serde(default)]
+    pub warnings_suppressed: u64,
+}
+
+/// Where the daemon listens unless told otherwise: a private directory in
+/// `$XDG_RUNTIME_DIR`, or in the temp dir when that isn't set.
+pub fn default_socket_path() -> PathBuf {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) if !runtime.is_empty() => PathBuf::from(runtime).join("spotify-rs"),
+        _ => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "default".to_string());
+            std::env::temp_dir().join(format!("spotify-rs-{user}"))
+        }
+    };
+    dir.join(SOCKET_NAME)
+}
+
+/// Parses one request line, answering version mismatches and garbage with
+/// an error response instead of dropping the connection.
+fn handle_line(line: &str, handler: &mut impl FnMut(ControlCommand) -> Response) -> Response {
+    let value: serde_json::Value = match serde_json::from_str(line) {
+        Ok(value) => value,
+        Err(e) => return Response::error(format!("Invalid request: {e}")),
+    };
+    let version = value.get("version").and_then(|v| v.as_u64());
+    if version != Some(PROTOCOL_VERSION as u64) {
+        return Response::error(format!(
+            "Unsupported protocol version {version:?}, the daemon speaks {PROTOCOL_VERSION}"
+        ));
+    }
+    match serde_json::from_value::<Request>(value) {
+        Ok(request) => handler(request.command),
+        Err(e) => Response::error(format!("Invalid request: {e}")),
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{send, ControlServer};
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use anyhow::Context;
+    use std::fs;
+    use std::io::{BufRead, BufReader, ErrorKind, Write};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::time::Duration;
+    use tracing::{debug, warn};
+
+    const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The daemon side of the control socket. Accepting never blocks, so the
+    /// daemon can check for commands in between polls of Spotify.
+    pub struct ControlServer {
+        path: PathBuf,
+        listener: UnixListener,
+    }
+
+    impl ControlServer {
+        /// Listens on `path`, readable by the owner only. A socket left behind
+        /// by a daemon that crashed is replaced, a live one is an error.
+        pub fn bind(path: &Path) -> Result<ControlServer> {
+            if let Some(dir) = path.parent() {
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)
+                    .with_context(|| format!("Could not create {}", dir.display()))?;
+            }
+
+            if fs::exists(path)? {
+                if UnixStream::connect(path).is_ok() {
+                    bail!("Another daemon is already listening on {}", path.display());
+                }
+                warn!("Replacing stale control socket {}", path.display());
+                fs::remove_file(path)?;
+            }
+
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("Could not listen on {}", path.display()))?;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            listener.set_nonblocking(true)?;
+            Ok(ControlServer {
+                path: path.to_path_buf(),
+                listener,
+            })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Serves every connection waiting right now, returns how many requests were handled.
+        pub fn poll(&self, mut handler: impl FnMut(ControlCommand) -> Response) -> Result<usize> {
+            let mut handled = 0;
+            loop {
+                match self.listener.accept() {
+                    Ok((stream, _)) => match serve(stream, &mut handler) {
+                        Ok(n) => handled += n,
+                        Err(e) => warn!("Control connection failed: {e}"),
+                    },
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(handled),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+
+    impl Drop for ControlServer {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn serve(
+        stream: UnixStream,
+        handler: &mut impl FnMut(ControlCommand) -> Response,
+    ) -> Result<usize> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut handled = 0;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let response = handle_line(&line, handler);
+            debug!("Control request {line} answered with ok={}", response.ok);
+            writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+            handled += 1;
+        }
+        Ok(handled)
+    }
+
+    /// Sends one command to the daemon listening on `path` and waits for its answer.
+    pub fn send(path: &Path, command: ControlCommand) -> Result<Response> {
+        let stream = UnixStream::connect(path).with_context(|| {
+            format!(
+                "Could not reach the daemon on {}, is it running?",
+                path.display()
+            )
+        })?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        let request = Request {
+            version: PROTOCOL_VERSION,
+            command,
+        };
+        let mut writer = stream.try_clone()?;
+        writeln!(writer, "{}", serde_json::to_string(&request)?)?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        if line.is_empty() {
+            bail!("The daemon closed the connection without answering");
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// There is no control socket outside Unix yet, a named pipe transport for
+/// Windows isn't written. The daemon falls back to the control directory.
+#[cfg(not(unix))]
+pub struct ControlServer;
+
+#[cfg(not(unix))]
+impl ControlServer {
+    /// Always fails, see [ControlServer].
+    pub fn bind(path: &Path) -> Result<ControlServer> {
+        bail!(
+            "The control socket {} needs a Unix platform, use the control directory instead",
+            path.display()
+        )
+    }
+}
+
+/// Always fails outside Unix, there is no named pipe transport for Windows yet.
+#[cfg(not(unix))]
+pub fn send(_path: &Path, _command: ControlCommand) -> Result<Response> {
+    bail!("The control socket needs a Unix platform, use the control directory instead")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    fn temp_socket(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ctl-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.join(SOCKET_NAME)
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let mut handler = |_| Response::ok();
+        let response = handle_line(r#"{"version": 99, "command": "status"}"#, &mut handler);
+        assert!(!response.ok);
+        assert!(response.error.unwrap().contains("version"));
+
+        let response = handle_line(r#"{"version": 1, "command": "dance"}"#, &mut handler);
+        assert!(!response.ok);
+        assert!(!handle_line("not json", &mut handler).ok);
+        assert!(
+            handle_line(
+                r#"{"version": 1, "command": "reload-config"}"#,
+                &mut handler
+            )
+            .ok
+        );
+    }
+
+    #[test]
+    fn test_stale_socket_is_replaced() {
+        let path = temp_socket("stale");
+        let server = ControlServer::bind(&path).unwrap();
+        // A second daemon can't take over a live socket
+        assert!(ControlServer::bind(&path).is_err());
+        drop(server);
+        assert!(!std::fs::exists(&path).unwrap());
+
+        // A crashed daemon leaves its socket file behind with nobody listening
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(std::fs::exists(&path).unwrap());
+        let server = ControlServer::bind(&path).unwrap();
+
+        let mode = std::fs::metadata(server.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::history::PlayHistoryEntry;
+
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const DEFAULT_DEVICE_ALIASES_FILE: &str = "device_aliases.json";
+/// The device of plays recorded without one, e.g. from older history
+pub const UNKNOWN_DEVICE: &str = "Unknown";
+
+/// Canonical labels for device names, e.g. `"Pixel 7": "Phone"` and
+/// `"Pixel 8 Pro": "Phone"` so a renamed or replaced phone stays one device.
+/// History keeps the raw names, labels are applied when it is queried.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceAliases {
+    aliases: HashMap<String, String>,
+}
+
+impl DeviceAliases {
+    pub fn new() -> DeviceAliases {
+        DeviceAliases::default()
+    }
+
+    /// Reads a JSON object of raw device names to labels. A missing file
+    /// means no aliases.
+    pub fn load(path: &Path) -> Result<DeviceAliases> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DeviceAliases::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let aliases = serde_json::from_str(&data)
+            .with_context(|| format!("{} is not a JSON object of device names", path.display()))?;
+        Ok(DeviceAliases { aliases })
+    }
+
+    pub fn with_alias(mut self, device: &str, label: &str) -> DeviceAliases {
+        self.aliases.insert(device.to_string(), label.to_string());
+        self
+    }
+
+    /// The label of a raw device name, the name itself when it has none.
+    pub fn label<'a>(&'a self, device: &'a str) -> &'a str {
+        self.aliases.get(device).map_or(device, String::as_str)
+    }
+
+    /// The label of the device `entry` played on.
+    pub fn device_of<'a>(&'a self, entry: &'a PlayHistoryEntry) -> &'a str {
+        entry
+            .device
+            .as_deref()
+            .map_or(UNKNOWN_DEVICE, |device| self.label(device))
+    }
+
+    /// Whether `entry` played on `device`, a label or a raw name, ignoring case.
+    pub fn played_on(&self, entry: &PlayHistoryEntry, device: &str) -> bool {
+        self.device_of(entry)
+            .eq_ignore_ascii_case(self.label(device))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HistoryStore;
+
+    fn aliases() -> DeviceAliases {
+        DeviceAliases::load(Path::new("sample_data/device_aliases.json")).unwrap()
+    }
+
+    #[test]
+    fn test_aliases_are_applied_at_query_time() {
+        let entries = HistoryStore::new("sample_data/device_history.jsonl")
+            .load()
+            .unwrap();
+        let aliases = aliases();
+        let devices: Vec<&str> = entries.iter().map(|e| aliases.device_of(e)).collect();
+        assert_eq!(
+            devices,
+            [
+                "Phone",
+                "Phone",
+                "Phone",
+                "Car",
+                "Car",
+                "Desk",
+                UNKNOWN_DEVICE
+            ]
+        );
+        // History keeps the raw name
+        assert_eq!(entries[2].device.as_deref(), Some("Pixel 8 Pro"));
+
+        let in_car = entries.iter().filter(|e| aliases.played_on(e, "car"));
+        assert_eq!(in_car.count(), 2);
+        // A raw name finds every play of its label
+        let on_phone = entries.iter().filter(|e| aliases.played_on(e, "Pixel 7"));
+        assert_eq!(on_phone.count(), 3);
+        // Without aliases only the raw name matches
+        let raw = DeviceAliases::new();
+        assert_eq!(
+            entries
+                .iter()
+                .filter(|e| raw.played_on(e, "Pixel 7"))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_missing_aliases_file() {
+        let aliases = DeviceAliases::load(Path::new("sample_data/no_such_aliases.json")).unwrap();
+        assert_eq!(aliases.label("Pixel 7"), "Pixel 7");
+        let aliases = aliases.with_alias("Pixel 7", "Phone");
+        assert_eq!(aliases.label("Pixel 7"), "Phone");
+
+        let path = std::env::temp_dir().join(format!("aliases-{}.json", std::process::id()));
+        fs::write(&path, "[\"Pixel 7\"]").unwrap();
+        assert!(DeviceAliases::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use crate::report::ListeningReport;
+use crate::state_file::StateFormat;
+
+use anyhow::{bail, Context, Result};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub const DEFAULT_EMAIL_OUTBOX_FILE: &str = "email_outbox.json";
+/// The key of the SMTP password in the creds storage.
+pub const SMTP_PASSWORD_KEY: &str = "smtp_password";
+
+const EMAIL_OUTBOX_FORMAT: StateFormat = StateFormat {
+    version: 1,
+    upgrades: &[],
+};
+/// A server that doesn't answer shouldn't hold up the daemon for long
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How the connection to the SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Upgraded with STARTTLS, usually on port 587
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// Plain text, only for a relay on the same machine
+    Plain,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SmtpSecurity> {
+        match s.to_ascii_lowercase().as_str() {
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "tls" => Ok(SmtpSecurity::Tls),
+            "none" => Ok(SmtpSecurity::Plain),
+            _ => bail!("expected starttls, tls or none, got {s:?}"),
+        }
+    }
+}
+
+/// Where reports are sent through and to whom.
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// A rendered email, kept until it was sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Email {
+    pub subject: String,
+    /// The Markdown of the report, for clients without HTML
+    pub text: String,
+    pub html: String,
+}
+
+impl From<&ListeningReport> for Email {
+    fn from(report: &ListeningReport) -> Email {
+        Email {
+            subject: report.subject(),
+            text: report.markdown(),
+            html: report.html(),
+        }
+    }
+}
+
+impl SmtpConfig {
+    /// `email` addressed to the recipients.
+    pub fn message(&self, email: &Email) -> Result<Message> {
+        let from: Mailbox = self
+            .from
+            .parse()
+            .with_context(|| format!("Not an email address: {:?}", self.from))?;
+        let mut builder = Message::builder().from(from).subject(&email.subject);
+        if self.to.is_empty() {
+            bail!("The report has no recipients");
+        }
+        for to in &self.to {
+            let to: Mailbox = to
+                .parse()
+                .with_context(|| format!("Not an email address: {to:?}"))?;
+            builder = builder.to(to);
+        }
+        let body = MultiPart::alternative_plain_html(email.text.clone(), email.html.clone());
+        Ok(builder.multipart(body)?)
+    }
+
+    /// The email as it would go out, e.g. for a dry run.
+    pub fn render(&self, email: &Email) -> Result<String> {
+        let message = self.message(email)?;
+        Ok(String::from_utf8_lossy(&message.formatted()).into_owned())
+    }
+
+    fn transport(&self) -> Result<SmtpTransport> {
+        let builder = match self.security {
+            SmtpSecurity::StartTls => SmtpTransport::starttls_relay(&self.host)?,
+            SmtpSecurity::Tls => SmtpTransport::relay(&self.host)?,
+            SmtpSecurity::Plain => SmtpTransport::builder_dangerous(&self.host),
+        };
+        let mut builder = builder.port(self.port).timeout(Some(SMTP_TIMEOUT));
+        if let Some(username) = &self.username {
+            let password = self.password.clone().unwrap_or_default();
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+        Ok(builder.build())
+    }
+}
+
+/// Emails that could not be sent yet, kept in a JSON file so neither an
+/// unreachable server nor a restart drops them.
+pub struct EmailOutbox {
+    path: PathBuf,
+    pending: Vec<Email>,
+}
+
+impl EmailOutbox {
+    /// The outbox in `path`, with what an earlier run left in it.
+    pub fn open(path: impl Into<PathBuf>) -> Result<EmailOutbox> {
+        let path = path.into();
+        let pending = match fs::read_to_string(&path) {
+            Ok(data) => EMAIL_OUTBOX_FORMAT.parse(&data, &path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(EmailOutbox { path, pending })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Writes the queue, removing the file once nothing is left in it.
+    fn save(&self) -> Result<()> {
+        if self.pending.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        fs::write(&self.path, EMAIL_OUTBOX_FORMAT.to_string(&self.pending)?)
+            .with_context(|| format!("Could not write {}", self.path.display()))
+    }
+}
+
+/// Sends reports over SMTP. Every email goes through the [EmailOutbox] and
+/// leaves it once the server took it, so a failed one is sent again with
+/// the next [EmailSender::flush].
+pub struct EmailSender {
+    config: SmtpConfig,
+    transport: SmtpTransport,
+    outbox: EmailOutbox,
+}
+
+impl EmailSender {
+    pub fn new(config: SmtpConfig, outbox: EmailOutbox) -> Result<EmailSender> {
+        Ok(EmailSender {
+            transport: config.transport()?,
+            config,
+            outbox,
+        })
+    }
+
+    pub fn outbox(&self) -> &EmailOutbox {
+        &self.outbox
+    }
+
+    /// Queues `email` behind the others and sends them all.
+    /// On Error: what failed, the emails left are kept in the outbox.
+    pub fn send(&mut self, email: Email) -> Result<()> {
+        // Checked before it's queued, a bad address won't get better later
+        self.config.message(&email)?;
+        self.outbox.pending.push(email);
+        self.outbox.save()?;
+        self.flush()
+    }
+
+    /// Sends the queued emails in order, stopping at the first failure.
+    pub fn flush(&mut self) -> Result<()> {
+        while let Some(email) = self.outbox.pending.first() {
+            let message = self.config.message(email)?;
+            if let Err(e) = self.transport.send(&message) {
+                warn!(
+                    "Keeping {} emails in {} for later",
+                    self.outbox.len(),
+                    self.outbox.path.display()
+                );
+                return Err(e).with_context(|| format!("Could not send {:?}", email.subject));
+            }
+            info!("Sent {:?} to {}", email.subject, self.config.to.join(", "));
+            self.outbox.pending.remove(0);
+            self.outbox.save()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn config(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::Plain,
+            username: None,
+            password: None,
+            from: "Music tracker <tracker@example.com>".to_string(),
+            to: vec!["jorge@example.com".to_string()],
+        }
+    }
+
+    fn email() -> Email {
+        Email {
+            subject: "Listening report, February 22 to February 28".to_string(),
+            text: "# Listening report\n".to_string(),
+            html: "<h1>Listening report</h1>".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rendered_email() {
+        let rendered = config(25).render(&email()).unwrap();
+        assert!(rendered.contains("<tracker@example.com>"));
+        assert!(rendered.contains("To: jorge@example.com"));
+        assert!(rendered.contains("Subject: Listening report, February 22 to February 28"));
+        assert!(rendered.contains("multipart/alternative"));
+        assert!(rendered.contains("<h1>Listening report</h1>"));
+
+        let mut no_one = config(25);
+        no_one.to.clear();
+        assert!(no_one.render(&email()).is_err());
+        assert_eq!(
+            "STARTTLS".parse::<SmtpSecurity>().unwrap(),
+            SmtpSecurity::StartTls
+        );
+        assert!("ssl".parse::<SmtpSecurity>().is_err());
+    }
+
+    #[test]
+    fn test_failed_email_is_queued() {
+        let dir = std::env::temp_dir().join(format!("email-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(DEFAULT_EMAIL_OUTBOX_FILE);
+        // Nothing listens on a port that was just given back
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let outbox = EmailOutbox::open(&path).unwrap();
+        let mut sender = EmailSender::new(config(port), outbox).unwrap();
+        assert!(sender.send(email()).is_err());
+        assert_eq!(sender.outbox().len(), 1);
+
+        let outbox = EmailOutbox::open(&path).unwrap();
+        assert_eq!(outbox.pending, vec![email()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,203 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Spotify failures callers may want to tell apart. They travel inside
+/// anyhow errors, find them with `downcast_ref`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpotifyError {
+    /// There are no stored creds for the user, they have to authorize first
+    MissingCreds,
+    /// Spotify refused the refresh token, only a new authorization helps
+    RefreshTokenRevoked,
+    /// Spotify rejected the access token even after a refresh
+    TokenRejected,
+    /// Spotify could not be reached at all
+    Network,
+    /// A playlist changed since the snapshot an edit was made against,
+    /// fetch it again before retrying
+    SnapshotOutdated,
+    /// Spotify answered with a gateway error page, a temporary outage
+    Upstream { status: u16 },
+    /// The endpoint acts for a user, an app-only client can't use it
+    UserAuthRequired,
+}
+
+impl fmt::Display for SpotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            SpotifyError::MissingCreds => "There are no Spotify creds for this user yet",
+            SpotifyError::RefreshTokenRevoked => {
+                "Your Spotify refresh token was revoked or has expired"
+            }
+            SpotifyError::TokenRejected => "Spotify rejected the access token",
+            SpotifyError::Network => "Could not reach Spotify",
+            SpotifyError::SnapshotOutdated => "The playlist changed since its snapshot was taken",
+            SpotifyError::UserAuthRequired => {
+                "This needs a user's authorization, not app-only auth"
+            }
+            SpotifyError::Upstream { status } => {
+                return write!(f, "Spotify is having a temporary outage (HTTP {status})");
+            }
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for SpotifyError {}
+
+/// Scopes a command needs that the user hasn't granted. Authorizing again
+/// with them keeps the ones granted before.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingScopes {
+    pub missing: Vec<String>,
+}
+
+impl fmt::Display for MissingScopes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "This needs scopes you haven't granted: {}",
+            self.missing.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for MissingScopes {}
+
+/// Spotify redirected back with an `error` instead of a code, usually
+/// because the user declined the app's access request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizationDenied {
+    /// The `error` Spotify sent, e.g. `access_denied`
+    pub reason: String,
+    pub description: Option<String>,
+}
+
+impl fmt::Display for AuthorizationDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason.as_str() {
+            "access_denied" => write!(f, "The authorization was declined")?,
+            reason => write!(f, "Spotify refused the authorization: {reason}")?,
+        }
+        if let Some(description) = &self.description {
+            write!(f, " ({description})")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AuthorizationDenied {}
+
+/// A state file written by a newer build, in a format this one may
+/// misread. It is left alone rather than overwritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewerStateFile {
+    pub path: PathBuf,
+    pub version: u32,
+    /// The newest version this build reads
+    pub supported: u32,
+}
+
+impl fmt::Display for NewerStateFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is format v{}, this build only reads up to v{}",
+            self.path.display(),
+            self.version,
+            self.supported
+        )
+    }
+}
+
+impl std::error::Error for NewerStateFile {}
+
+/// Failures of the secrets storage the creds live in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    /// `bitwarden_config.json` is missing or has a bad field
+    Config,
+    /// Logging into or talking to Bitwarden failed
+    Unreachable,
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            StorageError::Config => "bitwarden_config.json can't be used",
+            StorageError::Unreachable => "Bitwarden is unreachable",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// A store of the user's tokens that only partly went through. Lists every
+/// target by name: the local file and the bitwarden secret keys.
+#[derive(Debug)]
+pub struct StoreFailure {
+    pub stored: Vec<String>,
+    pub failed: Vec<String>,
+    /// Whether the failed bitwarden writes were queued for the next run
+    pub queued: bool,
+}
+
+impl fmt::Display for StoreFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Could not store {}", self.failed.join(", "))?;
+        if !self.stored.is_empty() {
+            write!(f, " (stored {})", self.stored.join(", "))?;
+        }
+        if self.queued {
+            write!(f, ", retrying on the next run")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for StoreFailure {}
+
+/// A [SecretProvider](crate::secrets::SecretProvider) has no secret under
+/// the key, as opposed to failing to look.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretNotFound {
+    pub key: String,
+}
+
+impl SecretNotFound {
+    pub fn new(key: &str) -> Self {
+        SecretNotFound {
+            key: key.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SecretNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret key <{}> does not exist", self.key)
+    }
+}
+
+impl std::error::Error for SecretNotFound {}
+
+/// Another daemon holds the lock on the history, see
+/// [InstanceLock](crate::instance_lock::InstanceLock).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlreadyRunning {
+    /// None when the holder hasn't written its pid yet
+    pub pid: Option<u32>,
+    pub lock_file: PathBuf,
+}
+
+impl fmt::Display for AlreadyRunning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "Another daemon (pid {pid}) is already running")?,
+            None => write!(f, "Another daemon is already running")?,
+        }
+        write!(f, ", it holds {}", self.lock_file.display())
+    }
+}
+
+impl std::error::Error for AlreadyRunning {}
@@ -0,0 +1,868 @@
+use crate::spotify_data::{Artist, Episode, PlayContext, Show, Track};
+use crate::state_file::StateFormat;
+use crate::stats::ListeningStats;
+use crate::streaming_history::{parse_streaming_history, streaming_history_files, KnownPlays};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+pub const DEFAULT_HISTORY_FILE: &str = "history.jsonl";
+
+/// How many lines of each other history were merged, and of each history
+/// pushed to or pulled from a remote, see [HistoryStore::sync_watermark].
+const SYNC_STATE_FORMAT: StateFormat = StateFormat {
+    version: 1,
+    upgrades: &[],
+};
+
+/// How sure we are a recorded play really happened.
+/// Plays seen by the player endpoint are `High`, plays only inferred from
+/// the queue endpoint are `Low`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Confidence {
+    #[default]
+    High,
+    Low,
+}
+
+/// Where a play was recorded. The daemon sees plays live, recently played
+/// and the account data export backfill them afterwards, without the
+/// device or where the track was skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaySource {
+    #[default]
+    Live,
+    Backfill,
+}
+
+impl PlaySource {
+    fn is_live(&self) -> bool {
+        *self == PlaySource::Live
+    }
+}
+
+/// One listen, stored as a line of the history file. Podcast episodes are
+/// stored like tracks, with their show as the album and no artists.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlayHistoryEntry {
+    pub track_id: String,
+    pub track_name: String,
+    pub artists: Vec<Artist>,
+    pub album: String,
+    pub duration_ms: u32,
+    pub played_at: SystemTime,
+    #[serde(default)]
+    pub confidence: Confidence,
+    /// Labels given while the track played, e.g. "focus"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The show of an episode, None for tracks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub show: Option<Show>,
+    /// How far into an episode playback got, None when it wasn't followed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress_ms: Option<u32>,
+    /// Where in the track playback moved on to something else, None when it
+    /// played to the end or wasn't followed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped_at_ms: Option<u32>,
+    /// The name of the device it played on, as Spotify reported it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// What it was played from, None when that wasn't recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<PlayContext>,
+    /// Plays recorded before this was count as live
+    #[serde(default, skip_serializing_if = "PlaySource::is_live")]
+    pub source: PlaySource,
+    /// When the backfilled play of the same track this one replaces was
+    /// played, see [HistoryStore::sync_in]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<SystemTime>,
+}
+
+impl PlayHistoryEntry {
+    pub fn from_track(track: &Track, played_at: SystemTime, confidence: Confidence) -> Self {
+        PlayHistoryEntry {
+            track_id: track.original_id().to_string(),
+            track_name: track.name.clone(),
+            artists: track.artists.clone(),
+            album: track.album.name.clone(),
+            duration_ms: track.duration_ms,
+            played_at,
+            confidence,
+            tags: Vec::new(),
+            show: None,
+            progress_ms: None,
+            skipped_at_ms: None,
+            device: None,
+            context: None,
+            source: PlaySource::Live,
+            replaces: None,
+        }
+    }
+
+    /// A play of `episode`, `progress_ms` into it. The episode's resume point
+    /// counts too, when the user got further on another device.
+    pub fn from_episode(
+        episode: &Episode,
+        progress_ms: Option<u32>,
+        played_at: SystemTime,
+        confidence: Confidence,
+    ) -> Self {
+        let resumed = episode
+            .resume_point
+            .map(|resume| resume.position_ms(episode.duration_ms));
+        PlayHistoryEntry {
+            track_id: episode.id.clone(),
+            track_name: episode.name.clone(),
+            artists: Vec::new(),
+            album: episode.show.name.clone(),
+            duration_ms: episode.duration_ms,
+            played_at,
+            confidence,
+            tags: Vec::new(),
+            show: Some(episode.show.clone()),
+            progress_ms: progress_ms.max(resumed),
+            skipped_at_ms: None,
+            device: None,
+            context: None,
+            source: PlaySource::Live,
+            replaces: None,
+        }
+    }
+
+    pub fn is_episode(&self) -> bool {
+        self.show.is_some()
+    }
+
+    /// How long was listened to. The whole track, or as far as an episode
+    /// got when that is known.
+    pub fn listened(&self) -> Duration {
+        let played = match self.progress_ms {
+            Some(progress) if self.is_episode() => progress.min(self.duration_ms),
+            _ => self.duration_ms,
+        };
+        Duration::from_millis(played as u64)
+    }
+
+    /// How much of an episode was played, from 0 to 1. None for tracks and
+    /// episodes that weren't followed.
+    pub fn completion(&self) -> Option<f64> {
+        let progress = self.progress_ms.filter(|_| self.is_episode())?;
+        if self.duration_ms == 0 {
+            return Some(1.0);
+        }
+        Some((progress as f64 / self.duration_ms as f64).min(1.0))
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+/// A track turning unplayable, or playable again, as first seen at `seen_at`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AvailabilityChange {
+    pub track_id: String,
+    pub track_name: String,
+    pub playable: bool,
+    /// Why Spotify won't play it, e.g. `market`, None when playable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub seen_at: SystemTime,
+}
+
+impl AvailabilityChange {
+    /// The availability Spotify reported for `track`, None when it reported
+    /// none, which it only does when the request named a market.
+    pub fn of_track(track: &Track, seen_at: SystemTime) -> Option<AvailabilityChange> {
+        if track.is_playable.is_none() && track.restrictions.is_none() {
+            return None;
+        }
+        let reason = track.playback_restriction_reason().map(str::to_string);
+        Some(AvailabilityChange {
+            track_id: track.original_id().to_string(),
+            track_name: track.name.clone(),
+            playable: track.is_playable.unwrap_or(reason.is_none()),
+            reason,
+            seen_at,
+        })
+    }
+}
+
+/// What auditing the saved tracks found, see `SpotifyClient::audit_library`.
+#[derive(Debug, Default)]
+pub struct LibraryAudit {
+    /// How many saved tracks were looked at
+    pub checked: usize,
+    /// The saved tracks Spotify won't play now, and why
+    pub unplayable: Vec<AvailabilityChange>,
+    /// The changes logged by the audit, tracks turning unplayable or
+    /// playable again since they were last seen
+    pub changes: usize,
+}
+
+/// The availability log next to a history file, e.g.
+/// `history.availability.jsonl` for `history.jsonl`.
+pub fn availability_path_for(history: &Path) -> PathBuf {
+    history.with_extension("availability.jsonl")
+}
+
+/// The merge watermarks next to a history file, e.g. `history.sync.json`
+/// for `history.jsonl`.
+pub fn sync_state_path_for(history: &Path) -> PathBuf {
+    history.with_extension("sync.json")
+}
+
+/// Whether plays of a track `length` long at `a` and `b` are the same one,
+/// seen once when it started and once when it finished.
+fn same_play(a: SystemTime, b: SystemTime, length: Duration) -> bool {
+    let apart = a.duration_since(b).or_else(|_| b.duration_since(a));
+    apart.is_ok_and(|apart| apart <= length)
+}
+
+/// What [HistoryStore::sync_in] did with the plays it was given.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncedPlays {
+    /// New plays
+    pub added: usize,
+    /// Backfilled plays the live ones replaced
+    pub replaced: usize,
+}
+
+/// Append-only JSON lines file of [PlayHistoryEntry]. A synced live play
+/// replacing a backfilled one is appended too, [HistoryStore::load] puts it
+/// in the backfilled one's place.
+pub struct HistoryStore {
+    path: PathBuf,
+    /// The last logged availability of each track, read from the log on the
+    /// first [HistoryStore::record_availability]
+    availability: Mutex<Option<HashMap<String, AvailabilityChange>>>,
+}
+
+impl HistoryStore {
+    pub fn new(path: impl Into<PathBuf>) -> HistoryStore {
+        HistoryStore {
+            path: path.into(),
+            availability: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &PlayHistoryEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // One write, so a line never interleaves with another process' append
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Appends the `entries` that aren't `known` yet, oldest first. A play is
+    /// told apart by its track and when it was played, plays of a track less
+    /// than its length apart are the same one: the daemon records when a
+    /// track started, recently played when it finished. Returns how many
+    /// were added.
+    pub fn append_new(
+        &self,
+        known: &[PlayHistoryEntry],
+        mut entries: Vec<PlayHistoryEntry>,
+    ) -> Result<usize> {
+        let mut seen: HashMap<String, Vec<SystemTime>> = HashMap::new();
+        for entry in known {
+            seen.entry(entry.track_id.clone())
+                .or_default()
+                .push(entry.played_at);
+        }
+        entries.sort_by_key(|entry| entry.played_at);
+        let mut added = 0;
+        for entry in entries {
+            let plays = seen.entry(entry.track_id.clone()).or_default();
+            let length = Duration::from_millis(entry.duration_ms as u64);
+            if plays
+                .iter()
+                .any(|&at| same_play(at, entry.played_at, length))
+            {
+                continue;
+            }
+            plays.push(entry.played_at);
+            self.append(&entry)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Adds the plays of another machine's history file, e.g. a copy of its
+    /// `history.jsonl`, that were added to it since the last merge from it.
+    /// How many of its lines were merged is kept per file in
+    /// [sync_state_path_for], plays already here are left out like by
+    /// [HistoryStore::append_new]. Returns how many were added.
+    pub fn merge_from(&self, other: &Path) -> Result<usize> {
+        let source = fs::canonicalize(other)
+            .with_context(|| format!("Could not find {}", other.display()))?;
+        let source = source.display().to_string();
+        let (plays, lines) = HistoryStore::new(other).plays_after(self.sync_watermark(&source)?)?;
+        let added = self.append_new(&self.load()?, plays)?;
+        self.set_sync_watermark(&source, lines)?;
+        Ok(added)
+    }
+
+    /// How many lines of a history were exchanged with `peer`: of the history
+    /// file merged from, or e.g. of this history for `push <url>` and of the
+    /// remote's for `pull <url>`. 0 before the first time.
+    pub fn sync_watermark(&self, peer: &str) -> Result<usize> {
+        Ok(self.sync_state()?.get(peer).copied().unwrap_or(0))
+    }
+
+    pub fn set_sync_watermark(&self, peer: &str, lines: usize) -> Result<()> {
+        let state_path = sync_state_path_for(&self.path);
+        let mut state = self.sync_state()?;
+        state.insert(peer.to_string(), lines);
+        fs::write(&state_path, SYNC_STATE_FORMAT.to_string(&state)?)
+            .with_context(|| format!("Could not write {}", state_path.display()))
+    }
+
+    fn sync_state(&self) -> Result<BTreeMap<String, usize>> {
+        let state_path = sync_state_path_for(&self.path);
+        match fs::read_to_string(&state_path) {
+            Ok(text) => SYNC_STATE_FORMAT.parse(&text, &state_path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The plays on the lines after the first `start`, in the order they
+    /// were added, and how many lines there are now. Plays added late, e.g.
+    /// backfilled ones older than the newest, are still after `start`. A line
+    /// still being written is left for the next time, and a history that got
+    /// shorter than `start`, e.g. replaced by another file, is read whole.
+    pub fn plays_after(&self, start: usize) -> Result<(Vec<PlayHistoryEntry>, usize)> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+            Err(e) => return Err(e.into()),
+        };
+        let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let lines: Vec<&[u8]> = data[..complete].split_inclusive(|&b| b == b'\n').collect();
+        let start = if start > lines.len() { 0 } else { start };
+        let plays = lines[start..]
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| !line.trim_ascii().is_empty())
+            .filter_map(|(n, line)| match serde_json::from_slice(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    let n = start + n + 1;
+                    warn!("Skipping line {n} of {}: {e}", self.path.display());
+                    None
+                }
+            })
+            .collect();
+        Ok((plays, lines.len()))
+    }
+
+    /// Adds the plays another machine synced, the ones already here are
+    /// told apart like by [HistoryStore::append_new]. A live play replaces
+    /// the backfilled one of the same play, it knows more, e.g. the device.
+    /// It is appended like a new one, so the history is never rewritten
+    /// under the daemon appending to it.
+    pub fn sync_in(&self, mut plays: Vec<PlayHistoryEntry>) -> Result<SyncedPlays> {
+        let mut known = self.load()?;
+        let mut seen: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, entry) in known.iter().enumerate() {
+            seen.entry(entry.track_id.clone()).or_default().push(i);
+        }
+        plays.sort_by_key(|play| play.played_at);
+        let mut synced = SyncedPlays::default();
+        for mut play in plays {
+            let length = Duration::from_millis(play.duration_ms as u64);
+            let same = seen
+                .entry(play.track_id.clone())
+                .or_default()
+                .iter()
+                .copied()
+                .find(|&i| same_play(known[i].played_at, play.played_at, length));
+            match same {
+                Some(i) => {
+                    if known[i].source == PlaySource::Backfill && play.source.is_live() {
+                        play.replaces = Some(known[i].played_at);
+                        self.append(&play)?;
+                        known[i] = play;
+                        synced.replaced += 1;
+                    }
+                }
+                None => {
+                    play.replaces = None;
+                    self.append(&play)?;
+                    seen.entry(play.track_id.clone())
+                        .or_default()
+                        .push(known.len());
+                    known.push(play);
+                    synced.added += 1;
+                }
+            }
+        }
+        Ok(synced)
+    }
+
+    /// Seeds the history from Spotify's account data export, a streaming
+    /// history file or the directory they were unpacked into. Both the simple
+    /// `StreamingHistory*.json` and the extended `endsong_*.json` schemas are
+    /// read, episodes are skipped. Plays already in the history, or in
+    /// another file of the export, are left out. Returns how many were added.
+    pub fn import_extended_history(&self, path: &Path) -> Result<usize> {
+        let mut known = KnownPlays::new(&self.load()?);
+        let mut imported = Vec::new();
+        for file in streaming_history_files(path)? {
+            let data = fs::read_to_string(&file)
+                .with_context(|| format!("Could not read {}", file.display()))?;
+            let entries = parse_streaming_history(&data)
+                .with_context(|| format!("{} is not a streaming history", file.display()))?;
+            for entry in entries {
+                if !known.contains(&entry) {
+                    known.insert(&entry);
+                    imported.push(entry);
+                }
+            }
+        }
+
+        imported.sort_by_key(|entry| entry.played_at);
+        for entry in &imported {
+            self.append(entry)?;
+        }
+        Ok(imported.len())
+    }
+
+    /// Logs the availability of `track` when it changed since it was last
+    /// seen. Tracks are taken to be playable until seen otherwise. Returns the
+    /// change, None when there was none. The log is only read the first
+    /// time, changes logged since by another process aren't seen.
+    pub fn record_availability(
+        &self,
+        track: &Track,
+        seen_at: SystemTime,
+    ) -> Result<Option<AvailabilityChange>> {
+        let Some(change) = AvailabilityChange::of_track(track, seen_at) else {
+            return Ok(None);
+        };
+        let mut availability = self.availability.lock().unwrap();
+        let last = match availability.as_mut() {
+            Some(last) => last,
+            None => availability.insert(
+                self.availability_changes()?
+                    .into_iter()
+                    .map(|change| (change.track_id.clone(), change))
+                    .collect(),
+            ),
+        };
+        let changed = match last.get(&change.track_id) {
+            Some(last) => (last.playable, &last.reason) != (change.playable, &change.reason),
+            None => !change.playable,
+        };
+        if !changed {
+            return Ok(None);
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(availability_path_for(&self.path))?;
+        writeln!(file, "{}", serde_json::to_string(&change)?)?;
+        last.insert(change.track_id.clone(), change.clone());
+        Ok(Some(change))
+    }
+
+    /// Every logged availability change, oldest first.
+    pub fn availability_changes(&self) -> Result<Vec<AvailabilityChange>> {
+        let path = availability_path_for(&self.path);
+        if !fs::exists(&path)? {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&path)?;
+        let changes = data
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(change) => Some(change),
+                Err(e) => {
+                    warn!("Skipping a line of {}: {e}", path.display());
+                    None
+                }
+            })
+            .collect();
+        Ok(changes)
+    }
+
+    /// Totals and the most played artists and tracks of the plays since
+    /// `since`, or of every play.
+    pub fn listening_stats(&self, since: Option<SystemTime>) -> Result<ListeningStats> {
+        let mut entries = self.load()?;
+        if let Some(since) = since {
+            entries.retain(|entry| entry.played_at >= since);
+        }
+        Ok(ListeningStats::from_entries(&entries))
+    }
+
+    /// Loads every entry, oldest first. A missing file is an empty history,
+    /// lines that don't parse are skipped with a warning. A play replacing a
+    /// backfilled one takes its place.
+    pub fn load(&self) -> Result<Vec<PlayHistoryEntry>> {
+        if !fs::exists(&self.path)? {
+            return Ok(Vec::new());
+        }
+
+        let data = fs::read_to_string(&self.path)?;
+        let lines = data
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(n, line)| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    warn!("Skipping line {} of {}: {e}", n + 1, self.path.display());
+                    None
+                }
+            })
+            .collect();
+        Ok(resolve_replacements(lines))
+    }
+}
+
+/// Puts every play with [PlayHistoryEntry::replaces] in the place of the
+/// play it replaces. One whose play isn't there is kept where it is.
+fn resolve_replacements(lines: Vec<PlayHistoryEntry>) -> Vec<PlayHistoryEntry> {
+    let mut entries: Vec<PlayHistoryEntry> = Vec::with_capacity(lines.len());
+    let mut at: HashMap<(String, SystemTime), usize> = HashMap::new();
+    for mut entry in lines {
+        let replaced = entry
+            .replaces
+            .take()
+            .and_then(|played_at| at.get(&(entry.track_id.clone(), played_at)).copied());
+        match replaced {
+            Some(i) => entries[i] = entry,
+            None => {
+                at.insert((entry.track_id.clone(), entry.played_at), entries.len());
+                entries.push(entry);
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::sample_track;
+
+    #[test]
+    fn test_append_and_load() {
+        let path = std::env::temp_dir().join(format!("history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = HistoryStore::new(&path);
+        assert!(store.load().unwrap().is_empty());
+
+        let entry =
+            PlayHistoryEntry::from_track(&sample_track(), SystemTime::now(), Confidence::Low);
+        store.append(&entry).unwrap();
+        store.append(&entry).unwrap();
+        fs::write(&path, fs::read_to_string(&path).unwrap() + "not json\n").unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].track_name, "The Divine Zero");
+        assert_eq!(loaded[0].confidence, Confidence::Low);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn sample_episode() -> Episode {
+        let data = std::fs::read_to_string("sample_data/saved_episodes.json").unwrap();
+        let page: crate::spotify_data::Page<crate::spotify_data::SavedEpisode> =
+            serde_json::from_str(&data).unwrap();
+        page.items[0].episode.clone()
+    }
+
+    #[test]
+    fn test_episode_entries() {
+        let episode = sample_episode();
+        // Further along than the resume point from another device
+        let entry = PlayHistoryEntry::from_episode(
+            &episode,
+            Some(2_000_000),
+            SystemTime::now(),
+            Confidence::High,
+        );
+        assert!(entry.is_episode());
+        assert_eq!(entry.album, "Listening Room");
+        assert_eq!(entry.listened(), Duration::from_millis(2_000_000));
+        let completion = entry.completion().unwrap();
+        assert!((completion - 2_000_000.0 / 2_685_023.0).abs() < 1e-9);
+
+        // Only the resume point knows how far the user got
+        let entry =
+            PlayHistoryEntry::from_episode(&episode, None, SystemTime::now(), Confidence::Low);
+        assert_eq!(entry.progress_ms, Some(1_342_511));
+
+        // Written and read back, tracks don't get the episode fields
+        let line = serde_json::to_string(&entry).unwrap();
+        let read: PlayHistoryEntry = serde_json::from_str(&line).unwrap();
+        assert_eq!(read.show, entry.show);
+        let track =
+            PlayHistoryEntry::from_track(&sample_track(), SystemTime::now(), Confidence::High);
+        let line = serde_json::to_string(&track).unwrap();
+        assert!(!line.contains("show") && !line.contains("progress_ms"));
+        assert_eq!(track.completion(), None);
+        assert_eq!(
+            track.listened(),
+            Duration::from_millis(track.duration_ms as u64)
+        );
+    }
+
+    #[test]
+    fn test_import_extended_history() {
+        let path = std::env::temp_dir().join(format!("import-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = HistoryStore::new(&path);
+
+        // The daemon saw the first play a few seconds in
+        let recorded = PlayHistoryEntry::from_track(
+            &sample_track(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_727_116_685),
+            Confidence::High,
+        );
+        store.append(&recorded).unwrap();
+
+        let extended = Path::new("sample_data/endsong_0.json");
+        assert_eq!(store.import_extended_history(extended).unwrap(), 1);
+        // Both schemas at once, only the plays the extended file didn't have
+        assert_eq!(
+            store
+                .import_extended_history(Path::new("sample_data"))
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store
+                .import_extended_history(Path::new("sample_data"))
+                .unwrap(),
+            0
+        );
+
+        let names: Vec<String> = store
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.track_name)
+            .collect();
+        assert_eq!(
+            names,
+            ["The Divine Zero", "Blinding Lights", "Dive In", "Yellow"]
+        );
+        assert!(store
+            .import_extended_history(Path::new("sample_data/me.json"))
+            .is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append_new_skips_known_plays() {
+        let path = std::env::temp_dir().join(format!("history-new-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = HistoryStore::new(&path);
+        let at = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let play = |secs| PlayHistoryEntry::from_track(&sample_track(), at(secs), Confidence::High);
+
+        store.append(&play(100)).unwrap();
+        store.append(&play(400)).unwrap();
+        let known = store.load().unwrap();
+        // Newest first like Spotify sends them, overlapping what is stored
+        let fetched = vec![play(1000), play(400), play(700), play(700), play(100)];
+        assert_eq!(store.append_new(&known, fetched).unwrap(), 2);
+
+        let played: Vec<SystemTime> = store.load().unwrap().iter().map(|e| e.played_at).collect();
+        assert_eq!(played, [at(100), at(400), at(700), at(1000)]);
+        let known = store.load().unwrap();
+        assert_eq!(store.append_new(&known, vec![play(1000)]).unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_append_new_knows_a_play_by_its_start_or_finish() {
+        let path =
+            std::env::temp_dir().join(format!("history-finish-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = HistoryStore::new(&path);
+        let track = sample_track();
+        let started = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let length = Duration::from_millis(track.duration_ms as u64);
+        let play = |at| PlayHistoryEntry::from_track(&track, at, Confidence::High);
+
+        // Recorded by the daemon when it started
+        store.append(&play(started)).unwrap();
+        let known = store.load().unwrap();
+        // Reported by recently played when it finished, then played again
+        let fetched = vec![play(started + length * 2), play(started + length)];
+        assert_eq!(store.append_new(&known, fetched).unwrap(), 1);
+
+        let played: Vec<SystemTime> = store.load().unwrap().iter().map(|e| e.played_at).collect();
+        assert_eq!(played, [started, started + length * 2]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_from_another_history() {
+        let dir = std::env::temp_dir().join(format!("history-merge-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let here = HistoryStore::new(dir.join("here.jsonl"));
+        let there = HistoryStore::new(dir.join("there.jsonl"));
+        let at = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let play = |secs| PlayHistoryEntry::from_track(&sample_track(), at(secs), Confidence::High);
+
+        here.append(&play(100)).unwrap();
+        there.append(&play(100)).unwrap();
+        there.append(&play(400)).unwrap();
+        assert_eq!(here.merge_from(there.path()).unwrap(), 1);
+        assert_eq!(here.merge_from(there.path()).unwrap(), 0);
+
+        // Only what the other machine played since is looked at
+        there.append(&play(700)).unwrap();
+        fs::write(here.path(), "").unwrap();
+        assert_eq!(here.merge_from(there.path()).unwrap(), 1);
+        let played: Vec<SystemTime> = here.load().unwrap().iter().map(|e| e.played_at).collect();
+        assert_eq!(played, [at(700)]);
+
+        assert!(here.merge_from(&dir.join("missing.jsonl")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_in_while_the_daemon_appends() {
+        let dir = std::env::temp_dir().join(format!("history-sync-in-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.jsonl");
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let play = move |secs, source| {
+            let mut play =
+                PlayHistoryEntry::from_track(&sample_track(), at(secs), Confidence::High);
+            play.source = source;
+            play
+        };
+        let store = HistoryStore::new(&path);
+        for i in 0..50 {
+            store.append(&play(i * 1000, PlaySource::Backfill)).unwrap();
+        }
+
+        let daemon = {
+            let store = HistoryStore::new(&path);
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    store
+                        .append(&play(100_000 + i * 1000, PlaySource::Live))
+                        .unwrap();
+                }
+            })
+        };
+        let live = (0..50)
+            .map(|i| play(i * 1000 + 30, PlaySource::Live))
+            .collect();
+        let synced = store.sync_in(live).unwrap();
+        daemon.join().unwrap();
+
+        assert_eq!(synced.replaced, 50);
+        let plays = store.load().unwrap();
+        assert_eq!(plays.len(), 250);
+        assert!(plays.iter().all(|play| play.source.is_live()));
+        // The replacements took the places of the backfilled plays
+        assert_eq!(plays[1].played_at, at(1030));
+        assert!(plays.iter().all(|play| play.replaces.is_none()));
+        // Nothing was rewritten, the replacements were appended
+        assert_eq!(store.plays_after(0).unwrap().1, 300);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_listening_stats_since() {
+        let path = std::env::temp_dir().join(format!("history-stats-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let store = HistoryStore::new(&path);
+        let at = |secs| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        for secs in [100, 200, 300] {
+            let entry = PlayHistoryEntry::from_track(&sample_track(), at(secs), Confidence::High);
+            store.append(&entry).unwrap();
+        }
+
+        let all = store.listening_stats(None).unwrap();
+        assert_eq!(all.plays, 3);
+        assert_eq!(all.top_tracks[0].plays, 3);
+        let recent = store.listening_stats(Some(at(200))).unwrap();
+        assert_eq!(recent.plays, 2);
+        assert_eq!(recent.listened, all.listened * 2 / 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn restricted_track() -> Track {
+        let data = fs::read_to_string("sample_data/restricted_track.json").unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+
+    #[test]
+    fn test_availability_changes() {
+        let path = std::env::temp_dir().join(format!("availability-{}.jsonl", std::process::id()));
+        let store = HistoryStore::new(&path);
+        let _ = fs::remove_file(availability_path_for(&path));
+        let at = |secs: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(1_727_000_000 + secs);
+
+        // Nothing known about its availability
+        let track = sample_track();
+        assert_eq!(store.record_availability(&track, at(0)).unwrap(), None);
+        let mut playable = restricted_track();
+        playable.is_playable = Some(true);
+        playable.restrictions = None;
+        assert_eq!(store.record_availability(&playable, at(10)).unwrap(), None);
+
+        let restricted = restricted_track();
+        let change = store
+            .record_availability(&restricted, at(20))
+            .unwrap()
+            .unwrap();
+        assert!(!change.playable);
+        assert_eq!(change.reason.as_deref(), Some("market"));
+        assert_eq!(change.seen_at, at(20));
+        // Seen again, still restricted
+        assert_eq!(
+            store.record_availability(&restricted, at(30)).unwrap(),
+            None
+        );
+        let change = store
+            .record_availability(&playable, at(40))
+            .unwrap()
+            .unwrap();
+        assert!(change.playable && change.reason.is_none());
+
+        let changes = store.availability_changes().unwrap();
+        let seen: Vec<(bool, SystemTime)> =
+            changes.iter().map(|c| (c.playable, c.seen_at)).collect();
+        assert_eq!(seen, [(false, at(20)), (true, at(40))]);
+        assert_eq!(changes[0].track_name, restricted.name);
+        // The play history is left alone
+        assert!(store.load().unwrap().is_empty());
+        fs::remove_file(availability_path_for(&path)).unwrap();
+    }
+}
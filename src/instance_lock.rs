@@ -0,0 +1,213 @@
+use crate::error::AlreadyRunning;
+
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// How long `daemon --takeover` waits for the old daemon to let go.
+pub const DEFAULT_TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Where the daemon recording into `history` takes its lock, next to it.
+pub fn lock_path_for(history: &Path) -> PathBuf {
+    history.with_extension("lock")
+}
+
+/// Keeps a second daemon from recording into the same history. It's an
+/// advisory lock the OS drops with the process, even one that crashed, so
+/// a lock is never stuck. The file holds the holder's pid to name it.
+pub struct InstanceLock {
+    path: PathBuf,
+    file: File,
+}
+
+impl InstanceLock {
+    /// Takes the lock at `path`, an [AlreadyRunning] error naming the holder
+    /// when another process has it. A pid left by a holder that died is
+    /// replaced.
+    pub fn acquire(path: &Path) -> Result<InstanceLock> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Could not create {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+
+        let mut holder = String::new();
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                file.read_to_string(&mut holder)?;
+                return Err(AlreadyRunning {
+                    pid: holder.trim().parse().ok(),
+                    lock_file: path.to_path_buf(),
+                }
+                .into());
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Could not lock {}", path.display()))
+            }
+        }
+
+        file.read_to_string(&mut holder)?;
+        if let Ok(pid) = holder.trim().parse::<u32>() {
+            warn!(
+                "Reclaiming {} from pid {pid}, it exited without letting go",
+                path.display()
+            );
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        Ok(InstanceLock {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Like [InstanceLock::acquire], but waits up to `timeout` for the
+    /// holder to let go, e.g. after asking it to shut down.
+    pub fn acquire_within(path: &Path, timeout: Duration) -> Result<InstanceLock> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match InstanceLock::acquire(path) {
+                Err(e) if e.is::<AlreadyRunning>() && Instant::now() < deadline => {
+                    thread::sleep(RETRY_DELAY)
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // The file stays, removing it would let two daemons lock different ones
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    /// Set for the child process holding the lock in [hold_lock].
+    const CHILD_LOCK_ENV: &str = "INSTANCE_LOCK_CHILD";
+
+    fn temp_lock(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lock-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("history.lock")
+    }
+
+    /// Runs [hold_lock] in a second process, returning once it holds the lock.
+    /// It lets go when its stdin is closed.
+    fn spawn_holder(path: &Path) -> Child {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["instance_lock::tests::hold_lock", "--exact", "--ignored"])
+            .args(["--nocapture", "--test-threads=1"])
+            .env(CHILD_LOCK_ENV, path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+        // After the `test ... ` libtest prints for it
+        let locked = lines.by_ref().any(|line| line.unwrap().ends_with("locked"));
+        assert!(locked, "the holder exited without locking");
+        // Read the rest, the holder fails writing to a closed pipe
+        std::thread::spawn(move || lines.for_each(drop));
+        child
+    }
+
+    #[test]
+    #[ignore = "run by the other tests as a second process"]
+    fn hold_lock() {
+        let Some(path) = std::env::var_os(CHILD_LOCK_ENV) else {
+            return;
+        };
+        let _lock = InstanceLock::acquire(Path::new(&path)).unwrap();
+        println!("locked");
+        let _ = std::io::stdin().read_to_end(&mut Vec::new());
+    }
+
+    #[test]
+    fn test_second_process_is_refused() {
+        let path = temp_lock("refused");
+        let mut holder = spawn_holder(&path);
+
+        let err = InstanceLock::acquire(&path).err().unwrap();
+        let running = err.downcast_ref::<AlreadyRunning>().unwrap();
+        assert_eq!(running.pid, Some(holder.id()));
+        assert_eq!(running.lock_file, path);
+        assert!(err.to_string().contains(&holder.id().to_string()));
+
+        // Told to let go, the holder exits and the lock can be taken over
+        drop(holder.stdin.take());
+        let lock = InstanceLock::acquire_within(&path, Duration::from_secs(10)).unwrap();
+        assert!(holder.wait().unwrap().success());
+        let pid = fs::read_to_string(lock.path()).unwrap();
+        assert_eq!(pid, std::process::id().to_string());
+
+        // Released on drop, the file stays
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        drop(InstanceLock::acquire(&path).unwrap());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_lock_of_killed_process_is_reclaimed() {
+        let path = temp_lock("killed");
+        let mut holder = spawn_holder(&path);
+        holder.kill().unwrap();
+        holder.wait().unwrap();
+
+        // The pid of the dead holder is still in the file
+        assert_eq!(fs::read_to_string(&path).unwrap(), holder.id().to_string());
+        let lock = InstanceLock::acquire(&path).unwrap();
+        let pid = fs::read_to_string(lock.path()).unwrap();
+        assert_eq!(pid, std::process::id().to_string());
+        drop(lock);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_waiting_gives_up() {
+        let path = temp_lock("timeout");
+        let lock = InstanceLock::acquire(&path).unwrap();
+        let err = InstanceLock::acquire_within(&path, Duration::from_millis(250))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<AlreadyRunning>().unwrap().pid,
+            Some(std::process::id())
+        );
+        drop(lock);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_lock_path_for() {
+        assert_eq!(
+            lock_path_for(Path::new("data/history.jsonl")),
+            Path::new("data/history.lock")
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use crate::error::NewerStateFile;
+use crate::history::PlayHistoryEntry;
+use crate::state_file::{unchanged, StateFormat};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// How often the daemon saves the play in progress by default.
+pub const DEFAULT_JOURNAL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Every line is one record in the envelope, v2 wrapped v1 in it.
+const JOURNAL_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+
+/// The journal next to a history file, e.g. `history.journal` for `history.jsonl`.
+pub fn journal_path_for(history: &Path) -> PathBuf {
+    history.with_extension("journal")
+}
+
+/// One saved state of the play detector.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalRecord {
+    /// Grows by one with every record, the highest one is the latest state
+    pub seq: u64,
+    pub saved_at: SystemTime,
+    /// The play in progress, None when nothing was playing
+    pub pending: Option<PlayHistoryEntry>,
+}
+
+/// Append-only JSON lines file of [JournalRecord], so the play in progress
+/// survives a crash or a power loss. Records are written without an fsync
+/// unless the journal is `durable`, a lost record costs one interval.
+pub struct PlayJournal {
+    path: PathBuf,
+    durable: bool,
+    next_seq: u64,
+    // The file ends in a partial line, the next write starts it over
+    torn: bool,
+}
+
+impl PlayJournal {
+    pub fn new(path: impl Into<PathBuf>) -> PlayJournal {
+        PlayJournal {
+            path: path.into(),
+            durable: false,
+            next_seq: 0,
+            torn: false,
+        }
+    }
+
+    /// Syncs every record to disk before carrying on.
+    pub fn durable(mut self, durable: bool) -> PlayJournal {
+        self.durable = durable;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The latest state in the journal, None when there is none. Lines a
+    /// crash left half written don't parse and are skipped, a line of a
+    /// newer format fails the replay. A last line without its newline was
+    /// cut short, it is cut off the file so the next record starts clean.
+    pub fn replay(&mut self) -> Result<Option<JournalRecord>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // Bytes, the torn line may end halfway through a UTF-8 character
+        let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+
+        let mut latest: Option<JournalRecord> = None;
+        let mut skipped = 0;
+        for line in data[..complete].split(|&b| b == b'\n') {
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_slice(line)
+                .map_err(anyhow::Error::from)
+                .and_then(|value| JOURNAL_FORMAT.parse_value::<JournalRecord>(value, &self.path));
+            match record {
+                Ok(record) if latest.as_ref().is_none_or(|l| record.seq > l.seq) => {
+                    latest = Some(record)
+                }
+                Ok(_) => {}
+                Err(e) if e.is::<NewerStateFile>() => return Err(e),
+                Err(_) => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            warn!(
+                "Skipped {skipped} corrupt lines of {}, likely from a crash",
+                self.path.display()
+            );
+        }
+        self.torn = false;
+        if complete < data.len() {
+            warn!(
+                "Cut the half written last line off {}, likely from a crash",
+                self.path.display()
+            );
+            let cut = OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .and_then(|file| file.set_len(complete as u64));
+            if let Err(e) = cut {
+                warn!("Could not cut {}: {e}", self.path.display());
+                self.torn = true;
+            }
+        }
+        if let Some(record) = &latest {
+            self.next_seq = self.next_seq.max(record.seq + 1);
+        }
+        Ok(latest)
+    }
+
+    /// Saves the play in progress at `now`.
+    pub fn append(&mut self, pending: Option<&PlayHistoryEntry>, now: SystemTime) -> Result<()> {
+        let record = JournalRecord {
+            seq: self.next_seq,
+            saved_at: now,
+            pending: pending.cloned(),
+        };
+        let line = JOURNAL_FORMAT.to_string(&record)? + "\n";
+        let mut file = self.open(self.torn)?;
+        // Assume the worst until the line is fully written
+        self.torn = true;
+        file.write_all(line.as_bytes())?;
+        if self.durable {
+            file.sync_data()?;
+        }
+        self.torn = false;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// Empties the journal, once its latest state is safe elsewhere.
+    /// Sequence numbers keep growing.
+    pub fn truncate(&mut self) -> Result<()> {
+        let file = self.open(true)?;
+        if self.durable {
+            file.sync_data()?;
+        }
+        self.torn = false;
+        Ok(())
+    }
+
+    fn open(&self, truncate: bool) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.create(true);
+        match truncate {
+            true => options.write(true).truncate(true),
+            false => options.append(true),
+        };
+        options.open(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Confidence;
+    use crate::testutil::sample_track;
+
+    fn journal(name: &str) -> PlayJournal {
+        let path = std::env::temp_dir().join(format!("{name}-{}.journal", std::process::id()));
+        let _ = fs::remove_file(&path);
+        PlayJournal::new(path)
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_727_000_000 + secs)
+    }
+
+    #[test]
+    fn test_replay_gives_the_latest_state() {
+        let mut journal = journal("journal-latest").durable(true);
+        assert!(journal.replay().unwrap().is_none());
+
+        let mut play = PlayHistoryEntry::from_track(&sample_track(), at(0), Confidence::Low);
+        journal.append(Some(&play), at(30)).unwrap();
+        play.confidence = Confidence::High;
+        play.tags.push("focus".to_string());
+        journal.append(Some(&play), at(60)).unwrap();
+
+        let latest = PlayJournal::new(journal.path()).replay().unwrap().unwrap();
+        assert_eq!(latest.seq, 1);
+        assert_eq!(latest.saved_at, at(60));
+        let pending = latest.pending.unwrap();
+        assert_eq!(pending.confidence, Confidence::High);
+        assert_eq!(pending.tags, ["focus"]);
+
+        // Sequence numbers keep growing over a truncation
+        journal.truncate().unwrap();
+        assert!(journal.replay().unwrap().is_none());
+        journal.append(None, at(90)).unwrap();
+        let latest = journal.replay().unwrap().unwrap();
+        assert_eq!(latest.seq, 2);
+        assert!(latest.pending.is_none());
+        fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_from_truncated_journals() {
+        let mut journal = journal("journal-torn");
+        let play = PlayHistoryEntry::from_track(&sample_track(), at(0), Confidence::High);
+        for secs in [30, 60, 90] {
+            journal.append(Some(&play), at(secs)).unwrap();
+        }
+        let data = fs::read_to_string(journal.path()).unwrap();
+
+        // Power lost halfway through the last record
+        let torn = &data[..data.len() - 40];
+        fs::write(journal.path(), torn).unwrap();
+        let mut recovered = PlayJournal::new(journal.path());
+        let latest = recovered.replay().unwrap().unwrap();
+        assert_eq!((latest.seq, latest.saved_at), (1, at(60)));
+
+        // The torn line was cut off, writing on doesn't glue onto it
+        assert_eq!(
+            fs::read_to_string(journal.path()).unwrap(),
+            data.split_inclusive('\n').take(2).collect::<String>()
+        );
+        recovered.append(None, at(120)).unwrap();
+        let latest = PlayJournal::new(journal.path()).replay().unwrap().unwrap();
+        assert_eq!((latest.seq, latest.saved_at), (2, at(120)));
+
+        // Only garbage and an empty line left
+        fs::write(journal.path(), "\n{\"seq\": 4, \"saved_").unwrap();
+        assert!(PlayJournal::new(journal.path()).replay().unwrap().is_none());
+
+        // A corrupt line in the middle doesn't hide the records after it
+        let lines: Vec<&str> = data.lines().collect();
+        fs::write(
+            journal.path(),
+            format!("{}\n{{garbage\n{}\n", lines[0], lines[2]),
+        )
+        .unwrap();
+        let latest = PlayJournal::new(journal.path()).replay().unwrap().unwrap();
+        assert_eq!(latest.seq, 2);
+        fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn test_recovery_from_a_torn_utf8_character() {
+        let mut journal = journal("journal-utf8");
+        let mut play = PlayHistoryEntry::from_track(&sample_track(), at(0), Confidence::High);
+        play.track_name = "Sjö".to_string();
+        journal.append(Some(&play), at(30)).unwrap();
+        journal.append(Some(&play), at(60)).unwrap();
+        let data = fs::read(journal.path()).unwrap();
+
+        // Power lost between the two bytes of the ö in the last record
+        let first_line = data.iter().position(|&b| b == b'\n').unwrap() + 1;
+        let o = first_line
+            + data[first_line..]
+                .windows(2)
+                .position(|w| w == "ö".as_bytes())
+                .unwrap();
+        fs::write(journal.path(), &data[..o + 1]).unwrap();
+        let mut recovered = PlayJournal::new(journal.path());
+        let latest = recovered.replay().unwrap().unwrap();
+        assert_eq!((latest.seq, latest.saved_at), (0, at(30)));
+        assert_eq!(fs::read(journal.path()).unwrap(), &data[..first_line]);
+
+        // And the next start reads it again
+        recovered.append(None, at(90)).unwrap();
+        let latest = PlayJournal::new(journal.path()).replay().unwrap().unwrap();
+        assert_eq!((latest.seq, latest.saved_at), (1, at(90)));
+        fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn test_journal_upgrades() {
+        let journal = journal("journal-upgrade");
+        let v1 = [
+            r#"{"seq": 3, "saved_at": {"secs_since_epoch": 1727000030, "nanos_since_epoch": 0}, "pending": null}"#,
+            r#"{"seq": 4, "saved_at": {"secs_since_epoch": 1727000060, "nanos_since_epoch": 0}, "pending": null}"#,
+        ];
+        fs::write(journal.path(), v1.join("\n") + "\n").unwrap();
+        let mut upgraded = PlayJournal::new(journal.path());
+        let latest = upgraded.replay().unwrap().unwrap();
+        assert_eq!((latest.seq, latest.saved_at), (4, at(60)));
+
+        // Appends in the current format, old and new lines read together
+        upgraded.append(None, at(90)).unwrap();
+        let data = fs::read_to_string(journal.path()).unwrap();
+        assert!(data.lines().last().unwrap().starts_with(r#"{"v":2,"#));
+        let latest = PlayJournal::new(journal.path()).replay().unwrap().unwrap();
+        assert_eq!((latest.seq, latest.saved_at), (5, at(90)));
+
+        // A newer line is refused, not skipped as corrupt
+        let newer = r#"{"v": 3, "data": {"seq": 6}}"#;
+        fs::write(journal.path(), format!("{data}{newer}\n")).unwrap();
+        let err = PlayJournal::new(journal.path()).replay().unwrap_err();
+        assert!(err.is::<NewerStateFile>());
+        fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn test_journal_path_for() {
+        assert_eq!(
+            journal_path_for(Path::new("data/history.jsonl")),
+            Path::new("data/history.journal")
+        );
+    }
+}
@@ -1,4 +1,50 @@
+pub mod analysis_cache;
+pub mod artist_cache;
+#[cfg(feature = "aws")]
+pub mod aws_secrets;
+pub mod browser;
+pub mod capture;
+#[cfg(feature = "charts")]
+pub mod charts;
+pub mod control;
+pub mod control_socket;
+pub mod device_aliases;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod error;
+pub mod history;
+pub mod instance_lock;
+pub mod journal;
+pub mod library_import;
+pub mod library_snapshot;
 pub mod local_store;
+pub mod locale;
+pub mod log_throttle;
+pub mod lyrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod notifications;
+pub mod now_cache;
+pub mod picker;
 pub mod pkce;
+pub mod rate_limit;
+pub mod redact;
+pub mod report;
+pub mod search;
+pub mod secrets;
+pub mod share;
+pub mod shared_auth;
 pub mod spotify_api;
 pub mod spotify_data;
+pub mod state_file;
+pub mod stats;
+pub mod streaming_history;
+#[cfg(feature = "sync")]
+pub mod sync;
+pub mod table;
+pub mod template;
+#[cfg(test)]
+pub(crate) mod testutil;
+pub mod tracker;
+pub mod watch_view;
+pub mod watcher;
@@ -0,0 +1,208 @@
+use crate::state_file::{unchanged, StateFormat};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// v2 wrapped v1 in the state file envelope.
+const IMPORT_STATE_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+
+/// Most tracks Spotify saves to the library in one call
+pub const IMPORT_BATCH_SIZE: usize = 50;
+
+/// Where an import of `playlists` keeps its progress by default: the temp
+/// dir, named after the playlists so a rerun with the same ones finds it.
+pub fn import_state_path_for(playlists: &[String]) -> PathBuf {
+    let digest = Sha256::digest(sorted(playlists).join(",").as_bytes());
+    let name: String = digest.iter().take(8).map(|b| format!("{b:02x}")).collect();
+    std::env::temp_dir().join(format!("spotify-rs-library-import-{name}.json"))
+}
+
+fn sorted(playlists: &[String]) -> Vec<String> {
+    let mut playlists = playlists.to_vec();
+    playlists.sort();
+    playlists.dedup();
+    playlists
+}
+
+/// The ids of every playlist's tracks in the order found, each only once.
+pub fn unique_track_ids(playlists: &[Vec<String>]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    playlists
+        .iter()
+        .flatten()
+        .filter(|id| seen.insert(id.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// What importing playlists into the library comes down to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportPlan {
+    pub playlists: Vec<String>,
+    /// Unique tracks in the playlists
+    pub found: usize,
+    pub already_saved: usize,
+    pub to_save: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ImportState {
+    playlists: Vec<String>,
+    total: usize,
+    remaining: Vec<String>,
+}
+
+/// An import in progress. The ids left to save are written to a file after
+/// every batch, so an interrupted run can continue where it stopped.
+pub struct LibraryImport {
+    path: PathBuf,
+    state: ImportState,
+}
+
+impl LibraryImport {
+    /// Starts saving the tracks of `plan`, keeping the progress at `path`.
+    pub fn start(path: impl Into<PathBuf>, plan: &ImportPlan) -> Result<LibraryImport> {
+        let import = LibraryImport {
+            path: path.into(),
+            state: ImportState {
+                playlists: sorted(&plan.playlists),
+                total: plan.to_save.len(),
+                remaining: plan.to_save.clone(),
+            },
+        };
+        import.save()?;
+        Ok(import)
+    }
+
+    /// The import of the same playlists an earlier run left at `path`,
+    /// None when there is none.
+    pub fn resume(path: impl Into<PathBuf>, playlists: &[String]) -> Result<Option<LibraryImport>> {
+        let path = path.into();
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let state: ImportState = IMPORT_STATE_FORMAT.parse(&data, &path)?;
+        if state.playlists != sorted(playlists) {
+            bail!(
+                "{} belongs to an import of other playlists: {}",
+                path.display(),
+                state.playlists.join(" ")
+            );
+        }
+        Ok(Some(LibraryImport { path, state }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn total(&self) -> usize {
+        self.state.total
+    }
+
+    pub fn saved(&self) -> usize {
+        self.state.total - self.state.remaining.len()
+    }
+
+    pub fn remaining(&self) -> &[String] {
+        &self.state.remaining
+    }
+
+    /// The ids to save next, empty once the import is done.
+    pub fn next_batch(&self) -> &[String] {
+        let end = self.state.remaining.len().min(IMPORT_BATCH_SIZE);
+        &self.state.remaining[..end]
+    }
+
+    /// Marks [LibraryImport::next_batch] as saved. The file goes away once
+    /// nothing is left.
+    pub fn batch_saved(&mut self) -> Result<()> {
+        let end = self.state.remaining.len().min(IMPORT_BATCH_SIZE);
+        self.state.remaining.drain(..end);
+        if self.state.remaining.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        fs::write(&self.path, IMPORT_STATE_FORMAT.to_string(&self.state)?)
+            .with_context(|| format!("Could not write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_unique_track_ids() {
+        let ids = unique_track_ids(&[strings(&["a", "b", "a"]), strings(&["c", "b", "d"])]);
+        assert_eq!(ids, ["a", "b", "c", "d"]);
+        assert!(unique_track_ids(&[]).is_empty());
+
+        let playlists = strings(&["pl2", "pl1"]);
+        assert_eq!(
+            import_state_path_for(&playlists),
+            import_state_path_for(&strings(&["pl1", "pl2", "pl1"]))
+        );
+        assert_ne!(
+            import_state_path_for(&playlists),
+            import_state_path_for(&strings(&["pl1"]))
+        );
+    }
+
+    #[test]
+    fn test_interrupted_import_resumes() {
+        let path = std::env::temp_dir().join(format!("import-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let playlists = strings(&["pl1", "pl2"]);
+        assert!(LibraryImport::resume(&path, &playlists).unwrap().is_none());
+
+        let plan = ImportPlan {
+            playlists: playlists.clone(),
+            found: 130,
+            already_saved: 10,
+            to_save: (0..120).map(|i| format!("t{i}")).collect(),
+        };
+        let mut import = LibraryImport::start(&path, &plan).unwrap();
+        assert_eq!(import.next_batch().len(), IMPORT_BATCH_SIZE);
+        import.batch_saved().unwrap();
+        // Interrupted here, by a build from before the state file versions
+        let state: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::write(&path, state["data"].to_string()).unwrap();
+        drop(import);
+
+        let reversed = strings(&["pl2", "pl1"]);
+        let mut import = LibraryImport::resume(&path, &reversed).unwrap().unwrap();
+        assert_eq!((import.saved(), import.total()), (50, 120));
+        assert_eq!(import.next_batch()[0], "t50");
+        assert!(LibraryImport::resume(&path, &strings(&["pl3"])).is_err());
+
+        import.batch_saved().unwrap();
+        assert_eq!(import.next_batch(), &plan.to_save[100..]);
+        import.batch_saved().unwrap();
+        assert!(import.next_batch().is_empty());
+        assert_eq!(import.saved(), 120);
+        // Done, there is nothing left to resume
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,791 @@
+use crate::spotify_data::{
+    Album, ArtistFull, Page, PlaylistItem, PlaylistSummary, SavedAlbum, SavedTrack, Track,
+};
+use crate::state_file::StateFormat;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_SNAPSHOT_FILE: &str = "library_snapshot.json";
+/// Items asked for in one page, the most Spotify returns of the lists
+pub const SNAPSHOT_PAGE_SIZE: u32 = 50;
+/// The version of the snapshot file, raised when its shape changes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const SNAPSHOT_STATE_FORMAT: StateFormat = StateFormat {
+    version: 1,
+    upgrades: &[],
+};
+
+/// One part of the library, fetched list by list.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotSection {
+    SavedTracks,
+    SavedAlbums,
+    FollowedArtists,
+    /// The user's playlists with the tracks of each
+    Playlists,
+}
+
+impl SnapshotSection {
+    pub const ALL: [SnapshotSection; 4] = [
+        SnapshotSection::SavedTracks,
+        SnapshotSection::SavedAlbums,
+        SnapshotSection::FollowedArtists,
+        SnapshotSection::Playlists,
+    ];
+
+    /// The name on the command line and in the manifest.
+    pub fn name(self) -> &'static str {
+        match self {
+            SnapshotSection::SavedTracks => "saved-tracks",
+            SnapshotSection::SavedAlbums => "saved-albums",
+            SnapshotSection::FollowedArtists => "followed-artists",
+            SnapshotSection::Playlists => "playlists",
+        }
+    }
+}
+
+impl fmt::Display for SnapshotSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for SnapshotSection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<SnapshotSection> {
+        match SnapshotSection::ALL
+            .iter()
+            .find(|section| section.name() == s)
+        {
+            Some(section) => Ok(*section),
+            None => bail!(
+                "expected saved-tracks, saved-albums, followed-artists or playlists, got {s:?}"
+            ),
+        }
+    }
+}
+
+/// What a snapshot has of an artist. Genres are only known of the followed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotArtist {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub genres: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotAlbum {
+    pub name: String,
+    pub artist_ids: Vec<String>,
+    pub release_date: String,
+    pub total_tracks: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotTrack {
+    pub name: String,
+    pub artist_ids: Vec<String>,
+    pub album_id: String,
+    pub duration_ms: u32,
+}
+
+/// Something saved to the library, and when.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SavedItem {
+    pub id: String,
+    pub added_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotPlaylist {
+    pub id: String,
+    pub name: String,
+    pub owner_id: String,
+    pub snapshot_id: String,
+    /// In playlist order, episodes and local files left out
+    pub track_ids: Vec<String>,
+}
+
+/// How one section of a snapshot went.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SectionManifest {
+    pub section: SnapshotSection,
+    /// Tracks, albums, artists or playlists in it
+    pub count: usize,
+    /// Time spent fetching it, over all runs it took
+    pub took: Duration,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotManifest {
+    pub version: u32,
+    pub started_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+    pub sections: Vec<SectionManifest>,
+}
+
+/// The user's library at one time. Tracks, albums and artists are each
+/// written once, keyed by id, and the lists refer to them by id, so two
+/// snapshots compare list by list.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LibrarySnapshot {
+    pub manifest: SnapshotManifest,
+    pub artists: BTreeMap<String, SnapshotArtist>,
+    pub albums: BTreeMap<String, SnapshotAlbum>,
+    pub tracks: BTreeMap<String, SnapshotTrack>,
+    /// Most recently saved first, like Spotify lists them
+    pub saved_tracks: Vec<SavedItem>,
+    pub saved_albums: Vec<SavedItem>,
+    pub followed_artists: Vec<String>,
+    pub playlists: Vec<SnapshotPlaylist>,
+}
+
+impl LibrarySnapshot {
+    /// A snapshot written by [SnapshotRun::finish].
+    pub fn load(path: &Path) -> Result<LibrarySnapshot> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let snapshot: LibrarySnapshot = serde_json::from_str(&data)
+            .with_context(|| format!("{} is not a library snapshot", path.display()))?;
+        if snapshot.manifest.version > SNAPSHOT_VERSION {
+            bail!(
+                "{} is a v{} snapshot, this build reads up to v{SNAPSHOT_VERSION}",
+                path.display(),
+                snapshot.manifest.version
+            );
+        }
+        Ok(snapshot)
+    }
+
+    fn add_artist(&mut self, id: &str, name: &str) {
+        self.artists
+            .entry(id.to_string())
+            .or_insert_with(|| SnapshotArtist {
+                name: name.to_string(),
+                genres: Vec::new(),
+            });
+    }
+}
+
+/// Where a section continues.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Cursor {
+    Offset(u32),
+    /// Followed artists are paged by the last id seen
+    After(Option<String>),
+    /// Every playlist was listed, the tracks of some are still to fetch
+    Listed,
+    Done,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SectionProgress {
+    pub section: SnapshotSection,
+    cursor: Cursor,
+    /// Items fetched so far
+    pub fetched: usize,
+    /// Items in the section, once Spotify told
+    pub total: Option<u32>,
+    pub took: Duration,
+}
+
+impl SectionProgress {
+    pub fn is_done(&self) -> bool {
+        self.cursor == Cursor::Done
+    }
+}
+
+/// The next request a snapshot needs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageRequest {
+    SavedTracks {
+        offset: u32,
+    },
+    SavedAlbums {
+        offset: u32,
+    },
+    FollowedArtists {
+        after: Option<String>,
+    },
+    Playlists {
+        offset: u32,
+    },
+    /// A page of the tracks of one playlist
+    PlaylistTracks {
+        playlist_id: String,
+        offset: u32,
+    },
+}
+
+/// What Spotify answered a [PageRequest] with.
+pub enum SnapshotPage {
+    SavedTracks(Page<SavedTrack>),
+    SavedAlbums(Page<SavedAlbum>),
+    /// The artists and the cursor of the next page
+    FollowedArtists(Vec<ArtistFull>, Option<String>),
+    Playlists(Page<PlaylistSummary>),
+    PlaylistTracks {
+        playlist_id: String,
+        page: Page<PlaylistItem>,
+    },
+}
+
+/// A playlist listed whose tracks weren't all fetched yet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct PendingPlaylist {
+    id: String,
+    /// Where its tracks continue
+    offset: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotState {
+    sections: Vec<SectionProgress>,
+    pending_playlists: Vec<PendingPlaylist>,
+    snapshot: LibrarySnapshot,
+}
+
+/// A snapshot in progress. What was fetched is written to a file after
+/// every page, so a run stopped by the rate limit or Ctrl-C continues
+/// where it was when run again, losing at most the page it was on.
+pub struct SnapshotRun {
+    path: PathBuf,
+    state: SnapshotState,
+}
+
+/// Where a snapshot to `out` keeps its progress by default.
+pub fn snapshot_state_path_for(out: &Path) -> PathBuf {
+    let mut name = out.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    out.with_file_name(name)
+}
+
+impl SnapshotRun {
+    /// Starts a snapshot of `sections`, in the order of
+    /// [SnapshotSection::ALL], keeping the progress at `path`.
+    pub fn start(path: impl Into<PathBuf>, sections: &[SnapshotSection]) -> Result<SnapshotRun> {
+        let sections = SnapshotSection::ALL
+            .iter()
+            .filter(|section| sections.contains(section))
+            .map(|section| SectionProgress {
+                section: *section,
+                cursor: match section {
+                    SnapshotSection::FollowedArtists => Cursor::After(None),
+                    _ => Cursor::Offset(0),
+                },
+                fetched: 0,
+                total: None,
+                took: Duration::ZERO,
+            })
+            .collect();
+        let run = SnapshotRun {
+            path: path.into(),
+            state: SnapshotState {
+                sections,
+                pending_playlists: Vec::new(),
+                snapshot: LibrarySnapshot {
+                    manifest: SnapshotManifest {
+                        version: SNAPSHOT_VERSION,
+                        started_at: SystemTime::now(),
+                        finished_at: None,
+                        sections: Vec::new(),
+                    },
+                    artists: BTreeMap::new(),
+                    albums: BTreeMap::new(),
+                    tracks: BTreeMap::new(),
+                    saved_tracks: Vec::new(),
+                    saved_albums: Vec::new(),
+                    followed_artists: Vec::new(),
+                    playlists: Vec::new(),
+                },
+            },
+        };
+        run.save()?;
+        Ok(run)
+    }
+
+    /// The snapshot of the same sections an earlier run left at `path`,
+    /// None when there is none.
+    pub fn resume(
+        path: impl Into<PathBuf>,
+        sections: &[SnapshotSection],
+    ) -> Result<Option<SnapshotRun>> {
+        let path = path.into();
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let state: SnapshotState = SNAPSHOT_STATE_FORMAT.parse(&data, &path)?;
+        for progress in &state.sections {
+            let valid = match (progress.section, &progress.cursor) {
+                (_, Cursor::Done) => true,
+                (SnapshotSection::FollowedArtists, Cursor::After(_)) => true,
+                (SnapshotSection::FollowedArtists, _) => false,
+                (_, Cursor::Offset(_)) => true,
+                (SnapshotSection::Playlists, Cursor::Listed) => !state.pending_playlists.is_empty(),
+                _ => false,
+            };
+            if !valid {
+                bail!(
+                    "{} is broken, {} can't continue at {:?}, remove it to start over",
+                    path.display(),
+                    progress.section,
+                    progress.cursor
+                );
+            }
+        }
+        let mut found: Vec<SnapshotSection> = state.sections.iter().map(|s| s.section).collect();
+        let mut wanted = sections.to_vec();
+        found.sort();
+        wanted.sort();
+        wanted.dedup();
+        if found != wanted {
+            let found: Vec<&str> = found.iter().map(|s| s.name()).collect();
+            bail!(
+                "{} belongs to a snapshot of other sections: {}",
+                path.display(),
+                found.join(" ")
+            );
+        }
+        Ok(Some(SnapshotRun { path, state }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn sections(&self) -> &[SectionProgress] {
+        &self.state.sections
+    }
+
+    /// The section fetched now, None once all are done.
+    pub fn current(&self) -> Option<&SectionProgress> {
+        self.state.sections.iter().find(|s| !s.is_done())
+    }
+
+    /// What to fetch next, None once every section is done.
+    pub fn next_request(&self) -> Option<PageRequest> {
+        let progress = self.current()?;
+        Some(match (&progress.section, &progress.cursor) {
+            (SnapshotSection::SavedTracks, Cursor::Offset(offset)) => {
+                PageRequest::SavedTracks { offset: *offset }
+            }
+            (SnapshotSection::SavedAlbums, Cursor::Offset(offset)) => {
+                PageRequest::SavedAlbums { offset: *offset }
+            }
+            (SnapshotSection::FollowedArtists, Cursor::After(after)) => {
+                PageRequest::FollowedArtists {
+                    after: after.clone(),
+                }
+            }
+            (SnapshotSection::Playlists, cursor) => match self.state.pending_playlists.first() {
+                Some(pending) => PageRequest::PlaylistTracks {
+                    playlist_id: pending.id.clone(),
+                    offset: pending.offset,
+                },
+                None => match cursor {
+                    Cursor::Offset(offset) => PageRequest::Playlists { offset: *offset },
+                    _ => unreachable!("playlists are done once none are pending"),
+                },
+            },
+            (section, cursor) => unreachable!("{section} is never at {cursor:?}"),
+        })
+    }
+
+    fn progress_mut(&mut self, section: SnapshotSection) -> Result<&mut SectionProgress> {
+        self.state
+            .sections
+            .iter_mut()
+            .find(|progress| progress.section == section)
+            .with_context(|| format!("The snapshot doesn't include {section}"))
+    }
+
+    /// Adds the answer to [SnapshotRun::next_request], `took` to get, and
+    /// writes the progress.
+    pub fn add_page(&mut self, page: SnapshotPage, took: Duration) -> Result<()> {
+        let snapshot = &mut self.state.snapshot;
+        let (section, fetched, total, cursor) = match page {
+            SnapshotPage::SavedTracks(page) => {
+                for saved in &page.items {
+                    add_track(snapshot, &saved.track);
+                    snapshot.saved_tracks.push(SavedItem {
+                        id: saved.track.id.clone(),
+                        added_at: saved.added_at.clone(),
+                    });
+                }
+                let cursor = offset_cursor(&page);
+                (
+                    SnapshotSection::SavedTracks,
+                    page.items.len(),
+                    Some(page.total),
+                    cursor,
+                )
+            }
+            SnapshotPage::SavedAlbums(page) => {
+                for saved in &page.items {
+                    add_album(snapshot, &saved.album);
+                    snapshot.saved_albums.push(SavedItem {
+                        id: saved.album.id.clone(),
+                        added_at: saved.added_at.clone(),
+                    });
+                }
+                let cursor = offset_cursor(&page);
+                (
+                    SnapshotSection::SavedAlbums,
+                    page.items.len(),
+                    Some(page.total),
+                    cursor,
+                )
+            }
+            SnapshotPage::FollowedArtists(artists, next) => {
+                for artist in &artists {
+                    snapshot.artists.insert(
+                        artist.id.clone(),
+                        SnapshotArtist {
+                            name: artist.name.clone(),
+                            genres: artist.genres.clone(),
+                        },
+                    );
+                    snapshot.followed_artists.push(artist.id.clone());
+                }
+                let cursor = match next {
+                    Some(after) if !artists.is_empty() => Cursor::After(Some(after)),
+                    _ => Cursor::Done,
+                };
+                (
+                    SnapshotSection::FollowedArtists,
+                    artists.len(),
+                    None,
+                    cursor,
+                )
+            }
+            SnapshotPage::Playlists(page) => {
+                for playlist in &page.items {
+                    self.state.pending_playlists.push(PendingPlaylist {
+                        id: playlist.id.clone(),
+                        offset: 0,
+                    });
+                    snapshot.playlists.push(SnapshotPlaylist {
+                        id: playlist.id.clone(),
+                        name: playlist.name.clone(),
+                        owner_id: playlist.owner.id.clone(),
+                        snapshot_id: playlist.snapshot_id.clone(),
+                        track_ids: Vec::new(),
+                    });
+                }
+                // Done once the tracks of the last playlists are in too
+                let cursor = match offset_cursor(&page) {
+                    Cursor::Done if !self.state.pending_playlists.is_empty() => Cursor::Listed,
+                    cursor => cursor,
+                };
+                (SnapshotSection::Playlists, 0, Some(page.total), cursor)
+            }
+            SnapshotPage::PlaylistTracks { playlist_id, page } => {
+                let tracks: Vec<Track> =
+                    page.items.iter().filter_map(PlaylistItem::track).collect();
+                for track in &tracks {
+                    add_track(snapshot, track);
+                }
+                if let Some(playlist) = snapshot.playlists.iter_mut().find(|p| p.id == playlist_id)
+                {
+                    playlist
+                        .track_ids
+                        .extend(tracks.into_iter().map(|track| track.id));
+                }
+                let pending = &mut self.state.pending_playlists;
+                let playlist_done = match offset_cursor(&page) {
+                    Cursor::Offset(offset) => {
+                        if let Some(playlist) = pending.iter_mut().find(|p| p.id == playlist_id) {
+                            playlist.offset = offset;
+                        }
+                        false
+                    }
+                    _ => {
+                        pending.retain(|p| p.id != playlist_id);
+                        true
+                    }
+                };
+                let all_fetched = pending.is_empty();
+                let cursor = match &self.progress_mut(SnapshotSection::Playlists)?.cursor {
+                    Cursor::Listed if all_fetched => Cursor::Done,
+                    cursor => cursor.clone(),
+                };
+                (
+                    SnapshotSection::Playlists,
+                    playlist_done as usize,
+                    None,
+                    cursor,
+                )
+            }
+        };
+        let progress = self.progress_mut(section)?;
+        progress.fetched += fetched;
+        progress.total = total.or(progress.total);
+        progress.took += took;
+        progress.cursor = cursor;
+        self.save()
+    }
+
+    /// Writes the snapshot to `out` and removes the progress file. Only a
+    /// run whose sections are all done finishes. The snapshot is written
+    /// next to `out` first, so an earlier one there is replaced whole.
+    pub fn finish(self, out: &Path) -> Result<LibrarySnapshot> {
+        if let Some(progress) = self.current() {
+            bail!("The snapshot of {} isn't done yet", progress.section);
+        }
+        let mut snapshot = self.state.snapshot;
+        snapshot.manifest.finished_at = Some(SystemTime::now());
+        snapshot.manifest.sections = self
+            .state
+            .sections
+            .iter()
+            .map(|progress| SectionManifest {
+                section: progress.section,
+                count: match progress.section {
+                    SnapshotSection::SavedTracks => snapshot.saved_tracks.len(),
+                    SnapshotSection::SavedAlbums => snapshot.saved_albums.len(),
+                    SnapshotSection::FollowedArtists => snapshot.followed_artists.len(),
+                    SnapshotSection::Playlists => snapshot.playlists.len(),
+                },
+                took: progress.took,
+            })
+            .collect();
+        let temp = out.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_string_pretty(&snapshot)?)
+            .with_context(|| format!("Could not write {}", temp.display()))?;
+        fs::rename(&temp, out).with_context(|| format!("Could not replace {}", out.display()))?;
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        Ok(snapshot)
+    }
+
+    /// Writes next to the progress file first, so a run stopped while
+    /// saving leaves the last progress whole.
+    fn save(&self) -> Result<()> {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temp = self.path.with_file_name(name);
+        fs::write(&temp, SNAPSHOT_STATE_FORMAT.to_string(&self.state)?)
+            .with_context(|| format!("Could not write {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("Could not replace {}", self.path.display()))
+    }
+}
+
+fn add_track(snapshot: &mut LibrarySnapshot, track: &Track) {
+    for artist in &track.artists {
+        snapshot.add_artist(&artist.id, &artist.name);
+    }
+    add_album(snapshot, &track.album);
+    snapshot.tracks.insert(
+        track.id.clone(),
+        SnapshotTrack {
+            name: track.name.clone(),
+            artist_ids: track.artists.iter().map(|a| a.id.clone()).collect(),
+            album_id: track.album.id.clone(),
+            duration_ms: track.duration_ms,
+        },
+    );
+}
+
+fn add_album(snapshot: &mut LibrarySnapshot, album: &Album) {
+    for artist in &album.artists {
+        snapshot.add_artist(&artist.id, &artist.name);
+    }
+    snapshot
+        .albums
+        .entry(album.id.clone())
+        .or_insert_with(|| SnapshotAlbum {
+            name: album.name.clone(),
+            artist_ids: album.artists.iter().map(|a| a.id.clone()).collect(),
+            release_date: album.release_date.clone(),
+            total_tracks: album.total_tracks,
+        });
+}
+
+/// Where the list continues after `page`.
+fn offset_cursor<T>(page: &Page<T>) -> Cursor {
+    match page.next {
+        Some(_) if !page.items.is_empty() => Cursor::Offset(page.offset + page.items.len() as u32),
+        _ => Cursor::Done,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::load_sample;
+    use serde_json::json;
+
+    fn saved_tracks(offset: u32, next: Option<&str>) -> Page<SavedTrack> {
+        let playing: serde_json::Value =
+            serde_json::from_str(&load_sample("currently_playing_track.json")).unwrap();
+        let mut track = playing["item"].clone();
+        track["id"] = format!("track{offset}").into();
+        serde_json::from_value(json!({
+            "items": [{ "added_at": "2024-09-01T10:00:00Z", "track": track }],
+            "limit": 1, "offset": offset, "total": 2, "next": next,
+        }))
+        .unwrap()
+    }
+
+    /// A page of one playlist item, the track with id `track{n}`.
+    fn playlist_items(track: u32, offset: u32, next: Option<&str>) -> Page<PlaylistItem> {
+        let saved = saved_tracks(track, None);
+        let mut item = serde_json::to_value(&saved.items[0].track).unwrap();
+        item["type"] = "track".into();
+        serde_json::from_value(json!({
+            "items": [{ "added_at": "2024-09-02T10:00:00Z", "track": item }],
+            "limit": 1, "offset": offset, "total": 2, "next": next,
+        }))
+        .unwrap()
+    }
+
+    fn playlists() -> Page<PlaylistSummary> {
+        serde_json::from_value(json!({
+            "items": [{
+                "id": "list1", "name": "Road trip", "snapshot_id": "snap1",
+                "owner": { "id": "tester", "display_name": "Tester" },
+            }],
+            "limit": 50, "offset": 0, "total": 1, "next": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_resumes_and_finishes() {
+        let dir = std::env::temp_dir().join(format!("library-snapshot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let out = dir.join(DEFAULT_SNAPSHOT_FILE);
+        let state = snapshot_state_path_for(&out);
+        assert_eq!(state, dir.join("library_snapshot.json.partial"));
+        let sections = [SnapshotSection::Playlists, SnapshotSection::SavedTracks];
+
+        let mut run = SnapshotRun::start(&state, &sections).unwrap();
+        assert_eq!(
+            run.next_request(),
+            Some(PageRequest::SavedTracks { offset: 0 })
+        );
+        let page = SnapshotPage::SavedTracks(saved_tracks(0, Some("next")));
+        run.add_page(page, Duration::from_millis(300)).unwrap();
+        drop(run);
+        assert!(!dir.join("library_snapshot.json.partial.tmp").exists());
+
+        // Stopped after the first page, a new run continues with the second
+        assert!(SnapshotRun::resume(&state, &[SnapshotSection::SavedAlbums]).is_err());
+        let mut run = SnapshotRun::resume(&state, &sections).unwrap().unwrap();
+        assert_eq!(
+            run.next_request(),
+            Some(PageRequest::SavedTracks { offset: 1 })
+        );
+        assert_eq!(run.current().unwrap().fetched, 1);
+        assert_eq!(run.current().unwrap().total, Some(2));
+        let page = SnapshotPage::SavedTracks(saved_tracks(1, None));
+        run.add_page(page, Duration::from_millis(200)).unwrap();
+
+        assert_eq!(
+            run.next_request(),
+            Some(PageRequest::Playlists { offset: 0 })
+        );
+        run.add_page(SnapshotPage::Playlists(playlists()), Duration::ZERO)
+            .unwrap();
+        assert_eq!(
+            run.next_request(),
+            Some(PageRequest::PlaylistTracks {
+                playlist_id: "list1".to_string(),
+                offset: 0,
+            })
+        );
+        let page = SnapshotPage::PlaylistTracks {
+            playlist_id: "list1".to_string(),
+            page: playlist_items(2, 0, Some("next")),
+        };
+        run.add_page(page, Duration::from_millis(60)).unwrap();
+        assert!(run.finish(&out).is_err());
+
+        // The playlist continues at its second page
+        let mut run = SnapshotRun::resume(&state, &sections).unwrap().unwrap();
+        assert_eq!(
+            run.next_request(),
+            Some(PageRequest::PlaylistTracks {
+                playlist_id: "list1".to_string(),
+                offset: 1,
+            })
+        );
+        let page = SnapshotPage::PlaylistTracks {
+            playlist_id: "list1".to_string(),
+            page: playlist_items(0, 1, None),
+        };
+        run.add_page(page, Duration::from_millis(40)).unwrap();
+        assert_eq!(run.next_request(), None);
+
+        let snapshot = run.finish(&out).unwrap();
+        assert!(!state.exists());
+        assert!(!out.with_extension("json.tmp").exists());
+        assert_eq!(LibrarySnapshot::load(&out).unwrap(), snapshot);
+        let manifest: Vec<(SnapshotSection, usize, Duration)> = snapshot
+            .manifest
+            .sections
+            .iter()
+            .map(|s| (s.section, s.count, s.took))
+            .collect();
+        assert_eq!(
+            manifest,
+            [
+                (SnapshotSection::SavedTracks, 2, Duration::from_millis(500)),
+                (SnapshotSection::Playlists, 1, Duration::from_millis(100)),
+            ]
+        );
+        // Tracks only in a playlist are written too, the album and artist
+        // of all three once
+        let tracks: Vec<&str> = snapshot.tracks.keys().map(String::as_str).collect();
+        assert_eq!(tracks, ["track0", "track1", "track2"]);
+        assert_eq!(snapshot.albums.len(), 1);
+        assert_eq!(snapshot.artists.len(), 1);
+        assert_eq!(snapshot.playlists[0].track_ids, ["track2", "track0"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_state_with_a_wrong_cursor() {
+        let path = std::env::temp_dir().join(format!("snapshot-cursor-{}", std::process::id()));
+        let sections = [SnapshotSection::SavedTracks];
+        SnapshotRun::start(&path, &sections).unwrap();
+        let mut state: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        // Saved tracks are paged by offset, never by the last id seen
+        state["data"]["sections"][0]["cursor"] = json!({ "after": "artist1" });
+        fs::write(&path, state.to_string()).unwrap();
+
+        let err = SnapshotRun::resume(&path, &sections).err().unwrap();
+        assert!(
+            err.to_string().contains("saved-tracks can't continue"),
+            "{err}"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_section_names() {
+        for section in SnapshotSection::ALL {
+            assert_eq!(section.name().parse::<SnapshotSection>().unwrap(), section);
+        }
+        assert!("tracks".parse::<SnapshotSection>().is_err());
+    }
+}
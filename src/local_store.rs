@@ -1,180 +1,595 @@
+#[cfg(feature = "aws")]
+use crate::aws_secrets::AwsSecretsProvider;
+use crate::error::{NewerStateFile, SecretNotFound, StorageError, StoreFailure};
+use crate::secrets::{BitwardenSecrets, SecretProvider};
 use crate::spotify_api::{self, AppAuthData, UserAuthData};
+use crate::spotify_data::UserProfile;
+use crate::state_file::{unchanged, StateFormat};
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Write;
-use std::time::SystemTime;
+#[cfg(feature = "blocking")]
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "blocking")]
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "blocking")]
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+use std::time::{Duration, SystemTime};
 use std::{fs, fs::OpenOptions};
 #[cfg(feature = "blocking")]
-use tokio::runtime::Runtime;
+use std::{pin::pin, thread};
+#[cfg(feature = "blocking")]
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
-use bitwarden::secrets_manager::secrets::{
-    SecretCreateRequest, SecretGetRequest, SecretIdentifiersRequest, SecretPutRequest,
-    SecretResponse,
-};
-use bitwarden::{auth::login::AccessTokenLoginRequest, secrets_manager::ClientSecretsExt, Client};
-
 const BITWARDEN_CONFIG: &str = "bitwarden_config.json";
+/// Keeps the secrets in AWS Secrets Manager instead of Bitwarden when present
+const AWS_CONFIG: &str = "aws_secrets_config.json";
 const APP_AUTH_DATA: &str = "app_auth.json";
 const LOCAL_USER_AUTH_DATA: &str = "user_auth.json";
+/// The Spotify user id `auth --detect-user` found, in the [default_config_dir]
+pub const DETECTED_USER_FILE: &str = "user_id";
+/// Secret writes that kept failing, replayed the next time storage starts
+const PENDING_SECRET_WRITES: &str = "pending_secret_writes.json";
+/// The local files holding creds or config, kept in the [default_config_dir]
+pub const LOCAL_CONFIG_FILES: [&str; 4] = [
+    BITWARDEN_CONFIG,
+    APP_AUTH_DATA,
+    LOCAL_USER_AUTH_DATA,
+    PENDING_SECRET_WRITES,
+];
+/// v2 wrapped v1 in the state file envelope
+const PENDING_WRITES_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+/// Waits between attempts of a secret write before it is queued
+const SECRET_WRITE_RETRY_DELAYS: [Duration; 2] =
+    [Duration::from_millis(500), Duration::from_secs(2)];
 
 const BW_SPOTIFY_APP_CLIENTID_KEY: &str = "spotify_client_id";
 const BW_SPOTIFY_TOKEN_KEY: &str = "spotify_access_token";
 const BW_SPOTIFY_REFRESH_KEY: &str = "spotify_refresh_token";
 
-#[derive(Deserialize)]
+#[derive(Clone)]
 struct BitwardenCreds {
     access_token: String,
     org_id: Uuid,
     project_id: Uuid,
+    /// Also keep the Spotify access token in bitwarden, e.g. to share it across machines
+    store_access_token: bool,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+/// Version of the [RefreshNote] written, bumped when its shape changes.
+/// Notes from before versioning have none and are version 0.
+pub const REFRESH_NOTE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
 pub struct RefreshNote {
+    #[serde(default)]
+    pub version: u32,
     pub expires_in: i64,
     pub last_refresh: Option<SystemTime>,
+    /// The scopes granted with the refresh token, None in notes written
+    /// before they were kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub meta: UserMeta,
 }
 
-pub struct CredStorage {
-    org_id: SecretIdentifiersRequest,
-    project_id: Uuid,
+/// Per-user settings kept next to the refresh token, so they survive restarts.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct UserMeta {
+    /// Device playback commands target when none is active
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_device: Option<String>,
+    /// The user's profile, so it isn't fetched from `/me` on every run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<CachedProfile>,
+}
+
+/// A profile as fetched with a token granting `scope`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedProfile {
+    pub profile: UserProfile,
+    pub fetched_at: SystemTime,
+    pub scope: String,
+}
+
+impl CachedProfile {
+    /// Whether the profile can still be used at `now` by a token granting
+    /// `scope`. A re-authorization with other scopes changes what `/me`
+    /// returns, e.g. the email, so the profile has to be fetched again.
+    pub fn is_fresh(&self, now: SystemTime, ttl: Duration, scope: &str) -> bool {
+        let age = now.duration_since(self.fetched_at).unwrap_or(Duration::MAX);
+        age < ttl && same_scopes(&self.scope, scope)
+    }
+}
+
+fn same_scopes(a: &str, b: &str) -> bool {
+    let mut a: Vec<&str> = a.split_whitespace().collect();
+    let mut b: Vec<&str> = b.split_whitespace().collect();
+    a.sort_unstable();
+    b.sort_unstable();
+    a == b
+}
+
+/// A bitwarden secret write, kept around when it failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct PendingWrite {
+    key: String,
+    value: String,
+    note: Option<String>,
+}
+
+/// The app's and the user's creds, kept in local files and in a
+/// [SecretProvider], the one the config picks unless another one is given.
+pub struct CredStorage<S: SecretProvider = ConfiguredSecrets> {
+    secrets: S,
     #[cfg(feature = "blocking")]
-    rt: Runtime,
-    bw_client: Client,
+    rt: Handle,
+    store_access_token: bool,
+    local_dir: PathBuf,
 }
 
-fn load_bitwarden_data() -> Result<BitwardenCreds> {
-    let bitwarden_data = fs::read_to_string(BITWARDEN_CONFIG)?;
-    let config: BitwardenCreds = serde_json::from_str(&bitwarden_data)?;
-    Ok(config)
-}
-
-impl CredStorage {
-    fn start_storage_setup() -> Result<(
-        SecretIdentifiersRequest,
-        Uuid,
-        Client,
-        AccessTokenLoginRequest,
-    )> {
-        let creds = load_bitwarden_data()?;
-        let access_token = creds.access_token;
-        let org_id = creds.org_id;
-        let project_id = creds.project_id;
-
-        let bw_client = Client::new(None);
-        let token = AccessTokenLoginRequest {
-            access_token,
-            state_file: None,
-        };
+/// How a [CredStorage] keeps the creds.
+#[derive(Debug, Clone, Default)]
+pub struct StorageOptions {
+    /// Also keep the Spotify access token in the secrets, not only the refresh token
+    pub store_access_token: bool,
+    /// Where the local files go, the working directory when empty
+    pub local_dir: PathBuf,
+    /// Runs the secret provider's futures, a runtime shared by every
+    /// storage when None. A current thread runtime only works when
+    /// another thread keeps driving it.
+    #[cfg(feature = "blocking")]
+    pub runtime: Option<Handle>,
+}
 
-        let org_id = SecretIdentifiersRequest {
-            organization_id: org_id,
-        };
+/// The runtime of the storages that weren't given one, started on first use.
+#[cfg(feature = "blocking")]
+fn shared_runtime() -> Handle {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("cred-storage")
+                .enable_all()
+                .build()
+                .expect("the storage runtime should start")
+        })
+        .handle()
+        .clone()
+}
 
-        Ok((org_id, project_id, bw_client, token))
+/// Waits for `future` from blocking code, on `rt` unless the caller is
+/// already inside a runtime. Blocking a runtime's thread without telling
+/// it panics, so a multi thread runtime is told with `block_in_place`.
+/// A current thread runtime can't hand its thread over, the future is
+/// polled right here with `rt` providing the IO and timers.
+#[cfg(feature = "blocking")]
+fn block_on<F: Future>(rt: &Handle, future: F) -> F::Output {
+    match Handle::try_current() {
+        Err(_) => rt.block_on(future),
+        Ok(current) if current.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| current.block_on(future))
+        }
+        Ok(_) => {
+            let _guard = rt.enter();
+            park_on(future)
+        }
     }
+}
 
-    #[cfg(feature = "blocking")]
-    pub fn new() -> Result<CredStorage> {
-        let (org_id, project_id, bw_client, token) = Self::start_storage_setup()?;
+#[cfg(feature = "blocking")]
+struct ThreadWaker(thread::Thread);
 
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
+#[cfg(feature = "blocking")]
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` on this thread, parking it in between.
+#[cfg(feature = "blocking")]
+fn park_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = TaskContext::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// Where the cred and config files are kept: `$XDG_CONFIG_HOME/spotify-rs`,
+/// or `~/.config/spotify-rs` when that isn't set. The working directory
+/// when there is no home either.
+pub fn default_config_dir() -> PathBuf {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config) if !config.is_empty() => PathBuf::from(config).join("spotify-rs"),
+        _ => match std::env::var_os("HOME") {
+            Some(home) if !home.is_empty() => PathBuf::from(home).join(".config/spotify-rs"),
+            _ => PathBuf::new(),
+        },
+    }
+}
+
+/// The user id [save_detected_user] kept in `dir`, None when there is none.
+pub fn load_detected_user(dir: &Path) -> Result<Option<String>> {
+    let path = dir.join(DETECTED_USER_FILE);
+    match fs::read_to_string(&path) {
+        Ok(user) => Ok(Some(user.trim().to_string()).filter(|user| !user.is_empty())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Could not read {}", path.display())),
+    }
+}
 
-        let _ = rt.block_on(async { bw_client.auth().login_access_token(&token).await })?;
+/// Keeps the id Spotify knows the authorized user by in `dir`, later runs
+/// use it when `--user` isn't given.
+pub fn save_detected_user(dir: &Path, user: &str) -> Result<()> {
+    let path = dir.join(DETECTED_USER_FILE);
+    create_private_dir(dir)?;
+    fs::write(&path, format!("{user}\n"))
+        .with_context(|| format!("Could not write {}", path.display()))
+}
 
-        Ok(CredStorage {
-            org_id,
-            project_id,
-            rt,
-            bw_client,
+/// Creates `dir` and its parents, only the user can open the ones created
+/// on unix.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+fn load_bitwarden_data() -> Result<BitwardenCreds> {
+    let path = default_config_dir().join(BITWARDEN_CONFIG);
+    let bitwarden_data =
+        fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    parse_bitwarden_config(&bitwarden_data)
+}
+
+/// Parses the bitwarden config field by field, so a mistake is reported
+/// against the field that has it instead of as a bare serde error.
+fn parse_bitwarden_config(data: &str) -> Result<BitwardenCreds> {
+    let config: serde_json::Value = serde_json::from_str(data)
+        .with_context(|| format!("{BITWARDEN_CONFIG} is not valid JSON"))?;
+    if !config.is_object() {
+        bail!("{BITWARDEN_CONFIG} must hold a JSON object");
+    }
+
+    let field = |name: &str| -> Result<&str> {
+        match config.get(name) {
+            None => bail!("{BITWARDEN_CONFIG} is missing the `{name}` field"),
+            Some(value) => value
+                .as_str()
+                .filter(|v| !v.trim().is_empty())
+                .ok_or_else(|| {
+                    anyhow!("`{name}` in {BITWARDEN_CONFIG} must be a non empty string")
+                }),
+        }
+    };
+    let uuid_field = |name: &str| -> Result<Uuid> {
+        let value = field(name)?;
+        Uuid::parse_str(value).with_context(|| {
+            format!("`{name}` in {BITWARDEN_CONFIG} is not a valid UUID: <{value}>")
         })
+    };
+
+    let store_access_token = match config.get("store_access_token") {
+        None => false,
+        Some(value) => value.as_bool().ok_or_else(|| {
+            anyhow!("`store_access_token` in {BITWARDEN_CONFIG} must be true or false")
+        })?,
+    };
+
+    Ok(BitwardenCreds {
+        access_token: field("access_token")?.to_string(),
+        org_id: uuid_field("org_id")?,
+        project_id: uuid_field("project_id")?,
+        store_access_token,
+    })
+}
+
+/// What `aws_secrets_config.json` holds.
+#[cfg(feature = "aws")]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+struct AwsCreds {
+    /// Put before every secret name, e.g. `spotify-rs/`
+    #[serde(default = "default_aws_prefix")]
+    prefix: String,
+    /// Also keep the Spotify access token in AWS, like in the bitwarden config
+    #[serde(default)]
+    store_access_token: bool,
+}
+
+#[cfg(feature = "aws")]
+fn default_aws_prefix() -> String {
+    "spotify-rs/".to_string()
+}
+
+/// The secret provider the config in the [default_config_dir] asks for.
+#[derive(Clone)]
+enum SecretsConfig {
+    Bitwarden(BitwardenCreds),
+    #[cfg(feature = "aws")]
+    Aws(AwsCreds),
+}
+
+impl SecretsConfig {
+    fn store_access_token(&self) -> bool {
+        match self {
+            SecretsConfig::Bitwarden(creds) => creds.store_access_token,
+            #[cfg(feature = "aws")]
+            SecretsConfig::Aws(creds) => creds.store_access_token,
+        }
+    }
+}
+
+/// AWS when there is an `aws_secrets_config.json`, Bitwarden otherwise.
+fn load_secrets_config() -> Result<SecretsConfig> {
+    let path = default_config_dir().join(AWS_CONFIG);
+    if !fs::exists(&path).unwrap_or(false) {
+        return Ok(SecretsConfig::Bitwarden(load_bitwarden_data()?));
+    }
+    #[cfg(feature = "aws")]
+    {
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        let creds = serde_json::from_str(&data)
+            .with_context(|| format!("{AWS_CONFIG} is not a valid AWS config"))?;
+        Ok(SecretsConfig::Aws(creds))
+    }
+    #[cfg(not(feature = "aws"))]
+    bail!(
+        "{} asks for AWS Secrets Manager, build with the `aws` feature to use it",
+        path.display()
+    )
+}
+
+/// The [SecretProvider] picked by the config: AWS Secrets Manager when
+/// `aws_secrets_config.json` is in the [default_config_dir], Bitwarden
+/// otherwise.
+pub enum ConfiguredSecrets {
+    Bitwarden(BitwardenSecrets),
+    #[cfg(feature = "aws")]
+    Aws(AwsSecretsProvider),
+}
+
+impl SecretProvider for ConfiguredSecrets {
+    async fn list(&self) -> Result<Vec<String>> {
+        match self {
+            ConfiguredSecrets::Bitwarden(secrets) => secrets.list().await,
+            #[cfg(feature = "aws")]
+            ConfiguredSecrets::Aws(secrets) => secrets.list().await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<(String, String)> {
+        match self {
+            ConfiguredSecrets::Bitwarden(secrets) => secrets.get(key).await,
+            #[cfg(feature = "aws")]
+            ConfiguredSecrets::Aws(secrets) => secrets.get(key).await,
+        }
+    }
+
+    async fn put(&self, key: &str, value: &str, note: Option<String>) -> Result<()> {
+        match self {
+            ConfiguredSecrets::Bitwarden(secrets) => secrets.put(key, value, note).await,
+            #[cfg(feature = "aws")]
+            ConfiguredSecrets::Aws(secrets) => secrets.put(key, value, note).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            ConfiguredSecrets::Bitwarden(secrets) => secrets.delete(key).await,
+            #[cfg(feature = "aws")]
+            ConfiguredSecrets::Aws(secrets) => secrets.delete(key).await,
+        }
+    }
+}
+
+impl CredStorage<ConfiguredSecrets> {
+    #[cfg(feature = "blocking")]
+    pub fn new() -> Result<CredStorage> {
+        CredStorage::with_runtime(None)
+    }
+
+    /// Like [CredStorage::new], running the secret provider's futures on
+    /// `runtime` instead of the shared one, see [StorageOptions::runtime].
+    #[cfg(feature = "blocking")]
+    pub fn with_runtime(runtime: Option<Handle>) -> Result<CredStorage> {
+        let config = load_secrets_config().context(StorageError::Config)?;
+        let rt = runtime.unwrap_or_else(shared_runtime);
+        let secrets =
+            block_on(&rt, connect_secrets(config.clone())).context(StorageError::Unreachable)?;
+        let options = StorageOptions {
+            store_access_token: config.store_access_token(),
+            local_dir: default_config_dir(),
+            runtime: Some(rt),
+        };
+        Ok(CredStorage::with_provider(secrets, options))
     }
 
     #[cfg(not(feature = "blocking"))]
     pub async fn new() -> Result<CredStorage> {
-        let (org_id, project_id, bw_client, token) = Self::start_storage_setup()?;
+        let config = load_secrets_config().context(StorageError::Config)?;
+        let secrets = connect_secrets(config.clone())
+            .await
+            .context(StorageError::Unreachable)?;
+        let options = StorageOptions {
+            store_access_token: config.store_access_token(),
+            local_dir: default_config_dir(),
+        };
+        Ok(CredStorage::with_provider(secrets, options).await)
+    }
 
-        bw_client.auth().login_access_token(&token).await?;
+    /// Checks the secrets config in the [default_config_dir] can be used before anything else runs:
+    /// every field parses and the secret provider lets us in.
+    #[cfg(feature = "blocking")]
+    pub fn validate_config() -> Result<()> {
+        let config = load_secrets_config()?;
+        block_on(&shared_runtime(), connect_secrets(config))?;
+        Ok(())
+    }
 
-        Ok(CredStorage {
-            org_id,
-            project_id,
-            bw_client,
-        })
+    #[cfg(not(feature = "blocking"))]
+    pub async fn validate_config() -> Result<()> {
+        let config = load_secrets_config()?;
+        connect_secrets(config).await?;
+        Ok(())
     }
+}
 
-    async fn list_secrets(&self) -> Result<HashMap<String, Uuid>> {
-        let res = self.bw_client.secrets().list(&self.org_id).await?;
-        debug!("List Secrets: {:?}", res);
-        let data = res.data;
-        let secrets: HashMap<String, Uuid> = data
-            .iter()
-            .map(|secret| (secret.key.clone(), secret.id))
-            .collect();
+async fn connect_secrets(config: SecretsConfig) -> Result<ConfiguredSecrets> {
+    match config {
+        SecretsConfig::Bitwarden(creds) => {
+            let secrets = login_bitwarden(creds)
+                .await
+                .context("Bitwarden rejected the `access_token`")?;
+            Ok(ConfiguredSecrets::Bitwarden(secrets))
+        }
+        #[cfg(feature = "aws")]
+        SecretsConfig::Aws(creds) => {
+            let secrets = AwsSecretsProvider::from_env(&creds.prefix).await;
+            // Loading the AWS config doesn't reach AWS, listing finds out
+            // whether its creds are any good
+            secrets
+                .list()
+                .await
+                .context("AWS Secrets Manager rejected the creds of the environment")?;
+            Ok(ConfiguredSecrets::Aws(secrets))
+        }
+    }
+}
 
-        Ok(secrets)
+async fn login_bitwarden(creds: BitwardenCreds) -> Result<BitwardenSecrets> {
+    BitwardenSecrets::login(creds.access_token, creds.org_id, creds.project_id).await
+}
+
+impl<S: SecretProvider> CredStorage<S> {
+    /// Keeps the creds in `secrets`. Writes an earlier run queued are
+    /// retried right away.
+    #[cfg(feature = "blocking")]
+    pub fn with_provider(secrets: S, options: StorageOptions) -> CredStorage<S> {
+        let storage = CredStorage {
+            secrets,
+            rt: options.runtime.unwrap_or_else(shared_runtime),
+            store_access_token: options.store_access_token,
+            local_dir: options.local_dir,
+        };
+        storage.block_on(storage.replay_pending_writes());
+        storage
     }
 
-    /// Gien the name of a secret, also named a key, we look for it in
-    /// secrets manager and return a tuple of the secret value and note.
-    async fn get_secret(&self, key: &str) -> Result<(String, String)> {
-        let secrets_md = self.list_secrets().await?;
-        let id = match secrets_md.get(key) {
-            Some(id) => id,
-            None => bail!("Secret key <{key}> does not exist in bitwarden"),
+    #[cfg(not(feature = "blocking"))]
+    pub async fn with_provider(secrets: S, options: StorageOptions) -> CredStorage<S> {
+        let storage = CredStorage {
+            secrets,
+            store_access_token: options.store_access_token,
+            local_dir: options.local_dir,
         };
+        storage.replay_pending_writes().await;
+        storage
+    }
 
-        let get_secret = SecretGetRequest { id: id.clone() };
-        let res: SecretResponse = self.bw_client.secrets().get(&get_secret).await?;
-        debug!("Get Secret: {:?}", res);
-
-        Ok((res.value, res.note))
-    }
-
-    async fn put_secret(&self, key: &str, value: &str, note: Option<String>) -> Result<()> {
-        let secrets_md = self.list_secrets().await?;
-        let id = match secrets_md.get(key) {
-            Some(id) => id,
-            None => {
-                warn!("Secret key <{key}> does not exist in bitwarden, we will try to create it");
-                let create_request = SecretCreateRequest {
-                    organization_id: self.org_id.organization_id,
-                    key: key.to_string(),
-                    value: value.to_string(),
-                    note: note.unwrap_or(String::new()),
-                    project_ids: Some(vec![self.project_id]),
-                };
-                let res: SecretResponse = self.bw_client.secrets().create(&create_request).await?;
-                debug!("Create Secret Response: {:?}", res);
-                debug!("Successfully created secret <{key}> in bitwarden");
-                return Ok(());
+    #[cfg(feature = "blocking")]
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        block_on(&self.rt, future)
+    }
+
+    /// Where the local files go, the working directory when empty.
+    pub fn local_dir(&self) -> &Path {
+        &self.local_dir
+    }
+
+    /// Whether the access token is kept in the secrets too.
+    pub fn stores_access_token(&self) -> bool {
+        self.store_access_token
+    }
+
+    /// The path of one of the local files.
+    fn local_file(&self, file_name: &str) -> String {
+        self.local_dir
+            .join(file_name)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    async fn put_secret_with_retry(&self, write: &PendingWrite) -> Result<()> {
+        let mut delays = SECRET_WRITE_RETRY_DELAYS.iter();
+        loop {
+            match self
+                .secrets
+                .put(&write.key, &write.value, write.note.clone())
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => match delays.next() {
+                    None => return Err(e),
+                    Some(delay) => {
+                        warn!(
+                            "Writing <{}> into bitwarden failed, retrying in {delay:?}: {e}",
+                            write.key
+                        );
+                        tokio::time::sleep(*delay).await;
+                    }
+                },
             }
-        };
+        }
+    }
 
-        let put_request = SecretPutRequest {
-            id: *id,
-            organization_id: self.org_id.organization_id,
-            key: key.to_string(),
-            value: value.to_string(),
-            note: note.unwrap_or(String::new()),
-            project_ids: Some(vec![self.project_id]),
+    /// Retries the writes an earlier run could not get through, once each.
+    async fn replay_pending_writes(&self) {
+        let pending_file = self.local_file(PENDING_SECRET_WRITES);
+        let pending = match load_pending_writes(&pending_file) {
+            Ok(pending) => pending,
+            Err(e) => {
+                error!("Not retrying the queued bitwarden writes: {e:#}");
+                return;
+            }
         };
-        let res: SecretResponse = self.bw_client.secrets().update(&put_request).await?;
-        debug!("Update Secret Response: {:?}", res);
-        debug!("Successfully updated secret <{key}>");
-        Ok(())
+        if pending.is_empty() {
+            return;
+        }
+        info!("Retrying {} queued bitwarden writes", pending.len());
+        let mut still_pending = Vec::new();
+        for write in pending {
+            if let Err(e) = self
+                .secrets
+                .put(&write.key, &write.value, write.note.clone())
+                .await
+            {
+                warn!("Queued write of <{}> failed again: {e}", write.key);
+                still_pending.push(write);
+            }
+        }
+        if let Err(e) = save_pending_writes(&pending_file, &still_pending) {
+            error!("Failed to update {PENDING_SECRET_WRITES}: {e}");
+        }
     }
 
     #[cfg(feature = "blocking")]
     pub fn load_app_auth_data(&self) -> Result<AppAuthData> {
-        Ok(self
-            .rt
-            .block_on(async { self.load_app_auth_data_async().await })?)
+        Ok(self.block_on(async { self.load_app_auth_data_async().await })?)
     }
 
     #[cfg(not(feature = "blocking"))]
@@ -192,19 +607,20 @@ impl CredStorage {
     /// Returns Err if bitwarden fails to respond or if it fails to
     /// write the json data file.
     async fn load_app_auth_data_async(&self) -> Result<AppAuthData> {
-        if let Ok(data) = load_json_data(APP_AUTH_DATA) {
+        let app_file = self.local_file(APP_AUTH_DATA);
+        if let Some(data) = load_auth_file(&app_file) {
             info!("Using AppAuthData found in local json file");
             return Ok(data);
         }
         info!("Did not find {APP_AUTH_DATA} with usable data, fetching from bitwarden");
 
-        let (app_id, _) = self.get_secret(BW_SPOTIFY_APP_CLIENTID_KEY).await?;
+        let (app_id, _) = self.secrets.get(BW_SPOTIFY_APP_CLIENTID_KEY).await?;
         let app_data = AppAuthData {
             client_id: app_id,
             client_secret: None,
         };
 
-        if let Err(e) = store_json_data(APP_AUTH_DATA, &app_data) {
+        if let Err(e) = store_json_data(&app_file, &app_data) {
             warn!("Problem writting data into a file: {e}");
         };
 
@@ -212,27 +628,43 @@ impl CredStorage {
     }
 
     #[cfg(feature = "blocking")]
-    pub fn load_user_auth_data(&self, user_id: &str) -> Option<UserAuthData> {
-        self.rt
-            .block_on(async { self.load_user_auth_data_async(user_id).await })
+    pub fn load_user_auth_data(
+        &self,
+        user_id: &str,
+        refresh_margin: Duration,
+    ) -> Option<UserAuthData> {
+        self.block_on(async {
+            self.load_user_auth_data_async(user_id, refresh_margin)
+                .await
+        })
     }
 
     #[cfg(not(feature = "blocking"))]
-    pub async fn load_user_auth_data(&self, user_id: &str) -> Option<UserAuthData> {
-        self.load_user_auth_data_async(user_id).await
+    pub async fn load_user_auth_data(
+        &self,
+        user_id: &str,
+        refresh_margin: Duration,
+    ) -> Option<UserAuthData> {
+        self.load_user_auth_data_async(user_id, refresh_margin)
+            .await
     }
 
     /// Loads an UserAuthData struct.
     /// The first attempt is using a local json file,
     /// if that fails, we can construct one using the remote value
-    /// stored in Bitwarden Secrets Manager.
+    /// stored in Bitwarden Secrets Manager. The local token counts as
+    /// expired `refresh_margin` early, like the client refreshing it does.
     ///
     /// Returns Err if bitwarden fails to respond or if it fails to
     /// write the json data file.
-    async fn load_user_auth_data_async(&self, user_id: &str) -> Option<UserAuthData> {
+    async fn load_user_auth_data_async(
+        &self,
+        user_id: &str,
+        refresh_margin: Duration,
+    ) -> Option<UserAuthData> {
         let mut local_data = None;
-        if let Ok(data) = load_json_data::<UserAuthData>(LOCAL_USER_AUTH_DATA) {
-            if !data.token_needs_refresh() {
+        if let Some(data) = load_auth_file::<UserAuthData>(&self.local_file(LOCAL_USER_AUTH_DATA)) {
+            if !data.token_needs_refresh(refresh_margin) {
                 return Some(data);
             }
             warn!("User auth data from file is expired, will check bitwarden");
@@ -240,7 +672,8 @@ impl CredStorage {
         }
 
         let refresh = self
-            .get_secret(&format!("{BW_SPOTIFY_REFRESH_KEY}_{user_id}"))
+            .secrets
+            .get(&format!("{BW_SPOTIFY_REFRESH_KEY}_{user_id}"))
             .await;
         debug!("Response from fetching refresh key: {refresh:?}");
 
@@ -260,80 +693,396 @@ impl CredStorage {
         }
         warn!("Found user auth data locally and in bitwarden but they don't match");
 
-        let (access_tok, _) = match self
-            .get_secret(&format!("{BW_SPOTIFY_TOKEN_KEY}_{user_id}"))
+        let access_tok = if self.store_access_token {
+            match self
+                .secrets
+                .get(&format!("{BW_SPOTIFY_TOKEN_KEY}_{user_id}"))
+                .await
+            {
+                Err(e) => {
+                    debug!("There was an error fetching spotify access token: {e}");
+                    warn!(
+                        "Did not find access token in bitwarden, but we did find a refresh token"
+                    );
+                    None
+                }
+                Ok((token, _)) => Some(token),
+            }
+        } else {
+            None
+        };
+
+        Some(user_auth_from_secrets(refresh_tok, access_tok, &note))
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn load_user_meta(&self, user_id: &str) -> UserMeta {
+        self.block_on(async { self.load_user_meta_async(user_id).await })
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn load_user_meta(&self, user_id: &str) -> UserMeta {
+        self.load_user_meta_async(user_id).await
+    }
+
+    /// The user's settings from the note on their refresh token, defaults
+    /// when there is no note yet.
+    async fn load_user_meta_async(&self, user_id: &str) -> UserMeta {
+        match self
+            .secrets
+            .get(&format!("{BW_SPOTIFY_REFRESH_KEY}_{user_id}"))
             .await
         {
             Err(e) => {
-                debug!("There was an error fetching spotify access token: {e}");
-                warn!("Did not find access token in bitwarden, but we did find a refresh token");
-                (String::new(), String::new())
+                debug!("No user meta in bitwarden: {e}");
+                UserMeta::default()
             }
-            Ok(tup) => tup,
-        };
+            Ok((_, note)) => read_refresh_note(&note).meta,
+        }
+    }
 
-        let refresh_note = serde_json::from_str(&note).unwrap_or(RefreshNote::default());
+    #[cfg(feature = "blocking")]
+    pub fn store_user_meta(&self, meta: &UserMeta, user_id: &str) -> Result<()> {
+        self.block_on(async { self.store_user_meta_async(meta, user_id).await })
+    }
 
-        Some(UserAuthData {
-            access_token: access_tok,
-            refresh_token: refresh_tok,
-            token_type: "Bearer".to_string(),
-            scope: spotify_api::SCOPE.to_string(),
-            // We don't know when was the last refresh
-            expires_in: refresh_note.expires_in,
-            last_refresh: refresh_note.last_refresh,
-        })
+    #[cfg(not(feature = "blocking"))]
+    pub async fn store_user_meta(&self, meta: &UserMeta, user_id: &str) -> Result<()> {
+        self.store_user_meta_async(meta, user_id).await
+    }
+
+    /// Rewrites the note on the refresh token, keeping the token and its expiry.
+    ///
+    /// On Error: a [NewerStateFile] when a newer build wrote the note, which
+    /// is left alone instead of losing its fields.
+    async fn store_user_meta_async(&self, meta: &UserMeta, user_id: &str) -> Result<()> {
+        let key = format!("{BW_SPOTIFY_REFRESH_KEY}_{user_id}");
+        let (refresh_tok, note) = self
+            .secrets
+            .get(&key)
+            .await
+            .context("Can't store user meta before the user has authorized")?;
+        let mut refresh_note = match parse_note(&note) {
+            Err(e) if e.is::<NewerStateFile>() => return Err(e),
+            parsed => parsed.unwrap_or_else(|_| read_refresh_note(&note)),
+        };
+        refresh_note.meta = meta.clone();
+        let note = serde_json::to_string(&refresh_note).ok();
+        self.secrets
+            .put(&key, &refresh_tok, note)
+            .await
+            .context("Failed to write user meta into the secrets")
     }
 
     #[cfg(feature = "blocking")]
-    pub fn store_user_auth_data(&self, user_auth: &UserAuthData, user_id: &str) {
-        self.rt
-            .block_on(async { self.store_user_auth_data_async(user_auth, user_id).await });
+    pub fn store_user_auth_data(
+        &self,
+        user_auth: &UserAuthData,
+        meta: &UserMeta,
+        user_id: &str,
+    ) -> Result<()> {
+        self.block_on(async {
+            self.store_user_auth_data_async(user_auth, meta, user_id)
+                .await
+        })
     }
 
     #[cfg(not(feature = "blocking"))]
-    pub async fn store_user_auth_data(&self, user_auth: &UserAuthData, user_id: &str) {
-        self.store_user_auth_data_async(user_auth, user_id).await;
+    pub async fn store_user_auth_data(
+        &self,
+        user_auth: &UserAuthData,
+        meta: &UserMeta,
+        user_id: &str,
+    ) -> Result<()> {
+        self.store_user_auth_data_async(user_auth, meta, user_id)
+            .await
     }
 
-    async fn store_user_auth_data_async(&self, user_auth: &UserAuthData, user_id: &str) {
-        if let Err(e) = store_json_data(LOCAL_USER_AUTH_DATA, user_auth) {
-            warn!("Failed to write User auth data file: {e}");
+    /// Writes the tokens to the local file and bitwarden. Bitwarden writes
+    /// are retried, and queued for the next run when they keep failing.
+    ///
+    /// On Error: a [StoreFailure] naming what was and wasn't stored.
+    async fn store_user_auth_data_async(
+        &self,
+        user_auth: &UserAuthData,
+        meta: &UserMeta,
+        user_id: &str,
+    ) -> Result<()> {
+        let mut stored = Vec::new();
+        let mut failed = Vec::new();
+        match store_json_data(&self.local_file(LOCAL_USER_AUTH_DATA), user_auth) {
+            Ok(()) => stored.push(LOCAL_USER_AUTH_DATA.to_string()),
+            Err(e) => {
+                warn!("Failed to write User auth data file: {e}");
+                failed.push(LOCAL_USER_AUTH_DATA.to_string());
+            }
         }
+
         debug!("Storing UserAuthData into bitwarden");
-        if let Err(e) = self
-            .put_secret(
-                &format!("{BW_SPOTIFY_REFRESH_KEY}_{user_id}"),
-                &user_auth.refresh_token,
-                make_refresh_note(user_auth),
-            )
-            .await
-        {
-            error!("Failed to write refresh token into bitwarden {e}");
+        let mut writes = vec![PendingWrite {
+            key: format!("{BW_SPOTIFY_REFRESH_KEY}_{user_id}"),
+            value: user_auth.refresh_token.clone(),
+            note: make_refresh_note(user_auth, meta),
+        }];
+        // Short lived, unless asked for the local file is enough
+        if self.store_access_token {
+            writes.push(PendingWrite {
+                key: format!("{BW_SPOTIFY_TOKEN_KEY}_{user_id}"),
+                value: user_auth.access_token.clone(),
+                note: make_refresh_note(user_auth, meta),
+            });
         }
-        if let Err(e) = self
-            .put_secret(
-                &format!("{BW_SPOTIFY_TOKEN_KEY}_{user_id}"),
-                &user_auth.access_token,
-                make_refresh_note(user_auth),
-            )
-            .await
-        {
-            error!("Failed to write refresh token into bitwarden: {e}");
+
+        let pending_file = self.local_file(PENDING_SECRET_WRITES);
+        let mut queued = true;
+        for write in writes {
+            match self.put_secret_with_retry(&write).await {
+                Ok(()) => {
+                    // Whatever was queued for this secret is outdated now
+                    if let Err(e) = drop_pending_write(&pending_file, &write.key) {
+                        warn!("Failed to update {PENDING_SECRET_WRITES}: {e}");
+                    }
+                    stored.push(write.key);
+                }
+                Err(e) => {
+                    error!("Failed to write <{}> into bitwarden: {e}", write.key);
+                    failed.push(write.key.clone());
+                    if let Err(e) = queue_pending_write(&pending_file, write) {
+                        error!("Failed to queue the write for the next run: {e}");
+                        queued = false;
+                    }
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            return Ok(());
+        }
+        Err(StoreFailure {
+            stored,
+            failed,
+            queued,
+        }
+        .into())
+    }
+
+    /// The value of another secret kept next to the creds, e.g. a password
+    /// of a service the daemon talks to.
+    #[cfg(feature = "blocking")]
+    pub fn load_secret(&self, key: &str) -> Result<String> {
+        self.block_on(async { self.secrets.get(key).await.map(|(value, _)| value) })
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn load_secret(&self, key: &str) -> Result<String> {
+        self.secrets.get(key).await.map(|(value, _)| value)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn rekey_user(&self, from: &str, to: &str) -> Result<()> {
+        self.block_on(async { self.rekey_user_async(from, to).await })
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn rekey_user(&self, from: &str, to: &str) -> Result<()> {
+        self.rekey_user_async(from, to).await
+    }
+
+    /// Moves the user's secrets from the `from` id to the `to` id, e.g. from
+    /// a placeholder to the id Spotify knows the user by. Secrets `from`
+    /// doesn't have are skipped, each one is written before the old one goes.
+    async fn rekey_user_async(&self, from: &str, to: &str) -> Result<()> {
+        if from == to {
+            return Ok(());
+        }
+        for prefix in [BW_SPOTIFY_REFRESH_KEY, BW_SPOTIFY_TOKEN_KEY] {
+            let old_key = format!("{prefix}_{from}");
+            let (value, note) = match self.secrets.get(&old_key).await {
+                Ok(secret) => secret,
+                Err(e) if e.is::<SecretNotFound>() => continue,
+                Err(e) => return Err(e.context(format!("Failed to read <{old_key}>"))),
+            };
+            let new_key = format!("{prefix}_{to}");
+            self.secrets
+                .put(&new_key, &value, Some(note))
+                .await
+                .with_context(|| format!("Failed to write <{new_key}>"))?;
+            self.secrets
+                .delete(&old_key)
+                .await
+                .with_context(|| format!("Failed to remove <{old_key}>"))?;
+            info!("Moved <{old_key}> to <{new_key}>");
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the user's auth data from the secrets in bitwarden. Without an
+/// access token the data is marked as never refreshed, so the first API
+/// call fetches a new access token with the refresh token.
+fn user_auth_from_secrets(
+    refresh_token: String,
+    access_token: Option<String>,
+    note: &str,
+) -> UserAuthData {
+    let refresh_note = read_refresh_note(note);
+    let last_refresh = match access_token {
+        Some(_) => refresh_note.last_refresh,
+        None => None,
+    };
+    UserAuthData {
+        access_token: access_token.unwrap_or_default(),
+        refresh_token,
+        token_type: "Bearer".to_string(),
+        scope: refresh_note
+            .scope
+            .unwrap_or_else(|| spotify_api::SCOPE.to_string()),
+        expires_in: refresh_note.expires_in,
+        last_refresh,
+    }
+}
+
+/// Parses the note on a refresh token, migrating notes written by older
+/// versions to the current shape. Fails on notes of a newer version, whose
+/// fields may mean something else.
+pub fn parse_note(note: &str) -> Result<RefreshNote> {
+    let value: serde_json::Value = serde_json::from_str(note).context("Note is not JSON")?;
+    let version = match value.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow!("Note version {version} is not a number"))?,
+    };
+    match version {
+        // The same fields, scope and meta were added with defaults
+        0 => {
+            let mut refresh_note: RefreshNote =
+                serde_json::from_value(value).context("Unversioned note has unknown fields")?;
+            refresh_note.version = REFRESH_NOTE_VERSION;
+            Ok(refresh_note)
+        }
+        REFRESH_NOTE_VERSION => Ok(serde_json::from_value(value)?),
+        newer => Err(NewerStateFile {
+            path: PathBuf::from("The note on the refresh token"),
+            version: newer,
+            supported: REFRESH_NOTE_VERSION,
         }
+        .into()),
     }
 }
 
-fn make_refresh_note(data: &UserAuthData) -> Option<String> {
+/// [parse_note] falling back to an empty note, which makes the access
+/// token get refreshed and loses the user's settings, so that's warned about.
+fn read_refresh_note(note: &str) -> RefreshNote {
+    if note.trim().is_empty() {
+        return RefreshNote::default();
+    }
+    parse_note(note).unwrap_or_else(|e| {
+        warn!("Ignoring the note on the refresh token: {e:#}");
+        RefreshNote::default()
+    })
+}
+
+fn make_refresh_note(data: &UserAuthData, meta: &UserMeta) -> Option<String> {
     data.last_refresh.and_then(|ts| {
         let note = RefreshNote {
+            version: REFRESH_NOTE_VERSION,
             expires_in: data.expires_in,
             last_refresh: Some(ts),
+            scope: Some(data.scope.clone()),
+            meta: meta.clone(),
         };
         serde_json::to_string(&note).ok()
     })
 }
 
+/// The queued writes, none when the file is missing or unreadable. One of
+/// a newer format is an error, it would be lost on the next save.
+fn load_pending_writes(file_name: &str) -> Result<Vec<PendingWrite>> {
+    if !fs::exists(file_name).unwrap_or(false) {
+        return Ok(Vec::new());
+    }
+    let writes = load_json_data(file_name)
+        .and_then(|data| PENDING_WRITES_FORMAT.parse_value(data, Path::new(file_name)));
+    match writes {
+        Ok(writes) => Ok(writes),
+        Err(e) if e.is::<NewerStateFile>() => Err(e),
+        Err(e) => {
+            warn!("Ignoring unreadable {file_name}: {e:#}");
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Rewrites the queue, removing the file once nothing is left in it.
+fn save_pending_writes(file_name: &str, writes: &[PendingWrite]) -> Result<()> {
+    if writes.is_empty() {
+        if fs::exists(file_name)? {
+            fs::remove_file(file_name)?;
+        }
+        return Ok(());
+    }
+    // The queued values are tokens
+    write_private(file_name, PENDING_WRITES_FORMAT.to_string(&writes)?)?;
+    Ok(())
+}
+
+/// Queues a write, replacing an older queued write of the same secret.
+fn queue_pending_write(file_name: &str, write: PendingWrite) -> Result<()> {
+    let mut writes = load_pending_writes(file_name)?;
+    writes.retain(|w| w.key != write.key);
+    writes.push(write);
+    save_pending_writes(file_name, &writes)
+}
+
+fn drop_pending_write(file_name: &str, key: &str) -> Result<()> {
+    let mut writes = load_pending_writes(file_name)?;
+    let before = writes.len();
+    writes.retain(|w| w.key != key);
+    if writes.len() == before {
+        return Ok(());
+    }
+    save_pending_writes(file_name, &writes)
+}
+
+/// Loads an auth file, a corrupt one counts as missing so the caller falls
+/// back to bitwarden. It is moved aside to `{file_name}.corrupt-{timestamp}`
+/// rather than being overwritten by the next store.
+fn load_auth_file<D>(file_name: &str) -> Option<D>
+where
+    D: serde::de::DeserializeOwned,
+{
+    let data_str = match fs::read_to_string(file_name) {
+        Ok(data_str) => data_str,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Could not read {file_name}: {e}");
+            return None;
+        }
+    };
+    match serde_json::from_str(&data_str) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            let backup = corrupt_backup_name(file_name, SystemTime::now());
+            match fs::rename(file_name, &backup) {
+                Ok(()) => warn!("{file_name} is corrupt ({e}), moved it to {backup}"),
+                Err(rename_err) => {
+                    warn!("{file_name} is corrupt ({e}) and could not be moved: {rename_err}")
+                }
+            }
+            None
+        }
+    }
+}
+
+fn corrupt_backup_name(file_name: &str, now: SystemTime) -> String {
+    let timestamp = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    format!("{file_name}.corrupt-{timestamp}")
+}
+
 fn load_json_data<D>(file_name: &str) -> Result<D>
 where
     D: serde::de::DeserializeOwned,
@@ -368,9 +1117,116 @@ where
     Ok(())
 }
 
+/// Writes a file holding a secret, only readable by the user on unix.
+/// An existing file is restricted before anything is written to it.
+pub fn write_private(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).truncate(true).create(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_ref())
+}
+
+/// What [migrate_local_files] did with one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Migration {
+    /// There was none to copy
+    Missing,
+    /// Copied to where there was none
+    Copied,
+    /// Copied over an older, different one
+    Replaced,
+    /// The one in place is the same
+    Unchanged,
+    /// The one in place is newer and was kept
+    KeptNewer,
+}
+
+/// Copies the [LOCAL_CONFIG_FILES] in `from` to `to`, e.g. from a directory
+/// the tool used to be run in. The originals stay, copies are only readable
+/// by the user. A file in `to` that is at least as new is never overwritten,
+/// so migrating again changes nothing.
+pub fn migrate_local_files(from: &Path, to: &Path) -> Result<Vec<(&'static str, Migration)>> {
+    let mut migrated = Vec::new();
+    for name in LOCAL_CONFIG_FILES {
+        let source = from.join(name);
+        let destination = to.join(name);
+        let migration = migrate_file(&source, &destination)
+            .with_context(|| format!("Could not migrate {}", source.display()))?;
+        if migration != Migration::Missing {
+            info!(
+                "{}: {migration:?} at {}",
+                source.display(),
+                destination.display()
+            );
+        }
+        migrated.push((name, migration));
+    }
+    Ok(migrated)
+}
+
+/// Copies the [LOCAL_CONFIG_FILES] the tool used to read from `from`, the
+/// working directory, to `to` where it reads them now. Only files missing
+/// from `to` are copied, one already there is never touched, so after the
+/// first run this does nothing. The originals stay.
+pub fn adopt_local_files(from: &Path, to: &Path) -> Result<Vec<&'static str>> {
+    let mut adopted = Vec::new();
+    for name in LOCAL_CONFIG_FILES {
+        let source = from.join(name);
+        let destination = to.join(name);
+        if fs::exists(&destination)? || !fs::exists(&source)? {
+            continue;
+        }
+        migrate_file(&source, &destination)
+            .with_context(|| format!("Could not migrate {}", source.display()))?;
+        info!(
+            "Copied {} to {}, it is read from there from now on",
+            source.display(),
+            destination.display()
+        );
+        adopted.push(name);
+    }
+    Ok(adopted)
+}
+
+fn migrate_file(source: &Path, destination: &Path) -> Result<Migration> {
+    let data = match fs::read(source) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Migration::Missing),
+        Err(e) => return Err(e.into()),
+    };
+    let migration = match fs::read(destination) {
+        Ok(existing) if existing == data => return Ok(Migration::Unchanged),
+        Ok(_) => {
+            let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified());
+            if modified(destination)? >= modified(source)? {
+                return Ok(Migration::KeptNewer);
+            }
+            Migration::Replaced
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Migration::Copied,
+        Err(e) => return Err(e.into()),
+    };
+    if let Some(dir) = destination.parent() {
+        create_private_dir(dir)?;
+    }
+    write_private(destination, data)?;
+    Ok(migration)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::secrets::InMemorySecrets;
 
     fn check_file(filename: &str) {
         match fs::exists(filename) {
@@ -381,6 +1237,752 @@ mod tests {
         }
     }
 
+    fn config_error(data: &str) -> String {
+        match parse_bitwarden_config(data) {
+            Ok(_) => panic!("expected the config to be rejected"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const ORG_ID: &str = "5f1b1c1e-8d1a-4c3b-9f6e-2a7d9c0b1e42";
+    const PROJECT_ID: &str = "0c9e8f7a-6b5d-4e3c-8a1b-9d8e7f6a5b4c";
+
+    fn pending(key: &str, value: &str) -> PendingWrite {
+        PendingWrite {
+            key: key.to_string(),
+            value: value.to_string(),
+            note: None,
+        }
+    }
+
+    #[test]
+    fn test_pending_write_queue() {
+        let path = std::env::temp_dir().join(format!("pending-writes-{}.json", std::process::id()));
+        let file = path.to_str().unwrap();
+        let _ = fs::remove_file(file);
+        assert!(load_pending_writes(file).unwrap().is_empty());
+
+        queue_pending_write(file, pending("refresh_me", "old")).unwrap();
+        queue_pending_write(file, pending("access_me", "token")).unwrap();
+        // A newer write of the same secret replaces the queued one
+        queue_pending_write(file, pending("refresh_me", "new")).unwrap();
+        assert_eq!(
+            load_pending_writes(file).unwrap(),
+            vec![pending("access_me", "token"), pending("refresh_me", "new")]
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        drop_pending_write(file, "unknown").unwrap();
+        drop_pending_write(file, "access_me").unwrap();
+        assert_eq!(
+            load_pending_writes(file).unwrap(),
+            vec![pending("refresh_me", "new")]
+        );
+        drop_pending_write(file, "refresh_me").unwrap();
+        assert!(!fs::exists(file).unwrap());
+    }
+
+    #[test]
+    fn test_pending_write_queue_upgrades() {
+        let path = std::env::temp_dir().join(format!("pending-v1-{}.json", std::process::id()));
+        let file = path.to_str().unwrap();
+        fs::write(
+            file,
+            r#"[{"key": "refresh_me", "value": "old", "note": null}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            load_pending_writes(file).unwrap(),
+            vec![pending("refresh_me", "old")]
+        );
+        queue_pending_write(file, pending("access_me", "token")).unwrap();
+        assert!(fs::read_to_string(file).unwrap().starts_with(r#"{"v":2,"#));
+
+        // A newer queue is neither read nor overwritten
+        let newer = r#"{"v": 3, "data": {"writes": []}}"#;
+        fs::write(file, newer).unwrap();
+        assert!(load_pending_writes(file).is_err());
+        assert!(queue_pending_write(file, pending("access_me", "token")).is_err());
+        assert_eq!(fs::read_to_string(file).unwrap(), newer);
+        fs::remove_file(file).unwrap();
+    }
+
+    fn storage_options(name: &str, store_access_token: bool) -> StorageOptions {
+        let local_dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&local_dir);
+        fs::create_dir_all(&local_dir).unwrap();
+        StorageOptions {
+            store_access_token,
+            local_dir,
+            #[cfg(feature = "blocking")]
+            runtime: None,
+        }
+    }
+
+    fn stored_user_auth() -> UserAuthData {
+        UserAuthData {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            scope: spotify_api::SCOPE.to_string(),
+            expires_in: 3600,
+            refresh_token: "refresh".to_string(),
+            last_refresh: Some(SystemTime::now()),
+        }
+    }
+
+    /// A note of a build that counts the expiry differently
+    const NEWER_NOTE: &str = r#"{"version": 2, "expires_at": 1700003600}"#;
+
+    fn kitchen_meta() -> UserMeta {
+        UserMeta {
+            preferred_device: Some("kitchen".to_string()),
+            profile: None,
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_user_creds_round_trip() {
+        let options = storage_options("user-creds", false);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options).await;
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), "me")
+            .await
+            .unwrap();
+        assert_eq!(storage.load_user_meta("me").await, kitchen_meta());
+        let mut keys = storage.secrets.list().await.unwrap();
+        keys.sort();
+        assert_eq!(keys, ["spotify_refresh_token_me"]);
+
+        // Without the local file the tokens are rebuilt from the secrets
+        fs::remove_file(dir.join(LOCAL_USER_AUTH_DATA)).unwrap();
+        let margin = spotify_api::DEFAULT_REFRESH_MARGIN;
+        let auth = storage.load_user_auth_data("me", margin).await.unwrap();
+        assert_eq!(auth.refresh_token, "refresh");
+        assert!(auth.access_token.is_empty());
+
+        // The client's margin decides whether the local token is fresh enough
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), "me")
+            .await
+            .unwrap();
+        let key = "spotify_refresh_token_me";
+        let (_, note) = storage.secrets.get(key).await.unwrap();
+        storage
+            .secrets
+            .put(key, "rotated", Some(note))
+            .await
+            .unwrap();
+        let minute = Duration::from_secs(60);
+        let auth = storage.load_user_auth_data("me", minute).await.unwrap();
+        assert_eq!(auth.refresh_token, "refresh");
+        let hours = Duration::from_secs(7200);
+        let auth = storage.load_user_auth_data("me", hours).await.unwrap();
+        assert_eq!(auth.refresh_token, "rotated");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_user_meta_leaves_a_newer_note() {
+        let options = storage_options("newer-note", false);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options).await;
+        let (key, note) = ("spotify_refresh_token_me", NEWER_NOTE.to_string());
+        storage
+            .secrets
+            .put(key, "refresh", Some(note.clone()))
+            .await
+            .unwrap();
+
+        let err = storage
+            .store_user_meta(&kitchen_meta(), "me")
+            .await
+            .unwrap_err();
+        assert!(err.is::<NewerStateFile>());
+        assert_eq!(storage.secrets.get(key).await.unwrap().1, note);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_user_meta_leaves_a_newer_note() {
+        let options = storage_options("newer-note", false);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+        let (key, note) = ("spotify_refresh_token_me", NEWER_NOTE.to_string());
+        storage
+            .block_on(storage.secrets.put(key, "refresh", Some(note.clone())))
+            .unwrap();
+
+        let err = storage.store_user_meta(&kitchen_meta(), "me").unwrap_err();
+        assert!(err.is::<NewerStateFile>());
+        assert_eq!(storage.block_on(storage.secrets.get(key)).unwrap().1, note);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_user_creds_round_trip() {
+        let options = storage_options("user-creds", false);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), "me")
+            .unwrap();
+        assert_eq!(storage.load_user_meta("me"), kitchen_meta());
+        let mut keys = storage.block_on(storage.secrets.list()).unwrap();
+        keys.sort();
+        assert_eq!(keys, ["spotify_refresh_token_me"]);
+
+        // Without the local file the tokens are rebuilt from the secrets
+        fs::remove_file(dir.join(LOCAL_USER_AUTH_DATA)).unwrap();
+        let margin = spotify_api::DEFAULT_REFRESH_MARGIN;
+        let auth = storage.load_user_auth_data("me", margin).unwrap();
+        assert_eq!(auth.refresh_token, "refresh");
+        assert!(auth.access_token.is_empty());
+
+        // The client's margin decides whether the local token is fresh enough
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), "me")
+            .unwrap();
+        let key = "spotify_refresh_token_me";
+        let (_, note) = storage.block_on(storage.secrets.get(key)).unwrap();
+        storage
+            .block_on(storage.secrets.put(key, "rotated", Some(note)))
+            .unwrap();
+        let minute = Duration::from_secs(60);
+        let auth = storage.load_user_auth_data("me", minute).unwrap();
+        assert_eq!(auth.refresh_token, "refresh");
+        let hours = Duration::from_secs(7200);
+        let auth = storage.load_user_auth_data("me", hours).unwrap();
+        assert_eq!(auth.refresh_token, "rotated");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_rekey_user() {
+        let options = storage_options("rekey-user", true);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options).await;
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), "placeholder")
+            .await
+            .unwrap();
+
+        storage.rekey_user("placeholder", "jorge").await.unwrap();
+        let mut keys = storage.secrets.list().await.unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["spotify_access_token_jorge", "spotify_refresh_token_jorge"]
+        );
+        assert_eq!(storage.load_user_meta("jorge").await, kitchen_meta());
+
+        // Nothing left under the old id to move
+        storage.rekey_user("placeholder", "other").await.unwrap();
+        assert_eq!(storage.secrets.list().await.unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_rekey_user() {
+        let options = storage_options("rekey-user", true);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), "placeholder")
+            .unwrap();
+
+        storage.rekey_user("placeholder", "jorge").unwrap();
+        let mut keys = storage.block_on(storage.secrets.list()).unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            ["spotify_access_token_jorge", "spotify_refresh_token_jorge"]
+        );
+        assert_eq!(storage.load_user_meta("jorge"), kitchen_meta());
+
+        // Nothing left under the old id to move
+        storage.rekey_user("placeholder", "other").unwrap();
+        assert_eq!(storage.block_on(storage.secrets.list()).unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Secrets that can't be read, like a provider that is unreachable.
+    struct UnreadableSecrets;
+
+    impl SecretProvider for UnreadableSecrets {
+        async fn list(&self) -> Result<Vec<String>> {
+            bail!("unreachable")
+        }
+
+        async fn get(&self, _key: &str) -> Result<(String, String)> {
+            bail!("unreachable")
+        }
+
+        async fn put(&self, _key: &str, _value: &str, _note: Option<String>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn delete(&self, _key: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_rekey_user_read_failure() {
+        let options = storage_options("rekey-unreadable", true);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(UnreadableSecrets, options).await;
+        // Not the same as having nothing to move
+        let err = storage
+            .rekey_user("placeholder", "jorge")
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("spotify_refresh_token_placeholder"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_rekey_user_read_failure() {
+        let options = storage_options("rekey-unreadable", true);
+        let dir = options.local_dir.clone();
+        let storage = CredStorage::with_provider(UnreadableSecrets, options);
+        // Not the same as having nothing to move
+        let err = storage.rekey_user("placeholder", "jorge").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("spotify_refresh_token_placeholder"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_app_auth_and_queued_writes() {
+        let options = storage_options("app-auth", true);
+        let dir = options.local_dir.clone();
+        let pending_file = dir.join(PENDING_SECRET_WRITES);
+        queue_pending_write(pending_file.to_str().unwrap(), pending("queued", "value")).unwrap();
+        let secrets = InMemorySecrets::new();
+        secrets
+            .put(BW_SPOTIFY_APP_CLIENTID_KEY, "client-id", None)
+            .await
+            .unwrap();
+
+        // Queued writes go through when the storage starts
+        let storage = CredStorage::with_provider(secrets, options).await;
+        assert_eq!(storage.secrets.get("queued").await.unwrap().0, "value");
+        assert!(!fs::exists(&pending_file).unwrap());
+
+        let app = storage.load_app_auth_data().await.unwrap();
+        assert_eq!(app.client_id, "client-id");
+        assert!(fs::exists(dir.join(APP_AUTH_DATA)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_app_auth_and_queued_writes() {
+        let options = storage_options("app-auth", true);
+        let dir = options.local_dir.clone();
+        let pending_file = dir.join(PENDING_SECRET_WRITES);
+        queue_pending_write(pending_file.to_str().unwrap(), pending("queued", "value")).unwrap();
+        let secrets = InMemorySecrets::new();
+
+        // Queued writes go through when the storage starts
+        let storage = CredStorage::with_provider(secrets, options);
+        let (value, _) = storage.block_on(storage.secrets.get("queued")).unwrap();
+        assert_eq!(value, "value");
+        assert!(!fs::exists(&pending_file).unwrap());
+
+        storage
+            .block_on(
+                storage
+                    .secrets
+                    .put(BW_SPOTIFY_APP_CLIENTID_KEY, "client-id", None),
+            )
+            .unwrap();
+        let app = storage.load_app_auth_data().unwrap();
+        assert_eq!(app.client_id, "client-id");
+        assert!(fs::exists(dir.join(APP_AUTH_DATA)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A user's creds survive a round trip through `storage`, its timers work.
+    #[cfg(feature = "blocking")]
+    fn check_storage(storage: &CredStorage<InMemorySecrets>, user_id: &str) {
+        storage
+            .store_user_auth_data(&stored_user_auth(), &kitchen_meta(), user_id)
+            .unwrap();
+        assert_eq!(storage.load_user_meta(user_id), kitchen_meta());
+        storage.block_on(async { tokio::time::sleep(Duration::from_millis(5)).await });
+        fs::remove_dir_all(&storage.local_dir).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_storage_from_plain_threads() {
+        let threads: Vec<_> = (0..3)
+            .map(|n| {
+                thread::spawn(move || {
+                    let options = storage_options(&format!("plain-thread-{n}"), false);
+                    let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+                    check_storage(&storage, "me");
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // A runtime of the caller's
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let options = StorageOptions {
+            runtime: Some(rt.handle().clone()),
+            ..storage_options("given-runtime", false)
+        };
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+        check_storage(&storage, "me");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[tokio::test]
+    async fn test_storage_inside_current_thread_runtime() {
+        let options = storage_options("current-thread", false);
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+        check_storage(&storage, "me");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_storage_inside_multi_thread_runtime() {
+        let options = storage_options("multi-thread", false);
+        let storage = CredStorage::with_provider(InMemorySecrets::new(), options);
+        check_storage(&storage, "me");
+    }
+
+    #[test]
+    fn test_store_failure_message() {
+        let failure = StoreFailure {
+            stored: vec![LOCAL_USER_AUTH_DATA.to_string()],
+            failed: vec!["spotify_refresh_token_me".to_string()],
+            queued: true,
+        };
+        assert_eq!(
+            failure.to_string(),
+            "Could not store spotify_refresh_token_me (stored user_auth.json), retrying on the next run"
+        );
+    }
+
+    #[test]
+    fn test_refresh_note_keeps_user_meta() {
+        // Notes written before user meta existed still parse
+        let old: RefreshNote =
+            serde_json::from_str(r#"{"expires_in": 3600, "last_refresh": null}"#).unwrap();
+        assert_eq!(old.meta, UserMeta::default());
+
+        let note = RefreshNote {
+            version: REFRESH_NOTE_VERSION,
+            expires_in: 3600,
+            last_refresh: None,
+            scope: None,
+            meta: UserMeta {
+                preferred_device: Some("kitchen".to_string()),
+                profile: None,
+            },
+        };
+        let parsed: RefreshNote =
+            serde_json::from_str(&serde_json::to_string(&note).unwrap()).unwrap();
+        assert_eq!(parsed.meta.preferred_device.as_deref(), Some("kitchen"));
+    }
+
+    #[test]
+    fn test_parse_legacy_note() {
+        let note = parse_note(
+            r#"{"expires_in": 3600, "last_refresh": {"secs_since_epoch": 1700000000, "nanos_since_epoch": 0}}"#,
+        )
+        .unwrap();
+        assert_eq!(note.version, REFRESH_NOTE_VERSION);
+        assert_eq!(note.expires_in, 3600);
+        assert_eq!(
+            note.last_refresh,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+        );
+        assert_eq!(note.scope, None);
+        assert_eq!(note.meta, UserMeta::default());
+    }
+
+    #[test]
+    fn test_parse_versioned_note() {
+        let note = parse_note(
+            r#"{"version": 1, "expires_in": 3600, "last_refresh": null, "scope": "user-top-read", "meta": {"preferred_device": "kitchen"}}"#,
+        )
+        .unwrap();
+        assert_eq!(note.scope.as_deref(), Some("user-top-read"));
+        assert_eq!(note.meta.preferred_device.as_deref(), Some("kitchen"));
+
+        let newer = parse_note(NEWER_NOTE).unwrap_err();
+        assert!(newer.is::<NewerStateFile>());
+        assert!(parse_note(r#"{"version": "1", "expires_in": 3600}"#).is_err());
+        assert!(parse_note("not json").is_err());
+        // A broken note loses the expiry, the access token is refreshed
+        assert_eq!(read_refresh_note("not json").last_refresh, None);
+    }
+
+    #[test]
+    fn test_bitwarden_config_parses() {
+        let data = format!(
+            r#"{{"access_token": "0.token", "org_id": "{ORG_ID}", "project_id": "{PROJECT_ID}"}}"#
+        );
+        let creds = parse_bitwarden_config(&data).unwrap();
+        assert_eq!(creds.org_id.to_string(), ORG_ID);
+        assert_eq!(creds.project_id.to_string(), PROJECT_ID);
+        assert!(!creds.store_access_token);
+    }
+
+    #[test]
+    fn test_bitwarden_config_store_access_token() {
+        let data = format!(
+            r#"{{"access_token": "0.token", "org_id": "{ORG_ID}", "project_id": "{PROJECT_ID}",
+                "store_access_token": true}}"#
+        );
+        assert!(parse_bitwarden_config(&data).unwrap().store_access_token);
+
+        let data = format!(
+            r#"{{"access_token": "0.token", "org_id": "{ORG_ID}", "project_id": "{PROJECT_ID}",
+                "store_access_token": "yes"}}"#
+        );
+        let err = config_error(&data);
+        assert!(err.contains("`store_access_token`"), "{err}");
+    }
+
+    #[test]
+    fn test_user_auth_from_secrets() {
+        let note = r#"{"expires_in": 3600, "last_refresh": {"secs_since_epoch": 1726602033, "nanos_since_epoch": 0}}"#;
+
+        let auth = user_auth_from_secrets("refresh".to_string(), None, note);
+        assert_eq!(auth.refresh_token, "refresh");
+        assert!(auth.access_token.is_empty());
+        assert!(auth.last_refresh.is_none());
+        assert!(auth.token_needs_refresh(spotify_api::DEFAULT_REFRESH_MARGIN));
+
+        let auth = user_auth_from_secrets("refresh".to_string(), Some("access".to_string()), note);
+        assert_eq!(auth.access_token, "access");
+        assert!(auth.last_refresh.is_some());
+        assert_eq!(auth.expires_in, 3600);
+        assert_eq!(auth.scope, spotify_api::SCOPE);
+
+        let note = r#"{"expires_in": 3600, "last_refresh": null, "scope": "user-read-email"}"#;
+        let auth = user_auth_from_secrets("refresh".to_string(), None, note);
+        assert_eq!(auth.granted_scopes(), ["user-read-email"]);
+    }
+
+    #[test]
+    fn test_cached_profile_freshness() {
+        let fetched_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_726_602_033);
+        let ttl = Duration::from_secs(3600);
+        let cached = CachedProfile {
+            profile: UserProfile {
+                id: "jorge".to_string(),
+                display_name: Some("Jorge".to_string()),
+                country: Some("SE".to_string()),
+                product: Some("premium".to_string()),
+                email: None,
+            },
+            fetched_at,
+            scope: "user-top-read user-read-private".to_string(),
+        };
+        let scope = "user-read-private user-top-read";
+        assert!(cached.is_fresh(fetched_at + Duration::from_secs(60), ttl, scope));
+        assert!(!cached.is_fresh(fetched_at + ttl, ttl, scope));
+        // Authorized again, now with the email
+        assert!(!cached.is_fresh(
+            fetched_at,
+            ttl,
+            "user-read-private user-top-read user-read-email"
+        ));
+        // A clock that went back doesn't keep it around
+        assert!(!cached.is_fresh(fetched_at - Duration::from_secs(60), ttl, scope));
+    }
+
+    #[test]
+    fn test_bitwarden_config_missing_field() {
+        let data = format!(r#"{{"access_token": "0.token", "org_id": "{ORG_ID}"}}"#);
+        let err = config_error(&data);
+        assert!(err.contains("missing the `project_id` field"), "{err}");
+
+        let data = format!(
+            r#"{{"access_token": "", "org_id": "{ORG_ID}", "project_id": "{PROJECT_ID}"}}"#
+        );
+        let err = config_error(&data);
+        assert!(err.contains("`access_token`"), "{err}");
+    }
+
+    #[test]
+    fn test_bitwarden_config_bad_uuid() {
+        let data = format!(
+            r#"{{"access_token": "0.token", "org_id": "not-a-uuid", "project_id": "{PROJECT_ID}"}}"#
+        );
+        let err = config_error(&data);
+        assert!(err.contains("`org_id`"), "{err}");
+        assert!(err.contains("not a valid UUID"), "{err}");
+    }
+
+    #[test]
+    fn test_corrupt_auth_file_is_moved_aside() {
+        let dir = std::env::temp_dir().join(format!("corrupt-auth-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("user_auth.json");
+        let file = path.to_str().unwrap();
+        fs::write(file, "{\"access_token\": \"trunc").unwrap();
+
+        assert!(load_auth_file::<UserAuthData>(file).is_none());
+        assert!(!fs::exists(file).unwrap());
+        let backups: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(backups[0].starts_with("user_auth.json.corrupt-"));
+        let backup = fs::read_to_string(dir.join(&backups[0])).unwrap();
+        assert_eq!(backup, "{\"access_token\": \"trunc");
+
+        // A missing file is just absent, nothing to move
+        assert!(load_auth_file::<UserAuthData>(file).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_backup_name() {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(1727190000);
+        assert_eq!(
+            corrupt_backup_name("user_auth.json", at),
+            "user_auth.json.corrupt-1727190000"
+        );
+    }
+
+    #[test]
+    fn test_migrate_local_files() {
+        let dir = std::env::temp_dir().join(format!("migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (from, to) = (dir.join("old-cwd"), dir.join("config"));
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join(APP_AUTH_DATA), "old app").unwrap();
+        fs::write(from.join(LOCAL_USER_AUTH_DATA), "old user").unwrap();
+
+        let migrated = migrate_local_files(&from, &to).unwrap();
+        assert_eq!(
+            migrated,
+            [
+                (BITWARDEN_CONFIG, Migration::Missing),
+                (APP_AUTH_DATA, Migration::Copied),
+                (LOCAL_USER_AUTH_DATA, Migration::Copied),
+                (PENDING_SECRET_WRITES, Migration::Missing),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(to.join(APP_AUTH_DATA)).unwrap(),
+            "old app"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&to.join(LOCAL_USER_AUTH_DATA)), 0o600);
+            assert_eq!(mode(&to), 0o700);
+        }
+        // Again, nothing changes and the originals stay
+        let again = migrate_local_files(&from, &to).unwrap();
+        assert!(again
+            .iter()
+            .all(|(_, m)| matches!(m, Migration::Missing | Migration::Unchanged)));
+        assert!(fs::exists(from.join(APP_AUTH_DATA)).unwrap());
+
+        // A token refreshed since in the new place is kept, an older one replaced
+        fs::write(to.join(LOCAL_USER_AUTH_DATA), "refreshed user").unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::write(to.join(APP_AUTH_DATA), "stale app").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(to.join(APP_AUTH_DATA))
+            .unwrap()
+            .set_modified(an_hour_ago)
+            .unwrap();
+        let migrated = migrate_local_files(&from, &to).unwrap();
+        assert_eq!(migrated[1], (APP_AUTH_DATA, Migration::Replaced));
+        assert_eq!(migrated[2], (LOCAL_USER_AUTH_DATA, Migration::KeptNewer));
+        assert_eq!(
+            fs::read_to_string(to.join(APP_AUTH_DATA)).unwrap(),
+            "old app"
+        );
+        assert_eq!(
+            fs::read_to_string(to.join(LOCAL_USER_AUTH_DATA)).unwrap(),
+            "refreshed user"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_adopt_local_files() {
+        let dir = std::env::temp_dir().join(format!("adopt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (cwd, config) = (dir.join("cwd"), dir.join("config"));
+        fs::create_dir_all(&cwd).unwrap();
+        fs::write(cwd.join(BITWARDEN_CONFIG), "bitwarden").unwrap();
+        fs::write(cwd.join(APP_AUTH_DATA), "app").unwrap();
+        fs::write(cwd.join(LOCAL_USER_AUTH_DATA), "old user").unwrap();
+        fs::write(cwd.join(PENDING_SECRET_WRITES), "pending").unwrap();
+        // The token was already refreshed in the config dir
+        fs::create_dir_all(&config).unwrap();
+        fs::write(config.join(LOCAL_USER_AUTH_DATA), "refreshed user").unwrap();
+
+        let adopted = adopt_local_files(&cwd, &config).unwrap();
+        assert_eq!(
+            adopted,
+            [BITWARDEN_CONFIG, APP_AUTH_DATA, PENDING_SECRET_WRITES]
+        );
+        // Secret writes that failed there are replayed from the config dir
+        assert_eq!(
+            fs::read_to_string(config.join(PENDING_SECRET_WRITES)).unwrap(),
+            "pending"
+        );
+        assert_eq!(
+            fs::read_to_string(config.join(APP_AUTH_DATA)).unwrap(),
+            "app"
+        );
+        assert_eq!(
+            fs::read_to_string(config.join(LOCAL_USER_AUTH_DATA)).unwrap(),
+            "refreshed user"
+        );
+        assert!(fs::exists(cwd.join(APP_AUTH_DATA)).unwrap());
+
+        // Only once, even when the working directory's file changes since
+        fs::write(cwd.join(APP_AUTH_DATA), "changed app").unwrap();
+        assert!(adopt_local_files(&cwd, &config).unwrap().is_empty());
+        assert_eq!(
+            fs::read_to_string(config.join(APP_AUTH_DATA)).unwrap(),
+            "app"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_load_json_data_but_file_is_missing() {
         let file = "random_file.json";
@@ -0,0 +1,230 @@
+use chrono::{Datelike, NaiveDate};
+use std::time::Duration;
+
+/// The language numbers, durations and dates of reports are written in.
+/// Only what is printed for people changes, JSON output stays raw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    English,
+    German,
+    Swedish,
+}
+
+/// What a locale writes differently.
+struct Names {
+    months: [&'static str; 12],
+    /// Monday first, like [chrono::Weekday::num_days_from_monday]
+    weekdays: [&'static str; 7],
+    thousands: char,
+    hours: &'static str,
+    minutes: &'static str,
+}
+
+const ENGLISH: Names = Names {
+    months: [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ],
+    weekdays: ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"],
+    thousands: ',',
+    hours: "h",
+    minutes: "m",
+};
+
+const GERMAN: Names = Names {
+    months: [
+        "Januar",
+        "Februar",
+        "März",
+        "April",
+        "Mai",
+        "Juni",
+        "Juli",
+        "August",
+        "September",
+        "Oktober",
+        "November",
+        "Dezember",
+    ],
+    weekdays: ["Mo.", "Di.", "Mi.", "Do.", "Fr.", "Sa.", "So."],
+    thousands: '.',
+    hours: "Std.",
+    minutes: "Min.",
+};
+
+const SWEDISH: Names = Names {
+    months: [
+        "januari",
+        "februari",
+        "mars",
+        "april",
+        "maj",
+        "juni",
+        "juli",
+        "augusti",
+        "september",
+        "oktober",
+        "november",
+        "december",
+    ],
+    weekdays: ["mån", "tis", "ons", "tors", "fre", "lör", "sön"],
+    thousands: ' ',
+    hours: "tim",
+    minutes: "min",
+};
+
+impl Locale {
+    /// The locale of a tag like `de-DE` or `sv_SE.UTF-8`, only the language
+    /// counts. Languages without names here fall back to English.
+    pub fn from_tag(tag: &str) -> Locale {
+        let language = tag.split(['-', '_', '.']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "de" => Locale::German,
+            "sv" => Locale::Swedish,
+            _ => Locale::English,
+        }
+    }
+
+    fn names(self) -> &'static Names {
+        match self {
+            Locale::English => &ENGLISH,
+            Locale::German => &GERMAN,
+            Locale::Swedish => &SWEDISH,
+        }
+    }
+
+    /// `n` with thousands separators, e.g. `12,345`.
+    pub fn number(self, n: usize) -> String {
+        let digits = n.to_string();
+        let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                out.push(self.names().thousands);
+            }
+            out.push(digit);
+        }
+        out
+    }
+
+    /// Whole hours and minutes, e.g. `3 h 25 m`. Shorter than a minute is
+    /// `0 m`, the seconds are dropped.
+    pub fn duration(self, duration: Duration) -> String {
+        let names = self.names();
+        let minutes = duration.as_secs() / 60;
+        match minutes / 60 {
+            0 => format!("{minutes} {}", names.minutes),
+            hours => format!(
+                "{} {} {} {}",
+                self.number(hours as usize),
+                names.hours,
+                minutes % 60,
+                names.minutes
+            ),
+        }
+    }
+
+    /// The day of the month and the month, e.g. `February 28`.
+    pub fn day_month(self, date: NaiveDate) -> String {
+        let month = self.names().months[date.month0() as usize];
+        match self {
+            Locale::English => format!("{month} {}", date.day()),
+            Locale::German => format!("{}. {month}", date.day()),
+            Locale::Swedish => format!("{} {month}", date.day()),
+        }
+    }
+
+    /// The full date with its weekday, e.g. `Wed, February 28, 2024`.
+    pub fn date(self, date: NaiveDate) -> String {
+        let weekday = self.names().weekdays[date.weekday().num_days_from_monday() as usize];
+        let day_month = self.day_month(date);
+        match self {
+            Locale::English => format!("{weekday}, {day_month}, {}", date.year()),
+            Locale::German => format!("{weekday}, {day_month} {}", date.year()),
+            Locale::Swedish => format!("{weekday} {day_month} {}", date.year()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(locale: Locale) -> Vec<String> {
+        let leap_day = NaiveDate::from_ymd_opt(2024, 2, 29).unwrap();
+        vec![
+            locale.number(7),
+            locale.number(12345),
+            locale.number(1234567),
+            locale.duration(Duration::from_secs(59)),
+            locale.duration(Duration::from_secs(25 * 60 + 30)),
+            locale.duration(Duration::from_secs(3 * 3600 + 25 * 60)),
+            locale.duration(Duration::from_secs(12345 * 60)),
+            locale.day_month(leap_day),
+            locale.date(leap_day),
+            locale.date(NaiveDate::from_ymd_opt(2023, 3, 5).unwrap()),
+        ]
+    }
+
+    #[test]
+    fn test_english_formats() {
+        assert_eq!(
+            render(Locale::English),
+            [
+                "7",
+                "12,345",
+                "1,234,567",
+                "0 m",
+                "25 m",
+                "3 h 25 m",
+                "205 h 45 m",
+                "February 29",
+                "Thu, February 29, 2024",
+                "Sun, March 5, 2023",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_german_formats() {
+        assert_eq!(
+            render(Locale::German),
+            [
+                "7",
+                "12.345",
+                "1.234.567",
+                "0 Min.",
+                "25 Min.",
+                "3 Std. 25 Min.",
+                "205 Std. 45 Min.",
+                "29. Februar",
+                "Do., 29. Februar 2024",
+                "So., 5. März 2023",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locale_from_tag() {
+        assert_eq!(Locale::from_tag("de-DE"), Locale::German);
+        assert_eq!(Locale::from_tag("de_AT.UTF-8"), Locale::German);
+        assert_eq!(Locale::from_tag("SV-se"), Locale::Swedish);
+        assert_eq!(Locale::from_tag("en-GB"), Locale::English);
+        assert_eq!(Locale::from_tag("ja-JP"), Locale::English);
+        assert_eq!(Locale::from_tag(""), Locale::English);
+        assert_eq!(
+            Locale::Swedish.duration(Duration::from_secs(4000 * 3600)),
+            "4 000 tim 0 min"
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How often a failure that keeps repeating is summarized.
+pub const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Keeps a failing loop from flooding the logs. The first failure of a
+/// category is logged in full, the repeats are counted and summarized once
+/// per interval with the latest message. Messages that change with every
+/// failure, e.g. naming a timestamp, are repeats all the same.
+///
+/// It takes the time from its callers, so it can be tested with a fake clock.
+pub struct LogThrottle {
+    interval: Duration,
+    failing: HashMap<String, Failing>,
+    suppressed: u64,
+}
+
+struct Failing {
+    since: Instant,
+    failures: u64,
+    window_start: Instant,
+    repeats: u64,
+}
+
+/// What to do with a failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogAction {
+    /// Log it in full
+    Log,
+    /// Log that it was seen `count` times over the last `over`
+    Summarize { count: u64, over: Duration },
+    /// Already logged, stay quiet
+    Suppress,
+}
+
+/// A category that works again after failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recovery {
+    pub failures: u64,
+    pub down_for: Duration,
+}
+
+impl LogThrottle {
+    pub fn new(interval: Duration) -> LogThrottle {
+        LogThrottle {
+            interval,
+            failing: HashMap::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Repeats that weren't logged in full since the start.
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// A failure of `category` at `now`. A summary is logged with the
+    /// failure that triggers it, so only the latest message is in it.
+    pub fn failure_at(&mut self, category: &str, now: Instant) -> LogAction {
+        let Some(failing) = self.failing.get_mut(category) else {
+            self.failing.insert(
+                category.to_string(),
+                Failing {
+                    since: now,
+                    failures: 1,
+                    window_start: now,
+                    repeats: 0,
+                },
+            );
+            return LogAction::Log;
+        };
+
+        failing.failures += 1;
+        self.suppressed += 1;
+        failing.repeats += 1;
+        let over = now.saturating_duration_since(failing.window_start);
+        if over < self.interval {
+            return LogAction::Suppress;
+        }
+        let count = failing.repeats;
+        failing.window_start = now;
+        failing.repeats = 0;
+        LogAction::Summarize { count, over }
+    }
+
+    /// A success of `category` at `now`, Some when it was failing before.
+    /// Recoveries are always worth logging.
+    pub fn success_at(&mut self, category: &str, now: Instant) -> Option<Recovery> {
+        let failing = self.failing.remove(category)?;
+        Some(Recovery {
+            failures: failing.failures,
+            down_for: now.saturating_duration_since(failing.since),
+        })
+    }
+}
+
+impl Default for LogThrottle {
+    fn default() -> Self {
+        LogThrottle::new(DEFAULT_SUMMARY_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_summarized() {
+        let mut throttle = LogThrottle::new(Duration::from_secs(600));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert_eq!(throttle.failure_at("poll", at(0)), LogAction::Log);
+        for secs in (5..600).step_by(5) {
+            assert_eq!(throttle.failure_at("poll", at(secs)), LogAction::Suppress);
+        }
+        assert_eq!(
+            throttle.failure_at("poll", at(600)),
+            LogAction::Summarize {
+                count: 120,
+                over: Duration::from_secs(600)
+            }
+        );
+        assert_eq!(throttle.failure_at("poll", at(605)), LogAction::Suppress);
+        assert_eq!(throttle.suppressed(), 121);
+
+        // Another category is throttled on its own
+        assert_eq!(throttle.failure_at("queue", at(605)), LogAction::Log);
+        assert_eq!(throttle.failure_at("poll", at(610)), LogAction::Suppress);
+    }
+
+    #[test]
+    fn test_recovery_is_reported_once() {
+        let mut throttle = LogThrottle::default();
+        let start = Instant::now();
+        assert_eq!(throttle.success_at("poll", start), None);
+
+        throttle.failure_at("poll", start);
+        throttle.failure_at("poll", start + Duration::from_secs(5));
+        assert_eq!(
+            throttle.success_at("poll", start + Duration::from_secs(10)),
+            Some(Recovery {
+                failures: 2,
+                down_for: Duration::from_secs(10)
+            })
+        );
+        assert_eq!(
+            throttle.success_at("poll", start + Duration::from_secs(15)),
+            None
+        );
+
+        // Failing again after recovering is logged in full again
+        assert_eq!(
+            throttle.failure_at("poll", start + Duration::from_secs(20)),
+            LogAction::Log
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use crate::spotify_data::Track;
+use crate::state_file::{unchanged, StateFormat};
+use crate::watcher::PlaybackExtrapolator;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+pub const DEFAULT_LYRICS_CACHE_DIR: &str = "lyrics_cache";
+
+/// v2 wrapped v1 in the state file envelope.
+const LYRICS_CACHE_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+
+/// What a provider gets to find the lyrics of a track.
+/// Providers use whichever identification they support.
+#[derive(Debug, Clone)]
+pub struct LyricsQuery {
+    pub isrc: Option<String>,
+    pub artist: String,
+    pub title: String,
+    pub album: String,
+    pub duration: Duration,
+}
+
+impl LyricsQuery {
+    pub fn from_track(track: &Track) -> LyricsQuery {
+        LyricsQuery {
+            isrc: track.external_ids.isrc.clone(),
+            artist: track
+                .artists
+                .first()
+                .map(|a| a.name.clone())
+                .unwrap_or_default(),
+            title: track.name.clone(),
+            album: track.album.name.clone(),
+            duration: Duration::from_millis(track.duration_ms as u64),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LyricLine {
+    pub at: Duration,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Lyrics {
+    /// Lines with timestamps, sorted by time.
+    Synced(Vec<LyricLine>),
+    Plain(String),
+}
+
+impl Lyrics {
+    /// Index of the synced line being sung at `position`.
+    pub fn line_index_at(&self, position: Duration) -> Option<usize> {
+        match self {
+            Lyrics::Synced(lines) => lines.partition_point(|l| l.at <= position).checked_sub(1),
+            Lyrics::Plain(_) => None,
+        }
+    }
+}
+
+/// A source of lyrics, e.g. a public lyrics API.
+pub trait LyricsProvider: Send + Sync {
+    /// `Ok(None)` when the provider has no lyrics for the track.
+    fn lookup(&self, query: &LyricsQuery) -> Result<Option<Lyrics>>;
+
+    /// The lyrics of `track`. Override it for a provider that looks tracks
+    /// up by their Spotify id rather than by a [LyricsQuery].
+    fn fetch(&self, track: &Track) -> Result<Option<Lyrics>> {
+        self.lookup(&LyricsQuery::from_track(track))
+    }
+}
+
+/// The provider of a client nobody gave one, it never has lyrics.
+pub struct NoLyrics;
+
+impl LyricsProvider for NoLyrics {
+    fn lookup(&self, _query: &LyricsQuery) -> Result<Option<Lyrics>> {
+        Ok(None)
+    }
+}
+
+/// Parses LRC formatted lyrics, `[mm:ss.xx] text`. A line may carry several
+/// timestamps, lines without any are skipped.
+pub fn parse_lrc(lrc: &str) -> Vec<LyricLine> {
+    let mut lines = Vec::new();
+    for raw in lrc.lines() {
+        let mut rest = raw.trim();
+        let mut stamps = Vec::new();
+        while let Some(tag) = rest.strip_prefix('[') {
+            let Some((stamp, after)) = tag.split_once(']') else {
+                break;
+            };
+            match parse_lrc_timestamp(stamp) {
+                Some(at) => stamps.push(at),
+                // Metadata tags like [ar:Artist]
+                None if stamps.is_empty() => {}
+                None => break,
+            }
+            rest = after;
+        }
+        let text = rest.trim();
+        lines.extend(stamps.into_iter().map(|at| LyricLine {
+            at,
+            text: text.to_string(),
+        }));
+    }
+    lines.sort_by_key(|l| l.at);
+    lines
+}
+
+fn parse_lrc_timestamp(stamp: &str) -> Option<Duration> {
+    let (minutes, seconds) = stamp.split_once(':')?;
+    let minutes: u64 = minutes.parse().ok()?;
+    let seconds: f64 = seconds.parse().ok()?;
+    if !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    Some(Duration::from_secs(minutes * 60) + Duration::from_secs_f64(seconds))
+}
+
+/// Lyrics lookups saved on disk, one JSON file per track id.
+/// Tracks the provider had nothing for are cached too so they aren't asked for again.
+#[derive(Clone)]
+pub struct LyricsCache {
+    dir: PathBuf,
+}
+
+impl LyricsCache {
+    pub fn new(dir: impl Into<PathBuf>) -> LyricsCache {
+        LyricsCache { dir: dir.into() }
+    }
+
+    fn path(&self, track_id: &str) -> PathBuf {
+        self.dir.join(format!("{track_id}.json"))
+    }
+
+    /// `None` when the track was never looked up, or its file can't be
+    /// read, e.g. one from a newer build.
+    pub fn get(&self, track_id: &str) -> Option<Option<Lyrics>> {
+        let path = self.path(track_id);
+        let data = fs::read_to_string(&path).ok()?;
+        LYRICS_CACHE_FORMAT.parse(&data, &path).ok()
+    }
+
+    pub fn put(&self, track_id: &str, lyrics: Option<&Lyrics>) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(track_id), LYRICS_CACHE_FORMAT.to_string(&lyrics)?)?;
+        Ok(())
+    }
+}
+
+/// Cached lookup, provider errors are logged and read as no lyrics.
+pub fn fetch_lyrics(
+    provider: &dyn LyricsProvider,
+    cache: &LyricsCache,
+    track_id: &str,
+    query: &LyricsQuery,
+) -> Option<Lyrics> {
+    if let Some(cached) = cache.get(track_id) {
+        debug!("Lyrics for {track_id} found in the cache");
+        return cached;
+    }
+    match provider.lookup(query) {
+        Ok(lyrics) => {
+            if let Err(e) = cache.put(track_id, lyrics.as_ref()) {
+                warn!("Failed to cache lyrics for {track_id}: {e}");
+            }
+            lyrics
+        }
+        Err(e) => {
+            warn!("Lyrics lookup for {} failed: {e}", query.title);
+            None
+        }
+    }
+}
+
+/// Lyrics shown alongside `watch`. Lookups run on a background thread when the
+/// track changes, so a slow provider never holds up polling. Without lyrics
+/// the pane simply shows nothing.
+pub struct LyricsPane {
+    provider: Arc<dyn LyricsProvider>,
+    cache: LyricsCache,
+    pending: Option<Receiver<Option<Lyrics>>>,
+    lyrics: Option<Lyrics>,
+    clock: Option<PlaybackExtrapolator>,
+    shown: Option<usize>,
+}
+
+impl LyricsPane {
+    pub fn new(provider: Arc<dyn LyricsProvider>, cache: LyricsCache) -> LyricsPane {
+        LyricsPane {
+            provider,
+            cache,
+            pending: None,
+            lyrics: None,
+            clock: None,
+            shown: None,
+        }
+    }
+
+    /// Drops the previous track's lyrics and starts looking up the new ones.
+    pub fn track_changed(&mut self, track: &Track) {
+        let (tx, rx) = mpsc::channel();
+        let provider = Arc::clone(&self.provider);
+        let cache = self.cache.clone();
+        let track_id = track.id.clone();
+        let query = LyricsQuery::from_track(track);
+        thread::spawn(move || {
+            let _ = tx.send(fetch_lyrics(provider.as_ref(), &cache, &track_id, &query));
+        });
+
+        self.pending = Some(rx);
+        self.lyrics = None;
+        self.shown = None;
+    }
+
+    /// Drops the lyrics without looking up new ones, e.g. when an episode starts.
+    pub fn clear(&mut self) {
+        self.pending = None;
+        self.lyrics = None;
+        self.shown = None;
+    }
+
+    /// Feeds the progress reported by the latest poll.
+    pub fn update_clock(&mut self, progress_ms: Option<u32>, is_playing: bool, now: Instant) {
+        self.clock = progress_ms.map(|ms| PlaybackExtrapolator::new(ms, None, is_playing, now));
+    }
+
+    /// Feeds the position estimate of the latest poll.
+    pub fn set_clock(&mut self, clock: Option<PlaybackExtrapolator>) {
+        self.clock = clock;
+    }
+
+    /// Text to display when it changed since the last call: the current
+    /// synced line, or the whole plain lyrics once they arrive.
+    pub fn next_line(&mut self, now: Instant) -> Option<String> {
+        if let Some(rx) = &self.pending {
+            match rx.try_recv() {
+                Ok(lyrics) => {
+                    self.lyrics = lyrics;
+                    self.pending = None;
+                }
+                Err(mpsc::TryRecvError::Empty) => return None,
+                Err(mpsc::TryRecvError::Disconnected) => self.pending = None,
+            }
+        }
+
+        match self.lyrics.as_ref()? {
+            Lyrics::Plain(text) => {
+                if self.shown.is_some() {
+                    return None;
+                }
+                self.shown = Some(0);
+                Some(text.clone())
+            }
+            lyrics @ Lyrics::Synced(lines) => {
+                let index = lyrics.line_index_at(self.clock?.progress_at(now))?;
+                if self.shown == Some(index) {
+                    return None;
+                }
+                self.shown = Some(index);
+                Some(lines[index].text.clone())
+            }
+        }
+    }
+}
+
+/// Lyrics from the LRCLIB public API, https://lrclib.net/docs
+/// LRCLIB matches on artist, title, album and duration, it has no ISRC lookup.
+#[cfg(feature = "lyrics")]
+pub struct LrclibProvider {
+    base_url: String,
+    http_client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "lyrics")]
+const LRCLIB_URL: &str = "https://lrclib.net";
+
+#[cfg(feature = "lyrics")]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LrclibResponse {
+    #[serde(default)]
+    instrumental: bool,
+    plain_lyrics: Option<String>,
+    synced_lyrics: Option<String>,
+}
+
+#[cfg(feature = "lyrics")]
+impl LrclibResponse {
+    fn into_lyrics(self) -> Option<Lyrics> {
+        if self.instrumental {
+            return None;
+        }
+        let synced = self.synced_lyrics.map(|lrc| parse_lrc(&lrc));
+        match (synced, self.plain_lyrics) {
+            (Some(lines), _) if !lines.is_empty() => Some(Lyrics::Synced(lines)),
+            (_, Some(plain)) if !plain.trim().is_empty() => Some(Lyrics::Plain(plain)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "lyrics")]
+impl Default for LrclibProvider {
+    fn default() -> Self {
+        LrclibProvider::with_base_url(LRCLIB_URL)
+    }
+}
+
+#[cfg(feature = "lyrics")]
+impl LrclibProvider {
+    pub fn new() -> LrclibProvider {
+        LrclibProvider::default()
+    }
+
+    pub fn with_base_url(base_url: &str) -> LrclibProvider {
+        LrclibProvider {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: reqwest::blocking::Client::new(),
+        }
+    }
+}
+
+#[cfg(feature = "lyrics")]
+impl LyricsProvider for LrclibProvider {
+    fn lookup(&self, query: &LyricsQuery) -> Result<Option<Lyrics>> {
+        let duration = query.duration.as_secs().to_string();
+        let response = self
+            .http_client
+            .get(format!("{}/api/get", self.base_url))
+            .query(&[
+                ("artist_name", query.artist.as_str()),
+                ("track_name", query.title.as_str()),
+                ("album_name", query.album.as_str()),
+                ("duration", duration.as_str()),
+            ])
+            .send()?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response: LrclibResponse = response.error_for_status()?.json()?;
+        Ok(response.into_lyrics())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::sample_track;
+    use anyhow::bail;
+
+    const LRC: &str = "[ar:Pierce The Veil]\n\
+        [00:01.50] First line\n\
+        [00:04.00][00:12.25] Chorus\n\
+        not a lyric line\n\
+        [00:08.00] Second line\n";
+
+    struct FixedProvider(Option<Lyrics>);
+
+    impl LyricsProvider for FixedProvider {
+        fn lookup(&self, _query: &LyricsQuery) -> Result<Option<Lyrics>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    struct FailingProvider;
+
+    impl LyricsProvider for FailingProvider {
+        fn lookup(&self, _query: &LyricsQuery) -> Result<Option<Lyrics>> {
+            bail!("provider is down")
+        }
+    }
+
+    fn temp_cache(name: &str) -> LyricsCache {
+        let dir = std::env::temp_dir().join(format!("lyrics-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        LyricsCache::new(dir)
+    }
+
+    /// Waits for the background lookup to land.
+    fn first_line(pane: &mut LyricsPane, now: Instant) -> Option<String> {
+        for _ in 0..100 {
+            if pane.pending.is_none() {
+                break;
+            }
+            if let Some(line) = pane.next_line(now) {
+                return Some(line);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        pane.next_line(now)
+    }
+
+    #[test]
+    fn test_parse_lrc() {
+        let lines = parse_lrc(LRC);
+        let texts: Vec<&str> = lines.iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(texts, ["First line", "Chorus", "Second line", "Chorus"]);
+        assert_eq!(lines[0].at, Duration::from_millis(1500));
+        assert_eq!(lines[3].at, Duration::from_millis(12250));
+
+        let lyrics = Lyrics::Synced(lines);
+        assert_eq!(lyrics.line_index_at(Duration::from_secs(1)), None);
+        assert_eq!(lyrics.line_index_at(Duration::from_secs(5)), Some(1));
+        assert_eq!(lyrics.line_index_at(Duration::from_secs(60)), Some(3));
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let cache = temp_cache("cache");
+        assert_eq!(cache.get("abc"), None);
+        cache.put("abc", None).unwrap();
+        assert_eq!(cache.get("abc"), Some(None));
+
+        // Lookups cached before the state file versions
+        let old = Lyrics::Plain("old".to_string());
+        fs::write(cache.path("old"), serde_json::to_string(&old).unwrap()).unwrap();
+        assert_eq!(cache.get("old"), Some(Some(old)));
+        fs::write(cache.path("newer"), r#"{"v": 3, "data": null}"#).unwrap();
+        assert_eq!(cache.get("newer"), None);
+
+        let lyrics = Lyrics::Plain("la la la".to_string());
+        let provider = FixedProvider(Some(lyrics.clone()));
+        let query = LyricsQuery::from_track(&sample_track());
+        assert_eq!(
+            fetch_lyrics(&provider, &cache, "def", &query),
+            Some(lyrics.clone())
+        );
+        // Served from the cache even once the provider fails
+        assert_eq!(
+            fetch_lyrics(&FailingProvider, &cache, "def", &query),
+            Some(lyrics)
+        );
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn test_pane_follows_playback_clock() {
+        let cache = temp_cache("pane");
+        let provider = Arc::new(FixedProvider(Some(Lyrics::Synced(parse_lrc(LRC)))));
+        let mut pane = LyricsPane::new(provider, cache.clone());
+        let start = Instant::now();
+
+        pane.track_changed(&sample_track());
+        pane.update_clock(Some(2_000), true, start);
+        assert_eq!(first_line(&mut pane, start).as_deref(), Some("First line"));
+        assert_eq!(pane.next_line(start), None);
+        // No poll since, the clock keeps running
+        let later = start + Duration::from_secs(3);
+        assert_eq!(pane.next_line(later).as_deref(), Some("Chorus"));
+
+        // Paused, the position stays put
+        pane.update_clock(Some(5_000), false, later);
+        assert_eq!(pane.next_line(later + Duration::from_secs(30)), None);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn test_pane_degrades_without_lyrics() {
+        let cache = temp_cache("degrade");
+        let mut pane = LyricsPane::new(Arc::new(FailingProvider), cache.clone());
+        let now = Instant::now();
+        pane.track_changed(&sample_track());
+        pane.update_clock(Some(2_000), true, now);
+        assert_eq!(first_line(&mut pane, now), None);
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+
+    #[cfg(feature = "lyrics")]
+    #[test]
+    fn test_lrclib_response() {
+        let data = std::fs::read_to_string("sample_data/lrclib_get.json").unwrap();
+        let response: LrclibResponse = serde_json::from_str(&data).unwrap();
+        match response.into_lyrics() {
+            Some(Lyrics::Synced(lines)) => assert_eq!(lines[0].text, "Now we're on the ground"),
+            other => panic!("expected synced lyrics, got {other:?}"),
+        }
+
+        let instrumental: LrclibResponse = serde_json::from_str(
+            r#"{"instrumental": true, "plainLyrics": null, "syncedLyrics": null}"#,
+        )
+        .unwrap();
+        assert_eq!(instrumental.into_lyrics(), None);
+    }
+}
@@ -1,29 +1,4211 @@
-use anyhow::Result;
-use spotify_rs::spotify_api::SpotifyClient;
-use tracing::{info, warn, Level};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+#[cfg(feature = "charts")]
+use clap::ValueEnum;
+use clap::{Args, Parser, Subcommand};
+use spotify_rs::analysis_cache::{AnalysisCache, DEFAULT_ANALYSIS_CACHE_DIR};
+use spotify_rs::browser::default_opener;
+use spotify_rs::capture::{CaptureConfig, DEFAULT_MAX_CAPTURES};
+#[cfg(feature = "charts")]
+use spotify_rs::charts::{self, Chart};
+use spotify_rs::control::{
+    ControlChannel, ControlCommand, DEFAULT_COMMAND_MAX_AGE, DEFAULT_CONTROL_DIR,
+};
+use spotify_rs::control_socket::{
+    self, default_socket_path, ControlServer, DaemonStatus, Response,
+};
+use spotify_rs::device_aliases::{DeviceAliases, DEFAULT_DEVICE_ALIASES_FILE};
+#[cfg(feature = "email")]
+use spotify_rs::email::{
+    Email, EmailOutbox, EmailSender, SmtpConfig, SmtpSecurity, DEFAULT_EMAIL_OUTBOX_FILE,
+    SMTP_PASSWORD_KEY,
+};
+use spotify_rs::error::{
+    AlreadyRunning, AuthorizationDenied, MissingScopes, NewerStateFile, SpotifyError, StorageError,
+};
+use spotify_rs::history::Confidence;
+use spotify_rs::history::{HistoryStore, PlayHistoryEntry, DEFAULT_HISTORY_FILE};
+use spotify_rs::instance_lock::{lock_path_for, InstanceLock, DEFAULT_TAKEOVER_TIMEOUT};
+use spotify_rs::journal::{journal_path_for, PlayJournal, DEFAULT_JOURNAL_INTERVAL};
+use spotify_rs::library_import::{import_state_path_for, LibraryImport};
+use spotify_rs::library_snapshot::{
+    snapshot_state_path_for, LibrarySnapshot, SectionProgress, SnapshotRun, SnapshotSection,
+    DEFAULT_SNAPSHOT_FILE,
+};
+use spotify_rs::local_store::{
+    adopt_local_files, default_config_dir, load_detected_user, migrate_local_files,
+    save_detected_user, write_private, CredStorage, Migration,
+};
+use spotify_rs::locale::Locale;
+use spotify_rs::log_throttle::{LogAction, LogThrottle, DEFAULT_SUMMARY_INTERVAL};
+use spotify_rs::lyrics::LyricsPane;
+#[cfg(feature = "lyrics")]
+use spotify_rs::lyrics::{LrclibProvider, LyricsCache, DEFAULT_LYRICS_CACHE_DIR};
+#[cfg(feature = "mqtt")]
+use spotify_rs::mqtt::{
+    MqttConfig, MqttSink, Outbox, Topics, DEFAULT_DISCOVERY_PREFIX, DEFAULT_MQTT_OUTBOX_FILE,
+    DEFAULT_TOPIC_ROOT, MQTT_PASSWORD_KEY,
+};
+#[cfg(feature = "desktop-notify")]
+use spotify_rs::notifications::DesktopNotifier;
+use spotify_rs::now_cache::{NowCache, DEFAULT_NOW_CACHE_FILE};
+use spotify_rs::picker::{self, PickAction, PickItem};
+use spotify_rs::redact::mask_secret;
+#[cfg(feature = "email")]
+use spotify_rs::report::{ListeningReport, ReportSchedule};
+use spotify_rs::search::search;
+#[cfg(feature = "songlink")]
+use spotify_rs::share::SongLinkResolver;
+use spotify_rs::share::{share_template, Share, DEFAULT_SHARE_TEMPLATE};
+use spotify_rs::spotify_api::{
+    Capability, SeenHeader, SpotifyClient, SpotifyClientBuilder, UserAuthData,
+    DEFAULT_REDIRECT_URI, DEFAULT_REFRESH_MARGIN, MAX_SEARCH_LIMIT,
+};
+use spotify_rs::spotify_data::{
+    CurrentlyPlayingTrack, PlayContext, PlayingItem, PlayingType, Track, UserProfile,
+};
+use spotify_rs::stats::{
+    context_stats, device_stats, discoveries, episode_completion, last_days_start,
+    listening_by_hour, on_this_day, plays_per_day, skip_points, tag_stats, top_artists,
+    Discoveries, ListeningStats, OnThisDay, SkipPoints,
+};
+#[cfg(feature = "sync")]
+use spotify_rs::sync::{load_sync_token, SyncClient, SyncServer, SYNC_PLAYS_PATH};
+use spotify_rs::table::{use_color, Align, Table};
+use spotify_rs::tracker::{reconcile, Listen, PlayTracker};
+use spotify_rs::watch_view::{render_up_next, WatchView};
+use spotify_rs::watcher::{PlaybackExtrapolator, WatchEvent, Watcher, DEFAULT_INDETERMINATE_LIMIT};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+#[cfg(feature = "email")]
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+#[cfg(feature = "lyrics")]
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn, Level};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+
+#[cfg(not(any(feature = "blocking", feature = "async")))]
+compile_error!("spotify-rs needs the blocking or the async feature");
+
+/// Waits for a client call. The async client is driven on a tokio runtime,
+/// the blocking one already returns the result.
+#[cfg(feature = "blocking")]
+macro_rules! wait {
+    ($call:expr) => {
+        $call
+    };
+}
+
+#[cfg(not(feature = "blocking"))]
+macro_rules! wait {
+    ($call:expr) => {
+        runtime().block_on($call)
+    };
+}
+
+/// One runtime for the whole run, the client's connections live on it.
+#[cfg(not(feature = "blocking"))]
+fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("the tokio runtime should start")
+    })
+}
 
 const USER: &str = "jorge";
+/// Holds the PKCE verifier between `auth` and `auth --redirect-url`
+const AUTH_PENDING_FILE: &str = "auth_pending.json";
+/// How often the lyrics line is refreshed between polls
+const LYRICS_TICK: Duration = Duration::from_millis(250);
+/// How often the daemon checks its control socket between polls
+const CONTROL_TICK: Duration = Duration::from_millis(100);
+/// How long the daemon waits before sending a report that failed again
+#[cfg(feature = "email")]
+const EMAIL_RETRY_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Parser)]
+#[command(about = "Tracks what you are listening to on Spotify")]
+struct Cli {
+    /// Write Spotify responses that fail to parse into this directory
+    #[arg(long)]
+    capture_dir: Option<PathBuf>,
+
+    /// Also capture successful responses of an endpoint, e.g. `endpoint=currently-playing`
+    #[arg(long = "capture", value_parser = parse_capture_endpoint, requires = "capture_dir")]
+    capture_endpoints: Vec<String>,
+
+    /// How many captures to keep before pruning the oldest
+    #[arg(long, default_value_t = DEFAULT_MAX_CAPTURES)]
+    max_captures: usize,
+
+    /// Seconds before expiry to refresh the Spotify access token
+    #[arg(long, default_value_t = DEFAULT_REFRESH_MARGIN.as_secs())]
+    refresh_margin: u64,
+
+    /// Fail instead of asking to authorize when the cached Spotify creds don't work
+    #[arg(long)]
+    no_interactive: bool,
+
+    /// Log Spotify request and response bodies, with tokens masked
+    #[arg(long)]
+    log_bodies: bool,
+
+    /// Also ask for the user's email when authorizing, `whoami` shows it
+    #[arg(long)]
+    email_scope: bool,
+
+    /// Also ask to upload images when authorizing, `playlist set-cover` needs it
+    #[arg(long)]
+    image_upload_scope: bool,
+
+    /// Always show Spotify's consent screen when authorizing, to switch accounts
+    #[arg(long)]
+    force_consent: bool,
+
+    /// Only print the authorization URL, don't open it in a browser
+    #[arg(long)]
+    no_browser: bool,
+
+    /// The redirect URI registered for the Spotify app, a custom scheme like
+    /// myapp://callback works too. Pass the URL it redirected to to `auth`
+    #[arg(long, default_value = DEFAULT_REDIRECT_URI, value_parser = parse_redirect_uri)]
+    redirect_uri: String,
+
+    /// The Spotify user id the creds are stored under. The one `auth
+    /// --detect-user` found by default
+    #[arg(long, global = true)]
+    user: Option<String>,
+
+    /// Print tables without colors, also honours the NO_COLOR env var
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Print the full chain of causes when something fails
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    /// IANA time zone reports group plays in, e.g. Europe/Stockholm. The
+    /// system's local time zone by default
+    #[arg(long, global = true, value_parser = parse_time_zone)]
+    time_zone: Option<Tz>,
+
+    /// Language of the numbers, durations and dates in reports, e.g. de-DE.
+    /// English, German and Swedish are known, others fall back to English
+    #[arg(long, global = true, default_value = "en-US", value_parser = parse_locale)]
+    locale: Locale,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the currently playing track, the default
+    Now {
+        #[command(flatten)]
+        grace: GraceArgs,
+    },
+    /// Print a ready to paste snippet of the currently playing track
+    Share {
+        /// Placeholders: {title}, {artists}, {album}, {spotify_url} and {link},
+        /// the song.link page when resolved or else the Spotify one
+        #[arg(long, default_value = DEFAULT_SHARE_TEMPLATE)]
+        template: String,
+        /// Link a song.link page for every streaming service, resolved with Odesli
+        #[cfg(feature = "songlink")]
+        #[arg(long)]
+        songlink: bool,
+        /// Also put the snippet on the system clipboard
+        #[cfg(feature = "clipboard")]
+        #[arg(long)]
+        copy: bool,
+    },
+    /// List the Spotify Connect devices currently available
+    Devices,
+    /// Control playback, on the preferred device when one is set
+    Player {
+        #[command(subcommand)]
+        command: PlayerCommand,
+    },
+    /// Manage the tracks saved to your library
+    Library {
+        #[command(subcommand)]
+        command: LibraryCommand,
+    },
+    /// Search Spotify's catalog for tracks
+    Search {
+        query: String,
+        /// How many tracks to fetch
+        #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=MAX_SEARCH_LIMIT as i64))]
+        limit: u32,
+        #[command(flatten)]
+        pick: PickArgs,
+    },
+    /// List the tracks queued up next
+    Queue {
+        #[command(flatten)]
+        pick: PickArgs,
+    },
+    /// Edit a playlist you own
+    Playlist {
+        #[command(subcommand)]
+        command: PlaylistCommand,
+    },
+    /// Check the local setup, e.g. bitwarden_config.json, before a first run.
+    /// With working creds also shows deprecation and rate limit headers Spotify sends
+    Doctor,
+    /// Copy bitwarden_config.json, app_auth.json and user_auth.json from
+    /// another directory into the config directory. The originals stay,
+    /// newer files already there are kept
+    Migrate {
+        /// Directory the files were left in, e.g. where the tool used to run
+        #[arg(long)]
+        from: PathBuf,
+    },
+    /// Authorize with Spotify without prompting. Run it once to get the URL to
+    /// open, then again with the URL the browser was redirected to
+    Auth {
+        /// The full URL Spotify redirected to
+        #[arg(long, conflicts_with = "redirect_file")]
+        redirect_url: Option<String>,
+        /// File holding the URL Spotify redirected to
+        #[arg(long)]
+        redirect_file: Option<PathBuf>,
+        /// Also ask for this scope, the ones granted before are kept
+        #[arg(long = "add-scope")]
+        add_scopes: Vec<String>,
+        /// Once authorized, move the creds to the id Spotify knows you by
+        #[arg(long)]
+        detect_user: bool,
+    },
+    /// Show the stored Spotify auth state for debugging, the tokens are masked
+    TokenInfo,
+    /// Whether the access token can still be used, going by its expiry.
+    /// Fails when it can't, e.g. for scripts
+    Status {
+        /// Ask Spotify with one `/me` call instead of trusting the expiry,
+        /// e.g. right after importing a token from another tool
+        #[arg(long)]
+        check: bool,
+    },
+    /// Print the configuration in effect as JSON, secrets are masked
+    Config,
+    /// Show the Spotify profile of the authorized user
+    Whoami,
+    /// Keep polling the player and report changes
+    Watch {
+        /// Seconds between polls
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+        /// Polls without a playing item to wait through before reporting a stop
+        #[arg(long, default_value_t = DEFAULT_INDETERMINATE_LIMIT)]
+        indeterminate_polls: u32,
+        /// Don't report audiobook chapters starting
+        #[arg(long)]
+        skip_chapters: bool,
+        /// Seconds an item has to keep playing before its change is reported,
+        /// items skipped past sooner are not reported at all
+        #[arg(long, default_value_t = 0)]
+        debounce: u64,
+        /// Also show the next items of the queue, fetched again whenever the item changes
+        #[arg(long)]
+        up_next: bool,
+        /// Show a desktop notification with the album cover when the track changes
+        #[cfg(feature = "desktop-notify")]
+        #[arg(long)]
+        notify: bool,
+        #[cfg(feature = "lyrics")]
+        #[command(flatten)]
+        lyrics: LyricsArgs,
+    },
+    /// Keep polling the player and record every play into the history file
+    Daemon {
+        /// Seconds between polls
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+        /// History file plays are appended to
+        #[arg(long, default_value = DEFAULT_HISTORY_FILE)]
+        history: PathBuf,
+        /// Fall back to the queue endpoint when the player reports nothing.
+        /// Plays only seen there are recorded with low confidence
+        #[arg(long)]
+        queue_assisted: bool,
+        /// Directory the daemon picks up commands from where there is no control socket
+        #[arg(long, default_value = DEFAULT_CONTROL_DIR)]
+        control_dir: PathBuf,
+        /// Control socket to listen on, defaults to one in the runtime dir
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Seconds between summaries of a warning that keeps repeating
+        #[arg(long, default_value_t = DEFAULT_SUMMARY_INTERVAL.as_secs())]
+        log_summary_interval: u64,
+        /// File the play in progress is saved to, so it survives a crash.
+        /// Next to the history file by default
+        #[arg(long)]
+        journal: Option<PathBuf>,
+        /// Seconds between saves of the play in progress
+        #[arg(long, default_value_t = DEFAULT_JOURNAL_INTERVAL.as_secs())]
+        journal_interval: u64,
+        /// Sync every journal write to disk, survives a power loss too
+        #[arg(long)]
+        durable: bool,
+        /// File the last currently playing answer is kept in, for `now --grace`
+        #[arg(long, default_value = DEFAULT_NOW_CACHE_FILE)]
+        now_cache: PathBuf,
+        /// Lock file keeping a second daemon off the same history. Next to
+        /// the history file by default
+        #[arg(long)]
+        lock_file: Option<PathBuf>,
+        /// Ask the daemon holding the lock to shut down and take over once it has
+        #[arg(long)]
+        takeover: bool,
+        #[cfg(feature = "mqtt")]
+        #[command(flatten)]
+        mqtt: MqttArgs,
+        /// Email the report of the last 7 days every week at this local
+        /// time, e.g. "mon 08:00". Needs --smtp-host
+        #[cfg(feature = "email")]
+        #[arg(long, requires = "smtp_host", value_parser = parse_report_schedule)]
+        email_report_at: Option<ReportSchedule>,
+        #[cfg(feature = "email")]
+        #[command(flatten)]
+        email: EmailArgs,
+    },
+    /// Tag the play in progress, e.g. `tag focus`. Needs the daemon running
+    Tag {
+        label: String,
+        /// Control directory of the running daemon, used where there is no control socket
+        #[arg(long, default_value = DEFAULT_CONTROL_DIR)]
+        control_dir: PathBuf,
+        /// Control socket of the running daemon
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+    /// Talk to the running daemon through its control socket
+    Ctl {
+        /// Control socket of the running daemon
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Summaries of the recorded listening history, works offline but for
+    /// fetching audio analyses
+    Stats {
+        /// History file written by the daemon
+        #[arg(long, default_value = DEFAULT_HISTORY_FILE)]
+        history: PathBuf,
+        /// Only count plays from the last this many calendar days, today included
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        days: Option<u64>,
+        #[command(flatten)]
+        devices: DeviceFilter,
+        #[command(subcommand)]
+        command: StatsCommand,
+    },
+    /// Listening reports, sent by email
+    #[cfg(feature = "email")]
+    Report {
+        #[command(subcommand)]
+        command: ReportCommand,
+    },
+    /// Look through the recorded listening history, works offline
+    History {
+        /// History file written by the daemon
+        #[arg(long, default_value = DEFAULT_HISTORY_FILE)]
+        history: PathBuf,
+        #[command(flatten)]
+        devices: DeviceFilter,
+        #[command(subcommand)]
+        command: HistoryCommand,
+    },
+}
+
+#[cfg(feature = "lyrics")]
+#[derive(Args)]
+struct LyricsArgs {
+    /// Show the lyrics line being sung, looked up on LRCLIB
+    #[arg(long)]
+    lyrics: bool,
+    /// Directory looked up lyrics are cached in
+    #[arg(long, default_value = DEFAULT_LYRICS_CACHE_DIR)]
+    lyrics_cache: PathBuf,
+}
+
+#[cfg(feature = "lyrics")]
+impl LyricsArgs {
+    fn pane(self) -> Option<LyricsPane> {
+        self.lyrics.then(|| {
+            LyricsPane::new(
+                Arc::new(LrclibProvider::new()),
+                LyricsCache::new(self.lyrics_cache),
+            )
+        })
+    }
+}
+
+#[cfg(feature = "mqtt")]
+#[derive(Args)]
+struct MqttArgs {
+    /// MQTT broker to publish now playing and every play to, e.g. for Home Assistant
+    #[arg(long)]
+    mqtt_host: Option<String>,
+    #[arg(long, default_value_t = 1883)]
+    mqtt_port: u16,
+    /// Connect to the broker with TLS
+    #[arg(long)]
+    mqtt_tls: bool,
+    /// User to log into the broker as, the password is the `mqtt_password`
+    /// secret next to the Spotify creds
+    #[arg(long)]
+    mqtt_username: Option<String>,
+    /// Topics go under <root>/<user>
+    #[arg(long, default_value = DEFAULT_TOPIC_ROOT)]
+    mqtt_topic_root: String,
+    /// Where Home Assistant looks for discovery messages
+    #[arg(long, default_value = DEFAULT_DISCOVERY_PREFIX)]
+    mqtt_discovery_prefix: String,
+    /// File messages wait in while the broker can't be reached
+    #[arg(long, default_value = DEFAULT_MQTT_OUTBOX_FILE)]
+    mqtt_outbox: PathBuf,
+}
+
+#[cfg(feature = "mqtt")]
+impl MqttArgs {
+    /// The sink publishing for the client's user, None without a broker.
+    fn sink(self, spotify: &SpotifyClient) -> Result<Option<MqttSink>> {
+        let Some(host) = self.mqtt_host else {
+            return Ok(None);
+        };
+        let password = match (&self.mqtt_username, spotify.creds_storage()) {
+            (Some(_), Some(storage)) => Some(
+                wait!(storage.load_secret(MQTT_PASSWORD_KEY))
+                    .map_err(|e| anyhow!("No {MQTT_PASSWORD_KEY} secret for the broker: {e}"))?,
+            ),
+            _ => None,
+        };
+        let mut topics = Topics::new(spotify.user_id());
+        topics.root = self.mqtt_topic_root;
+        topics.discovery_prefix = self.mqtt_discovery_prefix;
+        let outbox = Outbox::open(self.mqtt_outbox)?;
+        if !outbox.is_empty() {
+            info!("{} MQTT messages are left from the last run", outbox.len());
+        }
+        let config = MqttConfig {
+            host,
+            port: self.mqtt_port,
+            tls: self.mqtt_tls,
+            username: self.mqtt_username,
+            password,
+            topics,
+        };
+        Ok(Some(MqttSink::connect(config, outbox)))
+    }
+}
+
+#[cfg(feature = "email")]
+#[derive(Subcommand)]
+enum ReportCommand {
+    /// Email the report of the last days now. One that can't be sent is
+    /// kept in the outbox and goes out with the next one
+    Send {
+        /// Days the report covers, today included, e.g. 7d or 2w
+        #[arg(long, default_value = "7d", value_parser = parse_range)]
+        range: u64,
+        /// History file written by the daemon
+        #[arg(long, default_value = DEFAULT_HISTORY_FILE)]
+        history: PathBuf,
+        /// Print the email instead of sending it
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        email: EmailArgs,
+    },
+}
+
+#[cfg(feature = "email")]
+#[derive(Args)]
+struct EmailArgs {
+    /// SMTP server reports are sent through
+    #[arg(long)]
+    smtp_host: Option<String>,
+    #[arg(long, default_value_t = 587)]
+    smtp_port: u16,
+    /// starttls, tls or none
+    #[arg(long, default_value = "starttls", value_parser = parse_smtp_security)]
+    smtp_security: SmtpSecurity,
+    /// User to log into the server as, the password is the `smtp_password`
+    /// secret next to the Spotify creds
+    #[arg(long)]
+    smtp_username: Option<String>,
+    /// Sender of the reports, e.g. "Music tracker <tracker@example.com>"
+    #[arg(long)]
+    email_from: Option<String>,
+    /// Recipient of the reports, repeat it for more
+    #[arg(long)]
+    email_to: Vec<String>,
+    /// File reports wait in while they can't be sent
+    #[arg(long, default_value = DEFAULT_EMAIL_OUTBOX_FILE)]
+    email_outbox: PathBuf,
+}
+
+#[cfg(feature = "email")]
+impl EmailArgs {
+    /// Where and to whom reports go, the password is read from `storage`
+    /// when there is a user to log in as.
+    fn config(&self, storage: Option<&CredStorage>) -> Result<SmtpConfig> {
+        let Some(host) = &self.smtp_host else {
+            bail!("reports need an SMTP server, set --smtp-host");
+        };
+        let Some(from) = &self.email_from else {
+            bail!("reports need a sender, set --email-from");
+        };
+        let password = match (&self.smtp_username, storage) {
+            (Some(_), Some(storage)) => Some(
+                wait!(storage.load_secret(SMTP_PASSWORD_KEY))
+                    .map_err(|e| anyhow!("No {SMTP_PASSWORD_KEY} secret for the server: {e}"))?,
+            ),
+            _ => None,
+        };
+        Ok(SmtpConfig {
+            host: host.clone(),
+            port: self.smtp_port,
+            security: self.smtp_security,
+            username: self.smtp_username.clone(),
+            password,
+            from: from.clone(),
+            to: self.email_to.clone(),
+        })
+    }
+
+    fn sender(&self, storage: Option<&CredStorage>) -> Result<EmailSender> {
+        let outbox = EmailOutbox::open(&self.email_outbox)?;
+        if !outbox.is_empty() {
+            info!("{} reports are left from the last run", outbox.len());
+        }
+        EmailSender::new(self.config(storage)?, outbox)
+    }
+}
+
+#[derive(Args)]
+struct GraceArgs {
+    /// When Spotify can't be reached, print what the daemon last saw instead,
+    /// marked stale, if it is at most this many seconds old
+    #[arg(long)]
+    grace: Option<u64>,
+    /// File the daemon keeps the last currently playing answer in
+    #[arg(long, default_value = DEFAULT_NOW_CACHE_FILE)]
+    now_cache: PathBuf,
+}
+
+impl GraceArgs {
+    /// Prints the cached answer instead of failing with `err`, when grace is
+    /// on, `err` is an outage and the answer isn't older than it allows.
+    /// Anything else, like revoked creds, needs the user and still fails.
+    fn serve_stale(&self, err: anyhow::Error) -> Result<()> {
+        let Some(grace) = self.grace else {
+            return Err(err);
+        };
+        if !is_outage(&err) {
+            return Err(err);
+        }
+        let now = SystemTime::now();
+        let cache = NowCache::new(&self.now_cache);
+        match cache.fresh(now, Duration::from_secs(grace)) {
+            Ok(Some(cached)) => {
+                warn!("Spotify can't be reached, showing the last answer: {err:#}");
+                let age = cached.age(now);
+                print_playing(cached.playing, Some(age));
+                Ok(())
+            }
+            Ok(None) => Err(err),
+            Err(e) => {
+                warn!("Could not read the now playing cache: {e:#}");
+                Err(err)
+            }
+        }
+    }
+}
+
+#[derive(Args)]
+struct PickArgs {
+    /// Pick one of the tracks and print its URI. Without a terminal the
+    /// choice is read from stdin, e.g. `echo 2 | spotify-rs search heroes --pick`
+    #[arg(long)]
+    pick: bool,
+    /// What to do with the picked track: queue, like, playlist:<ID> or open.
+    /// Implies --pick
+    #[arg(long, value_name = "ACTION", value_parser = PickAction::parse)]
+    then: Option<PickAction>,
+}
+
+impl PickArgs {
+    fn capabilities(&self) -> Vec<Capability> {
+        self.then
+            .as_ref()
+            .map(PickAction::capabilities)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Subcommand)]
+enum PlayerCommand {
+    Pause,
+    Resume,
+    Next,
+    Previous,
+    /// Send playback commands to this device from now on, see `devices` for ids
+    UseDevice {
+        device_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum LibraryCommand {
+    /// Save every track of the playlists that isn't saved yet. An
+    /// interrupted run continues where it stopped when run again
+    ImportFromPlaylists {
+        #[arg(required = true)]
+        playlists: Vec<String>,
+        /// Only tell how many tracks would be saved
+        #[arg(long)]
+        dry_run: bool,
+        /// File the tracks left to save are kept in, one in the temp dir by default
+        #[arg(long)]
+        state: Option<PathBuf>,
+    },
+    /// Write the saved tracks and albums, the followed artists and the
+    /// playlists to one JSON snapshot, with a manifest of what it took. An
+    /// interrupted run continues where it stopped when run again
+    Snapshot {
+        /// Where to write the snapshot
+        #[arg(long, default_value = DEFAULT_SNAPSHOT_FILE)]
+        out: PathBuf,
+        /// Only these sections: saved-tracks, saved-albums, followed-artists
+        /// or playlists
+        #[arg(long, value_delimiter = ',', value_parser = parse_snapshot_section)]
+        only: Vec<SnapshotSection>,
+        /// Leave these sections out
+        #[arg(long, value_delimiter = ',', value_parser = parse_snapshot_section)]
+        skip: Vec<SnapshotSection>,
+        /// File the progress is kept in, next to the snapshot by default
+        #[arg(long)]
+        state: Option<PathBuf>,
+    },
+    /// List the saved tracks that can't be played in your market and why,
+    /// logging the ones that changed since the last audit with the history
+    Audit {
+        /// History file whose availability log the changes go to
+        #[arg(long, default_value = DEFAULT_HISTORY_FILE)]
+        history: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum PlaylistCommand {
+    /// Remove every occurrence of the given tracks
+    Remove {
+        playlist: String,
+        /// Track URI to remove, e.g. spotify:track:4iV5W9uYEdYUVa79Axb7Rh
+        #[arg(long = "uri", required = true)]
+        uris: Vec<String>,
+        /// Fail if the playlist changed since this snapshot
+        #[arg(long)]
+        snapshot: Option<String>,
+    },
+    /// Move items so the first ends up at position `to`, positions start at 0
+    Move {
+        playlist: String,
+        from: u32,
+        to: u32,
+        /// How many items to move along
+        #[arg(long, default_value_t = 1)]
+        count: u32,
+        /// Fail if the playlist changed since this snapshot
+        #[arg(long)]
+        snapshot: Option<String>,
+    },
+    /// Print the urls of the playlist's cover, largest first
+    Cover { playlist: String },
+    /// Replace the playlist's cover, asks to upload images if that wasn't granted
+    SetCover {
+        playlist: String,
+        /// JPEG to upload, at most 192 KB
+        #[cfg_attr(feature = "charts", arg(long, required_unless_present = "title"))]
+        #[cfg_attr(not(feature = "charts"), arg(long, required = true))]
+        image: Option<PathBuf>,
+        /// Draw a cover with this text instead, `\n` starts a new line
+        #[cfg(feature = "charts")]
+        #[arg(long, conflicts_with = "image")]
+        title: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// What the daemon is doing, answered without asking Spotify
+    Status,
+    /// Keep the daemon running but stop recording plays
+    PauseTracking,
+    ResumeTracking,
+    /// Tag the play in progress
+    Tag {
+        label: String,
+    },
+    /// Load the credentials again, e.g. after re-running `auth`
+    ReloadConfig,
+    /// Record the play in progress and stop the daemon
+    Shutdown,
+}
+
+impl From<CtlCommand> for ControlCommand {
+    fn from(command: CtlCommand) -> Self {
+        match command {
+            CtlCommand::Status => ControlCommand::Status,
+            CtlCommand::PauseTracking => ControlCommand::PauseTracking,
+            CtlCommand::ResumeTracking => ControlCommand::ResumeTracking,
+            CtlCommand::Tag { label } => ControlCommand::Tag { label },
+            CtlCommand::ReloadConfig => ControlCommand::ReloadConfig,
+            CtlCommand::Shutdown => ControlCommand::Shutdown,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum StatsCommand {
+    /// Total plays and listening time split between music and podcasts, with
+    /// the top artists, tracks and shows. `--days 7` makes it a weekly report
+    Summary {
+        /// Print the stats as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Plays and listening time per tag
+    Tags,
+    /// Plays on each day
+    Days,
+    /// Listening time by hour of the day
+    Hours,
+    /// Most played artists
+    Artists {
+        /// Most artists to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// How much of each podcast episode was played, last played first
+    Episodes,
+    /// Plays and listening time on each device
+    Devices,
+    /// How much was played from playlists, albums, artists, Liked Songs or
+    /// autoplay
+    Contexts {
+        /// Print the stats as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// The sections of a track its plays were skipped in, from where the
+    /// daemon saw them skipped. Fetches the track's audio analysis once
+    SkipPoints {
+        track_id: String,
+        /// Directory fetched audio analyses are kept in
+        #[arg(long, default_value = DEFAULT_ANALYSIS_CACHE_DIR)]
+        analysis_cache: PathBuf,
+        /// Print the sections and their skips as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Tracks and artists first played in the range, with nothing of them
+    /// in the history before it. Imported history counts as played
+    Discoveries {
+        /// Days looked at, today included, e.g. 7d or 2w. Ignores --days
+        #[arg(long, default_value = "7d", value_parser = parse_range)]
+        range: u64,
+        /// Add the new tracks to this playlist, e.g. a rolling "Discoveries"
+        /// one. Needs the Spotify client
+        #[arg(long)]
+        playlist: Option<String>,
+        /// Most tracks the playlist keeps, the oldest go first
+        #[arg(long, default_value_t = 100, requires = "playlist")]
+        keep: usize,
+        /// Print the discoveries as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Draw one of the stats as a chart
+    #[cfg(feature = "charts")]
+    Chart {
+        kind: ChartKind,
+        /// Where to write the chart, .svg or .png
+        #[arg(long)]
+        out: PathBuf,
+        /// Most artists to show in the top artists chart
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+    },
+}
+
+#[cfg(feature = "charts")]
+#[derive(Clone, Copy, ValueEnum)]
+enum ChartKind {
+    /// Bar chart of plays per day
+    Days,
+    /// Heatmap of listening time by weekday and hour
+    Hours,
+    /// Bars of the most played artists
+    Artists,
+}
+
+#[derive(Subcommand)]
+enum HistoryCommand {
+    /// Fuzzy search track names, artists and albums
+    Search {
+        query: String,
+        /// Most tracks to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Add the plays of Spotify's account data export, a StreamingHistory or
+    /// endsong JSON file or the directory the export was unpacked into
+    Import { path: PathBuf },
+    /// Add the plays of another machine's history file made since the last
+    /// merge from it, e.g. a copy of its history.jsonl
+    Merge {
+        #[arg(long)]
+        from: PathBuf,
+    },
+    /// Exchange plays with another machine's daemon over HTTP, both sides
+    /// need the same token in sync_token in the config directory
+    #[cfg(feature = "sync")]
+    Sync {
+        #[command(subcommand)]
+        command: SyncCommand,
+    },
+    /// Tracks the daemon saw turn unplayable or playable again, and why
+    Availability,
+    /// What was played on this calendar day in earlier years
+    OnThisDay {
+        /// How many years to look back
+        #[arg(long, default_value_t = 10)]
+        years_back: u32,
+        /// Look back from this day instead of today, e.g. 2023-06-01
+        #[arg(long)]
+        date: Option<NaiveDate>,
+        /// Most tracks to show for each year
+        #[arg(long, default_value_t = 5)]
+        limit: usize,
+        /// Print the plays as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[cfg(feature = "sync")]
+#[derive(Subcommand)]
+enum SyncCommand {
+    /// Answer the pushes and pulls of other machines until stopped
+    Serve {
+        #[arg(long, default_value = "0.0.0.0:7878")]
+        listen: String,
+    },
+    /// Send the plays recorded here since the last push to the remote
+    Push {
+        /// The other machine's `history sync serve`, e.g. http://nas:7878
+        #[arg(long)]
+        remote: String,
+    },
+    /// Add the plays the remote recorded since the last pull
+    Pull {
+        /// The other machine's `history sync serve`, e.g. http://nas:7878
+        #[arg(long)]
+        remote: String,
+    },
+}
+
+#[derive(Args)]
+struct DeviceFilter {
+    /// Only plays on this device, a label from the aliases file or a device name
+    #[arg(long)]
+    device: Option<String>,
+    /// JSON object mapping device names to labels, e.g. {"Pixel 7": "Phone"}
+    #[arg(long, default_value = DEFAULT_DEVICE_ALIASES_FILE)]
+    device_aliases: PathBuf,
+}
+
+impl DeviceFilter {
+    /// The aliases, and the history narrowed down to the device if one was given.
+    fn apply(&self, entries: &mut Vec<PlayHistoryEntry>) -> Result<DeviceAliases> {
+        let aliases = DeviceAliases::load(&self.device_aliases)?;
+        if let Some(device) = &self.device {
+            entries.retain(|entry| aliases.played_on(entry, device));
+        }
+        Ok(aliases)
+    }
+}
+
+impl Command {
+    /// What the command needs of Spotify, an authorization only asks for
+    /// their scopes and those of reading the player.
+    fn capabilities(&self) -> Vec<Capability> {
+        match self {
+            Command::Player { .. } => vec![Capability::ControlPlayback],
+            Command::Library {
+                command: LibraryCommand::Snapshot { .. },
+            } => vec![
+                Capability::ReadPlaylists,
+                Capability::ReadLibrary,
+                Capability::Following,
+            ],
+            Command::Library {
+                command: LibraryCommand::Audit { .. },
+            } => vec![Capability::ReadLibrary],
+            Command::Library { .. } => vec![
+                Capability::ReadPlaylists,
+                Capability::ReadLibrary,
+                Capability::EditLibrary,
+            ],
+            Command::Search { pick, .. } => pick.capabilities(),
+            Command::Queue { pick } => {
+                let mut capabilities = vec![Capability::ReadPlayback];
+                capabilities.extend(pick.capabilities());
+                capabilities
+            }
+            Command::Playlist {
+                command: PlaylistCommand::Cover { .. },
+            } => vec![Capability::ReadPlaylists],
+            Command::Playlist {
+                command: PlaylistCommand::SetCover { .. },
+            } => vec![Capability::EditPlaylists, Capability::ImageUpload],
+            Command::Playlist { .. } => vec![Capability::EditPlaylists],
+            Command::Stats {
+                command: StatsCommand::Discoveries { .. },
+                ..
+            } => vec![Capability::ReadPlaylists, Capability::EditPlaylists],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Cli {
+    fn capture_config(&self) -> Option<CaptureConfig> {
+        let mut capture = CaptureConfig::new(self.capture_dir.clone()?);
+        capture.endpoints = self.capture_endpoints.iter().cloned().collect();
+        capture.max_captures = self.max_captures;
+        Some(capture)
+    }
+}
+
+fn parse_capture_endpoint(arg: &str) -> Result<String> {
+    match arg.split_once('=') {
+        Some(("endpoint", name)) if !name.is_empty() => Ok(name.to_string()),
+        _ => bail!("expected endpoint=<name>, e.g. endpoint=currently-playing"),
+    }
+}
+
+fn parse_time_zone(arg: &str) -> Result<Tz> {
+    arg.parse().map_err(|_| {
+        anyhow!("unknown time zone {arg}, expected an IANA name like Europe/Stockholm")
+    })
+}
+
+/// Days like `7d`, or weeks like `2w`.
+fn parse_range(arg: &str) -> Result<u64> {
+    let (count, days_per) = match arg.strip_suffix('w') {
+        Some(weeks) => (weeks, 7),
+        None => (arg.strip_suffix('d').unwrap_or(arg), 1),
+    };
+    match count.parse::<u64>() {
+        Ok(count) if count > 0 => Ok(count * days_per),
+        _ => bail!("expected a number of days or weeks, e.g. 7d or 2w"),
+    }
+}
+
+#[cfg(feature = "email")]
+fn parse_smtp_security(arg: &str) -> Result<SmtpSecurity> {
+    arg.parse()
+}
+
+#[cfg(feature = "email")]
+fn parse_report_schedule(arg: &str) -> Result<ReportSchedule> {
+    arg.parse()
+}
+
+fn parse_snapshot_section(arg: &str) -> Result<SnapshotSection> {
+    arg.parse()
+}
+
+fn parse_locale(arg: &str) -> Result<Locale> {
+    Ok(Locale::from_tag(arg))
+}
+
+/// Checks the URI parses, but keeps it as given: Spotify wants it exactly
+/// as registered.
+fn parse_redirect_uri(arg: &str) -> Result<String> {
+    url::Url::parse(arg).map_err(|e| anyhow!("invalid redirect URI {arg}: {e}"))?;
+    Ok(arg.to_string())
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    setup_tracing(Level::INFO, cli.log_bodies);
+    adopt_working_dir_files();
+    let verbose = cli.verbose;
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            let report = ErrorReport::new(&e);
+            eprint!("{}", report.render(&e, verbose));
+            ExitCode::from(report.exit_code)
+        }
+    }
+}
+
+/// Exit codes scripts can branch on, clap already exits with 2 on bad arguments
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_AUTH: u8 = 3;
+const EXIT_NETWORK: u8 = 4;
+const EXIT_STORAGE: u8 = 5;
+
+/// A command used wrong in a way clap can't catch.
+#[derive(Debug)]
+struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// What a failure means for the user: a short explanation, what to try
+/// next and the exit code.
+struct ErrorReport {
+    message: String,
+    hint: Option<&'static str>,
+    exit_code: u8,
+}
+
+impl ErrorReport {
+    fn new(err: &anyhow::Error) -> ErrorReport {
+        let report = |message: String, hint, exit_code| ErrorReport {
+            message,
+            hint,
+            exit_code,
+        };
+        if let Some(e) = err.downcast_ref::<SpotifyError>() {
+            let (hint, exit_code) = match e {
+                SpotifyError::MissingCreds => {
+                    ("run `spotify-rs auth` to authorize this app", EXIT_AUTH)
+                }
+                SpotifyError::RefreshTokenRevoked | SpotifyError::TokenRejected => {
+                    ("run `spotify-rs auth` to authorize again", EXIT_AUTH)
+                }
+                SpotifyError::Network => {
+                    ("check your network connection and try again", EXIT_NETWORK)
+                }
+                SpotifyError::SnapshotOutdated => (
+                    "run it again without --snapshot, or with the current one",
+                    EXIT_FAILURE,
+                ),
+                SpotifyError::Upstream { .. } => (
+                    "Spotify is having trouble, try again in a few minutes",
+                    EXIT_NETWORK,
+                ),
+                SpotifyError::UserAuthRequired => (
+                    "use a client authorized by a user for this endpoint",
+                    EXIT_AUTH,
+                ),
+            };
+            return report(e.to_string(), Some(hint), exit_code);
+        }
+        if err.downcast_ref::<MissingScopes>().is_some() {
+            return report(
+                err.to_string(),
+                Some("run `spotify-rs auth --add-scope <scope>` for each of them"),
+                EXIT_AUTH,
+            );
+        }
+        if err.downcast_ref::<AuthorizationDenied>().is_some() {
+            return report(
+                err.to_string(),
+                Some("run `spotify-rs auth` again and accept the app's access request"),
+                EXIT_AUTH,
+            );
+        }
+        if let Some(e) = err.downcast_ref::<StorageError>() {
+            let hint = match e {
+                StorageError::Config => "run `spotify-rs doctor` to see which field is wrong",
+                StorageError::Unreachable => {
+                    "check the access token in bitwarden_config.json and your network connection"
+                }
+            };
+            return report(e.to_string(), Some(hint), EXIT_STORAGE);
+        }
+        if let Some(e) = err.downcast_ref::<NewerStateFile>() {
+            return report(
+                e.to_string(),
+                Some("run the newer spotify-rs that wrote it, or move the file away"),
+                EXIT_FAILURE,
+            );
+        }
+        if err.downcast_ref::<AlreadyRunning>().is_some() {
+            return report(
+                err.to_string(),
+                Some("stop it first, or run `spotify-rs daemon --takeover`"),
+                EXIT_FAILURE,
+            );
+        }
+        if let Some(e) = err.downcast_ref::<UsageError>() {
+            return report(e.to_string(), Some("see `spotify-rs --help`"), EXIT_USAGE);
+        }
+        report(err.to_string(), None, EXIT_FAILURE)
+    }
+
+    fn render(&self, err: &anyhow::Error, verbose: bool) -> String {
+        let mut out = format!("error: {}\n", self.message);
+        if let Some(hint) = self.hint {
+            out.push_str(&format!("  hint: {hint}\n"));
+        }
+        let causes: Vec<String> = err
+            .chain()
+            .map(|e| e.to_string())
+            .filter(|e| *e != self.message)
+            .collect();
+        if verbose {
+            for cause in causes {
+                out.push_str(&format!("  caused by: {cause}\n"));
+            }
+        } else if !causes.is_empty() {
+            out.push_str("  run with --verbose for details\n");
+        }
+        out
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let color = use_color(cli.no_color);
+    let capture = cli.capture_config();
+    let refresh_margin = cli.refresh_margin;
+    let no_interactive = cli.no_interactive;
+    let log_bodies = cli.log_bodies;
+    let email_scope = cli.email_scope;
+    let image_upload_scope = cli.image_upload_scope;
+    let force_consent = cli.force_consent;
+    let no_browser = cli.no_browser;
+    let redirect_uri = cli.redirect_uri;
+    let user = resolve_user(cli.user, &default_config_dir())?;
+    let command = match cli.command.unwrap_or(Command::Now {
+        grace: GraceArgs {
+            grace: None,
+            now_cache: PathBuf::from(DEFAULT_NOW_CACHE_FILE),
+        },
+    }) {
+        Command::History {
+            history,
+            devices,
+            command,
+        } => {
+            let store = HistoryStore::new(history);
+            let (user, locale) = (&user, cli.locale);
+            return match cli.time_zone {
+                Some(tz) => history_command(store, user, devices, command, color, locale, &tz),
+                None => history_command(store, user, devices, command, color, locale, &Local),
+            };
+        }
+        Command::Stats {
+            history,
+            days,
+            devices,
+            command,
+        } if !matches!(
+            command,
+            StatsCommand::SkipPoints { .. }
+                | StatsCommand::Discoveries {
+                    playlist: Some(_),
+                    ..
+                }
+        ) =>
+        {
+            let store = HistoryStore::new(history);
+            return match cli.time_zone {
+                Some(tz) => stats_command(store, days, devices, command, color, cli.locale, &tz),
+                None => stats_command(store, days, devices, command, color, cli.locale, &Local),
+            };
+        }
+        #[cfg(feature = "email")]
+        Command::Report { command } => return report_command(command, cli.locale, cli.time_zone),
+        Command::Tag {
+            label,
+            control_dir,
+            socket,
+        } => return tag(ControlChannel::new(control_dir), socket, &label),
+        Command::Ctl { socket, command } => return ctl(socket, command.into()),
+        Command::Migrate { from } => return migrate(&from),
+        Command::Doctor => {
+            doctor()?;
+            Command::Doctor
+        }
+        command => command,
+    };
 
-/// Depends on the "blocking" feature flags
-fn main() -> Result<()> {
-    setup_tracing(Level::INFO);
     info!("Running the spotify test cli!");
-    let mut spotify = SpotifyClient::new(USER.to_string()).unwrap();
-    spotify.setup_creds().unwrap();
+    // The doctor only looks, it never starts an authorization
+    let interactive = !no_interactive && !matches!(command, Command::Doctor);
+    let mut builder = SpotifyClientBuilder::new(user)
+        .with_refresh_margin(Duration::from_secs(refresh_margin))
+        .interactive(interactive)
+        .log_bodies(log_bodies)
+        .with_email_scope(email_scope)
+        .with_image_upload_scope(image_upload_scope)
+        .force_consent(force_consent)
+        .open_browser(!no_browser)
+        .with_redirect_uri(&redirect_uri)
+        .with_capabilities(&command.capabilities());
+    if let Some(capture) = capture {
+        info!("Capturing Spotify responses into {}", capture.dir.display());
+        builder = builder.with_capture(capture);
+    }
+    let mut spotify = match wait!(builder.build()) {
+        Ok(spotify) => spotify,
+        Err(e) => return now_or_stale(&command, e),
+    };
+    if let Command::Auth {
+        redirect_url,
+        redirect_file,
+        add_scopes,
+        detect_user,
+    } = command
+    {
+        wait!(spotify.load_creds())?;
+        spotify.add_wanted_scopes(&add_scopes);
+        return auth(&mut spotify, redirect_url, redirect_file, detect_user);
+    }
+    if let Command::Doctor = command {
+        api_doctor(&mut spotify);
+        return Ok(());
+    }
+    if let Command::Config = command {
+        // What is stored counts too, but config should work without it
+        if let Err(e) = wait!(spotify.load_creds()) {
+            warn!("Could not load the stored creds: {e:#}");
+        }
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&spotify.effective_config())?
+        );
+        return Ok(());
+    }
+    if let Command::TokenInfo = command {
+        wait!(spotify.load_creds())?;
+        let auth = spotify
+            .shared_auth()
+            .snapshot()
+            .ok_or(SpotifyError::MissingCreds)?;
+        let margin = Duration::from_secs(refresh_margin);
+        print!("{}", token_info(&auth, SystemTime::now(), margin));
+        return Ok(());
+    }
+    if let Command::Status { check } = command {
+        wait!(spotify.load_creds())?;
+        return token_status(&mut spotify, check);
+    }
+    if let Err(e) = wait!(spotify.setup_creds()) {
+        return now_or_stale(&command, e);
+    }
+    wait!(spotify.ensure_scopes(&command.capabilities()))?;
 
-    let resp = spotify.get_currently_playing_track()?;
-    let track_d = resp.and_then(|t| t.get_track_data());
-    match track_d {
-        Some(t) => info!("Currently Playing: {}", t.name),
-        None => warn!("No track info found"),
+    match command {
+        Command::Now { grace } => now_playing(&mut spotify, &grace),
+        Command::Share {
+            template,
+            #[cfg(feature = "songlink")]
+            songlink,
+            #[cfg(feature = "clipboard")]
+            copy,
+        } => {
+            // Checked first, a typo shouldn't cost a request
+            let template = share_template(&template)?;
+            let share = playing_share(&mut spotify)?;
+            #[cfg(feature = "songlink")]
+            let share = match songlink && template.uses("link") {
+                true => SongLinkResolver::new()?.add_link(share),
+                false => share,
+            };
+            let snippet = share.render(&template);
+            println!("{snippet}");
+            #[cfg(feature = "clipboard")]
+            if copy {
+                copy_to_clipboard(&snippet)?;
+            }
+            Ok(())
+        }
+        Command::Whoami => {
+            print!("{}", whoami(&wait!(spotify.get_user_profile())?));
+            Ok(())
+        }
+        // Only skip points and discoveries for a playlist get this far, the
+        // other stats work offline
+        Command::Stats {
+            history,
+            days,
+            devices,
+            command,
+        } => {
+            match &command {
+                StatsCommand::SkipPoints {
+                    track_id,
+                    analysis_cache,
+                    ..
+                } => cache_audio_analysis(
+                    &mut spotify,
+                    &AnalysisCache::new(analysis_cache),
+                    track_id,
+                )?,
+                StatsCommand::Discoveries {
+                    range,
+                    playlist: Some(playlist),
+                    keep,
+                    ..
+                } => {
+                    let entries = HistoryStore::new(&history).load()?;
+                    let found = match cli.time_zone {
+                        Some(tz) => discoveries_in(&entries, *range, &tz),
+                        None => discoveries_in(&entries, *range, &Local),
+                    };
+                    add_discoveries(&mut spotify, playlist, &found, *keep)?;
+                }
+                _ => {}
+            }
+            let store = HistoryStore::new(history);
+            match cli.time_zone {
+                Some(tz) => stats_command(store, days, devices, command, color, cli.locale, &tz),
+                None => stats_command(store, days, devices, command, color, cli.locale, &Local),
+            }
+        }
+        Command::Devices => list_devices(&mut spotify, color),
+        Command::Player { command } => player(&mut spotify, command),
+        Command::Playlist { command } => playlist(&mut spotify, command),
+        Command::Library { command } => library(&mut spotify, command, color),
+        Command::Search { query, limit, pick } => {
+            let tracks = wait!(spotify.search_tracks(&query, limit))?;
+            pick_tracks(&mut spotify, &tracks, pick, color)
+        }
+        Command::Queue { pick } => {
+            let queue = wait!(spotify.get_queue())?;
+            pick_tracks(&mut spotify, &queue.upcoming_tracks(), pick, color)
+        }
+        Command::Watch {
+            interval,
+            indeterminate_polls,
+            skip_chapters,
+            debounce,
+            up_next,
+            #[cfg(feature = "desktop-notify")]
+            notify,
+            #[cfg(feature = "lyrics")]
+            lyrics,
+        } => {
+            let watcher = Watcher::new()
+                .with_indeterminate_limit(indeterminate_polls)
+                .with_chapters(!skip_chapters)
+                .with_debounce(Duration::from_secs(debounce));
+            #[cfg(feature = "lyrics")]
+            let lyrics = lyrics.pane();
+            #[cfg(not(feature = "lyrics"))]
+            let lyrics = None;
+            let view = WatchView::new(watcher).with_up_next(up_next);
+            #[cfg(feature = "desktop-notify")]
+            let view = if notify {
+                view.with_notifications(Box::new(DesktopNotifier::new(spotify.album_art_cache())))
+            } else {
+                view
+            };
+            watch(
+                &mut spotify,
+                view,
+                Duration::from_secs(interval),
+                lyrics,
+                color,
+            )
+        }
+        Command::Daemon {
+            interval,
+            history,
+            queue_assisted,
+            control_dir,
+            socket,
+            log_summary_interval,
+            journal,
+            journal_interval,
+            durable,
+            now_cache,
+            lock_file,
+            takeover,
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            #[cfg(feature = "email")]
+            email_report_at,
+            #[cfg(feature = "email")]
+            email,
+        } => {
+            let lock = lock_instance(
+                &lock_file.unwrap_or_else(|| lock_path_for(&history)),
+                &control_dir,
+                socket.as_deref(),
+                takeover,
+            )?;
+            let state = Daemon::new(
+                Duration::from_secs(interval),
+                PlayJournal::new(journal.unwrap_or_else(|| journal_path_for(&history)))
+                    .durable(durable),
+                Duration::from_secs(journal_interval),
+                HistoryStore::new(history),
+                LogThrottle::new(Duration::from_secs(log_summary_interval)),
+            )
+            .with_now_cache(NowCache::new(now_cache));
+            #[cfg(feature = "mqtt")]
+            let state = match mqtt.sink(&spotify)? {
+                Some(sink) => state.with_mqtt(sink),
+                None => state,
+            };
+            #[cfg(feature = "email")]
+            let state = match email_report_at {
+                Some(schedule) => state.with_report(ScheduledReport::new(
+                    schedule,
+                    email.sender(spotify.creds_storage())?,
+                    cli.time_zone,
+                    cli.locale,
+                    SystemTime::now(),
+                )),
+                None => state,
+            };
+            daemon(
+                &mut spotify,
+                lock,
+                state,
+                ControlChannel::new(control_dir),
+                socket.unwrap_or_else(default_socket_path),
+                queue_assisted,
+                Duration::from_secs(interval),
+            )
+        }
+        #[cfg(feature = "email")]
+        Command::Report { .. } => unreachable!("offline and auth commands are handled before this"),
+        Command::History { .. }
+        | Command::Tag { .. }
+        | Command::Ctl { .. }
+        | Command::Migrate { .. }
+        | Command::Doctor
+        | Command::Auth { .. }
+        | Command::TokenInfo
+        | Command::Status { .. }
+        | Command::Config => {
+            unreachable!("offline and auth commands are handled before this")
+        }
     }
+}
+
+fn whoami(profile: &UserProfile) -> String {
+    let mut info = format!("Name:    {}\n", profile.name());
+    info += &format!("Id:      {}\n", profile.id);
+    if let Some(country) = &profile.country {
+        info += &format!("Country: {country}\n");
+    }
+    if let Some(product) = &profile.product {
+        info += &format!("Product: {product}\n");
+    }
+    let email = match &profile.email {
+        Some(email) => email,
+        None => "not granted, authorize again with --email-scope",
+    };
+    info += &format!("Email:   {email}\n");
+    info
+}
+
+/// The stored auth state, never with the full tokens.
+fn token_info(auth: &UserAuthData, now: SystemTime, margin: Duration) -> String {
+    let expires = match auth.expires_at() {
+        None => "unknown, the token was never refreshed".to_string(),
+        Some(expires_at) => {
+            let at = DateTime::<Local>::from(expires_at).format("%Y-%m-%d %H:%M:%S");
+            match expires_at.duration_since(now) {
+                Ok(left) => format!("{at} (in {} min)", left.as_secs() / 60),
+                Err(e) => format!("{at} (expired {} min ago)", e.duration().as_secs() / 60),
+            }
+        }
+    };
+    let refresh_due = match auth.token_needs_refresh_at(now, margin) {
+        true => "yes",
+        false => "no",
+    };
+
+    let mut info = format!("Token type:    {}\n", auth.token_type);
+    info += &format!("Expires at:    {expires}\n");
+    info += &format!("Refresh due:   {refresh_due}\n");
+    info += &format!("Access token:  {}\n", mask_secret(&auth.access_token));
+    info += &format!("Refresh token: {}\n", mask_secret(&auth.refresh_token));
+    info += "Scopes:\n";
+    for scope in auth.granted_scopes() {
+        info += &format!("  {scope}\n");
+    }
+    info
+}
 
+/// Prints whether the access token works, Err when it doesn't. Nothing is
+/// refreshed, the next command that calls Spotify does that.
+fn token_status(spotify: &mut SpotifyClient, check: bool) -> Result<()> {
+    if check {
+        if !wait!(spotify.validate_token_online())? {
+            bail!("Spotify rejected the access token");
+        }
+        println!("Spotify accepted the access token");
+        return Ok(());
+    }
+    let auth = spotify
+        .shared_auth()
+        .snapshot()
+        .ok_or(SpotifyError::MissingCreds)?;
+    if auth.token_needs_refresh_at(SystemTime::now(), Duration::ZERO) {
+        bail!("The access token has expired");
+    }
+    println!("The access token hasn't expired, `--check` asks Spotify whether it still works");
     Ok(())
 }
 
-fn setup_tracing(level: Level) {
-    tracing_subscriber::fmt()
-        .with_max_level(level)
-        .with_target(true)
+fn doctor() -> Result<()> {
+    match wait!(CredStorage::validate_config()) {
+        Ok(()) => {
+            info!("The secrets config is valid and the secret provider let us in");
+            Ok(())
+        }
+        Err(e) => bail!("{e:#}"),
+    }
+}
+
+/// The local files used to be read from the working directory. Copies the
+/// ones the config directory doesn't have yet there, once.
+fn adopt_working_dir_files() {
+    let config_dir = default_config_dir();
+    if let Err(e) = adopt_local_files(Path::new("."), &config_dir) {
+        warn!(
+            "Could not copy the local files to {}: {e:#}",
+            config_dir.display()
+        );
+    }
+}
+
+/// Copies the local files left in `from` to the config directory, where
+/// they are read from.
+fn migrate(from: &Path) -> Result<()> {
+    let to = default_config_dir();
+    for (name, migration) in migrate_local_files(from, &to)? {
+        let source = from.join(name);
+        let destination = to.join(name);
+        match migration {
+            Migration::Missing => println!("{}: not there", source.display()),
+            Migration::Copied | Migration::Replaced => {
+                println!("{}: copied to {}", source.display(), destination.display())
+            }
+            Migration::Unchanged => println!("{}: already there", source.display()),
+            Migration::KeptNewer => {
+                println!(
+                    "{}: kept the newer {}",
+                    source.display(),
+                    destination.display()
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes one request to see which headers of interest Spotify sends.
+/// Without working creds there is nothing to check yet, that is fine before a first run.
+fn api_doctor(spotify: &mut SpotifyClient) {
+    let probe =
+        wait!(spotify.load_creds()).and_then(|()| wait!(spotify.get_currently_playing_track()));
+    match probe {
+        Ok(_) => print!("{}", diagnostics_report(spotify.diagnostics())),
+        Err(e) => warn!("Skipped the Spotify API check: {e:#}"),
+    }
+}
+
+fn diagnostics_report(diagnostics: &BTreeMap<String, SeenHeader>) -> String {
+    if diagnostics.is_empty() {
+        return "Spotify sent no deprecation notices or rate limit headers\n".to_string();
+    }
+    let mut report = "Headers of interest from Spotify:\n".to_string();
+    for (name, seen) in diagnostics {
+        report += &format!("  {name}: {} (from {})\n", seen.value, seen.url);
+    }
+    report
+}
+
+#[cfg(feature = "sync")]
+fn sync_command(store: HistoryStore, user: &str, command: SyncCommand) -> Result<()> {
+    let token = load_sync_token(&default_config_dir())?;
+    match command {
+        SyncCommand::Serve { listen } => {
+            let server = SyncServer::bind(&listen, store, user, token)?;
+            info!("Serving {user}'s plays on {listen}{SYNC_PLAYS_PATH}");
+            server.serve();
+        }
+        SyncCommand::Push { remote } => {
+            let synced = SyncClient::new(&remote, user, token)?.push(&store)?;
+            println!(
+                "{remote} added {} plays and replaced {} backfilled ones",
+                synced.added, synced.replaced
+            );
+        }
+        SyncCommand::Pull { remote } => {
+            let synced = SyncClient::new(&remote, user, token)?.pull(&store)?;
+            println!(
+                "Added {} plays from {remote} and replaced {} backfilled ones",
+                synced.added, synced.replaced
+            );
+        }
+    }
+    Ok(())
+}
+
+fn history_command<Tz: TimeZone>(
+    store: HistoryStore,
+    #[cfg_attr(not(feature = "sync"), allow(unused_variables))] user: &str,
+    devices: DeviceFilter,
+    command: HistoryCommand,
+    color: bool,
+    locale: Locale,
+    tz: &Tz,
+) -> Result<()>
+where
+    Tz::Offset: fmt::Display,
+{
+    match command {
+        HistoryCommand::Search { query, limit } => {
+            let mut entries = store.load()?;
+            devices.apply(&mut entries)?;
+            let hits = search(&entries, &query, SystemTime::now());
+            if hits.is_empty() {
+                println!("Nothing in the history matches \"{query}\"");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Track", "Artists", "Album", "Plays", "Last played"])
+                .max_width(0, 40)
+                .max_width(1, 30)
+                .max_width(2, 30)
+                .align(3, Align::Right)
+                .with_color(color);
+            for hit in hits.into_iter().take(limit) {
+                let artists: Vec<String> = hit.artists.into_iter().map(|a| a.name).collect();
+                table.add_row(vec![
+                    hit.track_name,
+                    artists.join(", "),
+                    hit.album,
+                    hit.play_count.to_string(),
+                    DateTime::<Utc>::from(hit.last_played)
+                        .with_timezone(tz)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string(),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        HistoryCommand::Import { path } => {
+            let added = store.import_extended_history(&path)?;
+            println!("Imported {added} plays into {}", store.path().display());
+            Ok(())
+        }
+        HistoryCommand::Merge { from } => {
+            let added = store.merge_from(&from)?;
+            println!(
+                "Merged {added} plays from {} into {}",
+                from.display(),
+                store.path().display()
+            );
+            Ok(())
+        }
+        #[cfg(feature = "sync")]
+        HistoryCommand::Sync { command } => sync_command(store, user, command),
+        HistoryCommand::Availability => {
+            let changes = store.availability_changes()?;
+            if changes.is_empty() {
+                println!("No track has become unplayable");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Track", "Status", "Reason", "Seen"])
+                .max_width(0, 40)
+                .with_color(color);
+            for change in changes {
+                let status = if change.playable {
+                    "playable"
+                } else {
+                    "unplayable"
+                };
+                table.add_row(vec![
+                    change.track_name,
+                    status.to_string(),
+                    change.reason.unwrap_or_default(),
+                    DateTime::<Utc>::from(change.seen_at)
+                        .with_timezone(tz)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string(),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        HistoryCommand::OnThisDay {
+            years_back,
+            date,
+            limit,
+            json,
+        } => {
+            let mut entries = store.load()?;
+            devices.apply(&mut entries)?;
+            let day = date.unwrap_or_else(|| Utc::now().with_timezone(tz).date_naive());
+            let years = on_this_day(&entries, day, years_back, limit, tz);
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&years)?),
+                false => print!("{}", on_this_day_report(&years, day, color, locale)),
+            }
+            Ok(())
+        }
+    }
+}
+
+fn auth(
+    spotify: &mut SpotifyClient,
+    redirect_url: Option<String>,
+    redirect_file: Option<PathBuf>,
+    detect_user: bool,
+) -> Result<()> {
+    let redirect_url = match (redirect_url, redirect_file) {
+        (Some(url), _) => Some(url),
+        (None, Some(path)) => Some(fs::read_to_string(&path)?),
+        (None, None) => None,
+    };
+
+    let Some(redirect_url) = redirect_url else {
+        let url = spotify.begin_authorization()?;
+        let verifier = spotify.pending_code_verifier().unwrap_or_default();
+        // The verifier is as good as the auth code, keep it to the user
+        write_private(AUTH_PENDING_FILE, serde_json::to_string(verifier)?)?;
+        if spotify.open_authorization_url(&url) {
+            println!("Opened your browser to authorize the app, or open this URL:\n{url}");
+        } else {
+            println!("Open this URL to authorize the app:\n{url}");
+        }
+        println!("Then run `auth --redirect-url '<url you were redirected to>'`");
+        return Ok(());
+    };
+
+    let verifier: String = match fs::read_to_string(AUTH_PENDING_FILE) {
+        Ok(data) => serde_json::from_str(&data)?,
+        Err(_) => {
+            return Err(UsageError(
+                "No authorization in progress, run `auth` without arguments first".to_string(),
+            )
+            .into())
+        }
+    };
+    spotify.resume_authorization(verifier);
+    wait!(spotify.complete_authorization_from_url(&redirect_url))?;
+    fs::remove_file(AUTH_PENDING_FILE)?;
+    info!("Authorized with Spotify");
+    if detect_user {
+        let user_id = wait!(spotify.detect_user_id())?;
+        if user_id != spotify.user_id() {
+            wait!(spotify.rekey_user(user_id.clone()))?;
+        }
+        save_detected_user(&default_config_dir(), &user_id)?;
+        println!("Authorized as {user_id}, later commands use it unless `--user` says otherwise");
+    }
+    Ok(())
+}
+
+/// The `--user` given, else the one `auth --detect-user` kept in
+/// `config_dir`, else the default.
+fn resolve_user(user: Option<String>, config_dir: &Path) -> Result<String> {
+    match user {
+        Some(user) => Ok(user),
+        None => Ok(load_detected_user(config_dir)?.unwrap_or_else(|| USER.to_string())),
+    }
+}
+
+fn now_playing(spotify: &mut SpotifyClient, grace: &GraceArgs) -> Result<()> {
+    match wait!(spotify.get_currently_playing_track()) {
+        Ok(playing) => print_playing(playing, None),
+        Err(e) => return grace.serve_stale(e),
+    }
+    Ok(())
+}
+
+/// `now --grace` serves the cached answer when the client can't even be set
+/// up, e.g. refreshing the token fails to reach Spotify.
+fn now_or_stale(command: &Command, err: anyhow::Error) -> Result<()> {
+    match command {
+        Command::Now { grace } => grace.serve_stale(err),
+        _ => Err(err),
+    }
+}
+
+/// Spotify couldn't be reached or had an outage, a cached answer may stand in.
+fn is_outage(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<SpotifyError>(),
+        Some(SpotifyError::Network | SpotifyError::Upstream { .. })
+    )
+}
+
+/// `stale` is how old a cached answer is, None for a live one.
+fn print_playing(playing: Option<CurrentlyPlayingTrack>, stale: Option<Duration>) {
+    let stale = stale
+        .map(|age| format!(" (stale, {}s old)", age.as_secs()))
+        .unwrap_or_default();
+    match playing_line(playing) {
+        Some(line) => info!("{line}{stale}"),
+        None => warn!("No track info found{stale}"),
+    }
+}
+
+/// What `now` says about the player. Ads and items Spotify calls `unknown`
+/// come without an item, None is for an item that doesn't parse.
+fn playing_line(playing: Option<CurrentlyPlayingTrack>) -> Option<String> {
+    let Some(playing) = playing else {
+        return Some("Nothing is playing".to_string());
+    };
+    if playing.currently_playing_type == PlayingType::Unknown {
+        return Some("Unknown playback".to_string());
+    }
+    let line = match playing.into_playing_item()? {
+        PlayingItem::Track(track) => format!("Currently Playing: {}", track.name),
+        PlayingItem::Episode(episode) => format!(
+            "Currently Playing: {} from {}",
+            episode.name, episode.show.name
+        ),
+        PlayingItem::Chapter(chapter) => format!(
+            "Currently Playing: {} from {} by {}",
+            chapter.name,
+            chapter.audiobook.name,
+            chapter.audiobook.author_names()
+        ),
+        PlayingItem::Ad => "Advertisement playing".to_string(),
+    };
+    Some(line)
+}
+
+fn playing_share(spotify: &mut SpotifyClient) -> Result<Share> {
+    match wait!(spotify.get_currently_playing_track())?
+        .and_then(|playing| playing.into_playing_item())
+    {
+        Some(PlayingItem::Track(track)) => Ok(Share::new(&track)),
+        Some(_) => bail!("Only tracks can be shared"),
+        None => bail!("Nothing is playing"),
+    }
+}
+
+/// On X11 and Wayland the text only outlives the process with a clipboard
+/// manager running.
+#[cfg(feature = "clipboard")]
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .map_err(|e| anyhow!("Could not copy to the clipboard: {e}"))?;
+    info!("Copied to the clipboard");
+    Ok(())
+}
+
+fn player(spotify: &mut SpotifyClient, command: PlayerCommand) -> Result<()> {
+    match command {
+        PlayerCommand::Pause => wait!(spotify.pause_playback()),
+        PlayerCommand::Resume => wait!(spotify.resume_playback()),
+        PlayerCommand::Next => wait!(spotify.skip_to_next()),
+        PlayerCommand::Previous => wait!(spotify.skip_to_previous()),
+        PlayerCommand::UseDevice { device_id } => {
+            info!("Playback commands will go to device {device_id}");
+            wait!(spotify.set_preferred_device(device_id))
+        }
+    }
+}
+
+fn playlist(spotify: &mut SpotifyClient, command: PlaylistCommand) -> Result<()> {
+    let snapshot = match command {
+        PlaylistCommand::Remove {
+            playlist,
+            uris,
+            snapshot,
+        } => {
+            let uris: Vec<&str> = uris.iter().map(String::as_str).collect();
+            wait!(spotify.remove_tracks_from_playlist(&playlist, &uris, snapshot.as_deref()))?
+        }
+        PlaylistCommand::Move {
+            playlist,
+            from,
+            to,
+            count,
+            snapshot,
+        } => wait!(spotify.reorder_playlist(
+            &playlist,
+            from,
+            insert_before(from, to, count),
+            count,
+            snapshot.as_deref(),
+        ))?,
+        PlaylistCommand::Cover { playlist } => {
+            for image in wait!(spotify.get_playlist_cover(&playlist))? {
+                println!("{}", image.url);
+            }
+            return Ok(());
+        }
+        PlaylistCommand::SetCover {
+            playlist,
+            image,
+            #[cfg(feature = "charts")]
+            title,
+        } => {
+            let jpeg = match image {
+                Some(path) => fs::read(&path)
+                    .map_err(|e| anyhow!("Could not read {}: {e}", path.display()))?,
+                #[cfg(feature = "charts")]
+                None => charts::playlist_cover(&title.unwrap_or_default().replace("\\n", "\n"))?,
+                #[cfg(not(feature = "charts"))]
+                None => bail!("Give the cover to upload with --image"),
+            };
+            wait!(spotify.set_playlist_cover(&playlist, &jpeg))?;
+            info!("Uploaded the cover, Spotify shows it in a moment");
+            return Ok(());
+        }
+    };
+    info!("Playlist updated, its snapshot is now {snapshot}");
+    Ok(())
+}
+
+/// Lists the tracks, or lets the user pick one and prints it or acts on it.
+fn pick_tracks(
+    spotify: &mut SpotifyClient,
+    tracks: &[Track],
+    args: PickArgs,
+    color: bool,
+) -> Result<()> {
+    let action = args.then;
+    let items: Vec<PickItem> = tracks.iter().map(PickItem::from_track).collect();
+    if items.is_empty() {
+        println!("No tracks");
+        return Ok(());
+    }
+    if !args.pick && action.is_none() {
+        let mut table = Table::new(&["Track", "Artists", "Album", "Length"])
+            .max_width(0, 40)
+            .max_width(1, 30)
+            .max_width(2, 30)
+            .align(3, Align::Right)
+            .with_color(color);
+        for item in items {
+            let duration = item.duration();
+            table.add_row(vec![item.title, item.artists, item.album, duration]);
+        }
+        print!("{}", table.render());
+        return Ok(());
+    }
+
+    let Some(picked) = picker::pick(&items)? else {
+        return Ok(());
+    };
+    let item = &items[picked];
+    match action {
+        None => println!("{}", item.uri),
+        Some(action) => pick_action(spotify, &action, item)?,
+    }
+    Ok(())
+}
+
+fn pick_action(spotify: &mut SpotifyClient, action: &PickAction, item: &PickItem) -> Result<()> {
+    match action {
+        PickAction::Queue => {
+            wait!(spotify.add_to_queue(&item.uri))?;
+            info!("Queued {}", item.label());
+        }
+        PickAction::Like => {
+            wait!(spotify.save_tracks(&[item.id.as_str()]))?;
+            info!("Liked {}", item.label());
+        }
+        PickAction::Playlist(playlist) => {
+            let snapshot = wait!(spotify.add_tracks_to_playlist(playlist, &[item.uri.as_str()]))?;
+            info!(
+                "Added {} to the playlist, its snapshot is now {snapshot}",
+                item.label()
+            );
+        }
+        PickAction::Open => {
+            let opened = default_opener().is_some_and(|opener| opener.open(&item.url).is_ok());
+            if !opened {
+                println!("{}", item.url);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn library(spotify: &mut SpotifyClient, command: LibraryCommand, color: bool) -> Result<()> {
+    match command {
+        LibraryCommand::ImportFromPlaylists {
+            playlists,
+            dry_run,
+            state,
+        } => {
+            let path = state.unwrap_or_else(|| import_state_path_for(&playlists));
+            let mut import = match LibraryImport::resume(&path, &playlists)? {
+                Some(import) => {
+                    info!(
+                        "Continuing the import from {}, {} of {} tracks left to save",
+                        path.display(),
+                        import.remaining().len(),
+                        import.total()
+                    );
+                    import
+                }
+                None => {
+                    let ids: Vec<&str> = playlists.iter().map(String::as_str).collect();
+                    let plan = wait!(spotify.plan_library_import(&ids))?;
+                    info!(
+                        "{} tracks in {} playlists, {} already saved, {} to save",
+                        plan.found,
+                        playlists.len(),
+                        plan.already_saved,
+                        plan.to_save.len()
+                    );
+                    if dry_run || plan.to_save.is_empty() {
+                        return Ok(());
+                    }
+                    LibraryImport::start(&path, &plan)?
+                }
+            };
+            if dry_run {
+                return Ok(());
+            }
+            wait!(spotify.run_library_import(&mut import, |import| {
+                info!("Saved {} of {} tracks", import.saved(), import.total())
+            }))
+            .map_err(|e| {
+                e.context(format!(
+                    "Stopped with {} tracks left, run the import again to continue",
+                    import.remaining().len()
+                ))
+            })
+        }
+        LibraryCommand::Snapshot {
+            out,
+            only,
+            skip,
+            state,
+        } => {
+            let sections: Vec<SnapshotSection> = SnapshotSection::ALL
+                .into_iter()
+                .filter(|section| only.is_empty() || only.contains(section))
+                .filter(|section| !skip.contains(section))
+                .collect();
+            if sections.is_empty() {
+                bail!("Every section was left out, there is nothing to snapshot");
+            }
+            let path = state.unwrap_or_else(|| snapshot_state_path_for(&out));
+            let mut run = match SnapshotRun::resume(&path, &sections)? {
+                Some(run) => {
+                    info!("Continuing the snapshot from {}", path.display());
+                    run
+                }
+                None => SnapshotRun::start(&path, &sections)?,
+            };
+            let mut bars = SnapshotBars::new(io::stderr().is_terminal());
+            bars.draw(&run);
+            wait!(spotify.run_library_snapshot(&mut run, |run| bars.draw(run))).map_err(|e| {
+                e.context(format!(
+                    "Stopped, run the snapshot again to continue from {}",
+                    path.display()
+                ))
+            })?;
+            let snapshot = run.finish(&out)?;
+            print!("{}", snapshot_summary(&snapshot, color));
+            println!("Wrote {}", out.display());
+            Ok(())
+        }
+        LibraryCommand::Audit { history } => {
+            let audit = wait!(spotify.audit_library(&HistoryStore::new(history)))?;
+            info!(
+                "Checked {} saved tracks, {} changed since the last audit",
+                audit.checked, audit.changes
+            );
+            if audit.unplayable.is_empty() {
+                println!("Every saved track can be played");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Track", "Reason"])
+                .max_width(0, 40)
+                .with_color(color);
+            for track in audit.unplayable {
+                table.add_row(vec![
+                    track.track_name,
+                    track
+                        .reason
+                        .unwrap_or_else(|| "no reason given".to_string()),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+    }
+}
+
+/// A bar per section of a library snapshot on stderr, redrawn in place
+/// after every page. Nothing is drawn when stderr isn't a terminal.
+struct SnapshotBars {
+    terminal: bool,
+    drawn: usize,
+}
+
+impl SnapshotBars {
+    fn new(terminal: bool) -> SnapshotBars {
+        SnapshotBars { terminal, drawn: 0 }
+    }
+
+    fn draw(&mut self, run: &SnapshotRun) {
+        if !self.terminal {
+            return;
+        }
+        let mut out = String::new();
+        if self.drawn > 0 {
+            out += &format!("\x1b[{}A", self.drawn);
+        }
+        for progress in run.sections() {
+            out += &format!("\x1b[2K{}\n", snapshot_bar(progress));
+        }
+        self.drawn = run.sections().len();
+        eprint!("{out}");
+    }
+}
+
+fn snapshot_bar(progress: &SectionProgress) -> String {
+    const WIDTH: usize = 30;
+    let filled = match progress.total {
+        _ if progress.is_done() => WIDTH,
+        Some(total) if total > 0 => (progress.fetched * WIDTH / total as usize).min(WIDTH),
+        _ => 0,
+    };
+    let count = match progress.total {
+        Some(total) => format!("{}/{total}", progress.fetched),
+        None => progress.fetched.to_string(),
+    };
+    format!(
+        "{:<16} [{}{}] {count}",
+        progress.section.name(),
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled)
+    )
+}
+
+fn snapshot_summary(snapshot: &LibrarySnapshot, color: bool) -> String {
+    let mut table = Table::new(&["Section", "Items", "Took"])
+        .align(1, Align::Right)
+        .align(2, Align::Right)
+        .with_color(color);
+    for section in &snapshot.manifest.sections {
+        table.add_row(vec![
+            section.section.name().to_string(),
+            section.count.to_string(),
+            minutes_seconds(section.took.as_secs_f64()),
+        ]);
+    }
+    table.render()
+}
+
+/// Spotify places moved items before `insert_before`, counted before the
+/// move. Moving down, the items themselves still sit in front of the target.
+fn insert_before(from: u32, to: u32, count: u32) -> u32 {
+    if to > from {
+        to + count
+    } else {
+        to
+    }
+}
+
+fn list_devices(spotify: &mut SpotifyClient, color: bool) -> Result<()> {
+    let mut table = Table::new(&["Name", "Type", "Active", "Volume"])
+        .max_width(0, 32)
+        .align(3, Align::Right)
+        .with_color(color);
+    for device in wait!(spotify.get_devices())? {
+        table.add_row(vec![
+            device.name,
+            device.device_type,
+            if device.is_active { "yes" } else { "" }.to_string(),
+            device
+                .volume_percent
+                .map(|v| format!("{v}%"))
+                .unwrap_or_default(),
+        ]);
+    }
+    print!("{}", table.render());
+    Ok(())
+}
+
+fn watch(
+    spotify: &mut SpotifyClient,
+    mut view: WatchView,
+    interval: Duration,
+    mut lyrics: Option<LyricsPane>,
+    color: bool,
+) -> Result<()> {
+    loop {
+        match wait!(spotify.get_playback_state()) {
+            Err(e) => warn!("Failed to poll the player: {e}"),
+            Ok(state) => {
+                let clock = state
+                    .as_ref()
+                    .and_then(|s| PlaybackExtrapolator::from_state(s, Instant::now()));
+                let devices = if view.needs_devices(state.as_ref()) {
+                    wait!(spotify.get_devices())
+                        .inspect_err(|e| warn!("Failed to list devices: {e}"))
+                        .ok()
+                } else {
+                    None
+                };
+                for event in view.observe(state, devices.as_deref(), Instant::now()) {
+                    if let Some(pane) = lyrics.as_mut() {
+                        match &event {
+                            WatchEvent::TrackChanged(track) => pane.track_changed(track),
+                            WatchEvent::EpisodeChanged(_)
+                            | WatchEvent::ChapterChanged(_)
+                            | WatchEvent::AdStarted => pane.clear(),
+                            _ => {}
+                        }
+                    }
+                    log_event(&event);
+                }
+                if view.queue_due() {
+                    let queue = wait!(spotify.get_queue())
+                        .inspect_err(|e| debug!("No up next line: {e}"))
+                        .ok();
+                    view.queue_fetched(queue.as_ref());
+                    if let Some(line) = render_up_next(view.up_next(), color) {
+                        info!("{line}");
+                    }
+                }
+                if let Some(pane) = lyrics.as_mut() {
+                    pane.set_clock(clock);
+                }
+            }
+        }
+        match lyrics.as_mut() {
+            Some(pane) => show_lyrics_until(pane, Instant::now() + interval),
+            None => thread::sleep(interval),
+        }
+    }
+}
+
+/// Sleeps until `deadline`, printing lyrics lines as the playback clock reaches them.
+fn show_lyrics_until(pane: &mut LyricsPane, deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if let Some(line) = pane.next_line(now) {
+            info!("♪ {line}");
+        }
+        match deadline.checked_duration_since(now) {
+            Some(left) if !left.is_zero() => thread::sleep(left.min(LYRICS_TICK)),
+            _ => return,
+        }
+    }
+}
+
+/// What the daemon keeps between polls.
+struct Daemon {
+    store: HistoryStore,
+    journal: PlayJournal,
+    journal_interval: Duration,
+    // When the play in progress was last saved, None when it is due now
+    journaled_at: Option<Instant>,
+    tracker: PlayTracker,
+    tracking: bool,
+    plays_recorded: usize,
+    started_at: SystemTime,
+    shutting_down: bool,
+    log_throttle: LogThrottle,
+    // Where every answer of the player goes, for `now --grace`
+    now_cache: Option<NowCache>,
+    #[cfg(feature = "mqtt")]
+    mqtt: Option<MqttSink>,
+    #[cfg(feature = "email")]
+    report: Option<ScheduledReport>,
+}
+
+impl Daemon {
+    /// `interval` is the time between polls of the player.
+    fn new(
+        interval: Duration,
+        journal: PlayJournal,
+        journal_interval: Duration,
+        store: HistoryStore,
+        log_throttle: LogThrottle,
+    ) -> Daemon {
+        Daemon {
+            store,
+            journal,
+            journal_interval,
+            journaled_at: None,
+            tracker: PlayTracker::new(interval),
+            tracking: true,
+            plays_recorded: 0,
+            started_at: SystemTime::now(),
+            shutting_down: false,
+            log_throttle,
+            now_cache: None,
+            #[cfg(feature = "mqtt")]
+            mqtt: None,
+            #[cfg(feature = "email")]
+            report: None,
+        }
+    }
+
+    fn with_now_cache(mut self, now_cache: NowCache) -> Daemon {
+        self.now_cache = Some(now_cache);
+        self
+    }
+
+    #[cfg(feature = "mqtt")]
+    fn with_mqtt(mut self, sink: MqttSink) -> Daemon {
+        self.mqtt = Some(sink);
+        self
+    }
+
+    #[cfg(feature = "email")]
+    fn with_report(mut self, report: ScheduledReport) -> Daemon {
+        self.report = Some(report);
+        self
+    }
+
+    /// Emails the weekly report when it is due, and retries the ones that
+    /// failed now and then. The emails go out on a worker thread, this only
+    /// starts it and picks up how the last one went.
+    #[cfg(feature = "email")]
+    fn send_due_report(&mut self, now: SystemTime) {
+        let Some(mut report) = self.report.take() else {
+            return;
+        };
+        let sent = match report.sent() {
+            Ok(sent) => sent,
+            Err(e) => {
+                warn!("{e:#}, no more reports are emailed");
+                return;
+            }
+        };
+        match sent {
+            None => {}
+            Some(Ok(())) => self.succeeded("Sending the report"),
+            Some(Err(e)) => {
+                report.retry_at = now + EMAIL_RETRY_INTERVAL;
+                self.warn_throttled(
+                    "Sending the report",
+                    &format!("Failed to send the report, it stays queued: {e:#}"),
+                );
+            }
+        }
+        if let Some(sender) = &report.sender {
+            let retry = !sender.outbox().is_empty() && now >= report.retry_at;
+            if now >= report.next_at {
+                report.next_at = report.next_after(now);
+                match self.store.load() {
+                    Ok(entries) => {
+                        let email = report.email(&entries, now);
+                        report.start_sending(Some(email));
+                    }
+                    Err(e) => self.warn_throttled(
+                        "Sending the report",
+                        &format!("Failed to make the report: {e:#}"),
+                    ),
+                }
+            } else if retry {
+                report.start_sending(None);
+            }
+        }
+        self.report = Some(report);
+    }
+
+    /// Hands something to the MQTT sink, when there is one.
+    #[cfg(feature = "mqtt")]
+    fn publish(&mut self, send: impl FnOnce(&mut MqttSink) -> Result<()>) {
+        let Some(sink) = self.mqtt.as_mut() else {
+            return;
+        };
+        match send(sink) {
+            Ok(()) => self.succeeded("Publishing to MQTT"),
+            Err(e) => self.warn_throttled(
+                "Publishing to MQTT",
+                &format!("Failed to queue MQTT messages: {e:#}"),
+            ),
+        }
+    }
+
+    /// Keeps the player's answer for `now --grace`.
+    fn cache_now(&mut self, playing: Option<&CurrentlyPlayingTrack>, fetched_at: SystemTime) {
+        let Some(cache) = &self.now_cache else {
+            return;
+        };
+        match cache.save(playing, fetched_at) {
+            Ok(()) => self.succeeded("Saving the now playing cache"),
+            Err(e) => self.warn_throttled(
+                "Saving the now playing cache",
+                &format!("Failed to save the now playing cache: {e:#}"),
+            ),
+        }
+    }
+
+    /// Warns about a failure of `category`, repeats are only summarized now
+    /// and then so an outage doesn't flood the log.
+    fn warn_throttled(&mut self, category: &str, message: &str) {
+        match self.log_throttle.failure_at(category, Instant::now()) {
+            LogAction::Log => warn!("{message}"),
+            LogAction::Summarize { count, over } => warn!(
+                "{message} (seen {count} more times in the last {}m)",
+                over.as_secs() / 60
+            ),
+            LogAction::Suppress => {}
+        }
+    }
+
+    /// Logs that `category` works again, when it was failing.
+    fn succeeded(&mut self, category: &str) {
+        if let Some(recovery) = self.log_throttle.success_at(category, Instant::now()) {
+            info!(
+                "{category} works again after {} failures over {}s",
+                recovery.failures,
+                recovery.down_for.as_secs()
+            );
+        }
+    }
+
+    fn record(&mut self, play: PlayHistoryEntry) -> Result<()> {
+        info!("Recording play of {}", play.track_name);
+        self.store.append(&play)?;
+        self.plays_recorded += 1;
+        #[cfg(feature = "mqtt")]
+        self.publish(|sink| sink.play(&play));
+        // The journal only ever holds the play in progress
+        if let Err(e) = self.journal.truncate() {
+            self.warn_throttled(
+                "Saving progress",
+                &format!("Failed to clear the journal: {e}"),
+            );
+        }
+        self.journaled_at = None;
+        Ok(())
+    }
+
+    /// Carries on with the play the journal saved before a crash, unless it
+    /// made it into the history already.
+    fn recover(&mut self) -> Result<()> {
+        let record = match self.journal.replay() {
+            Ok(Some(record)) => record,
+            Ok(None) => return Ok(()),
+            // Losing one play in progress beats not recording at all
+            Err(e) if e.is::<io::Error>() => {
+                warn!(
+                    "Could not read {}, starting without it: {e:#}",
+                    self.journal.path().display()
+                );
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(play) = record.pending {
+            let history = self.store.load()?;
+            let recorded = history.last().is_some_and(|last| {
+                last.track_id == play.track_id && last.played_at == play.played_at
+            });
+            if !recorded {
+                let saved_at = DateTime::<Local>::from(record.saved_at);
+                info!(
+                    "Recovered the play of {} last saved at {}",
+                    play.track_name,
+                    saved_at.format("%Y-%m-%d %H:%M:%S")
+                );
+                self.tracker.resume(play);
+            }
+        }
+        self.journal.truncate()
+    }
+
+    /// Saves the play in progress when the journal interval has passed.
+    fn save_progress(&mut self, now: Instant) {
+        let due = self
+            .journaled_at
+            .is_none_or(|at| now.duration_since(at) >= self.journal_interval);
+        let Some(play) = self.tracker.current().filter(|_| due) else {
+            return;
+        };
+        match self.journal.append(Some(play), SystemTime::now()) {
+            Ok(()) => self.succeeded("Saving progress"),
+            Err(e) => self.warn_throttled(
+                "Saving progress",
+                &format!(
+                    "Failed to save the play in progress to {}: {e}",
+                    self.journal.path().display()
+                ),
+            ),
+        }
+        self.journaled_at = Some(now);
+    }
+
+    /// Records the play in progress, e.g. before pausing or exiting.
+    fn flush(&mut self) -> Result<()> {
+        match self.tracker.finish() {
+            Some(play) => self.record(play),
+            None => Ok(()),
+        }
+    }
+
+    /// `position_ms` is where the player is in the item it reported and
+    /// `context` what it plays it from, if any.
+    fn observe(
+        &mut self,
+        snapshot: Option<(Listen, Confidence)>,
+        device: Option<&str>,
+        position_ms: Option<u32>,
+        context: Option<PlayContext>,
+    ) -> Result<()> {
+        if !self.tracking {
+            return Ok(());
+        }
+        if let Some((Listen::Track(track), _)) = &snapshot {
+            let started = self
+                .tracker
+                .current()
+                .is_none_or(|p| p.track_id != track.original_id());
+            if started {
+                self.note_availability(track);
+            }
+        }
+        #[cfg(feature = "mqtt")]
+        self.publish(|sink| sink.now_playing(snapshot.as_ref().map(|(listen, _)| listen)));
+        let observed = snapshot.is_some();
+        let completed = self.tracker.observe(snapshot, SystemTime::now());
+        if let Some(device) = device {
+            self.tracker.note_device(device);
+        }
+        if let Some(position_ms) = position_ms.filter(|_| observed) {
+            self.tracker.note_position(position_ms);
+        }
+        if let Some(context) = context.filter(|_| observed) {
+            self.tracker.note_context(context);
+        }
+        match completed {
+            Some(play) => self.record(play),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs a track turning unplayable or playable again, once per play.
+    fn note_availability(&mut self, track: &Track) {
+        match self.store.record_availability(track, SystemTime::now()) {
+            Ok(Some(change)) if change.playable => {
+                info!("{} is playable again", change.track_name)
+            }
+            Ok(Some(change)) => info!(
+                "{} became unplayable: {}",
+                change.track_name,
+                change.reason.as_deref().unwrap_or("no reason given")
+            ),
+            Ok(None) => {}
+            Err(e) => self.warn_throttled(
+                "Saving availability",
+                &format!("Failed to log the availability of {}: {e}", track.name),
+            ),
+        }
+    }
+
+    fn handle(&mut self, spotify: &mut SpotifyClient, command: ControlCommand) -> Response {
+        info!("Control command: {command:?}");
+        let result = match command {
+            ControlCommand::Tag { label } => {
+                if self.tracker.tag_current(&label) {
+                    info!("Tagged the current play with <{label}>");
+                    self.journaled_at = None;
+                    Ok(())
+                } else {
+                    Err(anyhow!("Nothing is playing, dropping tag <{label}>"))
+                }
+            }
+            ControlCommand::Status => return Response::with_status(self.status()),
+            ControlCommand::PauseTracking => {
+                self.tracking = false;
+                self.flush()
+            }
+            ControlCommand::ResumeTracking => {
+                self.tracking = true;
+                Ok(())
+            }
+            ControlCommand::ReloadConfig => wait!(spotify.load_creds()),
+            ControlCommand::Shutdown => {
+                self.shutting_down = true;
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => Response::ok(),
+            Err(e) => {
+                warn!("{e}");
+                Response::error(e.to_string())
+            }
+        }
+    }
+
+    fn status(&self) -> DaemonStatus {
+        DaemonStatus {
+            pid: std::process::id(),
+            started_at: self.started_at,
+            tracking: self.tracking,
+            current_track: self.tracker.current().map(|p| p.track_name.clone()),
+            plays_recorded: self.plays_recorded,
+            warnings_suppressed: self.log_throttle.suppressed(),
+        }
+    }
+}
+
+/// Takes the daemon's lock. With `takeover` the daemon holding it is asked
+/// to shut down, through the control socket where there is one.
+fn lock_instance(
+    lock_file: &Path,
+    control_dir: &Path,
+    socket: Option<&Path>,
+    takeover: bool,
+) -> Result<InstanceLock> {
+    match InstanceLock::acquire(lock_file) {
+        Err(e) if takeover && e.is::<AlreadyRunning>() => {
+            info!("{e}, asking it to shut down");
+            if cfg!(unix) {
+                let socket = socket.map_or_else(default_socket_path, Path::to_path_buf);
+                let response = control_socket::send(&socket, ControlCommand::Shutdown)?;
+                if !response.ok {
+                    bail!("The daemon refused: {}", response.error.unwrap_or_default());
+                }
+            } else {
+                ControlChannel::new(control_dir.to_path_buf()).send(ControlCommand::Shutdown)?;
+            }
+            InstanceLock::acquire_within(lock_file, DEFAULT_TAKEOVER_TIMEOUT)
+        }
+        result => result,
+    }
+}
+
+/// The weekly report the daemon emails.
+#[cfg(feature = "email")]
+struct ScheduledReport {
+    schedule: ReportSchedule,
+    // None while a worker thread sends with it
+    sender: Option<EmailSender>,
+    // The worker hands the sender back with how sending went
+    sending: Option<Receiver<(EmailSender, Result<()>)>>,
+    // Reports are made in this time zone, the local one when None
+    tz: Option<Tz>,
+    locale: Locale,
+    next_at: SystemTime,
+    // When queued reports are tried again
+    retry_at: SystemTime,
+}
+
+#[cfg(feature = "email")]
+impl ScheduledReport {
+    fn new(
+        schedule: ReportSchedule,
+        sender: EmailSender,
+        tz: Option<Tz>,
+        locale: Locale,
+        now: SystemTime,
+    ) -> ScheduledReport {
+        let mut report = ScheduledReport {
+            schedule,
+            sender: Some(sender),
+            sending: None,
+            tz,
+            locale,
+            next_at: now,
+            retry_at: now,
+        };
+        report.next_at = report.next_after(now);
+        info!(
+            "Emailing the weekly report next at {}",
+            DateTime::<Local>::from(report.next_at).format("%a %Y-%m-%d %H:%M")
+        );
+        report
+    }
+
+    fn next_after(&self, now: SystemTime) -> SystemTime {
+        let now = DateTime::<Utc>::from(now);
+        match self.tz {
+            Some(tz) => self.schedule.next_after(&now.with_timezone(&tz)).into(),
+            None => self.schedule.next_after(&now.with_timezone(&Local)).into(),
+        }
+    }
+
+    /// The report of the week up to `now`.
+    fn email(&self, entries: &[PlayHistoryEntry], now: SystemTime) -> Email {
+        let report = listening_report(entries, 7, now, self.tz, self.locale);
+        Email::from(&report)
+    }
+
+    /// Sends `email` behind the queued ones on a worker thread, only the
+    /// queued ones when None, so a slow SMTP server doesn't hold up the
+    /// polls. What fails stays in the outbox for the next retry.
+    fn start_sending(&mut self, email: Option<Email>) {
+        let Some(mut sender) = self.sender.take() else {
+            return;
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let sent = match email {
+                Some(email) => sender.send(email),
+                None => sender.flush(),
+            };
+            let _ = tx.send((sender, sent));
+        });
+        self.sending = Some(rx);
+    }
+
+    /// How the worker's sending went once it is done, None while it is at
+    /// it or when nothing was sent. Err when the worker died with the sender.
+    fn sent(&mut self) -> Result<Option<Result<()>>> {
+        let Some(rx) = &self.sending else {
+            return Ok(None);
+        };
+        match rx.try_recv() {
+            Ok((sender, sent)) => {
+                self.sender = Some(sender);
+                self.sending = None;
+                Ok(Some(sent))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => bail!("The report's email worker stopped"),
+        }
+    }
+}
+
+/// The report of the last `days` up to `now`, in `tz` or the local time zone.
+#[cfg(feature = "email")]
+fn listening_report(
+    entries: &[PlayHistoryEntry],
+    days: u64,
+    now: SystemTime,
+    tz: Option<Tz>,
+    locale: Locale,
+) -> ListeningReport {
+    let now = DateTime::<Utc>::from(now);
+    match tz {
+        Some(tz) => ListeningReport::last_days(entries, days, &now.with_timezone(&tz), locale),
+        None => ListeningReport::last_days(entries, days, &now.with_timezone(&Local), locale),
+    }
+}
+
+#[cfg(feature = "email")]
+fn report_command(command: ReportCommand, locale: Locale, tz: Option<Tz>) -> Result<()> {
+    match command {
+        ReportCommand::Send {
+            range,
+            history,
+            dry_run,
+            email,
+        } => {
+            let entries = HistoryStore::new(history).load()?;
+            let report = listening_report(&entries, range, SystemTime::now(), tz, locale);
+            let message = Email::from(&report);
+            if dry_run {
+                print!("{}", email.config(None)?.render(&message)?);
+                return Ok(());
+            }
+            let storage = match email.smtp_username {
+                Some(_) => Some(wait!(CredStorage::new())?),
+                None => None,
+            };
+            let mut sender = email.sender(storage.as_ref())?;
+            if let Err(e) = sender.send(message) {
+                let outbox = sender.outbox().path().display();
+                return Err(e.context(format!(
+                    "The report is queued in {outbox}, it goes out with the next one"
+                )));
+            }
+            println!("Sent {:?}", report.subject());
+            Ok(())
+        }
+    }
+}
+
+fn daemon(
+    spotify: &mut SpotifyClient,
+    _lock: InstanceLock,
+    mut daemon: Daemon,
+    control: ControlChannel,
+    socket: PathBuf,
+    queue_assisted: bool,
+    interval: Duration,
+) -> Result<()> {
+    match wait!(spotify.get_user_profile()) {
+        Ok(profile) => info!(
+            "Recording plays of {} into {}",
+            profile.name(),
+            daemon.store.path().display()
+        ),
+        Err(e) => {
+            warn!("Could not get the user's profile: {e}");
+            info!("Recording plays into {}", daemon.store.path().display());
+        }
+    }
+    daemon.recover()?;
+    let server = match ControlServer::bind(&socket) {
+        Ok(server) => Some(server),
+        Err(e) if cfg!(unix) => return Err(e),
+        Err(e) => {
+            warn!("{e}");
+            None
+        }
+    };
+    loop {
+        match control.drain(SystemTime::now(), DEFAULT_COMMAND_MAX_AGE) {
+            Err(e) => daemon.warn_throttled(
+                "Reading commands",
+                &format!(
+                    "Failed to read commands from {}: {e}",
+                    control.dir().display()
+                ),
+            ),
+            Ok(commands) => {
+                daemon.succeeded("Reading commands");
+                for command in commands {
+                    daemon.handle(spotify, command);
+                }
+            }
+        }
+        if daemon.shutting_down {
+            break;
+        }
+
+        match wait!(spotify.get_playback_state()) {
+            Err(e) => daemon.warn_throttled(
+                "Polling the player",
+                &format!("Failed to poll the player: {e}"),
+            ),
+            Ok(state) => {
+                daemon.succeeded("Polling the player");
+                daemon.cache_now(state.as_ref().map(|s| &s.playing), SystemTime::now());
+                let player_idle = state.as_ref().is_none_or(|s| s.playing.item.is_none());
+                let queue = if queue_assisted && player_idle {
+                    match wait!(spotify.get_queue()) {
+                        Ok(queue) => {
+                            daemon.succeeded("Fetching the queue");
+                            Some(queue)
+                        }
+                        Err(e) => {
+                            daemon.warn_throttled(
+                                "Fetching the queue",
+                                &format!("Failed to fetch the queue: {e}"),
+                            );
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                let device = state.as_ref().map(|s| s.device.name.as_str());
+                let playing = state
+                    .as_ref()
+                    .map(|s| &s.playing)
+                    .filter(|p| p.item.is_some());
+                let position_ms = playing.and_then(|p| p.progress_ms);
+                let context = playing.map(|p| PlayContext::of(p.context.as_ref()));
+                daemon.observe(
+                    reconcile(state.as_ref(), queue.as_ref()),
+                    device,
+                    position_ms,
+                    context,
+                )?;
+            }
+        }
+        daemon.save_progress(Instant::now());
+        #[cfg(feature = "mqtt")]
+        daemon.publish(MqttSink::flush);
+        #[cfg(feature = "email")]
+        daemon.send_due_report(SystemTime::now());
+
+        // Wait for the next poll, answering the control socket meanwhile
+        let next_poll = Instant::now() + interval;
+        while !daemon.shutting_down && Instant::now() < next_poll {
+            if let Some(server) = &server {
+                match server.poll(|command| daemon.handle(spotify, command)) {
+                    Ok(_) => daemon.succeeded("The control socket"),
+                    Err(e) => daemon.warn_throttled(
+                        "The control socket",
+                        &format!("Control socket failed: {e}"),
+                    ),
+                }
+            }
+            thread::sleep(CONTROL_TICK.min(interval));
+        }
+        if daemon.shutting_down {
+            break;
+        }
+    }
+
+    info!("Shutting down");
+    daemon.flush()
+}
+
+fn tag(control: ControlChannel, socket: Option<PathBuf>, label: &str) -> Result<()> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(UsageError("The tag label can't be empty".to_string()).into());
+    }
+    let command = ControlCommand::Tag {
+        label: label.to_string(),
+    };
+    if cfg!(unix) {
+        return ctl(socket, command);
+    }
+    control.send(command)?;
+    info!("Asked the daemon to tag the current play with <{label}>");
+    Ok(())
+}
+
+fn ctl(socket: Option<PathBuf>, command: ControlCommand) -> Result<()> {
+    let socket = socket.unwrap_or_else(default_socket_path);
+    let response = control_socket::send(&socket, command)?;
+    if !response.ok {
+        bail!("The daemon refused: {}", response.error.unwrap_or_default());
+    }
+    match response.status {
+        Some(status) => {
+            let started = DateTime::<Local>::from(status.started_at).format("%Y-%m-%d %H:%M");
+            println!("Daemon pid {} running since {started}", status.pid);
+            println!(
+                "Tracking: {}",
+                if status.tracking { "on" } else { "paused" }
+            );
+            println!(
+                "Playing: {}",
+                status.current_track.as_deref().unwrap_or("nothing")
+            );
+            println!("Plays recorded: {}", status.plays_recorded);
+            println!("Warnings suppressed: {}", status.warnings_suppressed);
+        }
+        None => println!("Done"),
+    }
+    Ok(())
+}
+
+fn stats_command<Tz: TimeZone>(
+    store: HistoryStore,
+    days: Option<u64>,
+    devices: DeviceFilter,
+    command: StatsCommand,
+    color: bool,
+    locale: Locale,
+    tz: &Tz,
+) -> Result<()> {
+    let mut entries = store.load()?;
+    // What's new is told apart by the plays before the range, on any device
+    if let StatsCommand::Discoveries { range, json, .. } = command {
+        let found = discoveries_in(&entries, range, tz);
+        match json {
+            true => println!("{}", serde_json::to_string_pretty(&found)?),
+            false => print!("{}", discoveries_report(&found, color, locale, tz)),
+        }
+        return Ok(());
+    }
+    if let Some(days) = days {
+        let since = SystemTime::from(last_days_start(days, &Utc::now().with_timezone(tz)));
+        entries.retain(|entry| entry.played_at >= since);
+    }
+    let aliases = devices.apply(&mut entries)?;
+
+    match command {
+        StatsCommand::Summary { json } => {
+            let stats = ListeningStats::from_entries(&entries);
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&stats)?),
+                false => print!("{}", listening_summary(&stats, color, locale)),
+            }
+            Ok(())
+        }
+        StatsCommand::Tags => {
+            let stats = tag_stats(&entries);
+            if stats.is_empty() {
+                println!("No tagged plays yet, tag one with `tag <label>`");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Tag", "Plays", "Listened"])
+                .max_width(0, 30)
+                .align(1, Align::Right)
+                .align(2, Align::Right)
+                .with_color(color);
+            for tag in stats {
+                table.add_row(vec![
+                    tag.tag,
+                    locale.number(tag.plays),
+                    locale.duration(tag.listened),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Days => {
+            let days = plays_per_day(&entries, tz);
+            if days.is_empty() {
+                println!("No plays in this range");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Day", "Plays"])
+                .align(1, Align::Right)
+                .with_color(color);
+            for (day, plays) in days {
+                table.add_row(vec![locale.date(day), locale.number(plays)]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Hours => {
+            let listening = listening_by_hour(&entries, tz);
+            if listening.is_empty() {
+                println!("No plays in this range");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Hour", "Listened"])
+                .align(1, Align::Right)
+                .with_color(color);
+            for (hour, listened) in listening.by_hour().iter().enumerate() {
+                table.add_row(vec![format!("{hour:02}:00"), locale.duration(*listened)]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Artists { limit } => {
+            let artists = top_artists(&entries, limit);
+            if artists.is_empty() {
+                println!("No plays in this range");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Artist", "Plays", "Listened"])
+                .max_width(0, 40)
+                .align(1, Align::Right)
+                .align(2, Align::Right)
+                .with_color(color);
+            for artist in artists {
+                table.add_row(vec![
+                    artist.name,
+                    locale.number(artist.plays),
+                    locale.duration(artist.listened),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Devices => {
+            let devices = device_stats(&entries, &aliases);
+            if devices.is_empty() {
+                println!("No plays in this range");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Device", "Plays", "Listened"])
+                .max_width(0, 30)
+                .align(1, Align::Right)
+                .align(2, Align::Right)
+                .with_color(color);
+            for device in devices {
+                table.add_row(vec![
+                    device.device,
+                    locale.number(device.plays),
+                    locale.duration(device.listened),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Contexts { json } => {
+            let contexts = context_stats(&entries);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&contexts)?);
+                return Ok(());
+            }
+            if contexts.is_empty() {
+                println!("No plays in this range");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Played from", "Plays", "Listened", "Share"])
+                .align(1, Align::Right)
+                .align(2, Align::Right)
+                .align(3, Align::Right)
+                .with_color(color);
+            for context in contexts {
+                table.add_row(vec![
+                    context.kind,
+                    locale.number(context.plays),
+                    locale.duration(context.listened),
+                    format!("{:.0}%", context.share * 100.0),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Episodes => {
+            let episodes = episode_completion(&entries);
+            if episodes.is_empty() {
+                println!("No episodes played in this range");
+                return Ok(());
+            }
+
+            let mut table = Table::new(&["Episode", "Show", "Played"])
+                .max_width(0, 40)
+                .max_width(1, 30)
+                .align(2, Align::Right)
+                .with_color(color);
+            for episode in episodes {
+                table.add_row(vec![
+                    episode.name,
+                    episode.show,
+                    format!("{:.0}%", episode.completion * 100.0),
+                ]);
+            }
+            print!("{}", table.render());
+            Ok(())
+        }
+        StatsCommand::Discoveries { .. } => unreachable!("handled before the filters"),
+        StatsCommand::SkipPoints {
+            track_id,
+            analysis_cache,
+            json,
+        } => {
+            let analysis = AnalysisCache::new(analysis_cache)
+                .get(&track_id)?
+                .ok_or_else(|| anyhow!("No audio analysis of {track_id} was fetched"))?;
+            let points = skip_points(&entries, &track_id, &analysis);
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&points)?),
+                false => print!("{}", skip_points_report(&points, color)),
+            }
+            Ok(())
+        }
+        #[cfg(feature = "charts")]
+        StatsCommand::Chart { kind, out, limit } => {
+            match kind {
+                ChartKind::Days => Chart::PlaysPerDay(&plays_per_day(&entries, tz)).render(&out),
+                ChartKind::Hours => {
+                    Chart::ListeningByHour(&listening_by_hour(&entries, tz)).render(&out)
+                }
+                ChartKind::Artists => Chart::TopArtists(&top_artists(&entries, limit)).render(&out),
+            }?;
+            println!("Wrote {}", out.display());
+            Ok(())
+        }
+    }
+}
+
+/// The discoveries of the last `range` calendar days in `tz`.
+fn discoveries_in<Tz: TimeZone>(entries: &[PlayHistoryEntry], range: u64, tz: &Tz) -> Discoveries {
+    let since = last_days_start(range, &Utc::now().with_timezone(tz));
+    discoveries(entries, since.into())
+}
+
+fn discoveries_report<Tz: TimeZone>(
+    found: &Discoveries,
+    color: bool,
+    locale: Locale,
+    tz: &Tz,
+) -> String {
+    if found.is_empty() {
+        return "Nothing new in this range\n".to_string();
+    }
+    let day =
+        |at: SystemTime| locale.date(DateTime::<Utc>::from(at).with_timezone(tz).date_naive());
+    let mut out = String::new();
+    if !found.tracks.is_empty() {
+        let mut table = Table::new(&["New track", "Artists", "First played", "Plays"])
+            .max_width(0, 40)
+            .max_width(1, 30)
+            .align(3, Align::Right)
+            .with_color(color);
+        for track in &found.tracks {
+            table.add_row(vec![
+                track.name.clone(),
+                track.artists.clone(),
+                day(track.first_played),
+                locale.number(track.plays),
+            ]);
+        }
+        out += &table.render();
+    }
+    if !found.artists.is_empty() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        let mut table = Table::new(&["New artist", "First played", "Plays", "Listened"])
+            .max_width(0, 40)
+            .align(2, Align::Right)
+            .align(3, Align::Right)
+            .with_color(color);
+        for artist in &found.artists {
+            table.add_row(vec![
+                artist.name.clone(),
+                day(artist.first_played),
+                locale.number(artist.plays),
+                locale.duration(artist.listened),
+            ]);
+        }
+        out += &table.render();
+    }
+    out
+}
+
+/// Appends the discovered tracks `playlist` doesn't have yet, then removes
+/// its first tracks past `keep`, so it rolls on with what's new. The removal
+/// is made against the snapshot the append returned, so it fails instead of
+/// removing other tracks when the playlist changed in between.
+fn add_discoveries(
+    spotify: &mut SpotifyClient,
+    playlist: &str,
+    found: &Discoveries,
+    keep: usize,
+) -> Result<()> {
+    let mut ids = wait!(spotify.get_playlist_track_ids(playlist))?;
+    let mut known: HashSet<String> = ids.iter().cloned().collect();
+    let mut snapshot = None;
+    let new: Vec<String> = found
+        .tracks
+        .iter()
+        .map(|track| track.track_id.clone())
+        // Plays imported from the simple export have no Spotify id
+        .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()))
+        .filter(|id| known.insert(id.clone()))
+        .collect();
+    if !new.is_empty() {
+        let uris: Vec<String> = new.iter().map(|id| format!("spotify:track:{id}")).collect();
+        let uris: Vec<&str> = uris.iter().map(String::as_str).collect();
+        snapshot = Some(wait!(spotify.add_tracks_to_playlist(playlist, &uris))?);
+        ids.extend(new.iter().cloned());
+    }
+    let over = ids.len().saturating_sub(keep);
+    let (trimmed, removed) = tracks_to_trim(&ids, over);
+    if !trimmed.is_empty() {
+        let uris: Vec<String> = trimmed
+            .iter()
+            .map(|id| format!("spotify:track:{id}"))
+            .collect();
+        let uris: Vec<&str> = uris.iter().map(String::as_str).collect();
+        wait!(spotify.remove_tracks_from_playlist(playlist, &uris, snapshot.as_deref()))?;
+    }
+    info!(
+        "Added {} new tracks to {playlist}, removed {removed} old ones",
+        new.len()
+    );
+    Ok(())
+}
+
+/// The tracks to remove for dropping the first `over` of `ids`, and how
+/// many items that removes. Spotify removes every occurrence of a track, so
+/// one that is also further down stays rather than losing the later copy.
+fn tracks_to_trim(ids: &[String], over: usize) -> (Vec<&str>, usize) {
+    let (old, kept) = ids.split_at(over.min(ids.len()));
+    let kept: HashSet<&str> = kept.iter().map(String::as_str).collect();
+    let old: Vec<&str> = old
+        .iter()
+        .map(String::as_str)
+        .filter(|id| !kept.contains(id))
+        .collect();
+    let removed = old.len();
+    let mut seen = HashSet::new();
+    let tracks = old.into_iter().filter(|id| seen.insert(*id)).collect();
+    (tracks, removed)
+}
+
+/// Fetches the audio analysis of `track_id` into `cache`, unless it is
+/// there already.
+fn cache_audio_analysis(
+    spotify: &mut SpotifyClient,
+    cache: &AnalysisCache,
+    track_id: &str,
+) -> Result<()> {
+    if cache.get(track_id)?.is_none() {
+        let analysis = wait!(spotify.get_audio_analysis(track_id))?;
+        cache.put(track_id, &analysis)?;
+    }
+    Ok(())
+}
+
+/// Seconds as `m:ss`.
+fn minutes_seconds(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn skip_points_report(points: &SkipPoints, color: bool) -> String {
+    if points.plays == 0 {
+        return format!("{} was never played\n", points.track_id);
+    }
+    let mut out = format!("Plays: {}, skipped: {}\n", points.plays, points.skips);
+    let Some(most) = points.most_skipped() else {
+        out += "No skip positions recorded\n";
+        return out;
+    };
+    let section = &most.section;
+    out += &format!(
+        "Skipped most in section {}, {} to {}\n",
+        most.index + 1,
+        minutes_seconds(section.start),
+        minutes_seconds(section.start + section.duration)
+    );
+
+    let mut table = Table::new(&["Section", "Start", "Length", "Loudness", "Tempo", "Skips"])
+        .align(0, Align::Right)
+        .align(1, Align::Right)
+        .align(2, Align::Right)
+        .align(3, Align::Right)
+        .align(4, Align::Right)
+        .align(5, Align::Right)
+        .with_color(color);
+    for section in &points.sections {
+        table.add_row(vec![
+            (section.index + 1).to_string(),
+            minutes_seconds(section.section.start),
+            minutes_seconds(section.section.duration),
+            format!("{:.1} dB", section.section.loudness),
+            format!("{:.0} bpm", section.section.tempo),
+            section.skips.to_string(),
+        ]);
+    }
+    out += &table.render();
+    out
+}
+
+fn listening_summary(stats: &ListeningStats, color: bool, locale: Locale) -> String {
+    if stats.plays == 0 {
+        return "No plays in this range\n".to_string();
+    }
+    let mut out = format!("Plays:    {}\n", locale.number(stats.plays));
+    out += &format!("Listened: {}\n", locale.duration(stats.listened));
+    out += &format!("  Music:    {}\n", locale.duration(stats.music));
+    out += &format!("  Podcasts: {}\n\n", locale.duration(stats.podcasts));
+
+    let mut artists = Table::new(&["Top artist", "Plays", "Listened"])
+        .max_width(0, 40)
+        .align(1, Align::Right)
+        .align(2, Align::Right)
+        .with_color(color);
+    for artist in &stats.top_artists {
+        artists.add_row(vec![
+            artist.name.clone(),
+            locale.number(artist.plays),
+            locale.duration(artist.listened),
+        ]);
+    }
+    out += &artists.render();
+    out += "\n";
+
+    let mut tracks = Table::new(&["Top track", "Artists", "Plays"])
+        .max_width(0, 40)
+        .max_width(1, 30)
+        .align(2, Align::Right)
+        .with_color(color);
+    for track in &stats.top_tracks {
+        tracks.add_row(vec![
+            track.name.clone(),
+            track.artists.clone(),
+            locale.number(track.plays),
+        ]);
+    }
+    out += &tracks.render();
+    if stats.top_shows.is_empty() {
+        return out;
+    }
+
+    let mut shows = Table::new(&["Top show", "Episodes", "Listened"])
+        .max_width(0, 40)
+        .align(1, Align::Right)
+        .align(2, Align::Right)
+        .with_color(color);
+    for show in &stats.top_shows {
+        shows.add_row(vec![
+            show.name.clone(),
+            locale.number(show.plays),
+            locale.duration(show.listened),
+        ]);
+    }
+    out += "\n";
+    out += &shows.render();
+    out
+}
+
+/// The looked back years, each with a line of totals and its top tracks.
+fn on_this_day_report(years: &[OnThisDay], day: NaiveDate, color: bool, locale: Locale) -> String {
+    if years.is_empty() {
+        return format!(
+            "Nothing was played on {} in earlier years\n",
+            locale.day_month(day)
+        );
+    }
+    let mut out = String::new();
+    for (i, year) in years.iter().enumerate() {
+        if i > 0 {
+            out += "\n";
+        }
+        let days: Vec<String> = year.days.iter().map(|day| locale.date(*day)).collect();
+        out += &format!(
+            "{}: {} plays, {}\n",
+            days.join(" and "),
+            locale.number(year.plays),
+            locale.duration(year.listened)
+        );
+        if year.top_tracks.is_empty() {
+            continue;
+        }
+
+        let mut tracks = Table::new(&["Top track", "Artists", "Plays"])
+            .max_width(0, 40)
+            .max_width(1, 30)
+            .align(2, Align::Right)
+            .with_color(color);
+        for track in &year.top_tracks {
+            tracks.add_row(vec![
+                track.name.clone(),
+                track.artists.clone(),
+                locale.number(track.plays),
+            ]);
+        }
+        out += &tracks.render();
+    }
+    out
+}
+
+fn log_event(event: &WatchEvent) {
+    match event {
+        WatchEvent::TrackChanged(track) => info!("Now playing: {}", track.name),
+        WatchEvent::EpisodeChanged(episode) => {
+            info!("Now playing: {} from {}", episode.name, episode.show.name)
+        }
+        WatchEvent::ChapterChanged(chapter) => info!(
+            "Now playing: {} from {} by {}",
+            chapter.name,
+            chapter.audiobook.name,
+            chapter.audiobook.author_names()
+        ),
+        WatchEvent::AdStarted => info!("Advertisement playing"),
+        WatchEvent::Paused => info!("Playback paused"),
+        WatchEvent::Resumed => info!("Playback resumed"),
+        WatchEvent::Stopped => info!("Playback stopped"),
+        WatchEvent::DeviceDisconnected(device) => {
+            warn!("Device <{}> went offline mid-session", device.name)
+        }
+    }
+}
+
+/// Bodies are logged at trace level, only this crate's trace logs are
+/// let through for them.
+fn setup_tracing(level: Level, log_bodies: bool) {
+    let mut targets = Targets::new().with_default(level);
+    if log_bodies {
+        targets = targets.with_target("spotify_rs", Level::TRACE);
+    }
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_target(true))
+        .with(targets)
         .init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+    use spotify_rs::spotify_data::Section;
+    use spotify_rs::stats::{SectionSkips, ShowStats, TrackStats};
+
+    fn rendered(err: anyhow::Error, verbose: bool) -> (String, u8) {
+        let report = ErrorReport::new(&err);
+        (report.render(&err, verbose), report.exit_code)
+    }
+
+    #[test]
+    fn test_revoked_refresh_token() {
+        let err = anyhow::Error::from(SpotifyError::RefreshTokenRevoked)
+            .context("No working Spotify creds and not allowed to ask, run `auth` to authorize");
+        let (out, code) = rendered(err, false);
+        assert_eq!(
+            out,
+            "error: Your Spotify refresh token was revoked or has expired\n  \
+             hint: run `spotify-rs auth` to authorize again\n  \
+             run with --verbose for details\n"
+        );
+        assert_eq!(code, EXIT_AUTH);
+    }
+
+    #[test]
+    fn test_network_failure_verbose() {
+        let err: Result<()> = Err(anyhow!("connection refused")).context(SpotifyError::Network);
+        let (out, code) = rendered(err.unwrap_err(), true);
+        assert_eq!(
+            out,
+            "error: Could not reach Spotify\n  \
+             hint: check your network connection and try again\n  \
+             caused by: connection refused\n"
+        );
+        assert_eq!(code, EXIT_NETWORK);
+    }
+
+    #[test]
+    fn test_upstream_outage() {
+        let err = anyhow::Error::from(SpotifyError::Upstream { status: 503 });
+        let (out, code) = rendered(err, false);
+        assert_eq!(
+            out,
+            "error: Spotify is having a temporary outage (HTTP 503)\n  \
+             hint: Spotify is having trouble, try again in a few minutes\n"
+        );
+        assert_eq!(code, EXIT_NETWORK);
+    }
+
+    #[test]
+    fn test_authorization_denied() {
+        let err = anyhow::Error::from(AuthorizationDenied {
+            reason: "access_denied".to_string(),
+            description: Some("User said no".to_string()),
+        });
+        let (out, code) = rendered(err, false);
+        assert_eq!(
+            out,
+            "error: The authorization was declined (User said no)\n  \
+             hint: run `spotify-rs auth` again and accept the app's access request\n"
+        );
+        assert_eq!(code, EXIT_AUTH);
+    }
+
+    #[test]
+    fn test_storage_failures() {
+        let err: Result<()> = Err(anyhow!(
+            "bitwarden_config.json is missing the `org_id` field"
+        ))
+        .context(StorageError::Config);
+        let (out, code) = rendered(err.unwrap_err(), false);
+        assert_eq!(
+            out,
+            "error: bitwarden_config.json can't be used\n  \
+             hint: run `spotify-rs doctor` to see which field is wrong\n  \
+             run with --verbose for details\n"
+        );
+        assert_eq!(code, EXIT_STORAGE);
+
+        let (out, _) = rendered(StorageError::Unreachable.into(), false);
+        assert_eq!(
+            out,
+            "error: Bitwarden is unreachable\n  \
+             hint: check the access token in bitwarden_config.json and your network connection\n"
+        );
+    }
+
+    #[test]
+    fn test_newer_state_file() {
+        let err: Result<()> = Err(NewerStateFile {
+            path: PathBuf::from("history.journal"),
+            version: 3,
+            supported: 2,
+        }
+        .into());
+        let (out, code) = rendered(
+            err.context("Could not recover the play").unwrap_err(),
+            false,
+        );
+        assert_eq!(
+            out,
+            "error: history.journal is format v3, this build only reads up to v2\n  \
+             hint: run the newer spotify-rs that wrote it, or move the file away\n  \
+             run with --verbose for details\n"
+        );
+        assert_eq!(code, EXIT_FAILURE);
+    }
+
+    #[test]
+    fn test_usage_and_other_errors() {
+        let (out, code) = rendered(
+            UsageError("The tag label can't be empty".into()).into(),
+            false,
+        );
+        assert_eq!(
+            out,
+            "error: The tag label can't be empty\n  hint: see `spotify-rs --help`\n"
+        );
+        assert_eq!(code, EXIT_USAGE);
+
+        let (out, code) = rendered(anyhow!("History file is corrupt"), false);
+        assert_eq!(out, "error: History file is corrupt\n");
+        assert_eq!(code, EXIT_FAILURE);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("7d").unwrap(), 7);
+        assert_eq!(parse_range("2w").unwrap(), 14);
+        assert_eq!(parse_range("30").unwrap(), 30);
+        assert!(parse_range("0d").is_err());
+        assert!(parse_range("week").is_err());
+    }
+
+    #[test]
+    fn test_diagnostics_report() {
+        let mut diagnostics = BTreeMap::new();
+        assert_eq!(
+            diagnostics_report(&diagnostics),
+            "Spotify sent no deprecation notices or rate limit headers\n"
+        );
+        diagnostics.insert(
+            "spotify-deprecation".to_string(),
+            SeenHeader {
+                value: "2025-06-01".to_string(),
+                url: "https://api.spotify.com/v1/me".to_string(),
+                seen_at: SystemTime::UNIX_EPOCH,
+            },
+        );
+        assert_eq!(
+            diagnostics_report(&diagnostics),
+            "Headers of interest from Spotify:\n  \
+             spotify-deprecation: 2025-06-01 (from https://api.spotify.com/v1/me)\n"
+        );
+    }
+
+    #[test]
+    fn test_token_info_masks_tokens() {
+        let access_token = "BQDa1xY7kPq9mZ2wL4sT8vN3cR6hJ0uE";
+        let refresh_token = "AQC9xW2rT5yU8iO1pA4sD7fG0hJ3kL6z";
+        let refreshed_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_727_127_000);
+        let auth = UserAuthData {
+            access_token: access_token.to_string(),
+            token_type: "Bearer".to_string(),
+            scope: "user-read-playback-state user-top-read".to_string(),
+            expires_in: 3600,
+            refresh_token: refresh_token.to_string(),
+            last_refresh: Some(refreshed_at),
+        };
+
+        let now = refreshed_at + Duration::from_secs(600);
+        let info = token_info(&auth, now, Duration::from_secs(300));
+        assert!(!info.contains(access_token));
+        assert!(!info.contains(refresh_token));
+        assert!(info.contains("BQDa…(32 chars)"));
+        assert!(info.contains("(in 50 min)"));
+        assert!(info.contains("Refresh due:   no"));
+        assert!(info.contains("  user-top-read\n"));
+        // Debug output is masked the same way
+        let debug = format!("{auth:?}");
+        assert!(!debug.contains(access_token));
+        assert!(!debug.contains(refresh_token));
+
+        let later = refreshed_at + Duration::from_secs(3900);
+        let info = token_info(&auth, later, Duration::from_secs(300));
+        assert!(info.contains("(expired 5 min ago)"));
+        assert!(info.contains("Refresh due:   yes"));
+    }
+
+    #[test]
+    fn test_whoami() {
+        let mut profile = UserProfile {
+            id: "jorge".to_string(),
+            display_name: None,
+            country: Some("SE".to_string()),
+            product: Some("premium".to_string()),
+            email: None,
+        };
+        let info = whoami(&profile);
+        assert!(info.starts_with("Name:    jorge\n"));
+        assert!(info.contains("Email:   not granted"));
+
+        profile.email = Some("jorge@example.com".to_string());
+        assert!(whoami(&profile).contains("Email:   jorge@example.com\n"));
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[test]
+    fn test_wait_drives_the_runtime() {
+        let answer = wait!(async {
+            tokio::task::yield_now().await;
+            42
+        });
+        assert_eq!(answer, 42);
+    }
+
+    #[test]
+    fn test_listening_summary() {
+        let mut stats = ListeningStats {
+            plays: 3,
+            listened: Duration::from_secs(600),
+            music: Duration::from_secs(600),
+            podcasts: Duration::ZERO,
+            top_artists: Vec::new(),
+            top_tracks: vec![TrackStats {
+                track_id: "1VY8".to_string(),
+                name: "The Divine Zero".to_string(),
+                artists: "Pierce The Veil".to_string(),
+                plays: 3,
+                listened: Duration::from_secs(600),
+            }],
+            top_shows: Vec::new(),
+        };
+        let summary = listening_summary(&stats, false, Locale::English);
+        assert!(summary.starts_with("Plays:    3\nListened: 10 m\n"));
+        assert!(summary.contains("The Divine Zero  Pierce The Veil      3"));
+        assert!(!summary.contains("Top show"));
+
+        stats.plays += 1;
+        stats.podcasts = Duration::from_secs(1800);
+        stats.listened += stats.podcasts;
+        stats.top_shows.push(ShowStats {
+            show_id: "38bS".to_string(),
+            name: "Listening Room".to_string(),
+            plays: 1,
+            listened: stats.podcasts,
+        });
+        let summary = listening_summary(&stats, false, Locale::English);
+        assert!(summary.contains("Listened: 40 m\n  Music:    10 m\n  Podcasts: 30 m\n"));
+        assert!(summary.contains("Listening Room         1      30 m"));
+
+        stats.plays = 12345;
+        stats.podcasts = Duration::from_secs(12345 * 60);
+        stats.listened = stats.music + stats.podcasts;
+        let summary = listening_summary(&stats, false, Locale::German);
+        assert!(summary.starts_with(
+            "Plays:    12.345\nListened: 205 Std. 55 Min.\n  Music:    10 Min.\n  Podcasts: 205 Std. 45 Min.\n"
+        ));
+
+        let empty = ListeningStats::from_entries(&[]);
+        assert_eq!(
+            listening_summary(&empty, false, Locale::English),
+            "No plays in this range\n"
+        );
+    }
+
+    #[test]
+    fn test_playing_line() {
+        let data = fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let track: CurrentlyPlayingTrack = serde_json::from_str(&data).unwrap();
+        assert_eq!(
+            playing_line(Some(track.clone())).unwrap(),
+            "Currently Playing: The Divine Zero"
+        );
+
+        let mut ad = track.clone();
+        ad.currently_playing_type = PlayingType::Ad;
+        ad.item = None;
+        assert_eq!(playing_line(Some(ad)).unwrap(), "Advertisement playing");
+
+        let mut unknown = track.clone();
+        unknown.currently_playing_type = PlayingType::Unknown;
+        assert_eq!(playing_line(Some(unknown)).unwrap(), "Unknown playback");
+
+        assert_eq!(playing_line(None).unwrap(), "Nothing is playing");
+        let mut missing = track;
+        missing.item = None;
+        assert_eq!(playing_line(Some(missing)), None);
+    }
+
+    #[test]
+    fn test_grace_only_covers_outages() {
+        let cache = std::env::temp_dir().join(format!("grace-{}.json", std::process::id()));
+        let data = fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let track: CurrentlyPlayingTrack = serde_json::from_str(&data).unwrap();
+        NowCache::new(&cache)
+            .save(Some(&track), SystemTime::now())
+            .unwrap();
+        let grace = GraceArgs {
+            grace: Some(60),
+            now_cache: cache.clone(),
+        };
+
+        assert!(grace.serve_stale(SpotifyError::Network.into()).is_ok());
+        let outage = anyhow::Error::from(SpotifyError::Upstream { status: 502 });
+        assert!(grace
+            .serve_stale(outage.context("Failed to get the player"))
+            .is_ok());
+        for err in [
+            SpotifyError::RefreshTokenRevoked,
+            SpotifyError::MissingCreds,
+        ] {
+            let err = grace.serve_stale(err.into()).unwrap_err();
+            assert!(err.is::<SpotifyError>());
+        }
+        assert!(grace.serve_stale(anyhow!("unexpected answer")).is_err());
+
+        let off = GraceArgs {
+            grace: None,
+            now_cache: cache.clone(),
+        };
+        assert!(off.serve_stale(SpotifyError::Network.into()).is_err());
+        fs::remove_file(&cache).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_journal_starts_fresh() {
+        let dir = std::env::temp_dir().join(format!("unreadable-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // A directory where the journal should be can't be read as one
+        let mut daemon = Daemon::new(
+            Duration::from_secs(5),
+            PlayJournal::new(&dir),
+            Duration::from_secs(10),
+            HistoryStore::new(dir.join("history.json")),
+            LogThrottle::new(Duration::from_secs(60)),
+        );
+        daemon.recover().unwrap();
+        assert!(daemon.tracker.current().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_control_round_trip() {
+        let dir = std::env::temp_dir().join(format!("control-round-trip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let socket = dir.join("control.sock");
+        let server = ControlServer::bind(&socket).unwrap();
+        let mut daemon = Daemon::new(
+            Duration::from_secs(5),
+            PlayJournal::new(&dir),
+            Duration::from_secs(10),
+            HistoryStore::new(dir.join("history.jsonl")),
+            LogThrottle::new(Duration::from_secs(60)),
+        );
+        let auth = UserAuthData {
+            access_token: "test-access-token".to_string(),
+            token_type: "Bearer".to_string(),
+            scope: String::new(),
+            expires_in: 3600,
+            refresh_token: "test-refresh-token".to_string(),
+            last_refresh: Some(SystemTime::now()),
+        };
+        let builder = SpotifyClientBuilder::new("tester".to_string())
+            .with_in_memory_creds("test-client-id".to_string(), auth);
+        let mut spotify = wait!(builder.build()).unwrap();
+
+        // The daemon answers on this thread, the way it does between polls
+        let client = {
+            let socket = socket.clone();
+            thread::spawn(move || {
+                [
+                    ControlCommand::Status,
+                    ControlCommand::PauseTracking,
+                    ControlCommand::Status,
+                    ControlCommand::Tag {
+                        label: "gym".to_string(),
+                    },
+                    ControlCommand::Shutdown,
+                ]
+                .into_iter()
+                .map(|command| control_socket::send(&socket, command).unwrap())
+                .collect::<Vec<_>>()
+            })
+        };
+        while !client.is_finished() {
+            server
+                .poll(|command| daemon.handle(&mut spotify, command))
+                .unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+        let responses = client.join().unwrap();
+
+        let status = responses[0].status.as_ref().unwrap();
+        assert!(status.tracking);
+        assert_eq!(status.pid, std::process::id());
+        assert_eq!(status.current_track, None);
+        assert!(responses[1].ok);
+        assert!(!responses[2].status.as_ref().unwrap().tracking);
+        assert!(!responses[3].ok);
+        assert_eq!(
+            responses[3].error.as_deref(),
+            Some("Nothing is playing, dropping tag <gym>")
+        );
+        assert!(responses[4].ok);
+        assert!(daemon.shutting_down);
+
+        drop(server);
+        assert!(!fs::exists(&socket).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_points_report() {
+        let section = |start, duration, skips, index| SectionSkips {
+            index,
+            section: Section {
+                start,
+                duration,
+                loudness: -6.2,
+                tempo: 98.0,
+            },
+            skips,
+        };
+        let mut points = SkipPoints {
+            track_id: "1VY8".to_string(),
+            plays: 5,
+            skips: 3,
+            sections: vec![section(0.0, 21.4, 1, 0), section(21.4, 58.3, 2, 1)],
+        };
+        let report = skip_points_report(&points, false);
+        assert!(
+            report.starts_with("Plays: 5, skipped: 3\nSkipped most in section 2, 0:21 to 1:20\n")
+        );
+        assert!(report.contains("2   0:21    0:58   -6.2 dB  98 bpm      2"));
+
+        for section in &mut points.sections {
+            section.skips = 0;
+        }
+        assert!(skip_points_report(&points, false).ends_with("No skip positions recorded\n"));
+        points.plays = 0;
+        assert_eq!(
+            skip_points_report(&points, false),
+            "1VY8 was never played\n"
+        );
+    }
+
+    #[test]
+    fn test_on_this_day_report() {
+        let day = |date: &str| date.parse::<NaiveDate>().unwrap();
+        let years = vec![
+            OnThisDay {
+                year: 2024,
+                days: vec![day("2024-02-28"), day("2024-02-29")],
+                plays: 3,
+                listened: Duration::from_secs(600),
+                top_tracks: vec![TrackStats {
+                    track_id: "1VY8".to_string(),
+                    name: "The Divine Zero".to_string(),
+                    artists: "Pierce The Veil".to_string(),
+                    plays: 3,
+                    listened: Duration::from_secs(600),
+                }],
+            },
+            OnThisDay {
+                year: 2023,
+                days: vec![day("2023-02-28")],
+                plays: 1,
+                listened: Duration::from_secs(1800),
+                top_tracks: Vec::new(),
+            },
+        ];
+        let report = on_this_day_report(&years, day("2025-02-28"), false, Locale::English);
+        assert!(report
+            .starts_with("Wed, February 28, 2024 and Thu, February 29, 2024: 3 plays, 10 m\n"));
+        assert!(report.contains("The Divine Zero  Pierce The Veil      3"));
+        assert!(report.ends_with("\n\nTue, February 28, 2023: 1 plays, 30 m\n"));
+
+        let report = on_this_day_report(&years[1..], day("2025-02-28"), false, Locale::German);
+        assert_eq!(report, "Di., 28. Februar 2023: 1 plays, 30 Min.\n");
+
+        assert_eq!(
+            on_this_day_report(&[], day("2025-06-01"), false, Locale::English),
+            "Nothing was played on June 1 in earlier years\n"
+        );
+        assert_eq!(
+            on_this_day_report(&[], day("2025-06-01"), false, Locale::German),
+            "Nothing was played on 1. Juni in earlier years\n"
+        );
+    }
+
+    #[test]
+    fn test_insert_before() {
+        // [a, b, c, d]: moving a to 2 gives [b, c, a, d], before d
+        assert_eq!(insert_before(0, 2, 1), 3);
+        // Moving d to 1 gives [a, d, b, c], before b
+        assert_eq!(insert_before(3, 1, 1), 1);
+        // Moving [a, b] to 1 gives [c, a, b, d], before d
+        assert_eq!(insert_before(0, 1, 2), 3);
+    }
+
+    #[cfg(feature = "email")]
+    #[test]
+    fn test_report_is_sent_off_the_poll_loop() {
+        let dir = std::env::temp_dir().join(format!("report-worker-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Nothing listens on the port, so the send fails
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::Plain,
+            username: None,
+            password: None,
+            from: "Music tracker <tracker@example.com>".to_string(),
+            to: vec!["jorge@example.com".to_string()],
+        };
+        let outbox = EmailOutbox::open(dir.join("outbox.json")).unwrap();
+        let sender = EmailSender::new(config, outbox).unwrap();
+        let now = SystemTime::now();
+        let schedule = "mon 08:00".parse().unwrap();
+        let report = ScheduledReport::new(schedule, sender, None, Locale::English, now);
+        let mut daemon = Daemon::new(
+            Duration::from_secs(5),
+            PlayJournal::new(&dir),
+            Duration::from_secs(10),
+            HistoryStore::new(dir.join("history.jsonl")),
+            LogThrottle::new(Duration::from_secs(60)),
+        )
+        .with_report(report);
+        daemon.report.as_mut().unwrap().next_at = now;
+
+        daemon.send_due_report(now);
+        // The worker has the sender, the poll loop goes on
+        assert!(daemon.report.as_ref().unwrap().sender.is_none());
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while daemon.report.as_ref().unwrap().sending.is_some() {
+            assert!(Instant::now() < deadline, "the report was never sent");
+            thread::sleep(Duration::from_millis(10));
+            daemon.send_due_report(now);
+        }
+        let report = daemon.report.as_ref().unwrap();
+        assert_eq!(report.sender.as_ref().unwrap().outbox().len(), 1);
+        assert_eq!(report.retry_at, now + EMAIL_RETRY_INTERVAL);
+        assert!(report.next_at > now);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tracks_to_trim() {
+        let ids: Vec<String> = ["a", "b", "a", "c", "b", "d"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect();
+        // b is kept further down, both copies of a go
+        assert_eq!(tracks_to_trim(&ids, 3), (vec!["a"], 2));
+        assert_eq!(tracks_to_trim(&ids, 0), (vec![], 0));
+        assert_eq!(tracks_to_trim(&ids, 10).1, 6);
+    }
+
+    #[test]
+    fn test_detected_user_is_the_default() {
+        let dir = std::env::temp_dir().join(format!("detected-user-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(resolve_user(None, &dir).unwrap(), USER);
+
+        // What `auth --detect-user` keeps is picked up by the next command
+        save_detected_user(&dir, "31abcdefghijklmnopqrstuvwxyz").unwrap();
+        let cli = Cli::try_parse_from(["spotify-rs", "now"]).unwrap();
+        assert_eq!(
+            resolve_user(cli.user, &dir).unwrap(),
+            "31abcdefghijklmnopqrstuvwxyz"
+        );
+        let cli = Cli::try_parse_from(["spotify-rs", "now", "--user", "jorge"]).unwrap();
+        assert_eq!(resolve_user(cli.user, &dir).unwrap(), "jorge");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_then_takes_one_value() {
+        let cli =
+            Cli::try_parse_from(["spotify-rs", "search", "--then", "queue", "heroes"]).unwrap();
+        let Some(Command::Search { query, pick, .. }) = cli.command else {
+            panic!("not a search");
+        };
+        assert_eq!(query, "heroes");
+        assert_eq!(pick.then, Some(PickAction::Queue));
+
+        let cli = Cli::try_parse_from(["spotify-rs", "queue", "--then", "playlist:pl1"]).unwrap();
+        let Some(Command::Queue { pick }) = cli.command else {
+            panic!("not a queue");
+        };
+        assert_eq!(pick.then, Some(PickAction::Playlist("pl1".to_string())));
+        assert_eq!(pick.capabilities(), [Capability::EditPlaylists]);
+        assert!(Cli::try_parse_from(["spotify-rs", "queue", "--then", "playlist"]).is_err());
+    }
+
+    #[test]
+    fn test_pick_action() {
+        let mut server = mockito::Server::new();
+        let url = server.url();
+        let queued = server
+            .mock("POST", "/v1/me/player/queue")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "uri".into(),
+                "spotify:track:1VY8".into(),
+            ))
+            .with_status(204)
+            .create();
+        let liked = server
+            .mock("PUT", "/v1/me/tracks")
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "ids": ["1VY8"] }),
+            ))
+            .with_status(200)
+            .create();
+        let added = server
+            .mock("POST", "/v1/playlists/pl1/tracks")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({ "uris": ["spotify:track:1VY8"] }),
+            ))
+            .with_status(201)
+            .with_body(r#"{"snapshot_id": "snap2"}"#)
+            .create();
+        let auth = UserAuthData {
+            access_token: "test-access-token".to_string(),
+            token_type: "Bearer".to_string(),
+            scope: [
+                Capability::ReadPlayback,
+                Capability::ControlPlayback,
+                Capability::EditLibrary,
+                Capability::EditPlaylists,
+            ]
+            .iter()
+            .flat_map(|capability| capability.scopes().to_vec())
+            .collect::<Vec<_>>()
+            .join(" "),
+            expires_in: 3600,
+            refresh_token: "test-refresh-token".to_string(),
+            last_refresh: Some(SystemTime::now()),
+        };
+        let builder = SpotifyClientBuilder::new("tester".to_string())
+            .with_base_urls(&url, &format!("{url}/v1"))
+            .with_in_memory_creds("test-client-id".to_string(), auth);
+        let mut spotify = wait!(builder.build()).unwrap();
+        let item = PickItem {
+            uri: "spotify:track:1VY8".to_string(),
+            id: "1VY8".to_string(),
+            url: "https://open.spotify.com/track/1VY8".to_string(),
+            title: "The Divine Zero".to_string(),
+            artists: "Pierce The Veil".to_string(),
+            album: "Misadventures".to_string(),
+            duration_ms: 248853,
+        };
+
+        for action in [
+            PickAction::Queue,
+            PickAction::Like,
+            PickAction::Playlist("pl1".to_string()),
+        ] {
+            pick_action(&mut spotify, &action, &item).unwrap();
+        }
+        queued.assert();
+        liked.assert();
+        added.assert();
+    }
+}
@@ -0,0 +1,639 @@
+use crate::history::PlayHistoryEntry;
+use crate::state_file::{unchanged, StateFormat};
+use crate::tracker::Listen;
+
+use anyhow::{Context, Result};
+use rumqttc::{
+    Client, ClientError, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+pub const DEFAULT_TOPIC_ROOT: &str = "music-tracker";
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+pub const DEFAULT_MQTT_OUTBOX_FILE: &str = "mqtt_outbox.json";
+/// The key of the broker password in the creds storage.
+pub const MQTT_PASSWORD_KEY: &str = "mqtt_password";
+
+/// v2 wrapped v1 in the state file envelope.
+const OUTBOX_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const REQUEST_CAPACITY: usize = 64;
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(120);
+
+/// The topics of one user, under `<root>/<user>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topics {
+    pub root: String,
+    pub user: String,
+    /// Where Home Assistant looks for discovery messages
+    pub discovery_prefix: String,
+}
+
+impl Topics {
+    pub fn new(user: &str) -> Topics {
+        Topics {
+            root: DEFAULT_TOPIC_ROOT.to_string(),
+            user: user.to_string(),
+            discovery_prefix: DEFAULT_DISCOVERY_PREFIX.to_string(),
+        }
+    }
+
+    /// What is playing, retained, empty while nothing is.
+    pub fn now_playing(&self) -> String {
+        format!("{}/{}/now_playing", self.root, self.user)
+    }
+
+    /// Every counted play.
+    pub fn play(&self) -> String {
+        format!("{}/{}/play", self.root, self.user)
+    }
+
+    /// `online` while the daemon is connected, `offline` set by the broker
+    /// when it goes away.
+    pub fn status(&self) -> String {
+        format!("{}/{}/status", self.root, self.user)
+    }
+
+    /// The `object_id` of the Home Assistant entities, topic safe.
+    fn node_id(&self) -> String {
+        let user: String = self
+            .user
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("music_tracker_{user}")
+    }
+}
+
+/// One message to publish. All of them are retained, so a subscriber
+/// that connects later still gets the latest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub payload: String,
+    /// Only the latest message to the topic matters, a queued older one is
+    /// dropped for it
+    #[serde(default)]
+    pub latest_only: bool,
+}
+
+/// The now playing message, the one clearing the topic for None.
+pub fn now_playing_message(topics: &Topics, listen: Option<&Listen>) -> MqttMessage {
+    let payload = match listen {
+        None => String::new(),
+        Some(Listen::Track(track)) => {
+            let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+            json!({
+                "kind": "track",
+                "id": track.id,
+                "title": track.name,
+                "artists": artists.join(", "),
+                "album": track.album.name,
+                "duration_ms": track.duration_ms,
+                "url": track.spotify_url(),
+            })
+            .to_string()
+        }
+        Some(Listen::Episode { episode, .. }) => json!({
+            "kind": "episode",
+            "id": episode.id,
+            "title": episode.name,
+            "show": episode.show.name,
+            "duration_ms": episode.duration_ms,
+        })
+        .to_string(),
+    };
+    MqttMessage {
+        topic: topics.now_playing(),
+        payload,
+        latest_only: true,
+    }
+}
+
+/// The message of one counted play, the history entry as JSON.
+pub fn play_message(topics: &Topics, play: &PlayHistoryEntry) -> Result<MqttMessage> {
+    Ok(MqttMessage {
+        topic: topics.play(),
+        payload: serde_json::to_string(play)?,
+        latest_only: false,
+    })
+}
+
+/// Home Assistant MQTT discovery messages, so a now playing sensor and a
+/// last play sensor show up without any YAML.
+pub fn discovery_messages(topics: &Topics) -> Vec<MqttMessage> {
+    let node_id = topics.node_id();
+    let device = json!({
+        "identifiers": [node_id],
+        "name": format!("Music tracker ({})", topics.user),
+    });
+    let sensor = |object_id: &str, name: &str, state_topic: String, template: &str| {
+        let config = json!({
+            "name": name,
+            "unique_id": format!("{node_id}_{object_id}"),
+            "state_topic": state_topic,
+            "value_template": template,
+            "json_attributes_topic": state_topic,
+            "availability_topic": topics.status(),
+            "device": device,
+            "icon": "mdi:music",
+        });
+        MqttMessage {
+            topic: format!(
+                "{}/sensor/{node_id}/{object_id}/config",
+                topics.discovery_prefix
+            ),
+            payload: config.to_string(),
+            latest_only: true,
+        }
+    };
+    vec![
+        sensor(
+            "now_playing",
+            "Now playing",
+            topics.now_playing(),
+            "{{ value_json.title if value else 'idle' }}",
+        ),
+        sensor(
+            "last_play",
+            "Last play",
+            topics.play(),
+            "{{ value_json.track_name }}",
+        ),
+    ]
+}
+
+/// Messages waiting for the broker, kept in a JSON file so neither a lost
+/// connection nor a restart drops them.
+pub struct Outbox {
+    path: PathBuf,
+    /// Numbered in the order they were queued, see [Outbox::remove]
+    pending: VecDeque<(u64, MqttMessage)>,
+    next_id: u64,
+}
+
+impl Outbox {
+    /// The outbox in `path`, with what an earlier run left in it.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Outbox> {
+        let path = path.into();
+        let messages: Vec<MqttMessage> = match fs::read_to_string(&path) {
+            Ok(data) => OUTBOX_FORMAT.parse(&data, &path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Outbox {
+            path,
+            next_id: messages.len() as u64,
+            pending: (0..).zip(messages).collect(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queues `message` behind the others, replacing a queued one it makes
+    /// pointless.
+    pub fn push(&mut self, message: MqttMessage) -> Result<()> {
+        if message.latest_only {
+            self.pending
+                .retain(|(_, queued)| queued.topic != message.topic);
+        }
+        self.pending.push_back((self.next_id, message));
+        self.next_id += 1;
+        self.save()
+    }
+
+    pub fn front(&self) -> Option<&MqttMessage> {
+        self.pending.front().map(|(_, message)| message)
+    }
+
+    pub fn pop_front(&mut self) -> Option<MqttMessage> {
+        self.pending.pop_front().map(|(_, message)| message)
+    }
+
+    /// The queued messages, oldest first, with the id to remove them by.
+    pub fn queued(&self) -> impl Iterator<Item = (u64, &MqttMessage)> {
+        self.pending.iter().map(|(id, message)| (*id, message))
+    }
+
+    /// Drops the messages of `ids`, e.g. the ones the broker acknowledged,
+    /// and writes the rest.
+    pub fn remove(&mut self, ids: &[u64]) -> Result<()> {
+        self.pending.retain(|(id, _)| !ids.contains(id));
+        self.save()
+    }
+
+    /// Writes the queue, removing the file once nothing is left in it.
+    pub fn save(&self) -> Result<()> {
+        if self.pending.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let messages: Vec<&MqttMessage> = self.pending.iter().map(|(_, m)| m).collect();
+        fs::write(&self.path, OUTBOX_FORMAT.to_string(&messages)?)
+            .with_context(|| format!("Could not write {}", self.path.display()))
+    }
+}
+
+/// Which outbox messages the broker acknowledged. Publishes are tagged in
+/// the order they are handed to the client, which is the order the event
+/// loop sends them in, so the packet id one goes out with leads back to its
+/// message. A publish sent again after a reconnect keeps its packet id.
+#[derive(Debug, Default)]
+struct Deliveries {
+    /// Publishes handed to the client and not sent yet, the outbox id of
+    /// each or None for the ones not from the outbox
+    handed: VecDeque<Option<u64>>,
+    /// Sent publishes waiting for their PubAck, by packet id
+    in_flight: HashMap<u16, Option<u64>>,
+    /// Outbox messages the broker has, to leave the outbox
+    acked: Vec<u64>,
+}
+
+impl Deliveries {
+    fn sent(&mut self, pkid: u16) {
+        if self.in_flight.contains_key(&pkid) {
+            return;
+        }
+        if let Some(tag) = self.handed.pop_front() {
+            self.in_flight.insert(pkid, tag);
+        }
+    }
+
+    fn acknowledged(&mut self, pkid: u16) {
+        if let Some(Some(id)) = self.in_flight.remove(&pkid) {
+            self.acked.push(id);
+        }
+    }
+}
+
+/// Hands a retained publish to the client, tagged with `tag` under the lock
+/// so the tags keep the client's order.
+fn publish(
+    client: &Client,
+    deliveries: &Mutex<Deliveries>,
+    topic: &str,
+    payload: String,
+    tag: Option<u64>,
+) -> Result<(), ClientError> {
+    let mut deliveries = deliveries.lock().unwrap();
+    client.try_publish(topic, QoS::AtLeastOnce, true, payload)?;
+    deliveries.handed.push_back(tag);
+    Ok(())
+}
+
+/// Doubles the wait between reconnects up to a cap, back to the start once
+/// a connection works.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min,
+            max,
+            next: min,
+        }
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.min;
+    }
+}
+
+/// Where and as whom to connect.
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topics: Topics,
+}
+
+/// Publishes what the daemon sees to an MQTT broker. Every message goes
+/// through the [Outbox] and leaves it once the broker acknowledged it, so
+/// while the broker is away, or the daemon stops before it answered, they
+/// wait there. The connection is kept up on a thread of its own,
+/// reconnecting with a [Backoff].
+pub struct MqttSink {
+    topics: Topics,
+    client: Client,
+    connected: Arc<AtomicBool>,
+    outbox: Outbox,
+    deliveries: Arc<Mutex<Deliveries>>,
+    /// Outbox messages handed to the client, it sends them again itself
+    /// after a reconnect
+    handed: Vec<u64>,
+    // The id of the item last published as playing, None after a clear
+    now_playing: Option<Option<String>>,
+}
+
+impl MqttSink {
+    pub fn connect(config: MqttConfig, outbox: Outbox) -> MqttSink {
+        let client_id = format!("{}-{}", config.topics.node_id(), std::process::id());
+        let mut options = MqttOptions::new(client_id, &config.host, config.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            config.topics.status(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        if config.tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(false));
+        let deliveries = Arc::new(Mutex::new(Deliveries::default()));
+        let tracked = deliveries.clone();
+        let on_connect = discovery_messages(&config.topics);
+        let status = config.topics.status();
+        let publisher = client.clone();
+        let flag = connected.clone();
+        let broker = format!("{}:{}", config.host, config.port);
+        thread::Builder::new()
+            .name("mqtt".to_string())
+            .spawn(move || {
+                let mut backoff = Backoff::new(MIN_RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+                for notification in connection.iter() {
+                    match notification {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            info!("Connected to the MQTT broker {broker}");
+                            backoff.reset();
+                            for message in &on_connect {
+                                let payload = message.payload.clone();
+                                let _ =
+                                    publish(&publisher, &tracked, &message.topic, payload, None);
+                            }
+                            let online = "online".to_string();
+                            let _ = publish(&publisher, &tracked, &status, online, None);
+                            flag.store(true, Ordering::SeqCst);
+                        }
+                        Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                            tracked.lock().unwrap().sent(pkid)
+                        }
+                        Ok(Event::Incoming(Packet::PubAck(ack))) => {
+                            tracked.lock().unwrap().acknowledged(ack.pkid)
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            flag.store(false, Ordering::SeqCst);
+                            let delay = backoff.next_delay();
+                            warn!(
+                                "Lost the MQTT broker {broker}: {e}, retrying in {}s",
+                                delay.as_secs()
+                            );
+                            thread::sleep(delay);
+                        }
+                    }
+                }
+            })
+            .expect("the mqtt thread should start");
+
+        MqttSink {
+            topics: config.topics,
+            client,
+            connected,
+            outbox,
+            deliveries,
+            handed: Vec::new(),
+            now_playing: None,
+        }
+    }
+
+    /// Publishes what is playing when it changed since the last call,
+    /// clearing the topic when playback stopped.
+    pub fn now_playing(&mut self, listen: Option<&Listen>) -> Result<()> {
+        let id = listen.map(|listen| listen.id().to_string());
+        if self.now_playing.as_ref() == Some(&id) {
+            return Ok(());
+        }
+        self.now_playing = Some(id);
+        self.send(now_playing_message(&self.topics, listen))
+    }
+
+    pub fn play(&mut self, play: &PlayHistoryEntry) -> Result<()> {
+        self.send(play_message(&self.topics, play)?)
+    }
+
+    fn send(&mut self, message: MqttMessage) -> Result<()> {
+        self.outbox.push(message)?;
+        self.flush()
+    }
+
+    /// Drops the messages the broker acknowledged from the outbox and hands
+    /// the new ones to the client while the broker is there. Call it now and
+    /// then, what waited through an outage goes out once the connection is
+    /// back.
+    pub fn flush(&mut self) -> Result<()> {
+        let acked = std::mem::take(&mut self.deliveries.lock().unwrap().acked);
+        if !acked.is_empty() {
+            self.handed.retain(|id| !acked.contains(id));
+            self.outbox.remove(&acked)?;
+        }
+        if !self.connected.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        for (id, message) in self.outbox.queued() {
+            if self.handed.contains(&id) {
+                continue;
+            }
+            let payload = message.payload.clone();
+            let sent = publish(
+                &self.client,
+                &self.deliveries,
+                &message.topic,
+                payload,
+                Some(id),
+            );
+            if let Err(e) = sent {
+                debug!("Keeping {} MQTT messages for later: {e}", self.outbox.len());
+                break;
+            }
+            self.handed.push(id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Confidence;
+    use crate::testutil::sample_track;
+    use std::time::SystemTime;
+
+    fn track() -> Listen {
+        Listen::Track(sample_track())
+    }
+
+    fn temp_outbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mqtt-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(DEFAULT_MQTT_OUTBOX_FILE)
+    }
+
+    #[test]
+    fn test_messages() {
+        let topics = Topics::new("jorge");
+        let playing = now_playing_message(&topics, Some(&track()));
+        assert_eq!(playing.topic, "music-tracker/jorge/now_playing");
+        let payload: serde_json::Value = serde_json::from_str(&playing.payload).unwrap();
+        assert_eq!(payload["kind"], "track");
+        assert_eq!(payload["title"], "The Divine Zero");
+        assert!(playing.latest_only);
+
+        // Stopping clears the retained message
+        assert_eq!(now_playing_message(&topics, None).payload, "");
+
+        let Listen::Track(track) = track() else {
+            unreachable!()
+        };
+        let entry = PlayHistoryEntry::from_track(&track, SystemTime::now(), Confidence::High);
+        let play = play_message(&topics, &entry).unwrap();
+        assert_eq!(play.topic, "music-tracker/jorge/play");
+        assert!(!play.latest_only);
+        let payload: serde_json::Value = serde_json::from_str(&play.payload).unwrap();
+        assert_eq!(payload["track_name"], "The Divine Zero");
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let topics = Topics::new("jorge.s");
+        let messages = discovery_messages(&topics);
+        assert_eq!(
+            messages[0].topic,
+            "homeassistant/sensor/music_tracker_jorge_s/now_playing/config"
+        );
+        let config: serde_json::Value = serde_json::from_str(&messages[0].payload).unwrap();
+        assert_eq!(config["state_topic"], "music-tracker/jorge.s/now_playing");
+        assert_eq!(config["availability_topic"], "music-tracker/jorge.s/status");
+        assert_eq!(config["unique_id"], "music_tracker_jorge_s_now_playing");
+        assert_eq!(messages[1].topic.split('/').nth(3), Some("last_play"));
+    }
+
+    #[test]
+    fn test_outbox_survives_a_restart() {
+        let path = temp_outbox("restart");
+        let topics = Topics::new("jorge");
+        let mut outbox = Outbox::open(&path).unwrap();
+        outbox
+            .push(now_playing_message(&topics, Some(&track())))
+            .unwrap();
+        let play = MqttMessage {
+            topic: topics.play(),
+            payload: "{}".to_string(),
+            latest_only: false,
+        };
+        outbox.push(play.clone()).unwrap();
+        outbox.push(play.clone()).unwrap();
+        // Only the latest now playing matters, every play does
+        outbox.push(now_playing_message(&topics, None)).unwrap();
+
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.len(), 3);
+        assert_eq!(outbox.pop_front(), Some(play.clone()));
+        assert_eq!(outbox.pop_front(), Some(play.clone()));
+        assert_eq!(outbox.pop_front().unwrap().payload, "");
+        outbox.save().unwrap();
+        assert!(!fs::exists(&path).unwrap());
+        assert!(Outbox::open(&path).unwrap().is_empty());
+
+        // Outboxes from before the state file versions
+        fs::write(&path, serde_json::to_string(&[&play]).unwrap()).unwrap();
+        assert_eq!(Outbox::open(&path).unwrap().front(), Some(&play));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_messages_leave_the_outbox_on_their_puback() {
+        let path = temp_outbox("puback");
+        let mut outbox = Outbox::open(&path).unwrap();
+        let play = |payload: &str| MqttMessage {
+            topic: Topics::new("jorge").play(),
+            payload: payload.to_string(),
+            latest_only: false,
+        };
+        outbox.push(play("first")).unwrap();
+        outbox.push(play("second")).unwrap();
+        let ids: Vec<u64> = outbox.queued().map(|(id, _)| id).collect();
+
+        // The online status and both plays go out, only the second is acked
+        let mut deliveries = Deliveries::default();
+        deliveries.handed.extend([None, Some(ids[0]), Some(ids[1])]);
+        for pkid in 1..=3 {
+            deliveries.sent(pkid);
+        }
+        deliveries.acknowledged(1);
+        deliveries.acknowledged(3);
+        // The first is sent again after a reconnect, with its packet id
+        deliveries.sent(2);
+        assert!(deliveries.handed.is_empty());
+        assert_eq!(deliveries.acked, [ids[1]]);
+
+        outbox.remove(&deliveries.acked).unwrap();
+        let mut outbox = Outbox::open(&path).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.front(), Some(&play("first")));
+
+        deliveries.acknowledged(2);
+        assert_eq!(deliveries.acked, [ids[1], ids[0]]);
+        let first = outbox.queued().next().unwrap().0;
+        outbox.remove(&[first]).unwrap();
+        assert!(!fs::exists(&path).unwrap());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
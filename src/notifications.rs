@@ -0,0 +1,88 @@
+use crate::spotify_data::Track;
+
+#[cfg(feature = "desktop-notify")]
+use crate::spotify_api::AlbumArtCache;
+#[cfg(feature = "desktop-notify")]
+use crate::spotify_data::ImageSize;
+#[cfg(feature = "desktop-notify")]
+use anyhow::Result;
+#[cfg(feature = "desktop-notify")]
+use std::path::PathBuf;
+#[cfg(feature = "desktop-notify")]
+use std::thread;
+#[cfg(feature = "desktop-notify")]
+use tracing::warn;
+
+/// Where something gets told about the track that started playing, e.g. a
+/// desktop notification or a chat webhook. `watch` calls it once per
+/// change, after the debounce.
+pub trait NotificationSink {
+    /// Failures are the sink's to report, a notification that didn't make
+    /// it never stops the watch.
+    fn notify(&self, track: &Track);
+}
+
+/// Desktop notifications with the track, its artists and the album cover.
+/// Covers are downloaded on a background thread, so a slow one never
+/// holds up polling, and kept in the client's art cache.
+#[cfg(feature = "desktop-notify")]
+#[derive(Clone)]
+pub struct DesktopNotifier {
+    art: AlbumArtCache,
+}
+
+#[cfg(feature = "desktop-notify")]
+impl DesktopNotifier {
+    /// Keeps the covers in `art`, the client's cache, see
+    /// [SpotifyClient::album_art_cache](crate::spotify_api::SpotifyClient::album_art_cache).
+    pub fn new(art: AlbumArtCache) -> DesktopNotifier {
+        DesktopNotifier { art }
+    }
+
+    /// The cover of the track's album on disk, None when it has none.
+    fn album_art(&self, track: &Track) -> Result<Option<PathBuf>> {
+        if track.album.images.is_empty() {
+            return Ok(None);
+        }
+        #[cfg(feature = "blocking")]
+        let path = self.art.fetch(track, ImageSize::Medium)?;
+        // The notification has a thread of its own to wait on
+        #[cfg(not(feature = "blocking"))]
+        let path = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(self.art.fetch(track, ImageSize::Medium))?;
+        Ok(Some(path))
+    }
+
+    fn show(&self, track: &Track) -> Result<()> {
+        let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+        let mut notification = notify_rust::Notification::new();
+        notification
+            .appname("spotify-rs")
+            .summary(&track.name)
+            .body(&format!("{}\n{}", artists.join(", "), track.album.name));
+        match self.album_art(track) {
+            Ok(Some(path)) => {
+                notification.icon(&path.to_string_lossy());
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Failed to download the cover of {}: {e}", track.album.name),
+        }
+        notification.show()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "desktop-notify")]
+impl NotificationSink for DesktopNotifier {
+    fn notify(&self, track: &Track) {
+        let notifier = self.clone();
+        let track = track.clone();
+        thread::spawn(move || {
+            if let Err(e) = notifier.show(&track) {
+                warn!("Failed to show a notification for {}: {e}", track.name);
+            }
+        });
+    }
+}
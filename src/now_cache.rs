@@ -0,0 +1,148 @@
+use crate::spotify_data::CurrentlyPlayingTrack;
+use crate::state_file::{unchanged, StateFormat};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_NOW_CACHE_FILE: &str = "now_playing.json";
+
+/// v2 wrapped v1 in the state file envelope.
+const NOW_CACHE_FORMAT: StateFormat = StateFormat {
+    version: 2,
+    upgrades: &[unchanged],
+};
+
+/// The last currently playing answer the daemon got, and when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedNowPlaying {
+    pub fetched_at: SystemTime,
+    /// None when nothing was playing
+    pub playing: Option<CurrentlyPlayingTrack>,
+}
+
+impl CachedNowPlaying {
+    /// How long before `now` it was fetched, zero for a clock that went back.
+    pub fn age(&self, now: SystemTime) -> Duration {
+        now.duration_since(self.fetched_at).unwrap_or_default()
+    }
+}
+
+/// A JSON file with the last [CachedNowPlaying], so a status bar keeps
+/// showing the track through a network blip. The daemon writes it every
+/// poll, `now --grace` falls back to it when Spotify can't be reached.
+pub struct NowCache {
+    path: PathBuf,
+}
+
+impl NowCache {
+    pub fn new(path: impl Into<PathBuf>) -> NowCache {
+        NowCache { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Replaces the cached answer. Written to a temporary file first so a
+    /// reader never sees half of it.
+    pub fn save(
+        &self,
+        playing: Option<&CurrentlyPlayingTrack>,
+        fetched_at: SystemTime,
+    ) -> Result<()> {
+        let cached = CachedNowPlaying {
+            fetched_at,
+            playing: playing.cloned(),
+        };
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, NOW_CACHE_FORMAT.to_string(&cached)?)
+            .with_context(|| format!("Could not write {}", temp.display()))?;
+        fs::rename(&temp, &self.path)
+            .with_context(|| format!("Could not replace {}", self.path.display()))
+    }
+
+    /// The cached answer, None when there is none yet.
+    pub fn load(&self) -> Result<Option<CachedNowPlaying>> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(NOW_CACHE_FORMAT.parse(&data, &self.path)?))
+    }
+
+    /// The cached answer when it was fetched at most `max_age` before `now`.
+    pub fn fresh(&self, now: SystemTime, max_age: Duration) -> Result<Option<CachedNowPlaying>> {
+        Ok(self.load()?.filter(|cached| cached.age(now) <= max_age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::load_sample;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_727_000_000 + secs)
+    }
+
+    fn cache(name: &str) -> NowCache {
+        let path = std::env::temp_dir().join(format!("{name}-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        NowCache::new(path)
+    }
+
+    #[test]
+    fn test_staleness_cutoff() {
+        let cache = cache("now-cutoff");
+        let grace = Duration::from_secs(60);
+        assert!(cache.fresh(at(0), grace).unwrap().is_none());
+
+        let playing: CurrentlyPlayingTrack =
+            serde_json::from_str(&load_sample("currently_playing_track.json")).unwrap();
+        cache.save(Some(&playing), at(100)).unwrap();
+
+        let cached = cache.fresh(at(160), grace).unwrap().unwrap();
+        assert_eq!(cached.age(at(160)), grace);
+        assert_eq!(
+            cached.playing.unwrap().get_track_data().unwrap().name,
+            "The Divine Zero"
+        );
+        assert!(cache.fresh(at(161), grace).unwrap().is_none());
+        // A clock that went back doesn't make it stale
+        assert!(cache.fresh(at(90), grace).unwrap().is_some());
+
+        // Nothing playing is an answer too
+        cache.save(None, at(200)).unwrap();
+        let cached = cache.fresh(at(200), grace).unwrap().unwrap();
+        assert!(cached.playing.is_none());
+
+        fs::write(cache.path(), "{").unwrap();
+        assert!(cache.load().is_err());
+        fs::remove_file(cache.path()).unwrap();
+    }
+
+    #[test]
+    fn test_now_cache_upgrades() {
+        let cache = cache("now-upgrade");
+        let v1 = r#"{"fetched_at": {"secs_since_epoch": 1727000100, "nanos_since_epoch": 0}, "playing": null}"#;
+        fs::write(cache.path(), v1).unwrap();
+        let cached = cache.load().unwrap().unwrap();
+        assert_eq!(cached.fetched_at, at(100));
+        assert!(cached.playing.is_none());
+
+        cache.save(None, at(200)).unwrap();
+        assert!(fs::read_to_string(cache.path())
+            .unwrap()
+            .starts_with(r#"{"v":2,"#));
+
+        fs::write(cache.path(), r#"{"v": 3, "data": {}}"#).unwrap();
+        let err = cache.load().unwrap_err();
+        assert!(err.to_string().contains("is format v3"));
+        fs::remove_file(cache.path()).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+use crate::search::fold;
+use crate::spotify_api::Capability;
+use crate::spotify_data::Track;
+
+use anyhow::{bail, Result};
+use std::io::{self, BufRead, Write};
+
+/// A track the user can pick, with what the picker shows of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PickItem {
+    pub uri: String,
+    pub id: String,
+    pub url: String,
+    pub title: String,
+    pub artists: String,
+    pub album: String,
+    pub duration_ms: u32,
+}
+
+impl PickItem {
+    pub fn from_track(track: &Track) -> PickItem {
+        let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+        PickItem {
+            uri: track.uri(),
+            id: track.id.clone(),
+            url: track.spotify_url(),
+            title: track.name.clone(),
+            artists: artists.join(", "),
+            album: track.album.name.clone(),
+            duration_ms: track.duration_ms,
+        }
+    }
+
+    /// The length as `m:ss`.
+    pub fn duration(&self) -> String {
+        let secs = self.duration_ms / 1000;
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+
+    /// One line with everything the picker shows.
+    pub fn label(&self) -> String {
+        format!(
+            "{} - {} ({}) [{}]",
+            self.title,
+            self.artists,
+            self.album,
+            self.duration()
+        )
+    }
+
+    /// Every word of the filter is in the title, artists or album, ignoring
+    /// case and accents.
+    fn matches(&self, filter: &str) -> bool {
+        let haystack = fold(&format!("{} {} {}", self.title, self.artists, self.album));
+        fold(filter)
+            .split_whitespace()
+            .all(|word| haystack.contains(word))
+    }
+}
+
+/// The positions of the items matching `filter`, all of them for an empty one.
+pub fn filter_items(items: &[PickItem], filter: &str) -> Vec<usize> {
+    (0..items.len())
+        .filter(|&i| items[i].matches(filter))
+        .collect()
+}
+
+/// What happens to the picked track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PickAction {
+    Queue,
+    Like,
+    Playlist(String),
+    Open,
+}
+
+impl PickAction {
+    /// Parses `--then`: `queue`, `like`, `playlist:<id>` or `open`.
+    pub fn parse(arg: &str) -> Result<PickAction> {
+        Ok(match arg.split_once(':') {
+            None if arg == "queue" => PickAction::Queue,
+            None if arg == "like" => PickAction::Like,
+            None if arg == "open" => PickAction::Open,
+            None if arg == "playlist" => {
+                bail!("Say which playlist to add to: --then playlist:<id>")
+            }
+            Some(("playlist", "")) => bail!("Say which playlist to add to: --then playlist:<id>"),
+            Some(("playlist", id)) => PickAction::Playlist(id.to_string()),
+            _ => bail!("Unknown action <{arg}>, use queue, like, playlist:<id> or open"),
+        })
+    }
+
+    /// What the action needs to be allowed to do.
+    pub fn capabilities(&self) -> Vec<Capability> {
+        match self {
+            PickAction::Queue => vec![Capability::ControlPlayback],
+            PickAction::Like => vec![Capability::EditLibrary],
+            PickAction::Playlist(_) => vec![Capability::EditPlaylists],
+            PickAction::Open => Vec::new(),
+        }
+    }
+}
+
+/// Numbered selection over lines of `input`: a number picks that row of the
+/// list shown, anything else filters the list down to the matching items,
+/// an empty line or the end of the input picks nothing. Returns the
+/// position of the picked item in `items`.
+pub fn pick_numbered(
+    items: &[PickItem],
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<Option<usize>> {
+    let mut shown = filter_items(items, "");
+    loop {
+        for (row, &i) in shown.iter().enumerate() {
+            writeln!(output, "{:>3}. {}", row + 1, items[i].label())?;
+        }
+        write!(
+            output,
+            "Pick a number, type to filter or press enter to cancel: "
+        )?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if let Ok(row) = line.parse::<usize>() {
+            match shown.get(row.wrapping_sub(1)) {
+                Some(&i) => return Ok(Some(i)),
+                None => writeln!(output, "There is no {row}")?,
+            }
+            continue;
+        }
+        let matching = filter_items(items, line);
+        if matching.is_empty() {
+            writeln!(output, "Nothing matches <{line}>")?;
+        } else {
+            shown = matching;
+        }
+    }
+}
+
+/// Filtering list that narrows down as the user types.
+#[cfg(feature = "tui")]
+fn pick_fuzzy(items: &[PickItem]) -> Result<Option<usize>> {
+    let labels: Vec<String> = items.iter().map(PickItem::label).collect();
+    let picked = dialoguer::FuzzySelect::new()
+        .with_prompt("Pick a track")
+        .items(&labels)
+        .default(0)
+        .interact_opt()?;
+    Ok(picked)
+}
+
+/// Lets the user pick one of `items`. With the `tui` feature and a terminal
+/// this is a filtering list, otherwise [pick_numbered] on stdin. The list
+/// goes to stderr so stdout stays free for piping.
+pub fn pick(items: &[PickItem]) -> Result<Option<usize>> {
+    if items.is_empty() {
+        return Ok(None);
+    }
+    #[cfg(feature = "tui")]
+    {
+        use std::io::IsTerminal;
+        if io::stdin().is_terminal() && io::stderr().is_terminal() {
+            return pick_fuzzy(items);
+        }
+    }
+    pick_numbered(items, &mut io::stdin().lock(), &mut io::stderr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spotify_data::TrackSearch;
+    use crate::testutil::load_sample;
+    use std::io::Cursor;
+
+    fn items() -> Vec<PickItem> {
+        let search: TrackSearch = serde_json::from_str(&load_sample("search_tracks.json")).unwrap();
+        search
+            .tracks
+            .items
+            .iter()
+            .map(PickItem::from_track)
+            .collect()
+    }
+
+    fn pick_with(input: &str) -> (Option<usize>, String) {
+        let mut output = Vec::new();
+        let picked = pick_numbered(&items(), &mut Cursor::new(input), &mut output).unwrap();
+        (picked, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_pick_item() {
+        let items = items();
+        assert_eq!(items[0].uri, "spotify:track:4iV5W9uYEdYUVa79Axb7Rh");
+        assert_eq!(items[0].duration(), "6:11");
+        assert_eq!(
+            items[1].label(),
+            "Heroes - Peter Gabriel (Scratch My Back) [5:47]"
+        );
+        assert_eq!(filter_items(&items, "heroes"), [0, 1]);
+        assert_eq!(filter_items(&items, "GABRIEL heroes"), [1]);
+        assert_eq!(filter_items(&items, "selfish"), [2]);
+        assert!(filter_items(&items, "heroes selfish").is_empty());
+    }
+
+    #[test]
+    fn test_pick_numbered() {
+        let (picked, output) = pick_with("2\n");
+        assert_eq!(picked, Some(1));
+        assert!(output.starts_with("  1. Heroes - David Bowie"));
+
+        // Numbers count in the filtered list
+        let (picked, output) = pick_with("veil\n1\n");
+        assert_eq!(picked, Some(2));
+        assert!(output.contains("  1. Hold On Till May"));
+
+        // Filters that match nothing and rows that don't exist are retried
+        let (picked, output) = pick_with("abba\n4\n0\n3\n");
+        assert_eq!(picked, Some(2));
+        assert!(output.contains("Nothing matches <abba>"));
+        assert!(output.contains("There is no 4"));
+        assert!(output.contains("There is no 0"));
+
+        assert_eq!(pick_with("\n2\n").0, None);
+        assert_eq!(pick_with("").0, None);
+        assert_eq!(pick_with("heroes").0, None);
+    }
+
+    #[test]
+    fn test_pick_action() {
+        let parse = PickAction::parse;
+        assert_eq!(parse("queue").unwrap(), PickAction::Queue);
+        assert_eq!(parse("like").unwrap(), PickAction::Like);
+        assert_eq!(
+            parse("playlist:pl1").unwrap(),
+            PickAction::Playlist("pl1".to_string())
+        );
+        assert_eq!(parse("open").unwrap(), PickAction::Open);
+        assert!(parse("playlist").is_err());
+        assert!(parse("playlist:").is_err());
+        assert!(parse("queue:twice").is_err());
+        assert!(parse("skip").is_err());
+
+        assert_eq!(PickAction::Like.capabilities(), [Capability::EditLibrary]);
+        assert!(PickAction::Open.capabilities().is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Spotify rate limits per app, not per user. Clients of different users in
+/// one process share a limiter: a 429 seen by one pauses all of them, and
+/// the request budget is split evenly between the clients registered.
+///
+/// The state sits behind a lock that is only held to do the bookkeeping,
+/// waiting happens outside of it, so the blocking and async builds can't
+/// deadlock on it.
+pub struct RateLimiter {
+    budget: Option<Budget>,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+struct Budget {
+    per_second: f64,
+    burst: f64,
+}
+
+#[derive(Default)]
+struct State {
+    paused_until: Option<Instant>,
+    buckets: HashMap<u64, Bucket>,
+    next_id: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// A budget of `per_second` requests, with bursts of up to `burst`,
+    /// shared by every client registered.
+    pub fn new(per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            budget: Some(Budget {
+                per_second,
+                burst: f64::from(burst.max(1)),
+            }),
+            state: Mutex::default(),
+        }
+    }
+
+    /// No proactive budget, only backs off when Spotify says so.
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter {
+            budget: None,
+            state: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Plain bookkeeping, a panic elsewhere can't leave it half written
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Registers a client, its share of the budget is handed back to the
+    /// others when the returned handle drops.
+    pub fn register(self: &Arc<Self>) -> RateLimit {
+        let mut state = self.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        let now = Instant::now();
+        let tokens = self.budget.map_or(0.0, |budget| {
+            (budget.burst / (state.buckets.len() + 1) as f64).max(1.0)
+        });
+        state.buckets.insert(
+            id,
+            Bucket {
+                tokens,
+                last_refill: now,
+            },
+        );
+        RateLimit {
+            limiter: self.clone(),
+            id,
+        }
+    }
+
+    /// Holds every client back for `wait`, e.g. a Retry-After from Spotify.
+    /// A longer pause already in place is kept.
+    pub fn pause_for(&self, wait: Duration) {
+        self.pause_until(Instant::now() + wait);
+    }
+
+    fn pause_until(&self, until: Instant) {
+        let mut state = self.lock();
+        if state.paused_until.is_none_or(|paused| paused < until) {
+            state.paused_until = Some(until);
+        }
+    }
+
+    /// Takes a request from the client's share. Returns how long to wait
+    /// before asking again, zero when the request may go out now.
+    fn reserve_at(&self, id: u64, now: Instant) -> Duration {
+        let mut state = self.lock();
+        if let Some(until) = state.paused_until {
+            if until > now {
+                return until - now;
+            }
+            state.paused_until = None;
+        }
+        let Some(budget) = self.budget else {
+            return Duration::ZERO;
+        };
+
+        let clients = state.buckets.len().max(1) as f64;
+        let rate = budget.per_second / clients;
+        let capacity = (budget.burst / clients).max(1.0);
+        let bucket = state.buckets.entry(id).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - bucket.tokens) / rate)
+    }
+}
+
+/// One client's handle on a [RateLimiter].
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+    id: u64,
+}
+
+impl RateLimit {
+    /// Returns once the client may send a request.
+    #[cfg(feature = "blocking")]
+    pub fn wait(&self) {
+        loop {
+            let wait = self.limiter.reserve_at(self.id, Instant::now());
+            if wait.is_zero() {
+                return;
+            }
+            std::thread::sleep(wait);
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn wait(&self) {
+        loop {
+            let wait = self.limiter.reserve_at(self.id, Instant::now());
+            if wait.is_zero() {
+                return;
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn pause_for(&self, wait: Duration) {
+        self.limiter.pause_for(wait);
+    }
+}
+
+impl Drop for RateLimit {
+    fn drop(&mut self) {
+        self.limiter.lock().buckets.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserve(limit: &RateLimit, now: Instant) -> Duration {
+        limit.limiter.reserve_at(limit.id, now)
+    }
+
+    #[test]
+    fn test_pause_holds_every_client() {
+        let limiter = Arc::new(RateLimiter::unlimited());
+        let first = limiter.register();
+        let second = limiter.register();
+        let now = Instant::now();
+
+        limiter.pause_until(now + Duration::from_secs(2));
+        // A shorter pause doesn't cut the longer one
+        limiter.pause_until(now + Duration::from_secs(1));
+        assert_eq!(reserve(&first, now), Duration::from_secs(2));
+        assert_eq!(
+            reserve(&second, now + Duration::from_secs(1)),
+            Duration::from_secs(1)
+        );
+        assert!(reserve(&second, now + Duration::from_secs(2)).is_zero());
+    }
+
+    #[test]
+    fn test_budget_is_split_between_clients() {
+        let limiter = Arc::new(RateLimiter::new(10.0, 4));
+        let busy = limiter.register();
+        let quiet = limiter.register();
+        let now = Instant::now();
+
+        // Each client gets half the burst, the busy one can't eat the other's
+        assert!(reserve(&busy, now).is_zero());
+        assert!(reserve(&busy, now).is_zero());
+        assert_eq!(reserve(&busy, now), Duration::from_millis(200));
+        assert!(reserve(&quiet, now).is_zero());
+        assert!(reserve(&quiet, now).is_zero());
+
+        // Half of 10 per second refills one request every 200ms
+        let later = now + Duration::from_millis(200);
+        assert!(reserve(&busy, later).is_zero());
+        assert!(!reserve(&busy, later).is_zero());
+
+        // Once the quiet client is gone the whole budget is the busy one's
+        drop(quiet);
+        let much_later = later + Duration::from_secs(1);
+        for _ in 0..4 {
+            assert!(reserve(&busy, much_later).is_zero());
+        }
+        assert_eq!(reserve(&busy, much_later), Duration::from_millis(100));
+    }
+}
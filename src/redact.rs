@@ -0,0 +1,108 @@
+use serde_json::Value;
+
+const REDACTED: &str = "<redacted>";
+
+/// Keys whose values must never end up in logs or on disk.
+const SECRET_KEYS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "code",
+    "code_verifier",
+    "client_secret",
+];
+
+/// Masks every secret value found in a JSON tree, at any depth.
+pub fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// Returns a copy of a raw body safe to write out. Form encoded bodies,
+/// like the ones sent to the token endpoint, get their secret fields masked
+/// too. Anything else is returned untouched.
+pub fn redact_body(body: &str) -> String {
+    match serde_json::from_str::<Value>(body) {
+        Ok(mut value) => {
+            redact_json(&mut value);
+            value.to_string()
+        }
+        Err(_) if is_form(body) => redact_form(body),
+        Err(_) => body.to_string(),
+    }
+}
+
+/// Shows only enough of a token to tell it apart from another, e.g.
+/// `BQDa…(212 chars)`. Short values are masked whole.
+pub fn mask_secret(secret: &str) -> String {
+    const SHOWN: usize = 4;
+    let length = secret.chars().count();
+    if length <= SHOWN * 4 {
+        return REDACTED.to_string();
+    }
+    let prefix: String = secret.chars().take(SHOWN).collect();
+    format!("{prefix}…({length} chars)")
+}
+
+fn is_form(body: &str) -> bool {
+    body.contains('=') && !body.contains(char::is_whitespace)
+}
+
+fn redact_form(body: &str) -> String {
+    body.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if SECRET_KEYS.contains(&key) => format!("{key}={REDACTED}"),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_token_response() {
+        let body = r#"{"access_token":"BQDa1","token_type":"Bearer","refresh_token":"AQC9x","expires_in":3600}"#;
+        let redacted = redact_body(body);
+        assert!(!redacted.contains("BQDa1"));
+        assert!(!redacted.contains("AQC9x"));
+        assert!(redacted.contains("Bearer"));
+    }
+
+    #[test]
+    fn test_redact_form_body() {
+        let body = "grant_type=authorization_code&code=AQBx&code_verifier=v3r&client_id=app";
+        assert_eq!(
+            redact_body(body),
+            "grant_type=authorization_code&code=<redacted>&code_verifier=<redacted>&client_id=app"
+        );
+        assert_eq!(redact_body("not a form"), "not a form");
+    }
+
+    #[test]
+    fn test_redact_nested_values() {
+        let mut value = serde_json::json!({"items": [{"auth": {"code": "abc"}, "name": "keep"}]});
+        redact_json(&mut value);
+        assert_eq!(value["items"][0]["auth"]["code"], REDACTED);
+        assert_eq!(value["items"][0]["name"], "keep");
+    }
+
+    #[test]
+    fn test_mask_secret() {
+        let token = "BQDa1xY7kPq9mZ2wL4sT8vN3";
+        assert_eq!(mask_secret(token), "BQDa…(24 chars)");
+        assert_eq!(mask_secret("short-secret"), REDACTED);
+    }
+}
@@ -0,0 +1,398 @@
+use crate::history::PlayHistoryEntry;
+use crate::locale::Locale;
+use crate::stats::{discoveries, last_days_start, local_time, Discoveries, ListeningStats};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeZone, Weekday};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+/// Most tracks and artists listed as new to the listener
+const REPORT_DISCOVERIES: usize = 10;
+
+/// The listening stats of a range of days, written as Markdown for the
+/// plain text part of an email and as HTML for the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListeningReport {
+    pub first_day: NaiveDate,
+    pub last_day: NaiveDate,
+    pub stats: ListeningStats,
+    /// What was first played in the range
+    pub discoveries: Discoveries,
+    pub locale: Locale,
+}
+
+/// One table of the report.
+struct Section {
+    title: &'static str,
+    headers: [&'static str; 3],
+    /// The columns before this one are text, the others numbers
+    numbers_from: usize,
+    rows: Vec<[String; 3]>,
+}
+
+impl ListeningReport {
+    /// The report of the last `days` calendar days in the time zone of
+    /// `now`, today included. `entries` is the whole history, plays from
+    /// before the range tell what's new in it.
+    pub fn last_days<Tz: TimeZone>(
+        entries: &[PlayHistoryEntry],
+        days: u64,
+        now: &DateTime<Tz>,
+        locale: Locale,
+    ) -> ListeningReport {
+        let start = last_days_start(days, now);
+        let since = SystemTime::from(start.clone());
+        let in_range: Vec<PlayHistoryEntry> = entries
+            .iter()
+            .filter(|entry| entry.played_at >= since)
+            .cloned()
+            .collect();
+        ListeningReport {
+            first_day: start.date_naive(),
+            last_day: now.date_naive(),
+            stats: ListeningStats::from_entries(&in_range),
+            discoveries: discoveries(entries, since),
+            locale,
+        }
+    }
+
+    pub fn subject(&self) -> String {
+        format!(
+            "Listening report, {} to {}",
+            self.locale.day_month(self.first_day),
+            self.locale.day_month(self.last_day)
+        )
+    }
+
+    fn totals(&self) -> Vec<String> {
+        let locale = self.locale;
+        vec![
+            format!("Plays: {}", locale.number(self.stats.plays)),
+            format!(
+                "Listened: {} (music {}, podcasts {})",
+                locale.duration(self.stats.listened),
+                locale.duration(self.stats.music),
+                locale.duration(self.stats.podcasts)
+            ),
+        ]
+    }
+
+    fn sections(&self) -> Vec<Section> {
+        let locale = self.locale;
+        let mut sections = vec![
+            Section {
+                title: "Top artists",
+                headers: ["Artist", "Plays", "Listened"],
+                numbers_from: 1,
+                rows: self
+                    .stats
+                    .top_artists
+                    .iter()
+                    .map(|a| {
+                        [
+                            a.name.clone(),
+                            locale.number(a.plays),
+                            locale.duration(a.listened),
+                        ]
+                    })
+                    .collect(),
+            },
+            Section {
+                title: "Top tracks",
+                headers: ["Track", "Artists", "Plays"],
+                numbers_from: 2,
+                rows: self
+                    .stats
+                    .top_tracks
+                    .iter()
+                    .map(|t| [t.name.clone(), t.artists.clone(), locale.number(t.plays)])
+                    .collect(),
+            },
+        ];
+        if !self.stats.top_shows.is_empty() {
+            sections.push(Section {
+                title: "Top shows",
+                headers: ["Show", "Episodes", "Listened"],
+                numbers_from: 1,
+                rows: self
+                    .stats
+                    .top_shows
+                    .iter()
+                    .map(|s| {
+                        [
+                            s.name.clone(),
+                            locale.number(s.plays),
+                            locale.duration(s.listened),
+                        ]
+                    })
+                    .collect(),
+            });
+        }
+        if !self.discoveries.tracks.is_empty() {
+            sections.push(Section {
+                title: "New tracks",
+                headers: ["Track", "Artists", "Plays"],
+                numbers_from: 2,
+                rows: self
+                    .discoveries
+                    .tracks
+                    .iter()
+                    .take(REPORT_DISCOVERIES)
+                    .map(|t| [t.name.clone(), t.artists.clone(), locale.number(t.plays)])
+                    .collect(),
+            });
+        }
+        if !self.discoveries.artists.is_empty() {
+            sections.push(Section {
+                title: "New artists",
+                headers: ["Artist", "Plays", "Listened"],
+                numbers_from: 1,
+                rows: self
+                    .discoveries
+                    .artists
+                    .iter()
+                    .take(REPORT_DISCOVERIES)
+                    .map(|a| {
+                        [
+                            a.name.clone(),
+                            locale.number(a.plays),
+                            locale.duration(a.listened),
+                        ]
+                    })
+                    .collect(),
+            });
+        }
+        sections
+    }
+
+    pub fn markdown(&self) -> String {
+        let mut out = format!("# {}\n\n", self.subject());
+        if self.stats.plays == 0 {
+            return out + "No plays in this range.\n";
+        }
+        for line in self.totals() {
+            out += &format!("- {line}\n");
+        }
+        for section in self.sections() {
+            out += &format!("\n## {}\n\n", section.title);
+            let aligns: Vec<&str> = (0..3)
+                .map(|i| {
+                    if i < section.numbers_from {
+                        "---"
+                    } else {
+                        "---:"
+                    }
+                })
+                .collect();
+            out += &format!("| {} |\n", section.headers.join(" | "));
+            out += &format!("| {} |\n", aligns.join(" | "));
+            for row in &section.rows {
+                let cells: Vec<String> = row.iter().map(|cell| markdown_cell(cell)).collect();
+                out += &format!("| {} |\n", cells.join(" | "));
+            }
+        }
+        out
+    }
+
+    pub fn html(&self) -> String {
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<body>\n<h1>{}</h1>\n",
+            escape_html(&self.subject())
+        );
+        if self.stats.plays == 0 {
+            return out + "<p>No plays in this range.</p>\n</body>\n</html>\n";
+        }
+        out += "<ul>\n";
+        for line in self.totals() {
+            out += &format!("<li>{}</li>\n", escape_html(&line));
+        }
+        out += "</ul>\n";
+        for section in self.sections() {
+            out += &format!("<h2>{}</h2>\n<table>\n<tr>", section.title);
+            for header in section.headers {
+                out += &format!("<th>{header}</th>");
+            }
+            out += "</tr>\n";
+            for row in &section.rows {
+                out += "<tr>";
+                for (i, cell) in row.iter().enumerate() {
+                    let align = match i < section.numbers_from {
+                        true => "",
+                        false => " align=\"right\"",
+                    };
+                    out += &format!("<td{align}>{}</td>", escape_html(cell));
+                }
+                out += "</tr>\n";
+            }
+            out += "</table>\n";
+        }
+        out + "</body>\n</html>\n"
+    }
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// When a weekly report goes out, e.g. `mon 08:00`, in the time zone the
+/// reports are made in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportSchedule {
+    pub weekday: Weekday,
+    pub time: NaiveTime,
+}
+
+impl FromStr for ReportSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ReportSchedule> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let [weekday, time] = parts[..] else {
+            bail!("expected a weekday and a time, e.g. `mon 08:00`, got {s:?}");
+        };
+        let Ok(weekday) = weekday.parse() else {
+            bail!("unknown weekday {weekday:?}, expected e.g. mon or monday");
+        };
+        let Ok(time) = NaiveTime::parse_from_str(time, "%H:%M") else {
+            bail!("expected a time like 08:00, got {time:?}");
+        };
+        Ok(ReportSchedule { weekday, time })
+    }
+}
+
+impl ReportSchedule {
+    /// The first time the report is due after `now`. On a day the clocks
+    /// skip the time it is due when they jumped, see [local_time].
+    pub fn next_after<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> DateTime<Tz> {
+        let today = now.date_naive();
+        let ahead =
+            (7 + self.weekday.num_days_from_monday() - today.weekday().num_days_from_monday()) % 7;
+        let day = today + Days::new(ahead.into());
+        let due = local_time(day, self.time, &now.timezone());
+        if due > *now {
+            return due;
+        }
+        local_time(day + Days::new(7), self.time, &now.timezone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Confidence;
+    use crate::testutil::sample_track;
+    use chrono::Utc;
+    use chrono_tz::Europe::Stockholm;
+
+    fn play(rfc3339: &str) -> PlayHistoryEntry {
+        let at = DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        PlayHistoryEntry::from_track(&sample_track(), at.into(), Confidence::High)
+    }
+
+    fn report() -> ListeningReport {
+        let entries = [
+            play("2024-02-20T12:00:00Z"),
+            play("2024-02-22T12:00:00Z"),
+            play("2024-02-28T12:00:00Z"),
+        ];
+        let now = Utc.with_ymd_and_hms(2024, 2, 28, 20, 0, 0).unwrap();
+        ListeningReport::last_days(&entries, 7, &now, Locale::English)
+    }
+
+    #[test]
+    fn test_report_markdown() {
+        assert_eq!(
+            report().markdown(),
+            "# Listening report, February 22 to February 28\n\n\
+             - Plays: 2\n\
+             - Listened: 8 m (music 8 m, podcasts 0 m)\n\n\
+             ## Top artists\n\n\
+             | Artist | Plays | Listened |\n\
+             | --- | ---: | ---: |\n\
+             | Pierce The Veil | 2 | 8 m |\n\n\
+             ## Top tracks\n\n\
+             | Track | Artists | Plays |\n\
+             | --- | --- | ---: |\n\
+             | The Divine Zero | Pierce The Veil | 2 |\n"
+        );
+        let mut empty = report();
+        empty.stats = ListeningStats::from_entries(&[]);
+        assert!(empty.markdown().ends_with("\n\nNo plays in this range.\n"));
+    }
+
+    #[test]
+    fn test_report_html_is_escaped() {
+        let mut report = report();
+        report.stats.top_tracks[0].name = "<Divine> & \"Zero\"".to_string();
+        let html = report.html();
+        assert!(html.contains("<h1>Listening report, February 22 to February 28</h1>"));
+        assert!(html.contains("<li>Plays: 2</li>"));
+        assert!(html.contains("<td>&lt;Divine&gt; &amp; &quot;Zero&quot;</td>"));
+        assert!(html.contains("<td align=\"right\">8 m</td>"));
+        assert!(!html.contains("Top shows"));
+    }
+
+    #[test]
+    fn test_report_new_tracks() {
+        let entries = [play("2024-02-22T12:00:00Z"), play("2024-02-28T12:00:00Z")];
+        let now = Utc.with_ymd_and_hms(2024, 2, 28, 20, 0, 0).unwrap();
+        let fresh = ListeningReport::last_days(&entries, 7, &now, Locale::English);
+        assert!(fresh.markdown().ends_with(
+            "## New tracks\n\n\
+             | Track | Artists | Plays |\n\
+             | --- | --- | ---: |\n\
+             | The Divine Zero | Pierce The Veil | 2 |\n\n\
+             ## New artists\n\n\
+             | Artist | Plays | Listened |\n\
+             | --- | ---: | ---: |\n\
+             | Pierce The Veil | 2 | 8 m |\n"
+        ));
+        // Heard before the range in the other report
+        assert!(!report().markdown().contains("New tracks"));
+    }
+
+    #[test]
+    fn test_report_schedule() {
+        let schedule: ReportSchedule = "Mon 08:00".parse().unwrap();
+        assert_eq!(schedule.weekday, Weekday::Mon);
+        assert!("mon".parse::<ReportSchedule>().is_err());
+        assert!("someday 08:00".parse::<ReportSchedule>().is_err());
+        assert!("mon 8am".parse::<ReportSchedule>().is_err());
+
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        // Monday 2024-03-04, before and after the time
+        let before = at("2024-03-04T07:59:00+01:00").with_timezone(&Stockholm);
+        assert_eq!(
+            schedule.next_after(&before).to_rfc3339(),
+            "2024-03-04T08:00:00+01:00"
+        );
+        let after = at("2024-03-04T08:00:00+01:00").with_timezone(&Stockholm);
+        assert_eq!(
+            schedule.next_after(&after).to_rfc3339(),
+            "2024-03-11T08:00:00+01:00"
+        );
+
+        // Clocks jump from 02:00 to 03:00 on Sunday 2024-03-31
+        let skipped: ReportSchedule = "sunday 02:30".parse().unwrap();
+        let saturday = at("2024-03-30T12:00:00+01:00").with_timezone(&Stockholm);
+        assert_eq!(
+            skipped.next_after(&saturday).to_rfc3339(),
+            "2024-03-31T03:00:00+02:00"
+        );
+    }
+}
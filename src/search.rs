@@ -0,0 +1,213 @@
+use crate::history::PlayHistoryEntry;
+use crate::spotify_data::Artist;
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+/// Hits scoring below this are not worth showing.
+pub const MIN_MATCH_SCORE: f64 = 0.35;
+/// How much recency weighs in the ranking, the rest is match quality.
+const RECENCY_WEIGHT: f64 = 0.2;
+/// Recency halves roughly every this many days without a listen.
+const RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+
+/// A track from the history that matched a search.
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub track_id: String,
+    pub track_name: String,
+    pub artists: Vec<Artist>,
+    pub album: String,
+    pub play_count: usize,
+    pub last_played: SystemTime,
+    pub score: f64,
+}
+
+/// Case and accent insensitive fuzzy search over the plays, one hit per track.
+///
+/// A track's match score is the best score of its name, artists and album:
+/// - 1.0 when the query appears as is, partial words included ("divin" finds "The Divine Zero")
+/// - otherwise the share of query words that start a word of the field, e.g.
+///   "zero div" finds "The Divine Zero"
+/// - otherwise the trigram similarity, which survives typos ("devine zero")
+///
+/// Hits are ranked by match score mixed with how recently they were played.
+pub fn search(entries: &[PlayHistoryEntry], query: &str, now: SystemTime) -> Vec<SearchHit> {
+    let query = fold(query);
+    if query.trim().is_empty() {
+        return Vec::new();
+    }
+
+    let mut tracks: HashMap<&str, SearchHit> = HashMap::new();
+    for entry in entries {
+        let hit = tracks.entry(&entry.track_id).or_insert_with(|| SearchHit {
+            track_id: entry.track_id.clone(),
+            track_name: entry.track_name.clone(),
+            artists: entry.artists.clone(),
+            album: entry.album.clone(),
+            play_count: 0,
+            last_played: entry.played_at,
+            score: 0.0,
+        });
+        hit.play_count += 1;
+        hit.last_played = hit.last_played.max(entry.played_at);
+    }
+
+    let mut hits: Vec<(f64, SearchHit)> = tracks
+        .into_values()
+        .filter_map(|mut hit| {
+            let artists: Vec<&str> = hit.artists.iter().map(|a| a.name.as_str()).collect();
+            hit.score = [&hit.track_name, &artists.join(" "), &hit.album]
+                .iter()
+                .map(|field| match_score(&query, &fold(field)))
+                .fold(0.0, f64::max);
+            if hit.score < MIN_MATCH_SCORE {
+                return None;
+            }
+            let rank =
+                hit.score * (1.0 - RECENCY_WEIGHT) + recency(hit.last_played, now) * RECENCY_WEIGHT;
+            Some((rank, hit))
+        })
+        .collect();
+
+    hits.sort_by(|a, b| b.0.total_cmp(&a.0));
+    hits.into_iter().map(|(_, hit)| hit).collect()
+}
+
+/// Lowercase ASCII approximation, so "Beyoncé" and "beyonce" compare equal.
+pub fn fold(s: &str) -> String {
+    deunicode::deunicode(s).to_lowercase()
+}
+
+/// Both arguments are expected to be [fold]ed already.
+fn match_score(query: &str, text: &str) -> f64 {
+    if text.contains(query) {
+        return 1.0;
+    }
+
+    let query_words: Vec<&str> = query.split_whitespace().collect();
+    let text_words: Vec<&str> = text.split_whitespace().collect();
+    let prefixed = query_words
+        .iter()
+        .filter(|q| text_words.iter().any(|t| t.starts_with(*q)))
+        .count();
+    let prefix_score = 0.9 * prefixed as f64 / query_words.len() as f64;
+
+    prefix_score.max(trigram_similarity(query, text))
+}
+
+/// Share of the query's trigrams found in the text.
+fn trigram_similarity(query: &str, text: &str) -> f64 {
+    let query = trigrams(query);
+    if query.is_empty() {
+        return 0.0;
+    }
+    let text = trigrams(text);
+    query.intersection(&text).count() as f64 / query.len() as f64
+}
+
+fn trigrams(s: &str) -> HashSet<String> {
+    s.split_whitespace()
+        .flat_map(|word| {
+            let padded: Vec<char> = format!("  {word} ").chars().collect();
+            padded
+                .windows(3)
+                .map(|w| w.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// 1.0 for something played just now, decaying with the days since.
+fn recency(last_played: SystemTime, now: SystemTime) -> f64 {
+    let days = now
+        .duration_since(last_played)
+        .unwrap_or(Duration::ZERO)
+        .as_secs_f64()
+        / 86_400.0;
+    0.5_f64.powf(days / RECENCY_HALF_LIFE_DAYS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{Confidence, PlaySource};
+
+    fn entry(
+        id: &str,
+        name: &str,
+        artist: &str,
+        days_ago: u64,
+        now: SystemTime,
+    ) -> PlayHistoryEntry {
+        PlayHistoryEntry {
+            track_id: id.to_string(),
+            track_name: name.to_string(),
+            artists: vec![Artist {
+                name: artist.to_string(),
+                id: format!("{id}-artist"),
+            }],
+            album: format!("{name} (Single)"),
+            duration_ms: 200_000,
+            played_at: now - Duration::from_secs(days_ago * 86_400),
+            confidence: Confidence::High,
+            tags: Vec::new(),
+            show: None,
+            progress_ms: None,
+            skipped_at_ms: None,
+            device: None,
+            context: None,
+            source: PlaySource::Live,
+            replaces: None,
+        }
+    }
+
+    fn history(now: SystemTime) -> Vec<PlayHistoryEntry> {
+        vec![
+            entry("a", "The Divine Zero", "Pierce The Veil", 40, now),
+            entry("b", "Halo", "Beyoncé", 3, now),
+            entry("a", "The Divine Zero", "Pierce The Veil", 10, now),
+            entry("c", "Café del Mar", "Energy 52", 1, now),
+        ]
+    }
+
+    #[test]
+    fn test_diacritics_are_ignored() {
+        let now = SystemTime::now();
+        let hits = search(&history(now), "beyonce", now);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].track_name, "Halo");
+
+        let hits = search(&history(now), "CAFÉ", now);
+        assert_eq!(hits[0].track_id, "c");
+    }
+
+    #[test]
+    fn test_partial_words_and_play_counts() {
+        let now = SystemTime::now();
+        let hits = search(&history(now), "divin", now);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].play_count, 2);
+        assert_eq!(hits[0].last_played, now - Duration::from_secs(10 * 86_400));
+
+        let hits = search(&history(now), "zero div", now);
+        assert_eq!(hits[0].track_id, "a");
+        let hits = search(&history(now), "devine zero", now);
+        assert_eq!(hits[0].track_id, "a");
+    }
+
+    #[test]
+    fn test_ranking_mixes_recency() {
+        let now = SystemTime::now();
+        let entries = vec![
+            entry("old", "Halo", "Beyoncé", 300, now),
+            entry("new", "Halo", "Depeche Mode", 2, now),
+        ];
+        let hits = search(&entries, "halo", now);
+        assert_eq!(hits[0].track_id, "new");
+        assert_eq!(hits[1].track_id, "old");
+
+        assert!(search(&entries, "metallica", now).is_empty());
+        assert!(search(&entries, "  ", now).is_empty());
+    }
+}
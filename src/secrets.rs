@@ -0,0 +1,208 @@
+use crate::error::SecretNotFound;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Mutex, MutexGuard};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use bitwarden::secrets_manager::secrets::{
+    SecretCreateRequest, SecretGetRequest, SecretIdentifiersRequest, SecretPutRequest,
+    SecretResponse, SecretsDeleteRequest,
+};
+use bitwarden::{auth::login::AccessTokenLoginRequest, secrets_manager::ClientSecretsExt, Client};
+
+/// Where [CredStorage](crate::local_store::CredStorage) keeps its secrets.
+/// A secret is a value and a note, found by its key.
+pub trait SecretProvider {
+    /// The keys of every secret.
+    fn list(&self) -> impl Future<Output = Result<Vec<String>>>;
+
+    /// The value and note of a secret, Err([SecretNotFound]) when there is
+    /// none with `key`.
+    fn get(&self, key: &str) -> impl Future<Output = Result<(String, String)>>;
+
+    /// Creates the secret, or replaces the value and note of an existing one.
+    fn put(&self, key: &str, value: &str, note: Option<String>)
+        -> impl Future<Output = Result<()>>;
+
+    /// Removes the secret, Ok when there was none.
+    fn delete(&self, key: &str) -> impl Future<Output = Result<()>>;
+}
+
+/// Secrets in a Bitwarden Secrets Manager project.
+pub struct BitwardenSecrets {
+    org_id: SecretIdentifiersRequest,
+    project_id: Uuid,
+    bw_client: Client,
+}
+
+impl BitwardenSecrets {
+    /// Logs into Bitwarden with a machine account access token.
+    pub async fn login(access_token: String, org_id: Uuid, project_id: Uuid) -> Result<Self> {
+        let bw_client = Client::new(None);
+        let token = AccessTokenLoginRequest {
+            access_token,
+            state_file: None,
+        };
+        bw_client.auth().login_access_token(&token).await?;
+        Ok(BitwardenSecrets {
+            org_id: SecretIdentifiersRequest {
+                organization_id: org_id,
+            },
+            project_id,
+            bw_client,
+        })
+    }
+
+    async fn list_ids(&self) -> Result<HashMap<String, Uuid>> {
+        let res = self.bw_client.secrets().list(&self.org_id).await?;
+        debug!("List Secrets: {:?}", res);
+        let secrets: HashMap<String, Uuid> = res
+            .data
+            .iter()
+            .map(|secret| (secret.key.clone(), secret.id))
+            .collect();
+
+        Ok(secrets)
+    }
+}
+
+impl SecretProvider for BitwardenSecrets {
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.list_ids().await?.into_keys().collect())
+    }
+
+    async fn get(&self, key: &str) -> Result<(String, String)> {
+        let secrets_md = self.list_ids().await?;
+        let id = match secrets_md.get(key) {
+            Some(id) => id,
+            None => return Err(SecretNotFound::new(key).into()),
+        };
+
+        let get_secret = SecretGetRequest { id: *id };
+        let res: SecretResponse = self.bw_client.secrets().get(&get_secret).await?;
+        debug!("Get Secret: {:?}", res);
+
+        Ok((res.value, res.note))
+    }
+
+    async fn put(&self, key: &str, value: &str, note: Option<String>) -> Result<()> {
+        let secrets_md = self.list_ids().await?;
+        let id = match secrets_md.get(key) {
+            Some(id) => id,
+            None => {
+                warn!("Secret key <{key}> does not exist in bitwarden, we will try to create it");
+                let create_request = SecretCreateRequest {
+                    organization_id: self.org_id.organization_id,
+                    key: key.to_string(),
+                    value: value.to_string(),
+                    note: note.unwrap_or_default(),
+                    project_ids: Some(vec![self.project_id]),
+                };
+                let res: SecretResponse = self.bw_client.secrets().create(&create_request).await?;
+                debug!("Create Secret Response: {:?}", res);
+                debug!("Successfully created secret <{key}> in bitwarden");
+                return Ok(());
+            }
+        };
+
+        let put_request = SecretPutRequest {
+            id: *id,
+            organization_id: self.org_id.organization_id,
+            key: key.to_string(),
+            value: value.to_string(),
+            note: note.unwrap_or_default(),
+            project_ids: Some(vec![self.project_id]),
+        };
+        let res: SecretResponse = self.bw_client.secrets().update(&put_request).await?;
+        debug!("Update Secret Response: {:?}", res);
+        debug!("Successfully updated secret <{key}>");
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let Some(id) = self.list_ids().await?.remove(key) else {
+            return Ok(());
+        };
+        let delete_request = SecretsDeleteRequest { ids: vec![id] };
+        self.bw_client.secrets().delete(delete_request).await?;
+        debug!("Successfully deleted secret <{key}>");
+        Ok(())
+    }
+}
+
+/// Secrets that only live as long as the provider, e.g. for tests.
+#[derive(Default)]
+pub struct InMemorySecrets {
+    secrets: Mutex<HashMap<String, (String, String)>>,
+}
+
+impl InMemorySecrets {
+    pub fn new() -> InMemorySecrets {
+        InMemorySecrets::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (String, String)>> {
+        self.secrets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl SecretProvider for InMemorySecrets {
+    async fn list(&self) -> Result<Vec<String>> {
+        Ok(self.lock().keys().cloned().collect())
+    }
+
+    async fn get(&self, key: &str) -> Result<(String, String)> {
+        match self.lock().get(key) {
+            Some(secret) => Ok(secret.clone()),
+            None => Err(SecretNotFound::new(key).into()),
+        }
+    }
+
+    async fn put(&self, key: &str, value: &str, note: Option<String>) -> Result<()> {
+        self.lock().insert(
+            key.to_string(),
+            (value.to_string(), note.unwrap_or_default()),
+        );
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.lock().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_secrets() {
+        let secrets = InMemorySecrets::new();
+        let missing = secrets.get("refresh").await.unwrap_err();
+        assert_eq!(
+            missing.downcast_ref::<SecretNotFound>(),
+            Some(&SecretNotFound::new("refresh"))
+        );
+
+        secrets.put("refresh", "old", None).await.unwrap();
+        secrets
+            .put("refresh", "new", Some("note".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(
+            secrets.get("refresh").await.unwrap(),
+            ("new".to_string(), "note".to_string())
+        );
+        assert_eq!(secrets.list().await.unwrap(), ["refresh"]);
+
+        secrets.delete("refresh").await.unwrap();
+        secrets.delete("refresh").await.unwrap();
+        assert!(secrets.list().await.unwrap().is_empty());
+    }
+}
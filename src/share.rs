@@ -0,0 +1,187 @@
+use crate::spotify_data::Track;
+use crate::template::Template;
+
+use anyhow::Result;
+#[cfg(feature = "songlink")]
+use serde::Deserialize;
+#[cfg(feature = "songlink")]
+use std::time::Duration;
+#[cfg(feature = "songlink")]
+use tracing::warn;
+
+/// What a share template can use. `{link}` is the song.link page when one
+/// was resolved and the Spotify page otherwise.
+pub const SHARE_PLACEHOLDERS: [&str; 5] = ["title", "artists", "album", "spotify_url", "link"];
+pub const DEFAULT_SHARE_TEMPLATE: &str = "{title} by {artists} from {album}\n{link}";
+
+/// A track as a ready to paste snippet.
+pub struct Share {
+    title: String,
+    artists: String,
+    album: String,
+    spotify_url: String,
+    link: Option<String>,
+}
+
+impl Share {
+    pub fn new(track: &Track) -> Share {
+        let artists: Vec<&str> = track
+            .artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect();
+        Share {
+            title: track.name.clone(),
+            artists: artists.join(", "),
+            album: track.album.name.clone(),
+            spotify_url: track.spotify_url(),
+            link: None,
+        }
+    }
+
+    pub fn spotify_url(&self) -> &str {
+        &self.spotify_url
+    }
+
+    /// Uses `link`, e.g. a song.link page, for `{link}`.
+    pub fn with_link(mut self, link: String) -> Share {
+        self.link = Some(link);
+        self
+    }
+
+    pub fn render(&self, template: &Template) -> String {
+        template.render(&[
+            ("title", &self.title),
+            ("artists", &self.artists),
+            ("album", &self.album),
+            ("spotify_url", &self.spotify_url),
+            ("link", self.link.as_deref().unwrap_or(&self.spotify_url)),
+        ])
+    }
+}
+
+/// Parses a share template, checking its placeholders.
+pub fn share_template(text: &str) -> Result<Template> {
+    Template::parse(text, &SHARE_PLACEHOLDERS)
+}
+
+/// song.link pages from the Odesli public API, https://odesli.co
+/// A page links the track on every streaming service Odesli knows.
+#[cfg(feature = "songlink")]
+pub struct SongLinkResolver {
+    base_url: String,
+    http_client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "songlink")]
+const SONGLINK_URL: &str = "https://api.song.link";
+/// The snippet is printed right after, don't keep the user waiting
+#[cfg(feature = "songlink")]
+const SONGLINK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[cfg(feature = "songlink")]
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SongLinkResponse {
+    page_url: String,
+}
+
+#[cfg(feature = "songlink")]
+impl SongLinkResolver {
+    pub fn new() -> Result<SongLinkResolver> {
+        SongLinkResolver::with_base_url(SONGLINK_URL)
+    }
+
+    /// Err when the HTTP client can't be built, rather than a client
+    /// without the timeout.
+    pub fn with_base_url(base_url: &str) -> Result<SongLinkResolver> {
+        Ok(SongLinkResolver {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http_client: reqwest::blocking::Client::builder()
+                .timeout(SONGLINK_TIMEOUT)
+                .build()?,
+        })
+    }
+
+    /// The song.link page of a Spotify track url.
+    pub fn resolve(&self, spotify_url: &str) -> Result<String> {
+        let response: SongLinkResponse = self
+            .http_client
+            .get(format!("{}/v1-alpha.1/links", self.base_url))
+            .query(&[("url", spotify_url)])
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(response.page_url)
+    }
+
+    /// Adds the song.link page to `share`, leaves it with the Spotify url
+    /// when Odesli can't be reached or doesn't know the track.
+    pub fn add_link(&self, share: Share) -> Share {
+        match self.resolve(share.spotify_url()) {
+            Ok(link) => share.with_link(link),
+            Err(e) => {
+                warn!("No song.link page, sharing the Spotify url: {e}");
+                share
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::sample_track;
+
+    #[test]
+    fn test_default_share_snippet() {
+        let track = sample_track();
+        let template = share_template(DEFAULT_SHARE_TEMPLATE).unwrap();
+        let snippet = Share::new(&track).render(&template);
+        assert_eq!(
+            snippet,
+            format!(
+                "The Divine Zero by Pierce The Veil from {}\n\
+                https://open.spotify.com/track/1VY823dFzI9L8BEf2X7B5I",
+                track.album.name
+            )
+        );
+
+        let linked = Share::new(&track).with_link("https://song.link/s/1VY8".to_string());
+        let template = share_template("{link} ({spotify_url})").unwrap();
+        assert_eq!(
+            linked.render(&template),
+            "https://song.link/s/1VY8 (https://open.spotify.com/track/1VY823dFzI9L8BEf2X7B5I)"
+        );
+    }
+
+    #[cfg(feature = "songlink")]
+    #[test]
+    fn test_songlink_falls_back_to_spotify() {
+        let spotify_url = "https://open.spotify.com/track/1VY823dFzI9L8BEf2X7B5I";
+        let template = share_template("{link}").unwrap();
+
+        let mut server = mockito::Server::new();
+        let found = server
+            .mock("GET", "/v1-alpha.1/links")
+            .match_query(mockito::Matcher::UrlEncoded("url".into(), spotify_url.into()))
+            .with_body(r#"{"entityUniqueId": "SPOTIFY_SONG::1VY8", "pageUrl": "https://song.link/s/1VY8"}"#)
+            .create();
+        let resolver = SongLinkResolver::with_base_url(&server.url()).unwrap();
+        let linked = resolver.add_link(Share::new(&sample_track()));
+        assert_eq!(linked.render(&template), "https://song.link/s/1VY8");
+        found.assert();
+
+        // Odesli doesn't know the track
+        let mut server = mockito::Server::new();
+        let unknown = server
+            .mock("GET", "/v1-alpha.1/links")
+            .match_query(mockito::Matcher::Any)
+            .with_status(404)
+            .create();
+        let resolver = SongLinkResolver::with_base_url(&server.url()).unwrap();
+        let fallback = resolver.add_link(Share::new(&sample_track()));
+        assert_eq!(fallback.render(&template), spotify_url);
+        unknown.assert();
+    }
+}
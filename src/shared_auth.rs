@@ -0,0 +1,275 @@
+use crate::spotify_api::UserAuthData;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+#[cfg(not(feature = "blocking"))]
+use std::future::Future;
+
+/// The user's tokens, shared between clients that hand each other a clone.
+///
+/// The tokens sit behind a lock that is only ever held to copy them in or
+/// out, never across network IO, so reading the access token doesn't wait
+/// for a slow refresh. Refreshes queue on a separate gate and re-check the
+/// tokens once through it, so only the first of many concurrent callers
+/// actually talks to Spotify.
+#[derive(Clone, Default)]
+pub struct SharedAuth {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    auth: Mutex<Option<UserAuthData>>,
+    #[cfg(feature = "blocking")]
+    refresh_gate: Mutex<()>,
+    #[cfg(not(feature = "blocking"))]
+    refresh_gate: tokio::sync::Mutex<()>,
+}
+
+impl SharedAuth {
+    pub fn new(auth: Option<UserAuthData>) -> SharedAuth {
+        let shared = SharedAuth::default();
+        *shared.lock() = auth;
+        shared
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<UserAuthData>> {
+        // The data is replaced whole, a panic elsewhere can't leave it half written
+        self.inner
+            .auth
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.lock().is_some()
+    }
+
+    pub fn access_token(&self) -> Option<String> {
+        self.lock().as_ref().map(|auth| auth.access_token.clone())
+    }
+
+    /// A copy of the tokens, the lock is released before this returns.
+    pub fn snapshot(&self) -> Option<UserAuthData> {
+        self.lock().clone()
+    }
+
+    pub fn set(&self, auth: Option<UserAuthData>) {
+        *self.lock() = auth;
+    }
+
+    fn snapshot_if(&self, stale: &impl Fn(&UserAuthData) -> bool) -> Option<UserAuthData> {
+        self.lock().as_ref().filter(|auth| stale(auth)).cloned()
+    }
+
+    /// Runs `refresh` on a copy of the tokens when `stale` says they need it,
+    /// and stores what it returns. Returns whether this call refreshed.
+    ///
+    /// `stale` is checked again after waiting for the gate, callers that
+    /// queued behind a refresh see the new tokens and skip theirs.
+    #[cfg(feature = "blocking")]
+    pub fn refresh_if(
+        &self,
+        stale: impl Fn(&UserAuthData) -> bool,
+        refresh: impl FnOnce(UserAuthData) -> Result<UserAuthData>,
+    ) -> Result<bool> {
+        if self.snapshot_if(&stale).is_none() {
+            return Ok(false);
+        }
+        let _gate = self
+            .inner
+            .refresh_gate
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(current) = self.snapshot_if(&stale) else {
+            return Ok(false);
+        };
+        let refreshed = refresh(current)?;
+        self.set(Some(refreshed));
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn refresh_if<F, Fut>(
+        &self,
+        stale: impl Fn(&UserAuthData) -> bool,
+        refresh: F,
+    ) -> Result<bool>
+    where
+        F: FnOnce(UserAuthData) -> Fut,
+        Fut: Future<Output = Result<UserAuthData>>,
+    {
+        if self.snapshot_if(&stale).is_none() {
+            return Ok(false);
+        }
+        let _gate = self.inner.refresh_gate.lock().await;
+        let Some(current) = self.snapshot_if(&stale) else {
+            return Ok(false);
+        };
+        let refreshed = refresh(current).await?;
+        self.set(Some(refreshed));
+        Ok(true)
+    }
+}
+
+/// The tokens of every user, for processes that build several clients for
+/// one user, e.g. the poll loop and the control socket handlers. Clients
+/// built with [crate::spotify_api::SpotifyClientBuilder::with_token_cache]
+/// get their user's [SharedAuth], so a refresh by one reaches all of them
+/// and a rotated refresh token doesn't leave the others with a dead one.
+#[derive(Clone, Default)]
+pub struct TokenCache {
+    users: Arc<RwLock<HashMap<String, SharedAuth>>>,
+}
+
+impl TokenCache {
+    pub fn new() -> TokenCache {
+        TokenCache::default()
+    }
+
+    /// The user's tokens, empty the first time the user is asked for.
+    pub fn for_user(&self, user_id: &str) -> SharedAuth {
+        let users = self.users.read().unwrap_or_else(|p| p.into_inner());
+        if let Some(auth) = users.get(user_id) {
+            return auth.clone();
+        }
+        drop(users);
+        let mut users = self.users.write().unwrap_or_else(|p| p.into_inner());
+        users.entry(user_id.to_string()).or_default().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const READERS: usize = 16;
+
+    fn auth(access_token: &str) -> UserAuthData {
+        UserAuthData {
+            access_token: access_token.to_string(),
+            token_type: "Bearer".to_string(),
+            scope: String::new(),
+            expires_in: 3600,
+            refresh_token: "refresh-token".to_string(),
+            last_refresh: None,
+        }
+    }
+
+    fn is_old(auth: &UserAuthData) -> bool {
+        auth.access_token == "old"
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_concurrent_refresh_happens_once() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        let shared = SharedAuth::new(Some(auth("old")));
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let handles: Vec<_> = (0..READERS)
+            .map(|_| {
+                let shared = shared.clone();
+                let refreshes = refreshes.clone();
+                let started_tx = started_tx.clone();
+                thread::spawn(move || {
+                    shared
+                        .refresh_if(is_old, |_| {
+                            refreshes.fetch_add(1, Ordering::SeqCst);
+                            let _ = started_tx.send(());
+                            thread::sleep(Duration::from_millis(300));
+                            Ok(auth("new"))
+                        })
+                        .unwrap();
+                    shared.access_token().unwrap()
+                })
+            })
+            .collect();
+
+        // Reading while the slow refresh is in flight doesn't wait for it
+        started_rx.recv().unwrap();
+        let read_at = Instant::now();
+        assert_eq!(shared.access_token().as_deref(), Some("old"));
+        assert!(read_at.elapsed() < Duration::from_millis(100));
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), "new");
+        }
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_concurrent_refresh_happens_once() {
+        let shared = SharedAuth::new(Some(auth("old")));
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let reads_during_refresh = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..READERS {
+            let shared = shared.clone();
+            let refreshes = refreshes.clone();
+            let reads = reads_during_refresh.clone();
+            tasks.push(tokio::spawn(async move {
+                let reader = shared.clone();
+                shared
+                    .refresh_if(is_old, |_| async move {
+                        refreshes.fetch_add(1, Ordering::SeqCst);
+                        // A slow refresh: let every other task run meanwhile
+                        for _ in 0..READERS * 4 {
+                            if reader.access_token().as_deref() == Some("old") {
+                                reads.fetch_add(1, Ordering::SeqCst);
+                            }
+                            tokio::task::yield_now().await;
+                        }
+                        Ok(auth("new"))
+                    })
+                    .await
+                    .unwrap();
+                shared.access_token().unwrap()
+            }));
+        }
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), "new");
+        }
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        assert_eq!(reads_during_refresh.load(Ordering::SeqCst), READERS * 4);
+    }
+
+    #[test]
+    fn test_token_cache_is_per_user() {
+        let cache = TokenCache::new();
+        let jorge = cache.for_user("jorge");
+        assert!(!jorge.is_set());
+        cache.for_user("jorge").set(Some(auth("new")));
+        assert_eq!(jorge.access_token().as_deref(), Some("new"));
+        assert!(!cache.for_user("ana").is_set());
+        assert_eq!(
+            cache.clone().for_user("jorge").access_token().as_deref(),
+            Some("new")
+        );
+    }
+
+    #[test]
+    fn test_fresh_tokens_are_left_alone() {
+        let shared = SharedAuth::new(Some(auth("new")));
+        #[cfg(feature = "blocking")]
+        let refreshed = shared.refresh_if(is_old, |_| unreachable!()).unwrap();
+        #[cfg(not(feature = "blocking"))]
+        let refreshed = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(shared.refresh_if(is_old, |_| async { unreachable!() }))
+            .unwrap();
+        assert!(!refreshed);
+        assert!(!SharedAuth::new(None).is_set());
+    }
+}
@@ -1,31 +1,230 @@
-use crate::local_store::CredStorage;
+use crate::artist_cache::{ArtistCache, CachedArtist};
+use crate::browser::{default_opener, UrlOpener};
+use crate::capture::CaptureConfig;
+use crate::error::{AuthorizationDenied, MissingScopes, SpotifyError};
+use crate::history::{
+    AvailabilityChange, Confidence, HistoryStore, LibraryAudit, PlayHistoryEntry, PlaySource,
+};
+use crate::library_import::{unique_track_ids, ImportPlan, LibraryImport};
+use crate::library_snapshot::{PageRequest, SnapshotPage, SnapshotRun, SNAPSHOT_PAGE_SIZE};
+use crate::local_store::{CachedProfile, CredStorage, UserMeta};
+use crate::lyrics::{Lyrics, LyricsProvider, NoLyrics};
 use crate::pkce;
-use crate::spotify_data::CurrentlyPlayingTrack;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::redact;
+use crate::shared_auth::{SharedAuth, TokenCache};
+use crate::spotify_data::{
+    Album, AlbumFull, ArtistFull, Artists, AudioAnalysis, AudioFeatures, Audiobook,
+    CurrentlyPlayingTrack, CursorPage, Device, Devices, FollowedArtists, Image, ImageSize,
+    NewReleases, Page, PlayContext, PlaybackState, PlayingItem, PlaylistItem, PlaylistSnapshot,
+    PlaylistSummary, Queue, RecentlyPlayed, SavedAlbum, SavedEpisode, SavedTrack, Show,
+    ShowEpisode, Track, TrackSearch, UserProfile,
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
 use std::io;
-use std::time::SystemTime;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::slice::Chunks;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[cfg(feature = "blocking")]
-use reqwest::blocking::{Client, Response};
+use reqwest::blocking::{Client, RequestBuilder};
+#[cfg(feature = "blocking")]
+use tokio::runtime::Handle;
 
 #[cfg(not(feature = "blocking"))]
-use reqwest::{Client, Response};
+use reqwest::{Client, RequestBuilder};
 
-use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderName, RETRY_AFTER};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info, warn};
+#[cfg(not(feature = "blocking"))]
+use tracing::Instrument;
+use tracing::{debug, error, info, info_span, trace, warn, Span};
 use url::Url;
 
-pub const SCOPE: &str = "user-read-playback-state user-read-currently-playing playlist-read-private user-read-playback-position user-top-read user-read-recently-played user-library-read";
-const SPOTIFY_AUTH_URL: &str = "https://accounts.spotify.com/authorize";
-const SPOTIFY_TOKENS_URL: &str = "https://accounts.spotify.com/api/token";
-const SPOTIFY_API_URL: &str = "https://api.spotify.com/v1/me/player";
-const CUR_PLAYING_API_PATH: &str = "/currently-playing";
-const REDIRECT_URI: &str = "http://localhost:8080";
+pub const SCOPE: &str = "user-read-playback-state user-read-currently-playing playlist-read-private playlist-modify-public playlist-modify-private user-read-playback-position user-top-read user-read-recently-played user-library-read user-library-modify user-follow-read user-modify-playback-state";
+/// Lets `/me` include the user's email, only requested when asked for
+pub const EMAIL_SCOPE: &str = "user-read-email";
+/// Lets the client upload playlist covers, only requested when asked for
+pub const IMAGE_UPLOAD_SCOPE: &str = "ugc-image-upload";
+/// Directory under the temp dir [SpotifyClient::fetch_album_art] keeps covers in
+pub const DEFAULT_ART_CACHE_DIR: &str = "spotify-rs-album-art";
+
+/// What a client is used for. A client built with
+/// [SpotifyClientBuilder::with_capabilities] only asks for their scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// What is and was playing, always asked for
+    ReadPlayback,
+    ControlPlayback,
+    ReadLibrary,
+    EditLibrary,
+    ReadPlaylists,
+    EditPlaylists,
+    TopItems,
+    Following,
+    Email,
+    ImageUpload,
+}
+
+impl Capability {
+    pub fn scopes(self) -> &'static [&'static str] {
+        match self {
+            Capability::ReadPlayback => &[
+                "user-read-playback-state",
+                "user-read-currently-playing",
+                "user-read-recently-played",
+                "user-read-playback-position",
+            ],
+            Capability::ControlPlayback => &["user-modify-playback-state"],
+            Capability::ReadLibrary => &["user-library-read"],
+            Capability::EditLibrary => &["user-library-read", "user-library-modify"],
+            Capability::ReadPlaylists => &["playlist-read-private"],
+            Capability::EditPlaylists => &[
+                "playlist-read-private",
+                "playlist-modify-public",
+                "playlist-modify-private",
+            ],
+            Capability::TopItems => &["user-top-read"],
+            Capability::Following => &["user-follow-read"],
+            Capability::Email => &[EMAIL_SCOPE],
+            Capability::ImageUpload => &[IMAGE_UPLOAD_SCOPE],
+        }
+    }
+}
+const SPOTIFY_ACCOUNTS_URL: &str = "https://accounts.spotify.com";
+const SPOTIFY_API_URL: &str = "https://api.spotify.com/v1";
+const AUTH_PATH: &str = "/authorize";
+const TOKENS_PATH: &str = "/api/token";
+const ME_API_PATH: &str = "/me";
+// Episodes and audiobook chapters are only reported by the player when asked for,
+// and whether tracks are playable only for a market
+const PLAYER_API_PATH: &str = "/me/player?additional_types=episode,chapter&market=from_token";
+/// The market tracks are looked up in, the one of the user's account
+const MARKET: &str = "from_token";
+const TRANSFER_API_PATH: &str = "/me/player";
+const PAUSE_API_PATH: &str = "/me/player/pause";
+const PLAY_API_PATH: &str = "/me/player/play";
+const NEXT_API_PATH: &str = "/me/player/next";
+const PREVIOUS_API_PATH: &str = "/me/player/previous";
+const CUR_PLAYING_API_PATH: &str = "/me/player/currently-playing?additional_types=episode,chapter";
+const DEVICES_API_PATH: &str = "/me/player/devices";
+const QUEUE_API_PATH: &str = "/me/player/queue";
+const AUDIO_FEATURES_API_PATH: &str = "/audio-features";
+const AUDIO_ANALYSIS_API_PATH: &str = "/audio-analysis";
+const SAVED_ALBUMS_API_PATH: &str = "/me/albums";
+const SAVED_ALBUMS_CONTAINS_API_PATH: &str = "/me/albums/contains";
+const MY_PLAYLISTS_API_PATH: &str = "/me/playlists";
+const PLAYLISTS_API_PATH: &str = "/playlists";
+const FOLLOWING_API_PATH: &str = "/me/following";
+const ARTISTS_API_PATH: &str = "/artists";
+const NEW_RELEASES_API_PATH: &str = "/browse/new-releases";
+const SAVED_AUDIOBOOKS_API_PATH: &str = "/me/audiobooks";
+const SAVED_TRACKS_API_PATH: &str = "/me/tracks";
+const SAVED_TRACKS_CONTAINS_API_PATH: &str = "/me/tracks/contains";
+const SAVED_EPISODES_API_PATH: &str = "/me/episodes";
+const SHOWS_API_PATH: &str = "/shows";
+const ALBUMS_API_PATH: &str = "/albums";
+const RECENTLY_PLAYED_API_PATH: &str = "/me/player/recently-played";
+const SEARCH_API_PATH: &str = "/search";
+const ME_ENDPOINT: &str = "me";
+const PLAYER_ENDPOINT: &str = "player";
+const CUR_PLAYING_ENDPOINT: &str = "currently-playing";
+const DEVICES_ENDPOINT: &str = "devices";
+const QUEUE_ENDPOINT: &str = "queue";
+const AUDIO_FEATURES_ENDPOINT: &str = "audio-features";
+const AUDIO_ANALYSIS_ENDPOINT: &str = "audio-analysis";
+const SAVED_ALBUMS_CONTAINS_ENDPOINT: &str = "albums-contains";
+const SAVED_TRACKS_CONTAINS_ENDPOINT: &str = "tracks-contains";
+const PLAYLIST_ITEMS_ENDPOINT: &str = "playlist-items";
+const FOLLOWERS_CONTAINS_ENDPOINT: &str = "followers-contains";
+const FOLLOWED_ARTISTS_ENDPOINT: &str = "followed-artists";
+const ARTISTS_ENDPOINT: &str = "artists";
+const NEW_RELEASES_ENDPOINT: &str = "new-releases";
+const PLAYLIST_TRACKS_ENDPOINT: &str = "playlist-tracks";
+const SAVED_AUDIOBOOKS_ENDPOINT: &str = "saved-audiobooks";
+const RECENTLY_PLAYED_ENDPOINT: &str = "recently-played";
+const PLAYLIST_IMAGES_ENDPOINT: &str = "playlist-images";
+const SAVED_EPISODES_ENDPOINT: &str = "saved-episodes";
+const SAVED_TRACKS_ENDPOINT: &str = "saved-tracks";
+const SAVED_ALBUMS_ENDPOINT: &str = "saved-albums";
+const MY_PLAYLISTS_ENDPOINT: &str = "my-playlists";
+const SHOW_ENDPOINT: &str = "show";
+const ALBUM_ENDPOINT: &str = "album";
+const SHOW_EPISODES_ENDPOINT: &str = "show-episodes";
+const SEARCH_ENDPOINT: &str = "search";
+/// Captures of the raw requests, whatever path they went to
+const RAW_ENDPOINT: &str = "raw";
+/// Most ids Spotify accepts in one `/me/albums/contains` call
+const MAX_SAVED_ALBUMS_IDS: usize = 20;
+/// Most user ids Spotify accepts in one playlist `followers/contains` call
+const MAX_FOLLOWER_IDS: usize = 5;
+/// Most ids Spotify accepts in one `/artists` call
+const MAX_ARTISTS_IDS: usize = 50;
+/// Most artists Spotify returns in one page of `/me/following`
+const MAX_FOLLOWED_ARTISTS_LIMIT: u32 = 50;
+/// Most albums Spotify returns in one page of `/browse/new-releases`
+const MAX_NEW_RELEASES_LIMIT: u32 = 50;
+/// Most audiobooks Spotify returns in one page of `/me/audiobooks`
+const MAX_SAVED_AUDIOBOOKS_LIMIT: u32 = 50;
+/// Most episodes Spotify returns in one page of `/me/episodes` or a show's episodes
+const MAX_EPISODES_LIMIT: u32 = 50;
+/// Most items Spotify returns in one page of `/me/tracks`, `/me/albums` or
+/// `/me/playlists`
+const MAX_LIBRARY_PAGE_LIMIT: u32 = 50;
+/// Most tracks Spotify returns in one page of `/me/player/recently-played`
+pub const MAX_RECENTLY_PLAYED_LIMIT: u32 = 50;
+/// Most ids Spotify accepts in one save or remove of `/me/tracks`
+const MAX_SAVED_TRACKS_IDS: usize = 50;
+/// Most items Spotify returns in one page of a playlist's items
+const MAX_PLAYLIST_ITEMS_LIMIT: u32 = 100;
+/// Most items Spotify removes from a playlist in one call
+const MAX_PLAYLIST_REMOVE_URIS: usize = 100;
+/// Most items Spotify adds to a playlist in one call
+const MAX_PLAYLIST_ADD_URIS: usize = 100;
+/// Most results Spotify returns in one page of `/search`
+pub const MAX_SEARCH_LIMIT: u32 = 50;
+/// Biggest playlist cover Spotify accepts, counted in base64
+pub const MAX_COVER_UPLOAD_SIZE: usize = 256 * 1024;
+const TOKEN_ENDPOINT: &str = "token";
+/// How long before expiry an access token is refreshed by default
+pub const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(5);
+/// How often a rate limited request is retried before giving up
+const MAX_RATE_LIMITED_RETRIES: u32 = 3;
+/// How often a redirected URL is asked for when the one pasted is no good
+const REDIRECT_ATTEMPTS: u32 = 3;
+/// How often the player is checked for a device that playback was moved to
+const DEVICE_ACTIVATION_POLLS: u32 = 5;
+/// The wait between two of those checks
+const DEVICE_ACTIVATION_INTERVAL: Duration = Duration::from_millis(200);
+/// The wait after a 429 that came without a Retry-After header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// How long the user's profile is used before `/me` is asked again
+pub const PROFILE_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Where Spotify sends the browser after authorizing, the app's settings
+/// have to list it. A custom scheme like `myapp://callback` works as well.
+pub const DEFAULT_REDIRECT_URI: &str = "http://localhost:8080";
 const CHALLENGE_METHOD: &str = "S256";
 const CONTENT_TYPE: &str = "Content-Type";
 const CONTENT_TYPE_URL_ENCODED: &str = "application/x-www-form-urlencoded";
+const CONTENT_TYPE_JPEG: &str = "image/jpeg";
+/// Response headers that announce deprecations or changes to the API
+const NOTICE_HEADERS: [&str; 3] = ["warning", "deprecation", "sunset"];
+/// Any header with this prefix is Spotify telling us something
+const SPOTIFY_HEADER_PREFIX: &str = "spotify-";
+/// Rate limit headers, only kept for diagnostics since they change every request
+const RATE_LIMIT_HEADER_PREFIXES: [&str; 3] = ["retry-after", "x-ratelimit-", "ratelimit-"];
+/// Every JPEG starts with these
+const JPEG_MAGIC: [u8; 3] = [0xFF, 0xD8, 0xFF];
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AppAuthData {
@@ -33,7 +232,8 @@ pub struct AppAuthData {
     pub client_secret: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Debug masks the tokens, see [redact::mask_secret].
+#[derive(Serialize, Deserialize, Clone)]
 pub struct UserAuthData {
     pub access_token: String,
     // token type is always "Bearer"
@@ -41,25 +241,255 @@ pub struct UserAuthData {
     // A space-separated list of scopes which have been granted for this access_token
     pub scope: String,
     pub expires_in: i64,
+    // Refresh responses may leave it out, the one refreshed with stays valid then
+    #[serde(default)]
     pub refresh_token: String,
     pub last_refresh: Option<SystemTime>,
 }
 
+/// A client credentials token, https://developer.spotify.com/documentation/web-api/tutorials/client-credentials-flow
+#[derive(Deserialize)]
+struct AppToken {
+    access_token: String,
+    token_type: String,
+    expires_in: i64,
+}
+
+/// Where the client sends its requests. Defaults to Spotify's production
+/// URLs, override it to target a mock server or a mirror.
+#[derive(Debug, Clone)]
+pub struct SpotifyEndpoints {
+    pub auth_url: String,
+    pub tokens_url: String,
+    pub api_url: String,
+}
+
+impl Default for SpotifyEndpoints {
+    fn default() -> Self {
+        SpotifyEndpoints::with_base_urls(SPOTIFY_ACCOUNTS_URL, SPOTIFY_API_URL)
+    }
+}
+
+impl SpotifyEndpoints {
+    /// `accounts_base` hosts the authorize and token endpoints,
+    /// `api_base` is the root of the Web API, i.e. what `/v1` maps to.
+    pub fn with_base_urls(accounts_base: &str, api_base: &str) -> SpotifyEndpoints {
+        let accounts_base = accounts_base.trim_end_matches('/');
+        SpotifyEndpoints {
+            auth_url: format!("{accounts_base}{AUTH_PATH}"),
+            tokens_url: format!("{accounts_base}{TOKENS_PATH}"),
+            api_url: api_base.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn api(&self, path: &str) -> String {
+        format!("{}{path}", self.api_url)
+    }
+}
+
 pub struct SpotifyClient {
     user_id: String,
     app_client_id: Option<String>,
-    user_auth: Option<UserAuthData>,
-    creds_storage: CredStorage,
+    // Set for confidential apps, token requests then authenticate with it instead of PKCE
+    app_client_secret: Option<String>,
+    // Using a client credentials token, there is no user to act for
+    app_only: bool,
+    user_auth: SharedAuth,
+    // The tokens are in a TokenCache, another client may hold newer ones than storage
+    cached_tokens: bool,
+    // None when the client was built with in-memory credentials
+    creds_storage: Option<CredStorage>,
     http_client: Client,
+    endpoints: SpotifyEndpoints,
+    // Where Spotify sends the browser after an authorization
+    redirect_uri: String,
+    capture: Option<CaptureConfig>,
+    // PKCE verifier of an authorization that was started but not completed
+    pending_code_verifier: Option<String>,
+    refresh_margin: Duration,
+    // Whether setup_creds may fall back to asking the user to authorize
+    interactive: bool,
+    user_meta: UserMeta,
+    // The scopes an authorization asks for
+    scope: String,
+    // Scopes a command found missing, added to the next authorization
+    wanted_scopes: Vec<String>,
+    // Whether an authorization always shows Spotify's consent screen
+    force_consent: bool,
+    log_bodies: bool,
+    rate_limit: RateLimit,
+    // How far behind Spotify's timestamp the last currently playing answer arrived
+    server_time_offset: Option<Duration>,
+    // Answers younger than this are served from `playing_cache`
+    min_refetch_interval: Duration,
+    playing_cache: Option<CachedPlaying>,
+    lyrics_provider: Arc<dyn LyricsProvider>,
+    // Shows the authorization page, None when the URL is only printed
+    url_opener: Option<Arc<dyn UrlOpener>>,
+    // The last value of every header of interest Spotify sent
+    diagnostics: BTreeMap<String, SeenHeader>,
+    // Header API requests carry `correlation_id` in, when both are set
+    correlation_id_header: Option<String>,
+    correlation_id: Option<String>,
+    // Where downloaded album covers are kept, by album id and size
+    art_cache_dir: PathBuf,
+}
+
+/// A header of interest from the last response that had it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeenHeader {
+    pub value: String,
+    /// The request that got it
+    pub url: String,
+    pub seen_at: SystemTime,
+}
+
+/// What a client ended up configured with, after the builder's defaults and
+/// overrides. Secrets are masked, so it can be printed or pasted in a bug report.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct EffectiveConfig {
+    pub user_id: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub auth_url: String,
+    pub tokens_url: String,
+    pub api_url: String,
+    pub redirect_uri: String,
+    pub market: String,
+    /// Where the creds are kept: `bitwarden` or `in-memory`
+    pub cred_store: String,
+    /// Where the local files go, None for in-memory creds
+    pub local_dir: Option<PathBuf>,
+    pub store_access_token: bool,
+    pub capture_dir: Option<PathBuf>,
+    pub refresh_margin_secs: u64,
+    pub min_refetch_interval_ms: u64,
+    pub interactive: bool,
+    pub force_consent: bool,
+    pub log_bodies: bool,
+    pub opens_browser: bool,
+    pub preferred_device: Option<String>,
+    /// What an authorization asks for
+    pub requested_scopes: Vec<String>,
+    /// Missing scopes the next authorization adds
+    pub wanted_scopes: Vec<String>,
+    /// What the stored token was granted
+    pub granted_scopes: Vec<String>,
+    pub access_token: Option<String>,
+    pub refresh_token: Option<String>,
+}
+
+/// The last currently playing answer, None when nothing was playing.
+struct CachedPlaying {
+    playing: Option<CurrentlyPlayingTrack>,
+    fetched_at: Instant,
+}
+
+pub struct SpotifyClientBuilder {
+    user_id: String,
+    endpoints: SpotifyEndpoints,
+    redirect_uri: String,
+    capture: Option<CaptureConfig>,
+    in_memory_creds: Option<(String, UserAuthData)>,
+    client_secret: Option<String>,
+    refresh_margin: Duration,
+    interactive: bool,
+    log_bodies: bool,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    token_cache: Option<TokenCache>,
+    email_scope: bool,
+    image_upload_scope: bool,
+    capabilities: Option<Vec<Capability>>,
+    force_consent: bool,
+    min_refetch_interval: Duration,
+    lyrics_provider: Option<Arc<dyn LyricsProvider>>,
+    open_browser: bool,
+    url_opener: Option<Arc<dyn UrlOpener>>,
+    correlation_id_header: Option<String>,
+    art_cache_dir: Option<PathBuf>,
+    #[cfg(feature = "blocking")]
+    runtime: Option<Handle>,
+}
+
+/// What a request sends besides its query.
+#[derive(Clone, Copy)]
+enum RequestBody<'a> {
+    Json(&'a serde_json::Value),
+    Form(&'a [(&'a str, &'a str)]),
+    /// A base64 encoded JPEG, Spotify wants it labelled as the JPEG itself
+    Jpeg(&'a str),
+}
+
+/// A Spotify response read in full, so the body is still around
+/// for debug captures after a failed parse.
+struct ApiResponse {
+    url: String,
+    status: StatusCode,
+    body: String,
+    retry_after: Option<Duration>,
+    /// Headers of interest by their lowercase name, see [headers_of_interest]
+    headers: Vec<(String, String)>,
+}
+
+impl ApiResponse {
+    /// Spotify's gateway answers outages with an HTML page instead of a
+    /// JSON error, there is nothing in it worth parsing.
+    fn check_upstream(&self, content_type: Option<&str>) -> Result<()> {
+        let is_json = content_type.is_some_and(|ct| ct.contains("json"));
+        if self.status.is_server_error() && !is_json {
+            debug!("Non JSON error page from <{}>: {}", self.url, self.body);
+            return Err(SpotifyError::Upstream {
+                status: self.status.as_u16(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// The deprecation notices, `Spotify-*` and rate limit headers of a response.
+fn headers_of_interest(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .filter(|(name, _)| is_notice_header(name.as_str()) || is_rate_limit_header(name.as_str()))
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect()
+}
+
+fn is_notice_header(name: &str) -> bool {
+    NOTICE_HEADERS.contains(&name) || name.starts_with(SPOTIFY_HEADER_PREFIX)
+}
+
+fn is_rate_limit_header(name: &str) -> bool {
+    RATE_LIMIT_HEADER_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// True the first time this process sees `value` for the header `name`,
+/// so a deprecation notice is logged once instead of on every poll.
+fn first_sighting(name: &str, value: &str) -> bool {
+    static SEEN: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    SEEN.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(format!("{name}: {value}"))
 }
 
 impl UserAuthData {
-    pub fn token_needs_refresh(&self) -> bool {
+    /// True when the access token expired or expires within `margin`.
+    /// A bigger margin refreshes a bit early, so a token never expires
+    /// mid-request on a slow network or with a drifting clock.
+    pub fn token_needs_refresh(&self, margin: Duration) -> bool {
+        self.token_needs_refresh_at(SystemTime::now(), margin)
+    }
+
+    pub fn token_needs_refresh_at(&self, now: SystemTime, margin: Duration) -> bool {
         if let Some(last_refresh) = self.last_refresh {
-            match last_refresh.elapsed() {
+            match now.duration_since(last_refresh) {
                 Ok(elapsed) => {
-                    // Adding a 5 second buffer
-                    if elapsed.as_secs() < (self.expires_in as u64 - 5) {
+                    let lifetime = Duration::from_secs(self.expires_in.max(0) as u64);
+                    if elapsed + margin < lifetime {
                         return false;
                     }
                 }
@@ -71,371 +501,7358 @@ impl UserAuthData {
 
         true
     }
+
+    /// When the access token expires, None when it isn't known when it was
+    /// handed out.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let lifetime = Duration::from_secs(self.expires_in.max(0) as u64);
+        self.last_refresh
+            .map(|last_refresh| last_refresh + lifetime)
+    }
+
+    /// When [UserAuthData::token_needs_refresh] with `margin` turns true,
+    /// None when it isn't known when the token was handed out. In the past
+    /// for a token that needs refreshing already.
+    pub fn refresh_due(&self, margin: Duration) -> Option<SystemTime> {
+        let expires_at = self.expires_at()?;
+        Some(expires_at.checked_sub(margin).unwrap_or(UNIX_EPOCH))
+    }
+
+    /// The scopes granted to the access token.
+    pub fn granted_scopes(&self) -> Vec<&str> {
+        self.scope.split_whitespace().collect()
+    }
 }
 
-impl SpotifyClient {
-    #[cfg(feature = "blocking")]
-    pub fn new(user_id: String) -> Result<SpotifyClient> {
-        let creds_storage = CredStorage::new()?;
-        Ok(SpotifyClient {
-            user_id,
-            app_client_id: None,
-            user_auth: None,
-            creds_storage,
-            http_client: Client::new(),
-        })
+impl fmt::Debug for UserAuthData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserAuthData")
+            .field("access_token", &redact::mask_secret(&self.access_token))
+            .field("token_type", &self.token_type)
+            .field("scope", &self.scope)
+            .field("expires_in", &self.expires_in)
+            .field("refresh_token", &redact::mask_secret(&self.refresh_token))
+            .field("last_refresh", &self.last_refresh)
+            .finish()
     }
+}
 
-    #[cfg(not(feature = "blocking"))]
-    pub async fn new(user_id: String) -> Result<SpotifyClient> {
-        let creds_storage = CredStorage::new().await?;
-        Ok(SpotifyClient {
+impl SpotifyClientBuilder {
+    pub fn new(user_id: String) -> SpotifyClientBuilder {
+        SpotifyClientBuilder {
             user_id,
-            app_client_id: None,
-            user_auth: None,
-            creds_storage,
-            http_client: Client::new(),
-        })
+            endpoints: SpotifyEndpoints::default(),
+            redirect_uri: DEFAULT_REDIRECT_URI.to_string(),
+            capture: None,
+            in_memory_creds: None,
+            client_secret: None,
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            interactive: true,
+            log_bodies: false,
+            rate_limiter: None,
+            token_cache: None,
+            email_scope: false,
+            image_upload_scope: false,
+            capabilities: None,
+            force_consent: false,
+            min_refetch_interval: Duration::ZERO,
+            lyrics_provider: None,
+            open_browser: true,
+            url_opener: None,
+            correlation_id_header: None,
+            art_cache_dir: None,
+            #[cfg(feature = "blocking")]
+            runtime: None,
+        }
     }
 
-    fn creds_are_loaded(&self) -> bool {
-        self.app_client_id.is_some() && self.user_auth.is_some()
+    /// When the cached credentials don't work, `setup_creds` starts an
+    /// authorization on stdin. Turn that off for headless use, it errors instead.
+    pub fn interactive(mut self, interactive: bool) -> SpotifyClientBuilder {
+        self.interactive = interactive;
+        self
     }
 
-    fn access_token(&self) -> String {
-        let auth = self.user_auth.as_ref().unwrap();
-        auth.access_token.clone()
+    /// Logs request and response bodies at trace level, with tokens and
+    /// auth codes masked. Off by default.
+    pub fn log_bodies(mut self, log_bodies: bool) -> SpotifyClientBuilder {
+        self.log_bodies = log_bodies;
+        self
     }
 
-    #[cfg(feature = "blocking")]
-    fn update_user_auth(&mut self, response: Response) -> Result<()> {
-        let mut user_auth_data: UserAuthData = match response.json() {
-            Err(_) => {
-                bail!("Could not parse response json into a UserAuthData struct");
-            }
-            Ok(auth) => auth,
-        };
-        user_auth_data.last_refresh = Some(SystemTime::now());
-        self.creds_storage
-            .store_user_auth_data(&user_auth_data, &self.user_id);
-        self.user_auth = Some(user_auth_data);
+    /// Also asks for [EMAIL_SCOPE] when authorizing, so the profile has the
+    /// user's email. Off by default, the tracker doesn't need it. Takes
+    /// effect with the next authorization.
+    pub fn with_email_scope(mut self, email_scope: bool) -> SpotifyClientBuilder {
+        self.email_scope = email_scope;
+        self
+    }
 
-        Ok(())
+    /// Also asks for [IMAGE_UPLOAD_SCOPE] when authorizing, needed to set
+    /// playlist covers. Off by default, takes effect with the next authorization.
+    pub fn with_image_upload_scope(mut self, image_upload_scope: bool) -> SpotifyClientBuilder {
+        self.image_upload_scope = image_upload_scope;
+        self
     }
 
-    #[cfg(not(feature = "blocking"))]
-    async fn update_user_auth(&mut self, response: Response) -> Result<()> {
-        let mut user_auth_data: UserAuthData = match response.json().await {
-            Err(_) => {
-                bail!("Could not parse response json into a UserAuthData struct");
-            }
-            Ok(auth) => auth,
-        };
-        user_auth_data.last_refresh = Some(SystemTime::now());
-        self.creds_storage
-            .store_user_auth_data(&user_auth_data, &self.user_id)
-            .await;
-        self.user_auth = Some(user_auth_data);
+    /// Only asks for the scopes of `capabilities` when authorizing, along
+    /// with those of [Capability::ReadPlayback] and the ones the user already
+    /// granted. Without it every scope in [SCOPE] is asked for.
+    pub fn with_capabilities(mut self, capabilities: &[Capability]) -> SpotifyClientBuilder {
+        self.capabilities = Some(capabilities.to_vec());
+        self
+    }
 
-        Ok(())
+    /// Makes Spotify show its consent screen on every authorization, so a
+    /// different account can log in. Off by default, Spotify skips the screen
+    /// for a user who already approved the app.
+    pub fn force_consent(mut self, force_consent: bool) -> SpotifyClientBuilder {
+        self.force_consent = force_consent;
+        self
     }
 
-    #[cfg(feature = "blocking")]
-    fn refresh_access_token(&mut self) -> Result<()> {
-        let app_client_id = self
-            .app_client_id
-            .clone()
-            .expect("Missing app_client_id data");
-        let auth = self.user_auth.as_ref().expect("Missing user_auth data");
+    /// Shares a [RateLimiter] with the other clients of this app, so they
+    /// back off together and split its budget. Without one the client only
+    /// waits out the 429s it gets itself.
+    pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> SpotifyClientBuilder {
+        self.rate_limiter = Some(limiter);
+        self
+    }
 
-        if !auth.token_needs_refresh() {
-            return Ok(());
-        }
-        info!("Refreshing API access token");
+    /// Reads the user's tokens from `cache` and writes refreshes to it, so
+    /// every client of the user built with it refreshes once between them.
+    /// Without one the client keeps its tokens to itself.
+    pub fn with_token_cache(mut self, cache: TokenCache) -> SpotifyClientBuilder {
+        self.token_cache = Some(cache);
+        self
+    }
 
-        let response = self
-            .http_client
-            .post(SPOTIFY_TOKENS_URL)
-            .header(CONTENT_TYPE, CONTENT_TYPE_URL_ENCODED)
-            .form(&[
-                ("grant_type", "refresh_token"),
-                ("refresh_token", &auth.refresh_token),
-                ("client_id", &app_client_id),
-            ])
-            .send();
-
-        let response = match response {
-            Ok(resp) => resp,
-            Err(e) => {
-                bail!("Problem interacting with Spotify API trying to refresh token: {e}")
-            }
-        };
+    /// Runs the creds storage on `runtime`, e.g. the one the caller already
+    /// has, instead of a runtime shared by every client.
+    #[cfg(feature = "blocking")]
+    pub fn with_runtime(mut self, runtime: Handle) -> SpotifyClientBuilder {
+        self.runtime = Some(runtime);
+        self
+    }
 
-        self.update_user_auth(response)
+    /// [SpotifyClient::get_currently_playing_track] answers from memory when
+    /// it asked Spotify less than `interval` ago, e.g. for a status bar that
+    /// renders more often than it should poll. Off by default.
+    pub fn with_min_refetch_interval(mut self, interval: Duration) -> SpotifyClientBuilder {
+        self.min_refetch_interval = interval;
+        self
     }
 
-    /// Checks if access token has expired or is about to expire within 5 seconds.
-    /// If so, an attempt is made to refresh the token and store the new values.
-    ///
-    /// On Error: access token failed to refresh, there was an issue interacting with Spotify's API
-    #[cfg(not(feature = "blocking"))]
-    async fn refresh_access_token(&mut self) -> Result<()> {
-        let app_client_id = self
-            .app_client_id
-            .clone()
-            .expect("Missing app_client_id data");
-        let auth = self.user_auth.as_ref().expect("Missing user_auth data");
+    /// Where [SpotifyClient::get_lyrics] looks lyrics up. Without one there
+    /// are never any lyrics.
+    pub fn with_lyrics_provider(
+        mut self,
+        provider: Arc<dyn LyricsProvider>,
+    ) -> SpotifyClientBuilder {
+        self.lyrics_provider = Some(provider);
+        self
+    }
 
-        if !auth.token_needs_refresh() {
-            return Ok(());
-        }
-        info!("Refreshing API access token");
+    /// Where [SpotifyClient::fetch_album_art] keeps covers, by default
+    /// [DEFAULT_ART_CACHE_DIR] under the temp dir.
+    pub fn with_art_cache_dir(mut self, dir: impl Into<PathBuf>) -> SpotifyClientBuilder {
+        self.art_cache_dir = Some(dir.into());
+        self
+    }
 
-        let response = self
-            .http_client
-            .post(SPOTIFY_TOKENS_URL)
-            .header(CONTENT_TYPE, CONTENT_TYPE_URL_ENCODED)
-            .form(&[
-                ("grant_type", "refresh_token"),
-                ("refresh_token", &auth.refresh_token),
-                ("client_id", &app_client_id),
-            ])
-            .send()
-            .await;
+    /// Whether an authorization opens its page in a browser. On by default,
+    /// which needs the `open` feature or an opener from
+    /// [SpotifyClientBuilder::with_url_opener]. The URL is printed either way.
+    pub fn open_browser(mut self, open_browser: bool) -> SpotifyClientBuilder {
+        self.open_browser = open_browser;
+        self
+    }
 
-        let response = match response {
-            Ok(resp) => resp,
-            Err(e) => {
-                bail!("Problem interacting with Spotify API trying to refresh token: {e}")
-            }
-        };
+    /// What opens the authorization page instead of the default browser.
+    pub fn with_url_opener(mut self, opener: Arc<dyn UrlOpener>) -> SpotifyClientBuilder {
+        self.url_opener = Some(opener);
+        self
+    }
 
-        self.update_user_auth(response).await
+    /// Sends the id set with [SpotifyClient::with_correlation_id] in the
+    /// `header` of every API request, e.g. `X-Request-Id`, to find a request
+    /// of this crate in the traces of the system around it.
+    pub fn with_correlation_id_header(mut self, header: &str) -> SpotifyClientBuilder {
+        self.correlation_id_header = Some(header.to_string());
+        self
     }
 
-    fn read_spotify_code() -> Option<String> {
-        let mut in_buffer = String::new();
-        info!("Paste full redirected URL:\n");
-        io::stdin().read_line(&mut in_buffer).unwrap();
-        let parsed_url = Url::parse(&in_buffer);
-        if let Err(e) = parsed_url {
-            error!("Invalid input URL/URI, failed parsing {e}");
-            return None;
-        }
+    /// Refresh the access token this long before it expires, see
+    /// [UserAuthData::token_needs_refresh]. Defaults to [DEFAULT_REFRESH_MARGIN].
+    pub fn with_refresh_margin(mut self, margin: Duration) -> SpotifyClientBuilder {
+        self.refresh_margin = margin;
+        self
+    }
 
-        get_code_from_query_pairs(parsed_url.unwrap())
+    /// Points the client somewhere other than Spotify's production URLs,
+    /// see [SpotifyEndpoints::with_base_urls].
+    pub fn with_base_urls(mut self, accounts_base: &str, api_base: &str) -> SpotifyClientBuilder {
+        self.endpoints = SpotifyEndpoints::with_base_urls(accounts_base, api_base);
+        self
     }
 
-    #[cfg(feature = "blocking")]
-    pub fn setup_creds(&mut self) -> Result<()> {
-        let client_id = self.creds_storage.load_app_auth_data()?.client_id;
-        self.app_client_id = Some(client_id.clone());
-        self.user_auth = self.creds_storage.load_user_auth_data(&self.user_id);
+    pub fn with_capture(mut self, capture: CaptureConfig) -> SpotifyClientBuilder {
+        self.capture = Some(capture);
+        self
+    }
 
-        if self.creds_are_loaded() {
-            let _ = self.refresh_access_token()?;
-            info!("Spotify API creds are ready to go");
-            return Ok(());
-        }
+    /// Seeds the credentials directly instead of loading them from the
+    /// secrets store. Nothing is read from or written to storage, refreshed
+    /// tokens only live in memory.
+    pub fn with_in_memory_creds(
+        mut self,
+        app_client_id: String,
+        user_auth: UserAuthData,
+    ) -> SpotifyClientBuilder {
+        self.in_memory_creds = Some((app_client_id, user_auth));
+        self
+    }
 
-        warn!("We need to generate auth tokens from Spotify, starting now");
+    /// The redirect URI registered for the app, [DEFAULT_REDIRECT_URI] by
+    /// default. Sent as given, Spotify compares it to the registered one
+    /// character by character.
+    pub fn with_redirect_uri(mut self, redirect_uri: &str) -> SpotifyClientBuilder {
+        self.redirect_uri = redirect_uri.to_string();
+        self
+    }
 
-        // Step 1: Auth with Spotify
-        let code_verifier = pkce::generate_code_verifier();
-        let code_challenge = pkce::encode_s256(&code_verifier);
-        let url = Url::parse_with_params(
-            SPOTIFY_AUTH_URL,
-            &[
-                ("response_type", "code"),
-                ("client_id", &client_id),
-                ("scope", SCOPE),
-                ("code_challenge_method", CHALLENGE_METHOD),
-                ("code_challenge", &code_challenge),
-                ("redirect_uri", REDIRECT_URI),
-            ],
-        )?;
-        info!("Paste this into your browser to auth this app: \n{}", url);
+    /// The secret of a confidential app, for in-memory creds. Stored creds
+    /// take it from the app's auth data.
+    pub fn with_client_secret(mut self, client_secret: String) -> SpotifyClientBuilder {
+        self.client_secret = Some(client_secret);
+        self
+    }
 
-        // Step 2: User must input code/state into this CLI
-        let spotify_auth_code = match Self::read_spotify_code() {
-            None => bail!("Could not get user input"),
-            Some(c) => c,
+    fn into_client(self, creds_storage: Option<CredStorage>) -> SpotifyClient {
+        let (app_client_id, auth) = match self.in_memory_creds {
+            Some((id, auth)) => (Some(id), Some(auth)),
+            None => (None, None),
         };
-        info!("Parsed auth code: {}", spotify_auth_code);
-
-        // Step 3: Ask spotify for an access token using the code
-        let response = self
-            .http_client
-            .post(SPOTIFY_TOKENS_URL)
-            .header(CONTENT_TYPE, CONTENT_TYPE_URL_ENCODED)
-            .form(&[
-                ("grant_type", "authorization_code"),
-                ("code", &spotify_auth_code),
-                ("client_id", &client_id),
-                ("code_verifier", &String::from_utf8(code_verifier)?),
-                ("redirect_uri", REDIRECT_URI),
-            ])
-            .send();
+        let user_auth = match &self.token_cache {
+            Some(cache) => {
+                let shared = cache.for_user(&self.user_id);
+                // An earlier client may have refreshed what is passed in
+                if !shared.is_set() {
+                    shared.set(auth);
+                }
+                shared
+            }
+            None => SharedAuth::new(auth),
+        };
+        SpotifyClient {
+            user_id: self.user_id,
+            app_client_id,
+            app_client_secret: self.client_secret,
+            app_only: false,
+            user_auth,
+            cached_tokens: self.token_cache.is_some(),
+            creds_storage,
+            http_client: Client::new(),
+            endpoints: self.endpoints,
+            redirect_uri: self.redirect_uri,
+            capture: self.capture,
+            pending_code_verifier: None,
+            refresh_margin: self.refresh_margin,
+            interactive: self.interactive,
+            user_meta: UserMeta::default(),
+            scope: match &self.capabilities {
+                Some(capabilities) => {
+                    let optional = [
+                        (self.email_scope, Capability::Email),
+                        (self.image_upload_scope, Capability::ImageUpload),
+                    ];
+                    let wanted = optional.iter().filter(|(wanted, _)| *wanted);
+                    let mut capabilities = capabilities.clone();
+                    capabilities.extend(wanted.map(|(_, capability)| *capability));
+                    scope_for(&capabilities)
+                }
+                None => requested_scope(self.email_scope, self.image_upload_scope),
+            },
+            wanted_scopes: Vec::new(),
+            force_consent: self.force_consent,
+            log_bodies: self.log_bodies,
+            rate_limit: self
+                .rate_limiter
+                .unwrap_or_else(|| Arc::new(RateLimiter::unlimited()))
+                .register(),
+            server_time_offset: None,
+            min_refetch_interval: self.min_refetch_interval,
+            playing_cache: None,
+            lyrics_provider: self.lyrics_provider.unwrap_or_else(|| Arc::new(NoLyrics)),
+            url_opener: match self.open_browser {
+                true => self.url_opener.or_else(default_opener),
+                false => None,
+            },
+            diagnostics: BTreeMap::new(),
+            correlation_id_header: self.correlation_id_header,
+            correlation_id: None,
+            art_cache_dir: self
+                .art_cache_dir
+                .unwrap_or_else(|| std::env::temp_dir().join(DEFAULT_ART_CACHE_DIR)),
+        }
+    }
 
-        debug!("Full Response from Spotify: {:?}", response);
+    fn check_correlation_id_header(&self) -> Result<()> {
+        if let Some(header) = &self.correlation_id_header {
+            HeaderName::try_from(header.as_str())
+                .with_context(|| format!("Not a header name: {header:?}"))?;
+        }
+        Ok(())
+    }
 
-        let resp = response?;
-        self.update_user_auth(resp)
+    #[cfg(feature = "blocking")]
+    pub fn build(self) -> Result<SpotifyClient> {
+        self.check_correlation_id_header()?;
+        let creds_storage = match self.in_memory_creds {
+            Some(_) => None,
+            None => Some(CredStorage::with_runtime(self.runtime.clone())?),
+        };
+        Ok(self.into_client(creds_storage))
     }
 
     #[cfg(not(feature = "blocking"))]
-    pub async fn setup_creds(&mut self) -> Result<()> {
-        let client_id = self.creds_storage.load_app_auth_data().await?.client_id;
-        self.app_client_id = Some(client_id.clone());
-        self.user_auth = self.creds_storage.load_user_auth_data(&self.user_id).await;
+    pub async fn build(self) -> Result<SpotifyClient> {
+        self.check_correlation_id_header()?;
+        let creds_storage = match self.in_memory_creds {
+            Some(_) => None,
+            None => Some(CredStorage::new().await?),
+        };
+        Ok(self.into_client(creds_storage))
+    }
+}
 
-        if self.creds_are_loaded() {
-            let _ = self.refresh_access_token().await?;
-            info!("Spotify API creds are ready to go");
-            return Ok(());
-        }
+impl SpotifyClient {
+    #[cfg(feature = "blocking")]
+    pub fn new(user_id: String) -> Result<SpotifyClient> {
+        SpotifyClientBuilder::new(user_id).build()
+    }
 
-        error!("We need to generate auth tokens from Spotify, starting now");
+    #[cfg(not(feature = "blocking"))]
+    pub async fn new(user_id: String) -> Result<SpotifyClient> {
+        SpotifyClientBuilder::new(user_id).build().await
+    }
 
-        // Step 1: Auth with Spotify
-        let code_verifier = pkce::generate_code_verifier();
-        let code_challenge = pkce::encode_s256(&code_verifier);
-        let url = Url::parse_with_params(
-            SPOTIFY_AUTH_URL,
-            &[
-                ("response_type", "code"),
-                ("client_id", &client_id),
-                ("scope", SCOPE),
-                ("code_challenge_method", CHALLENGE_METHOD),
-                ("code_challenge", &code_challenge),
-                ("redirect_uri", REDIRECT_URI),
-            ],
-        )?;
-        info!("Paste this into your browser to auth this app: \n{}", url);
+    fn creds_are_loaded(&self) -> bool {
+        self.app_client_id.is_some() && self.user_auth.is_set()
+    }
 
-        // Step 2: User must input code/state into this CLI
-        let spotify_auth_code = match Self::read_spotify_code() {
-            None => bail!("Could not get user input"),
-            Some(c) => c,
-        };
-        info!("Parsed auth code: {}", spotify_auth_code);
+    fn access_token(&self) -> String {
+        self.user_auth.access_token().unwrap()
+    }
 
-        // Step 3: Ask spotify for an access token using the code
-        let response = self
-            .http_client
-            .post(SPOTIFY_TOKENS_URL)
-            .header(CONTENT_TYPE, CONTENT_TYPE_URL_ENCODED)
-            .form(&[
-                ("grant_type", "authorization_code"),
-                ("code", &spotify_auth_code),
-                ("client_id", &client_id),
-                ("code_verifier", &String::from_utf8(code_verifier)?),
-                ("redirect_uri", REDIRECT_URI),
-            ])
-            .send()
-            .await;
-        debug!("Full Response from Spotify: {:?}", response);
+    /// The tokens this client uses. Hand a clone to another client with
+    /// [SpotifyClient::share_auth] so they refresh them once between them.
+    pub fn shared_auth(&self) -> SharedAuth {
+        self.user_auth.clone()
+    }
 
-        self.update_user_auth(response?).await
+    pub fn share_auth(&mut self, auth: SharedAuth) {
+        self.user_auth = auth;
     }
 
-    #[cfg(feature = "blocking")]
-    pub fn get_currently_playing_track(&mut self) -> Result<Option<CurrentlyPlayingTrack>> {
-        if !self.creds_are_loaded() {
-            bail!("Creds are misconfigured, cannot execute API");
-        }
-        let _ = self.refresh_access_token()?;
+    /// Enables writing raw response bodies to disk, see [CaptureConfig].
+    pub fn set_capture_config(&mut self, capture: CaptureConfig) {
+        self.capture = Some(capture);
+    }
 
-        let access_token = self.access_token();
-        let api_url = format!("{SPOTIFY_API_URL}{CUR_PLAYING_API_PATH}");
-        let request = self.http_client.get(api_url).bearer_auth(access_token);
-        debug!("Full request to Spotify: {:?}", request);
-        let response = request.send();
-        debug!("Full Response from Spotify: {:?}", response);
-        if let Err(e) = response {
-            bail!("Problem calling Spotify API: {e}");
-        }
-        let payload = response?;
-        let status = payload.status();
-        debug!("API Response status <{}>", status);
-        if !status.is_success() {
-            warn!("Spotify response status was not success <{}>", status);
+    /// Parses a response body, capturing it to disk when it fails to parse
+    /// or when captures were requested for this endpoint.
+    fn parse_response<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        response: &ApiResponse,
+    ) -> Result<T> {
+        let parsed = serde_json::from_str::<T>(&response.body);
+        if let Some(capture) = &self.capture {
+            let parse_error = parsed.as_ref().err().map(|e| e.to_string());
+            if parse_error.is_some() || capture.captures_successes_for(endpoint) {
+                let captured = capture.capture(
+                    endpoint,
+                    &response.url,
+                    response.status.as_u16(),
+                    &response.body,
+                    parse_error.as_deref(),
+                );
+                if let Err(e) = captured {
+                    warn!("Failed to capture {endpoint} response: {e}");
+                }
+            }
         }
-        if StatusCode::NO_CONTENT == status {
-            // Nothing is playing right now
-            return Ok(None);
+
+        match parsed {
+            Err(e) => bail!("Could not parse {endpoint} response: {e}"),
+            Ok(data) => Ok(data),
         }
-        match payload.json::<CurrentlyPlayingTrack>() {
-            Err(_) => {
-                bail!("Could not parse response into a CurrentlyPlayingTrack");
+    }
+
+    /// Parses a token response and stores the new tokens. `refreshed` is the
+    /// refresh token the request used, kept when the response has none.
+    #[cfg(feature = "blocking")]
+    fn parse_user_auth(
+        &self,
+        response: ApiResponse,
+        refreshed: Option<&str>,
+    ) -> Result<UserAuthData> {
+        let user_auth_data = self.parse_token_response(&response, refreshed)?;
+        if let Some(storage) = &self.creds_storage {
+            if let Err(e) =
+                storage.store_user_auth_data(&user_auth_data, &self.user_meta, &self.user_id)
+            {
+                error!("The new tokens were not fully persisted: {e}");
             }
-            Ok(data) => return Ok(Some(data)),
         }
+        Ok(user_auth_data)
     }
 
     #[cfg(not(feature = "blocking"))]
-    pub async fn get_currently_playing_track(&mut self) -> Result<Option<CurrentlyPlayingTrack>> {
-        if !self.creds_are_loaded() {
-            bail!("Creds are misconfigured, cannot execute API");
+    async fn parse_user_auth(
+        &self,
+        response: ApiResponse,
+        refreshed: Option<&str>,
+    ) -> Result<UserAuthData> {
+        let user_auth_data = self.parse_token_response(&response, refreshed)?;
+        if let Some(storage) = &self.creds_storage {
+            if let Err(e) = storage
+                .store_user_auth_data(&user_auth_data, &self.user_meta, &self.user_id)
+                .await
+            {
+                error!("The new tokens were not fully persisted: {e}");
+            }
         }
-        let _ = self.refresh_access_token().await?;
+        Ok(user_auth_data)
+    }
 
-        let access_token = self.access_token();
-        let api_url = format!("{SPOTIFY_API_URL}{CUR_PLAYING_API_PATH}");
-        let request = self.http_client.get(api_url).bearer_auth(access_token);
-        debug!("Full request to Spotify: {:?}", request);
-        let response = request.send().await;
-        debug!("Full Response from Spotify: {:?}", response);
-        if let Err(e) = response {
-            bail!("Problem calling Spotify API: {e}");
-        }
-        let payload = response?;
-        let status = payload.status();
-        debug!("API Response status <{}>", status);
-        if !status.is_success() {
-            warn!("Spotify response status was not success <{}>", status);
+    fn parse_token_response(
+        &self,
+        response: &ApiResponse,
+        refreshed: Option<&str>,
+    ) -> Result<UserAuthData> {
+        let mut user_auth_data: UserAuthData = self.parse_response(TOKEN_ENDPOINT, response)?;
+        if user_auth_data.refresh_token.is_empty() {
+            match refreshed {
+                Some(token) => user_auth_data.refresh_token = token.to_string(),
+                None => warn!("Spotify sent no refresh token, the access token can't be renewed"),
+            }
         }
-        if StatusCode::NO_CONTENT == status {
-            // Nothing is playing right now
-            return Ok(None);
+        user_auth_data.last_refresh = Some(SystemTime::now());
+        Ok(user_auth_data)
+    }
+
+    /// A request to the token endpoint. A confidential app authenticates
+    /// with its secret, a public one names its client id in the form.
+    fn token_request(&self, form: &[(&str, &str)]) -> RequestBuilder {
+        let app_client_id = self
+            .app_client_id
+            .as_deref()
+            .expect("Missing app_client_id data");
+        let request = self
+            .http_client
+            .post(&self.endpoints.tokens_url)
+            .header(CONTENT_TYPE, CONTENT_TYPE_URL_ENCODED);
+        let request = self.with_correlation_header(request);
+        match &self.app_client_secret {
+            Some(secret) => request.basic_auth(app_client_id, Some(secret)).form(form),
+            None => request.form(&[form, &[("client_id", app_client_id)]].concat()),
         }
-        match payload.json::<CurrentlyPlayingTrack>().await {
-            Err(_) => {
-                bail!("Could not parse response into a CurrentlyPlayingTrack");
-            }
-            Ok(data) => return Ok(Some(data)),
+    }
+
+    fn token_refresh_request(&self, auth: &UserAuthData) -> RequestBuilder {
+        self.token_request(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &auth.refresh_token),
+        ])
+    }
+
+    /// Asks for a client credentials token, see [SpotifyClient::app_only].
+    #[cfg(feature = "blocking")]
+    fn request_app_token(&self) -> Result<UserAuthData> {
+        info!("Requesting an app-only access token");
+        let request = self.token_request(&[("grant_type", "client_credentials")]);
+        let response = send_request(request, self.log_bodies)
+            .context("Problem interacting with Spotify API trying to get an app token")?;
+        self.parse_app_token(&response)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn request_app_token(&self) -> Result<UserAuthData> {
+        info!("Requesting an app-only access token");
+        let request = self.token_request(&[("grant_type", "client_credentials")]);
+        let response = send_request(request, self.log_bodies)
+            .await
+            .context("Problem interacting with Spotify API trying to get an app token")?;
+        self.parse_app_token(&response)
+    }
+
+    /// App tokens come without a scope or a refresh token, they aren't stored.
+    fn parse_app_token(&self, response: &ApiResponse) -> Result<UserAuthData> {
+        if !response.status.is_success() {
+            bail!(
+                "Spotify refused the app's client credentials <{}>: {}",
+                response.status,
+                response.body
+            );
         }
+        let token: AppToken = self.parse_response(TOKEN_ENDPOINT, response)?;
+        Ok(UserAuthData {
+            access_token: token.access_token,
+            token_type: token.token_type,
+            scope: String::new(),
+            expires_in: token.expires_in,
+            refresh_token: String::new(),
+            last_refresh: Some(SystemTime::now()),
+        })
     }
-}
 
-fn get_code_from_query_pairs(url: Url) -> Option<String> {
-    let mut qpairs = url.query_pairs();
-    while let Some((k, v)) = qpairs.next() {
-        if k.eq("error") {
-            let issue = v;
-            error!("Auth process encountered an issue {}", issue);
-            return None;
+    /// Exchanges the refresh token for new tokens. Only reads the client,
+    /// the caller decides where the result goes.
+    #[cfg(feature = "blocking")]
+    fn request_token_refresh(&self, auth: UserAuthData) -> Result<UserAuthData> {
+        if self.app_only {
+            return self.request_app_token();
         }
-        if k.eq("code") {
-            debug!("Successfully found code in url");
-            return Some(String::from(v));
+        info!("Refreshing API access token");
+        let span = self.request_span(&Method::POST, TOKENS_PATH);
+        let response = span
+            .in_scope(|| send_request(self.token_refresh_request(&auth), self.log_bodies))
+            .context("Problem interacting with Spotify API trying to refresh token")?;
+        check_refresh_status(&response)?;
+        self.parse_user_auth(response, Some(&auth.refresh_token))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn request_token_refresh(&self, auth: UserAuthData) -> Result<UserAuthData> {
+        if self.app_only {
+            return self.request_app_token().await;
         }
+        info!("Refreshing API access token");
+        let span = self.request_span(&Method::POST, TOKENS_PATH);
+        let response = send_request(self.token_refresh_request(&auth), self.log_bodies)
+            .instrument(span)
+            .await
+            .context("Problem interacting with Spotify API trying to refresh token")?;
+        check_refresh_status(&response)?;
+        self.parse_user_auth(response, Some(&auth.refresh_token))
+            .await
     }
 
-    debug!("Did not find code or error in parsed url");
-    None
-}
+    #[cfg(feature = "blocking")]
+    fn refresh_access_token(&mut self) -> Result<()> {
+        let margin = self.refresh_margin;
+        let shared = self.user_auth.clone();
+        shared.refresh_if(
+            |auth| auth.token_needs_refresh(margin),
+            |auth| self.request_token_refresh(auth),
+        )?;
+        Ok(())
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Refreshes the access token after Spotify rejected it. Skipped when
+    /// another client sharing the tokens already replaced the rejected one.
+    #[cfg(feature = "blocking")]
+    fn force_refresh_access_token(&mut self) -> Result<()> {
+        let rejected = self.access_token();
+        let shared = self.user_auth.clone();
+        shared.refresh_if(
+            |auth| auth.access_token == rejected,
+            |auth| self.request_token_refresh(auth),
+        )?;
+        Ok(())
+    }
 
+    /// Checks if access token has expired or is about to expire within the refresh margin.
+    /// If so, an attempt is made to refresh the token and store the new values.
+    ///
+    /// On Error: access token failed to refresh, there was an issue interacting with Spotify's API
+    #[cfg(not(feature = "blocking"))]
+    async fn refresh_access_token(&mut self) -> Result<()> {
+        let margin = self.refresh_margin;
+        let shared = self.user_auth.clone();
+        shared
+            .refresh_if(
+                |auth| auth.token_needs_refresh(margin),
+                |auth| self.request_token_refresh(auth),
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn force_refresh_access_token(&mut self) -> Result<()> {
+        let rejected = self.access_token();
+        let shared = self.user_auth.clone();
+        shared
+            .refresh_if(
+                |auth| auth.access_token == rejected,
+                |auth| self.request_token_refresh(auth),
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn read_redirect_url() -> Result<String> {
+        let mut in_buffer = String::new();
+        info!("Paste full redirected URL:\n");
+        io::stdin().read_line(&mut in_buffer)?;
+        Ok(in_buffer)
+    }
+
+    /// Asks for the redirected URL until one with a code is pasted. Asking
+    /// again after the user declined won't change their mind, so that stops.
+    fn read_authorized_redirect_url(&self) -> Result<String> {
+        for _ in 0..REDIRECT_ATTEMPTS {
+            let redirect_url = Self::read_redirect_url()?;
+            match parse_redirect(&redirect_url, &self.redirect_uri) {
+                RedirectResult::Code { .. } => return Ok(redirect_url),
+                RedirectResult::Denied {
+                    reason,
+                    description,
+                } => {
+                    return Err(AuthorizationDenied {
+                        reason,
+                        description,
+                    }
+                    .into())
+                }
+                RedirectResult::Invalid => warn!(
+                    "That is not the URL your browser was sent to, it starts with {} and has a code",
+                    self.redirect_uri
+                ),
+            }
+        }
+        bail!("No redirected URL with an auth code after {REDIRECT_ATTEMPTS} attempts")
+    }
+
+    /// Starts an authorization, returning the URL the user has to open.
+    /// The PKCE verifier is kept until [SpotifyClient::complete_authorization_from_url].
+    pub fn begin_authorization(&mut self) -> Result<String> {
+        let Some(client_id) = self.app_client_id.clone() else {
+            bail!("No app client id available, cannot authorize with Spotify");
+        };
+
+        // Authorizing again must not take away what was granted before
+        let granted = self
+            .user_auth
+            .snapshot()
+            .map(|auth| auth.scope)
+            .unwrap_or_default();
+        let wanted = self.wanted_scopes.join(" ");
+        let scope = merge_scopes(&[&self.scope, &granted, &wanted]);
+        let mut url = Url::parse_with_params(
+            &self.endpoints.auth_url,
+            &[
+                ("response_type", "code"),
+                ("client_id", &client_id),
+                ("scope", &scope),
+                ("redirect_uri", &self.redirect_uri),
+            ],
+        )?;
+        // A confidential app proves itself with its secret when exchanging the code
+        if self.app_client_secret.is_none() {
+            let code_verifier = pkce::generate_code_verifier();
+            url.query_pairs_mut()
+                .append_pair("code_challenge_method", CHALLENGE_METHOD)
+                .append_pair("code_challenge", &pkce::encode_s256(&code_verifier));
+            self.pending_code_verifier = Some(String::from_utf8(code_verifier)?);
+        }
+        if self.force_consent {
+            url.query_pairs_mut().append_pair("show_dialog", "true");
+        }
+        Ok(url.to_string())
+    }
+
+    /// A snapshot of what is in effect, for `config`. Tokens and the client
+    /// secret are masked with [redact::mask_secret].
+    pub fn effective_config(&self) -> EffectiveConfig {
+        let auth = self.user_auth.snapshot();
+        let words = |scope: &str| scope.split_whitespace().map(String::from).collect();
+        EffectiveConfig {
+            user_id: self.user_id.clone(),
+            client_id: self.app_client_id.clone(),
+            client_secret: self.app_client_secret.as_deref().map(redact::mask_secret),
+            auth_url: self.endpoints.auth_url.clone(),
+            tokens_url: self.endpoints.tokens_url.clone(),
+            api_url: self.endpoints.api_url.clone(),
+            redirect_uri: self.redirect_uri.clone(),
+            market: MARKET.to_string(),
+            cred_store: match self.creds_storage {
+                Some(_) => "bitwarden",
+                None => "in-memory",
+            }
+            .to_string(),
+            local_dir: self
+                .creds_storage
+                .as_ref()
+                .map(|storage| storage.local_dir().to_path_buf()),
+            store_access_token: self
+                .creds_storage
+                .as_ref()
+                .is_some_and(CredStorage::stores_access_token),
+            capture_dir: self.capture.as_ref().map(|capture| capture.dir.clone()),
+            refresh_margin_secs: self.refresh_margin.as_secs(),
+            min_refetch_interval_ms: self.min_refetch_interval.as_millis() as u64,
+            interactive: self.interactive,
+            force_consent: self.force_consent,
+            log_bodies: self.log_bodies,
+            opens_browser: self.url_opener.is_some(),
+            preferred_device: self.preferred_device().map(String::from),
+            requested_scopes: words(&self.scope),
+            wanted_scopes: self.wanted_scopes.clone(),
+            granted_scopes: auth
+                .as_ref()
+                .map_or_else(Vec::new, |auth| words(&auth.scope)),
+            access_token: auth
+                .as_ref()
+                .map(|auth| redact::mask_secret(&auth.access_token)),
+            refresh_token: auth
+                .as_ref()
+                .map(|auth| redact::mask_secret(&auth.refresh_token)),
+        }
+    }
+
+    /// Opens the page of [SpotifyClient::begin_authorization] in a browser.
+    /// Returns false when there is no browser to open or it failed to, the
+    /// user then has to open `url` themselves.
+    pub fn open_authorization_url(&self, url: &str) -> bool {
+        let Some(opener) = &self.url_opener else {
+            return false;
+        };
+        match opener.open(url) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Could not open a browser: {e}");
+                false
+            }
+        }
+    }
+
+    /// Asks for `scopes` too on the next authorization, on top of the ones
+    /// the client was built with and the ones already granted. A scope
+    /// already wanted isn't added again.
+    pub fn add_wanted_scopes(&mut self, scopes: &[String]) {
+        for scope in scopes {
+            if !self.wanted_scopes.contains(scope) {
+                self.wanted_scopes.push(scope.clone());
+            }
+        }
+    }
+
+    /// The scopes of `capabilities` the user hasn't granted. Empty without a
+    /// user's creds to tell by.
+    pub fn missing_scopes(&self, capabilities: &[Capability]) -> Vec<String> {
+        match self.user_auth.snapshot() {
+            Some(auth) if !self.app_only => scope_diff(&auth.scope, &scope_for(capabilities)),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The verifier of the authorization in progress, for finishing it from
+    /// another process with [SpotifyClient::resume_authorization].
+    pub fn pending_code_verifier(&self) -> Option<&str> {
+        self.pending_code_verifier.as_deref()
+    }
+
+    pub fn resume_authorization(&mut self, code_verifier: String) {
+        self.pending_code_verifier = Some(code_verifier);
+    }
+
+    fn code_exchange_request(&mut self, redirect_url: &str) -> Result<RequestBuilder> {
+        if self.app_client_id.is_none() {
+            bail!("No app client id available, cannot authorize with Spotify");
+        }
+        let code = code_from_redirect_url(redirect_url, &self.redirect_uri)?;
+        let code_verifier = self.pending_code_verifier.take();
+        debug!("Parsed auth code from the redirect URL");
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", &self.redirect_uri),
+        ];
+        if self.app_client_secret.is_none() {
+            let Some(code_verifier) = &code_verifier else {
+                bail!("No authorization in progress, start one before completing it");
+            };
+            form.push(("code_verifier", code_verifier));
+        }
+        Ok(self.token_request(&form))
+    }
+
+    /// Finishes the authorization using the URL Spotify redirected the
+    /// browser to, exchanging its code for tokens. Nothing is read from stdin.
+    #[cfg(feature = "blocking")]
+    pub fn complete_authorization_from_url(&mut self, redirect_url: &str) -> Result<()> {
+        let request = self.code_exchange_request(redirect_url)?;
+        let auth = self.parse_user_auth(send_request(request, self.log_bodies)?, None)?;
+        self.user_auth.set(Some(auth));
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn complete_authorization_from_url(&mut self, redirect_url: &str) -> Result<()> {
+        let request = self.code_exchange_request(redirect_url)?;
+        let auth = self
+            .parse_user_auth(send_request(request, self.log_bodies).await?, None)
+            .await?;
+        self.user_auth.set(Some(auth));
+        Ok(())
+    }
+
+    /// Loads whatever credentials the storage has, without starting an authorization.
+    #[cfg(feature = "blocking")]
+    pub fn load_creds(&mut self) -> Result<()> {
+        if let Some(storage) = &self.creds_storage {
+            let app = storage.load_app_auth_data()?;
+            self.app_client_id = Some(app.client_id);
+            self.app_client_secret = app.client_secret;
+            // Stored tokens can be older than the cached ones, a
+            // refresh by another client may not have been stored yet
+            if !(self.cached_tokens && self.user_auth.is_set()) {
+                self.user_auth
+                    .set(storage.load_user_auth_data(&self.user_id, self.refresh_margin));
+            }
+            self.user_meta = storage.load_user_meta(&self.user_id);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn load_creds(&mut self) -> Result<()> {
+        if let Some(storage) = &self.creds_storage {
+            let app = storage.load_app_auth_data().await?;
+            self.app_client_id = Some(app.client_id);
+            self.app_client_secret = app.client_secret;
+            // Stored tokens can be older than the cached ones, a
+            // refresh by another client may not have been stored yet
+            if !(self.cached_tokens && self.user_auth.is_set()) {
+                let stored = storage
+                    .load_user_auth_data(&self.user_id, self.refresh_margin)
+                    .await;
+                self.user_auth.set(stored);
+            }
+            self.user_meta = storage.load_user_meta(&self.user_id).await;
+        }
+        Ok(())
+    }
+
+    /// Switches to app-only auth with the client credentials flow, for the
+    /// catalog and browse endpoints that don't act for a user. Needs the app's
+    /// client secret. There is no refresh token, a new token is requested when
+    /// this one expires. Endpoints of a user fail with [SpotifyError::UserAuthRequired].
+    #[cfg(feature = "blocking")]
+    pub fn app_only(&mut self) -> Result<()> {
+        if self.app_client_id.is_none() {
+            if let Some(storage) = &self.creds_storage {
+                let app = storage.load_app_auth_data()?;
+                self.app_client_id = Some(app.client_id);
+                self.app_client_secret = app.client_secret;
+            }
+        }
+        self.check_app_credentials()?;
+        let token = self.request_app_token()?;
+        self.start_app_only(token);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn app_only(&mut self) -> Result<()> {
+        if self.app_client_id.is_none() {
+            if let Some(storage) = &self.creds_storage {
+                let app = storage.load_app_auth_data().await?;
+                self.app_client_id = Some(app.client_id);
+                self.app_client_secret = app.client_secret;
+            }
+        }
+        self.check_app_credentials()?;
+        let token = self.request_app_token().await?;
+        self.start_app_only(token);
+        Ok(())
+    }
+
+    fn check_app_credentials(&self) -> Result<()> {
+        if self.app_client_id.is_none() || self.app_client_secret.is_none() {
+            bail!("App-only auth needs the app's client id and client secret");
+        }
+        Ok(())
+    }
+
+    /// Other clients sharing the user's tokens keep them.
+    fn start_app_only(&mut self, token: UserAuthData) {
+        self.app_only = true;
+        self.user_auth = SharedAuth::new(Some(token));
+    }
+
+    pub fn is_app_only(&self) -> bool {
+        self.app_only
+    }
+
+    /// Fails requests that act for a user when there is none, before they go out.
+    fn check_auth_mode(&self, method: &Method, path: &str) -> Result<()> {
+        if self.app_only && needs_user(method, path) {
+            return Err(SpotifyError::UserAuthRequired.into());
+        }
+        Ok(())
+    }
+
+    /// Checks the access token as it is with a cheap `/me` call, without
+    /// refreshing it first. Ok(false) when Spotify rejects the token.
+    #[cfg(feature = "blocking")]
+    fn access_token_is_valid(&mut self) -> Result<bool> {
+        if self.user_auth.access_token().is_none() {
+            return Err(SpotifyError::MissingCreds.into());
+        }
+        let payload = self.send_limited(Method::GET, ME_API_PATH, None)?;
+        token_validation_result(payload.status)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn access_token_is_valid(&mut self) -> Result<bool> {
+        if self.user_auth.access_token().is_none() {
+            return Err(SpotifyError::MissingCreds.into());
+        }
+        let payload = self.send_limited(Method::GET, ME_API_PATH, None).await?;
+        token_validation_result(payload.status)
+    }
+
+    /// Asks Spotify whether the access token works with one `/me` call,
+    /// instead of trusting its expiry, e.g. right after importing a token
+    /// from another tool. Unlike [SpotifyClient::refresh_access_token] it
+    /// changes nothing, an expired token is reported, not refreshed.
+    /// Ok(false) when Spotify rejects the token, other answers are errors.
+    /// Takes `&mut self` only because the answer's headers are kept for
+    /// [SpotifyClient::diagnostics] like those of every other call.
+    #[cfg(feature = "blocking")]
+    pub fn validate_token_online(&mut self) -> Result<bool> {
+        self.access_token_is_valid()
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn validate_token_online(&mut self) -> Result<bool> {
+        self.access_token_is_valid().await
+    }
+
+    /// Makes sure the loaded creds work, refreshing the token once if Spotify rejects it.
+    #[cfg(feature = "blocking")]
+    fn verify_creds(&mut self) -> Result<()> {
+        self.refresh_access_token()?;
+        if self.access_token_is_valid()? {
+            return Ok(());
+        }
+        warn!("Spotify rejected the access token, refreshing it");
+        self.force_refresh_access_token()?;
+        if !self.access_token_is_valid()? {
+            return Err(SpotifyError::TokenRejected.into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn verify_creds(&mut self) -> Result<()> {
+        self.refresh_access_token().await?;
+        if self.access_token_is_valid().await? {
+            return Ok(());
+        }
+        warn!("Spotify rejected the access token, refreshing it");
+        self.force_refresh_access_token().await?;
+        if !self.access_token_is_valid().await? {
+            return Err(SpotifyError::TokenRejected.into());
+        }
+        Ok(())
+    }
+
+    /// Loads the creds and checks them against Spotify. When there are none
+    /// or Spotify rejects them, the user is asked to authorize on stdin,
+    /// unless the client isn't interactive. Other failures are returned.
+    #[cfg(feature = "blocking")]
+    pub fn setup_creds(&mut self) -> Result<()> {
+        self.load_creds()?;
+
+        let failure = if self.creds_are_loaded() {
+            match self.verify_creds() {
+                Ok(()) => {
+                    info!("Spotify API creds are ready to go");
+                    return Ok(());
+                }
+                Err(e) if needs_authorization(&e) => {
+                    warn!("Cached Spotify creds don't work: {e}");
+                    e
+                }
+                // Authorizing again wouldn't help e.g. a network failure
+                Err(e) => return Err(e),
+            }
+        } else {
+            SpotifyError::MissingCreds.into()
+        };
+        if !self.interactive {
+            return Err(failure.context(
+                "No working Spotify creds and not allowed to ask, run `auth` to authorize",
+            ));
+        }
+
+        warn!("We need to generate auth tokens from Spotify, starting now");
+        self.authorize_on_stdin()
+    }
+
+    /// Makes sure the user granted the scopes of `capabilities`. Missing ones
+    /// are asked for on the next authorization, an interactive client starts
+    /// it right away. The old tokens stay in use until new ones are granted.
+    #[cfg(feature = "blocking")]
+    pub fn ensure_scopes(&mut self, capabilities: &[Capability]) -> Result<()> {
+        let missing = self.missing_scopes(capabilities);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        self.add_wanted_scopes(&missing);
+        let error = MissingScopes { missing };
+        if !self.interactive {
+            return Err(error.into());
+        }
+        warn!("{error}, authorize again to grant them, the others are kept");
+        self.authorize_on_stdin()
+    }
+
+    /// [SpotifyClient::ensure_scopes] for one operation, called before it
+    /// is sent so a minimal grant is widened only once the user needs more.
+    #[cfg(feature = "blocking")]
+    pub fn ensure_scope(&mut self, capability: Capability) -> Result<()> {
+        self.ensure_scopes(&[capability])
+    }
+
+    #[cfg(feature = "blocking")]
+    fn authorize_on_stdin(&mut self) -> Result<()> {
+        // Step 1: Auth with Spotify
+        let url = self.begin_authorization()?;
+        if self.open_authorization_url(&url) {
+            info!("Opened your browser to auth this app, or paste this into it: \n{url}");
+        } else {
+            info!("Paste this into your browser to auth this app: \n{}", url);
+        }
+
+        // Step 2: User must input the redirected URL into this CLI
+        let redirect_url = self.read_authorized_redirect_url()?;
+
+        // Step 3: Ask spotify for an access token using the code
+        self.complete_authorization_from_url(&redirect_url)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn setup_creds(&mut self) -> Result<()> {
+        self.load_creds().await?;
+
+        let failure = if self.creds_are_loaded() {
+            match self.verify_creds().await {
+                Ok(()) => {
+                    info!("Spotify API creds are ready to go");
+                    return Ok(());
+                }
+                Err(e) if needs_authorization(&e) => {
+                    warn!("Cached Spotify creds don't work: {e}");
+                    e
+                }
+                // Authorizing again wouldn't help e.g. a network failure
+                Err(e) => return Err(e),
+            }
+        } else {
+            SpotifyError::MissingCreds.into()
+        };
+        if !self.interactive {
+            return Err(failure.context(
+                "No working Spotify creds and not allowed to ask, run `auth` to authorize",
+            ));
+        }
+
+        error!("We need to generate auth tokens from Spotify, starting now");
+        self.authorize_on_stdin().await
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn ensure_scopes(&mut self, capabilities: &[Capability]) -> Result<()> {
+        let missing = self.missing_scopes(capabilities);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        self.add_wanted_scopes(&missing);
+        let error = MissingScopes { missing };
+        if !self.interactive {
+            return Err(error.into());
+        }
+        warn!("{error}, authorize again to grant them, the others are kept");
+        self.authorize_on_stdin().await
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn ensure_scope(&mut self, capability: Capability) -> Result<()> {
+        self.ensure_scopes(&[capability]).await
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn authorize_on_stdin(&mut self) -> Result<()> {
+        // Step 1: Auth with Spotify
+        let url = self.begin_authorization()?;
+        if self.open_authorization_url(&url) {
+            info!("Opened your browser to auth this app, or paste this into it: \n{url}");
+        } else {
+            info!("Paste this into your browser to auth this app: \n{}", url);
+        }
+
+        // Step 2: User must input the redirected URL into this CLI
+        let redirect_url = self.read_authorized_redirect_url()?;
+
+        // Step 3: Ask spotify for an access token using the code
+        self.complete_authorization_from_url(&redirect_url).await
+    }
+
+    /// On a 429 pauses every client sharing the rate limiter for as long as
+    /// Spotify asked. Returns whether the request should be sent again.
+    fn back_off_if_limited(&self, payload: &ApiResponse, rate_limited: &mut u32) -> bool {
+        if payload.status != StatusCode::TOO_MANY_REQUESTS {
+            return false;
+        }
+        if *rate_limited == MAX_RATE_LIMITED_RETRIES {
+            warn!("Still rate limited after {rate_limited} retries, giving up");
+            return false;
+        }
+        *rate_limited += 1;
+        let wait = payload.retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+        warn!("Rate limited by Spotify, pausing requests for {wait:?}");
+        self.rate_limit.pause_for(wait);
+        true
+    }
+
+    /// Keeps the headers of interest of a response for [SpotifyClient::diagnostics],
+    /// warning about a new deprecation notice or `Spotify-*` header.
+    fn record_headers(&mut self, payload: &ApiResponse) {
+        for (name, value) in &payload.headers {
+            if is_notice_header(name) && first_sighting(name, value) {
+                warn!("Spotify sent <{name}: {value}> on <{}>", payload.url);
+            } else {
+                debug!("Header of interest <{name}: {value}> on <{}>", payload.url);
+            }
+            self.diagnostics.insert(
+                name.clone(),
+                SeenHeader {
+                    value: value.clone(),
+                    url: payload.url.clone(),
+                    seen_at: SystemTime::now(),
+                },
+            );
+        }
+    }
+
+    /// Tags the requests of one operation with `id`, token refreshes
+    /// included, in the header set with
+    /// [SpotifyClientBuilder::with_correlation_id_header] and in the
+    /// `correlation_id` field of their span. The id is dropped along with
+    /// the returned client, e.g. at the end of the statement in
+    /// `client.with_correlation_id(id).get_album(album_id)`.
+    pub fn with_correlation_id(&mut self, id: &str) -> Correlated<'_> {
+        self.correlation_id = Some(id.to_string());
+        Correlated { client: self }
+    }
+
+    /// The span an API request is sent in.
+    fn request_span(&self, method: &Method, path: &str) -> Span {
+        info_span!(
+            "spotify_request",
+            %method,
+            path,
+            correlation_id = self.correlation_id.as_deref().map(tracing::field::display),
+        )
+    }
+
+    fn with_correlation_header(&self, request: RequestBuilder) -> RequestBuilder {
+        match (&self.correlation_id_header, &self.correlation_id) {
+            (Some(header), Some(id)) => request.header(header.as_str(), id.as_str()),
+            _ => request,
+        }
+    }
+
+    /// GETs an API path with the user's bearer token, refreshing it first if needed.
+    #[cfg(feature = "blocking")]
+    fn api_get(&mut self, path: &str) -> Result<ApiResponse> {
+        self.api_request(Method::GET, path)
+    }
+
+    /// Sends a request with the user's bearer token, refreshing it first if needed.
+    /// Anything but a GET goes out with an empty body, Spotify wants a length.
+    #[cfg(feature = "blocking")]
+    fn api_request(&mut self, method: Method, path: &str) -> Result<ApiResponse> {
+        self.api_send(method, path, None)
+    }
+
+    /// Like [SpotifyClient::api_request], sending `body` as JSON.
+    #[cfg(feature = "blocking")]
+    fn api_request_json(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<ApiResponse> {
+        self.api_send(method, path, Some(RequestBody::Json(body)))
+    }
+
+    #[cfg(feature = "blocking")]
+    fn api_send(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<RequestBody<'_>>,
+    ) -> Result<ApiResponse> {
+        if !self.creds_are_loaded() {
+            bail!("Creds are misconfigured, cannot execute API");
+        }
+        self.check_auth_mode(&method, path)?;
+        self.refresh_access_token()?;
+
+        let payload = self.send_limited(method, path, body)?;
+        if !payload.status.is_success() {
+            warn!(
+                "Spotify response status was not success <{}>",
+                payload.status
+            );
+        }
+
+        Ok(payload)
+    }
+
+    /// Sends a request with the access token as it is, once the rate
+    /// limiter lets it, and again after a 429.
+    #[cfg(feature = "blocking")]
+    fn send_limited(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<RequestBody<'_>>,
+    ) -> Result<ApiResponse> {
+        let api_url = self.endpoints.api(path);
+        let span = self.request_span(&method, path);
+        let mut rate_limited = 0;
+        loop {
+            self.rate_limit.wait();
+            let mut request = self
+                .http_client
+                .request(method.clone(), &api_url)
+                .bearer_auth(self.access_token());
+            match body {
+                Some(RequestBody::Json(body)) => request = request.json(body),
+                Some(RequestBody::Form(form)) => request = request.form(form),
+                Some(RequestBody::Jpeg(base64)) => {
+                    request = request
+                        .header(CONTENT_TYPE, CONTENT_TYPE_JPEG)
+                        .body(base64.to_string())
+                }
+                None if method != Method::GET => request = request.body(""),
+                None => {}
+            }
+            let request = self.with_correlation_header(request);
+            let payload = span.in_scope(|| send_request(request, self.log_bodies))?;
+            self.record_headers(&payload);
+            if !self.back_off_if_limited(&payload, &mut rate_limited) {
+                return Ok(payload);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn api_get(&mut self, path: &str) -> Result<ApiResponse> {
+        self.api_request(Method::GET, path).await
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn api_request(&mut self, method: Method, path: &str) -> Result<ApiResponse> {
+        self.api_send(method, path, None).await
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn api_request_json(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<ApiResponse> {
+        self.api_send(method, path, Some(RequestBody::Json(body)))
+            .await
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn api_send(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<RequestBody<'_>>,
+    ) -> Result<ApiResponse> {
+        if !self.creds_are_loaded() {
+            bail!("Creds are misconfigured, cannot execute API");
+        }
+        self.check_auth_mode(&method, path)?;
+        self.refresh_access_token().await?;
+
+        let payload = self.send_limited(method, path, body).await?;
+        if !payload.status.is_success() {
+            warn!(
+                "Spotify response status was not success <{}>",
+                payload.status
+            );
+        }
+
+        Ok(payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn send_limited(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<RequestBody<'_>>,
+    ) -> Result<ApiResponse> {
+        let api_url = self.endpoints.api(path);
+        let span = self.request_span(&method, path);
+        let mut rate_limited = 0;
+        loop {
+            self.rate_limit.wait().await;
+            let mut request = self
+                .http_client
+                .request(method.clone(), &api_url)
+                .bearer_auth(self.access_token());
+            match body {
+                Some(RequestBody::Json(body)) => request = request.json(body),
+                Some(RequestBody::Form(form)) => request = request.form(form),
+                Some(RequestBody::Jpeg(base64)) => {
+                    request = request
+                        .header(CONTENT_TYPE, CONTENT_TYPE_JPEG)
+                        .body(base64.to_string())
+                }
+                None if method != Method::GET => request = request.body(""),
+                None => {}
+            }
+            let request = self.with_correlation_header(request);
+            let payload = send_request(request, self.log_bodies)
+                .instrument(span.clone())
+                .await?;
+            self.record_headers(&payload);
+            if !self.back_off_if_limited(&payload, &mut rate_limited) {
+                return Ok(payload);
+            }
+        }
+    }
+
+    /// What is playing, None when nothing is. With a min refetch interval,
+    /// see [SpotifyClientBuilder::with_min_refetch_interval], a recent answer
+    /// is given again instead of asking Spotify.
+    #[cfg(feature = "blocking")]
+    pub fn get_currently_playing_track(&mut self) -> Result<Option<CurrentlyPlayingTrack>> {
+        if let Some(cached) = self.fresh_playing(Instant::now()) {
+            return Ok(cached);
+        }
+        let payload = self.api_get(CUR_PLAYING_API_PATH)?;
+        let playing = self.parse_currently_playing(&payload)?;
+        Ok(self.cache_playing(playing))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_currently_playing_track(&mut self) -> Result<Option<CurrentlyPlayingTrack>> {
+        if let Some(cached) = self.fresh_playing(Instant::now()) {
+            return Ok(cached);
+        }
+        let payload = self.api_get(CUR_PLAYING_API_PATH).await?;
+        let playing = self.parse_currently_playing(&payload)?;
+        Ok(self.cache_playing(playing))
+    }
+
+    /// The lyrics of the track playing, from the provider set with
+    /// [SpotifyClientBuilder::with_lyrics_provider]. None when no track is
+    /// playing or the provider has no lyrics for it.
+    #[cfg(feature = "blocking")]
+    pub fn get_lyrics(&mut self) -> Result<Option<Lyrics>> {
+        let Some(track) = self
+            .get_currently_playing_track()?
+            .and_then(|playing| playing.get_track_data())
+        else {
+            return Ok(None);
+        };
+        self.lyrics_provider.fetch(&track)
+    }
+
+    /// Providers are blocking, e.g. [crate::lyrics::LrclibProvider] with
+    /// its blocking HTTP client, so the lookup runs on tokio's blocking pool.
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_lyrics(&mut self) -> Result<Option<Lyrics>> {
+        let Some(track) = self
+            .get_currently_playing_track()
+            .await?
+            .and_then(|playing| playing.get_track_data())
+        else {
+            return Ok(None);
+        };
+        let provider = self.lyrics_provider.clone();
+        tokio::task::spawn_blocking(move || provider.fetch(&track))
+            .await
+            .context("The lyrics lookup panicked")?
+    }
+
+    fn parse_currently_playing(
+        &mut self,
+        response: &ApiResponse,
+    ) -> Result<Option<CurrentlyPlayingTrack>> {
+        if StatusCode::NO_CONTENT == response.status {
+            // Nothing is playing right now
+            return Ok(None);
+        }
+        let playing: CurrentlyPlayingTrack = self.parse_response(CUR_PLAYING_ENDPOINT, response)?;
+        self.server_time_offset = Some(time_offset(playing.timestamp, SystemTime::now()));
+        Ok(Some(playing))
+    }
+
+    /// The cached answer when it is younger than the min refetch interval.
+    fn fresh_playing(&self, now: Instant) -> Option<Option<CurrentlyPlayingTrack>> {
+        self.playing_cache
+            .as_ref()
+            .filter(|cached| now.duration_since(cached.fetched_at) < self.min_refetch_interval)
+            .map(|cached| cached.playing.clone())
+    }
+
+    fn cache_playing(
+        &mut self,
+        playing: Option<CurrentlyPlayingTrack>,
+    ) -> Option<CurrentlyPlayingTrack> {
+        if !self.min_refetch_interval.is_zero() {
+            self.playing_cache = Some(CachedPlaying {
+                playing: playing.clone(),
+                fetched_at: Instant::now(),
+            });
+        }
+        playing
+    }
+
+    /// How long ago Spotify was last asked what is playing, None before the
+    /// first answer or without a min refetch interval. Answers from the
+    /// cache are this old.
+    pub fn currently_playing_age(&self) -> Option<Duration> {
+        self.playing_cache
+            .as_ref()
+            .map(|cached| cached.fetched_at.elapsed())
+    }
+
+    /// Playback state including the active device.
+    /// Returns None when Spotify answers 204, there is no active device.
+    #[cfg(feature = "blocking")]
+    pub fn get_playback_state(&mut self) -> Result<Option<PlaybackState>> {
+        let payload = self.api_get(PLAYER_API_PATH)?;
+        if StatusCode::NO_CONTENT == payload.status {
+            return Ok(None);
+        }
+        self.parse_response(PLAYER_ENDPOINT, &payload).map(Some)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_playback_state(&mut self) -> Result<Option<PlaybackState>> {
+        let payload = self.api_get(PLAYER_API_PATH).await?;
+        if StatusCode::NO_CONTENT == payload.status {
+            return Ok(None);
+        }
+        self.parse_response(PLAYER_ENDPOINT, &payload).map(Some)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn get_devices(&mut self) -> Result<Vec<Device>> {
+        let payload = self.api_get(DEVICES_API_PATH)?;
+        let devices: Devices = self.parse_response(DEVICES_ENDPOINT, &payload)?;
+        Ok(devices.devices)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_devices(&mut self) -> Result<Vec<Device>> {
+        let payload = self.api_get(DEVICES_API_PATH).await?;
+        let devices: Devices = self.parse_response(DEVICES_ENDPOINT, &payload)?;
+        Ok(devices.devices)
+    }
+
+    /// Playback commands go to this device from now on, and it is remembered
+    /// for the user. Spotify wakes it up when nothing is active.
+    /// Err when it couldn't be remembered, it is still used for this run.
+    #[cfg(feature = "blocking")]
+    pub fn set_preferred_device(&mut self, device_id: String) -> Result<()> {
+        self.user_meta.preferred_device = Some(device_id);
+        match &self.creds_storage {
+            Some(storage) => storage.store_user_meta(&self.user_meta, &self.user_id),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn set_preferred_device(&mut self, device_id: String) -> Result<()> {
+        self.user_meta.preferred_device = Some(device_id);
+        match &self.creds_storage {
+            Some(storage) => {
+                storage
+                    .store_user_meta(&self.user_meta, &self.user_id)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn preferred_device(&self) -> Option<&str> {
+        self.user_meta.preferred_device.as_deref()
+    }
+
+    /// The cached profile when it is younger than [PROFILE_CACHE_TTL] and
+    /// was fetched with the scopes of the current token.
+    fn cached_profile(&self, now: SystemTime) -> Option<UserProfile> {
+        let scope = self.user_auth.snapshot()?.scope;
+        self.user_meta
+            .profile
+            .as_ref()
+            .filter(|cached| cached.is_fresh(now, PROFILE_CACHE_TTL, &scope))
+            .map(|cached| cached.profile.clone())
+    }
+
+    fn profile_to_cache(&self, profile: UserProfile) -> CachedProfile {
+        CachedProfile {
+            profile,
+            fetched_at: SystemTime::now(),
+            scope: self
+                .user_auth
+                .snapshot()
+                .map(|auth| auth.scope)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The user's profile. It is cached with the user's settings for
+    /// [PROFILE_CACHE_TTL], or until the token's scopes change. `email` is
+    /// only set when the user granted [EMAIL_SCOPE].
+    #[cfg(feature = "blocking")]
+    pub fn get_user_profile(&mut self) -> Result<UserProfile> {
+        if let Some(profile) = self.cached_profile(SystemTime::now()) {
+            debug!("Using the cached profile of {}", profile.id);
+            return Ok(profile);
+        }
+        let payload = self.api_get(ME_API_PATH)?;
+        let profile: UserProfile = self.parse_response(ME_ENDPOINT, &payload)?;
+        self.user_meta.profile = Some(self.profile_to_cache(profile.clone()));
+        if let Some(storage) = &self.creds_storage {
+            if let Err(e) = storage.store_user_meta(&self.user_meta, &self.user_id) {
+                warn!("Could not cache the profile: {e:#}");
+            }
+        }
+        Ok(profile)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_user_profile(&mut self) -> Result<UserProfile> {
+        if let Some(profile) = self.cached_profile(SystemTime::now()) {
+            debug!("Using the cached profile of {}", profile.id);
+            return Ok(profile);
+        }
+        let payload = self.api_get(ME_API_PATH).await?;
+        let profile: UserProfile = self.parse_response(ME_ENDPOINT, &payload)?;
+        self.user_meta.profile = Some(self.profile_to_cache(profile.clone()));
+        if let Some(storage) = &self.creds_storage {
+            if let Err(e) = storage
+                .store_user_meta(&self.user_meta, &self.user_id)
+                .await
+            {
+                warn!("Could not cache the profile: {e:#}");
+            }
+        }
+        Ok(profile)
+    }
+
+    /// The id Spotify knows the authorized user by, from their profile.
+    #[cfg(feature = "blocking")]
+    pub fn detect_user_id(&mut self) -> Result<String> {
+        Ok(self.get_user_profile()?.id)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn detect_user_id(&mut self) -> Result<String> {
+        Ok(self.get_user_profile().await?.id)
+    }
+
+    /// Switches the client to `user_id`, moving the stored secrets over from
+    /// the id it was built with, e.g. a placeholder used before the real id
+    /// was known. See [SpotifyClient::detect_user_id].
+    #[cfg(feature = "blocking")]
+    pub fn rekey_user(&mut self, user_id: String) -> Result<()> {
+        if let Some(storage) = &self.creds_storage {
+            storage.rekey_user(&self.user_id, &user_id)?;
+        }
+        self.user_id = user_id;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn rekey_user(&mut self, user_id: String) -> Result<()> {
+        if let Some(storage) = &self.creds_storage {
+            storage.rekey_user(&self.user_id, &user_id).await?;
+        }
+        self.user_id = user_id;
+        Ok(())
+    }
+
+    /// When the access token is due to be refreshed, its expiry less the
+    /// refresh margin, for a scheduler to sleep until instead of polling.
+    /// None without creds or when it isn't known when they were refreshed.
+    pub fn next_refresh_due(&self) -> Option<SystemTime> {
+        self.user_auth.snapshot()?.refresh_due(self.refresh_margin)
+    }
+
+    /// The user id the secrets are stored under.
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Where the creds are kept, None for in-memory creds.
+    pub fn creds_storage(&self) -> Option<&CredStorage> {
+        self.creds_storage.as_ref()
+    }
+
+    /// How far the local clock was past the `timestamp` of the last currently
+    /// playing answer when it arrived, clock drift plus latency. Add it to
+    /// `progress_ms` and the time since the fetch to extrapolate the position
+    /// between polls. None before the first fetch with something playing.
+    pub fn server_time_offset(&self) -> Option<Duration> {
+        self.server_time_offset
+    }
+
+    /// The last value of every deprecation notice, `Spotify-*` and rate limit
+    /// header Spotify sent this client, by lowercase header name.
+    pub fn diagnostics(&self) -> &BTreeMap<String, SeenHeader> {
+        &self.diagnostics
+    }
+
+    /// Sends a playback command, aimed at the preferred device when there is one.
+    #[cfg(feature = "blocking")]
+    fn player_command(&mut self, method: Method, path: &str) -> Result<()> {
+        self.ensure_scope(Capability::ControlPlayback)?;
+        let path = with_device_id(path, self.preferred_device());
+        let payload = self.api_request(method, &path)?;
+        check_player_command(&path, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn player_command(&mut self, method: Method, path: &str) -> Result<()> {
+        self.ensure_scope(Capability::ControlPlayback).await?;
+        let path = with_device_id(path, self.preferred_device());
+        let payload = self.api_request(method, &path).await?;
+        check_player_command(&path, &payload)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn pause_playback(&mut self) -> Result<()> {
+        self.player_command(Method::PUT, PAUSE_API_PATH)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn pause_playback(&mut self) -> Result<()> {
+        self.player_command(Method::PUT, PAUSE_API_PATH).await
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn resume_playback(&mut self) -> Result<()> {
+        self.player_command(Method::PUT, PLAY_API_PATH)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn resume_playback(&mut self) -> Result<()> {
+        self.player_command(Method::PUT, PLAY_API_PATH).await
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn skip_to_next(&mut self) -> Result<()> {
+        self.player_command(Method::POST, NEXT_API_PATH)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn skip_to_next(&mut self) -> Result<()> {
+        self.player_command(Method::POST, NEXT_API_PATH).await
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn skip_to_previous(&mut self) -> Result<()> {
+        self.player_command(Method::POST, PREVIOUS_API_PATH)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn skip_to_previous(&mut self) -> Result<()> {
+        self.player_command(Method::POST, PREVIOUS_API_PATH).await
+    }
+
+    /// Moves playback to the device, starting it when `play` is true.
+    /// The device may take a moment to become active after Spotify accepted it.
+    #[cfg(feature = "blocking")]
+    pub fn transfer_playback(&mut self, device_id: &str, play: bool) -> Result<()> {
+        self.ensure_scope(Capability::ControlPlayback)?;
+        let body = serde_json::json!({ "device_ids": [device_id], "play": play });
+        let payload = self.api_request_json(Method::PUT, TRANSFER_API_PATH, &body)?;
+        check_player_command(TRANSFER_API_PATH, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn transfer_playback(&mut self, device_id: &str, play: bool) -> Result<()> {
+        self.ensure_scope(Capability::ControlPlayback).await?;
+        let body = serde_json::json!({ "device_ids": [device_id], "play": play });
+        let payload = self
+            .api_request_json(Method::PUT, TRANSFER_API_PATH, &body)
+            .await?;
+        check_player_command(TRANSFER_API_PATH, &payload)
+    }
+
+    /// Moves playback to the device without starting it, waits until the
+    /// player reports it active and then runs `action`, e.g. a resume. A
+    /// command sent right after a transfer may reach the old device or none.
+    ///
+    /// On Error: the device was not active after [DEVICE_ACTIVATION_POLLS]
+    /// checks, `action` is not run.
+    #[cfg(feature = "blocking")]
+    pub fn ensure_active_then<T>(
+        &mut self,
+        device_id: &str,
+        action: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.transfer_playback(device_id, false)?;
+        for poll in 1..=DEVICE_ACTIVATION_POLLS {
+            if is_active_device(self.get_playback_state()?.as_ref(), device_id) {
+                debug!("Device {device_id} is active after {poll} checks");
+                return action(self);
+            }
+            if poll < DEVICE_ACTIVATION_POLLS {
+                std::thread::sleep(DEVICE_ACTIVATION_INTERVAL);
+            }
+        }
+        bail!("Device {device_id} did not become active after the playback transfer")
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn ensure_active_then<T>(
+        &mut self,
+        device_id: &str,
+        action: impl AsyncFnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.transfer_playback(device_id, false).await?;
+        for poll in 1..=DEVICE_ACTIVATION_POLLS {
+            if is_active_device(self.get_playback_state().await?.as_ref(), device_id) {
+                debug!("Device {device_id} is active after {poll} checks");
+                return action(self).await;
+            }
+            if poll < DEVICE_ACTIVATION_POLLS {
+                tokio::time::sleep(DEVICE_ACTIVATION_INTERVAL).await;
+            }
+        }
+        bail!("Device {device_id} did not become active after the playback transfer")
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn get_queue(&mut self) -> Result<Queue> {
+        let payload = self.api_get(QUEUE_API_PATH)?;
+        self.parse_response(QUEUE_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_queue(&mut self) -> Result<Queue> {
+        let payload = self.api_get(QUEUE_API_PATH).await?;
+        self.parse_response(QUEUE_ENDPOINT, &payload)
+    }
+
+    /// Adds a track or episode to the end of the queue.
+    #[cfg(feature = "blocking")]
+    pub fn add_to_queue(&mut self, uri: &str) -> Result<()> {
+        self.player_command(Method::POST, &add_to_queue_path(uri))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn add_to_queue(&mut self, uri: &str) -> Result<()> {
+        self.player_command(Method::POST, &add_to_queue_path(uri))
+            .await
+    }
+
+    /// The first `limit` tracks of Spotify's catalog matching `query`, in
+    /// Spotify's order.
+    #[cfg(feature = "blocking")]
+    pub fn search_tracks(&mut self, query: &str, limit: u32) -> Result<Vec<Track>> {
+        let payload = self.api_get(&track_search_path(query, limit)?)?;
+        let search: TrackSearch = self.parse_response(SEARCH_ENDPOINT, &payload)?;
+        Ok(search.tracks.items)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn search_tracks(&mut self, query: &str, limit: u32) -> Result<Vec<Track>> {
+        let payload = self.api_get(&track_search_path(query, limit)?).await?;
+        let search: TrackSearch = self.parse_response(SEARCH_ENDPOINT, &payload)?;
+        Ok(search.tracks.items)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn get_audio_features(&mut self, track_id: &str) -> Result<AudioFeatures> {
+        let payload = self.api_get(&format!("{AUDIO_FEATURES_API_PATH}/{track_id}"))?;
+        self.parse_response(AUDIO_FEATURES_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_audio_features(&mut self, track_id: &str) -> Result<AudioFeatures> {
+        let payload = self
+            .api_get(&format!("{AUDIO_FEATURES_API_PATH}/{track_id}"))
+            .await?;
+        self.parse_response(AUDIO_FEATURES_ENDPOINT, &payload)
+    }
+
+    /// The sections of a track, e.g. its intro, verses and choruses. The
+    /// response is large and never changes, keep it in an
+    /// [crate::analysis_cache::AnalysisCache].
+    #[cfg(feature = "blocking")]
+    pub fn get_audio_analysis(&mut self, track_id: &str) -> Result<AudioAnalysis> {
+        let payload = self.api_get(&format!("{AUDIO_ANALYSIS_API_PATH}/{track_id}"))?;
+        self.parse_response(AUDIO_ANALYSIS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_audio_analysis(&mut self, track_id: &str) -> Result<AudioAnalysis> {
+        let payload = self
+            .api_get(&format!("{AUDIO_ANALYSIS_API_PATH}/{track_id}"))
+            .await?;
+        self.parse_response(AUDIO_ANALYSIS_ENDPOINT, &payload)
+    }
+
+    /// The album with its first page of tracks, e.g. to list the album of
+    /// the track playing.
+    #[cfg(feature = "blocking")]
+    pub fn get_album(&mut self, album_id: &str) -> Result<AlbumFull> {
+        let payload = self.api_get(&album_path(album_id)?)?;
+        check_found("album", album_id, &payload)?;
+        self.parse_response(ALBUM_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_album(&mut self, album_id: &str) -> Result<AlbumFull> {
+        let payload = self.api_get(&album_path(album_id)?).await?;
+        check_found("album", album_id, &payload)?;
+        self.parse_response(ALBUM_ENDPOINT, &payload)
+    }
+
+    /// The cover of the track's album in `size`, or the closest Spotify
+    /// has, downloaded once and then served from the art cache. Returns the
+    /// cover's path. See [SpotifyClientBuilder::with_art_cache_dir].
+    /// On Error: the album has no cover, or it could not be downloaded.
+    #[cfg(feature = "blocking")]
+    pub fn fetch_album_art(&self, track: &Track, size: ImageSize) -> Result<PathBuf> {
+        self.album_art_cache().fetch(track, size)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn fetch_album_art(&self, track: &Track, size: ImageSize) -> Result<PathBuf> {
+        self.album_art_cache().fetch(track, size).await
+    }
+
+    /// The art cache [SpotifyClient::fetch_album_art] uses, to fetch covers
+    /// away from the client, e.g. on another thread.
+    pub fn album_art_cache(&self) -> AlbumArtCache {
+        AlbumArtCache {
+            http_client: self.http_client.clone(),
+            dir: self.art_cache_dir.clone(),
+        }
+    }
+
+    /// The current track along with its audio features, for mood displays.
+    /// Returns None when nothing is playing or when an episode is playing,
+    /// episodes have no audio features.
+    #[cfg(feature = "blocking")]
+    pub fn get_currently_playing_with_features(
+        &mut self,
+    ) -> Result<Option<(Track, AudioFeatures)>> {
+        let playing = self.get_currently_playing_track()?;
+        let Some(track) = playing.and_then(music_track) else {
+            return Ok(None);
+        };
+        let features = self.get_audio_features(&track.id)?;
+        Ok(Some((track, features)))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_currently_playing_with_features(
+        &mut self,
+    ) -> Result<Option<(Track, AudioFeatures)>> {
+        let playing = self.get_currently_playing_track().await?;
+        let Some(track) = playing.and_then(music_track) else {
+            return Ok(None);
+        };
+        let features = self.get_audio_features(&track.id).await?;
+        Ok(Some((track, features)))
+    }
+
+    /// Whether each album is in the user's library, in the order of `ids`.
+    #[cfg(feature = "blocking")]
+    pub fn check_saved_albums(&mut self, ids: &[&str]) -> Result<Vec<bool>> {
+        let mut saved = Vec::with_capacity(ids.len());
+        for chunk in id_chunks(ids, MAX_SAVED_ALBUMS_IDS)? {
+            let payload = self.api_get(&format!("{SAVED_ALBUMS_CONTAINS_API_PATH}?ids={chunk}"))?;
+            saved.extend(
+                self.parse_response::<Vec<bool>>(SAVED_ALBUMS_CONTAINS_ENDPOINT, &payload)?,
+            );
+        }
+        check_contains_len(ids, saved)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn check_saved_albums(&mut self, ids: &[&str]) -> Result<Vec<bool>> {
+        let mut saved = Vec::with_capacity(ids.len());
+        for chunk in id_chunks(ids, MAX_SAVED_ALBUMS_IDS)? {
+            let payload = self
+                .api_get(&format!("{SAVED_ALBUMS_CONTAINS_API_PATH}?ids={chunk}"))
+                .await?;
+            saved.extend(
+                self.parse_response::<Vec<bool>>(SAVED_ALBUMS_CONTAINS_ENDPOINT, &payload)?,
+            );
+        }
+        check_contains_len(ids, saved)
+    }
+
+    /// Whether each track is in the user's library, in the order of `ids`.
+    #[cfg(feature = "blocking")]
+    pub fn check_saved_tracks(&mut self, ids: &[&str]) -> Result<Vec<bool>> {
+        let mut saved = Vec::with_capacity(ids.len());
+        for chunk in id_chunks(ids, MAX_SAVED_TRACKS_IDS)? {
+            let payload = self.api_get(&format!("{SAVED_TRACKS_CONTAINS_API_PATH}?ids={chunk}"))?;
+            saved.extend(
+                self.parse_response::<Vec<bool>>(SAVED_TRACKS_CONTAINS_ENDPOINT, &payload)?,
+            );
+        }
+        check_contains_len(ids, saved)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn check_saved_tracks(&mut self, ids: &[&str]) -> Result<Vec<bool>> {
+        let mut saved = Vec::with_capacity(ids.len());
+        for chunk in id_chunks(ids, MAX_SAVED_TRACKS_IDS)? {
+            let payload = self
+                .api_get(&format!("{SAVED_TRACKS_CONTAINS_API_PATH}?ids={chunk}"))
+                .await?;
+            saved.extend(
+                self.parse_response::<Vec<bool>>(SAVED_TRACKS_CONTAINS_ENDPOINT, &payload)?,
+            );
+        }
+        check_contains_len(ids, saved)
+    }
+
+    /// Saves tracks to the user's library, in batches Spotify accepts.
+    #[cfg(feature = "blocking")]
+    pub fn save_tracks(&mut self, ids: &[&str]) -> Result<()> {
+        self.update_saved_tracks(Method::PUT, ids)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn save_tracks(&mut self, ids: &[&str]) -> Result<()> {
+        self.update_saved_tracks(Method::PUT, ids).await
+    }
+
+    /// Removes tracks from the user's library, in batches Spotify accepts.
+    #[cfg(feature = "blocking")]
+    pub fn remove_saved_tracks(&mut self, ids: &[&str]) -> Result<()> {
+        self.update_saved_tracks(Method::DELETE, ids)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn remove_saved_tracks(&mut self, ids: &[&str]) -> Result<()> {
+        self.update_saved_tracks(Method::DELETE, ids).await
+    }
+
+    #[cfg(feature = "blocking")]
+    fn update_saved_tracks(&mut self, method: Method, ids: &[&str]) -> Result<()> {
+        for batch in id_batches(ids, MAX_SAVED_TRACKS_IDS)? {
+            let body = serde_json::json!({ "ids": batch });
+            let payload = self.api_request_json(method.clone(), SAVED_TRACKS_API_PATH, &body)?;
+            check_library_update(&method, &payload)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn update_saved_tracks(&mut self, method: Method, ids: &[&str]) -> Result<()> {
+        for batch in id_batches(ids, MAX_SAVED_TRACKS_IDS)? {
+            let body = serde_json::json!({ "ids": batch });
+            let payload = self
+                .api_request_json(method.clone(), SAVED_TRACKS_API_PATH, &body)
+                .await?;
+            check_library_update(&method, &payload)?;
+        }
+        Ok(())
+    }
+
+    /// Whether each user follows the playlist, in the order of `user_ids`.
+    #[cfg(feature = "blocking")]
+    pub fn check_following_playlist(
+        &mut self,
+        playlist_id: &str,
+        user_ids: &[&str],
+    ) -> Result<Vec<bool>> {
+        let mut following = Vec::with_capacity(user_ids.len());
+        for chunk in id_chunks(user_ids, MAX_FOLLOWER_IDS)? {
+            let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/followers/contains?ids={chunk}");
+            let payload = self.api_get(&path)?;
+            following
+                .extend(self.parse_response::<Vec<bool>>(FOLLOWERS_CONTAINS_ENDPOINT, &payload)?);
+        }
+        check_contains_len(user_ids, following)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn check_following_playlist(
+        &mut self,
+        playlist_id: &str,
+        user_ids: &[&str],
+    ) -> Result<Vec<bool>> {
+        let mut following = Vec::with_capacity(user_ids.len());
+        for chunk in id_chunks(user_ids, MAX_FOLLOWER_IDS)? {
+            let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/followers/contains?ids={chunk}");
+            let payload = self.api_get(&path).await?;
+            following
+                .extend(self.parse_response::<Vec<bool>>(FOLLOWERS_CONTAINS_ENDPOINT, &payload)?);
+        }
+        check_contains_len(user_ids, following)
+    }
+
+    /// Removes every occurrence of `uris` from the playlist, in batches
+    /// Spotify accepts. The first batch is made against `snapshot_id` when
+    /// given, each later one against the snapshot the previous returned.
+    /// Returns the playlist's new snapshot id.
+    ///
+    /// On Error: [SpotifyError::SnapshotOutdated] when the playlist changed
+    /// since `snapshot_id`.
+    #[cfg(feature = "blocking")]
+    pub fn remove_tracks_from_playlist(
+        &mut self,
+        playlist_id: &str,
+        uris: &[&str],
+        snapshot_id: Option<&str>,
+    ) -> Result<String> {
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/tracks");
+        let mut snapshot = snapshot_id.map(String::from);
+        for batch in id_batches(uris, MAX_PLAYLIST_REMOVE_URIS)? {
+            let body = remove_tracks_body(batch, snapshot.as_deref());
+            let payload = self.api_request_json(Method::DELETE, &path, &body)?;
+            snapshot = Some(self.parse_playlist_change(&payload)?);
+        }
+        snapshot.context("No playlist items to remove")
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn remove_tracks_from_playlist(
+        &mut self,
+        playlist_id: &str,
+        uris: &[&str],
+        snapshot_id: Option<&str>,
+    ) -> Result<String> {
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/tracks");
+        let mut snapshot = snapshot_id.map(String::from);
+        for batch in id_batches(uris, MAX_PLAYLIST_REMOVE_URIS)? {
+            let body = remove_tracks_body(batch, snapshot.as_deref());
+            let payload = self.api_request_json(Method::DELETE, &path, &body).await?;
+            snapshot = Some(self.parse_playlist_change(&payload)?);
+        }
+        snapshot.context("No playlist items to remove")
+    }
+
+    /// Appends `uris` to the playlist, in batches Spotify accepts. Returns
+    /// the playlist's new snapshot id.
+    #[cfg(feature = "blocking")]
+    pub fn add_tracks_to_playlist(&mut self, playlist_id: &str, uris: &[&str]) -> Result<String> {
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/tracks");
+        let mut snapshot = None;
+        for batch in id_batches(uris, MAX_PLAYLIST_ADD_URIS)? {
+            let body = serde_json::json!({ "uris": batch });
+            let payload = self.api_request_json(Method::POST, &path, &body)?;
+            snapshot = Some(self.parse_playlist_change(&payload)?);
+        }
+        snapshot.context("No playlist items to add")
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn add_tracks_to_playlist(
+        &mut self,
+        playlist_id: &str,
+        uris: &[&str],
+    ) -> Result<String> {
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/tracks");
+        let mut snapshot = None;
+        for batch in id_batches(uris, MAX_PLAYLIST_ADD_URIS)? {
+            let body = serde_json::json!({ "uris": batch });
+            let payload = self.api_request_json(Method::POST, &path, &body).await?;
+            snapshot = Some(self.parse_playlist_change(&payload)?);
+        }
+        snapshot.context("No playlist items to add")
+    }
+
+    /// One page of the playlist's items.
+    #[cfg(feature = "blocking")]
+    pub fn get_playlist_items(
+        &mut self,
+        playlist_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<PlaylistItem>> {
+        let payload = self.api_get(&playlist_items_path(playlist_id, limit, offset)?)?;
+        self.parse_response(PLAYLIST_ITEMS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_playlist_items(
+        &mut self,
+        playlist_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<PlaylistItem>> {
+        let payload = self
+            .api_get(&playlist_items_path(playlist_id, limit, offset)?)
+            .await?;
+        self.parse_response(PLAYLIST_ITEMS_ENDPOINT, &payload)
+    }
+
+    /// The ids of every track in the playlist, in playlist order. Episodes
+    /// and local files are left out.
+    #[cfg(feature = "blocking")]
+    pub fn get_playlist_track_ids(&mut self, playlist_id: &str) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = self.get_playlist_items(playlist_id, MAX_PLAYLIST_ITEMS_LIMIT, offset)?;
+            ids.extend(
+                page.items
+                    .iter()
+                    .filter_map(PlaylistItem::track_id)
+                    .map(String::from),
+            );
+            if page.next.is_none() || page.items.is_empty() {
+                return Ok(ids);
+            }
+            offset += page.items.len() as u32;
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_playlist_track_ids(&mut self, playlist_id: &str) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = self
+                .get_playlist_items(playlist_id, MAX_PLAYLIST_ITEMS_LIMIT, offset)
+                .await?;
+            ids.extend(
+                page.items
+                    .iter()
+                    .filter_map(PlaylistItem::track_id)
+                    .map(String::from),
+            );
+            if page.next.is_none() || page.items.is_empty() {
+                return Ok(ids);
+            }
+            offset += page.items.len() as u32;
+        }
+    }
+
+    /// Walks the playlists for their tracks, each counted once however many
+    /// playlists have it, and finds the ones not in the library yet.
+    #[cfg(feature = "blocking")]
+    pub fn plan_library_import(&mut self, playlist_ids: &[&str]) -> Result<ImportPlan> {
+        let mut playlists = Vec::with_capacity(playlist_ids.len());
+        for playlist_id in playlist_ids {
+            playlists.push(self.get_playlist_track_ids(playlist_id)?);
+        }
+        let ids = unique_track_ids(&playlists);
+        let saved = match ids.is_empty() {
+            true => Vec::new(),
+            false => {
+                self.check_saved_tracks(&ids.iter().map(String::as_str).collect::<Vec<_>>())?
+            }
+        };
+        Ok(import_plan(playlist_ids, ids, &saved))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn plan_library_import(&mut self, playlist_ids: &[&str]) -> Result<ImportPlan> {
+        let mut playlists = Vec::with_capacity(playlist_ids.len());
+        for playlist_id in playlist_ids {
+            playlists.push(self.get_playlist_track_ids(playlist_id).await?);
+        }
+        let ids = unique_track_ids(&playlists);
+        let saved = match ids.is_empty() {
+            true => Vec::new(),
+            false => {
+                self.check_saved_tracks(&ids.iter().map(String::as_str).collect::<Vec<_>>())
+                    .await?
+            }
+        };
+        Ok(import_plan(playlist_ids, ids, &saved))
+    }
+
+    /// Saves what is left of `import` batch by batch, calling `progress`
+    /// after each. Every request waits for the rate limiter and retries on a
+    /// 429, a failure leaves the rest in the import's file for the next run.
+    #[cfg(feature = "blocking")]
+    pub fn run_library_import(
+        &mut self,
+        import: &mut LibraryImport,
+        mut progress: impl FnMut(&LibraryImport),
+    ) -> Result<()> {
+        while !import.next_batch().is_empty() {
+            let batch: Vec<&str> = import.next_batch().iter().map(String::as_str).collect();
+            self.save_tracks(&batch)?;
+            import.batch_saved()?;
+            progress(import);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn run_library_import(
+        &mut self,
+        import: &mut LibraryImport,
+        mut progress: impl FnMut(&LibraryImport),
+    ) -> Result<()> {
+        while !import.next_batch().is_empty() {
+            let batch: Vec<&str> = import.next_batch().iter().map(String::as_str).collect();
+            self.save_tracks(&batch).await?;
+            import.batch_saved()?;
+            progress(import);
+        }
+        Ok(())
+    }
+
+    /// Fetches what is left of `snapshot` page by page, calling `progress`
+    /// after each. Every request waits for the rate limiter and retries on a
+    /// 429, a failure leaves what was fetched in the snapshot's file for the
+    /// next run.
+    #[cfg(feature = "blocking")]
+    pub fn run_library_snapshot(
+        &mut self,
+        snapshot: &mut SnapshotRun,
+        mut progress: impl FnMut(&SnapshotRun),
+    ) -> Result<()> {
+        while let Some(request) = snapshot.next_request() {
+            let started = Instant::now();
+            let page = match request {
+                PageRequest::SavedTracks { offset } => {
+                    SnapshotPage::SavedTracks(self.get_saved_tracks(SNAPSHOT_PAGE_SIZE, offset)?)
+                }
+                PageRequest::SavedAlbums { offset } => {
+                    SnapshotPage::SavedAlbums(self.get_saved_albums(SNAPSHOT_PAGE_SIZE, offset)?)
+                }
+                PageRequest::FollowedArtists { after } => {
+                    let (artists, next) =
+                        self.get_followed_artists(SNAPSHOT_PAGE_SIZE, after.as_deref())?;
+                    SnapshotPage::FollowedArtists(artists, next)
+                }
+                PageRequest::Playlists { offset } => {
+                    SnapshotPage::Playlists(self.get_my_playlists(SNAPSHOT_PAGE_SIZE, offset)?)
+                }
+                PageRequest::PlaylistTracks {
+                    playlist_id,
+                    offset,
+                } => SnapshotPage::PlaylistTracks {
+                    page: self.get_playlist_items(&playlist_id, SNAPSHOT_PAGE_SIZE, offset)?,
+                    playlist_id,
+                },
+            };
+            snapshot.add_page(page, started.elapsed())?;
+            progress(snapshot);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn run_library_snapshot(
+        &mut self,
+        snapshot: &mut SnapshotRun,
+        mut progress: impl FnMut(&SnapshotRun),
+    ) -> Result<()> {
+        while let Some(request) = snapshot.next_request() {
+            let started = Instant::now();
+            let page = match request {
+                PageRequest::SavedTracks { offset } => SnapshotPage::SavedTracks(
+                    self.get_saved_tracks(SNAPSHOT_PAGE_SIZE, offset).await?,
+                ),
+                PageRequest::SavedAlbums { offset } => SnapshotPage::SavedAlbums(
+                    self.get_saved_albums(SNAPSHOT_PAGE_SIZE, offset).await?,
+                ),
+                PageRequest::FollowedArtists { after } => {
+                    let (artists, next) = self
+                        .get_followed_artists(SNAPSHOT_PAGE_SIZE, after.as_deref())
+                        .await?;
+                    SnapshotPage::FollowedArtists(artists, next)
+                }
+                PageRequest::Playlists { offset } => SnapshotPage::Playlists(
+                    self.get_my_playlists(SNAPSHOT_PAGE_SIZE, offset).await?,
+                ),
+                PageRequest::PlaylistTracks {
+                    playlist_id,
+                    offset,
+                } => SnapshotPage::PlaylistTracks {
+                    page: self
+                        .get_playlist_items(&playlist_id, SNAPSHOT_PAGE_SIZE, offset)
+                        .await?,
+                    playlist_id,
+                },
+            };
+            snapshot.add_page(page, started.elapsed())?;
+            progress(snapshot);
+        }
+        Ok(())
+    }
+
+    /// Moves `range_length` items starting at `range_start` to just before
+    /// the item at `insert_before`, positions as they were before the move.
+    /// Returns the playlist's new snapshot id.
+    ///
+    /// On Error: [SpotifyError::SnapshotOutdated] when the playlist changed
+    /// since `snapshot_id`.
+    #[cfg(feature = "blocking")]
+    pub fn reorder_playlist(
+        &mut self,
+        playlist_id: &str,
+        range_start: u32,
+        insert_before: u32,
+        range_length: u32,
+        snapshot_id: Option<&str>,
+    ) -> Result<String> {
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/tracks");
+        let body = reorder_body(range_start, insert_before, range_length, snapshot_id);
+        let payload = self.api_request_json(Method::PUT, &path, &body)?;
+        self.parse_playlist_change(&payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn reorder_playlist(
+        &mut self,
+        playlist_id: &str,
+        range_start: u32,
+        insert_before: u32,
+        range_length: u32,
+        snapshot_id: Option<&str>,
+    ) -> Result<String> {
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/tracks");
+        let body = reorder_body(range_start, insert_before, range_length, snapshot_id);
+        let payload = self.api_request_json(Method::PUT, &path, &body).await?;
+        self.parse_playlist_change(&payload)
+    }
+
+    /// The playlist's cover in the sizes Spotify has, largest first. Empty
+    /// when the playlist has no cover.
+    #[cfg(feature = "blocking")]
+    pub fn get_playlist_cover(&mut self, playlist_id: &str) -> Result<Vec<Image>> {
+        let payload = self.api_get(&format!("{PLAYLISTS_API_PATH}/{playlist_id}/images"))?;
+        self.parse_playlist_cover(&payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_playlist_cover(&mut self, playlist_id: &str) -> Result<Vec<Image>> {
+        let payload = self
+            .api_get(&format!("{PLAYLISTS_API_PATH}/{playlist_id}/images"))
+            .await?;
+        self.parse_playlist_cover(&payload)
+    }
+
+    fn parse_playlist_cover(&self, response: &ApiResponse) -> Result<Vec<Image>> {
+        if StatusCode::NO_CONTENT == response.status || response.body.trim().is_empty() {
+            return Ok(Vec::new());
+        }
+        // Playlists without a cover answer null
+        let images: Option<Vec<Image>> = self.parse_response(PLAYLIST_IMAGES_ENDPOINT, response)?;
+        Ok(images.unwrap_or_default())
+    }
+
+    /// Replaces the playlist's cover with a JPEG. Spotify processes it after
+    /// answering, the new cover shows up a moment later.
+    ///
+    /// On Error: the JPEG is over [MAX_COVER_UPLOAD_SIZE] once base64
+    /// encoded, or the token wasn't granted [IMAGE_UPLOAD_SCOPE].
+    #[cfg(feature = "blocking")]
+    pub fn set_playlist_cover(&mut self, playlist_id: &str, jpeg: &[u8]) -> Result<()> {
+        let base64 = self.cover_upload(jpeg)?;
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/images");
+        let payload = self.api_send(Method::PUT, &path, Some(RequestBody::Jpeg(&base64)))?;
+        check_cover_upload(&path, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn set_playlist_cover(&mut self, playlist_id: &str, jpeg: &[u8]) -> Result<()> {
+        let base64 = self.cover_upload(jpeg)?;
+        let path = format!("{PLAYLISTS_API_PATH}/{playlist_id}/images");
+        let payload = self
+            .api_send(Method::PUT, &path, Some(RequestBody::Jpeg(&base64)))
+            .await?;
+        check_cover_upload(&path, &payload)
+    }
+
+    /// The base64 to upload as a cover, checked before anything is sent.
+    fn cover_upload(&self, jpeg: &[u8]) -> Result<String> {
+        if !jpeg.starts_with(&JPEG_MAGIC) {
+            bail!("Playlist covers have to be JPEGs");
+        }
+        let base64 = BASE64_STANDARD.encode(jpeg);
+        if base64.len() > MAX_COVER_UPLOAD_SIZE {
+            bail!(
+                "The cover is {} KB base64 encoded, Spotify takes at most {} KB",
+                base64.len().div_ceil(1024),
+                MAX_COVER_UPLOAD_SIZE / 1024
+            );
+        }
+        let granted = self.user_auth.snapshot();
+        if granted.is_some_and(|auth| !auth.granted_scopes().contains(&IMAGE_UPLOAD_SCOPE)) {
+            bail!("Uploading a cover needs the {IMAGE_UPLOAD_SCOPE} scope, authorize again");
+        }
+        Ok(base64)
+    }
+
+    fn parse_playlist_change(&self, response: &ApiResponse) -> Result<String> {
+        check_playlist_change(response)?;
+        let snapshot: PlaylistSnapshot = self.parse_response(PLAYLIST_TRACKS_ENDPOINT, response)?;
+        Ok(snapshot.snapshot_id)
+    }
+
+    /// One page of the artists the user follows, along with the cursor for
+    /// the next page. Pass that cursor as `after` to continue.
+    #[cfg(feature = "blocking")]
+    pub fn get_followed_artists(
+        &mut self,
+        limit: u32,
+        after: Option<&str>,
+    ) -> Result<(Vec<ArtistFull>, Option<String>)> {
+        let payload = self.api_get(&followed_artists_path(limit, after)?)?;
+        let followed: FollowedArtists = self.parse_response(FOLLOWED_ARTISTS_ENDPOINT, &payload)?;
+        let next = followed.artists.next_cursor();
+        Ok((followed.artists.items, next))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_followed_artists(
+        &mut self,
+        limit: u32,
+        after: Option<&str>,
+    ) -> Result<(Vec<ArtistFull>, Option<String>)> {
+        let payload = self.api_get(&followed_artists_path(limit, after)?).await?;
+        let followed: FollowedArtists = self.parse_response(FOLLOWED_ARTISTS_ENDPOINT, &payload)?;
+        let next = followed.artists.next_cursor();
+        Ok((followed.artists.items, next))
+    }
+
+    /// The details of many artists, in batches Spotify accepts. Ids Spotify
+    /// doesn't know are left out.
+    #[cfg(feature = "blocking")]
+    pub fn get_artists(&mut self, ids: &[&str]) -> Result<Vec<ArtistFull>> {
+        let mut artists = Vec::with_capacity(ids.len());
+        for chunk in id_chunks(ids, MAX_ARTISTS_IDS)? {
+            let payload = self.api_get(&format!("{ARTISTS_API_PATH}?ids={chunk}"))?;
+            let page: Artists = self.parse_response(ARTISTS_ENDPOINT, &payload)?;
+            artists.extend(page.artists.into_iter().flatten());
+        }
+        Ok(artists)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_artists(&mut self, ids: &[&str]) -> Result<Vec<ArtistFull>> {
+        let mut artists = Vec::with_capacity(ids.len());
+        for chunk in id_chunks(ids, MAX_ARTISTS_IDS)? {
+            let payload = self
+                .api_get(&format!("{ARTISTS_API_PATH}?ids={chunk}"))
+                .await?;
+            let page: Artists = self.parse_response(ARTISTS_ENDPOINT, &payload)?;
+            artists.extend(page.artists.into_iter().flatten());
+        }
+        Ok(artists)
+    }
+
+    /// The details of many artists, fresh ones from `cache` and the rest
+    /// fetched in as few calls as Spotify allows. Fetched artists are saved
+    /// to the cache. Ids Spotify doesn't know are left out.
+    #[cfg(feature = "blocking")]
+    pub fn get_or_fetch_artists(
+        &mut self,
+        cache: &mut ArtistCache,
+        ids: &[&str],
+    ) -> Result<Vec<CachedArtist>> {
+        let now = SystemTime::now();
+        let misses = cache_misses(cache, ids, now);
+        if !misses.is_empty() {
+            for artist in self.get_artists(&misses)? {
+                cache.insert(&artist, now);
+            }
+            cache.save()?;
+        }
+        Ok(cached_artists(cache, ids))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_or_fetch_artists(
+        &mut self,
+        cache: &mut ArtistCache,
+        ids: &[&str],
+    ) -> Result<Vec<CachedArtist>> {
+        let now = SystemTime::now();
+        let misses = cache_misses(cache, ids, now);
+        if !misses.is_empty() {
+            for artist in self.get_artists(&misses).await? {
+                cache.insert(&artist, now);
+            }
+            cache.save()?;
+        }
+        Ok(cached_artists(cache, ids))
+    }
+
+    /// Albums newly released on Spotify, in `country` when given as an
+    /// ISO 3166-1 alpha-2 code, e.g. "SE".
+    #[cfg(feature = "blocking")]
+    pub fn get_new_releases(
+        &mut self,
+        country: Option<&str>,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<Album>> {
+        let payload = self.api_get(&new_releases_path(country, limit, offset)?)?;
+        let releases: NewReleases = self.parse_response(NEW_RELEASES_ENDPOINT, &payload)?;
+        Ok(releases.albums)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_new_releases(
+        &mut self,
+        country: Option<&str>,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<Album>> {
+        let payload = self
+            .api_get(&new_releases_path(country, limit, offset)?)
+            .await?;
+        let releases: NewReleases = self.parse_response(NEW_RELEASES_ENDPOINT, &payload)?;
+        Ok(releases.albums)
+    }
+
+    /// The audiobooks saved in the user's library.
+    #[cfg(feature = "blocking")]
+    pub fn get_saved_audiobooks(&mut self, limit: u32, offset: u32) -> Result<Page<Audiobook>> {
+        let payload = self.api_get(&saved_audiobooks_path(limit, offset)?)?;
+        self.parse_response(SAVED_AUDIOBOOKS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_saved_audiobooks(
+        &mut self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<Audiobook>> {
+        let payload = self.api_get(&saved_audiobooks_path(limit, offset)?).await?;
+        self.parse_response(SAVED_AUDIOBOOKS_ENDPOINT, &payload)
+    }
+
+    /// One page of the tracks the user saved, most recently saved first.
+    #[cfg(feature = "blocking")]
+    pub fn get_saved_tracks(&mut self, limit: u32, offset: u32) -> Result<Page<SavedTrack>> {
+        let payload = self.api_get(&library_page_path(SAVED_TRACKS_API_PATH, limit, offset)?)?;
+        self.parse_response(SAVED_TRACKS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_saved_tracks(&mut self, limit: u32, offset: u32) -> Result<Page<SavedTrack>> {
+        let payload = self
+            .api_get(&library_page_path(SAVED_TRACKS_API_PATH, limit, offset)?)
+            .await?;
+        self.parse_response(SAVED_TRACKS_ENDPOINT, &payload)
+    }
+
+    /// Like [SpotifyClient::get_saved_tracks], as they are in the user's
+    /// market: each track tells whether it can be played there and why not.
+    #[cfg(feature = "blocking")]
+    pub fn get_saved_tracks_in_market(
+        &mut self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<SavedTrack>> {
+        let payload = self.api_get(&saved_tracks_in_market_path(limit, offset)?)?;
+        self.parse_response(SAVED_TRACKS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_saved_tracks_in_market(
+        &mut self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<SavedTrack>> {
+        let payload = self
+            .api_get(&saved_tracks_in_market_path(limit, offset)?)
+            .await?;
+        self.parse_response(SAVED_TRACKS_ENDPOINT, &payload)
+    }
+
+    /// Checks every saved track for whether it can still be played, logging
+    /// the ones that turned unplayable or playable again in `store`, see
+    /// [HistoryStore::record_availability].
+    #[cfg(feature = "blocking")]
+    pub fn audit_library(&mut self, store: &HistoryStore) -> Result<LibraryAudit> {
+        let mut audit = LibraryAudit::default();
+        let mut offset = 0;
+        loop {
+            let page = self.get_saved_tracks_in_market(MAX_LIBRARY_PAGE_LIMIT, offset)?;
+            audit_saved_tracks(&page.items, store, &mut audit)?;
+            if page.next.is_none() || page.items.is_empty() {
+                return Ok(audit);
+            }
+            offset += page.items.len() as u32;
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn audit_library(&mut self, store: &HistoryStore) -> Result<LibraryAudit> {
+        let mut audit = LibraryAudit::default();
+        let mut offset = 0;
+        loop {
+            let page = self
+                .get_saved_tracks_in_market(MAX_LIBRARY_PAGE_LIMIT, offset)
+                .await?;
+            audit_saved_tracks(&page.items, store, &mut audit)?;
+            if page.next.is_none() || page.items.is_empty() {
+                return Ok(audit);
+            }
+            offset += page.items.len() as u32;
+        }
+    }
+
+    /// One page of the albums the user saved, most recently saved first.
+    #[cfg(feature = "blocking")]
+    pub fn get_saved_albums(&mut self, limit: u32, offset: u32) -> Result<Page<SavedAlbum>> {
+        let payload = self.api_get(&library_page_path(SAVED_ALBUMS_API_PATH, limit, offset)?)?;
+        self.parse_response(SAVED_ALBUMS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_saved_albums(&mut self, limit: u32, offset: u32) -> Result<Page<SavedAlbum>> {
+        let payload = self
+            .api_get(&library_page_path(SAVED_ALBUMS_API_PATH, limit, offset)?)
+            .await?;
+        self.parse_response(SAVED_ALBUMS_ENDPOINT, &payload)
+    }
+
+    /// One page of the playlists the user owns or follows.
+    #[cfg(feature = "blocking")]
+    pub fn get_my_playlists(&mut self, limit: u32, offset: u32) -> Result<Page<PlaylistSummary>> {
+        let payload = self.api_get(&library_page_path(MY_PLAYLISTS_API_PATH, limit, offset)?)?;
+        self.parse_response(MY_PLAYLISTS_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_my_playlists(
+        &mut self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<PlaylistSummary>> {
+        let payload = self
+            .api_get(&library_page_path(MY_PLAYLISTS_API_PATH, limit, offset)?)
+            .await?;
+        self.parse_response(MY_PLAYLISTS_ENDPOINT, &payload)
+    }
+
+    /// One page of the episodes the user saved, most recently saved first.
+    /// Their resume points tell how far the user got.
+    #[cfg(feature = "blocking")]
+    pub fn get_saved_episodes(&mut self, limit: u32, offset: u32) -> Result<Page<SavedEpisode>> {
+        let payload = self.api_get(&episodes_path(SAVED_EPISODES_API_PATH, limit, offset)?)?;
+        self.parse_response(SAVED_EPISODES_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_saved_episodes(
+        &mut self,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<SavedEpisode>> {
+        let payload = self
+            .api_get(&episodes_path(SAVED_EPISODES_API_PATH, limit, offset)?)
+            .await?;
+        self.parse_response(SAVED_EPISODES_ENDPOINT, &payload)
+    }
+
+    #[cfg(feature = "blocking")]
+    pub fn get_show(&mut self, show_id: &str) -> Result<Show> {
+        let payload = self.api_get(&format!("{SHOWS_API_PATH}/{show_id}"))?;
+        self.parse_response(SHOW_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_show(&mut self, show_id: &str) -> Result<Show> {
+        let payload = self.api_get(&format!("{SHOWS_API_PATH}/{show_id}")).await?;
+        self.parse_response(SHOW_ENDPOINT, &payload)
+    }
+
+    /// One page of a show's episodes, newest first.
+    #[cfg(feature = "blocking")]
+    pub fn get_show_episodes(
+        &mut self,
+        show_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<ShowEpisode>> {
+        let path = format!("{SHOWS_API_PATH}/{show_id}/episodes");
+        let payload = self.api_get(&episodes_path(&path, limit, offset)?)?;
+        self.parse_response(SHOW_EPISODES_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_show_episodes(
+        &mut self,
+        show_id: &str,
+        limit: u32,
+        offset: u32,
+    ) -> Result<Page<ShowEpisode>> {
+        let path = format!("{SHOWS_API_PATH}/{show_id}/episodes");
+        let payload = self.api_get(&episodes_path(&path, limit, offset)?).await?;
+        self.parse_response(SHOW_EPISODES_ENDPOINT, &payload)
+    }
+
+    /// One page of the tracks the user played last, newest first, along with
+    /// the cursor of the older page. Pass that cursor as `before` to continue.
+    #[cfg(feature = "blocking")]
+    pub fn get_recently_played(
+        &mut self,
+        limit: u32,
+        before: Option<&str>,
+    ) -> Result<(Vec<RecentlyPlayed>, Option<String>)> {
+        let cursor = before.map(|before| (PageOrder::NewestFirst, before));
+        let page = self.recently_played_page(limit, cursor)?;
+        let older = page.before_cursor();
+        Ok((page.items, older))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn get_recently_played(
+        &mut self,
+        limit: u32,
+        before: Option<&str>,
+    ) -> Result<(Vec<RecentlyPlayed>, Option<String>)> {
+        let cursor = before.map(|before| (PageOrder::NewestFirst, before));
+        let page = self.recently_played_page(limit, cursor).await?;
+        let older = page.before_cursor();
+        Ok((page.items, older))
+    }
+
+    /// A page of the recently played tracks before or after a cursor, as
+    /// Spotify sends it: newest first either way.
+    #[cfg(feature = "blocking")]
+    fn recently_played_page(
+        &mut self,
+        limit: u32,
+        cursor: Option<(PageOrder, &str)>,
+    ) -> Result<CursorPage<RecentlyPlayed>> {
+        let payload = self.api_get(&recently_played_path(limit, cursor)?)?;
+        self.parse_response(RECENTLY_PLAYED_ENDPOINT, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn recently_played_page(
+        &mut self,
+        limit: u32,
+        cursor: Option<(PageOrder, &str)>,
+    ) -> Result<CursorPage<RecentlyPlayed>> {
+        let payload = self.api_get(&recently_played_path(limit, cursor)?).await?;
+        self.parse_response(RECENTLY_PLAYED_ENDPOINT, &payload)
+    }
+
+    /// Pages through the recently played tracks in `order` from `start`, a
+    /// cursor in milliseconds since the epoch, see [HistoryPager].
+    pub fn history_pager(&mut self, order: PageOrder, start: Option<String>) -> HistoryPager<'_> {
+        HistoryPager {
+            client: self,
+            order,
+            cursor: start,
+            stop: None,
+            limit: MAX_RECENTLY_PLAYED_LIMIT,
+            done: false,
+        }
+    }
+
+    /// Fills the gaps of `store` from the recently played tracks, e.g. plays
+    /// made while the daemon wasn't running. Pages back until the last stored
+    /// play, returns how many plays were added. Spotify only remembers the
+    /// last 50 plays.
+    #[cfg(feature = "blocking")]
+    pub fn sync_recent_to_history(&mut self, store: &HistoryStore) -> Result<usize> {
+        let known = store.load()?;
+        let since = known.iter().map(|entry| entry.played_at).max();
+        let mut plays = Vec::new();
+        let mut pager = self.history_pager(PageOrder::NewestFirst, None);
+        if let Some(since) = since {
+            pager = pager.until(since);
+        }
+        while let Some(items) = pager.next_page()? {
+            collect_recent_plays(items, &mut plays);
+        }
+        store.append_new(&known, plays)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn sync_recent_to_history(&mut self, store: &HistoryStore) -> Result<usize> {
+        let known = store.load()?;
+        let since = known.iter().map(|entry| entry.played_at).max();
+        let mut plays = Vec::new();
+        let mut pager = self.history_pager(PageOrder::NewestFirst, None);
+        if let Some(since) = since {
+            pager = pager.until(since);
+        }
+        while let Some(items) = pager.next_page().await? {
+            collect_recent_plays(items, &mut plays);
+        }
+        store.append_new(&known, plays)
+    }
+
+    /// Sends a raw request, see [SpotifyClient::raw_get].
+    #[cfg(feature = "blocking")]
+    fn raw_request(
+        &mut self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<RequestBody<'_>>,
+    ) -> Result<serde_json::Value> {
+        let path = raw_api_path(&self.endpoints.api_url, path, query)?;
+        let payload = self.api_send(method, &path, body)?;
+        self.parse_raw_response(&path, &payload)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    async fn raw_request(
+        &mut self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<RequestBody<'_>>,
+    ) -> Result<serde_json::Value> {
+        let path = raw_api_path(&self.endpoints.api_url, path, query)?;
+        let payload = self.api_send(method, &path, body).await?;
+        self.parse_raw_response(&path, &payload)
+    }
+
+    fn parse_raw_response(&self, path: &str, response: &ApiResponse) -> Result<serde_json::Value> {
+        if !response.status.is_success() {
+            bail!(
+                "Spotify refused {path} with <{}>: {}",
+                response.status,
+                response.body.trim()
+            );
+        }
+        if response.body.trim().is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+        self.parse_response(RAW_ENDPOINT, response)
+    }
+
+    /// Unstable, for endpoints without a method yet: the raw calls may
+    /// change shape as typed methods are added. GETs `path`, relative to
+    /// the API root like `/me/shows`, with `query` encoded. Token refreshes,
+    /// rate limiting and 429 retries work like for any other call. Paths
+    /// leaving the API host are refused, the bearer token only goes to Spotify.
+    ///
+    /// Returns the JSON answer, Null when there was no body. A failure
+    /// status is an error with Spotify's answer.
+    #[cfg(feature = "blocking")]
+    pub fn raw_get(&mut self, path: &str, query: &[(&str, &str)]) -> Result<serde_json::Value> {
+        self.raw_request(Method::GET, path, query, None)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn raw_get(
+        &mut self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::GET, path, query, None).await
+    }
+
+    /// Unstable, DELETEs a path under the API root, see [SpotifyClient::raw_get].
+    #[cfg(feature = "blocking")]
+    pub fn raw_delete(&mut self, path: &str, query: &[(&str, &str)]) -> Result<serde_json::Value> {
+        self.raw_request(Method::DELETE, path, query, None)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn raw_delete(
+        &mut self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::DELETE, path, query, None).await
+    }
+
+    /// Unstable, PUTs `body` as JSON, see [SpotifyClient::raw_get].
+    #[cfg(feature = "blocking")]
+    pub fn raw_put_json(
+        &mut self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::PUT, path, &[], Some(RequestBody::Json(body)))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn raw_put_json(
+        &mut self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::PUT, path, &[], Some(RequestBody::Json(body)))
+            .await
+    }
+
+    /// Unstable, POSTs `body` as JSON, see [SpotifyClient::raw_get].
+    #[cfg(feature = "blocking")]
+    pub fn raw_post_json(
+        &mut self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::POST, path, &[], Some(RequestBody::Json(body)))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn raw_post_json(
+        &mut self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::POST, path, &[], Some(RequestBody::Json(body)))
+            .await
+    }
+
+    /// Unstable, POSTs `form` url encoded, see [SpotifyClient::raw_get].
+    #[cfg(feature = "blocking")]
+    pub fn raw_post_form(
+        &mut self,
+        path: &str,
+        form: &[(&str, &str)],
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::POST, path, &[], Some(RequestBody::Form(form)))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn raw_post_form(
+        &mut self,
+        path: &str,
+        form: &[(&str, &str)],
+    ) -> Result<serde_json::Value> {
+        self.raw_request(Method::POST, path, &[], Some(RequestBody::Form(form)))
+            .await
+    }
+}
+
+/// Which way a [HistoryPager] goes through the recently played tracks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageOrder {
+    /// Back in time from the start cursor, Spotify's `before`
+    NewestFirst,
+    /// On in time from the start cursor, Spotify's `after`
+    OldestFirst,
+}
+
+impl PageOrder {
+    fn cursor_param(self) -> &'static str {
+        match self {
+            PageOrder::NewestFirst => "before",
+            PageOrder::OldestFirst => "after",
+        }
+    }
+}
+
+/// Pages through the recently played tracks one way, from a start cursor
+/// until Spotify runs out or a stop time is passed. Each page comes in the
+/// pager's order, pages newest first come with `before` cursors, pages
+/// oldest first with `after` ones.
+///
+/// Spotify only remembers the last 50 or so plays and answers an empty page
+/// past them, which ends the paging like a page without a cursor does.
+/// Blocking clients can use the pager as an [Iterator] of pages.
+pub struct HistoryPager<'a> {
+    client: &'a mut SpotifyClient,
+    order: PageOrder,
+    cursor: Option<String>,
+    stop: Option<SystemTime>,
+    limit: u32,
+    done: bool,
+}
+
+impl HistoryPager<'_> {
+    /// Stops at `stop`: plays before it aren't handed out newest first,
+    /// plays after it aren't oldest first. A play right at it is.
+    pub fn until(mut self, stop: SystemTime) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    /// Tracks requested per page, up to [MAX_RECENTLY_PLAYED_LIMIT].
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// The next page, None once the paging is over.
+    #[cfg(feature = "blocking")]
+    pub fn next_page(&mut self) -> Result<Option<Vec<RecentlyPlayed>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let cursor = request_cursor(self.order, self.cursor.as_deref());
+        let page = self.client.recently_played_page(self.limit, cursor);
+        self.turn(page)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn next_page(&mut self) -> Result<Option<Vec<RecentlyPlayed>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let cursor = request_cursor(self.order, self.cursor.as_deref());
+        let page = self.client.recently_played_page(self.limit, cursor).await;
+        self.turn(page)
+    }
+
+    /// Moves the cursor on past `page` and puts its plays in order. A failed
+    /// request ends the paging.
+    fn turn(
+        &mut self,
+        page: Result<CursorPage<RecentlyPlayed>>,
+    ) -> Result<Option<Vec<RecentlyPlayed>>> {
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                self.done = true;
+                return Err(e);
+            }
+        };
+        if page.items.is_empty() {
+            self.done = true;
+            return Ok(None);
+        }
+
+        let next = match self.order {
+            PageOrder::NewestFirst => page.before_cursor(),
+            // The newest play of the page, Spotify's `after` cursor isn't
+            // always there
+            PageOrder::OldestFirst => page
+                .items
+                .iter()
+                .filter_map(RecentlyPlayed::played_at)
+                .max()
+                .and_then(|newest| newest.duration_since(UNIX_EPOCH).ok())
+                .map(|newest| newest.as_millis().to_string())
+                .filter(|next| self.cursor.as_ref() != Some(next)),
+        };
+        let mut items = page.items;
+        if self.order == PageOrder::OldestFirst {
+            items.reverse();
+        }
+        if let Some(stop) = self.stop {
+            let order = self.order;
+            let before = items.len();
+            // Plays with a bad time are left for the caller to skip
+            items.retain(|item| {
+                item.played_at().is_none_or(|at| match order {
+                    PageOrder::NewestFirst => at >= stop,
+                    PageOrder::OldestFirst => at <= stop,
+                })
+            });
+            if items.len() < before {
+                self.done = true;
+            }
+        }
+        match next {
+            Some(next) if !self.done => self.cursor = Some(next),
+            _ => self.done = true,
+        }
+        Ok(Some(items))
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl Iterator for HistoryPager<'_> {
+    type Item = Result<Vec<RecentlyPlayed>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_page().transpose()
+    }
+}
+
+/// The cursor a [HistoryPager] requests its next page with. Oldest first
+/// starts from the oldest play Spotify remembers without one.
+fn request_cursor(order: PageOrder, cursor: Option<&str>) -> Option<(PageOrder, &str)> {
+    match (order, cursor) {
+        (order, Some(cursor)) => Some((order, cursor)),
+        (PageOrder::OldestFirst, None) => Some((PageOrder::OldestFirst, "0")),
+        (PageOrder::NewestFirst, None) => None,
+    }
+}
+
+/// Adds the plays of a recently played page, skipping ones with a bad time.
+fn collect_recent_plays(items: Vec<RecentlyPlayed>, plays: &mut Vec<PlayHistoryEntry>) {
+    for item in items {
+        let Some(played_at) = item.played_at() else {
+            warn!(
+                "Skipping a recent play with a bad time <{}>",
+                item.played_at
+            );
+            continue;
+        };
+        let mut play = PlayHistoryEntry::from_track(&item.track, played_at, Confidence::High);
+        play.context = Some(PlayContext::of(item.context.as_ref()));
+        play.source = PlaySource::Backfill;
+        plays.push(play);
+    }
+}
+
+/// The API path of a raw request with its query. Only relative paths that
+/// stay under the API root go through, so the token can't leak to another host.
+fn raw_api_path(api_url: &str, path: &str, query: &[(&str, &str)]) -> Result<String> {
+    if !path.starts_with('/') || path.starts_with("//") || path.contains('\\') {
+        bail!("Raw requests take a path relative to the API root like /me/shows, got <{path}>");
+    }
+    if path.contains(['?', '#']) {
+        bail!("Pass the query of <{path}> as query params");
+    }
+    let mut full_path = path.to_string();
+    if !query.is_empty() {
+        let query = url::form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(query)
+            .finish();
+        full_path = format!("{path}?{query}");
+    }
+
+    // Dot segments, also percent encoded ones, could still climb out
+    let base = Url::parse(api_url)?;
+    let url = Url::parse(&format!("{}{full_path}", api_url.trim_end_matches('/')))?;
+    let root = format!("{}/", base.path().trim_end_matches('/'));
+    if url.origin() != base.origin() || !url.path().starts_with(&root) {
+        bail!("Raw request path <{path}> leaves the API root");
+    }
+    Ok(full_path)
+}
+
+fn recently_played_path(limit: u32, cursor: Option<(PageOrder, &str)>) -> Result<String> {
+    if !(1..=MAX_RECENTLY_PLAYED_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_RECENTLY_PLAYED_LIMIT}, got {limit}");
+    }
+    let limit = limit.to_string();
+    let mut query = vec![("limit", limit.as_str())];
+    if let Some((order, cursor)) = cursor {
+        query.push((order.cursor_param(), cursor));
+    }
+    Ok(with_query(RECENTLY_PLAYED_API_PATH, &query))
+}
+
+/// `path` with `query` appended, encoded. Cursors come from Spotify and
+/// the user, they may hold anything.
+fn with_query(path: &str, query: &[(&str, &str)]) -> String {
+    // A base only to have a URL to build the query on, it is cut off again
+    let mut url = Url::parse("http://localhost")
+        .and_then(|base| base.join(path))
+        .expect("API paths are valid URL paths");
+    url.query_pairs_mut().extend_pairs(query);
+    url[url::Position::BeforePath..].to_string()
+}
+
+fn playlist_items_path(playlist_id: &str, limit: u32, offset: u32) -> Result<String> {
+    if !(1..=MAX_PLAYLIST_ITEMS_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_PLAYLIST_ITEMS_LIMIT}, got {limit}");
+    }
+    Ok(format!(
+        "{PLAYLISTS_API_PATH}/{playlist_id}/tracks?limit={limit}&offset={offset}"
+    ))
+}
+
+/// The plan of an import, `saved` tells which of `ids` are in the library.
+fn import_plan(playlist_ids: &[&str], ids: Vec<String>, saved: &[bool]) -> ImportPlan {
+    let found = ids.len();
+    let to_save: Vec<String> = ids
+        .into_iter()
+        .zip(saved)
+        .filter(|(_, saved)| !**saved)
+        .map(|(id, _)| id)
+        .collect();
+    ImportPlan {
+        playlists: playlist_ids.iter().map(|id| id.to_string()).collect(),
+        found,
+        already_saved: found - to_save.len(),
+        to_save,
+    }
+}
+
+fn track_search_path(query: &str, limit: u32) -> Result<String> {
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_SEARCH_LIMIT}, got {limit}");
+    }
+    if query.trim().is_empty() {
+        bail!("Search for something");
+    }
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("q", query)
+        .append_pair("type", "track")
+        .append_pair("limit", &limit.to_string())
+        .finish();
+    Ok(format!("{SEARCH_API_PATH}?{query}"))
+}
+
+fn add_to_queue_path(uri: &str) -> String {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("uri", uri)
+        .finish();
+    format!("{QUEUE_API_PATH}?{query}")
+}
+
+fn followed_artists_path(limit: u32, after: Option<&str>) -> Result<String> {
+    if !(1..=MAX_FOLLOWED_ARTISTS_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_FOLLOWED_ARTISTS_LIMIT}, got {limit}");
+    }
+    let limit = limit.to_string();
+    let mut query = vec![("type", "artist"), ("limit", limit.as_str())];
+    if let Some(after) = after {
+        query.push(("after", after));
+    }
+    Ok(with_query(FOLLOWING_API_PATH, &query))
+}
+
+fn new_releases_path(country: Option<&str>, limit: u32, offset: u32) -> Result<String> {
+    if !(1..=MAX_NEW_RELEASES_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_NEW_RELEASES_LIMIT}, got {limit}");
+    }
+    let mut path = format!("{NEW_RELEASES_API_PATH}?limit={limit}&offset={offset}");
+    if let Some(country) = country {
+        if country.len() != 2 || !country.bytes().all(|b| b.is_ascii_uppercase()) {
+            bail!("The country must be an ISO 3166-1 alpha-2 code like \"SE\", got {country:?}");
+        }
+        path.push_str(&format!("&country={country}"));
+    }
+    Ok(path)
+}
+
+fn library_page_path(path: &str, limit: u32, offset: u32) -> Result<String> {
+    if !(1..=MAX_LIBRARY_PAGE_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_LIBRARY_PAGE_LIMIT}, got {limit}");
+    }
+    Ok(format!("{path}?limit={limit}&offset={offset}"))
+}
+
+fn saved_tracks_in_market_path(limit: u32, offset: u32) -> Result<String> {
+    let path = library_page_path(SAVED_TRACKS_API_PATH, limit, offset)?;
+    Ok(format!("{path}&market={MARKET}"))
+}
+
+/// Logs the availability of a page of saved tracks and notes the unplayable ones.
+fn audit_saved_tracks(
+    saved: &[SavedTrack],
+    store: &HistoryStore,
+    audit: &mut LibraryAudit,
+) -> Result<()> {
+    let now = SystemTime::now();
+    for SavedTrack { track, .. } in saved {
+        audit.checked += 1;
+        if store.record_availability(track, now)?.is_some() {
+            audit.changes += 1;
+        }
+        match AvailabilityChange::of_track(track, now) {
+            Some(state) if !state.playable => audit.unplayable.push(state),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn saved_audiobooks_path(limit: u32, offset: u32) -> Result<String> {
+    if !(1..=MAX_SAVED_AUDIOBOOKS_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_SAVED_AUDIOBOOKS_LIMIT}, got {limit}");
+    }
+    Ok(format!(
+        "{SAVED_AUDIOBOOKS_API_PATH}?limit={limit}&offset={offset}"
+    ))
+}
+
+/// Ids are base62, anything else would end up in the path.
+fn album_path(album_id: &str) -> Result<String> {
+    if album_id.is_empty() || !album_id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        bail!("Not a Spotify album id: {album_id:?}");
+    }
+    Ok(format!("{ALBUMS_API_PATH}/{album_id}"))
+}
+
+/// The client of [SpotifyClient::with_correlation_id], its requests carry
+/// the correlation id until it is dropped.
+pub struct Correlated<'a> {
+    client: &'a mut SpotifyClient,
+}
+
+impl Deref for Correlated<'_> {
+    type Target = SpotifyClient;
+
+    fn deref(&self) -> &SpotifyClient {
+        self.client
+    }
+}
+
+impl DerefMut for Correlated<'_> {
+    fn deref_mut(&mut self) -> &mut SpotifyClient {
+        self.client
+    }
+}
+
+impl Drop for Correlated<'_> {
+    fn drop(&mut self) {
+        self.client.correlation_id = None;
+    }
+}
+
+/// Album covers by album id and size, each downloaded once. Clones share
+/// the directory and the HTTP client.
+#[derive(Clone)]
+pub struct AlbumArtCache {
+    http_client: Client,
+    dir: PathBuf,
+}
+
+impl AlbumArtCache {
+    /// See [SpotifyClient::fetch_album_art].
+    #[cfg(feature = "blocking")]
+    pub fn fetch(&self, track: &Track, size: ImageSize) -> Result<PathBuf> {
+        let (url, path) = self.path(track, size)?;
+        if !path.exists() {
+            let bytes = self
+                .http_client
+                .get(url)
+                .send()?
+                .error_for_status()?
+                .bytes()?;
+            write_album_art(&path, &bytes)?;
+        }
+        Ok(path)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    pub async fn fetch(&self, track: &Track, size: ImageSize) -> Result<PathBuf> {
+        let (url, path) = self.path(track, size)?;
+        if !path.exists() {
+            let bytes = self
+                .http_client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+            write_album_art(&path, &bytes)?;
+        }
+        Ok(path)
+    }
+
+    /// The url of the cover to fetch, and where it is cached.
+    fn path<'a>(&self, track: &'a Track, size: ImageSize) -> Result<(&'a str, PathBuf)> {
+        let album = &track.album;
+        let Some(image) = album.best_image(size.width()) else {
+            bail!("The album {:?} has no cover", album.name);
+        };
+        // Checked like an id in a request path, it names the file
+        album_path(&album.id)?;
+        let name = format!("{}-{}.jpg", album.id, size.width());
+        Ok((&image.url, self.dir.join(name)))
+    }
+}
+
+/// Writes next to `path` first, a download cut short is never served from
+/// the cache.
+fn write_album_art(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let partial = path.with_extension("part");
+    fs::write(&partial, bytes)?;
+    fs::rename(&partial, path).with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// Spotify answers an id it doesn't know with 404.
+fn check_found(kind: &str, id: &str, response: &ApiResponse) -> Result<()> {
+    if response.status == StatusCode::NOT_FOUND {
+        bail!("Spotify has no {kind} with the id {id}");
+    }
+    Ok(())
+}
+
+/// A page of the saved episodes or of a show's episodes at `path`.
+fn episodes_path(path: &str, limit: u32, offset: u32) -> Result<String> {
+    if !(1..=MAX_EPISODES_LIMIT).contains(&limit) {
+        bail!("The limit must be between 1 and {MAX_EPISODES_LIMIT}, got {limit}");
+    }
+    Ok(format!("{path}?limit={limit}&offset={offset}"))
+}
+
+/// Spotify answers a revoked or expired refresh token with 400 `invalid_grant`.
+fn check_refresh_status(response: &ApiResponse) -> Result<()> {
+    if matches!(
+        response.status,
+        StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED
+    ) {
+        return Err(SpotifyError::RefreshTokenRevoked.into());
+    }
+    Ok(())
+}
+
+/// Whether a request acts for a user: anything under `/me` and every change.
+/// Catalog and browse reads work with an app-only token.
+fn needs_user(method: &Method, path: &str) -> bool {
+    let first = path.trim_start_matches('/').split(['/', '?']).next();
+    first == Some("me") || method != Method::GET
+}
+
+/// The scopes of [Capability::ReadPlayback] and `capabilities`.
+fn scope_for(capabilities: &[Capability]) -> String {
+    let scopes = [Capability::ReadPlayback]
+        .iter()
+        .chain(capabilities)
+        .map(|capability| capability.scopes().join(" "))
+        .collect::<Vec<_>>();
+    let scopes: Vec<&str> = scopes.iter().map(String::as_str).collect();
+    merge_scopes(&scopes)
+}
+
+/// Space separated scope lists joined into one, each scope once and in the
+/// order first seen.
+fn merge_scopes(lists: &[&str]) -> String {
+    let mut seen = HashSet::new();
+    lists
+        .iter()
+        .flat_map(|list| list.split_whitespace())
+        .filter(|scope| seen.insert(*scope))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The scopes of `needed` that `granted` lacks, both space separated.
+fn scope_diff(granted: &str, needed: &str) -> Vec<String> {
+    let granted: HashSet<&str> = granted.split_whitespace().collect();
+    needed
+        .split_whitespace()
+        .filter(|scope| !granted.contains(scope))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The scopes an authorization asks for, [SCOPE] and the optional ones.
+fn requested_scope(email: bool, image_upload: bool) -> String {
+    let mut scope = SCOPE.to_string();
+    for (wanted, extra) in [(email, EMAIL_SCOPE), (image_upload, IMAGE_UPLOAD_SCOPE)] {
+        if wanted {
+            scope = format!("{scope} {extra}");
+        }
+    }
+    scope
+}
+
+/// Adds the `device_id` query param when a device is given.
+fn with_device_id(path: &str, device_id: Option<&str>) -> String {
+    match device_id {
+        None => path.to_string(),
+        Some(id) => {
+            let separator = if path.contains('?') { '&' } else { '?' };
+            format!("{path}{separator}device_id={id}")
+        }
+    }
+}
+
+/// The player is playing on the device, not just listing it.
+fn is_active_device(state: Option<&PlaybackState>, device_id: &str) -> bool {
+    state.is_some_and(|state| {
+        state.device.is_active && state.device.id.as_deref() == Some(device_id)
+    })
+}
+
+/// Player commands answer 204, or an error like 404 when there is no device to play on.
+fn check_player_command(path: &str, response: &ApiResponse) -> Result<()> {
+    if !response.status.is_success() {
+        bail!(
+            "Spotify refused {path} with <{}>: {}",
+            response.status,
+            response.body.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Spotify answers 202 once it has the cover, it is processed after.
+fn check_cover_upload(path: &str, response: &ApiResponse) -> Result<()> {
+    if !response.status.is_success() {
+        bail!(
+            "Spotify refused the cover for {path} with <{}>: {}",
+            response.status,
+            response.body.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Whether `err` means the stored tokens are no good, so only a new
+/// authorization helps.
+fn needs_authorization(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<SpotifyError>(),
+        Some(SpotifyError::TokenRejected | SpotifyError::RefreshTokenRevoked)
+    )
+}
+
+/// A 401 means the token was rejected, any other failure says nothing about it.
+fn token_validation_result(status: StatusCode) -> Result<bool> {
+    match status {
+        StatusCode::UNAUTHORIZED => Ok(false),
+        status if status.is_success() => Ok(true),
+        status => bail!("Spotify answered <{status}> when validating the access token"),
+    }
+}
+
+/// The ids `cache` has no fresh artist for, each once.
+fn cache_misses<'a>(cache: &mut ArtistCache, ids: &[&'a str], now: SystemTime) -> Vec<&'a str> {
+    let mut seen = HashSet::new();
+    let misses: Vec<&str> = ids
+        .iter()
+        .copied()
+        .filter(|id| seen.insert(*id) && cache.fresh(id, now).is_none())
+        .collect();
+    let counters = cache.counters();
+    debug!(
+        "Artist cache: {} misses of {} ids, {} hits and {} misses so far",
+        misses.len(),
+        ids.len(),
+        counters.hits,
+        counters.misses
+    );
+    misses
+}
+
+/// The cached artists of `ids`, in their order.
+fn cached_artists(cache: &ArtistCache, ids: &[&str]) -> Vec<CachedArtist> {
+    ids.iter().filter_map(|id| cache.get(id).cloned()).collect()
+}
+
+/// Splits ids into batches of at most `limit`, keeping their order.
+fn id_batches<'a>(ids: &'a [&'a str], limit: usize) -> Result<Chunks<'a, &'a str>> {
+    if ids.is_empty() {
+        bail!("At least one id is needed");
+    }
+    Ok(ids.chunks(limit))
+}
+
+/// Splits ids into comma separated batches of at most `limit`, keeping their order.
+fn id_chunks(ids: &[&str], limit: usize) -> Result<Vec<String>> {
+    Ok(id_batches(ids, limit)?
+        .map(|chunk| chunk.join(","))
+        .collect())
+}
+
+fn remove_tracks_body(uris: &[&str], snapshot_id: Option<&str>) -> serde_json::Value {
+    let tracks: Vec<_> = uris
+        .iter()
+        .map(|uri| serde_json::json!({ "uri": uri }))
+        .collect();
+    let mut body = serde_json::json!({ "tracks": tracks });
+    if let Some(snapshot_id) = snapshot_id {
+        body["snapshot_id"] = snapshot_id.into();
+    }
+    body
+}
+
+fn reorder_body(
+    range_start: u32,
+    insert_before: u32,
+    range_length: u32,
+    snapshot_id: Option<&str>,
+) -> serde_json::Value {
+    let mut body = serde_json::json!({
+        "range_start": range_start,
+        "insert_before": insert_before,
+        "range_length": range_length,
+    });
+    if let Some(snapshot_id) = snapshot_id {
+        body["snapshot_id"] = snapshot_id.into();
+    }
+    body
+}
+
+/// A playlist edit against a stale snapshot is refused with an error
+/// mentioning it, tell those apart so the caller can refetch and retry.
+fn check_playlist_change(response: &ApiResponse) -> Result<()> {
+    if response.status.is_success() {
+        return Ok(());
+    }
+    let stale = matches!(
+        response.status,
+        StatusCode::BAD_REQUEST | StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED
+    ) && response.body.to_lowercase().contains("snapshot");
+    if stale {
+        return Err(SpotifyError::SnapshotOutdated.into());
+    }
+    bail!(
+        "Spotify refused the playlist change with <{}>: {}",
+        response.status,
+        response.body.trim()
+    );
+}
+
+/// Spotify answers a library change with 200, or 204 on some endpoints.
+fn check_library_update(method: &Method, response: &ApiResponse) -> Result<()> {
+    if !matches!(response.status, StatusCode::OK | StatusCode::NO_CONTENT) {
+        bail!(
+            "Spotify refused {method} {SAVED_TRACKS_API_PATH} with <{}>: {}",
+            response.status,
+            response.body.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Contains endpoints answer one bool per id, anything else can't be matched back up.
+fn check_contains_len(ids: &[&str], answers: Vec<bool>) -> Result<Vec<bool>> {
+    if answers.len() != ids.len() {
+        bail!(
+            "Spotify answered {} results for {} ids",
+            answers.len(),
+            ids.len()
+        );
+    }
+    Ok(answers)
+}
+
+/// Time from a Spotify `timestamp`, in ms since the epoch, to `now`. Zero
+/// when the local clock is behind Spotify's, progress can't go backwards.
+fn time_offset(timestamp_ms: u64, now: SystemTime) -> Duration {
+    let server_time = UNIX_EPOCH + Duration::from_millis(timestamp_ms);
+    now.duration_since(server_time).unwrap_or_default()
+}
+
+/// The playing item as a music track, None for episodes, ads and the like.
+fn music_track(playing: CurrentlyPlayingTrack) -> Option<Track> {
+    match playing.into_playing_item()? {
+        PlayingItem::Track(track) => Some(track),
+        PlayingItem::Episode(_) | PlayingItem::Chapter(_) | PlayingItem::Ad => None,
+    }
+}
+
+#[cfg(feature = "blocking")]
+fn send_request(request: RequestBuilder, log_bodies: bool) -> Result<ApiResponse> {
+    debug!("Full request to Spotify: {:?}", request);
+    if log_bodies {
+        let body = request.try_clone().and_then(|r| r.build().ok());
+        trace_request_body(body.as_ref().and_then(|r| r.body()?.as_bytes()));
+    }
+    let response = request.send();
+    debug!("Full Response from Spotify: {:?}", response);
+    let response = response.context(SpotifyError::Network)?;
+    let url = response.url().to_string();
+    let status = response.status();
+    debug!("API Response status <{}>", status);
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let headers = headers_of_interest(response.headers());
+
+    let body = response.text()?;
+    if log_bodies {
+        trace!("Response body from <{url}>: {}", redact::redact_body(&body));
+    }
+    let response = ApiResponse {
+        url,
+        status,
+        body,
+        retry_after,
+        headers,
+    };
+    response.check_upstream(content_type.as_deref())?;
+    Ok(response)
+}
+
+#[cfg(not(feature = "blocking"))]
+async fn send_request(request: RequestBuilder, log_bodies: bool) -> Result<ApiResponse> {
+    debug!("Full request to Spotify: {:?}", request);
+    if log_bodies {
+        let body = request.try_clone().and_then(|r| r.build().ok());
+        trace_request_body(body.as_ref().and_then(|r| r.body()?.as_bytes()));
+    }
+    let response = request.send().await;
+    debug!("Full Response from Spotify: {:?}", response);
+    let response = response.context(SpotifyError::Network)?;
+    let url = response.url().to_string();
+    let status = response.status();
+    debug!("API Response status <{}>", status);
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok()?.trim().parse().ok())
+        .map(Duration::from_secs);
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let headers = headers_of_interest(response.headers());
+
+    let body = response.text().await?;
+    if log_bodies {
+        trace!("Response body from <{url}>: {}", redact::redact_body(&body));
+    }
+    let response = ApiResponse {
+        url,
+        status,
+        body,
+        retry_after,
+        headers,
+    };
+    response.check_upstream(content_type.as_deref())?;
+    Ok(response)
+}
+
+fn trace_request_body(body: Option<&[u8]>) {
+    match body {
+        Some(body) if !body.is_empty() => {
+            let body = String::from_utf8_lossy(body);
+            trace!("Request body: {}", redact::redact_body(&body));
+        }
+        _ => trace!("Request without body"),
+    }
+}
+
+/// What the URL Spotify redirected to says about an authorization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedirectResult {
+    /// Authorized, the code is exchanged for tokens
+    Code { code: String, state: Option<String> },
+    /// The user declined, or Spotify refused for another `reason`
+    Denied {
+        reason: String,
+        description: Option<String>,
+    },
+    /// Not a redirect to the redirect URI, or one with neither a code nor an
+    /// error, e.g. a truncated paste
+    Invalid,
+}
+
+/// Reads the URL Spotify redirected to, which has to be one to
+/// `redirect_uri`. Any scheme works, an app may register its own.
+pub fn parse_redirect(redirect_url: &str, redirect_uri: &str) -> RedirectResult {
+    match Url::parse(redirect_url.trim()) {
+        Ok(url) if redirects_to(&url, redirect_uri) => get_code_from_query_pairs(url),
+        Ok(url) => {
+            debug!("{url} is not a redirect to {redirect_uri}");
+            RedirectResult::Invalid
+        }
+        Err(e) => {
+            debug!("Failed parsing the redirect URL: {e}");
+            RedirectResult::Invalid
+        }
+    }
+}
+
+/// Pulls the auth code out of the URL Spotify redirected to. A denial is
+/// an [AuthorizationDenied] error.
+fn code_from_redirect_url(redirect_url: &str, redirect_uri: &str) -> Result<String> {
+    match parse_redirect(redirect_url, redirect_uri) {
+        RedirectResult::Code { code, .. } => Ok(code),
+        RedirectResult::Denied {
+            reason,
+            description,
+        } => Err(AuthorizationDenied {
+            reason,
+            description,
+        }
+        .into()),
+        RedirectResult::Invalid => {
+            bail!("Not a URL Spotify redirected to {redirect_uri} with an auth code")
+        }
+    }
+}
+
+/// Whether `url` is `redirect_uri` with a query added. Both are parsed, so
+/// e.g. `http://localhost:8080` and `http://localhost:8080/` are the same.
+fn redirects_to(url: &Url, redirect_uri: &str) -> bool {
+    let Ok(expected) = Url::parse(redirect_uri) else {
+        return false;
+    };
+    url.scheme() == expected.scheme()
+        && url.host_str() == expected.host_str()
+        && url.port_or_known_default() == expected.port_or_known_default()
+        && url.path() == expected.path()
+}
+
+/// The code or the error of the redirect's query. An error wins over a
+/// code sent along with it.
+fn get_code_from_query_pairs(url: Url) -> RedirectResult {
+    let mut code = None;
+    let mut state = None;
+    let mut error = None;
+    let mut description = None;
+    for (key, value) in url.query_pairs() {
+        let value = Some(value.into_owned());
+        match key.as_ref() {
+            "code" => code = value,
+            "state" => state = value,
+            "error" => error = value,
+            "error_description" => description = value,
+            _ => {}
+        }
+    }
+
+    match (error, code) {
+        (Some(reason), _) => {
+            error!("Auth process encountered an issue {reason}");
+            RedirectResult::Denied {
+                reason,
+                description,
+            }
+        }
+        (None, Some(code)) => {
+            debug!("Successfully found code in url");
+            RedirectResult::Code { code, state }
+        }
+        (None, None) => {
+            debug!("Did not find code or error in parsed url");
+            RedirectResult::Invalid
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artist_cache::CacheCounters;
+    use crate::library_snapshot::{LibrarySnapshot, SnapshotSection};
+    use crate::spotify_data::PlayingType;
+
+    #[test]
+    fn test_getting_code_from_params() {
+        let url = String::from("http://localhost:8080/?code=AQAJQs0ZXTxhvkRUMXn1PVLQQBw2VXSldRqfou5RPM_RPkHdexx7v7lUNcjXjWzPKFW3bxxPLuHCJqoQy6NbIr-70-ZpPszqktjxBgzqqmKLv653gjh_f_-ELVPdWscUvlNlICrcyUGtGPCIIdDLWHg9bVEsBMFtyrEtA8S6bYoUbC-3YhqhNr6GC90rM3AmmTUqhTC2jkINQ9aFMCalO2l34NLE9kXqIVe2hBMaEdOuBNfi3zXhdG0kulgAJ8a03nAVMs9HBJXKFzD5bVFvl7eXj3p6DwMOnQFxFJq9wJHbg57a507DPmVr8vO_nYRcr6uXhVgMEY4WkR0djj3CgeKSUNOVGB-VwUs8YcyZH-kfaUoeOsY-6hyiDUizDPGXorL0vskU7GmTGsat2UwsSkanGeJvr3BP9-GVVIQFcU91WNiG2rkAa8rIWJz_EgRtqco7yA");
+        let url = Url::parse(&url).unwrap();
+        let spotify_auth_code = get_code_from_query_pairs(url);
+        assert_eq!(spotify_auth_code, RedirectResult::Code { state: None, code: String::from("AQAJQs0ZXTxhvkRUMXn1PVLQQBw2VXSldRqfou5RPM_RPkHdexx7v7lUNcjXjWzPKFW3bxxPLuHCJqoQy6NbIr-70-ZpPszqktjxBgzqqmKLv653gjh_f_-ELVPdWscUvlNlICrcyUGtGPCIIdDLWHg9bVEsBMFtyrEtA8S6bYoUbC-3YhqhNr6GC90rM3AmmTUqhTC2jkINQ9aFMCalO2l34NLE9kXqIVe2hBMaEdOuBNfi3zXhdG0kulgAJ8a03nAVMs9HBJXKFzD5bVFvl7eXj3p6DwMOnQFxFJq9wJHbg57a507DPmVr8vO_nYRcr6uXhVgMEY4WkR0djj3CgeKSUNOVGB-VwUs8YcyZH-kfaUoeOsY-6hyiDUizDPGXorL0vskU7GmTGsat2UwsSkanGeJvr3BP9-GVVIQFcU91WNiG2rkAa8rIWJz_EgRtqco7yA") });
+    }
+
+    #[test]
+    fn test_refresh_margin_boundary() {
+        let now = SystemTime::now();
+        let mut auth = fresh_user_auth();
+        // 3540s into a token that lasts 3600s
+        auth.last_refresh = Some(now - Duration::from_secs(3540));
+
+        assert!(!auth.token_needs_refresh_at(now, DEFAULT_REFRESH_MARGIN));
+        assert!(!auth.token_needs_refresh_at(now, Duration::from_secs(59)));
+        assert!(auth.token_needs_refresh_at(now, Duration::from_secs(60)));
+
+        let just_before = now - Duration::from_secs(1);
+        assert!(!auth.token_needs_refresh_at(just_before, Duration::from_secs(60)));
+        assert!(auth.token_needs_refresh_at(now + Duration::from_secs(55), DEFAULT_REFRESH_MARGIN));
+
+        auth.last_refresh = None;
+        assert!(auth.token_needs_refresh_at(now, Duration::ZERO));
+    }
+
+    #[test]
+    fn test_refresh_due() {
+        let now = SystemTime::now();
+        let mut auth = fresh_user_auth();
+        auth.last_refresh = Some(now);
+        let margin = Duration::from_secs(60);
+        let due = auth.refresh_due(margin).unwrap();
+        assert_eq!(due, now + Duration::from_secs(3540));
+        assert!(!auth.token_needs_refresh_at(due - Duration::from_secs(1), margin));
+        assert!(auth.token_needs_refresh_at(due, margin));
+
+        // Expired an hour ago
+        auth.last_refresh = Some(now - Duration::from_secs(7200));
+        assert!(auth.refresh_due(margin).unwrap() <= now);
+        auth.last_refresh = None;
+        assert_eq!(auth.refresh_due(margin), None);
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_next_refresh_due() {
+        let before = SystemTime::now();
+        let client = mock_client_builder("http://localhost")
+            .with_refresh_margin(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+        let due = client.next_refresh_due().unwrap();
+        assert!(due >= before + Duration::from_secs(3540));
+        assert!(due <= SystemTime::now() + Duration::from_secs(3540));
+
+        let expired = mock_client_builder("http://localhost")
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .build()
+            .await
+            .unwrap();
+        assert!(expired.next_refresh_due().unwrap() <= SystemTime::now());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_next_refresh_due() {
+        let before = SystemTime::now();
+        let client = mock_client_builder("http://localhost")
+            .with_refresh_margin(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        let due = client.next_refresh_due().unwrap();
+        assert!(due >= before + Duration::from_secs(3540));
+        assert!(due <= SystemTime::now() + Duration::from_secs(3540));
+
+        let expired = mock_client_builder("http://localhost")
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .build()
+            .unwrap();
+        assert!(expired.next_refresh_due().unwrap() <= SystemTime::now());
+    }
+
+    #[test]
+    fn test_code_from_redirect_url() {
+        let code = code_from_redirect_url(
+            "http://localhost:8080/?code=abc123&state=xyz\n",
+            DEFAULT_REDIRECT_URI,
+        );
+        assert_eq!(code.unwrap(), "abc123");
+        let denied = "http://localhost:8080/?error=access_denied";
+        assert!(code_from_redirect_url(denied, DEFAULT_REDIRECT_URI).is_err());
+        assert!(code_from_redirect_url("not a url", DEFAULT_REDIRECT_URI).is_err());
+        let elsewhere = "http://localhost:9090/?code=abc123";
+        assert!(code_from_redirect_url(elsewhere, DEFAULT_REDIRECT_URI).is_err());
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        assert_eq!(
+            parse_redirect(
+                "http://localhost:8080/?code=abc123&state=xyz\n",
+                DEFAULT_REDIRECT_URI
+            ),
+            RedirectResult::Code {
+                code: "abc123".to_string(),
+                state: Some("xyz".to_string())
+            }
+        );
+        assert_eq!(
+            parse_redirect(
+                "http://localhost:8080/?error=access_denied&error_description=User+said+no",
+                DEFAULT_REDIRECT_URI
+            ),
+            RedirectResult::Denied {
+                reason: "access_denied".to_string(),
+                description: Some("User said no".to_string())
+            }
+        );
+        assert_eq!(
+            parse_redirect("myapp://callback?error=invalid_scope", "myapp://callback"),
+            RedirectResult::Denied {
+                reason: "invalid_scope".to_string(),
+                description: None
+            }
+        );
+        assert_eq!(
+            parse_redirect("not a url", DEFAULT_REDIRECT_URI),
+            RedirectResult::Invalid
+        );
+        assert_eq!(
+            parse_redirect("http://localhost:8080/?state=xyz", DEFAULT_REDIRECT_URI),
+            RedirectResult::Invalid
+        );
+        assert_eq!(
+            parse_redirect("http://localhost:9090/?code=abc123", DEFAULT_REDIRECT_URI),
+            RedirectResult::Invalid
+        );
+    }
+
+    #[test]
+    fn test_redirect_error_wins_over_code() {
+        let url = "http://localhost:8080/?code=abc123&error=access_denied&state=xyz";
+        assert_eq!(
+            parse_redirect(url, DEFAULT_REDIRECT_URI),
+            RedirectResult::Denied {
+                reason: "access_denied".to_string(),
+                description: None
+            }
+        );
+        let err = code_from_redirect_url(url, DEFAULT_REDIRECT_URI).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AuthorizationDenied>(),
+            Some(&AuthorizationDenied {
+                reason: "access_denied".to_string(),
+                description: None
+            })
+        );
+        assert_eq!(err.to_string(), "The authorization was declined");
+    }
+
+    #[test]
+    fn test_code_from_custom_scheme_redirect_url() {
+        let code =
+            code_from_redirect_url("myapp://callback?code=abc123&state=xyz", "myapp://callback");
+        assert_eq!(code.unwrap(), "abc123");
+        let code = code_from_redirect_url("myapp:/callback?code=abc123", "myapp:/callback");
+        assert_eq!(code.unwrap(), "abc123");
+
+        let err = code_from_redirect_url("http://localhost:8080/?code=abc123", "myapp://callback")
+            .unwrap_err();
+        assert!(err.to_string().contains("myapp://callback"));
+        assert!(
+            code_from_redirect_url("otherapp://callback?code=abc123", "myapp://callback").is_err()
+        );
+        assert!(
+            code_from_redirect_url("myapp://callback?error=access_denied", "myapp://callback")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_new_releases_path() {
+        assert_eq!(
+            new_releases_path(Some("SE"), 20, 40).unwrap(),
+            "/browse/new-releases?limit=20&offset=40&country=SE"
+        );
+        assert_eq!(
+            new_releases_path(None, 50, 0).unwrap(),
+            "/browse/new-releases?limit=50&offset=0"
+        );
+        assert!(new_releases_path(Some("se"), 20, 0).is_err());
+        assert!(new_releases_path(Some("SWE"), 20, 0).is_err());
+        assert!(new_releases_path(None, 0, 0).is_err());
+        assert!(new_releases_path(None, 51, 0).is_err());
+    }
+
+    #[test]
+    fn test_episodes_path() {
+        assert_eq!(
+            episodes_path(SAVED_EPISODES_API_PATH, 20, 40).unwrap(),
+            "/me/episodes?limit=20&offset=40"
+        );
+        assert_eq!(
+            episodes_path("/shows/38bS44xjbVVZ3No3ByF1dJ/episodes", 50, 0).unwrap(),
+            "/shows/38bS44xjbVVZ3No3ByF1dJ/episodes?limit=50&offset=0"
+        );
+        assert!(episodes_path(SAVED_EPISODES_API_PATH, 0, 0).is_err());
+        assert!(episodes_path(SAVED_EPISODES_API_PATH, 51, 0).is_err());
+    }
+
+    #[test]
+    fn test_currently_playing_with_features_assembly() {
+        let playing = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let features = std::fs::read_to_string("sample_data/audio_features.json").unwrap();
+        let playing: CurrentlyPlayingTrack = serde_json::from_str(&playing).unwrap();
+        let features: AudioFeatures = serde_json::from_str(&features).unwrap();
+
+        let track = music_track(playing).unwrap();
+        assert_eq!(track.id, features.id);
+    }
+
+    #[test]
+    fn test_episodes_have_no_features() {
+        let playing = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let mut playing: CurrentlyPlayingTrack = serde_json::from_str(&playing).unwrap();
+        playing.currently_playing_type = PlayingType::Episode;
+        assert!(music_track(playing).is_none());
+    }
+
+    #[test]
+    fn test_contains_response_parsing() {
+        let data = std::fs::read_to_string("sample_data/contains.json").unwrap();
+        let answers: Vec<bool> = serde_json::from_str(&data).unwrap();
+        let ids = [
+            "4aawyAB9vmqN3uQ7FjRGTy",
+            "382ObEPsp2rxGrnsizN5TX",
+            "1A2GTWGtFfWp7KSQTwWOyo",
+        ];
+        assert_eq!(
+            check_contains_len(&ids, answers).unwrap(),
+            vec![false, true, true]
+        );
+        assert!(check_contains_len(&ids[..2], vec![true]).is_err());
+    }
+
+    #[test]
+    fn test_id_chunks() {
+        let ids: Vec<String> = (0..45).map(|n| format!("id{n}")).collect();
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let chunks = id_chunks(&ids, MAX_SAVED_ALBUMS_IDS).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks[0].starts_with("id0,id1,"));
+        assert!(chunks[1].starts_with("id20,"));
+        assert_eq!(chunks[2], "id40,id41,id42,id43,id44");
+        assert!(id_chunks(&[], MAX_FOLLOWER_IDS).is_err());
+    }
+
+    fn fresh_user_auth() -> UserAuthData {
+        UserAuthData {
+            access_token: "test-access-token".to_string(),
+            token_type: "Bearer".to_string(),
+            scope: SCOPE.to_string(),
+            expires_in: 3600,
+            refresh_token: "test-refresh-token".to_string(),
+            last_refresh: Some(SystemTime::now()),
+        }
+    }
+
+    fn mock_client_builder(server_url: &str) -> SpotifyClientBuilder {
+        SpotifyClientBuilder::new("tester".to_string())
+            .with_base_urls(server_url, &format!("{server_url}/v1"))
+            .with_in_memory_creds("test-client-id".to_string(), fresh_user_auth())
+    }
+
+    #[test]
+    fn test_endpoints_with_base_urls() {
+        let endpoints =
+            SpotifyEndpoints::with_base_urls("http://127.0.0.1:8888/", "http://127.0.0.1:8888/v1/");
+        assert_eq!(endpoints.auth_url, "http://127.0.0.1:8888/authorize");
+        assert_eq!(endpoints.tokens_url, "http://127.0.0.1:8888/api/token");
+        assert_eq!(
+            endpoints.api(CUR_PLAYING_API_PATH),
+            "http://127.0.0.1:8888/v1/me/player/currently-playing?additional_types=episode,chapter"
+        );
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_custom_base_url_is_used() {
+        let mut server = mockito::Server::new_async().await;
+        let body = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let mock = server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "additional_types".into(),
+                "episode,chapter".into(),
+            ))
+            .match_header("authorization", "Bearer test-access-token")
+            .with_body(body)
+            .create_async()
+            .await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let playing = client.get_currently_playing_track().await.unwrap().unwrap();
+        mock.assert_async().await;
+        assert!(playing.is_playing);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_custom_base_url_is_used() {
+        let mut server = mockito::Server::new();
+        let body = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let mock = server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "additional_types".into(),
+                "episode,chapter".into(),
+            ))
+            .match_header("authorization", "Bearer test-access-token")
+            .with_body(body)
+            .create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let playing = client.get_currently_playing_track().unwrap().unwrap();
+        mock.assert();
+        assert!(playing.is_playing);
+    }
+
+    #[test]
+    fn test_time_offset() {
+        let playing = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let playing: CurrentlyPlayingTrack = serde_json::from_str(&playing).unwrap();
+        let now = UNIX_EPOCH + Duration::from_millis(1727127574000);
+        assert_eq!(
+            time_offset(playing.timestamp, now),
+            Duration::from_millis(1438)
+        );
+        // A local clock behind Spotify's doesn't rewind the progress
+        assert!(time_offset(playing.timestamp + 5000, now).is_zero());
+    }
+
+    /// The currently playing fixture with its timestamp `ago` before now.
+    fn playing_since(ago: Duration) -> String {
+        let playing = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let mut playing: serde_json::Value = serde_json::from_str(&playing).unwrap();
+        let timestamp = SystemTime::now() - ago;
+        playing["timestamp"] =
+            serde_json::json!(timestamp.duration_since(UNIX_EPOCH).unwrap().as_millis());
+        playing.to_string()
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_server_time_offset() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::Any)
+            .with_body(playing_since(Duration::from_secs(3)))
+            .create_async()
+            .await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert_eq!(client.server_time_offset(), None);
+
+        client.get_currently_playing_track().await.unwrap();
+        let offset = client.server_time_offset().unwrap();
+        assert!(offset >= Duration::from_secs(3));
+        assert!(offset < Duration::from_secs(4));
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_server_time_offset() {
+        let mut server = mockito::Server::new();
+        let _mock = server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::Any)
+            .with_body(playing_since(Duration::from_secs(3)))
+            .create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert_eq!(client.server_time_offset(), None);
+
+        client.get_currently_playing_track().unwrap();
+        let offset = client.server_time_offset().unwrap();
+        assert!(offset >= Duration::from_secs(3));
+        assert!(offset < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_headers_of_interest() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse().unwrap());
+        headers.insert("warning", "299 - \"Deprecated API\"".parse().unwrap());
+        headers.insert("spotify-api-version", "2024-11".parse().unwrap());
+        headers.insert("x-ratelimit-remaining", "12".parse().unwrap());
+        let mut found = headers_of_interest(&headers);
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                ("spotify-api-version".to_string(), "2024-11".to_string()),
+                (
+                    "warning".to_string(),
+                    "299 - \"Deprecated API\"".to_string()
+                ),
+                ("x-ratelimit-remaining".to_string(), "12".to_string()),
+            ]
+        );
+        assert!(is_notice_header("sunset"));
+        assert!(!is_notice_header("x-ratelimit-remaining"));
+
+        assert!(first_sighting("deprecation", "test-sighting"));
+        assert!(!first_sighting("deprecation", "test-sighting"));
+        assert!(first_sighting("deprecation", "test-sighting-2"));
+    }
+
+    fn deprecated_playing_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::Any)
+            .with_header("Warning", "299 - \"currently-playing is deprecated\"")
+            .with_header("Spotify-Deprecation", "2025-06-01")
+            .with_header("X-RateLimit-Remaining", "99")
+            .with_body(playing_since(Duration::ZERO))
+    }
+
+    fn assert_diagnostics(client: &SpotifyClient) {
+        let diagnostics = client.diagnostics();
+        let names: Vec<&str> = diagnostics.keys().map(String::as_str).collect();
+        assert_eq!(
+            names,
+            ["spotify-deprecation", "warning", "x-ratelimit-remaining"]
+        );
+        let warning = &diagnostics["warning"];
+        assert_eq!(warning.value, "299 - \"currently-playing is deprecated\"");
+        assert!(warning.url.contains("/v1/me/player/currently-playing"));
+        assert_eq!(diagnostics["x-ratelimit-remaining"].value, "99");
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_diagnostics_keep_headers_of_interest() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = deprecated_playing_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert!(client.diagnostics().is_empty());
+
+        client.get_currently_playing_track().await.unwrap();
+        assert_diagnostics(&client);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_diagnostics_keep_headers_of_interest() {
+        let mut server = mockito::Server::new();
+        let _mock = deprecated_playing_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert!(client.diagnostics().is_empty());
+
+        client.get_currently_playing_track().unwrap();
+        assert_diagnostics(&client);
+    }
+
+    /// Remembers the tracks it was asked for.
+    #[derive(Default)]
+    struct RecordingProvider {
+        asked: Mutex<Vec<String>>,
+    }
+
+    impl LyricsProvider for RecordingProvider {
+        fn lookup(&self, _query: &crate::lyrics::LyricsQuery) -> Result<Option<Lyrics>> {
+            unreachable!("fetch is overridden")
+        }
+
+        fn fetch(&self, track: &Track) -> Result<Option<Lyrics>> {
+            self.asked.lock().unwrap().push(track.id.clone());
+            Ok(Some(Lyrics::Plain(format!("Lyrics of {}", track.name))))
+        }
+    }
+
+    fn playing_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::Any)
+            .with_body(playing_since(Duration::ZERO))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_get_lyrics() {
+        let mut server = mockito::Server::new_async().await;
+        let _mock = playing_mock(&mut server).create_async().await;
+        let provider = Arc::new(RecordingProvider::default());
+        let mut client = mock_client_builder(&server.url())
+            .with_lyrics_provider(provider.clone())
+            .build()
+            .await
+            .unwrap();
+
+        let lyrics = client.get_lyrics().await.unwrap();
+        assert_eq!(
+            lyrics,
+            Some(Lyrics::Plain("Lyrics of The Divine Zero".to_string()))
+        );
+        assert_eq!(*provider.asked.lock().unwrap(), ["1VY823dFzI9L8BEf2X7B5I"]);
+
+        // Without a provider there are no lyrics
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert_eq!(client.get_lyrics().await.unwrap(), None);
+    }
+
+    #[cfg(all(not(feature = "blocking"), feature = "lyrics"))]
+    #[tokio::test]
+    async fn test_get_lyrics_from_a_blocking_provider() {
+        let mut server = mockito::Server::new_async().await;
+        let _playing = playing_mock(&mut server).create_async().await;
+        let lrclib = server
+            .mock("GET", "/api/get")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "track_name".into(),
+                "The Divine Zero".into(),
+            ))
+            .with_body(crate::testutil::load_sample("lrclib_get.json"))
+            .create_async()
+            .await;
+        // Its blocking HTTP client can't be made or dropped on the runtime
+        let url = server.url();
+        let provider = tokio::task::spawn_blocking(move || {
+            Arc::new(crate::lyrics::LrclibProvider::with_base_url(&url))
+        })
+        .await
+        .unwrap();
+        let mut client = mock_client_builder(&server.url())
+            .with_lyrics_provider(provider.clone())
+            .build()
+            .await
+            .unwrap();
+
+        match client.get_lyrics().await.unwrap() {
+            Some(Lyrics::Synced(lines)) => assert_eq!(lines[0].text, "Now we're on the ground"),
+            other => panic!("expected synced lyrics, got {other:?}"),
+        }
+        lrclib.assert_async().await;
+        drop(client);
+        tokio::task::spawn_blocking(move || drop(provider))
+            .await
+            .unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_get_lyrics() {
+        let mut server = mockito::Server::new();
+        let _mock = playing_mock(&mut server).create();
+        let provider = Arc::new(RecordingProvider::default());
+        let mut client = mock_client_builder(&server.url())
+            .with_lyrics_provider(provider.clone())
+            .build()
+            .unwrap();
+
+        let lyrics = client.get_lyrics().unwrap();
+        assert_eq!(
+            lyrics,
+            Some(Lyrics::Plain("Lyrics of The Divine Zero".to_string()))
+        );
+        assert_eq!(*provider.asked.lock().unwrap(), ["1VY823dFzI9L8BEf2X7B5I"]);
+
+        // Without a provider there are no lyrics
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert_eq!(client.get_lyrics().unwrap(), None);
+    }
+
+    fn cached_playing_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/me/player/currently-playing")
+            .match_query(mockito::Matcher::Any)
+            .with_body(playing_since(Duration::ZERO))
+            .expect(2)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_currently_playing_from_cache() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = cached_playing_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url())
+            .with_min_refetch_interval(Duration::from_millis(300))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.currently_playing_age(), None);
+
+        let first = client.get_currently_playing_track().await.unwrap().unwrap();
+        let cached = client.get_currently_playing_track().await.unwrap().unwrap();
+        assert_eq!(first.timestamp, cached.timestamp);
+        assert!(client.currently_playing_age().unwrap() < Duration::from_millis(300));
+
+        // Asks again once the interval is over
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        client.get_currently_playing_track().await.unwrap();
+        mock.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_currently_playing_from_cache() {
+        let mut server = mockito::Server::new();
+        let mock = cached_playing_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url())
+            .with_min_refetch_interval(Duration::from_millis(300))
+            .build()
+            .unwrap();
+        assert_eq!(client.currently_playing_age(), None);
+
+        let first = client.get_currently_playing_track().unwrap().unwrap();
+        let cached = client.get_currently_playing_track().unwrap().unwrap();
+        assert_eq!(first.timestamp, cached.timestamp);
+        assert!(client.currently_playing_age().unwrap() < Duration::from_millis(300));
+
+        // Asks again once the interval is over
+        std::thread::sleep(Duration::from_millis(300));
+        client.get_currently_playing_track().unwrap();
+        mock.assert();
+    }
+
+    fn token_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("POST", "/api/token")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("grant_type".into(), "authorization_code".into()),
+                mockito::Matcher::UrlEncoded("code".into(), "redirected-code".into()),
+                mockito::Matcher::UrlEncoded("client_id".into(), "test-client-id".into()),
+            ]))
+            .with_body(
+                r#"{"access_token": "new-access-token", "token_type": "Bearer",
+                    "scope": "user-read-playback-state", "expires_in": 3600,
+                    "refresh_token": "new-refresh-token"}"#,
+            )
+    }
+
+    const REDIRECTED_URL: &str = "http://localhost:8080/?code=redirected-code&state=abc";
+
+    #[test]
+    fn test_scope_diff_and_merge() {
+        let playback = Capability::ReadPlayback.scopes().join(" ");
+        assert_eq!(scope_for(&[]), playback);
+        // Every scope once, in the order first asked for
+        assert_eq!(
+            scope_for(&[Capability::ReadLibrary, Capability::EditLibrary]),
+            format!("{playback} user-library-read user-library-modify")
+        );
+        let everything: Vec<&str> = SCOPE.split_whitespace().collect();
+        let all = [
+            Capability::ControlPlayback,
+            Capability::EditLibrary,
+            Capability::EditPlaylists,
+            Capability::TopItems,
+            Capability::Following,
+        ];
+        let mut scopes: Vec<String> = scope_for(&all)
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+        scopes.sort();
+        let mut expected = everything.clone();
+        expected.sort();
+        assert_eq!(scopes, expected);
+
+        assert_eq!(
+            scope_diff(&playback, &scope_for(&[Capability::ControlPlayback])),
+            ["user-modify-playback-state"]
+        );
+        assert!(scope_diff(SCOPE, &scope_for(&all)).is_empty());
+        assert_eq!(
+            scope_diff("", "user-top-read  user-follow-read"),
+            ["user-top-read", "user-follow-read"]
+        );
+        assert_eq!(merge_scopes(&["a b", "", "b c", "a"]), "a b c");
+    }
+
+    /// Only allowed to read the player.
+    fn read_only_auth() -> UserAuthData {
+        UserAuthData {
+            scope: Capability::ReadPlayback.scopes().join(" "),
+            ..fresh_user_auth()
+        }
+    }
+
+    fn requested_scopes(auth_url: &str) -> Vec<String> {
+        let url = Url::parse(auth_url).unwrap();
+        let (_, scope) = url.query_pairs().find(|(key, _)| key == "scope").unwrap();
+        scope.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_missing_scopes_are_added_to_the_next_authorization() {
+        let mut client = mock_client_builder("http://localhost")
+            .with_capabilities(&[])
+            .with_in_memory_creds("test-client-id".to_string(), read_only_auth())
+            .into_client(None);
+        let control = [Capability::ControlPlayback];
+        assert_eq!(
+            client.missing_scopes(&control),
+            ["user-modify-playback-state"]
+        );
+        assert!(client.missing_scopes(&[]).is_empty());
+
+        let missing = client.missing_scopes(&control);
+        client.add_wanted_scopes(&missing);
+        // Asking again on every failed call doesn't pile them up
+        client.add_wanted_scopes(&missing);
+        assert_eq!(client.effective_config().wanted_scopes, missing);
+        let scopes = requested_scopes(&client.begin_authorization().unwrap());
+        let mut expected: Vec<&str> = Capability::ReadPlayback.scopes().to_vec();
+        expected.push("user-modify-playback-state");
+        assert_eq!(scopes, expected);
+
+        // Scopes granted before are asked for again, not dropped
+        let granted = UserAuthData {
+            scope: format!("{} {EMAIL_SCOPE}", read_only_auth().scope),
+            ..fresh_user_auth()
+        };
+        client.shared_auth().set(Some(granted));
+        client.add_wanted_scopes(&["user-top-read".to_string()]);
+        let scopes = requested_scopes(&client.begin_authorization().unwrap());
+        assert!(scopes.iter().any(|scope| scope == EMAIL_SCOPE));
+        assert!(scopes.iter().any(|scope| scope == "user-top-read"));
+        assert!(scopes
+            .iter()
+            .any(|scope| scope == "user-modify-playback-state"));
+    }
+
+    fn failed_exchange_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("POST", "/api/token")
+            .with_status(400)
+            .with_body(
+                r#"{"error": "invalid_grant", "error_description": "Invalid authorization code"}"#,
+            )
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_reauthorizing_keeps_the_old_tokens_until_it_succeeds() {
+        let mut server = mockito::Server::new_async().await;
+        let failed = failed_exchange_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url())
+            .with_capabilities(&[])
+            .with_in_memory_creds("test-client-id".to_string(), read_only_auth())
+            .interactive(false)
+            .build()
+            .await
+            .unwrap();
+        // Not allowed to ask, the caller is told what is missing
+        let control = [Capability::ControlPlayback];
+        let err = client.ensure_scopes(&control).await.unwrap_err();
+        let missing = err.downcast_ref::<MissingScopes>().unwrap();
+        assert_eq!(missing.missing, ["user-modify-playback-state"]);
+        let scopes = requested_scopes(&client.begin_authorization().unwrap());
+        assert!(scopes
+            .iter()
+            .any(|scope| scope == "user-modify-playback-state"));
+        assert!(client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .await
+            .is_err());
+        failed.assert_async().await;
+        let kept = client.shared_auth().snapshot().unwrap();
+        assert_eq!(kept.refresh_token, "test-refresh-token");
+        assert_eq!(kept.scope, read_only_auth().scope);
+
+        failed.remove_async().await;
+        let exchange = token_mock(&mut server).create_async().await;
+        client.begin_authorization().unwrap();
+        client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .await
+            .unwrap();
+        exchange.assert_async().await;
+        let auth = client.shared_auth().snapshot().unwrap();
+        assert_eq!(auth.refresh_token, "new-refresh-token");
+    }
+
+    const CUSTOM_REDIRECT_URI: &str = "myapp://callback";
+
+    /// The exchange of a code redirected to [CUSTOM_REDIRECT_URI].
+    fn custom_scheme_token_mock(server: &mut mockito::Server) -> mockito::Mock {
+        token_mock(server).match_body(mockito::Matcher::AllOf(vec![
+            mockito::Matcher::UrlEncoded("code".into(), "custom-code".into()),
+            mockito::Matcher::UrlEncoded("redirect_uri".into(), CUSTOM_REDIRECT_URI.into()),
+        ]))
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_authorizing_with_a_custom_scheme_redirect() {
+        let mut server = mockito::Server::new_async().await;
+        let exchange = custom_scheme_token_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url())
+            .with_redirect_uri(CUSTOM_REDIRECT_URI)
+            .build()
+            .await
+            .unwrap();
+        let url = Url::parse(&client.begin_authorization().unwrap()).unwrap();
+        assert!(url
+            .query_pairs()
+            .any(|(key, value)| key == "redirect_uri" && value == CUSTOM_REDIRECT_URI));
+
+        // Pasted from a redirect to the default URI, it's the wrong one
+        assert!(client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .await
+            .is_err());
+        client.begin_authorization().unwrap();
+        client
+            .complete_authorization_from_url("myapp://callback?code=custom-code&state=abc")
+            .await
+            .unwrap();
+        exchange.assert_async().await;
+        assert_eq!(client.effective_config().redirect_uri, CUSTOM_REDIRECT_URI);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_authorizing_with_a_custom_scheme_redirect() {
+        let mut server = mockito::Server::new();
+        let exchange = custom_scheme_token_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url())
+            .with_redirect_uri(CUSTOM_REDIRECT_URI)
+            .build()
+            .unwrap();
+        let url = Url::parse(&client.begin_authorization().unwrap()).unwrap();
+        assert!(url
+            .query_pairs()
+            .any(|(key, value)| key == "redirect_uri" && value == CUSTOM_REDIRECT_URI));
+
+        // Pasted from a redirect to the default URI, it's the wrong one
+        assert!(client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .is_err());
+        client.begin_authorization().unwrap();
+        client
+            .complete_authorization_from_url("myapp://callback?code=custom-code&state=abc")
+            .unwrap();
+        exchange.assert();
+        assert_eq!(client.effective_config().redirect_uri, CUSTOM_REDIRECT_URI);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_reauthorizing_keeps_the_old_tokens_until_it_succeeds() {
+        let mut server = mockito::Server::new();
+        let failed = failed_exchange_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url())
+            .with_capabilities(&[])
+            .with_in_memory_creds("test-client-id".to_string(), read_only_auth())
+            .interactive(false)
+            .build()
+            .unwrap();
+        // Not allowed to ask, the caller is told what is missing
+        let control = [Capability::ControlPlayback];
+        let err = client.ensure_scopes(&control).unwrap_err();
+        let missing = err.downcast_ref::<MissingScopes>().unwrap();
+        assert_eq!(missing.missing, ["user-modify-playback-state"]);
+        let scopes = requested_scopes(&client.begin_authorization().unwrap());
+        assert!(scopes
+            .iter()
+            .any(|scope| scope == "user-modify-playback-state"));
+        assert!(client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .is_err());
+        failed.assert();
+        let kept = client.shared_auth().snapshot().unwrap();
+        assert_eq!(kept.refresh_token, "test-refresh-token");
+        assert_eq!(kept.scope, read_only_auth().scope);
+
+        failed.remove();
+        let exchange = token_mock(&mut server).create();
+        client.begin_authorization().unwrap();
+        client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .unwrap();
+        exchange.assert();
+        let auth = client.shared_auth().snapshot().unwrap();
+        assert_eq!(auth.refresh_token, "new-refresh-token");
+    }
+
+    /// base64 of `test-client-id:test-secret`
+    const BASIC_AUTH: &str = "Basic dGVzdC1jbGllbnQtaWQ6dGVzdC1zZWNyZXQ=";
+
+    /// A token request of a confidential app, authenticated by the secret
+    /// instead of a client id or PKCE verifier in the form.
+    fn confidential_token_mock(server: &mut mockito::Server, grant_type: &str) -> mockito::Mock {
+        let body = match grant_type {
+            "client_credentials" => {
+                r#"{"access_token": "app-access-token", "token_type": "Bearer", "expires_in": 1}"#
+            }
+            // Spotify often keeps the refresh token, and leaves it out
+            "refresh_token" => {
+                r#"{"access_token": "new-access-token", "token_type": "Bearer",
+                    "scope": "user-read-playback-state", "expires_in": 3600}"#
+            }
+            _ => {
+                r#"{"access_token": "new-access-token", "token_type": "Bearer",
+                    "scope": "user-read-playback-state", "expires_in": 3600,
+                    "refresh_token": "new-refresh-token"}"#
+            }
+        };
+        server
+            .mock("POST", "/api/token")
+            .match_header("authorization", BASIC_AUTH)
+            .match_body(mockito::Matcher::UrlEncoded(
+                "grant_type".into(),
+                grant_type.into(),
+            ))
+            .match_request(|request| {
+                let body = request.utf8_lossy_body().unwrap();
+                !body.contains("client_id") && !body.contains("code_verifier")
+            })
+            .with_body(body)
+    }
+
+    fn confidential_client_builder(server_url: &str) -> SpotifyClientBuilder {
+        mock_client_builder(server_url).with_client_secret("test-secret".to_string())
+    }
+
+    fn new_releases_mock(server: &mut mockito::Server, access_token: &str) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/browse/new-releases")
+            .match_query(mockito::Matcher::Any)
+            .match_header("authorization", format!("Bearer {access_token}").as_str())
+            .with_body_from_file("sample_data/new_releases.json")
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_confidential_app_authenticates_with_its_secret() {
+        let mut server = mockito::Server::new_async().await;
+        let exchange = confidential_token_mock(&mut server, "authorization_code")
+            .create_async()
+            .await;
+        let mut client = confidential_client_builder(&server.url())
+            .build()
+            .await
+            .unwrap();
+        let auth_url = client.begin_authorization().unwrap();
+        assert!(!auth_url.contains("code_challenge"));
+        client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .await
+            .unwrap();
+        exchange.assert_async().await;
+
+        // Refreshes too
+        let refresh = confidential_token_mock(&mut server, "refresh_token")
+            .create_async()
+            .await;
+        let _releases = new_releases_mock(&mut server, "new-access-token")
+            .create_async()
+            .await;
+        client.shared_auth().set(Some(expired_user_auth()));
+        client.get_new_releases(None, 20, 0).await.unwrap();
+        refresh.assert_async().await;
+        let auth = client.shared_auth().snapshot().unwrap();
+        assert_eq!(auth.access_token, "new-access-token");
+        assert_eq!(auth.refresh_token, "test-refresh-token");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_confidential_app_authenticates_with_its_secret() {
+        let mut server = mockito::Server::new();
+        let exchange = confidential_token_mock(&mut server, "authorization_code").create();
+        let mut client = confidential_client_builder(&server.url()).build().unwrap();
+        let auth_url = client.begin_authorization().unwrap();
+        assert!(!auth_url.contains("code_challenge"));
+        client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .unwrap();
+        exchange.assert();
+
+        // Refreshes too
+        let refresh = confidential_token_mock(&mut server, "refresh_token").create();
+        let _releases = new_releases_mock(&mut server, "new-access-token").create();
+        client.shared_auth().set(Some(expired_user_auth()));
+        client.get_new_releases(None, 20, 0).unwrap();
+        refresh.assert();
+        let auth = client.shared_auth().snapshot().unwrap();
+        assert_eq!(auth.access_token, "new-access-token");
+        assert_eq!(auth.refresh_token, "test-refresh-token");
+    }
+
+    fn assert_needs_user(err: anyhow::Error) {
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::UserAuthRequired)
+        );
+    }
+
+    #[test]
+    fn test_needs_user() {
+        assert!(needs_user(&Method::GET, "/me"));
+        assert!(needs_user(&Method::GET, "/me?fields=id"));
+        assert!(needs_user(&Method::GET, CUR_PLAYING_API_PATH));
+        assert!(needs_user(&Method::PUT, "/playlists/37i9/followers"));
+        assert!(!needs_user(&Method::GET, "/browse/new-releases?limit=20"));
+        assert!(!needs_user(&Method::GET, "/shows/5CfC"));
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_app_only() {
+        let mut server = mockito::Server::new_async().await;
+        // The token lives a second, it is requested again before the second call
+        let token = confidential_token_mock(&mut server, "client_credentials")
+            .expect(3)
+            .create_async()
+            .await;
+        let releases = new_releases_mock(&mut server, "app-access-token")
+            .expect(2)
+            .create_async()
+            .await;
+
+        // A public app has no secret to ask with
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert!(client.app_only().await.is_err());
+        let mut client = confidential_client_builder(&server.url())
+            .build()
+            .await
+            .unwrap();
+        let user_auth = client.shared_auth();
+        client.app_only().await.unwrap();
+        assert!(client.is_app_only());
+        client.get_new_releases(None, 20, 0).await.unwrap();
+        client.get_new_releases(None, 20, 0).await.unwrap();
+        token.assert_async().await;
+        releases.assert_async().await;
+
+        assert_needs_user(client.get_currently_playing_track().await.unwrap_err());
+        assert_needs_user(client.get_user_profile().await.unwrap_err());
+        // The user's tokens are left alone
+        assert_eq!(user_auth.access_token().unwrap(), "test-access-token");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_app_only() {
+        let mut server = mockito::Server::new();
+        // The token lives a second, it is requested again before the second call
+        let token = confidential_token_mock(&mut server, "client_credentials")
+            .expect(3)
+            .create();
+        let releases = new_releases_mock(&mut server, "app-access-token")
+            .expect(2)
+            .create();
+
+        // A public app has no secret to ask with
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert!(client.app_only().is_err());
+        let mut client = confidential_client_builder(&server.url()).build().unwrap();
+        let user_auth = client.shared_auth();
+        client.app_only().unwrap();
+        assert!(client.is_app_only());
+        client.get_new_releases(None, 20, 0).unwrap();
+        client.get_new_releases(None, 20, 0).unwrap();
+        token.assert();
+        releases.assert();
+
+        assert_needs_user(client.get_currently_playing_track().unwrap_err());
+        assert_needs_user(client.get_user_profile().unwrap_err());
+        // The user's tokens are left alone
+        assert_eq!(user_auth.access_token().unwrap(), "test-access-token");
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_complete_authorization_from_url() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = token_mock(&mut server).create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert!(client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .await
+            .is_err());
+
+        let auth_url = client.begin_authorization().unwrap();
+        assert!(auth_url.starts_with(&format!("{}/authorize?", server.url())));
+        client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .await
+            .unwrap();
+        mock.assert_async().await;
+        assert_eq!(client.access_token(), "new-access-token");
+        assert!(client.pending_code_verifier().is_none());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_complete_authorization_from_url() {
+        let mut server = mockito::Server::new();
+        let mock = token_mock(&mut server).create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert!(client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .is_err());
+
+        let auth_url = client.begin_authorization().unwrap();
+        assert!(auth_url.starts_with(&format!("{}/authorize?", server.url())));
+        client
+            .complete_authorization_from_url(REDIRECTED_URL)
+            .unwrap();
+        mock.assert();
+        assert_eq!(client.access_token(), "new-access-token");
+        assert!(client.pending_code_verifier().is_none());
+    }
+
+    fn me_mock(server: &mut mockito::Server, access_token: &str, status: usize) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/me")
+            .match_header("authorization", format!("Bearer {access_token}").as_str())
+            .with_status(status)
+            .with_body(r#"{"id": "tester"}"#)
+    }
+
+    fn refresh_mock(server: &mut mockito::Server, status: usize) -> mockito::Mock {
+        let body = match status {
+            200 => {
+                r#"{"access_token": "new-access-token", "token_type": "Bearer",
+                    "scope": "user-read-playback-state", "expires_in": 3600,
+                    "refresh_token": "new-refresh-token"}"#
+            }
+            _ => r#"{"error": "invalid_grant", "error_description": "Refresh token revoked"}"#,
+        };
+        server
+            .mock("POST", "/api/token")
+            .match_body(mockito::Matcher::UrlEncoded(
+                "grant_type".into(),
+                "refresh_token".into(),
+            ))
+            .with_status(status)
+            .with_body(body)
+    }
+
+    fn track_ids(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("track{i}")).collect()
+    }
+
+    /// A library change of `ids`, which Spotify must receive as a JSON body.
+    fn saved_tracks_mock(
+        server: &mut mockito::Server,
+        method: &str,
+        ids: &[String],
+        status: usize,
+    ) -> mockito::Mock {
+        server
+            .mock(method, "/v1/me/tracks")
+            .match_header("content-type", "application/json")
+            .match_body(mockito::Matcher::Json(serde_json::json!({ "ids": ids })))
+            .with_status(status)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_save_and_remove_tracks() {
+        let mut server = mockito::Server::new_async().await;
+        let ids = track_ids(51);
+        let first_batch = saved_tracks_mock(&mut server, "PUT", &ids[..50], 200)
+            .create_async()
+            .await;
+        let second_batch = saved_tracks_mock(&mut server, "PUT", &ids[50..], 200)
+            .create_async()
+            .await;
+        let removed = saved_tracks_mock(&mut server, "DELETE", &ids[..1], 204)
+            .create_async()
+            .await;
+        let untouched = server
+            .mock("PUT", "/v1/me/tracks")
+            .expect(0)
+            .create_async()
+            .await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        client.save_tracks(&ids).await.unwrap();
+        client.remove_saved_tracks(&ids[..1]).await.unwrap();
+        assert!(client.save_tracks(&[]).await.is_err());
+        first_batch.assert_async().await;
+        second_batch.assert_async().await;
+        removed.assert_async().await;
+        untouched.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_save_and_remove_tracks() {
+        let mut server = mockito::Server::new();
+        let ids = track_ids(51);
+        let first_batch = saved_tracks_mock(&mut server, "PUT", &ids[..50], 200).create();
+        let second_batch = saved_tracks_mock(&mut server, "PUT", &ids[50..], 200).create();
+        let removed = saved_tracks_mock(&mut server, "DELETE", &ids[..1], 204).create();
+        let untouched = server.mock("PUT", "/v1/me/tracks").expect(0).create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        client.save_tracks(&ids).unwrap();
+        client.remove_saved_tracks(&ids[..1]).unwrap();
+        assert!(client.save_tracks(&[]).is_err());
+        first_batch.assert();
+        second_batch.assert();
+        removed.assert();
+        untouched.assert();
+    }
+
+    const BOWIE: &str = "0oSGxfWSnnOXhD2fKuz2Gy";
+    const UNKNOWN_ARTIST: &str = "0000000000000000000000";
+
+    /// A cache that only has The Beatles, fetched just now.
+    fn beatles_cache(name: &str) -> ArtistCache {
+        let path = std::env::temp_dir().join(format!("{name}-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let data = std::fs::read_to_string("sample_data/followed_artists.json").unwrap();
+        let followed: FollowedArtists = serde_json::from_str(&data).unwrap();
+        let mut cache = ArtistCache::load(path).unwrap();
+        cache.insert(&followed.artists.items[0], SystemTime::now());
+        cache
+    }
+
+    fn artists_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/artists")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "ids".into(),
+                format!("{BOWIE},{UNKNOWN_ARTIST}"),
+            ))
+            .with_body_from_file("sample_data/artists.json")
+            .expect(1)
+    }
+
+    fn assert_artists(artists: &[CachedArtist], cache: &ArtistCache) {
+        let names: Vec<&str> = artists.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["The Beatles", "David Bowie", "David Bowie"]);
+        assert_eq!(artists[1].popularity, Some(75));
+        assert_eq!(cache.counters(), CacheCounters { hits: 3, misses: 2 });
+        // Fetched artists made it to the file
+        let saved = ArtistCache::load(cache.path()).unwrap();
+        assert!(saved.get(BOWIE).is_some());
+        std::fs::remove_file(cache.path()).unwrap();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_get_or_fetch_artists_only_fetches_misses() {
+        let mut server = mockito::Server::new_async().await;
+        let artists = artists_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let mut cache = beatles_cache("artists-async");
+
+        let beatles = "3WrFJ7ztbogyGnTHbHJFl2";
+        let ids = [beatles, BOWIE, UNKNOWN_ARTIST, BOWIE];
+        client.get_or_fetch_artists(&mut cache, &ids).await.unwrap();
+        // Both are cached now, nothing is fetched
+        let fetched = client
+            .get_or_fetch_artists(&mut cache, &[beatles, BOWIE, BOWIE])
+            .await
+            .unwrap();
+        artists.assert_async().await;
+        assert_artists(&fetched, &cache);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_get_or_fetch_artists_only_fetches_misses() {
+        let mut server = mockito::Server::new();
+        let artists = artists_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let mut cache = beatles_cache("artists-blocking");
+
+        let beatles = "3WrFJ7ztbogyGnTHbHJFl2";
+        let ids = [beatles, BOWIE, UNKNOWN_ARTIST, BOWIE];
+        client.get_or_fetch_artists(&mut cache, &ids).unwrap();
+        // Both are cached now, nothing is fetched
+        let fetched = client
+            .get_or_fetch_artists(&mut cache, &[beatles, BOWIE, BOWIE])
+            .unwrap();
+        artists.assert();
+        assert_artists(&fetched, &cache);
+    }
+
+    fn playlist_edit_mock(
+        server: &mut mockito::Server,
+        method: &str,
+        body: serde_json::Value,
+        status: usize,
+        answer: &str,
+    ) -> mockito::Mock {
+        server
+            .mock(method, "/v1/playlists/pl1/tracks")
+            .match_body(mockito::Matcher::Json(body))
+            .with_status(status)
+            .with_body(answer)
+    }
+
+    fn uris(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("spotify:track:t{i}")).collect()
+    }
+
+    const STALE_SNAPSHOT: &str = r#"{"error": {"status": 400, "message": "Invalid snapshot id"}}"#;
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_playlist_edits_thread_snapshots() {
+        let mut server = mockito::Server::new_async().await;
+        let uris = uris(101);
+        let first = remove_tracks_body(
+            &uris[..100].iter().map(String::as_str).collect::<Vec<_>>(),
+            Some("s0"),
+        );
+        let second = remove_tracks_body(&[uris[100].as_str()], Some("s1"));
+        let first = playlist_edit_mock(
+            &mut server,
+            "DELETE",
+            first,
+            200,
+            r#"{"snapshot_id": "s1"}"#,
+        )
+        .create_async()
+        .await;
+        let second = playlist_edit_mock(
+            &mut server,
+            "DELETE",
+            second,
+            200,
+            r#"{"snapshot_id": "s2"}"#,
+        )
+        .create_async()
+        .await;
+        let stale = playlist_edit_mock(
+            &mut server,
+            "PUT",
+            reorder_body(0, 3, 1, Some("s0")),
+            400,
+            STALE_SNAPSHOT,
+        )
+        .create_async()
+        .await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+
+        let uris: Vec<&str> = uris.iter().map(String::as_str).collect();
+        let snapshot = client
+            .remove_tracks_from_playlist("pl1", &uris, Some("s0"))
+            .await
+            .unwrap();
+        assert_eq!(snapshot, "s2");
+        let err = client
+            .reorder_playlist("pl1", 0, 3, 1, Some("s0"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::SnapshotOutdated)
+        );
+        first.assert_async().await;
+        second.assert_async().await;
+        stale.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_playlist_edits_thread_snapshots() {
+        let mut server = mockito::Server::new();
+        let uris = uris(101);
+        let first = remove_tracks_body(
+            &uris[..100].iter().map(String::as_str).collect::<Vec<_>>(),
+            Some("s0"),
+        );
+        let second = remove_tracks_body(&[uris[100].as_str()], Some("s1"));
+        let first = playlist_edit_mock(
+            &mut server,
+            "DELETE",
+            first,
+            200,
+            r#"{"snapshot_id": "s1"}"#,
+        )
+        .create();
+        let second = playlist_edit_mock(
+            &mut server,
+            "DELETE",
+            second,
+            200,
+            r#"{"snapshot_id": "s2"}"#,
+        )
+        .create();
+        let stale = playlist_edit_mock(
+            &mut server,
+            "PUT",
+            reorder_body(0, 3, 1, Some("s0")),
+            400,
+            STALE_SNAPSHOT,
+        )
+        .create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+
+        let uris: Vec<&str> = uris.iter().map(String::as_str).collect();
+        let snapshot = client
+            .remove_tracks_from_playlist("pl1", &uris, Some("s0"))
+            .unwrap();
+        assert_eq!(snapshot, "s2");
+        let err = client
+            .reorder_playlist("pl1", 0, 3, 1, Some("s0"))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::SnapshotOutdated)
+        );
+        first.assert();
+        second.assert();
+        stale.assert();
+    }
+
+    const DIVE_IN: &str = "6mFkJmJqdDVQ1REhVfGgd1";
+    const TEXAS: &str = "4N1MFKjziFHH4IS3RYYUrU";
+    const HEROES: &str = "4iV5W9uYEdYUVa79Axb7Rh";
+    const GABRIEL_HEROES: &str = "7Jh1bpe76CNTCgdgAdBw4Z";
+
+    fn playlist_page(ids: &[&str]) -> String {
+        let items: Vec<_> = ids
+            .iter()
+            .map(|id| serde_json::json!({ "is_local": false, "track": { "type": "track", "id": id } }))
+            .collect();
+        serde_json::json!({ "items": items, "limit": 100, "offset": 0, "total": ids.len(), "next": null })
+            .to_string()
+    }
+
+    fn playlist_page_mock(
+        server: &mut mockito::Server,
+        playlist: &str,
+        offset: &str,
+    ) -> mockito::Mock {
+        server
+            .mock("GET", format!("/v1/playlists/{playlist}/tracks").as_str())
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("limit".into(), "100".into()),
+                mockito::Matcher::UrlEncoded("offset".into(), offset.into()),
+            ]))
+    }
+
+    /// pl1 spans two pages, pl2 shares a track with it. Only Texas Is
+    /// Forever is saved already.
+    fn library_import_mocks(server: &mut mockito::Server) -> Vec<mockito::Mock> {
+        vec![
+            playlist_page_mock(server, "pl1", "0")
+                .with_body_from_file("sample_data/playlist_items.json"),
+            playlist_page_mock(server, "pl1", "5").with_body(playlist_page(&[HEROES])),
+            playlist_page_mock(server, "pl2", "0")
+                .with_body(playlist_page(&[TEXAS, GABRIEL_HEROES])),
+            server
+                .mock("GET", "/v1/me/tracks/contains")
+                .match_query(mockito::Matcher::UrlEncoded(
+                    "ids".into(),
+                    [DIVE_IN, TEXAS, HEROES, GABRIEL_HEROES].join(","),
+                ))
+                .with_body("[false, true, false, false]"),
+            server
+                .mock("PUT", "/v1/me/tracks")
+                .match_body(mockito::Matcher::Json(
+                    serde_json::json!({ "ids": [DIVE_IN, HEROES, GABRIEL_HEROES] }),
+                ))
+                .with_status(200),
+        ]
+    }
+
+    fn assert_import_plan(plan: &ImportPlan) {
+        assert_eq!(plan.found, 4);
+        assert_eq!(plan.already_saved, 1);
+        assert_eq!(plan.to_save, [DIVE_IN, HEROES, GABRIEL_HEROES]);
+    }
+
+    fn import_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_library_import_saves_only_missing_tracks() {
+        let mut server = mockito::Server::new_async().await;
+        let mut mocks = Vec::new();
+        for mock in library_import_mocks(&mut server) {
+            mocks.push(mock.create_async().await);
+        }
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+
+        let plan = client.plan_library_import(&["pl1", "pl2"]).await.unwrap();
+        assert_import_plan(&plan);
+        let path = import_path("library-import-async");
+        let mut import = LibraryImport::start(&path, &plan).unwrap();
+        let mut reported = Vec::new();
+        client
+            .run_library_import(&mut import, |import| reported.push(import.saved()))
+            .await
+            .unwrap();
+        assert_eq!(reported, [3]);
+        assert!(!path.exists());
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_library_import_saves_only_missing_tracks() {
+        let mut server = mockito::Server::new();
+        let mocks: Vec<_> = library_import_mocks(&mut server)
+            .into_iter()
+            .map(mockito::Mock::create)
+            .collect();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+
+        let plan = client.plan_library_import(&["pl1", "pl2"]).unwrap();
+        assert_import_plan(&plan);
+        let path = import_path("library-import-blocking");
+        let mut import = LibraryImport::start(&path, &plan).unwrap();
+        let mut reported = Vec::new();
+        client
+            .run_library_import(&mut import, |import| reported.push(import.saved()))
+            .unwrap();
+        assert_eq!(reported, [3]);
+        assert!(!path.exists());
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    /// Two pages of followed artists, the second after the sample's cursor,
+    /// and a single playlist, pl2, whose tracks span two pages.
+    fn library_snapshot_mocks(server: &mut mockito::Server) -> Vec<mockito::Mock> {
+        let playing: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap(),
+        )
+        .unwrap();
+        let last_tracks = serde_json::json!({
+            "items": [{ "is_local": false, "track": playing["item"] }],
+            "limit": 50, "offset": 5, "total": 6, "next": null,
+        });
+        let mut tracks_page = |offset: &str| {
+            server
+                .mock("GET", "/v1/playlists/pl2/tracks")
+                .match_query(mockito::Matcher::AllOf(vec![
+                    mockito::Matcher::UrlEncoded("limit".into(), "50".into()),
+                    mockito::Matcher::UrlEncoded("offset".into(), offset.into()),
+                ]))
+        };
+        let first_tracks = tracks_page("0").with_body_from_file("sample_data/playlist_items.json");
+        let last_tracks = tracks_page("5").with_body(last_tracks.to_string());
+        let last_artist = serde_json::json!({ "artists": {
+            "items": [{ "name": "Pierce The Veil", "id": "4iJLPqClelZOBCBifm8Fzv" }],
+            "limit": 50, "next": null, "cursors": { "after": null }, "total": 3,
+        }});
+        let playlists = serde_json::json!({
+            "items": [{
+                "id": "pl2", "name": "Heroes", "snapshot_id": "snap2",
+                "owner": { "id": "tester" },
+            }],
+            "limit": 50, "offset": 0, "total": 1, "next": null,
+        });
+        vec![
+            server
+                .mock("GET", "/v1/me/following")
+                .match_query(mockito::Matcher::Regex("^type=artist&limit=50$".into()))
+                .with_body_from_file("sample_data/followed_artists.json"),
+            server
+                .mock("GET", "/v1/me/following")
+                .match_query(mockito::Matcher::UrlEncoded(
+                    "after".into(),
+                    "0oSGxfWSnnOXhD2fKuz2Gy".into(),
+                ))
+                .with_body(last_artist.to_string()),
+            server
+                .mock("GET", "/v1/me/playlists")
+                .match_query(mockito::Matcher::Regex("^limit=50&offset=0$".into()))
+                .with_body(playlists.to_string()),
+            first_tracks,
+            last_tracks,
+        ]
+    }
+
+    fn assert_library_snapshot(snapshot: &LibrarySnapshot) {
+        assert_eq!(snapshot.followed_artists.len(), 3);
+        assert_eq!(
+            snapshot.artists["4iJLPqClelZOBCBifm8Fzv"].name,
+            "Pierce The Veil"
+        );
+        assert_eq!(snapshot.playlists[0].id, "pl2");
+        let tracks = ["6mFkJmJqdDVQ1REhVfGgd1", TEXAS, "1VY823dFzI9L8BEf2X7B5I"];
+        assert_eq!(snapshot.playlists[0].track_ids, tracks);
+        // Written with their album and artists like saved tracks
+        assert_eq!(snapshot.tracks[TEXAS].name, "Texas Is Forever");
+        assert_eq!(snapshot.tracks.len(), 3);
+        assert!(snapshot
+            .albums
+            .contains_key(&snapshot.tracks[TEXAS].album_id));
+        let counts: Vec<usize> = snapshot.manifest.sections.iter().map(|s| s.count).collect();
+        assert_eq!(counts, [3, 1]);
+    }
+
+    const SNAPSHOT_SECTIONS: [SnapshotSection; 2] =
+        [SnapshotSection::FollowedArtists, SnapshotSection::Playlists];
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_library_snapshot() {
+        let mut server = mockito::Server::new_async().await;
+        let mut mocks = Vec::new();
+        for mock in library_snapshot_mocks(&mut server) {
+            mocks.push(mock.create_async().await);
+        }
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+
+        let path = import_path("library-snapshot-async");
+        let mut run = SnapshotRun::start(&path, &SNAPSHOT_SECTIONS).unwrap();
+        let mut pages = 0;
+        client
+            .run_library_snapshot(&mut run, |_| pages += 1)
+            .await
+            .unwrap();
+        assert_eq!(pages, 5);
+        let out = import_path("library-snapshot-async-out");
+        assert_library_snapshot(&run.finish(&out).unwrap());
+        assert!(!path.exists());
+        std::fs::remove_file(&out).unwrap();
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_library_snapshot() {
+        let mut server = mockito::Server::new();
+        let mocks: Vec<_> = library_snapshot_mocks(&mut server)
+            .into_iter()
+            .map(mockito::Mock::create)
+            .collect();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+
+        let path = import_path("library-snapshot-blocking");
+        let mut run = SnapshotRun::start(&path, &SNAPSHOT_SECTIONS).unwrap();
+        let mut pages = 0;
+        client
+            .run_library_snapshot(&mut run, |_| pages += 1)
+            .unwrap();
+        assert_eq!(pages, 5);
+        let out = import_path("library-snapshot-blocking-out");
+        assert_library_snapshot(&run.finish(&out).unwrap());
+        assert!(!path.exists());
+        std::fs::remove_file(&out).unwrap();
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    fn search_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/search")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("q".into(), "heroes & villains".into()),
+                mockito::Matcher::UrlEncoded("type".into(), "track".into()),
+                mockito::Matcher::UrlEncoded("limit".into(), "3".into()),
+            ]))
+            .with_body_from_file("sample_data/search_tracks.json")
+    }
+
+    fn add_to_queue_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("POST", "/v1/me/player/queue")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "uri".into(),
+                "spotify:track:4iV5W9uYEdYUVa79Axb7Rh".into(),
+            ))
+            .with_status(204)
+    }
+
+    fn add_to_playlist_mock(server: &mut mockito::Server) -> mockito::Mock {
+        playlist_edit_mock(
+            server,
+            "POST",
+            serde_json::json!({ "uris": ["spotify:track:4iV5W9uYEdYUVa79Axb7Rh"] }),
+            201,
+            r#"{"snapshot_id": "s1"}"#,
+        )
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_searched_track_is_queued_and_added() {
+        let mut server = mockito::Server::new_async().await;
+        let search = search_mock(&mut server).create_async().await;
+        let queue = add_to_queue_mock(&mut server).create_async().await;
+        let add = add_to_playlist_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+
+        let tracks = client.search_tracks("heroes & villains", 3).await.unwrap();
+        assert_eq!(tracks.len(), 3);
+        let uri = tracks[0].uri();
+        client.add_to_queue(&uri).await.unwrap();
+        let snapshot = client
+            .add_tracks_to_playlist("pl1", &[uri.as_str()])
+            .await
+            .unwrap();
+        assert_eq!(snapshot, "s1");
+        assert!(client.search_tracks(" ", 3).await.is_err());
+        assert!(client.search_tracks("heroes", 51).await.is_err());
+        search.assert_async().await;
+        queue.assert_async().await;
+        add.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_searched_track_is_queued_and_added() {
+        let mut server = mockito::Server::new();
+        let search = search_mock(&mut server).create();
+        let queue = add_to_queue_mock(&mut server).create();
+        let add = add_to_playlist_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+
+        let tracks = client.search_tracks("heroes & villains", 3).unwrap();
+        assert_eq!(tracks.len(), 3);
+        let uri = tracks[0].uri();
+        client.add_to_queue(&uri).unwrap();
+        let snapshot = client
+            .add_tracks_to_playlist("pl1", &[uri.as_str()])
+            .unwrap();
+        assert_eq!(snapshot, "s1");
+        assert!(client.search_tracks(" ", 3).is_err());
+        assert!(client.search_tracks("heroes", 51).is_err());
+        search.assert();
+        queue.assert();
+        add.assert();
+    }
+
+    #[test]
+    fn test_playlist_edit_bodies() {
+        assert_eq!(
+            remove_tracks_body(&["spotify:track:a"], None),
+            serde_json::json!({ "tracks": [{ "uri": "spotify:track:a" }] })
+        );
+        assert_eq!(
+            reorder_body(4, 0, 2, Some("s0")),
+            serde_json::json!({
+                "range_start": 4, "insert_before": 0, "range_length": 2, "snapshot_id": "s0"
+            })
+        );
+    }
+
+    const STRESS_CLIENTS: usize = 4;
+    const STRESS_REQUESTS: usize = 3;
+
+    /// The first devices request is rate limited, every other one succeeds.
+    fn rate_limited_mocks(server: &mut mockito::Server) -> (mockito::Mock, mockito::Mock) {
+        let path = format!("/v1{DEVICES_API_PATH}");
+        let limited = server
+            .mock("GET", path.as_str())
+            .with_status(429)
+            .with_header("Retry-After", "1")
+            .expect(1);
+        let devices = server
+            .mock("GET", path.as_str())
+            .with_status(200)
+            .with_body(std::fs::read_to_string("sample_data/devices.json").unwrap())
+            .expect(STRESS_CLIENTS * STRESS_REQUESTS);
+        (limited, devices)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_clients_share_rate_limit() {
+        let mut server = mockito::Server::new_async().await;
+        let (limited, devices) = rate_limited_mocks(&mut server);
+        let (limited, devices) = (limited.create_async().await, devices.create_async().await);
+        let limiter = Arc::new(RateLimiter::new(50.0, 8));
+
+        let started = std::time::Instant::now();
+        let mut tasks = Vec::new();
+        for _ in 0..STRESS_CLIENTS {
+            let builder = mock_client_builder(&server.url()).with_rate_limiter(limiter.clone());
+            tasks.push(tokio::spawn(async move {
+                let mut client = builder.build().await.unwrap();
+                for _ in 0..STRESS_REQUESTS {
+                    client.get_devices().await.unwrap();
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        limited.assert_async().await;
+        devices.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_clients_share_rate_limit() {
+        let mut server = mockito::Server::new();
+        let (limited, devices) = rate_limited_mocks(&mut server);
+        let (limited, devices) = (limited.create(), devices.create());
+        let limiter = Arc::new(RateLimiter::new(50.0, 8));
+
+        let started = std::time::Instant::now();
+        let handles: Vec<_> = (0..STRESS_CLIENTS)
+            .map(|_| {
+                let builder = mock_client_builder(&server.url()).with_rate_limiter(limiter.clone());
+                std::thread::spawn(move || {
+                    let mut client = builder.build().unwrap();
+                    for _ in 0..STRESS_REQUESTS {
+                        client.get_devices().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        limited.assert();
+        devices.assert();
+    }
+
+    /// A client of the user in `cache`, passed the expired tokens of [expired_user_auth].
+    fn cached_client_builder(server_url: &str, cache: &TokenCache) -> SpotifyClientBuilder {
+        mock_client_builder(server_url)
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .with_token_cache(cache.clone())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_clients_share_cached_tokens() {
+        let mut server = mockito::Server::new_async().await;
+        let refresh = refresh_mock(&mut server, 200).create_async().await;
+        let releases = new_releases_mock(&mut server, "new-access-token")
+            .expect(4)
+            .create_async()
+            .await;
+        let cache = TokenCache::new();
+        let mut first = cached_client_builder(&server.url(), &cache)
+            .build()
+            .await
+            .unwrap();
+        let mut second = cached_client_builder(&server.url(), &cache)
+            .build()
+            .await
+            .unwrap();
+
+        let (a, b) = tokio::join!(
+            first.get_new_releases(None, 20, 0),
+            second.get_new_releases(None, 20, 0)
+        );
+        a.unwrap();
+        b.unwrap();
+        refresh.assert_async().await;
+        assert_eq!(
+            second.shared_auth().snapshot().unwrap().refresh_token,
+            "new-refresh-token"
+        );
+
+        // A client built later gets the refreshed tokens, not the expired ones passed in
+        let mut late = cached_client_builder(&server.url(), &cache)
+            .build()
+            .await
+            .unwrap();
+        late.get_new_releases(None, 20, 0).await.unwrap();
+        refresh.assert_async().await;
+        // Without a cache a client refreshes its own
+        let mut alone = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .build()
+            .await
+            .unwrap();
+        alone.get_new_releases(None, 20, 0).await.unwrap();
+        releases.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_clients_share_cached_tokens() {
+        let mut server = mockito::Server::new();
+        let refresh = refresh_mock(&mut server, 200).create();
+        let releases = new_releases_mock(&mut server, "new-access-token")
+            .expect(2)
+            .create();
+
+        let cache = TokenCache::new();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let builder = cached_client_builder(&server.url(), &cache);
+                std::thread::spawn(move || {
+                    let mut client = builder.build().unwrap();
+                    client.get_new_releases(None, 20, 0).unwrap();
+                    client.shared_auth().snapshot().unwrap().refresh_token
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), "new-refresh-token");
+        }
+        refresh.assert();
+        releases.assert();
+    }
+
+    /// Collects everything logged while the guard lives, on this thread.
+    #[derive(Clone, Default)]
+    struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogBuffer {
+        fn capture() -> (LogBuffer, tracing::subscriber::DefaultGuard) {
+            let buffer = LogBuffer::default();
+            let writer = buffer.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish();
+            (buffer, tracing::subscriber::set_default(subscriber))
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    fn correlated_releases_mock(server: &mut mockito::Server) -> mockito::Mock {
+        new_releases_mock(server, "new-access-token").match_header("x-request-id", "trace-42")
+    }
+
+    fn uncorrelated_releases_mock(server: &mut mockito::Server) -> mockito::Mock {
+        new_releases_mock(server, "new-access-token")
+            .match_header("x-request-id", mockito::Matcher::Missing)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_requests_carry_the_correlation_id() {
+        let mut server = mockito::Server::new_async().await;
+        let refresh = refresh_mock(&mut server, 200)
+            .match_header("x-request-id", "trace-42")
+            .create_async()
+            .await;
+        let releases = correlated_releases_mock(&mut server).create_async().await;
+        let untagged = uncorrelated_releases_mock(&mut server).create_async().await;
+        let mut client = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .with_correlation_id_header("X-Request-Id")
+            .build()
+            .await
+            .unwrap();
+
+        let (logs, _guard) = LogBuffer::capture();
+        client
+            .with_correlation_id("trace-42")
+            .get_new_releases(None, 20, 0)
+            .await
+            .unwrap();
+        refresh.assert_async().await;
+        releases.assert_async().await;
+        assert!(logs.contents().contains("correlation_id=trace-42"));
+
+        client.get_new_releases(None, 20, 0).await.unwrap();
+        untagged.assert_async().await;
+
+        let invalid = mock_client_builder(&server.url())
+            .with_correlation_id_header("X Request Id")
+            .build()
+            .await;
+        assert!(invalid.is_err());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_requests_carry_the_correlation_id() {
+        let mut server = mockito::Server::new();
+        let refresh = refresh_mock(&mut server, 200)
+            .match_header("x-request-id", "trace-42")
+            .create();
+        let releases = correlated_releases_mock(&mut server).create();
+        let untagged = uncorrelated_releases_mock(&mut server).create();
+        let mut client = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .with_correlation_id_header("X-Request-Id")
+            .build()
+            .unwrap();
+
+        let (logs, _guard) = LogBuffer::capture();
+        client
+            .with_correlation_id("trace-42")
+            .get_new_releases(None, 20, 0)
+            .unwrap();
+        refresh.assert();
+        releases.assert();
+        assert!(logs.contents().contains("correlation_id=trace-42"));
+
+        client.get_new_releases(None, 20, 0).unwrap();
+        untagged.assert();
+
+        let invalid = mock_client_builder(&server.url())
+            .with_correlation_id_header("X Request Id")
+            .build();
+        assert!(invalid.is_err());
+    }
+
+    fn assert_token_exchange_masked(logs: &str) {
+        assert!(logs.contains("Request body: grant_type=refresh_token&refresh_token=<redacted>"));
+        assert!(logs.contains(r#""access_token":"<redacted>""#));
+        assert!(!logs.contains("new-access-token"));
+        assert!(!logs.contains("new-refresh-token"));
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_logged_token_exchange_is_masked() {
+        let mut server = mockito::Server::new_async().await;
+        let refresh = refresh_mock(&mut server, 200).create_async().await;
+        let mut client = mock_client_builder(&server.url())
+            .log_bodies(true)
+            .build()
+            .await
+            .unwrap();
+
+        let (logs, _guard) = LogBuffer::capture();
+        client.force_refresh_access_token().await.unwrap();
+        refresh.assert_async().await;
+        assert_token_exchange_masked(&logs.contents());
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_logged_token_exchange_is_masked() {
+        let mut server = mockito::Server::new();
+        let refresh = refresh_mock(&mut server, 200).create();
+        let mut client = mock_client_builder(&server.url())
+            .log_bodies(true)
+            .build()
+            .unwrap();
+
+        let (logs, _guard) = LogBuffer::capture();
+        client.force_refresh_access_token().unwrap();
+        refresh.assert();
+        assert_token_exchange_masked(&logs.contents());
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_rejected_token_is_refreshed() {
+        let mut server = mockito::Server::new_async().await;
+        let rejected = me_mock(&mut server, "test-access-token", 401)
+            .create_async()
+            .await;
+        let accepted = me_mock(&mut server, "new-access-token", 200)
+            .create_async()
+            .await;
+        let refresh = refresh_mock(&mut server, 200).create_async().await;
+
+        let mut client = mock_client_builder(&server.url())
+            .interactive(false)
+            .build()
+            .await
+            .unwrap();
+        client.setup_creds().await.unwrap();
+        rejected.assert_async().await;
+        accepted.assert_async().await;
+        refresh.assert_async().await;
+        assert_eq!(client.access_token(), "new-access-token");
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_rejected_token_is_refreshed() {
+        let mut server = mockito::Server::new();
+        let rejected = me_mock(&mut server, "test-access-token", 401).create();
+        let accepted = me_mock(&mut server, "new-access-token", 200).create();
+        let refresh = refresh_mock(&mut server, 200).create();
+
+        let mut client = mock_client_builder(&server.url())
+            .interactive(false)
+            .build()
+            .unwrap();
+        client.setup_creds().unwrap();
+        rejected.assert();
+        accepted.assert();
+        refresh.assert();
+        assert_eq!(client.access_token(), "new-access-token");
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_revoked_token_without_interaction() {
+        let mut server = mockito::Server::new_async().await;
+        let rejected = me_mock(&mut server, "test-access-token", 401)
+            .create_async()
+            .await;
+        let refresh = refresh_mock(&mut server, 400).create_async().await;
+
+        let mut client = mock_client_builder(&server.url())
+            .interactive(false)
+            .build()
+            .await
+            .unwrap();
+        let err = client.setup_creds().await.unwrap_err();
+        assert!(err.to_string().contains("run `auth`"));
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::RefreshTokenRevoked)
+        );
+        rejected.assert_async().await;
+        refresh.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_revoked_token_without_interaction() {
+        let mut server = mockito::Server::new();
+        let rejected = me_mock(&mut server, "test-access-token", 401).create();
+        let refresh = refresh_mock(&mut server, 400).create();
+
+        let mut client = mock_client_builder(&server.url())
+            .interactive(false)
+            .build()
+            .unwrap();
+        let err = client.setup_creds().unwrap_err();
+        assert!(err.to_string().contains("run `auth`"));
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::RefreshTokenRevoked)
+        );
+        rejected.assert();
+        refresh.assert();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_failed_check_is_not_an_authorization() {
+        let mut server = mockito::Server::new_async().await;
+        let forbidden = me_mock(&mut server, "test-access-token", 403)
+            .create_async()
+            .await;
+
+        let mut client = mock_client_builder(&server.url())
+            .interactive(true)
+            .build()
+            .await
+            .unwrap();
+        let err = client.setup_creds().await.unwrap_err();
+        assert!(err.to_string().contains("403"));
+        assert!(client.pending_code_verifier().is_none());
+        forbidden.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_failed_check_is_not_an_authorization() {
+        let mut server = mockito::Server::new();
+        let forbidden = me_mock(&mut server, "test-access-token", 403).create();
+
+        let mut client = mock_client_builder(&server.url())
+            .interactive(true)
+            .build()
+            .unwrap();
+        let err = client.setup_creds().unwrap_err();
+        assert!(err.to_string().contains("403"));
+        assert!(client.pending_code_verifier().is_none());
+        forbidden.assert();
+    }
+
+    #[test]
+    fn test_cursor_queries_are_encoded() {
+        assert_eq!(
+            recently_played_path(50, None).unwrap(),
+            "/me/player/recently-played?limit=50"
+        );
+        let after = Some((PageOrder::OldestFirst, "1727125800000"));
+        assert_eq!(
+            recently_played_path(20, after).unwrap(),
+            "/me/player/recently-played?limit=20&after=1727125800000"
+        );
+        let sneaky = Some((PageOrder::NewestFirst, "1&limit=50"));
+        assert_eq!(
+            recently_played_path(20, sneaky).unwrap(),
+            "/me/player/recently-played?limit=20&before=1%26limit%3D50"
+        );
+        assert_eq!(
+            followed_artists_path(50, Some("0TnOYISbd1XYRBk9myaseg")).unwrap(),
+            "/me/following?type=artist&limit=50&after=0TnOYISbd1XYRBk9myaseg"
+        );
+        assert_eq!(
+            followed_artists_path(50, Some("a b#c")).unwrap(),
+            "/me/following?type=artist&limit=50&after=a+b%23c"
+        );
+    }
+
+    #[test]
+    fn test_with_device_id() {
+        assert_eq!(with_device_id(PAUSE_API_PATH, None), "/me/player/pause");
+        assert_eq!(
+            with_device_id(PAUSE_API_PATH, Some("abc")),
+            "/me/player/pause?device_id=abc"
+        );
+        assert_eq!(
+            with_device_id("/me/player/play?market=ES", Some("abc")),
+            "/me/player/play?market=ES&device_id=abc"
+        );
+    }
+
+    fn pause_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("PUT", "/v1/me/player/pause")
+            .match_query(mockito::Matcher::UrlEncoded(
+                "device_id".into(),
+                "kitchen-speaker".into(),
+            ))
+            .with_status(204)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_preferred_device_is_targeted() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = pause_mock(&mut server).create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        client
+            .set_preferred_device("kitchen-speaker".to_string())
+            .await
+            .unwrap();
+        client.pause_playback().await.unwrap();
+        mock.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_preferred_device_is_targeted() {
+        let mut server = mockito::Server::new();
+        let mock = pause_mock(&mut server).create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        client
+            .set_preferred_device("kitchen-speaker".to_string())
+            .unwrap();
+        client.pause_playback().unwrap();
+        mock.assert();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_pausing_without_the_modify_scope_asks_for_it() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = pause_mock(&mut server).expect(0).create_async().await;
+        let mut client = mock_client_builder(&server.url())
+            .with_capabilities(&[])
+            .with_in_memory_creds("test-client-id".to_string(), read_only_auth())
+            .interactive(false)
+            .build()
+            .await
+            .unwrap();
+        client
+            .set_preferred_device("kitchen-speaker".to_string())
+            .await
+            .unwrap();
+
+        let err = client.pause_playback().await.unwrap_err();
+        let missing = err.downcast_ref::<MissingScopes>().unwrap();
+        assert_eq!(missing.missing, ["user-modify-playback-state"]);
+        mock.assert_async().await;
+        let scopes = requested_scopes(&client.begin_authorization().unwrap());
+        assert!(scopes
+            .iter()
+            .any(|scope| scope == "user-modify-playback-state"));
+    }
+
+    #[cfg(feature = "blocking")]
     #[test]
-    fn test_getting_code_from_params() {
-        let url = String::from("http://localhost:8080/?code=AQAJQs0ZXTxhvkRUMXn1PVLQQBw2VXSldRqfou5RPM_RPkHdexx7v7lUNcjXjWzPKFW3bxxPLuHCJqoQy6NbIr-70-ZpPszqktjxBgzqqmKLv653gjh_f_-ELVPdWscUvlNlICrcyUGtGPCIIdDLWHg9bVEsBMFtyrEtA8S6bYoUbC-3YhqhNr6GC90rM3AmmTUqhTC2jkINQ9aFMCalO2l34NLE9kXqIVe2hBMaEdOuBNfi3zXhdG0kulgAJ8a03nAVMs9HBJXKFzD5bVFvl7eXj3p6DwMOnQFxFJq9wJHbg57a507DPmVr8vO_nYRcr6uXhVgMEY4WkR0djj3CgeKSUNOVGB-VwUs8YcyZH-kfaUoeOsY-6hyiDUizDPGXorL0vskU7GmTGsat2UwsSkanGeJvr3BP9-GVVIQFcU91WNiG2rkAa8rIWJz_EgRtqco7yA");
-        let url = Url::parse(&url).unwrap();
-        let spotify_auth_code = get_code_from_query_pairs(url);
-        assert_eq!(spotify_auth_code, Some(String::from("AQAJQs0ZXTxhvkRUMXn1PVLQQBw2VXSldRqfou5RPM_RPkHdexx7v7lUNcjXjWzPKFW3bxxPLuHCJqoQy6NbIr-70-ZpPszqktjxBgzqqmKLv653gjh_f_-ELVPdWscUvlNlICrcyUGtGPCIIdDLWHg9bVEsBMFtyrEtA8S6bYoUbC-3YhqhNr6GC90rM3AmmTUqhTC2jkINQ9aFMCalO2l34NLE9kXqIVe2hBMaEdOuBNfi3zXhdG0kulgAJ8a03nAVMs9HBJXKFzD5bVFvl7eXj3p6DwMOnQFxFJq9wJHbg57a507DPmVr8vO_nYRcr6uXhVgMEY4WkR0djj3CgeKSUNOVGB-VwUs8YcyZH-kfaUoeOsY-6hyiDUizDPGXorL0vskU7GmTGsat2UwsSkanGeJvr3BP9-GVVIQFcU91WNiG2rkAa8rIWJz_EgRtqco7yA")));
+    fn test_pausing_without_the_modify_scope_asks_for_it() {
+        let mut server = mockito::Server::new();
+        let mock = pause_mock(&mut server).expect(0).create();
+        let mut client = mock_client_builder(&server.url())
+            .with_capabilities(&[])
+            .with_in_memory_creds("test-client-id".to_string(), read_only_auth())
+            .interactive(false)
+            .build()
+            .unwrap();
+        client
+            .set_preferred_device("kitchen-speaker".to_string())
+            .unwrap();
+
+        let err = client.pause_playback().unwrap_err();
+        let missing = err.downcast_ref::<MissingScopes>().unwrap();
+        assert_eq!(missing.missing, ["user-modify-playback-state"]);
+        mock.assert();
+        let scopes = requested_scopes(&client.begin_authorization().unwrap());
+        assert!(scopes
+            .iter()
+            .any(|scope| scope == "user-modify-playback-state"));
+    }
+
+    const SPEAKER_ID: &str = "3f228e06c8562e2f439e22932da6c3231715ed53";
+
+    fn transfer_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("PUT", "/v1/me/player")
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "device_ids": [SPEAKER_ID], "play": false }),
+            ))
+            .with_status(204)
+    }
+
+    fn player_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/me/player")
+            .match_query(mockito::Matcher::Any)
+    }
+
+    fn play_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server.mock("PUT", "/v1/me/player/play").with_status(204)
+    }
+
+    /// The speaker wakes up on the third check, after two without a device.
+    fn activation_mocks(server: &mut mockito::Server) -> [mockito::Mock; 2] {
+        [
+            player_mock(server).with_status(204).expect(2),
+            player_mock(server)
+                .with_body(std::fs::read_to_string("sample_data/playback_state.json").unwrap())
+                .expect(1),
+        ]
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_ensure_active_then() {
+        let mut server = mockito::Server::new_async().await;
+        let transfer = transfer_mock(&mut server).create_async().await;
+        let [waking, active] = activation_mocks(&mut server);
+        let waking = waking.create_async().await;
+        let active = active.create_async().await;
+        let play = play_mock(&mut server).create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        client
+            .ensure_active_then(SPEAKER_ID, async |client: &mut SpotifyClient| {
+                client.resume_playback().await
+            })
+            .await
+            .unwrap();
+        for mock in [transfer, waking, active, play] {
+            mock.assert_async().await;
+        }
+
+        // The speaker never shows up, the action is not run
+        let mut server = mockito::Server::new_async().await;
+        let transfer = transfer_mock(&mut server).create_async().await;
+        let asleep = player_mock(&mut server)
+            .with_status(204)
+            .expect(DEVICE_ACTIVATION_POLLS as usize)
+            .create_async()
+            .await;
+        let play = play_mock(&mut server).expect(0).create_async().await;
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let result = client
+            .ensure_active_then(SPEAKER_ID, async |client: &mut SpotifyClient| {
+                client.resume_playback().await
+            })
+            .await;
+        assert!(result.is_err());
+        for mock in [transfer, asleep, play] {
+            mock.assert_async().await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_ensure_active_then() {
+        let mut server = mockito::Server::new();
+        let transfer = transfer_mock(&mut server).create();
+        let [waking, active] = activation_mocks(&mut server).map(|mock| mock.create());
+        let play = play_mock(&mut server).create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        client
+            .ensure_active_then(SPEAKER_ID, |client| client.resume_playback())
+            .unwrap();
+        for mock in [transfer, waking, active, play] {
+            mock.assert();
+        }
+
+        // The speaker never shows up, the action is not run
+        let mut server = mockito::Server::new();
+        let transfer = transfer_mock(&mut server).create();
+        let asleep = player_mock(&mut server)
+            .with_status(204)
+            .expect(DEVICE_ACTIVATION_POLLS as usize)
+            .create();
+        let play = play_mock(&mut server).expect(0).create();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let result = client.ensure_active_then(SPEAKER_ID, |client| client.resume_playback());
+        assert!(result.is_err());
+        for mock in [transfer, asleep, play] {
+            mock.assert();
+        }
+    }
+
+    #[test]
+    fn test_email_scope_is_requested() {
+        let scope_of = |url: String| {
+            let url = Url::parse(&url).unwrap();
+            url.query_pairs()
+                .find(|(key, _)| key == "scope")
+                .map(|(_, scope)| scope.to_string())
+                .unwrap()
+        };
+        let mut client = mock_client_builder("http://localhost").into_client(None);
+        let scope = scope_of(client.begin_authorization().unwrap());
+        assert_eq!(scope, SCOPE);
+
+        let mut client = mock_client_builder("http://localhost")
+            .with_email_scope(true)
+            .into_client(None);
+        let scope = scope_of(client.begin_authorization().unwrap());
+        assert!(scope.starts_with(SCOPE));
+        assert!(scope.split_whitespace().any(|scope| scope == EMAIL_SCOPE));
+    }
+
+    /// Remembers the URLs it was asked to open, failing when told to.
+    #[derive(Default)]
+    struct RecordingOpener {
+        opened: Mutex<Vec<String>>,
+        fail: bool,
+    }
+
+    impl UrlOpener for RecordingOpener {
+        fn open(&self, url: &str) -> Result<()> {
+            if self.fail {
+                bail!("no display");
+            }
+            self.opened.lock().unwrap().push(url.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_effective_config_reflects_overrides() {
+        let mut client = mock_client_builder("http://127.0.0.1:8888")
+            .with_refresh_margin(Duration::from_secs(60))
+            .with_min_refetch_interval(Duration::from_millis(1500))
+            .with_capabilities(&[Capability::ReadPlayback])
+            .with_client_secret("confidential-client-secret".to_string())
+            .force_consent(true)
+            .open_browser(false)
+            .into_client(None);
+        client.add_wanted_scopes(&[EMAIL_SCOPE.to_string()]);
+
+        let config = client.effective_config();
+        assert_eq!(config.client_id.as_deref(), Some("test-client-id"));
+        assert_eq!(config.tokens_url, "http://127.0.0.1:8888/api/token");
+        assert_eq!(config.api_url, "http://127.0.0.1:8888/v1");
+        assert_eq!(config.redirect_uri, DEFAULT_REDIRECT_URI);
+        assert_eq!(config.cred_store, "in-memory");
+        assert_eq!(config.local_dir, None);
+        assert_eq!(config.refresh_margin_secs, 60);
+        assert_eq!(config.min_refetch_interval_ms, 1500);
+        assert!(config.force_consent);
+        assert!(!config.opens_browser);
+        assert_eq!(
+            config.requested_scopes,
+            scope_for(&[Capability::ReadPlayback])
+                .split_whitespace()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(config.wanted_scopes, [EMAIL_SCOPE]);
+        assert_eq!(config.granted_scopes.join(" "), SCOPE);
+
+        assert_eq!(config.access_token.as_deref(), Some("test…(17 chars)"));
+        let printed = serde_json::to_string(&config).unwrap();
+        for secret in [
+            "test-access-token",
+            "test-refresh-token",
+            "confidential-client-secret",
+        ] {
+            assert!(!printed.contains(secret), "{secret} leaked");
+        }
+    }
+
+    #[test]
+    fn test_authorization_url_is_opened_unless_told_not_to() {
+        let opener = Arc::new(RecordingOpener::default());
+        let mut client = mock_client_builder("http://localhost")
+            .with_url_opener(opener.clone())
+            .into_client(None);
+        let url = client.begin_authorization().unwrap();
+        let mut headless = mock_client_builder("http://localhost")
+            .with_url_opener(opener.clone())
+            .open_browser(false)
+            .into_client(None);
+        let headless_url = Url::parse(&headless.begin_authorization().unwrap()).unwrap();
+
+        // The opener doesn't change what is asked of Spotify
+        let params = |url: &Url| -> Vec<(String, String)> {
+            url.query_pairs()
+                .filter(|(key, _)| key != "code_challenge")
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_eq!(params(&Url::parse(&url).unwrap()), params(&headless_url));
+        assert!(url.starts_with("http://localhost/authorize?response_type=code"));
+
+        assert!(!headless.open_authorization_url(headless_url.as_str()));
+        assert!(opener.opened.lock().unwrap().is_empty());
+        assert!(client.open_authorization_url(&url));
+        assert_eq!(*opener.opened.lock().unwrap(), [url.as_str()]);
+
+        // A browser that fails to open leaves it to the user
+        let failing = Arc::new(RecordingOpener {
+            fail: true,
+            ..Default::default()
+        });
+        let client = mock_client_builder("http://localhost")
+            .with_url_opener(failing)
+            .into_client(None);
+        assert!(!client.open_authorization_url(&url));
+    }
+
+    #[test]
+    fn test_image_upload_scope_is_requested() {
+        assert_eq!(requested_scope(false, false), SCOPE);
+        assert_eq!(
+            requested_scope(true, true),
+            format!("{SCOPE} {EMAIL_SCOPE} {IMAGE_UPLOAD_SCOPE}")
+        );
+        let mut client = mock_client_builder("http://localhost")
+            .with_image_upload_scope(true)
+            .into_client(None);
+        let url = Url::parse(&client.begin_authorization().unwrap()).unwrap();
+        let (_, scope) = url.query_pairs().find(|(key, _)| key == "scope").unwrap();
+        assert_eq!(scope, format!("{SCOPE} {IMAGE_UPLOAD_SCOPE}"));
+    }
+
+    #[test]
+    fn test_force_consent_shows_dialog() {
+        let show_dialog = |client: &mut SpotifyClient| {
+            let url = Url::parse(&client.begin_authorization().unwrap()).unwrap();
+            url.query_pairs()
+                .find(|(key, _)| key == "show_dialog")
+                .map(|(_, show)| show.to_string())
+        };
+        let mut client = mock_client_builder("http://localhost").into_client(None);
+        assert_eq!(show_dialog(&mut client), None);
+
+        let mut client = mock_client_builder("http://localhost")
+            .force_consent(true)
+            .into_client(None);
+        assert_eq!(show_dialog(&mut client).as_deref(), Some("true"));
+    }
+
+    /// Just the start of image marker, enough to be taken for a JPEG
+    const FAKE_JPEG: [u8; 6] = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+
+    fn with_image_upload_scope(client: &SpotifyClient) {
+        let mut auth = fresh_user_auth();
+        auth.scope = format!("{SCOPE} {IMAGE_UPLOAD_SCOPE}");
+        client.shared_auth().set(Some(auth));
+    }
+
+    fn cover_mocks(server: &mut mockito::Server) -> [mockito::Mock; 3] {
+        [
+            server
+                .mock("GET", "/v1/playlists/with-cover/images")
+                .with_body(
+                    r#"[{"url": "https://mosaic.scdn.co/640/ab67", "height": 640, "width": 640},
+                        {"url": "https://mosaic.scdn.co/300/ab67", "height": 300, "width": 300}]"#,
+                ),
+            server
+                .mock("GET", "/v1/playlists/no-cover/images")
+                .with_body("null"),
+            server
+                .mock("PUT", "/v1/playlists/with-cover/images")
+                .match_header("content-type", "image/jpeg")
+                .match_body(BASE64_STANDARD.encode(FAKE_JPEG).as_str())
+                .with_status(202),
+        ]
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_playlist_cover() {
+        let mut server = mockito::Server::new_async().await;
+        let [with_cover, no_cover, upload] = cover_mocks(&mut server);
+        let with_cover = with_cover.create_async().await;
+        let no_cover = no_cover.create_async().await;
+        let upload = upload.create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let cover = client.get_playlist_cover("with-cover").await.unwrap();
+        assert_eq!(cover.len(), 2);
+        assert_eq!(cover[0].url, "https://mosaic.scdn.co/640/ab67");
+        assert!(client
+            .get_playlist_cover("no-cover")
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Not granted the scope yet, nothing is sent
+        assert!(client
+            .set_playlist_cover("with-cover", &FAKE_JPEG)
+            .await
+            .is_err());
+        with_image_upload_scope(&client);
+        client
+            .set_playlist_cover("with-cover", &FAKE_JPEG)
+            .await
+            .unwrap();
+        // Not a JPEG, or too big once encoded
+        assert!(client
+            .set_playlist_cover("with-cover", b"GIF89a")
+            .await
+            .is_err());
+        let mut huge = FAKE_JPEG.to_vec();
+        huge.resize(MAX_COVER_UPLOAD_SIZE * 3 / 4 + 1, 0);
+        assert!(client
+            .set_playlist_cover("with-cover", &huge)
+            .await
+            .is_err());
+        for mock in [with_cover, no_cover, upload] {
+            mock.assert_async().await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_playlist_cover() {
+        let mut server = mockito::Server::new();
+        let [with_cover, no_cover, upload] = cover_mocks(&mut server).map(|mock| mock.create());
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let cover = client.get_playlist_cover("with-cover").unwrap();
+        assert_eq!(cover.len(), 2);
+        assert_eq!(cover[0].url, "https://mosaic.scdn.co/640/ab67");
+        assert!(client.get_playlist_cover("no-cover").unwrap().is_empty());
+
+        // Not granted the scope yet, nothing is sent
+        assert!(client.set_playlist_cover("with-cover", &FAKE_JPEG).is_err());
+        with_image_upload_scope(&client);
+        client.set_playlist_cover("with-cover", &FAKE_JPEG).unwrap();
+        // Not a JPEG, or too big once encoded
+        assert!(client.set_playlist_cover("with-cover", b"GIF89a").is_err());
+        let mut huge = FAKE_JPEG.to_vec();
+        huge.resize(MAX_COVER_UPLOAD_SIZE * 3 / 4 + 1, 0);
+        assert!(client.set_playlist_cover("with-cover", &huge).is_err());
+        for mock in [with_cover, no_cover, upload] {
+            mock.assert();
+        }
+    }
+
+    fn profile_mock(server: &mut mockito::Server) -> mockito::Mock {
+        me_mock(server, "test-access-token", 200)
+            .with_body(std::fs::read_to_string("sample_data/me.json").unwrap())
+    }
+
+    fn with_email_scope(client: &SpotifyClient) {
+        let mut auth = fresh_user_auth();
+        auth.scope = format!("{SCOPE} {EMAIL_SCOPE}");
+        client.shared_auth().set(Some(auth));
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_user_profile_is_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = profile_mock(&mut server).expect(2).create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let profile = client.get_user_profile().await.unwrap();
+        assert_eq!(profile.id, "jorge");
+        assert_eq!(client.get_user_profile().await.unwrap(), profile);
+
+        // Authorized again with other scopes
+        with_email_scope(&client);
+        let profile = client.get_user_profile().await.unwrap();
+        assert_eq!(profile.email.as_deref(), Some("jorge@example.com"));
+        mock.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_user_profile_is_cached() {
+        let mut server = mockito::Server::new();
+        let mock = profile_mock(&mut server).expect(2).create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let profile = client.get_user_profile().unwrap();
+        assert_eq!(profile.id, "jorge");
+        assert_eq!(client.get_user_profile().unwrap(), profile);
+
+        // Authorized again with other scopes
+        with_email_scope(&client);
+        let profile = client.get_user_profile().unwrap();
+        assert_eq!(profile.email.as_deref(), Some("jorge@example.com"));
+        mock.assert();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_detect_user_id() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = profile_mock(&mut server).create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert_eq!(client.user_id(), "tester");
+        let user_id = client.detect_user_id().await.unwrap();
+        assert_eq!(user_id, "jorge");
+        client.rekey_user(user_id).await.unwrap();
+        assert_eq!(client.user_id(), "jorge");
+        mock.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_detect_user_id() {
+        let mut server = mockito::Server::new();
+        let mock = profile_mock(&mut server).create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert_eq!(client.user_id(), "tester");
+        let user_id = client.detect_user_id().unwrap();
+        assert_eq!(user_id, "jorge");
+        client.rekey_user(user_id).unwrap();
+        assert_eq!(client.user_id(), "jorge");
+        mock.assert();
+    }
+
+    fn audio_analysis_mock(server: &mut mockito::Server) -> mockito::Mock {
+        server
+            .mock("GET", "/v1/audio-analysis/3BovdzfaX4jb5KFQwoPfAw")
+            .with_body(std::fs::read_to_string("sample_data/audio_analysis.json").unwrap())
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_get_audio_analysis() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = audio_analysis_mock(&mut server).create_async().await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let analysis = client
+            .get_audio_analysis("3BovdzfaX4jb5KFQwoPfAw")
+            .await
+            .unwrap();
+        assert_eq!(analysis.sections.len(), 4);
+        mock.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_get_audio_analysis() {
+        let mut server = mockito::Server::new();
+        let mock = audio_analysis_mock(&mut server).create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let analysis = client.get_audio_analysis("3BovdzfaX4jb5KFQwoPfAw").unwrap();
+        assert_eq!(analysis.sections.len(), 4);
+        mock.assert();
+    }
+
+    fn album_mocks(server: &mut mockito::Server) -> (mockito::Mock, mockito::Mock) {
+        let album = server
+            .mock("GET", "/v1/albums/1wV3Oun1eOsGZWihTuTApq")
+            .with_body_from_file("sample_data/album.json");
+        let missing = server
+            .mock("GET", "/v1/albums/0000000000000000000000")
+            .with_status(404)
+            .with_body(r#"{"error": {"status": 404, "message": "Resource not found"}}"#);
+        (album, missing)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_get_album() {
+        let mut server = mockito::Server::new_async().await;
+        let (album, missing) = album_mocks(&mut server);
+        let (album, missing) = (album.create_async().await, missing.create_async().await);
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let full = client.get_album("1wV3Oun1eOsGZWihTuTApq").await.unwrap();
+        assert_eq!(full.tracks.items.len(), 11);
+        let err = client
+            .get_album("0000000000000000000000")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Spotify has no album with the id 0000000000000000000000"
+        );
+        assert!(client.get_album("../me").await.is_err());
+        album.assert_async().await;
+        missing.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_get_album() {
+        let mut server = mockito::Server::new();
+        let (album, missing) = album_mocks(&mut server);
+        let (album, missing) = (album.create(), missing.create());
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let full = client.get_album("1wV3Oun1eOsGZWihTuTApq").unwrap();
+        assert_eq!(full.tracks.items.len(), 11);
+        let err = client.get_album("0000000000000000000000").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Spotify has no album with the id 0000000000000000000000"
+        );
+        assert!(client.get_album("../me").is_err());
+        album.assert();
+        missing.assert();
+    }
+
+    /// The sample track with a single 300 wide cover served by `server`, and
+    /// a client caching covers in a fresh directory named after `name`.
+    fn album_art_setup(name: &str, server_url: &str) -> (Track, SpotifyClientBuilder, PathBuf) {
+        let mut track = crate::testutil::sample_track();
+        track.album.images = vec![Image {
+            url: format!("{server_url}/image/ab67616d00001e02"),
+            height: Some(300),
+            width: Some(300),
+        }];
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let builder = mock_client_builder(server_url).with_art_cache_dir(&dir);
+        (track, builder, dir)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_album_art_is_cached() {
+        let mut server = mockito::Server::new_async().await;
+        let image = server
+            .mock("GET", "/image/ab67616d00001e02")
+            .with_header("content-type", "image/jpeg")
+            .with_body(b"\xff\xd8cover")
+            .expect(1)
+            .create_async()
+            .await;
+
+        let (mut track, builder, dir) = album_art_setup("art-async", &server.url());
+        let client = builder.build().await.unwrap();
+        let path = client
+            .fetch_album_art(&track, ImageSize::Medium)
+            .await
+            .unwrap();
+        assert_eq!(path, dir.join(format!("{}-300.jpg", track.album.id)));
+        assert_eq!(std::fs::read(&path).unwrap(), b"\xff\xd8cover");
+        // Served from the cache, the mock expects a single request
+        let again = client
+            .fetch_album_art(&track, ImageSize::Medium)
+            .await
+            .unwrap();
+        assert_eq!(again, path);
+        image.assert_async().await;
+
+        track.album.images.clear();
+        let err = client
+            .fetch_album_art(&track, ImageSize::Large)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("has no cover"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_album_art_is_cached() {
+        let mut server = mockito::Server::new();
+        let image = server
+            .mock("GET", "/image/ab67616d00001e02")
+            .with_header("content-type", "image/jpeg")
+            .with_body(b"\xff\xd8cover")
+            .expect(1)
+            .create();
+
+        let (mut track, builder, dir) = album_art_setup("art-blocking", &server.url());
+        let client = builder.build().unwrap();
+        let path = client.fetch_album_art(&track, ImageSize::Medium).unwrap();
+        assert_eq!(path, dir.join(format!("{}-300.jpg", track.album.id)));
+        assert_eq!(std::fs::read(&path).unwrap(), b"\xff\xd8cover");
+        // Served from the cache, the mock expects a single request
+        let again = client.fetch_album_art(&track, ImageSize::Medium).unwrap();
+        assert_eq!(again, path);
+        image.assert();
+
+        track.album.images.clear();
+        let err = client
+            .fetch_album_art(&track, ImageSize::Large)
+            .unwrap_err();
+        assert!(err.to_string().contains("has no cover"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Two pages of recent plays. The older page overlaps a history with one
+    /// play stored at `SYNCED_SINCE` and reaches past it.
+    fn recently_played_mocks(server: &mut mockito::Server) -> (mockito::Mock, mockito::Mock) {
+        let newest = std::fs::read_to_string("sample_data/recently_played.json").unwrap();
+        let mut older: serde_json::Value = serde_json::from_str(&newest).unwrap();
+        let divine_zero = older["items"][0]["track"].clone();
+        let circles = older["items"][1]["track"].clone();
+        older["next"] = serde_json::Value::Null;
+        older["items"] = serde_json::json!([
+            { "track": circles, "played_at": "2024-09-23T21:20:00Z" },
+            // The play the daemon recorded at its start, SYNCED_SINCE
+            { "track": divine_zero, "played_at": "2024-09-23T21:14:08Z" },
+            { "track": circles, "played_at": "2024-09-23T21:00:00Z" },
+        ]);
+
+        let newest_mock = server
+            .mock("GET", "/v1/me/player/recently-played")
+            .match_query(mockito::Matcher::Exact("limit=50".into()))
+            .with_body(newest);
+        let older_mock = server
+            .mock("GET", "/v1/me/player/recently-played")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("limit".into(), "50".into()),
+                mockito::Matcher::UrlEncoded("before".into(), "1727126812345".into()),
+            ]))
+            .with_body(older.to_string());
+        (newest_mock, older_mock)
+    }
+
+    /// 2024-09-23T21:10:00Z
+    const SYNCED_SINCE: u64 = 1727125800;
+
+    fn synced_history(name: &str) -> HistoryStore {
+        let path = std::env::temp_dir().join(format!("{name}-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = HistoryStore::new(path);
+        let played_at = UNIX_EPOCH + Duration::from_secs(SYNCED_SINCE);
+        let entry = PlayHistoryEntry::from_track(
+            &crate::testutil::sample_track(),
+            played_at,
+            Confidence::High,
+        );
+        store.append(&entry).unwrap();
+        store
+    }
+
+    fn check_synced_history(store: &HistoryStore) {
+        let entries = store.load().unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.track_name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["The Divine Zero", "Circles", "Circles", "The Divine Zero"]
+        );
+        let contexts: Vec<Option<PlayContext>> = entries.into_iter().map(|e| e.context).collect();
+        assert_eq!(
+            contexts,
+            [
+                None,
+                Some(PlayContext::Autoplay),
+                Some(PlayContext::Autoplay),
+                Some(PlayContext::Album("1wV3Oun1eOsGZWihTuTApq".to_string())),
+            ]
+        );
+        std::fs::remove_file(store.path()).unwrap();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_sync_recent_to_history() {
+        let mut server = mockito::Server::new_async().await;
+        let (newest, older) = recently_played_mocks(&mut server);
+        let newest = newest.expect(2).create_async().await;
+        let older = older.create_async().await;
+
+        let store = synced_history("sync-recent-async");
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        assert_eq!(client.sync_recent_to_history(&store).await.unwrap(), 3);
+        // Synced already, the newest page reaches back far enough now
+        assert_eq!(client.sync_recent_to_history(&store).await.unwrap(), 0);
+        newest.assert_async().await;
+        older.assert_async().await;
+        check_synced_history(&store);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_sync_recent_to_history() {
+        let mut server = mockito::Server::new();
+        let (newest, older) = recently_played_mocks(&mut server);
+        let newest = newest.expect(2).create();
+        let older = older.create();
+
+        let store = synced_history("sync-recent");
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        assert_eq!(client.sync_recent_to_history(&store).unwrap(), 3);
+        // Synced already, the newest page reaches back far enough now
+        assert_eq!(client.sync_recent_to_history(&store).unwrap(), 0);
+        newest.assert();
+        older.assert();
+        check_synced_history(&store);
+    }
+
+    /// A saved tracks page in the market, one restricted track and one playable.
+    fn audit_mock(server: &mut mockito::Server) -> mockito::Mock {
+        let restricted: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("sample_data/restricted_track.json").unwrap(),
+        )
+        .unwrap();
+        let mut playable = restricted.clone();
+        playable["id"] = "playable-id".into();
+        playable["is_playable"] = true.into();
+        playable.as_object_mut().unwrap().remove("restrictions");
+        let page = serde_json::json!({
+            "items": [
+                { "added_at": "2024-09-23T21:00:00Z", "track": restricted },
+                { "added_at": "2024-09-22T21:00:00Z", "track": playable },
+            ],
+            "limit": 50, "offset": 0, "total": 2, "next": null,
+        });
+        server
+            .mock("GET", "/v1/me/tracks")
+            .match_query(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("market".into(), "from_token".into()),
+                mockito::Matcher::UrlEncoded("limit".into(), "50".into()),
+            ]))
+            .with_body(page.to_string())
+            .expect(2)
+    }
+
+    fn check_audits(first: LibraryAudit, again: LibraryAudit, store: &HistoryStore) {
+        assert_eq!(first.checked, 2);
+        assert_eq!(first.changes, 1);
+        let reasons: Vec<Option<&str>> = first
+            .unplayable
+            .iter()
+            .map(|t| t.reason.as_deref())
+            .collect();
+        assert_eq!(reasons, [Some("market")]);
+        // Still unplayable, nothing new to log
+        assert_eq!(again.changes, 0);
+        assert_eq!(again.unplayable.len(), 1);
+        assert_eq!(store.availability_changes().unwrap().len(), 1);
+        std::fs::remove_file(crate::history::availability_path_for(store.path())).unwrap();
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_audit_library() {
+        let mut server = mockito::Server::new_async().await;
+        let saved = audit_mock(&mut server).create_async().await;
+        let path = std::env::temp_dir().join(format!("audit-async-{}.jsonl", std::process::id()));
+        let store = HistoryStore::new(path);
+        let _ = std::fs::remove_file(crate::history::availability_path_for(store.path()));
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let first = client.audit_library(&store).await.unwrap();
+        let again = client.audit_library(&store).await.unwrap();
+        saved.assert_async().await;
+        check_audits(first, again, &store);
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_audit_library() {
+        let mut server = mockito::Server::new();
+        let saved = audit_mock(&mut server).create();
+        let path = std::env::temp_dir().join(format!("audit-{}.jsonl", std::process::id()));
+        let store = HistoryStore::new(path);
+        let _ = std::fs::remove_file(crate::history::availability_path_for(store.path()));
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let first = client.audit_library(&store).unwrap();
+        let again = client.audit_library(&store).unwrap();
+        saved.assert();
+        check_audits(first, again, &store);
+    }
+
+    /// When the play `minutes` after [SYNCED_SINCE] finished.
+    fn minute_at(minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(SYNCED_SINCE + minutes * 60)
+    }
+
+    fn minute_cursor(minutes: u64) -> String {
+        let at = minute_at(minutes).duration_since(UNIX_EPOCH).unwrap();
+        at.as_millis().to_string()
+    }
+
+    /// A recently played page of plays `minutes` after [SYNCED_SINCE], in
+    /// the order given, with a `before` cursor to an older page.
+    fn recent_page(minutes: &[u64], before: Option<u64>) -> String {
+        let sample = crate::testutil::load_sample("recently_played.json");
+        let sample: serde_json::Value = serde_json::from_str(&sample).unwrap();
+        let track = &sample["items"][0]["track"];
+        let items: Vec<serde_json::Value> = minutes
+            .iter()
+            .map(|&m| {
+                let played_at = chrono::DateTime::<chrono::Utc>::from(minute_at(m));
+                serde_json::json!({ "track": track, "played_at": played_at.to_rfc3339() })
+            })
+            .collect();
+        serde_json::json!({
+            "items": items,
+            "limit": 2,
+            "next": before.map(|_| "https://api.spotify.com/v1/me/player/recently-played"),
+            "cursors": before.map(|b| serde_json::json!({ "before": minute_cursor(b) })),
+        })
+        .to_string()
+    }
+
+    /// Four plays, two to a page, paged newest first with `before` and
+    /// oldest first with `after`, each way ending on an empty page. Along
+    /// with how often each is requested by `test_history_pager`.
+    fn history_pager_mocks(server: &mut mockito::Server) -> Vec<(mockito::Mock, usize)> {
+        let mut page = |query: String, body: String| {
+            server
+                .mock("GET", "/v1/me/player/recently-played")
+                .match_query(mockito::Matcher::Exact(query))
+                .with_body(body)
+        };
+        vec![
+            (page("limit=2".into(), recent_page(&[3, 2], Some(2))), 2),
+            (
+                page(
+                    format!("limit=2&before={}", minute_cursor(2)),
+                    recent_page(&[1, 0], Some(0)),
+                ),
+                2,
+            ),
+            (
+                page(
+                    format!("limit=2&before={}", minute_cursor(0)),
+                    recent_page(&[], None),
+                ),
+                1,
+            ),
+            // Spotify sends plays newest first after a cursor too
+            (
+                page("limit=2&after=0".into(), recent_page(&[1, 0], None)),
+                1,
+            ),
+            (
+                page(
+                    format!("limit=2&after={}", minute_cursor(1)),
+                    recent_page(&[3, 2], None),
+                ),
+                1,
+            ),
+            (
+                page(
+                    format!("limit=2&after={}", minute_cursor(3)),
+                    recent_page(&[], None),
+                ),
+                1,
+            ),
+        ]
+    }
+
+    fn page_minutes(page: &[RecentlyPlayed]) -> Vec<u64> {
+        page.iter()
+            .map(|play| {
+                let at = play.played_at().unwrap().duration_since(UNIX_EPOCH);
+                (at.unwrap().as_secs() - SYNCED_SINCE) / 60
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_history_pager() {
+        let mut server = mockito::Server::new_async().await;
+        let mut mocks = Vec::new();
+        for (mock, hits) in history_pager_mocks(&mut server) {
+            mocks.push(mock.expect(hits).create_async().await);
+        }
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+
+        for (order, expected) in [
+            (PageOrder::NewestFirst, [[3, 2], [1, 0]]),
+            (PageOrder::OldestFirst, [[0, 1], [2, 3]]),
+        ] {
+            let mut pager = client.history_pager(order, None).with_limit(2);
+            let mut pages = Vec::new();
+            while let Some(page) = pager.next_page().await.unwrap() {
+                pages.push(page_minutes(&page));
+            }
+            assert_eq!(pages, expected);
+            // Over for good, nothing more is requested
+            assert!(pager.next_page().await.unwrap().is_none());
+        }
+
+        // Stops at the stop time instead of the empty page
+        let mut pager = client
+            .history_pager(PageOrder::NewestFirst, None)
+            .with_limit(2)
+            .until(minute_at(1));
+        let mut pages = Vec::new();
+        while let Some(page) = pager.next_page().await.unwrap() {
+            pages.push(page_minutes(&page));
+        }
+        assert_eq!(pages, [vec![3, 2], vec![1]]);
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_history_pager() {
+        let mut server = mockito::Server::new();
+        let mocks: Vec<mockito::Mock> = history_pager_mocks(&mut server)
+            .into_iter()
+            .map(|(mock, hits)| mock.expect(hits).create())
+            .collect();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+
+        for (order, expected) in [
+            (PageOrder::NewestFirst, [[3, 2], [1, 0]]),
+            (PageOrder::OldestFirst, [[0, 1], [2, 3]]),
+        ] {
+            let mut pager = client.history_pager(order, None).with_limit(2);
+            let pages: Vec<Vec<u64>> = pager
+                .by_ref()
+                .map(|page| page_minutes(&page.unwrap()))
+                .collect();
+            assert_eq!(pages, expected);
+            // Over for good, nothing more is requested
+            assert!(pager.next().is_none());
+        }
+
+        // Stops at the stop time instead of the empty page
+        let pages: Vec<Vec<u64>> = client
+            .history_pager(PageOrder::NewestFirst, None)
+            .with_limit(2)
+            .until(minute_at(1))
+            .map(|page| page_minutes(&page.unwrap()))
+            .collect();
+        assert_eq!(pages, [vec![3, 2], vec![1]]);
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    #[test]
+    fn test_raw_api_path() {
+        let api = "https://api.spotify.com/v1";
+        assert_eq!(raw_api_path(api, "/me/shows", &[]).unwrap(), "/me/shows");
+        assert_eq!(
+            raw_api_path(
+                api,
+                "/me/shows",
+                &[("limit", "2"), ("market", "from token")]
+            )
+            .unwrap(),
+            "/me/shows?limit=2&market=from+token"
+        );
+        for path in [
+            "me/shows",
+            "https://evil.example/v1/me",
+            "//evil.example/v1/me",
+            "/me?limit=2",
+            "/../api/token",
+            "/me/%2e%2e/%2E%2E/api/token",
+            "/\\evil.example",
+        ] {
+            assert!(raw_api_path(api, path, &[]).is_err(), "{path}");
+        }
+    }
+
+    /// A rate limited GET with a token that needs a refresh first, then a
+    /// PUT and a form POST.
+    fn raw_mocks(server: &mut mockito::Server) -> Vec<mockito::Mock> {
+        let mut shows = |status: usize| {
+            server
+                .mock("GET", "/v1/me/shows")
+                .match_query(mockito::Matcher::UrlEncoded("limit".into(), "2".into()))
+                .match_header("authorization", "Bearer new-access-token")
+                .with_status(status)
+        };
+        let limited = shows(429).with_header("Retry-After", "0").expect(1);
+        let answered = shows(200).with_body(r#"{"items": []}"#);
+        let put = server
+            .mock("PUT", "/v1/me/shows")
+            .match_header("authorization", "Bearer new-access-token")
+            .match_body(mockito::Matcher::Json(
+                serde_json::json!({ "ids": ["5CfC"] }),
+            ))
+            .with_status(204);
+        let form = server
+            .mock("POST", "/v1/me/notes")
+            .match_header("content-type", "application/x-www-form-urlencoded")
+            .match_body("note=on+repeat")
+            .with_status(201)
+            .with_body(r#"{"id": "n1"}"#);
+        vec![refresh_mock(server, 200), limited, answered, put, form]
+    }
+
+    fn expired_user_auth() -> UserAuthData {
+        UserAuthData {
+            last_refresh: Some(UNIX_EPOCH),
+            ..fresh_user_auth()
+        }
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_raw_requests() {
+        let mut server = mockito::Server::new_async().await;
+        let mut mocks = Vec::new();
+        for mock in raw_mocks(&mut server) {
+            mocks.push(mock.create_async().await);
+        }
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        client.shared_auth().set(Some(expired_user_auth()));
+
+        let shows = client.raw_get("/me/shows", &[("limit", "2")]).await;
+        assert_eq!(shows.unwrap(), serde_json::json!({ "items": [] }));
+        let body = serde_json::json!({ "ids": ["5CfC"] });
+        let saved = client.raw_put_json("/me/shows", &body).await.unwrap();
+        assert!(saved.is_null());
+        let note = client.raw_post_form("/me/notes", &[("note", "on repeat")]);
+        assert_eq!(note.await.unwrap()["id"], "n1");
+        assert!(client.raw_get("//evil.example/me", &[]).await.is_err());
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_raw_requests() {
+        let mut server = mockito::Server::new();
+        let mocks: Vec<_> = raw_mocks(&mut server)
+            .into_iter()
+            .map(|mock| mock.create())
+            .collect();
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        client.shared_auth().set(Some(expired_user_auth()));
+
+        let shows = client.raw_get("/me/shows", &[("limit", "2")]);
+        assert_eq!(shows.unwrap(), serde_json::json!({ "items": [] }));
+        let body = serde_json::json!({ "ids": ["5CfC"] });
+        let saved = client.raw_put_json("/me/shows", &body).unwrap();
+        assert!(saved.is_null());
+        let note = client.raw_post_form("/me/notes", &[("note", "on repeat")]);
+        assert_eq!(note.unwrap()["id"], "n1");
+        assert!(client.raw_get("//evil.example/me", &[]).is_err());
+        for mock in mocks {
+            mock.assert();
+        }
+    }
+
+    const OUTAGE_PAGE: &str =
+        "<html><body><h1>503 Service Temporarily Unavailable</h1></body></html>";
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_html_outage_page() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = me_mock(&mut server, "test-access-token", 503)
+            .with_header("content-type", "text/html")
+            .with_body(OUTAGE_PAGE)
+            .create_async()
+            .await;
+
+        let mut client = mock_client_builder(&server.url()).build().await.unwrap();
+        let err = client.get_user_profile().await.unwrap_err();
+        mock.assert_async().await;
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::Upstream { status: 503 })
+        );
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_html_outage_page() {
+        let mut server = mockito::Server::new();
+        let mock = me_mock(&mut server, "test-access-token", 503)
+            .with_header("content-type", "text/html")
+            .with_body(OUTAGE_PAGE)
+            .create();
+
+        let mut client = mock_client_builder(&server.url()).build().unwrap();
+        let err = client.get_user_profile().unwrap_err();
+        mock.assert();
+        assert_eq!(
+            err.downcast_ref::<SpotifyError>(),
+            Some(&SpotifyError::Upstream { status: 503 })
+        );
+    }
+
+    /// A valid token answered after a 429, and a rejected one.
+    fn validate_token_mocks(
+        server: &mut mockito::Server,
+    ) -> (mockito::Mock, mockito::Mock, mockito::Mock) {
+        let limited = server
+            .mock("GET", "/v1/me")
+            .match_header("authorization", "Bearer test-access-token")
+            .with_status(429)
+            .with_header("Retry-After", "0")
+            .expect(1);
+        let valid = server
+            .mock("GET", "/v1/me")
+            .match_header("authorization", "Bearer test-access-token")
+            .with_body(std::fs::read_to_string("sample_data/me.json").unwrap());
+        let rejected = server
+            .mock("GET", "/v1/me")
+            .match_header("authorization", "Bearer revoked-token")
+            .with_status(401)
+            .with_body(r#"{"error": {"status": 401, "message": "Invalid access token"}}"#);
+        (limited, valid, rejected)
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    #[tokio::test]
+    async fn test_validate_token_online() {
+        let mut server = mockito::Server::new_async().await;
+        let (limited, valid, rejected) = validate_token_mocks(&mut server);
+        let limited = limited.create_async().await;
+        let valid = valid.create_async().await;
+        let rejected = rejected.create_async().await;
+        // Nothing is refreshed, expired or not
+        let refresh = server
+            .mock("POST", "/api/token")
+            .expect(0)
+            .create_async()
+            .await;
+
+        let mut client = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .build()
+            .await
+            .unwrap();
+        let before = client.shared_auth().snapshot().unwrap();
+        assert!(client.validate_token_online().await.unwrap());
+        let after = client.shared_auth().snapshot().unwrap();
+        assert_eq!(after.access_token, before.access_token);
+        assert_eq!(after.last_refresh, before.last_refresh);
+
+        let revoked = UserAuthData {
+            access_token: "revoked-token".to_string(),
+            ..fresh_user_auth()
+        };
+        let mut client = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), revoked)
+            .build()
+            .await
+            .unwrap();
+        assert!(!client.validate_token_online().await.unwrap());
+        limited.assert_async().await;
+        valid.assert_async().await;
+        rejected.assert_async().await;
+        refresh.assert_async().await;
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn test_validate_token_online() {
+        let mut server = mockito::Server::new();
+        let (limited, valid, rejected) = validate_token_mocks(&mut server);
+        let limited = limited.create();
+        let valid = valid.create();
+        let rejected = rejected.create();
+        // Nothing is refreshed, expired or not
+        let refresh = server.mock("POST", "/api/token").expect(0).create();
+
+        let mut client = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), expired_user_auth())
+            .build()
+            .unwrap();
+        let before = client.shared_auth().snapshot().unwrap();
+        assert!(client.validate_token_online().unwrap());
+        let after = client.shared_auth().snapshot().unwrap();
+        assert_eq!(after.access_token, before.access_token);
+        assert_eq!(after.last_refresh, before.last_refresh);
+
+        let revoked = UserAuthData {
+            access_token: "revoked-token".to_string(),
+            ..fresh_user_auth()
+        };
+        let mut client = mock_client_builder(&server.url())
+            .with_in_memory_creds("test-client-id".to_string(), revoked)
+            .build()
+            .unwrap();
+        assert!(!client.validate_token_online().unwrap());
+        limited.assert();
+        valid.assert();
+        rejected.assert();
+        refresh.assert();
+    }
+
+    #[test]
+    fn test_token_validation_result() {
+        assert!(token_validation_result(StatusCode::OK).unwrap());
+        assert!(!token_validation_result(StatusCode::UNAUTHORIZED).unwrap());
+        assert!(token_validation_result(StatusCode::TOO_MANY_REQUESTS).is_err());
     }
 
     #[test]
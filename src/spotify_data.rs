@@ -1,13 +1,21 @@
+use chrono::DateTime;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 
 /// Item returned from Spotify's API: GetCurrentlyPlayingTrack
 /// https://developer.spotify.com/documentation/web-api/reference/get-the-users-currently-playing-tracka
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CurrentlyPlayingTrack {
     pub timestamp: u64,
     pub progress_ms: Option<u32>,
-    pub currently_playing_type: String,
+    pub currently_playing_type: PlayingType,
     pub is_playing: bool,
+    /// What playback was started from, null for autoplay and radio
+    #[serde(default)]
+    pub context: Option<Context>,
     // Partially parse to check if this will be a valid track
     pub item: Option<serde_json::Value>,
 }
@@ -18,15 +26,446 @@ impl CurrentlyPlayingTrack {
             .as_ref()
             .and_then(|v| serde_json::from_value(v.clone()).ok())
     }
+
+    /// The playing item, parsed according to `currently_playing_type`.
+    /// Ads come without an item, `Unknown` items and items that fail to
+    /// parse give None.
+    pub fn into_playing_item(self) -> Option<PlayingItem> {
+        match self.currently_playing_type {
+            PlayingType::Track => serde_json::from_value(self.item?)
+                .ok()
+                .map(PlayingItem::Track),
+            PlayingType::Episode => serde_json::from_value(self.item?)
+                .ok()
+                .map(PlayingItem::Episode),
+            PlayingType::Chapter => serde_json::from_value(self.item?)
+                .ok()
+                .map(PlayingItem::Chapter),
+            PlayingType::Ad => Some(PlayingItem::Ad),
+            PlayingType::Unknown => None,
+        }
+    }
+
+    /// Whether the player is going, see [PlaybackStatus].
+    pub fn status(&self) -> PlaybackStatus {
+        // Ads never come with an item
+        let has_item = self.item.is_some() || self.currently_playing_type == PlayingType::Ad;
+        match (has_item, self.is_playing) {
+            (true, true) => PlaybackStatus::Playing,
+            (true, false) => PlaybackStatus::Paused,
+            (false, true) => PlaybackStatus::Transitioning,
+            (false, false) => PlaybackStatus::Stopped,
+        }
+    }
+}
+
+/// What the player is doing, as far as one answer tells.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    /// `is_playing: true` without an item. Spotify answers like that for a
+    /// moment between two items, e.g. right after a skip, so what played
+    /// before is still the best guess
+    Transitioning,
+    /// No item and not playing
+    Stopped,
+}
+
+/// Whatever the player is playing. Episodes and audiobook chapters only
+/// show up when they are requested with `additional_types`.
+#[derive(Debug, Clone)]
+pub enum PlayingItem {
+    Track(Track),
+    Episode(Episode),
+    Chapter(Chapter),
+    Ad,
+}
+
+/// What kind of item is playing, Spotify reports `unknown` for some items
+/// and anything new it adds is treated the same.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayingType {
+    Track,
+    Episode,
+    /// Audiobook chapters, reported as `audiobook` in some markets
+    #[serde(alias = "audiobook")]
+    Chapter,
+    Ad,
+    #[serde(other)]
+    Unknown,
+}
+
+impl PlayingType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PlayingType::Track => "track",
+            PlayingType::Episode => "episode",
+            PlayingType::Chapter => "chapter",
+            PlayingType::Ad => "ad",
+            PlayingType::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for PlayingType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Context object in Spotify's API: the playlist, album, artist or
+/// collection an item is played from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Context {
+    #[serde(rename = "type")]
+    pub context_type: String,
+    pub uri: String,
+}
+
+/// Where a play came from, classified from its [Context].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum PlayContext {
+    Playlist(String),
+    Album(String),
+    Artist(String),
+    Show(String),
+    /// The user's Liked Songs, `spotify:user:<id>:collection`
+    Collection,
+    /// No context: autoplay or radio after whatever was started ran out
+    Autoplay,
+    /// A context URI that isn't understood
+    Unknown,
+}
+
+impl PlayContext {
+    /// Classifies the context a play reported, None being autoplay.
+    pub fn of(context: Option<&Context>) -> PlayContext {
+        match context {
+            Some(context) => PlayContext::from_uri(&context.uri),
+            None => PlayContext::Autoplay,
+        }
+    }
+
+    /// Classifies a context URI like `spotify:album:<id>`. Playlists also
+    /// come in the old `spotify:user:<user>:playlist:<id>` form, Liked Songs
+    /// as `spotify:user:<user>:collection`.
+    pub fn from_uri(uri: &str) -> PlayContext {
+        let parts: Vec<&str> = uri.split(':').collect();
+        let parts = match parts.as_slice() {
+            ["spotify", "user", user, rest @ ..] if is_id(user) => rest,
+            ["spotify", rest @ ..] => rest,
+            _ => return PlayContext::Unknown,
+        };
+        match parts {
+            ["playlist", id] if is_id(id) => PlayContext::Playlist(id.to_string()),
+            ["album", id] if is_id(id) => PlayContext::Album(id.to_string()),
+            ["artist", id] if is_id(id) => PlayContext::Artist(id.to_string()),
+            ["show", id] if is_id(id) => PlayContext::Show(id.to_string()),
+            ["collection"] | ["collection", "tracks"] => PlayContext::Collection,
+            // Radio stations stand in for autoplay in some clients
+            ["station", ..] => PlayContext::Autoplay,
+            _ => PlayContext::Unknown,
+        }
+    }
+
+    /// The kind of context, e.g. "playlist", without its id.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PlayContext::Playlist(_) => "playlist",
+            PlayContext::Album(_) => "album",
+            PlayContext::Artist(_) => "artist",
+            PlayContext::Show(_) => "show",
+            PlayContext::Collection => "liked songs",
+            PlayContext::Autoplay => "autoplay",
+            PlayContext::Unknown => "unknown",
+        }
+    }
+}
+
+/// Spotify ids and user names in URIs, no separators or spaces.
+fn is_id(part: &str) -> bool {
+    !part.is_empty()
+        && part
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || b == b'-')
+}
+
+/// Item returned from Spotify's API: GetPlaybackState
+/// https://developer.spotify.com/documentation/web-api/reference/get-information-about-the-users-current-playback
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlaybackState {
+    pub device: Device,
+    pub shuffle_state: bool,
+    pub repeat_state: String,
+    #[serde(flatten)]
+    pub playing: CurrentlyPlayingTrack,
+}
+
+/// Item returned from Spotify's API: GetAvailableDevices
+/// https://developer.spotify.com/documentation/web-api/reference/get-a-users-available-devices
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Device {
+    pub id: Option<String>,
+    pub is_active: bool,
+    pub is_private_session: bool,
+    pub is_restricted: bool,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub volume_percent: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub struct Devices {
+    pub devices: Vec<Device>,
+}
+
+/// Item returned from Spotify's API: GetCurrentUser'sProfile
+/// https://developer.spotify.com/documentation/web-api/reference/get-current-users-profile
+/// `email` is only there when the user granted `user-read-email`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserProfile {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub country: Option<String>,
+    pub product: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+impl UserProfile {
+    /// The display name, or the id for users without one.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// Item returned from Spotify's API: GetTheUser'sQueue
+/// https://developer.spotify.com/documentation/web-api/reference/get-queue
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Queue {
+    pub currently_playing: Option<serde_json::Value>,
+    pub queue: Vec<serde_json::Value>,
+}
+
+impl Queue {
+    pub fn get_current_track(&self) -> Option<Track> {
+        self.currently_playing
+            .as_ref()
+            .and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+
+    /// The tracks queued up next, episodes are left out.
+    pub fn upcoming_tracks(&self) -> Vec<Track> {
+        self.queue
+            .iter()
+            .filter_map(|v| serde_json::from_value(v.clone()).ok())
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Artist {
     pub name: String,
     pub id: String,
 }
 
+/// Artist object with the profile details, as returned by the artist and
+/// following endpoints.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArtistFull {
+    pub name: String,
+    pub id: String,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    pub popularity: Option<u32>,
+    pub followers: Option<Followers>,
+    #[serde(default)]
+    pub images: Vec<Image>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Followers {
+    pub total: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Image {
+    pub url: String,
+    pub height: Option<u32>,
+    pub width: Option<u32>,
+}
+
+/// The cover sizes Spotify has, see [Album::best_image].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ImageSize {
+    pub fn width(self) -> u32 {
+        match self {
+            ImageSize::Small => 64,
+            ImageSize::Medium => 300,
+            ImageSize::Large => 640,
+        }
+    }
+}
+
+/// Item returned from Spotify's API: GetFollowed
+/// https://developer.spotify.com/documentation/web-api/reference/get-followed
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FollowedArtists {
+    pub artists: CursorPage<ArtistFull>,
+}
+
+/// A page of a cursor paginated list, the next page starts after `cursors.after`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub limit: u32,
+    pub next: Option<String>,
+    pub cursors: Option<Cursors>,
+    pub total: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Cursors {
+    pub after: Option<String>,
+    /// Only on lists paged back in time, like the recently played tracks
+    #[serde(default)]
+    pub before: Option<String>,
+}
+
+impl<T> CursorPage<T> {
+    /// The cursor to request the next page with, None on the last page.
+    pub fn next_cursor(&self) -> Option<String> {
+        self.next.as_ref()?;
+        self.cursors.as_ref()?.after.clone()
+    }
+
+    /// The cursor to request the older page with, for lists paged back in
+    /// time. None on the last page.
+    pub fn before_cursor(&self) -> Option<String> {
+        self.next.as_ref()?;
+        self.cursors.as_ref()?.before.clone()
+    }
+}
+
+/// Item returned from Spotify's API: GetRecentlyPlayed
+/// https://developer.spotify.com/documentation/web-api/reference/get-recently-played
 #[derive(Serialize, Deserialize, Debug)]
+pub struct RecentlyPlayed {
+    pub track: Track,
+    /// When the track finished playing, e.g. "2024-09-23T21:39:34.000Z"
+    pub played_at: String,
+    #[serde(default)]
+    pub context: Option<Context>,
+}
+
+impl RecentlyPlayed {
+    pub fn played_at(&self) -> Option<SystemTime> {
+        DateTime::parse_from_rfc3339(&self.played_at)
+            .ok()
+            .map(SystemTime::from)
+    }
+}
+
+/// Item returned from Spotify's API: GetPlaylistItems
+/// https://developer.spotify.com/documentation/web-api/reference/get-playlists-tracks
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlaylistItem {
+    pub added_at: Option<String>,
+    #[serde(default)]
+    pub is_local: bool,
+    /// A track or an episode, null when it is no longer available
+    pub track: Option<serde_json::Value>,
+}
+
+impl PlaylistItem {
+    /// The id of the item's track, None for episodes, local files and
+    /// items that are gone.
+    pub fn track_id(&self) -> Option<&str> {
+        let track = self.track.as_ref().filter(|_| !self.is_local)?;
+        if track.get("type")?.as_str()? != "track" {
+            return None;
+        }
+        track.get("id")?.as_str()
+    }
+
+    /// The item's track, None like for [PlaylistItem::track_id].
+    pub fn track(&self) -> Option<Track> {
+        self.track_id()?;
+        serde_json::from_value(self.track.clone()?).ok()
+    }
+}
+
+/// Item returned from Spotify's API: GetListOfCurrentUsersPlaylists
+/// https://developer.spotify.com/documentation/web-api/reference/get-a-list-of-current-users-playlists
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistSummary {
+    pub id: String,
+    pub name: String,
+    pub owner: PlaylistOwner,
+    pub snapshot_id: String,
+    #[serde(default)]
+    pub collaborative: bool,
+    /// None when the owner didn't say
+    #[serde(default)]
+    pub public: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlaylistOwner {
+    pub id: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+}
+
+/// The version of a playlist an edit produced, pass it to the next edit.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlaylistSnapshot {
+    pub snapshot_id: String,
+}
+
+/// A page of an offset paginated list.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub limit: u32,
+    pub offset: u32,
+    pub total: u32,
+    pub next: Option<String>,
+}
+
+/// Item returned from Spotify's API: GetMultipleArtists
+/// https://developer.spotify.com/documentation/web-api/reference/get-multiple-artists
+/// Ids Spotify doesn't know come back as null.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Artists {
+    pub artists: Vec<Option<ArtistFull>>,
+}
+
+/// Item returned from Spotify's API: SearchForItem, with `type=track`
+/// https://developer.spotify.com/documentation/web-api/reference/search
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TrackSearch {
+    pub tracks: Page<Track>,
+}
+
+/// Item returned from Spotify's API: GetNewReleases
+/// https://developer.spotify.com/documentation/web-api/reference/get-new-releases
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NewReleases {
+    pub albums: Page<Album>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Album {
     pub name: String,
     pub id: String,
@@ -34,16 +473,78 @@ pub struct Album {
     pub release_date: String,
     pub album_type: String,
     pub artists: Vec<Artist>,
+    #[serde(default)]
+    pub images: Vec<Image>,
 }
 
+impl Album {
+    /// The smallest cover at least `size` pixels wide, the largest one when
+    /// none is that big.
+    pub fn best_image(&self, size: u32) -> Option<&Image> {
+        self.images
+            .iter()
+            .filter(|image| image.width.is_some_and(|width| width >= size))
+            .min_by_key(|image| image.width)
+            .or_else(|| self.images.iter().max_by_key(|image| image.width))
+    }
+}
+
+/// Item returned from Spotify's API: GetAlbum, the lean [Album] along with
+/// its label and the first page of its tracks
+/// https://developer.spotify.com/documentation/web-api/reference/get-an-album
 #[derive(Serialize, Deserialize, Debug)]
+pub struct AlbumFull {
+    #[serde(flatten)]
+    pub album: Album,
+    /// Up to 50 tracks, `next` continues past them
+    pub tracks: Page<AlbumTrack>,
+    /// Usually empty, Spotify keeps genres on the artists
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub copyrights: Vec<Copyright>,
+}
+
+/// A track as listed on its album, without the album.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlbumTrack {
+    pub name: String,
+    pub id: String,
+    pub artists: Vec<Artist>,
+    pub disc_number: i32,
+    pub track_number: i32,
+    pub duration_ms: u32,
+    pub explicit: bool,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    /// Only there when the request named a market
+    #[serde(default)]
+    pub is_playable: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Copyright {
+    pub text: String,
+    /// `C` for the copyright, `P` for the sound recording (performance) one
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ExternalId {
     pub isrc: Option<String>,
     pub ean: Option<String>,
     pub upc: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ExternalUrls {
+    pub spotify: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Track {
     pub name: String,
     pub id: String,
@@ -52,21 +553,746 @@ pub struct Track {
     pub disc_number: i32,
     pub duration_ms: u32,
     pub external_ids: ExternalId,
+    #[serde(default)]
+    pub external_urls: ExternalUrls,
+    pub explicit: bool,
+    /// Only there when the request named a market
+    #[serde(default)]
+    pub is_playable: Option<bool>,
+    #[serde(default)]
+    pub restrictions: Option<Restrictions>,
+    /// The track that was asked for, when Spotify relinked it to this one
+    /// because that one isn't available in the market. Boxed since it is
+    /// rare and tracks are carried around in enums.
+    #[serde(default)]
+    pub linked_from: Option<Box<LinkedTrack>>,
+    /// Only there when the request didn't name a market
+    #[serde(default)]
+    pub available_markets: Markets,
+}
+
+/// ISO 3166-1 alpha-2 codes of the markets something is available in, as
+/// Spotify lists them. Looking one up builds a set on first use, so
+/// filtering many tracks by market doesn't scan every list each time.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct Markets {
+    codes: Vec<String>,
+    lookup: OnceLock<HashSet<String>>,
+}
+
+impl Markets {
+    pub fn contains(&self, code: &str) -> bool {
+        self.lookup
+            .get_or_init(|| self.codes.iter().cloned().collect())
+            .contains(code)
+    }
+
+    pub fn as_slice(&self) -> &[String] {
+        &self.codes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+}
+
+impl From<Vec<String>> for Markets {
+    fn from(codes: Vec<String>) -> Markets {
+        Markets {
+            codes,
+            lookup: OnceLock::new(),
+        }
+    }
+}
+
+impl From<Markets> for Vec<String> {
+    fn from(markets: Markets) -> Vec<String> {
+        markets.codes
+    }
+}
+
+impl PartialEq for Markets {
+    fn eq(&self, other: &Markets) -> bool {
+        self.codes == other.codes
+    }
+}
+
+impl fmt::Debug for Markets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.codes).finish()
+    }
+}
+
+/// The original of a relinked track.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkedTrack {
+    pub id: String,
+    pub uri: String,
+}
+
+/// Why Spotify won't play something, e.g. `market`, `product` or `explicit`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Restrictions {
+    pub reason: String,
+}
+
+impl Track {
+    /// The track's page on open.spotify.com.
+    pub fn spotify_url(&self) -> String {
+        self.external_urls
+            .spotify
+            .clone()
+            .unwrap_or_else(|| format!("https://open.spotify.com/track/{}", self.id))
+    }
+
+    /// The track's Spotify URI, as the queue and playlists take it.
+    pub fn uri(&self) -> String {
+        format!("spotify:track:{}", self.id)
+    }
+
+    /// The id of the track that was asked for, e.g. the one in the user's
+    /// library, which differs from `id` when Spotify relinked it.
+    pub fn original_id(&self) -> &str {
+        self.linked_from
+            .as_ref()
+            .map_or(&self.id, |linked| &linked.id)
+    }
+
+    /// Why the track can't be played, e.g. to explain a greyed out row.
+    /// None when it is playable or Spotify gave no reason.
+    pub fn playback_restriction_reason(&self) -> Option<&str> {
+        self.restrictions
+            .as_ref()
+            .map(|restrictions| restrictions.reason.as_str())
+    }
+
+    /// Whether Spotify lists `market` among the track's markets. Tracks
+    /// fetched for a market come without the list, `is_playable` tells then.
+    pub fn is_available_in(&self, market: &str) -> bool {
+        self.available_markets.contains(market)
+    }
+}
+
+/// Podcast episode, as found in the player's `item`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Episode {
+    pub name: String,
+    pub id: String,
+    pub duration_ms: u32,
+    pub release_date: Option<String>,
     pub explicit: bool,
+    /// Only there with the `user-read-playback-position` scope
+    #[serde(default)]
+    pub resume_point: Option<ResumePoint>,
+    pub show: Show,
+}
+
+/// Item returned from Spotify's API: GetShow
+/// https://developer.spotify.com/documentation/web-api/reference/get-a-show
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Show {
+    pub name: String,
+    pub id: String,
+    pub publisher: String,
+    #[serde(default)]
+    pub total_episodes: Option<u32>,
+}
+
+/// Where the user left off in an episode.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ResumePoint {
+    pub fully_played: bool,
+    pub resume_position_ms: u32,
+}
+
+impl ResumePoint {
+    /// How far into an episode of `duration_ms` the user got, a fully played
+    /// episode is all of it.
+    pub fn position_ms(&self, duration_ms: u32) -> u32 {
+        match self.fully_played {
+            true => duration_ms,
+            false => self.resume_position_ms.min(duration_ms),
+        }
+    }
+}
+
+/// Episode in a show's episode list, which doesn't repeat the show.
+/// https://developer.spotify.com/documentation/web-api/reference/get-a-shows-episodes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShowEpisode {
+    pub name: String,
+    pub id: String,
+    pub duration_ms: u32,
+    pub release_date: Option<String>,
+    pub explicit: bool,
+    #[serde(default)]
+    pub resume_point: Option<ResumePoint>,
+}
+
+/// Item returned from Spotify's API: GetUser'sSavedEpisodes
+/// https://developer.spotify.com/documentation/web-api/reference/get-users-saved-episodes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedEpisode {
+    pub added_at: String,
+    pub episode: Episode,
+}
+
+/// Item returned from Spotify's API: GetUsersSavedTracks
+/// https://developer.spotify.com/documentation/web-api/reference/get-users-saved-tracks
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedTrack {
+    pub added_at: String,
+    pub track: Track,
+}
+
+/// Item returned from Spotify's API: GetUsersSavedAlbums
+/// https://developer.spotify.com/documentation/web-api/reference/get-users-saved-albums
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedAlbum {
+    pub added_at: String,
+    pub album: Album,
+}
+
+/// Audiobook chapter, as found in the player's `item`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Chapter {
+    pub name: String,
+    pub id: String,
+    pub duration_ms: u32,
+    pub chapter_number: Option<u32>,
+    pub audiobook: Audiobook,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Audiobook {
+    pub name: String,
+    pub id: String,
+    pub authors: Vec<Author>,
+    pub publisher: Option<String>,
+    pub total_chapters: Option<u32>,
+}
+
+impl Audiobook {
+    /// The author names joined for display, e.g. "Terry Pratchett, Neil Gaiman".
+    pub fn author_names(&self) -> String {
+        let names: Vec<_> = self.authors.iter().map(|a| a.name.as_str()).collect();
+        names.join(", ")
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Author {
+    pub name: String,
+}
+
+/// Item returned from Spotify's API: GetTrack'sAudioFeatures
+/// https://developer.spotify.com/documentation/web-api/reference/get-audio-features
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AudioFeatures {
+    pub id: String,
+    pub acousticness: f32,
+    pub danceability: f32,
+    pub energy: f32,
+    pub instrumentalness: f32,
+    pub key: i32,
+    pub liveness: f32,
+    pub loudness: f32,
+    pub mode: i32,
+    pub speechiness: f32,
+    pub tempo: f32,
+    pub time_signature: i32,
+    pub valence: f32,
+    pub duration_ms: u32,
+}
+
+/// Item returned from Spotify's API: GetTrack'sAudioAnalysis, only the
+/// sections are kept of it
+/// https://developer.spotify.com/documentation/web-api/reference/get-audio-analysis
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AudioAnalysis {
+    pub sections: Vec<Section>,
+}
+
+/// A part of a track, e.g. a verse or a chorus. Times are in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Section {
+    pub start: f64,
+    pub duration: f64,
+    pub loudness: f64,
+    pub tempo: f64,
+}
+
+impl AudioAnalysis {
+    /// Index of the section playing `position` into the track, the last one
+    /// for positions past its end.
+    pub fn section_at(&self, position: Duration) -> Option<usize> {
+        let secs = position.as_secs_f64();
+        self.sections
+            .partition_point(|section| section.start <= secs)
+            .checked_sub(1)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil::load_sample;
 
     #[test]
     fn test_currently_playing() {
-        let full_response =
-            std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let full_response = load_sample("currently_playing_track.json");
         let res: CurrentlyPlayingTrack = serde_json::from_str(&full_response).unwrap();
-        assert_eq!(res.currently_playing_type, "track");
+        assert_eq!(res.currently_playing_type, PlayingType::Track);
+        assert!(res.is_playing);
+        assert_eq!(res.progress_ms, Some(1961));
         let track: Track = serde_json::from_value(res.item.unwrap()).unwrap();
-        println!("parsed track: {track:?}");
-        assert!(false);
+        assert_eq!(track.id, "1VY823dFzI9L8BEf2X7B5I");
+        assert_eq!(track.name, "The Divine Zero");
+        assert_eq!(track.duration_ms, 248853);
+        assert_eq!(track.album.name, "Misadventures");
+        assert_eq!(track.artists.len(), 1);
+        assert_eq!(track.artists[0].name, "Pierce The Veil");
+        assert!(!track.explicit);
+    }
+
+    fn playing(name: &str) -> CurrentlyPlayingTrack {
+        let full_response = load_sample(name);
+        serde_json::from_str(&full_response).unwrap()
+    }
+
+    #[test]
+    fn test_playing_item_track() {
+        match playing("currently_playing_track.json").into_playing_item() {
+            Some(PlayingItem::Track(track)) => assert_eq!(track.name, "The Divine Zero"),
+            other => panic!("expected a track, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_play_context_from_uri() {
+        let cases = [
+            (
+                "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                PlayContext::Playlist("37i9dQZF1DXcBWIGoYBM5M".to_string()),
+            ),
+            (
+                "spotify:user:spotify:playlist:37i9dQZF1DXcBWIGoYBM5M",
+                PlayContext::Playlist("37i9dQZF1DXcBWIGoYBM5M".to_string()),
+            ),
+            (
+                "spotify:album:1wV3Oun1eOsGZWihTuTApq",
+                PlayContext::Album("1wV3Oun1eOsGZWihTuTApq".to_string()),
+            ),
+            (
+                "spotify:artist:4iHNK0tOyZPYnBU7nGAgpQ",
+                PlayContext::Artist("4iHNK0tOyZPYnBU7nGAgpQ".to_string()),
+            ),
+            (
+                "spotify:show:5CfCWKI5pZ28U0uOzXkDHe",
+                PlayContext::Show("5CfCWKI5pZ28U0uOzXkDHe".to_string()),
+            ),
+            (
+                "spotify:user:1260305620:collection",
+                PlayContext::Collection,
+            ),
+            (
+                "spotify:user:some.user_name:collection",
+                PlayContext::Collection,
+            ),
+            ("spotify:collection:tracks", PlayContext::Collection),
+            (
+                "spotify:station:artist:4iHNK0tOyZPYnBU7nGAgpQ",
+                PlayContext::Autoplay,
+            ),
+            ("spotify:album:", PlayContext::Unknown),
+            (
+                "spotify:album:1wV3Oun1eOsGZWihTuTApq:extra",
+                PlayContext::Unknown,
+            ),
+            ("spotify:user::collection", PlayContext::Unknown),
+            ("spotify:user:1260305620", PlayContext::Unknown),
+            ("spotify:playlist:not an id", PlayContext::Unknown),
+            (
+                "spotify:audiobook:7iHfbu1YPACw6oZPAFJtqe",
+                PlayContext::Unknown,
+            ),
+            (
+                "https://open.spotify.com/album/1wV3Oun1eOsGZWihTuTApq",
+                PlayContext::Unknown,
+            ),
+            ("", PlayContext::Unknown),
+        ];
+        for (uri, expected) in cases {
+            assert_eq!(PlayContext::from_uri(uri), expected, "{uri}");
+        }
+        assert_eq!(PlayContext::of(None), PlayContext::Autoplay);
+        let liked = playing("currently_playing_track.json").context;
+        assert_eq!(PlayContext::of(liked.as_ref()), PlayContext::Collection);
+    }
+
+    #[test]
+    fn test_transitioning_status() {
+        let transition = playing("currently_playing_transition.json");
+        assert_eq!(transition.status(), PlaybackStatus::Transitioning);
+        assert!(transition.get_track_data().is_none());
+
+        let mut stopped = transition;
+        stopped.is_playing = false;
+        assert_eq!(stopped.status(), PlaybackStatus::Stopped);
+        let mut paused = playing("currently_playing_track.json");
+        assert_eq!(paused.status(), PlaybackStatus::Playing);
+        paused.is_playing = false;
+        assert_eq!(paused.status(), PlaybackStatus::Paused);
+    }
+
+    #[test]
+    fn test_playing_item_episode() {
+        let res = playing("currently_playing_episode.json");
+        assert!(res.get_track_data().is_none());
+        match res.into_playing_item() {
+            Some(PlayingItem::Episode(episode)) => {
+                assert_eq!(episode.name, "The Sound of Cities");
+                assert_eq!(episode.show.name, "Listening Room");
+                assert_eq!(episode.duration_ms, 2685023);
+            }
+            other => panic!("expected an episode, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_playing_item_chapter() {
+        let res = playing("currently_playing_chapter.json");
+        assert_eq!(res.currently_playing_type, PlayingType::Chapter);
+        match res.into_playing_item() {
+            Some(PlayingItem::Chapter(chapter)) => {
+                assert_eq!(chapter.name, "Chapter 3: The Long Road");
+                assert_eq!(chapter.chapter_number, Some(3));
+                assert_eq!(chapter.duration_ms, 1842000);
+                assert_eq!(chapter.audiobook.name, "A Wizard of Earthsea");
+                assert_eq!(chapter.audiobook.author_names(), "Ursula K. Le Guin");
+            }
+            other => panic!("expected a chapter, got {other:?}"),
+        }
+
+        // A chapter type with a track body doesn't parse
+        let mut res = playing("currently_playing_track.json");
+        res.currently_playing_type = PlayingType::Chapter;
+        assert!(res.into_playing_item().is_none());
+    }
+
+    #[test]
+    fn test_saved_audiobooks() {
+        let full_response = load_sample("saved_audiobooks.json");
+        let page: Page<Audiobook> = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.items[1].name, "Good Omens");
+        assert_eq!(page.items[1].author_names(), "Terry Pratchett, Neil Gaiman");
+        assert_eq!(page.items[0].total_chapters, Some(12));
+    }
+
+    #[test]
+    fn test_saved_episodes() {
+        let full_response = load_sample("saved_episodes.json");
+        let page: Page<SavedEpisode> = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(page.total, 2);
+        let episode = &page.items[0].episode;
+        assert_eq!(episode.show.name, "Listening Room");
+        assert_eq!(episode.show.total_episodes, Some(212));
+        let resume = episode.resume_point.unwrap();
+        assert_eq!(resume.position_ms(episode.duration_ms), 1_342_511);
+
+        // Fully played episodes start over at 0
+        let played = &page.items[1].episode;
+        let resume = played.resume_point.unwrap();
+        assert_eq!(resume.resume_position_ms, 0);
+        assert_eq!(resume.position_ms(played.duration_ms), played.duration_ms);
+    }
+
+    #[test]
+    fn test_show_episodes() {
+        let full_response = load_sample("show_episodes.json");
+        let page: Page<ShowEpisode> = serde_json::from_str(&full_response).unwrap();
+        assert_eq!((page.total, page.items.len()), (212, 2));
+        assert!(page.next.is_some());
+        assert_eq!(page.items[1].name, "Room Tone");
+        assert!(page.items[1].resume_point.is_none());
+
+        // The player's episodes have no resume point either without the scope
+        let playing = playing("currently_playing_episode.json");
+        let Some(PlayingItem::Episode(episode)) = playing.into_playing_item() else {
+            panic!("Not an episode");
+        };
+        assert!(episode.resume_point.is_none());
+    }
+
+    #[test]
+    fn test_playing_item_ad_and_unknown() {
+        let mut res = playing("currently_playing_track.json");
+        res.currently_playing_type = PlayingType::Ad;
+        res.item = None;
+        assert!(matches!(res.into_playing_item(), Some(PlayingItem::Ad)));
+
+        let mut res = playing("currently_playing_track.json");
+        res.currently_playing_type = PlayingType::Unknown;
+        assert!(res.into_playing_item().is_none());
+
+        // An episode type with a track body doesn't parse as either
+        let mut res = playing("currently_playing_track.json");
+        res.currently_playing_type = PlayingType::Episode;
+        assert!(res.into_playing_item().is_none());
+    }
+
+    #[test]
+    fn test_playing_type() {
+        for (raw, expected) in [
+            ("track", PlayingType::Track),
+            ("episode", PlayingType::Episode),
+            ("ad", PlayingType::Ad),
+            ("chapter", PlayingType::Chapter),
+            ("audiobook", PlayingType::Chapter),
+            ("unknown", PlayingType::Unknown),
+            ("audiobook_v2", PlayingType::Unknown),
+        ] {
+            let parsed: PlayingType = serde_json::from_str(&format!("\"{raw}\"")).unwrap();
+            assert_eq!(parsed, expected);
+        }
+        assert_eq!(PlayingType::Episode.to_string(), "episode");
+    }
+
+    #[test]
+    fn test_playback_state() {
+        let full_response = load_sample("playback_state.json");
+        let res: PlaybackState = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(res.device.name, "Kitchen speaker");
+        assert!(res.playing.is_playing);
+        assert_eq!(
+            res.playing.get_track_data().unwrap().name,
+            "The Divine Zero"
+        );
+    }
+
+    #[test]
+    fn test_devices() {
+        let full_response = load_sample("devices.json");
+        let res: Devices = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(res.devices.len(), 2);
+        assert_eq!(res.devices[1].device_type, "Computer");
+    }
+
+    #[test]
+    fn test_queue() {
+        let full_response = load_sample("queue.json");
+        let res: Queue = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(res.get_current_track().unwrap().name, "The Divine Zero");
+        assert_eq!(res.queue.len(), 1);
+        assert_eq!(res.upcoming_tracks().len(), 1);
+    }
+
+    #[test]
+    fn test_playlist_items() {
+        let full_response = load_sample("playlist_items.json");
+        let res: Page<PlaylistItem> = serde_json::from_str(&full_response).unwrap();
+        let ids: Vec<Option<&str>> = res.items.iter().map(PlaylistItem::track_id).collect();
+        assert_eq!(
+            ids,
+            [
+                Some("6mFkJmJqdDVQ1REhVfGgd1"),
+                Some("4N1MFKjziFHH4IS3RYYUrU"),
+                None,
+                None,
+                None
+            ]
+        );
+        assert!(res.next.is_some());
+    }
+
+    #[test]
+    fn test_track_search() {
+        let full_response = load_sample("search_tracks.json");
+        let res: TrackSearch = serde_json::from_str(&full_response).unwrap();
+        let names: Vec<&str> = res.tracks.items.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["Heroes", "Heroes", "Hold On Till May"]);
+        assert_eq!(res.tracks.items[1].artists[0].name, "Peter Gabriel");
+        assert_eq!(res.tracks.total, 900);
+    }
+
+    #[test]
+    fn test_restricted_track() {
+        let full_response = load_sample("restricted_track.json");
+        let track: Track = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(track.is_playable, Some(false));
+        assert_eq!(track.playback_restriction_reason(), Some("market"));
+
+        let playing = playing("currently_playing_track.json");
+        let track = playing.get_track_data().unwrap();
+        assert_eq!(track.is_playable, None);
+        assert_eq!(track.playback_restriction_reason(), None);
+    }
+
+    #[test]
+    fn test_relinked_track() {
+        let track: Track = serde_json::from_str(&load_sample("relinked_track.json")).unwrap();
+        assert_eq!(track.id, "6mFkJmJqdDVQ1REhVfGgd1");
+        assert_eq!(track.original_id(), "2GnPNoTCTkDDvwSO3mdHa6");
+        let linked = track.linked_from.unwrap();
+        assert_eq!(linked.uri, "spotify:track:2GnPNoTCTkDDvwSO3mdHa6");
+
+        let track = playing("currently_playing_track.json")
+            .get_track_data()
+            .unwrap();
+        assert!(track.linked_from.is_none());
+        assert_eq!(track.original_id(), track.id);
+    }
+
+    #[test]
+    fn test_available_markets() {
+        let mut item = playing("currently_playing_track.json").item.unwrap();
+        let listed = ["SE", "NO", "DK", "FI", "US", "GB", "DE", "FR"];
+        item["available_markets"] = serde_json::json!(listed);
+        let track: Track = serde_json::from_value(item).unwrap();
+        assert_eq!(track.available_markets.as_slice(), listed);
+
+        // The set gives the same answers as scanning the list
+        for code in ["SE", "GB", "FR", "JP", "BR", "se", ""] {
+            let scanned = track.available_markets.as_slice().iter().any(|c| c == code);
+            assert_eq!(track.is_available_in(code), scanned, "market {code}");
+        }
+        let json = serde_json::to_value(&track).unwrap();
+        assert_eq!(json["available_markets"], serde_json::json!(listed));
+
+        // Fetched for a market, the list is empty or missing
+        let track = playing("currently_playing_track.json")
+            .get_track_data()
+            .unwrap();
+        assert!(track.available_markets.is_empty());
+        assert!(!track.is_available_in("SE"));
+        let track: Track = serde_json::from_str(&load_sample("relinked_track.json")).unwrap();
+        assert!(track.available_markets.is_empty());
+    }
+
+    #[test]
+    fn test_best_image() {
+        let track = playing("currently_playing_track.json")
+            .get_track_data()
+            .unwrap();
+        let width = |size| track.album.best_image(size).and_then(|image| image.width);
+        assert_eq!(width(300), Some(300));
+        assert_eq!(width(200), Some(300));
+        assert_eq!(width(0), Some(64));
+        assert_eq!(width(1000), Some(640));
+
+        let mut album = track.album.clone();
+        album.images.clear();
+        assert!(album.best_image(300).is_none());
+    }
+
+    #[test]
+    fn test_audio_analysis() {
+        let analysis: AudioAnalysis =
+            serde_json::from_str(&load_sample("audio_analysis.json")).unwrap();
+        assert_eq!(analysis.sections.len(), 4);
+        assert_eq!(analysis.sections[1].start, 21.43129);
+        assert_eq!(analysis.sections[1].tempo, 98.004);
+
+        let at = |secs: f64| analysis.section_at(Duration::from_secs_f64(secs));
+        assert_eq!(at(0.0), Some(0));
+        assert_eq!(at(21.43), Some(0));
+        assert_eq!(at(21.44), Some(1));
+        assert_eq!(at(500.0), Some(3));
+    }
+
+    #[test]
+    fn test_audio_features() {
+        let full_response = load_sample("audio_features.json");
+        let res: AudioFeatures = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(res.id, "1VY823dFzI9L8BEf2X7B5I");
+        assert_eq!(res.time_signature, 4);
+    }
+
+    #[test]
+    fn test_followed_artists() {
+        let full_response = load_sample("followed_artists.json");
+        let res: FollowedArtists = serde_json::from_str(&full_response).unwrap();
+        let page = res.artists;
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.items[0].name, "The Beatles");
+        assert_eq!(page.items[0].followers.as_ref().unwrap().total, 31207764);
+        assert_eq!(page.items[1].genres.len(), 3);
+        assert_eq!(
+            page.next_cursor().as_deref(),
+            Some("0oSGxfWSnnOXhD2fKuz2Gy")
+        );
+
+        let last_page: CursorPage<ArtistFull> = serde_json::from_value(serde_json::json!({
+            "items": [], "limit": 2, "next": null,
+            "cursors": { "after": "0oSGxfWSnnOXhD2fKuz2Gy" }, "total": 14
+        }))
+        .unwrap();
+        assert_eq!(last_page.next_cursor(), None);
+    }
+
+    #[test]
+    fn test_recently_played() {
+        let full_response = load_sample("recently_played.json");
+        let page: CursorPage<RecentlyPlayed> = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(page.items.len(), 2);
+        assert_eq!(page.items[0].track.name, "The Divine Zero");
+        assert_eq!(
+            page.items[1].played_at(),
+            Some(SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1727126812345))
+        );
+        assert_eq!(page.before_cursor().as_deref(), Some("1727126812345"));
+    }
+
+    #[test]
+    fn test_new_releases() {
+        let full_response = load_sample("new_releases.json");
+        let res: NewReleases = serde_json::from_str(&full_response).unwrap();
+        let page = res.albums;
+        assert_eq!(page.total, 100);
+        assert!(page.next.is_some());
+        let names: Vec<_> = page.items.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, ["Random Access Memories", "Wish You Were Here"]);
+        assert_eq!(page.items[0].images.len(), 2);
+        assert_eq!(
+            page.items[1].images[0].url,
+            "https://i.scdn.co/image/ab67616d0000b273f05e5ac32fe2b4d6d8b0f1d4"
+        );
+    }
+
+    #[test]
+    fn test_album_full() {
+        let album: AlbumFull = serde_json::from_str(&load_sample("album.json")).unwrap();
+        assert_eq!(album.album.name, "Misadventures");
+        assert_eq!(album.tracks.items.len(), album.album.total_tracks as usize);
+        assert_eq!(album.tracks.total, 11);
+        assert!(album.tracks.next.is_none());
+        let track = &album.tracks.items[2];
+        assert_eq!(
+            (track.name.as_str(), track.track_number),
+            ("The Divine Zero", 3)
+        );
+        assert_eq!(track.id, "1VY823dFzI9L8BEf2X7B5I");
+        assert_eq!(album.label.as_deref(), Some("Fearless Records"));
+        assert_eq!(album.copyrights[1].kind, "P");
+        assert!(album.genres.is_empty());
+    }
+
+    #[test]
+    fn test_user_profile() {
+        let full_response = load_sample("me.json");
+        let profile: UserProfile = serde_json::from_str(&full_response).unwrap();
+        assert_eq!(profile.name(), "Jorge");
+        assert_eq!(profile.product.as_deref(), Some("premium"));
+        assert_eq!(profile.email.as_deref(), Some("jorge@example.com"));
+
+        // Without user-read-email
+        let profile: UserProfile = serde_json::from_value(serde_json::json!({
+            "id": "jorge", "display_name": null, "country": "SE", "product": "free"
+        }))
+        .unwrap();
+        assert_eq!(profile.name(), "jorge");
+        assert_eq!(profile.email, None);
     }
 }
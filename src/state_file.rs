@@ -0,0 +1,166 @@
+use crate::error::NewerStateFile;
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+
+/// Turns the data of one version into the next.
+pub type Upgrade = fn(Value) -> Result<Value>;
+
+/// The version and history of one internal state file, written wrapped in
+/// `{"v": 2, "data": ...}` so a file from a newer build is refused instead
+/// of misparsed. Files from before the wrapping are version 1.
+pub struct StateFormat {
+    /// The version written
+    pub version: u32,
+    /// `upgrades[n]` turns data of version `n + 1` into version `n + 2`,
+    /// one for each version before [StateFormat::version]
+    pub upgrades: &'static [Upgrade],
+}
+
+/// The upgrade of data whose shape didn't change, e.g. from version 1 when
+/// only the wrapping was added.
+pub fn unchanged(data: Value) -> Result<Value> {
+    Ok(data)
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, D> {
+    v: u32,
+    data: &'a D,
+}
+
+impl StateFormat {
+    pub fn to_string<D: Serialize>(&self, data: &D) -> Result<String> {
+        let envelope = Envelope {
+            v: self.version,
+            data,
+        };
+        Ok(serde_json::to_string(&envelope)?)
+    }
+
+    /// Reads what [StateFormat::to_string] wrote, upgrading older versions.
+    /// On Error: not JSON of this format, or a [NewerStateFile].
+    pub fn parse<D: DeserializeOwned>(&self, text: &str, path: &Path) -> Result<D> {
+        let value: Value = serde_json::from_str(text)
+            .with_context(|| format!("{} is not JSON", path.display()))?;
+        self.parse_value(value, path)
+    }
+
+    /// [StateFormat::parse] of JSON read already.
+    pub fn parse_value<D: DeserializeOwned>(&self, value: Value, path: &Path) -> Result<D> {
+        let (mut version, mut data) = unwrap_envelope(value);
+        if version > self.version {
+            return Err(NewerStateFile {
+                path: path.to_path_buf(),
+                version,
+                supported: self.version,
+            }
+            .into());
+        }
+        while version < self.version {
+            let Some(upgrade) = self.upgrades.get(version as usize - 1) else {
+                bail!(
+                    "{} is v{version}, but no upgrade from it to v{} is known",
+                    path.display(),
+                    self.version
+                );
+            };
+            data = upgrade(data)
+                .with_context(|| format!("Could not upgrade {} from v{version}", path.display()))?;
+            version += 1;
+        }
+        serde_json::from_value(data)
+            .with_context(|| format!("{} has unexpected contents", path.display()))
+    }
+}
+
+/// The version and the data of a file, version 1 without an envelope.
+fn unwrap_envelope(value: Value) -> (u32, Value) {
+    if let Value::Object(mut map) = value {
+        let version = map.get("v").and_then(Value::as_u64);
+        if let (Some(version), true, 2) = (version, map.contains_key("data"), map.len()) {
+            let data = map.remove("data").unwrap_or_default();
+            return (u32::try_from(version).unwrap_or(u32::MAX).max(1), data);
+        }
+        return (1, Value::Object(map));
+    }
+    (1, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Counter {
+        count: u32,
+        label: String,
+    }
+
+    /// v2 renamed `n` to `count`, v3 added `label`.
+    const COUNTER: StateFormat = StateFormat {
+        version: 3,
+        upgrades: &[rename_n, add_label],
+    };
+
+    fn rename_n(mut data: Value) -> Result<Value> {
+        if let Some(map) = data.as_object_mut() {
+            let n = map.remove("n").unwrap_or_default();
+            map.insert("count".to_string(), n);
+        }
+        Ok(data)
+    }
+
+    fn add_label(mut data: Value) -> Result<Value> {
+        data["label"] = "unnamed".into();
+        Ok(data)
+    }
+
+    #[test]
+    fn test_state_format_upgrades() {
+        let path = Path::new("counter.json");
+        let counter = Counter {
+            count: 3,
+            label: "plays".to_string(),
+        };
+        let text = COUNTER.to_string(&counter).unwrap();
+        assert_eq!(text, r#"{"v":3,"data":{"count":3,"label":"plays"}}"#);
+        assert_eq!(COUNTER.parse::<Counter>(&text, path).unwrap(), counter);
+
+        // Untagged is version 1
+        let upgraded: Counter = COUNTER.parse(r#"{"n": 5}"#, path).unwrap();
+        assert_eq!((upgraded.count, upgraded.label.as_str()), (5, "unnamed"));
+        let upgraded: Counter = COUNTER
+            .parse(r#"{"v": 2, "data": {"count": 6}}"#, path)
+            .unwrap();
+        assert_eq!(upgraded.count, 6);
+
+        let err = COUNTER
+            .parse::<Counter>(r#"{"v": 4, "data": {"count": 7}}"#, path)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<NewerStateFile>(),
+            Some(&NewerStateFile {
+                path: path.to_path_buf(),
+                version: 4,
+                supported: 3,
+            })
+        );
+        assert!(COUNTER.parse::<Counter>("{", path).is_err());
+
+        // A format missing an upgrade errors instead of panicking
+        let missing = StateFormat {
+            version: 3,
+            upgrades: &[rename_n],
+        };
+        let err = missing.parse::<Counter>(r#"{"n": 5}"#, path).unwrap_err();
+        assert!(err.to_string().contains("no upgrade"), "{err}");
+        assert!(COUNTER
+            .parse::<Counter>(r#"{"v": 3, "data": 1}"#, path)
+            .is_err());
+    }
+}
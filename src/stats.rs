@@ -0,0 +1,1271 @@
+use crate::device_aliases::DeviceAliases;
+use crate::history::PlayHistoryEntry;
+use crate::spotify_data::{Artist, AudioAnalysis, PlayContext, Section};
+
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeDelta, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+
+/// How many artists, tracks and shows [ListeningStats] ranks.
+pub const LISTENING_STATS_TOP: usize = 5;
+
+/// How much was listened to under one tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagStats {
+    pub tag: String,
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+/// Plays and listening time per tag, most played first.
+/// A play with several tags counts towards each of them.
+pub fn tag_stats(entries: &[PlayHistoryEntry]) -> Vec<TagStats> {
+    let mut by_tag: HashMap<&str, TagStats> = HashMap::new();
+    for entry in entries {
+        for tag in &entry.tags {
+            let stats = by_tag.entry(tag).or_insert_with(|| TagStats {
+                tag: tag.clone(),
+                plays: 0,
+                listened: Duration::ZERO,
+            });
+            stats.plays += 1;
+            stats.listened += entry.listened();
+        }
+    }
+
+    let mut stats: Vec<TagStats> = by_tag.into_values().collect();
+    stats.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.tag.cmp(&b.tag)));
+    stats
+}
+
+/// When `day` starts in `tz`. That's midnight, unless a DST change skips it,
+/// then it's the moment the clocks jump to.
+pub fn start_of_day<Tz: TimeZone>(day: NaiveDate, tz: &Tz) -> DateTime<Tz> {
+    local_time(day, NaiveTime::MIN, tz)
+}
+
+/// `time` on `day` in `tz`, the earlier one when the clocks go back over it
+/// and the moment they jump to when they skip it.
+pub fn local_time<Tz: TimeZone>(day: NaiveDate, time: NaiveTime, tz: &Tz) -> DateTime<Tz> {
+    let local = day.and_time(time);
+    // Clocks jump by whole quarters of an hour
+    (0..24 * 4)
+        .find_map(|quarter| {
+            tz.from_local_datetime(&(local + TimeDelta::minutes(15 * quarter)))
+                .earliest()
+        })
+        .unwrap_or_else(|| tz.from_utc_datetime(&local))
+}
+
+/// Start of the range covering the last `days` calendar days in the time
+/// zone of `now`, today included. Follows the local day boundaries, so a
+/// range over a DST change is an hour longer or shorter.
+pub fn last_days_start<Tz: TimeZone>(days: u64, now: &DateTime<Tz>) -> DateTime<Tz> {
+    let today = now.date_naive();
+    let first = today
+        .checked_sub_days(Days::new(days.saturating_sub(1)))
+        .unwrap_or(NaiveDate::MIN);
+    start_of_day(first, &now.timezone())
+}
+
+/// How much one artist was listened to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArtistStats {
+    pub name: String,
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+/// Plays and listening time per artist, most played first, at most `limit`.
+/// A play with several artists counts towards each of them.
+pub fn top_artists(entries: &[PlayHistoryEntry], limit: usize) -> Vec<ArtistStats> {
+    let mut by_artist: HashMap<&str, ArtistStats> = HashMap::new();
+    for entry in entries {
+        for artist in &entry.artists {
+            let stats = by_artist
+                .entry(&artist.name)
+                .or_insert_with(|| ArtistStats {
+                    name: artist.name.clone(),
+                    plays: 0,
+                    listened: Duration::ZERO,
+                });
+            stats.plays += 1;
+            stats.listened += entry.listened();
+        }
+    }
+
+    let mut stats: Vec<ArtistStats> = by_artist.into_values().collect();
+    stats.sort_by(|a, b| b.plays.cmp(&a.plays).then_with(|| a.name.cmp(&b.name)));
+    stats.truncate(limit);
+    stats
+}
+
+/// How much one track was listened to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrackStats {
+    pub track_id: String,
+    pub name: String,
+    /// The track's artists, comma separated
+    pub artists: String,
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+/// Plays and listening time per track, most played first, at most `limit`.
+/// Episodes are left out, see [top_shows].
+pub fn top_tracks(entries: &[PlayHistoryEntry], limit: usize) -> Vec<TrackStats> {
+    let mut by_track: HashMap<&str, TrackStats> = HashMap::new();
+    for entry in entries.iter().filter(|entry| !entry.is_episode()) {
+        let stats = by_track
+            .entry(&entry.track_id)
+            .or_insert_with(|| TrackStats {
+                track_id: entry.track_id.clone(),
+                name: entry.track_name.clone(),
+                artists: entry
+                    .artists
+                    .iter()
+                    .map(|artist| artist.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                plays: 0,
+                listened: Duration::ZERO,
+            });
+        stats.plays += 1;
+        stats.listened += entry.listened();
+    }
+
+    let mut stats: Vec<TrackStats> = by_track.into_values().collect();
+    stats.sort_by(|a, b| {
+        b.plays
+            .cmp(&a.plays)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.track_id.cmp(&b.track_id))
+    });
+    stats.truncate(limit);
+    stats
+}
+
+/// How much of one podcast was listened to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShowStats {
+    pub show_id: String,
+    pub name: String,
+    /// Episode plays, an episode played twice counts twice
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+/// Plays and listening time per show, most listened first, at most `limit`.
+pub fn top_shows(entries: &[PlayHistoryEntry], limit: usize) -> Vec<ShowStats> {
+    let mut by_show: HashMap<&str, ShowStats> = HashMap::new();
+    for entry in entries {
+        let Some(show) = &entry.show else {
+            continue;
+        };
+        let stats = by_show.entry(&show.id).or_insert_with(|| ShowStats {
+            show_id: show.id.clone(),
+            name: show.name.clone(),
+            plays: 0,
+            listened: Duration::ZERO,
+        });
+        stats.plays += 1;
+        stats.listened += entry.listened();
+    }
+
+    let mut stats: Vec<ShowStats> = by_show.into_values().collect();
+    stats.sort_by(|a, b| {
+        b.listened
+            .cmp(&a.listened)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.show_id.cmp(&b.show_id))
+    });
+    stats.truncate(limit);
+    stats
+}
+
+/// How much was listened to on one device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceStats {
+    /// The device's label, see [DeviceAliases]
+    pub device: String,
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+/// Plays and listening time per device label, most listened first.
+pub fn device_stats(entries: &[PlayHistoryEntry], aliases: &DeviceAliases) -> Vec<DeviceStats> {
+    let mut by_device: HashMap<&str, DeviceStats> = HashMap::new();
+    for entry in entries {
+        let device = aliases.device_of(entry);
+        let stats = by_device.entry(device).or_insert_with(|| DeviceStats {
+            device: device.to_string(),
+            plays: 0,
+            listened: Duration::ZERO,
+        });
+        stats.plays += 1;
+        stats.listened += entry.listened();
+    }
+
+    let mut stats: Vec<DeviceStats> = by_device.into_values().collect();
+    stats.sort_by(|a, b| {
+        b.listened
+            .cmp(&a.listened)
+            .then_with(|| a.device.cmp(&b.device))
+    });
+    stats
+}
+
+/// How much was listened to from one kind of context, e.g. playlists.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContextStats {
+    /// See [PlayContext::kind], [UNRECORDED_CONTEXT] for plays without one
+    pub kind: String,
+    pub plays: usize,
+    pub listened: Duration,
+    /// Of all the listening time, from 0 to 1
+    pub share: f64,
+}
+
+/// The kind of the plays recorded before contexts were, and imported ones.
+pub const UNRECORDED_CONTEXT: &str = "not recorded";
+
+/// Plays and listening time per kind of context, most listened first. Tells
+/// playlist-driven listening from albums and Liked Songs shuffle.
+pub fn context_stats(entries: &[PlayHistoryEntry]) -> Vec<ContextStats> {
+    let mut by_kind: HashMap<&str, ContextStats> = HashMap::new();
+    let mut total = Duration::ZERO;
+    for entry in entries {
+        let kind = entry
+            .context
+            .as_ref()
+            .map_or(UNRECORDED_CONTEXT, PlayContext::kind);
+        let stats = by_kind.entry(kind).or_insert_with(|| ContextStats {
+            kind: kind.to_string(),
+            plays: 0,
+            listened: Duration::ZERO,
+            share: 0.0,
+        });
+        stats.plays += 1;
+        stats.listened += entry.listened();
+        total += entry.listened();
+    }
+
+    let mut stats: Vec<ContextStats> = by_kind.into_values().collect();
+    for kind in &mut stats {
+        if !total.is_zero() {
+            kind.share = kind.listened.as_secs_f64() / total.as_secs_f64();
+        }
+    }
+    stats.sort_by(|a, b| {
+        b.listened
+            .cmp(&a.listened)
+            .then_with(|| a.kind.cmp(&b.kind))
+    });
+    stats
+}
+
+/// How far the user got in one episode.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EpisodeStats {
+    pub episode_id: String,
+    pub name: String,
+    pub show: String,
+    /// The furthest any play of it got, from 0 to 1
+    pub completion: f64,
+    pub last_played: SystemTime,
+}
+
+/// The completion of every followed episode, last played first. Several
+/// plays of an episode are usually one listen paused and picked up again,
+/// the furthest of them counts.
+pub fn episode_completion(entries: &[PlayHistoryEntry]) -> Vec<EpisodeStats> {
+    let mut by_episode: HashMap<&str, EpisodeStats> = HashMap::new();
+    for entry in entries {
+        let (Some(show), Some(completion)) = (&entry.show, entry.completion()) else {
+            continue;
+        };
+        let stats = by_episode
+            .entry(&entry.track_id)
+            .or_insert_with(|| EpisodeStats {
+                episode_id: entry.track_id.clone(),
+                name: entry.track_name.clone(),
+                show: show.name.clone(),
+                completion,
+                last_played: entry.played_at,
+            });
+        stats.completion = stats.completion.max(completion);
+        stats.last_played = stats.last_played.max(entry.played_at);
+    }
+
+    let mut stats: Vec<EpisodeStats> = by_episode.into_values().collect();
+    stats.sort_by(|a, b| {
+        b.last_played
+            .cmp(&a.last_played)
+            .then_with(|| a.episode_id.cmp(&b.episode_id))
+    });
+    stats
+}
+
+/// How often playback of a track moved on during one of its sections.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SectionSkips {
+    /// Position of the section in the track, from 0
+    pub index: usize,
+    #[serde(flatten)]
+    pub section: Section,
+    pub skips: usize,
+}
+
+/// Where the plays of one track were skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SkipPoints {
+    pub track_id: String,
+    pub plays: usize,
+    /// Plays that moved on well before the end of the track
+    pub skips: usize,
+    /// Every section of the track, in order
+    pub sections: Vec<SectionSkips>,
+}
+
+impl SkipPoints {
+    /// The section skipped in most, the earliest of a tie. None when no
+    /// skip position is known.
+    pub fn most_skipped(&self) -> Option<&SectionSkips> {
+        self.sections
+            .iter()
+            .filter(|section| section.skips > 0)
+            .max_by(|a, b| a.skips.cmp(&b.skips).then(b.index.cmp(&a.index)))
+    }
+}
+
+/// Puts the recorded skip positions of a track's plays into the sections of
+/// its audio analysis.
+pub fn skip_points(
+    entries: &[PlayHistoryEntry],
+    track_id: &str,
+    analysis: &AudioAnalysis,
+) -> SkipPoints {
+    let mut sections: Vec<SectionSkips> = analysis
+        .sections
+        .iter()
+        .enumerate()
+        .map(|(index, section)| SectionSkips {
+            index,
+            section: section.clone(),
+            skips: 0,
+        })
+        .collect();
+    let mut plays = 0;
+    let mut skips = 0;
+    for entry in entries.iter().filter(|entry| entry.track_id == track_id) {
+        plays += 1;
+        let Some(skipped_at) = entry.skipped_at_ms else {
+            continue;
+        };
+        skips += 1;
+        let position = Duration::from_millis(skipped_at as u64);
+        if let Some(index) = analysis.section_at(position) {
+            sections[index].skips += 1;
+        }
+    }
+    SkipPoints {
+        track_id: track_id.to_string(),
+        plays,
+        skips,
+        sections,
+    }
+}
+
+/// Totals of a stretch of history, with its most played artists, tracks and
+/// shows. `listened` is split between music and podcasts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ListeningStats {
+    pub plays: usize,
+    pub listened: Duration,
+    pub music: Duration,
+    pub podcasts: Duration,
+    pub top_artists: Vec<ArtistStats>,
+    pub top_tracks: Vec<TrackStats>,
+    pub top_shows: Vec<ShowStats>,
+}
+
+impl ListeningStats {
+    pub fn from_entries(entries: &[PlayHistoryEntry]) -> ListeningStats {
+        let (episodes, tracks): (Vec<_>, Vec<_>) =
+            entries.iter().partition(|entry| entry.is_episode());
+        let podcasts: Duration = episodes.iter().map(|entry| entry.listened()).sum();
+        let music: Duration = tracks.iter().map(|entry| entry.listened()).sum();
+        ListeningStats {
+            plays: entries.len(),
+            listened: music + podcasts,
+            music,
+            podcasts,
+            top_artists: top_artists(entries, LISTENING_STATS_TOP),
+            top_tracks: top_tracks(entries, LISTENING_STATS_TOP),
+            top_shows: top_shows(entries, LISTENING_STATS_TOP),
+        }
+    }
+}
+
+/// Plays on each day in `tz`, from the day of the first play to the day of
+/// the last. Days without plays are included with 0.
+pub fn plays_per_day<Tz: TimeZone>(
+    entries: &[PlayHistoryEntry],
+    tz: &Tz,
+) -> Vec<(NaiveDate, usize)> {
+    let mut by_day: BTreeMap<NaiveDate, usize> = BTreeMap::new();
+    for entry in entries {
+        let day = DateTime::<chrono::Utc>::from(entry.played_at)
+            .with_timezone(tz)
+            .date_naive();
+        *by_day.entry(day).or_default() += 1;
+    }
+    let (Some(&first), Some(&last)) = (by_day.keys().next(), by_day.keys().next_back()) else {
+        return Vec::new();
+    };
+    first
+        .iter_days()
+        .take_while(|day| *day <= last)
+        .map(|day| (day, by_day.get(&day).copied().unwrap_or(0)))
+        .collect()
+}
+
+/// Listening time by weekday and hour of the day.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HourlyListening {
+    /// Monday first, hours from 0 to 23
+    pub cells: [[Duration; 24]; 7],
+}
+
+impl HourlyListening {
+    /// Listening time per hour of the day, all weekdays together.
+    pub fn by_hour(&self) -> [Duration; 24] {
+        let mut hours = [Duration::ZERO; 24];
+        for day in &self.cells {
+            for (total, listened) in hours.iter_mut().zip(day) {
+                *total += *listened;
+            }
+        }
+        hours
+    }
+
+    pub fn max(&self) -> Duration {
+        self.cells
+            .iter()
+            .flatten()
+            .copied()
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max().is_zero()
+    }
+}
+
+/// Listening time by when plays started in `tz`, a play counts wholly
+/// towards the hour it started in.
+pub fn listening_by_hour<Tz: TimeZone>(entries: &[PlayHistoryEntry], tz: &Tz) -> HourlyListening {
+    let mut listening = HourlyListening::default();
+    for entry in entries {
+        let started = DateTime::<chrono::Utc>::from(entry.played_at).with_timezone(tz);
+        let weekday = started.weekday().num_days_from_monday() as usize;
+        listening.cells[weekday][started.hour() as usize] += entry.listened();
+    }
+    listening
+}
+
+/// What was played on the looked back day in one earlier year.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnThisDay {
+    pub year: i32,
+    /// The days of that year standing in for the looked back day, see
+    /// [anniversary_days]
+    pub days: Vec<NaiveDate>,
+    pub plays: usize,
+    pub listened: Duration,
+    pub top_tracks: Vec<TrackStats>,
+}
+
+/// The days of `year` that stand in for `day`, the same calendar day.
+/// The 29th of February falls on the 28th in common years, and from the
+/// 28th of a common year the 29th of leap years is looked at too, so leap
+/// days aren't lost in the three years out of four without one.
+pub fn anniversary_days(day: NaiveDate, year: i32) -> Vec<NaiveDate> {
+    let Some(same) = day.with_year(year) else {
+        // The 29th of February in a common year
+        return NaiveDate::from_ymd_opt(year, 2, 28).into_iter().collect();
+    };
+    let common_year = NaiveDate::from_ymd_opt(day.year(), 2, 29).is_none();
+    match NaiveDate::from_ymd_opt(year, 2, 29) {
+        Some(leap_day) if (day.month(), day.day()) == (2, 28) && common_year => {
+            vec![same, leap_day]
+        }
+        _ => vec![same],
+    }
+}
+
+/// The plays on `day`'s calendar day in each of the `years_back` years
+/// before it, most recent year first, with the `limit` most played tracks
+/// of each. Days follow `tz`, years without plays are left out.
+pub fn on_this_day<Tz: TimeZone>(
+    entries: &[PlayHistoryEntry],
+    day: NaiveDate,
+    years_back: u32,
+    limit: usize,
+    tz: &Tz,
+) -> Vec<OnThisDay> {
+    (1..=years_back as i32)
+        .filter_map(|back| {
+            let year = day.year().checked_sub(back)?;
+            let days = anniversary_days(day, year);
+            let ranges: Vec<(SystemTime, SystemTime)> = days
+                .iter()
+                .filter_map(|day| {
+                    let end = start_of_day(day.succ_opt()?, tz);
+                    Some((start_of_day(*day, tz).into(), end.into()))
+                })
+                .collect();
+            let plays: Vec<PlayHistoryEntry> = entries
+                .iter()
+                .filter(|entry| {
+                    ranges
+                        .iter()
+                        .any(|(start, end)| (*start..*end).contains(&entry.played_at))
+                })
+                .cloned()
+                .collect();
+            if plays.is_empty() {
+                return None;
+            }
+            Some(OnThisDay {
+                year,
+                days,
+                plays: plays.len(),
+                listened: plays.iter().map(|entry| entry.listened()).sum(),
+                top_tracks: top_tracks(&plays, limit),
+            })
+        })
+        .collect()
+}
+
+/// A track first played in a range.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrackDiscovery {
+    pub track_id: String,
+    pub name: String,
+    /// The track's artists, comma separated
+    pub artists: String,
+    pub first_played: SystemTime,
+    /// Plays in the range, the first one included
+    pub plays: usize,
+}
+
+/// An artist first played in a range.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArtistDiscovery {
+    pub name: String,
+    pub first_played: SystemTime,
+    /// Plays in the range, the first one included
+    pub plays: usize,
+    pub listened: Duration,
+}
+
+/// The tracks and artists first played in a range, first heard first.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Discoveries {
+    pub tracks: Vec<TrackDiscovery>,
+    pub artists: Vec<ArtistDiscovery>,
+}
+
+impl Discoveries {
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty() && self.artists.is_empty()
+    }
+}
+
+/// What a track or an artist is known by in [FirstPlays].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum PlayKey {
+    Id(String),
+    /// The lowercase name, and the first artist's for a track
+    Name(String, String),
+}
+
+fn track_keys(entry: &PlayHistoryEntry) -> [PlayKey; 2] {
+    let artist = entry
+        .artists
+        .first()
+        .map(|artist| artist.name.to_lowercase())
+        .unwrap_or_default();
+    [
+        PlayKey::Id(entry.track_id.clone()),
+        PlayKey::Name(entry.track_name.to_lowercase(), artist),
+    ]
+}
+
+fn artist_keys(artist: &Artist) -> impl Iterator<Item = PlayKey> {
+    let id = Some(&artist.id)
+        .filter(|id| !id.is_empty())
+        .map(|id| PlayKey::Id(id.clone()));
+    let name = PlayKey::Name(artist.name.to_lowercase(), String::new());
+    id.into_iter().chain([name])
+}
+
+fn keep_earliest(first: &mut HashMap<PlayKey, SystemTime>, key: PlayKey, at: SystemTime) {
+    first
+        .entry(key)
+        .and_modify(|first| *first = (*first).min(at))
+        .or_insert(at);
+}
+
+/// When each track and artist was first played, indexed in one pass over
+/// the history so looking up a play doesn't go through the plays before
+/// it. Besides by id, tracks are known by their name and first artist and
+/// artists by their name: imported history has no artist ids and, from the
+/// simple export, no track ids either, but still counts as heard.
+pub struct FirstPlays {
+    tracks: HashMap<PlayKey, SystemTime>,
+    artists: HashMap<PlayKey, SystemTime>,
+}
+
+impl FirstPlays {
+    pub fn new(entries: &[PlayHistoryEntry]) -> FirstPlays {
+        let mut first = FirstPlays {
+            tracks: HashMap::new(),
+            artists: HashMap::new(),
+        };
+        for entry in entries.iter().filter(|entry| !entry.is_episode()) {
+            for key in track_keys(entry) {
+                keep_earliest(&mut first.tracks, key, entry.played_at);
+            }
+            for key in entry.artists.iter().flat_map(artist_keys) {
+                keep_earliest(&mut first.artists, key, entry.played_at);
+            }
+        }
+        first
+    }
+
+    /// When the track of `entry` was first played, None if it isn't indexed.
+    pub fn track(&self, entry: &PlayHistoryEntry) -> Option<SystemTime> {
+        track_keys(entry)
+            .iter()
+            .filter_map(|key| self.tracks.get(key))
+            .min()
+            .copied()
+    }
+
+    /// When `artist` was first played, None if they aren't indexed.
+    pub fn artist(&self, artist: &Artist) -> Option<SystemTime> {
+        artist_keys(artist)
+            .filter_map(|key| self.artists.get(&key))
+            .min()
+            .copied()
+    }
+}
+
+/// The tracks and artists played since `since` that weren't played before
+/// it anywhere in `entries`, the whole history. Episodes are left out.
+pub fn discoveries(entries: &[PlayHistoryEntry], since: SystemTime) -> Discoveries {
+    let first = FirstPlays::new(entries);
+    let mut in_range: Vec<&PlayHistoryEntry> = entries
+        .iter()
+        .filter(|entry| entry.played_at >= since && !entry.is_episode())
+        .collect();
+    in_range.sort_by_key(|entry| entry.played_at);
+
+    let mut discoveries = Discoveries::default();
+    let mut tracks: HashMap<PlayKey, usize> = HashMap::new();
+    let mut artists: HashMap<String, usize> = HashMap::new();
+    for entry in in_range {
+        if let Some(first_played) = first.track(entry).filter(|at| *at >= since) {
+            let [_, name] = track_keys(entry);
+            let i = *tracks.entry(name).or_insert_with(|| {
+                discoveries.tracks.push(TrackDiscovery {
+                    track_id: entry.track_id.clone(),
+                    name: entry.track_name.clone(),
+                    artists: entry
+                        .artists
+                        .iter()
+                        .map(|artist| artist.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    first_played,
+                    plays: 0,
+                });
+                discoveries.tracks.len() - 1
+            });
+            discoveries.tracks[i].plays += 1;
+        }
+        for artist in &entry.artists {
+            let Some(first_played) = first.artist(artist).filter(|at| *at >= since) else {
+                continue;
+            };
+            let i = *artists
+                .entry(artist.name.to_lowercase())
+                .or_insert_with(|| {
+                    discoveries.artists.push(ArtistDiscovery {
+                        name: artist.name.clone(),
+                        first_played,
+                        plays: 0,
+                        listened: Duration::ZERO,
+                    });
+                    discoveries.artists.len() - 1
+                });
+            discoveries.artists[i].plays += 1;
+            discoveries.artists[i].listened += entry.listened();
+        }
+    }
+    discoveries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{Confidence, PlayHistoryEntry};
+    use crate::spotify_data::CurrentlyPlayingTrack;
+    use chrono::Utc;
+    use chrono_tz::Europe::Stockholm;
+
+    fn tagged(tags: &[&str]) -> PlayHistoryEntry {
+        let data = std::fs::read_to_string("sample_data/currently_playing_track.json").unwrap();
+        let playing: CurrentlyPlayingTrack = serde_json::from_str(&data).unwrap();
+        let track = playing.get_track_data().unwrap();
+        let mut entry = PlayHistoryEntry::from_track(&track, SystemTime::now(), Confidence::High);
+        entry.tags = tags.iter().map(|t| t.to_string()).collect();
+        entry
+    }
+
+    fn played_at(entry: PlayHistoryEntry, rfc3339: &str) -> PlayHistoryEntry {
+        let at = DateTime::parse_from_rfc3339(rfc3339).unwrap();
+        PlayHistoryEntry {
+            played_at: at.into(),
+            ..entry
+        }
+    }
+
+    #[test]
+    fn test_plays_per_day_fills_gaps() {
+        let entries = vec![
+            played_at(tagged(&[]), "2024-09-23T08:00:00Z"),
+            played_at(tagged(&[]), "2024-09-23T22:30:00Z"),
+            played_at(tagged(&[]), "2024-09-26T12:00:00Z"),
+        ];
+        let days: Vec<(String, usize)> = plays_per_day(&entries, &Utc)
+            .into_iter()
+            .map(|(day, plays)| (day.to_string(), plays))
+            .collect();
+        assert_eq!(
+            days,
+            [
+                ("2024-09-23".to_string(), 2),
+                ("2024-09-24".to_string(), 0),
+                ("2024-09-25".to_string(), 0),
+                ("2024-09-26".to_string(), 1)
+            ]
+        );
+        assert!(plays_per_day(&[], &Utc).is_empty());
+    }
+
+    fn day(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn test_dst_days_are_23_and_25_hours() {
+        let hours = |date: &str| {
+            let start = start_of_day(day(date), &Stockholm);
+            let end = start_of_day(day(date).succ_opt().unwrap(), &Stockholm);
+            (end - start).num_hours()
+        };
+        assert_eq!(hours("2024-03-31"), 23);
+        assert_eq!(hours("2024-10-27"), 25);
+        assert_eq!(hours("2024-10-28"), 24);
+        assert_eq!(
+            start_of_day(day("2024-10-27"), &Stockholm).to_rfc3339(),
+            "2024-10-27T00:00:00+02:00"
+        );
+    }
+
+    #[test]
+    fn test_start_of_day_skipped_midnight() {
+        // Santiago springs forward at midnight, the 8th of September 2024
+        // starts at 01:00
+        let start = start_of_day(day("2024-09-08"), &chrono_tz::America::Santiago);
+        assert_eq!(start.to_rfc3339(), "2024-09-08T01:00:00-03:00");
+    }
+
+    #[test]
+    fn test_last_days_start() {
+        let now = DateTime::parse_from_rfc3339("2024-10-28T09:00:00Z")
+            .unwrap()
+            .with_timezone(&Stockholm);
+        assert_eq!(
+            last_days_start(1, &now).to_rfc3339(),
+            "2024-10-28T00:00:00+01:00"
+        );
+        // Reaches back over the 25 hour day
+        assert_eq!(
+            last_days_start(2, &now).to_rfc3339(),
+            "2024-10-27T00:00:00+02:00"
+        );
+    }
+
+    #[test]
+    fn test_plays_per_day_across_dst_end() {
+        let entries = vec![
+            // 00:30 local on the 27th, still the 26th in UTC
+            played_at(tagged(&[]), "2024-10-26T22:30:00Z"),
+            // 02:30 summer time, then 02:30 again in winter time
+            played_at(tagged(&[]), "2024-10-27T00:30:00Z"),
+            played_at(tagged(&[]), "2024-10-27T01:30:00Z"),
+            // 23:30 local, the 25th hour of the day
+            played_at(tagged(&[]), "2024-10-27T22:30:00Z"),
+            // 00:30 local on the 28th
+            played_at(tagged(&[]), "2024-10-27T23:30:00Z"),
+        ];
+        let days: Vec<(String, usize)> = plays_per_day(&entries, &Stockholm)
+            .into_iter()
+            .map(|(day, plays)| (day.to_string(), plays))
+            .collect();
+        assert_eq!(
+            days,
+            [("2024-10-27".to_string(), 4), ("2024-10-28".to_string(), 1)]
+        );
+
+        // The repeated hour holds both of its plays, each counted once
+        let listening = listening_by_hour(&entries, &Stockholm);
+        let track = Duration::from_millis(entries[0].duration_ms as u64);
+        assert_eq!(listening.cells[6][2], track * 2);
+        let total: Duration = listening.by_hour().iter().sum();
+        assert_eq!(total, track * entries.len() as u32);
+    }
+
+    #[test]
+    fn test_anniversary_days() {
+        let days = |date: &str, year: i32| -> Vec<String> {
+            anniversary_days(day(date), year)
+                .iter()
+                .map(|day| day.to_string())
+                .collect()
+        };
+        assert_eq!(days("2025-06-01", 2023), ["2023-06-01"]);
+        assert_eq!(days("2024-02-29", 2023), ["2023-02-28"]);
+        assert_eq!(days("2024-02-29", 2020), ["2020-02-29"]);
+        assert_eq!(days("2025-02-28", 2024), ["2024-02-28", "2024-02-29"]);
+        assert_eq!(days("2025-02-28", 2023), ["2023-02-28"]);
+        // A leap year has its own 29th
+        assert_eq!(days("2024-02-28", 2020), ["2020-02-28"]);
+    }
+
+    #[test]
+    fn test_on_this_day() {
+        let entries = vec![
+            // 00:30 local on the 1st of June, still the 31st of May in UTC
+            played_at(
+                play("Circles", &["Pierce The Veil"], 200_000),
+                "2023-05-31T22:30:00Z",
+            ),
+            played_at(
+                play("Circles", &["Pierce The Veil"], 200_000),
+                "2023-06-01T12:00:00Z",
+            ),
+            played_at(
+                play("Heroes", &["David Bowie"], 370_000),
+                "2023-06-01T13:00:00Z",
+            ),
+            // 00:30 local on the 2nd
+            played_at(
+                play("Help!", &["The Beatles"], 140_000),
+                "2023-06-01T22:30:00Z",
+            ),
+            played_at(
+                play("Heroes", &["David Bowie"], 370_000),
+                "2021-06-01T09:00:00Z",
+            ),
+            // This year's plays are not looked back at
+            played_at(
+                play("Help!", &["The Beatles"], 140_000),
+                "2025-06-01T09:00:00Z",
+            ),
+        ];
+        let years = on_this_day(&entries, day("2025-06-01"), 10, 1, &Stockholm);
+        let summary: Vec<(i32, usize, Vec<String>)> = years
+            .iter()
+            .map(|year| {
+                let tracks = year.top_tracks.iter().map(|t| t.name.clone()).collect();
+                (year.year, year.plays, tracks)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (2023, 3, vec!["Circles".to_string()]),
+                (2021, 1, vec!["Heroes".to_string()])
+            ]
+        );
+        assert_eq!(years[0].listened, Duration::from_millis(770_000));
+        assert_eq!(years[0].days, [day("2023-06-01")]);
+
+        // Only as far back as asked
+        assert_eq!(
+            on_this_day(&entries, day("2025-06-01"), 2, 5, &Stockholm).len(),
+            1
+        );
+        assert!(on_this_day(&entries, day("2025-06-02"), 10, 5, &Utc).is_empty());
+    }
+
+    #[test]
+    fn test_on_this_day_leap_day() {
+        let entries = vec![
+            played_at(
+                play("Circles", &["Pierce The Veil"], 200_000),
+                "2024-02-29T12:00:00Z",
+            ),
+            played_at(
+                play("Heroes", &["David Bowie"], 370_000),
+                "2023-02-28T12:00:00Z",
+            ),
+        ];
+        let years = |date: &str| -> Vec<i32> {
+            on_this_day(&entries, day(date), 5, 5, &Utc)
+                .iter()
+                .map(|year| year.year)
+                .collect()
+        };
+        // The leap day shows up from the 28th of common years
+        assert_eq!(years("2025-02-28"), [2024, 2023]);
+        assert_eq!(years("2028-02-29"), [2024, 2023]);
+        assert_eq!(years("2025-03-01"), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_listening_by_hour() {
+        let entries = vec![
+            // A Monday
+            played_at(tagged(&[]), "2024-09-23T08:10:00Z"),
+            played_at(tagged(&[]), "2024-09-23T08:50:00Z"),
+            // The Sunday after
+            played_at(tagged(&[]), "2024-09-29T08:00:00Z"),
+        ];
+        let track = Duration::from_millis(entries[0].duration_ms as u64);
+        let listening = listening_by_hour(&entries, &Utc);
+        assert_eq!(listening.cells[0][8], track * 2);
+        assert_eq!(listening.cells[6][8], track);
+        assert_eq!(listening.by_hour()[8], track * 3);
+        assert_eq!(listening.max(), track * 2);
+        assert!(listening_by_hour(&[], &Utc).is_empty());
+    }
+
+    #[test]
+    fn test_top_artists() {
+        let entries = vec![tagged(&[]), tagged(&[])];
+        let artists = top_artists(&entries, 10);
+        assert_eq!(artists.len(), entries[0].artists.len());
+        assert!(artists.iter().all(|artist| artist.plays == 2));
+        assert!(top_artists(&entries, 0).is_empty());
+    }
+
+    #[test]
+    fn test_device_stats() {
+        let entries = crate::history::HistoryStore::new("sample_data/device_history.jsonl")
+            .load()
+            .unwrap();
+        let aliases =
+            DeviceAliases::load(std::path::Path::new("sample_data/device_aliases.json")).unwrap();
+        let stats: Vec<(String, usize, u64)> = device_stats(&entries, &aliases)
+            .into_iter()
+            .map(|d| (d.device, d.plays, d.listened.as_secs()))
+            .collect();
+        assert_eq!(
+            stats,
+            [
+                ("Phone".to_string(), 3, 734),
+                ("Car".to_string(), 2, 532),
+                ("Unknown".to_string(), 1, 229),
+                ("Desk".to_string(), 1, 200),
+            ]
+        );
+
+        // The raw names without aliases
+        let raw = device_stats(&entries, &DeviceAliases::new());
+        assert_eq!(raw.len(), 5);
+        assert!(raw
+            .iter()
+            .any(|d| d.device == "Pixel 8 Pro" && d.plays == 1));
+    }
+
+    #[test]
+    fn test_context_stats() {
+        let from = |context: Option<PlayContext>, duration_ms| PlayHistoryEntry {
+            context,
+            ..play("Circles", &["Pierce The Veil"], duration_ms)
+        };
+        let entries = vec![
+            from(Some(PlayContext::Playlist("p1".to_string())), 300_000),
+            from(Some(PlayContext::Playlist("p2".to_string())), 300_000),
+            from(Some(PlayContext::Collection), 200_000),
+            from(Some(PlayContext::Album("a1".to_string())), 100_000),
+            from(None, 100_000),
+        ];
+        let stats: Vec<(String, usize, u64)> = context_stats(&entries)
+            .into_iter()
+            .map(|c| (c.kind, c.plays, c.listened.as_secs()))
+            .collect();
+        assert_eq!(
+            stats,
+            [
+                ("playlist".to_string(), 2, 600),
+                ("liked songs".to_string(), 1, 200),
+                ("album".to_string(), 1, 100),
+                (UNRECORDED_CONTEXT.to_string(), 1, 100),
+            ]
+        );
+        assert_eq!(context_stats(&entries)[0].share, 0.6);
+        assert!(context_stats(&[]).is_empty());
+    }
+
+    fn play(track: &str, artists: &[&str], duration_ms: u32) -> PlayHistoryEntry {
+        PlayHistoryEntry {
+            track_id: format!("id-{track}"),
+            track_name: track.to_string(),
+            artists: artists
+                .iter()
+                .map(|name| Artist {
+                    name: name.to_string(),
+                    id: format!("id-{name}"),
+                })
+                .collect(),
+            duration_ms,
+            ..tagged(&[])
+        }
+    }
+
+    #[test]
+    fn test_listening_stats() {
+        let mut entries = vec![
+            play("Circles", &["Pierce The Veil"], 200_000),
+            play("Circles", &["Pierce The Veil"], 200_000),
+            play("Circles", &["Pierce The Veil"], 200_000),
+            play("Heroes", &["David Bowie"], 370_000),
+            play("Heroes", &["David Bowie"], 370_000),
+            play("Under Pressure", &["Queen", "David Bowie"], 248_000),
+            play("Help!", &["The Beatles"], 140_000),
+        ];
+        for track in ["A", "B", "C", "D"] {
+            entries.push(play(track, &["Filler"], 60_000));
+        }
+        let stats = ListeningStats::from_entries(&entries);
+        assert_eq!(stats.plays, 11);
+        assert_eq!(stats.listened, Duration::from_millis(1_968_000));
+
+        let artists: Vec<(&str, usize)> = stats
+            .top_artists
+            .iter()
+            .map(|a| (a.name.as_str(), a.plays))
+            .collect();
+        assert_eq!(
+            artists,
+            [
+                ("Filler", 4),
+                ("David Bowie", 3),
+                ("Pierce The Veil", 3),
+                ("Queen", 1),
+                ("The Beatles", 1)
+            ]
+        );
+        let tracks: Vec<(&str, usize)> = stats
+            .top_tracks
+            .iter()
+            .map(|t| (t.name.as_str(), t.plays))
+            .collect();
+        assert_eq!(
+            tracks,
+            [("Circles", 3), ("Heroes", 2), ("A", 1), ("B", 1), ("C", 1)]
+        );
+        assert_eq!(stats.top_tracks[1].listened, Duration::from_secs(740));
+        assert_eq!(
+            top_tracks(&entries, 10)
+                .iter()
+                .find(|t| t.name == "Under Pressure")
+                .unwrap()
+                .artists,
+            "Queen, David Bowie"
+        );
+
+        let empty = ListeningStats::from_entries(&[]);
+        assert_eq!((empty.plays, empty.listened), (0, Duration::ZERO));
+        assert!(empty.top_artists.is_empty() && empty.top_tracks.is_empty());
+    }
+
+    #[test]
+    fn test_skip_points() {
+        let data = std::fs::read_to_string("sample_data/audio_analysis.json").unwrap();
+        let analysis: AudioAnalysis = serde_json::from_str(&data).unwrap();
+        let skipped_at = |ms| PlayHistoryEntry {
+            skipped_at_ms: Some(ms),
+            ..play("Circles", &["Pierce The Veil"], 207_959)
+        };
+        let entries = vec![
+            skipped_at(30_000),
+            play("Circles", &["Pierce The Veil"], 207_959),
+            // The second section starts at 21.43129s
+            skipped_at(21_432),
+            skipped_at(21_431),
+            skipped_at(170_000),
+            PlayHistoryEntry {
+                skipped_at_ms: Some(30_000),
+                ..play("Dive In", &["Pierce The Veil"], 180_000)
+            },
+        ];
+
+        let points = skip_points(&entries, "id-Circles", &analysis);
+        assert_eq!((points.plays, points.skips), (5, 4));
+        let skips: Vec<usize> = points.sections.iter().map(|s| s.skips).collect();
+        assert_eq!(skips, [1, 2, 0, 1]);
+        let most = points.most_skipped().unwrap();
+        assert_eq!((most.index, most.section.start), (1, 21.43129));
+
+        let json = serde_json::to_value(&points).unwrap();
+        assert_eq!(json["sections"][1]["tempo"], 98.004);
+
+        let points = skip_points(&entries, "id-unknown", &analysis);
+        assert_eq!((points.plays, points.skips), (0, 0));
+        assert!(points.most_skipped().is_none());
+    }
+
+    fn episode(name: &str, show: &str, duration_ms: u32, progress_ms: u32) -> PlayHistoryEntry {
+        let data = std::fs::read_to_string("sample_data/saved_episodes.json").unwrap();
+        let page: crate::spotify_data::Page<crate::spotify_data::SavedEpisode> =
+            serde_json::from_str(&data).unwrap();
+        let mut episode = page.items[0].episode.clone();
+        episode.id = format!("id-{name}");
+        episode.name = name.to_string();
+        episode.duration_ms = duration_ms;
+        episode.resume_point = None;
+        episode.show.id = format!("id-{show}");
+        episode.show.name = show.to_string();
+        PlayHistoryEntry::from_episode(
+            &episode,
+            Some(progress_ms),
+            SystemTime::now(),
+            Confidence::High,
+        )
+    }
+
+    #[test]
+    fn test_podcast_stats() {
+        let mut entries = vec![
+            play("Circles", &["Pierce The Veil"], 200_000),
+            episode("Room Tone", "Listening Room", 2_400_000, 2_400_000),
+            // Paused halfway and picked up again later
+            episode(
+                "The Sound of Cities",
+                "Listening Room",
+                2_000_000,
+                1_000_000,
+            ),
+            episode(
+                "The Sound of Cities",
+                "Listening Room",
+                2_000_000,
+                1_500_000,
+            ),
+            episode("Pilot", "Short Takes", 600_000, 150_000),
+        ];
+        entries[4].played_at -= Duration::from_secs(3600);
+        let stats = ListeningStats::from_entries(&entries);
+        assert_eq!(stats.plays, 5);
+        assert_eq!(stats.music, Duration::from_secs(200));
+        assert_eq!(
+            stats.podcasts,
+            Duration::from_secs(2_400 + 1_000 + 1_500 + 150)
+        );
+        assert_eq!(stats.listened, stats.music + stats.podcasts);
+        // Episodes are neither tracks nor have artists
+        assert_eq!(stats.top_tracks.len(), 1);
+        assert_eq!(stats.top_artists.len(), 1);
+
+        let shows: Vec<(&str, usize)> = stats
+            .top_shows
+            .iter()
+            .map(|show| (show.name.as_str(), show.plays))
+            .collect();
+        assert_eq!(shows, [("Listening Room", 3), ("Short Takes", 1)]);
+
+        let completion = episode_completion(&entries);
+        let episodes: Vec<(&str, f64)> = completion
+            .iter()
+            .map(|episode| (episode.name.as_str(), episode.completion))
+            .collect();
+        assert_eq!(episodes.len(), 3);
+        assert!(episodes.contains(&("Room Tone", 1.0)));
+        assert!(episodes.contains(&("The Sound of Cities", 0.75)));
+        // Played an hour before the others
+        assert_eq!(episodes[2], ("Pilot", 0.25));
+    }
+
+    #[test]
+    fn test_discoveries() {
+        let mut imported = play("Circles", &["Pierce The Veil"], 200_000);
+        imported.track_id = "streaming-history:Pierce The Veil:Circles".to_string();
+        imported.artists[0].id = String::new();
+        let sleepwalking = || play("Sleepwalking", &["Bring Me The Horizon"], 230_000);
+        let bronx = || play("Bulls in the Bronx", &["Pierce The Veil"], 300_000);
+        let entries = vec![
+            played_at(imported, "2024-01-10T12:00:00Z"),
+            played_at(bronx(), "2024-02-01T12:00:00Z"),
+            played_at(sleepwalking(), "2024-02-28T13:00:00Z"),
+            played_at(
+                play("circles", &["Pierce the Veil"], 200_000),
+                "2024-02-26T12:00:00Z",
+            ),
+            played_at(
+                play("Hold On Till May", &["Pierce The Veil"], 250_000),
+                "2024-02-27T12:00:00Z",
+            ),
+            played_at(bronx(), "2024-02-24T12:00:00Z"),
+            played_at(sleepwalking(), "2024-02-23T13:00:00Z"),
+        ];
+        let since = DateTime::parse_from_rfc3339("2024-02-22T00:00:00Z").unwrap();
+        let found = discoveries(&entries, since.into());
+
+        let tracks: Vec<(&str, usize)> = found
+            .tracks
+            .iter()
+            .map(|t| (t.name.as_str(), t.plays))
+            .collect();
+        assert_eq!(tracks, [("Sleepwalking", 2), ("Hold On Till May", 1)]);
+        assert_eq!(found.tracks[0].first_played, entries[6].played_at);
+        assert_eq!(found.artists.len(), 1);
+        assert_eq!(found.artists[0].name, "Bring Me The Horizon");
+        assert_eq!(found.artists[0].plays, 2);
+        assert_eq!(found.artists[0].listened, Duration::from_secs(460));
+
+        // Nothing was played before the whole history
+        let all = discoveries(&entries, entries[0].played_at);
+        assert_eq!(all.tracks.len(), 4);
+        assert_eq!(all.artists.len(), 2);
+        let later = DateTime::parse_from_rfc3339("2024-03-01T00:00:00Z").unwrap();
+        assert!(discoveries(&entries, later.into()).is_empty());
+    }
+
+    #[test]
+    fn test_tag_stats() {
+        let entries = vec![
+            tagged(&["focus"]),
+            tagged(&[]),
+            tagged(&["gym", "focus"]),
+            tagged(&["gym"]),
+            tagged(&["focus"]),
+        ];
+        let stats = tag_stats(&entries);
+        let summary: Vec<(&str, usize)> = stats.iter().map(|s| (s.tag.as_str(), s.plays)).collect();
+        assert_eq!(summary, [("focus", 3), ("gym", 2)]);
+        assert_eq!(
+            stats[0].listened,
+            Duration::from_millis(3 * entries[0].duration_ms as u64)
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use crate::history::{Confidence, PlayHistoryEntry, PlaySource};
+use crate::spotify_data::Artist;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDateTime};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// An imported play is the same as a known one when it is of the same track
+/// and started this close to it. Simple exports only give the minute a play
+/// ended, and the daemon sees a play start up to a poll late.
+pub const IMPORT_MATCH_WINDOW: Duration = Duration::from_secs(90);
+/// Starts the made up track ids of simple exports, which have no track URIs
+const IMPORTED_ID_PREFIX: &str = "streaming-history:";
+
+/// One row of Spotify's account data export, either schema.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ExportRow {
+    Extended(ExtendedRow),
+    Simple(SimpleRow),
+}
+
+/// `endsong_*.json` and `Streaming_History_Audio_*.json` of the extended
+/// streaming history. Episodes have no track metadata.
+#[derive(Deserialize, Debug)]
+struct ExtendedRow {
+    /// When playback stopped, RFC 3339
+    ts: String,
+    ms_played: u32,
+    master_metadata_track_name: Option<String>,
+    master_metadata_album_artist_name: Option<String>,
+    master_metadata_album_album_name: Option<String>,
+    spotify_track_uri: Option<String>,
+}
+
+/// `StreamingHistory*.json` of the default account data export.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SimpleRow {
+    /// When playback stopped, `YYYY-MM-DD HH:MM` in UTC
+    end_time: String,
+    artist_name: String,
+    track_name: String,
+    ms_played: u32,
+}
+
+impl ExportRow {
+    /// The play as a history entry, None for episodes and rows that played
+    /// nothing. A play starts `ms_played` before it ended.
+    fn into_entry(self) -> Result<Option<PlayHistoryEntry>> {
+        let (track_id, track_name, artist, album, ended, ms_played) = match self {
+            ExportRow::Extended(row) => {
+                let (Some(name), Some(artist), Some(uri)) = (
+                    row.master_metadata_track_name,
+                    row.master_metadata_album_artist_name,
+                    row.spotify_track_uri,
+                ) else {
+                    return Ok(None);
+                };
+                let ended = DateTime::parse_from_rfc3339(&row.ts)
+                    .with_context(|| format!("bad ts {}", row.ts))?;
+                let id = uri.rsplit(':').next().unwrap_or_default().to_string();
+                let album = row.master_metadata_album_album_name.unwrap_or_default();
+                (
+                    id,
+                    name,
+                    artist,
+                    album,
+                    SystemTime::from(ended),
+                    row.ms_played,
+                )
+            }
+            ExportRow::Simple(row) => {
+                let ended = NaiveDateTime::parse_from_str(&row.end_time, "%Y-%m-%d %H:%M")
+                    .with_context(|| format!("bad endTime {}", row.end_time))?;
+                let id = format!("{IMPORTED_ID_PREFIX}{}:{}", row.artist_name, row.track_name);
+                let ended = SystemTime::from(ended.and_utc());
+                (
+                    id,
+                    row.track_name,
+                    row.artist_name,
+                    String::new(),
+                    ended,
+                    row.ms_played,
+                )
+            }
+        };
+        if ms_played == 0 {
+            return Ok(None);
+        }
+        Ok(Some(PlayHistoryEntry {
+            track_id,
+            track_name,
+            artists: vec![Artist {
+                name: artist,
+                id: String::new(),
+            }],
+            album,
+            // All that is known of the track is how much was played
+            duration_ms: ms_played,
+            played_at: ended - Duration::from_millis(ms_played as u64),
+            confidence: Confidence::High,
+            tags: Vec::new(),
+            show: None,
+            progress_ms: None,
+            skipped_at_ms: None,
+            device: None,
+            context: None,
+            source: PlaySource::Backfill,
+            replaces: None,
+        }))
+    }
+}
+
+/// The track plays of one export file, in either schema.
+pub fn parse_streaming_history(data: &str) -> Result<Vec<PlayHistoryEntry>> {
+    let rows: Vec<ExportRow> = serde_json::from_str(data)?;
+    let mut entries = Vec::new();
+    for row in rows {
+        entries.extend(row.into_entry()?);
+    }
+    Ok(entries)
+}
+
+/// The export files at `path`, the file itself or the streaming history
+/// files of a directory, e.g. the unpacked export.
+pub fn streaming_history_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        let name = file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        let is_history = ["StreamingHistory", "Streaming_History_Audio_", "endsong_"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix));
+        if is_history && name.ends_with(".json") {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Plays already in the history, found by track and artist name since
+/// simple exports have no track ids.
+#[derive(Default)]
+pub struct KnownPlays {
+    starts: HashMap<(String, String), Vec<SystemTime>>,
+}
+
+impl KnownPlays {
+    pub fn new(entries: &[PlayHistoryEntry]) -> KnownPlays {
+        let mut known = KnownPlays::default();
+        for entry in entries {
+            known.insert(entry);
+        }
+        known
+    }
+
+    fn key(entry: &PlayHistoryEntry) -> (String, String) {
+        let artist = entry.artists.first().map(|a| a.name.to_lowercase());
+        (entry.track_name.to_lowercase(), artist.unwrap_or_default())
+    }
+
+    /// Whether a play of the same track started within [IMPORT_MATCH_WINDOW].
+    pub fn contains(&self, entry: &PlayHistoryEntry) -> bool {
+        let Some(starts) = self.starts.get(&KnownPlays::key(entry)) else {
+            return false;
+        };
+        starts.iter().any(|start| {
+            let apart = start
+                .duration_since(entry.played_at)
+                .or_else(|_| entry.played_at.duration_since(*start))
+                .unwrap_or_default();
+            apart <= IMPORT_MATCH_WINDOW
+        })
+    }
+
+    pub fn insert(&mut self, entry: &PlayHistoryEntry) {
+        self.starts
+            .entry(KnownPlays::key(entry))
+            .or_default()
+            .push(entry.played_at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> SystemTime {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap().into()
+    }
+
+    #[test]
+    fn test_simple_export() {
+        let data = fs::read_to_string("sample_data/StreamingHistory0.json").unwrap();
+        let entries = parse_streaming_history(&data).unwrap();
+        // The row that played nothing is left out
+        assert_eq!(entries.len(), 3);
+        let first = &entries[0];
+        assert_eq!(first.track_name, "The Divine Zero");
+        assert_eq!(first.artists[0].name, "Pierce The Veil");
+        assert_eq!(
+            first.track_id,
+            "streaming-history:Pierce The Veil:The Divine Zero"
+        );
+        assert_eq!(first.played_at, at(2024, 9, 23, 18, 37, 47));
+        assert_eq!(first.listened(), Duration::from_millis(253000));
+    }
+
+    #[test]
+    fn test_extended_export() {
+        let data = fs::read_to_string("sample_data/endsong_0.json").unwrap();
+        let entries = parse_streaming_history(&data).unwrap();
+        // The episode is left out
+        let names: Vec<&str> = entries.iter().map(|e| e.track_name.as_str()).collect();
+        assert_eq!(names, ["The Divine Zero", "Blinding Lights"]);
+        assert_eq!(entries[0].track_id, "1VY823dFzI9L8BEf2X7B5I");
+        assert_eq!(entries[0].album, "Misadventures");
+        assert_eq!(entries[0].played_at, at(2024, 9, 23, 18, 38, 0));
+        assert_eq!(
+            entries[1].played_at,
+            at(2024, 9, 24, 7, 16, 9) + Duration::from_millis(960)
+        );
+
+        assert!(parse_streaming_history(
+            r#"[{"ts": "yesterday", "ms_played": 1000,
+            "master_metadata_track_name": "Halo", "master_metadata_album_artist_name": "Beyoncé",
+            "master_metadata_album_album_name": null, "spotify_track_uri": "spotify:track:1"}]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_known_plays_match_both_schemas() {
+        let simple = parse_streaming_history(
+            &fs::read_to_string("sample_data/StreamingHistory0.json").unwrap(),
+        )
+        .unwrap();
+        let extended =
+            parse_streaming_history(&fs::read_to_string("sample_data/endsong_0.json").unwrap())
+                .unwrap();
+        let known = KnownPlays::new(&simple);
+        // Started 13 seconds apart
+        assert!(known.contains(&extended[0]));
+        assert!(!known.contains(&extended[1]));
+
+        let mut later = extended[0].clone();
+        later.played_at += IMPORT_MATCH_WINDOW + Duration::from_secs(1);
+        assert!(!known.contains(&later));
+    }
+
+    #[test]
+    fn test_streaming_history_files() {
+        let files = streaming_history_files(Path::new("sample_data")).unwrap();
+        assert_eq!(
+            files,
+            [
+                Path::new("sample_data/StreamingHistory0.json"),
+                Path::new("sample_data/endsong_0.json")
+            ]
+        );
+        let file = Path::new("sample_data/endsong_0.json");
+        assert_eq!(streaming_history_files(file).unwrap(), [file]);
+    }
+}
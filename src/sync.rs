@@ -0,0 +1,475 @@
+use crate::history::{HistoryStore, PlayHistoryEntry, SyncedPlays};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{info, warn};
+
+/// Where plays are pushed to and pulled from.
+pub const SYNC_PLAYS_PATH: &str = "/api/sync/plays";
+/// The file in the config directory holding the token both sides share.
+pub const SYNC_TOKEN_FILE: &str = "sync_token";
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The plays of one user, the body of a push and the answer to a pull.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SyncPlays {
+    pub user: String,
+    pub plays: Vec<PlayHistoryEntry>,
+    /// How many lines the history answering a pull has, where the next
+    /// pull starts
+    #[serde(default)]
+    pub lines: usize,
+}
+
+/// Reads the shared token from [SYNC_TOKEN_FILE] in `dir`.
+pub fn load_sync_token(dir: &Path) -> Result<String> {
+    let path = dir.join(SYNC_TOKEN_FILE);
+    let token = fs::read_to_string(&path).with_context(|| {
+        format!(
+            "Could not read {}, put the token the machines share in it",
+            path.display()
+        )
+    })?;
+    let token = token.trim();
+    if token.is_empty() {
+        bail!("{} is empty", path.display());
+    }
+    Ok(token.to_string())
+}
+
+/// Answers pushes and pulls of another machine's daemon for one user's
+/// history, to requests with the shared token.
+pub struct SyncServer {
+    server: Server,
+    store: HistoryStore,
+    user: String,
+    token: String,
+}
+
+impl SyncServer {
+    pub fn bind(addr: &str, store: HistoryStore, user: &str, token: String) -> Result<SyncServer> {
+        let server = Server::http(addr).map_err(|e| anyhow!("Could not listen on {addr}: {e}"))?;
+        Ok(SyncServer {
+            server,
+            store,
+            user: user.to_string(),
+            token,
+        })
+    }
+
+    /// Where it listens, e.g. to find the port it was given for port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Answers requests until [SyncServer::stop] is called.
+    pub fn serve(&self) {
+        for mut request in self.server.incoming_requests() {
+            let (status, body) = self.answer(&mut request);
+            let content_type = Header::from_bytes("Content-Type", "application/json")
+                .expect("the header is valid");
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(content_type);
+            if let Err(e) = request.respond(response) {
+                warn!("Could not answer a sync request: {e}");
+            }
+        }
+    }
+
+    /// Makes [SyncServer::serve] return, from another thread.
+    pub fn stop(&self) {
+        self.server.unblock();
+    }
+
+    fn answer(&self, request: &mut Request) -> (u16, String) {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        if path != SYNC_PLAYS_PATH {
+            return error_body(404, format!("Nothing at {path}"));
+        }
+        let bearer = format!("Bearer {}", self.token);
+        let authorized = request
+            .headers()
+            .iter()
+            .any(|header| header.field.equiv("Authorization") && header.value.as_str() == bearer);
+        if !authorized {
+            return error_body(401, "Wrong or missing sync token".to_string());
+        }
+
+        let answer = match request.method() {
+            Method::Get => self.pull(query),
+            Method::Post => self.push(request),
+            method => return error_body(405, format!("{method} is not supported")),
+        };
+        match answer {
+            Ok(body) => (200, body),
+            Err((status, message)) => error_body(status, message),
+        }
+    }
+
+    /// The plays after the first `after` lines of the history.
+    fn pull(&self, query: &str) -> Result<String, (u16, String)> {
+        let mut user = None;
+        let mut after = 0;
+        for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            match name.as_ref() {
+                "user" => user = Some(value.into_owned()),
+                "after" => {
+                    after = value
+                        .parse()
+                        .map_err(|_| (400, format!("Bad after <{value}>")))?;
+                }
+                _ => {}
+            }
+        }
+        self.check_user(user.as_deref())?;
+        let (plays, lines) = self.store.plays_after(after).map_err(internal)?;
+        let body = SyncPlays {
+            user: self.user.clone(),
+            plays,
+            lines,
+        };
+        serde_json::to_string(&body).map_err(|e| internal(e.into()))
+    }
+
+    fn push(&self, request: &mut Request) -> Result<String, (u16, String)> {
+        let mut body = String::new();
+        request
+            .as_reader()
+            .read_to_string(&mut body)
+            .map_err(|e| (400, format!("Could not read the plays: {e}")))?;
+        let pushed: SyncPlays =
+            serde_json::from_str(&body).map_err(|e| (400, format!("Bad plays: {e}")))?;
+        self.check_user(Some(&pushed.user))?;
+        let synced = self.store.sync_in(pushed.plays).map_err(internal)?;
+        info!(
+            "Synced in {} plays, {} replaced backfilled ones",
+            synced.added, synced.replaced
+        );
+        serde_json::to_string(&synced).map_err(|e| internal(e.into()))
+    }
+
+    fn check_user(&self, user: Option<&str>) -> Result<(), (u16, String)> {
+        match user {
+            Some(user) if user == self.user => Ok(()),
+            Some(user) => Err((
+                403,
+                format!("This history is {}'s, not {user}'s", self.user),
+            )),
+            None => Err((400, "Say whose plays with user".to_string())),
+        }
+    }
+}
+
+fn internal(e: anyhow::Error) -> (u16, String) {
+    warn!("Failed to answer a sync request: {e:#}");
+    (500, format!("{e:#}"))
+}
+
+fn error_body(status: u16, message: String) -> (u16, String) {
+    (status, serde_json::json!({ "error": message }).to_string())
+}
+
+/// Pushes plays to and pulls them from another machine's [SyncServer].
+/// Only plays added since the last exchange with `remote` go either way,
+/// how many lines of each history went is kept next to the history, see
+/// [HistoryStore::sync_watermark].
+pub struct SyncClient {
+    http_client: reqwest::blocking::Client,
+    remote: String,
+    user: String,
+    token: String,
+}
+
+impl SyncClient {
+    /// `remote` is the other daemon's address, e.g. `http://nas:7878`.
+    pub fn new(remote: &str, user: &str, token: String) -> Result<SyncClient> {
+        Ok(SyncClient {
+            http_client: reqwest::blocking::Client::builder()
+                .timeout(SYNC_TIMEOUT)
+                .build()?,
+            remote: remote.trim_end_matches('/').to_string(),
+            user: user.to_string(),
+            token,
+        })
+    }
+
+    /// Sends the plays added to `store` since the last push.
+    pub fn push(&self, store: &HistoryStore) -> Result<SyncedPlays> {
+        let peer = format!("push {}", self.remote);
+        let (plays, lines) = store.plays_after(store.sync_watermark(&peer)?)?;
+        if plays.is_empty() {
+            return Ok(SyncedPlays::default());
+        }
+        let body = SyncPlays {
+            user: self.user.clone(),
+            plays,
+            lines,
+        };
+        let response = self
+            .http_client
+            .post(format!("{}{SYNC_PLAYS_PATH}", self.remote))
+            .bearer_auth(&self.token)
+            .json(&body)
+            .send()
+            .with_context(|| format!("Could not reach {}", self.remote))?;
+        let synced: SyncedPlays = checked(response)?.json()?;
+        store.set_sync_watermark(&peer, lines)?;
+        Ok(synced)
+    }
+
+    /// Adds the plays added to the remote's history since the last pull to
+    /// `store`.
+    pub fn pull(&self, store: &HistoryStore) -> Result<SyncedPlays> {
+        let peer = format!("pull {}", self.remote);
+        let after = store.sync_watermark(&peer)?.to_string();
+        let response = self
+            .http_client
+            .get(format!("{}{SYNC_PLAYS_PATH}", self.remote))
+            .bearer_auth(&self.token)
+            .query(&[("user", self.user.as_str()), ("after", after.as_str())])
+            .send()
+            .with_context(|| format!("Could not reach {}", self.remote))?;
+        let pulled: SyncPlays = checked(response)?.json()?;
+        let synced = store.sync_in(pulled.plays)?;
+        store.set_sync_watermark(&peer, pulled.lines)?;
+        Ok(synced)
+    }
+}
+
+/// The response, or its error message as an error.
+fn checked(response: reqwest::blocking::Response) -> Result<reqwest::blocking::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response
+        .json::<serde_json::Value>()
+        .ok()
+        .and_then(|body| body["error"].as_str().map(str::to_string))
+        .unwrap_or_default();
+    bail!("The remote answered {status}: {message}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::{Confidence, PlaySource};
+    use crate::testutil::sample_track;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::SystemTime;
+
+    const TOKEN: &str = "shared-token";
+
+    /// One machine: its own directory with a history.
+    struct Instance {
+        dir: PathBuf,
+    }
+
+    impl Instance {
+        fn new(name: &str) -> Instance {
+            let dir = std::env::temp_dir().join(format!("sync-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Instance { dir }
+        }
+
+        fn store(&self) -> HistoryStore {
+            HistoryStore::new(self.dir.join("history.jsonl"))
+        }
+
+        /// Serves its history on a free port until the returned stop is called.
+        fn serve(&self) -> (String, impl FnOnce()) {
+            let server = Arc::new(
+                SyncServer::bind("127.0.0.1:0", self.store(), "tester", TOKEN.into()).unwrap(),
+            );
+            let url = format!("http://{}", server.local_addr().unwrap());
+            let serving = {
+                let server = server.clone();
+                thread::spawn(move || server.serve())
+            };
+            let stop = move || {
+                server.stop();
+                serving.join().unwrap();
+            };
+            (url, stop)
+        }
+    }
+
+    impl Drop for Instance {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn play(secs: u64, source: PlaySource) -> PlayHistoryEntry {
+        let at = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let mut play = PlayHistoryEntry::from_track(&sample_track(), at, Confidence::High);
+        play.source = source;
+        if source == PlaySource::Live {
+            play.device = Some("Laptop".to_string());
+        }
+        play
+    }
+
+    fn played(store: &HistoryStore) -> Vec<(u64, PlaySource)> {
+        let plays = store.load().unwrap();
+        plays
+            .iter()
+            .map(|play| {
+                let at = play.played_at.duration_since(SystemTime::UNIX_EPOCH);
+                (at.unwrap().as_secs(), play.source)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_push_and_pull_between_instances() {
+        let (here, there) = (Instance::new("here"), Instance::new("there"));
+        let (url, stop) = there.serve();
+        let client = SyncClient::new(&url, "tester", TOKEN.into()).unwrap();
+
+        here.store().append(&play(1000, PlaySource::Live)).unwrap();
+        here.store().append(&play(2000, PlaySource::Live)).unwrap();
+        there.store().append(&play(1000, PlaySource::Live)).unwrap();
+        let pushed = client.push(&here.store()).unwrap();
+        assert_eq!(
+            pushed,
+            SyncedPlays {
+                added: 1,
+                replaced: 0
+            }
+        );
+        // Only plays since the last push go again
+        assert_eq!(client.push(&here.store()).unwrap(), SyncedPlays::default());
+        here.store().append(&play(3000, PlaySource::Live)).unwrap();
+        assert_eq!(client.push(&here.store()).unwrap().added, 1);
+        assert_eq!(
+            played(&there.store()),
+            [
+                (1000, PlaySource::Live),
+                (2000, PlaySource::Live),
+                (3000, PlaySource::Live)
+            ]
+        );
+
+        there.store().append(&play(4000, PlaySource::Live)).unwrap();
+        assert_eq!(client.pull(&here.store()).unwrap().added, 1);
+        assert_eq!(client.pull(&here.store()).unwrap(), SyncedPlays::default());
+        assert_eq!(played(&here.store()).len(), 4);
+        stop();
+    }
+
+    #[test]
+    fn test_backfilled_plays_go_after_newer_ones() {
+        let (here, there) = (Instance::new("late"), Instance::new("early"));
+        let (url, stop) = there.serve();
+        let client = SyncClient::new(&url, "tester", TOKEN.into()).unwrap();
+
+        here.store().append(&play(5000, PlaySource::Live)).unwrap();
+        assert_eq!(client.push(&here.store()).unwrap().added, 1);
+        // Recently played backfills a play from before the last push
+        here.store()
+            .append(&play(1000, PlaySource::Backfill))
+            .unwrap();
+        assert_eq!(client.push(&here.store()).unwrap().added, 1);
+        assert_eq!(
+            played(&there.store()),
+            [(5000, PlaySource::Live), (1000, PlaySource::Backfill)]
+        );
+
+        there
+            .store()
+            .append(&play(3000, PlaySource::Backfill))
+            .unwrap();
+        assert_eq!(client.pull(&here.store()).unwrap().added, 1);
+        there
+            .store()
+            .append(&play(2000, PlaySource::Backfill))
+            .unwrap();
+        assert_eq!(client.pull(&here.store()).unwrap().added, 1);
+        assert_eq!(played(&here.store()).len(), 4);
+        stop();
+    }
+
+    #[test]
+    fn test_live_plays_win_over_backfilled_ones() {
+        let (here, there) = (Instance::new("live"), Instance::new("backfill"));
+        let (url, stop) = there.serve();
+        let client = SyncClient::new(&url, "tester", TOKEN.into()).unwrap();
+
+        // Recently played reports a play when it finished, the daemon when it started
+        here.store().append(&play(1000, PlaySource::Live)).unwrap();
+        here.store()
+            .append(&play(2100, PlaySource::Backfill))
+            .unwrap();
+        there
+            .store()
+            .append(&play(1100, PlaySource::Backfill))
+            .unwrap();
+        there.store().append(&play(2000, PlaySource::Live)).unwrap();
+
+        let pushed = client.push(&here.store()).unwrap();
+        assert_eq!(
+            pushed,
+            SyncedPlays {
+                added: 0,
+                replaced: 1
+            }
+        );
+        assert_eq!(
+            played(&there.store()),
+            [(1000, PlaySource::Live), (2000, PlaySource::Live)]
+        );
+        let pulled = client.pull(&here.store()).unwrap();
+        assert_eq!(
+            pulled,
+            SyncedPlays {
+                added: 0,
+                replaced: 1
+            }
+        );
+        assert_eq!(
+            played(&here.store()),
+            [(1000, PlaySource::Live), (2000, PlaySource::Live)]
+        );
+        assert!(here.store().load().unwrap()[1].device.is_some());
+        stop();
+    }
+
+    #[test]
+    fn test_sync_needs_the_token_and_user() {
+        let (here, there) = (Instance::new("intruder"), Instance::new("guarded"));
+        let (url, stop) = there.serve();
+        here.store().append(&play(1000, PlaySource::Live)).unwrap();
+
+        let wrong_token = SyncClient::new(&url, "tester", "guess".into()).unwrap();
+        let err = wrong_token.push(&here.store()).unwrap_err();
+        assert!(err.to_string().contains("401"), "{err}");
+        let wrong_user = SyncClient::new(&url, "someone-else", TOKEN.into()).unwrap();
+        let err = wrong_user.pull(&here.store()).unwrap_err();
+        assert!(err.to_string().contains("403"), "{err}");
+        // Nothing got through and the watermark didn't move
+        assert!(there.store().load().unwrap().is_empty());
+        let client = SyncClient::new(&url, "tester", TOKEN.into()).unwrap();
+        assert_eq!(client.push(&here.store()).unwrap().added, 1);
+        stop();
+    }
+
+    #[test]
+    fn test_load_sync_token() {
+        let instance = Instance::new("token");
+        assert!(load_sync_token(&instance.dir).is_err());
+        fs::write(instance.dir.join(SYNC_TOKEN_FILE), "  shared-token\n").unwrap();
+        assert_eq!(load_sync_token(&instance.dir).unwrap(), "shared-token");
+    }
+}
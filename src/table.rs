@@ -0,0 +1,207 @@
+use std::ffi::OsString;
+use std::io::IsTerminal;
+
+const ELLIPSIS: char = '…';
+const COLUMN_GAP: &str = "  ";
+const HEADER_STYLE: &str = "\x1b[1;36m";
+const RESET_STYLE: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Right,
+}
+
+struct Column {
+    header: String,
+    align: Align,
+    max_width: Option<usize>,
+}
+
+/// Plain text table for the CLI's list-style output.
+/// Columns are padded to their widest cell, cells over a column's max width
+/// are cut with an ellipsis.
+pub struct Table {
+    columns: Vec<Column>,
+    rows: Vec<Vec<String>>,
+    color: bool,
+}
+
+impl Table {
+    pub fn new(headers: &[&str]) -> Table {
+        let columns = headers
+            .iter()
+            .map(|header| Column {
+                header: header.to_string(),
+                align: Align::Left,
+                max_width: None,
+            })
+            .collect();
+        Table {
+            columns,
+            rows: Vec::new(),
+            color: false,
+        }
+    }
+
+    pub fn align(mut self, column: usize, align: Align) -> Table {
+        self.columns[column].align = align;
+        self
+    }
+
+    pub fn max_width(mut self, column: usize, width: usize) -> Table {
+        self.columns[column].max_width = Some(width);
+        self
+    }
+
+    /// Colors the header row, see [use_color] for when that's appropriate.
+    pub fn with_color(mut self, color: bool) -> Table {
+        self.color = color;
+        self
+    }
+
+    /// Missing cells are left blank, extra cells are dropped.
+    pub fn add_row(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    pub fn render(&self) -> String {
+        let cell = |row: &[String], i: usize| {
+            let value = row.get(i).map(String::as_str).unwrap_or("");
+            match self.columns[i].max_width {
+                Some(max) => truncate(value, max),
+                None => value.to_string(),
+            }
+        };
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| (0..self.columns.len()).map(|i| cell(row, i)).collect())
+            .collect();
+        let headers: Vec<String> = self
+            .columns
+            .iter()
+            .map(|column| match column.max_width {
+                Some(max) => truncate(&column.header, max),
+                None => column.header.clone(),
+            })
+            .collect();
+
+        let widths: Vec<usize> = (0..self.columns.len())
+            .map(|i| {
+                rows.iter()
+                    .map(|row| width(&row[i]))
+                    .chain([width(&headers[i])])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let mut out = String::new();
+        let header_line = self.render_line(&headers, &widths);
+        if self.color {
+            out.push_str(HEADER_STYLE);
+            out.push_str(&header_line);
+            out.push_str(RESET_STYLE);
+        } else {
+            out.push_str(&header_line);
+        }
+        out.push('\n');
+        for row in &rows {
+            out.push_str(&self.render_line(row, &widths));
+            out.push('\n');
+        }
+        out
+    }
+
+    fn render_line(&self, cells: &[String], widths: &[usize]) -> String {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(widths)
+            .zip(&self.columns)
+            .map(|((cell, &w), column)| {
+                let pad = " ".repeat(w - width(cell));
+                match column.align {
+                    Align::Left => format!("{cell}{pad}"),
+                    Align::Right => format!("{pad}{cell}"),
+                }
+            })
+            .collect();
+        padded.join(COLUMN_GAP).trim_end().to_string()
+    }
+}
+
+fn width(s: &str) -> usize {
+    s.chars().count()
+}
+
+/// Cuts `s` down to `max` characters, the last one being an ellipsis.
+pub fn truncate(s: &str, max: usize) -> String {
+    if width(s) <= max {
+        return s.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+    let mut cut: String = s.chars().take(max - 1).collect();
+    cut.push(ELLIPSIS);
+    cut
+}
+
+/// Headers are only colored on a terminal, and never when `--no-color` or
+/// the `NO_COLOR` env var (https://no-color.org) is set.
+pub fn use_color(no_color_flag: bool) -> bool {
+    color_allowed(
+        no_color_flag,
+        std::env::var_os("NO_COLOR"),
+        std::io::stdout().is_terminal(),
+    )
+}
+
+fn color_allowed(no_color_flag: bool, no_color_env: Option<OsString>, is_tty: bool) -> bool {
+    let env_set = no_color_env.is_some_and(|v| !v.is_empty());
+    is_tty && !no_color_flag && !env_set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("Kitchen speaker", 20), "Kitchen speaker");
+        assert_eq!(truncate("Kitchen speaker", 7), "Kitche…");
+        assert_eq!(truncate("Beyoncé Knowles", 8), "Beyoncé…");
+        assert_eq!(truncate("abc", 0), "");
+    }
+
+    #[test]
+    fn test_render_alignment_and_truncation() {
+        let mut table = Table::new(&["Name", "Volume"])
+            .align(1, Align::Right)
+            .max_width(0, 10);
+        table.add_row(vec!["Living room TV".to_string(), "5".to_string()]);
+        table.add_row(vec!["Phone".to_string(), "100".to_string()]);
+
+        let expected = "Name        Volume\nLiving ro…       5\nPhone          100\n";
+        assert_eq!(table.render(), expected);
+    }
+
+    #[test]
+    fn test_color_fallback() {
+        assert!(color_allowed(false, None, true));
+        assert!(!color_allowed(false, None, false));
+        assert!(!color_allowed(true, None, true));
+        assert!(!color_allowed(false, Some("1".into()), true));
+        // An empty NO_COLOR doesn't count as set
+        assert!(color_allowed(false, Some("".into()), true));
+
+        let mut table = Table::new(&["Name"]).with_color(false);
+        table.add_row(vec!["Phone".to_string()]);
+        assert!(!table.render().contains('\x1b'));
+        assert!(Table::new(&["Name"])
+            .with_color(true)
+            .render()
+            .starts_with(HEADER_STYLE));
+    }
+}
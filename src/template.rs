@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+
+/// A text with `{name}` placeholders, e.g. `{title} by {artists}`.
+/// `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Placeholder(String),
+}
+
+impl Template {
+    /// Parses `text`, every placeholder in it has to be one of `known` so a
+    /// typo is reported instead of rendering as nothing.
+    pub fn parse(text: &str, known: &[&str]) -> Result<Template> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        bail!("Unclosed {{ in template \"{text}\"");
+                    };
+                    let name = rest[..end].trim();
+                    if !known.contains(&name) {
+                        bail!(
+                            "Unknown placeholder {{{name}}} in template, use one of {}",
+                            known
+                                .iter()
+                                .map(|known| format!("{{{known}}}"))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        );
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(name.to_string()));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => bail!("Unmatched }} in template \"{text}\", write }}}} for a literal one"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Whether the template uses the placeholder, e.g. to skip looking up a
+    /// value nobody will see.
+    pub fn uses(&self, name: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder(p) if p == name))
+    }
+
+    /// Fills in the placeholders, those without a value render empty.
+    pub fn render(&self, values: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Placeholder(name) => {
+                    if let Some((_, value)) = values.iter().find(|(key, _)| key == name) {
+                        out.push_str(value);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KNOWN: [&str; 3] = ["title", "artists", "url"];
+
+    #[test]
+    fn test_render_template() {
+        let template = Template::parse("{title} by { artists }\n{{{url}}}", &KNOWN).unwrap();
+        assert!(template.uses("url"));
+        assert!(!template.uses("album"));
+        assert_eq!(
+            template.render(&[("title", "The Divine Zero"), ("artists", "Pierce The Veil")]),
+            "The Divine Zero by Pierce The Veil\n{}"
+        );
+    }
+
+    #[test]
+    fn test_bad_templates() {
+        let unknown = Template::parse("{titel}", &KNOWN).unwrap_err();
+        assert!(unknown.to_string().contains("{title}, {artists}, {url}"));
+        assert!(Template::parse("{title", &KNOWN).is_err());
+        assert!(Template::parse("title}", &KNOWN).is_err());
+        assert_eq!(
+            Template::parse("", &KNOWN)
+                .unwrap()
+                .render(&[("title", "x")]),
+            ""
+        );
+    }
+}
@@ -0,0 +1,38 @@
+//! Fixture loading for tests, independent of the directory they run from.
+
+use crate::spotify_data::{CurrentlyPlayingTrack, Track};
+
+use std::fs;
+use std::path::PathBuf;
+
+/// The path of a file in `sample_data`, e.g. `sample_path("me.json")`.
+pub fn sample_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("sample_data")
+        .join(name)
+}
+
+/// The contents of a file in `sample_data`, panicking when it can't be read.
+pub fn load_sample(name: &str) -> String {
+    let path = sample_path(name);
+    fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Could not read sample {}: {e}", path.display()))
+}
+
+/// The track of `currently_playing_track.json`, The Divine Zero.
+pub fn sample_track() -> Track {
+    let playing: CurrentlyPlayingTrack =
+        serde_json::from_str(&load_sample("currently_playing_track.json")).unwrap();
+    playing.get_track_data().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_resolve_from_any_directory() {
+        assert!(sample_path("me.json").is_absolute());
+        assert!(load_sample("me.json").contains("display_name"));
+    }
+}
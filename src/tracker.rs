@@ -0,0 +1,478 @@
+use crate::history::{Confidence, PlayHistoryEntry};
+use crate::spotify_data::{Episode, PlayContext, PlaybackState, PlayingType, Queue, Track};
+
+use std::time::{Duration, SystemTime};
+
+/// Added to the poll interval for the skip tolerance, polls come late when
+/// Spotify is slow to answer.
+const SKIP_TOLERANCE_MARGIN: Duration = Duration::from_secs(5);
+
+/// How close to its end a track that moved on still played to the end, when
+/// the player is polled every `poll_interval`. The last poll of a track
+/// comes up to an interval before its end.
+pub fn skip_tolerance(poll_interval: Duration) -> Duration {
+    poll_interval + SKIP_TOLERANCE_MARGIN
+}
+
+/// Something worth recording, a track or a podcast episode along with how
+/// far into it the player is.
+#[derive(Debug, Clone)]
+pub enum Listen {
+    Track(Track),
+    Episode {
+        episode: Episode,
+        progress_ms: Option<u32>,
+    },
+}
+
+impl Listen {
+    /// The id plays are recorded under, the one asked for when Spotify
+    /// relinked the track.
+    pub fn id(&self) -> &str {
+        match self {
+            Listen::Track(track) => track.original_id(),
+            Listen::Episode { episode, .. } => &episode.id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Listen::Track(track) => &track.name,
+            Listen::Episode { episode, .. } => &episode.name,
+        }
+    }
+
+    fn progress_ms(&self) -> Option<u32> {
+        match self {
+            Listen::Track(_) => None,
+            Listen::Episode { progress_ms, .. } => *progress_ms,
+        }
+    }
+
+    fn entry(&self, played_at: SystemTime, confidence: Confidence) -> PlayHistoryEntry {
+        match self {
+            Listen::Track(track) => PlayHistoryEntry::from_track(track, played_at, confidence),
+            Listen::Episode {
+                episode,
+                progress_ms,
+            } => PlayHistoryEntry::from_episode(episode, *progress_ms, played_at, confidence),
+        }
+    }
+}
+
+impl From<Track> for Listen {
+    fn from(track: Track) -> Self {
+        Listen::Track(track)
+    }
+}
+
+/// Picks what to record from the player and, in queue-assisted mode, the
+/// queue endpoint.
+///
+/// Rules:
+/// - Whenever the player reports an item, the player wins, even if the
+///   queue disagrees. Tracks and episodes are recorded, chapters and ads
+///   are not.
+/// - Only when the player has nothing (204 or `item: null`) is the queue's
+///   `currently_playing` used, and that snapshot is `Low` confidence.
+pub fn reconcile(
+    player: Option<&PlaybackState>,
+    queue: Option<&Queue>,
+) -> Option<(Listen, Confidence)> {
+    if let Some(state) = player.filter(|s| s.playing.item.is_some()) {
+        let playing = &state.playing;
+        let listen = match playing.currently_playing_type {
+            PlayingType::Episode => Listen::Episode {
+                episode: serde_json::from_value(playing.item.clone()?).ok()?,
+                progress_ms: playing.progress_ms,
+            },
+            _ => Listen::Track(playing.get_track_data()?),
+        };
+        return Some((listen, Confidence::High));
+    }
+
+    queue
+        .and_then(|q| q.get_current_track())
+        .map(|track| (Listen::Track(track), Confidence::Low))
+}
+
+/// Turns reconciled snapshots into history entries without double counting.
+///
+/// The play in progress is kept pending and handed back once a different
+/// track starts. Dedup rules:
+/// - A snapshot of the pending track continues that play. A `High`
+///   snapshot upgrades a pending `Low` play rather than adding another.
+/// - A `Low` snapshot never starts a new play of the pending track, the
+///   queue keeps reporting the last track long after playback stopped.
+/// - A `High` snapshot of the pending track starts a new play only once the
+///   track's full duration has elapsed since it started (repeat). Episodes
+///   get paused and picked up again, they keep their play however long it
+///   takes and remember the furthest progress.
+/// - Empty snapshots keep the pending play, they are usually pauses or the
+///   brief gap Spotify reports between tracks.
+///
+/// A track that something else took over from well before its end is
+/// completed with where that happened, see [PlayTracker::note_position].
+pub struct PlayTracker {
+    pending: Option<PlayHistoryEntry>,
+    /// Where the player last was in the pending track
+    position_ms: Option<u32>,
+    skip_tolerance: Duration,
+}
+
+impl PlayTracker {
+    /// A tracker of the player polled every `poll_interval`.
+    pub fn new(poll_interval: Duration) -> PlayTracker {
+        PlayTracker {
+            pending: None,
+            position_ms: None,
+            skip_tolerance: skip_tolerance(poll_interval),
+        }
+    }
+
+    /// Returns the previous play when this snapshot completes it.
+    pub fn observe(
+        &mut self,
+        snapshot: Option<(Listen, Confidence)>,
+        now: SystemTime,
+    ) -> Option<PlayHistoryEntry> {
+        let (listen, confidence) = snapshot?;
+
+        if let Some(pending) = self.pending.as_mut() {
+            if pending.track_id == listen.id() {
+                if confidence == Confidence::Low {
+                    return None;
+                }
+                let elapsed = now
+                    .duration_since(pending.played_at)
+                    .unwrap_or(Duration::ZERO);
+                let repeat = elapsed >= Duration::from_millis(pending.duration_ms as u64);
+                if pending.is_episode() {
+                    pending.progress_ms = pending.progress_ms.max(listen.progress_ms());
+                }
+                if pending.is_episode() || !repeat {
+                    pending.confidence = Confidence::High;
+                    return None;
+                }
+            }
+        }
+
+        let entry = listen.entry(now, confidence);
+        let position_ms = self.position_ms.take();
+        let mut completed = self.pending.replace(entry)?;
+        completed.skipped_at_ms = position_ms.filter(|&position| {
+            !completed.is_episode()
+                && Duration::from_millis(position as u64) + self.skip_tolerance
+                    < Duration::from_millis(completed.duration_ms as u64)
+        });
+        Some(completed)
+    }
+
+    /// Notes where the player is in the track in progress, as it reported
+    /// it with the snapshot just observed.
+    pub fn note_position(&mut self, position_ms: u32) {
+        if self.pending.as_ref().is_some_and(|p| !p.is_episode()) {
+            self.position_ms = Some(position_ms);
+        }
+    }
+
+    /// Notes the device the play in progress is on. The play keeps the
+    /// device it started on.
+    pub fn note_device(&mut self, device: &str) {
+        if let Some(pending) = self.pending.as_mut().filter(|p| p.device.is_none()) {
+            pending.device = Some(device.to_string());
+        }
+    }
+
+    /// Notes what the play in progress is played from. Like the device, the
+    /// play keeps the context it started in.
+    pub fn note_context(&mut self, context: PlayContext) {
+        if let Some(pending) = self.pending.as_mut().filter(|p| p.context.is_none()) {
+            pending.context = Some(context);
+        }
+    }
+
+    pub fn current(&self) -> Option<&PlayHistoryEntry> {
+        self.pending.as_ref()
+    }
+
+    /// Labels the play in progress, false when nothing is playing.
+    pub fn tag_current(&mut self, label: &str) -> bool {
+        let Some(pending) = self.pending.as_mut() else {
+            return false;
+        };
+        if !pending.has_tag(label) {
+            pending.tags.push(label.to_string());
+        }
+        true
+    }
+
+    /// Carries on with a play saved before a restart, e.g. from the journal.
+    /// It is completed like any other once a different track shows up.
+    pub fn resume(&mut self, pending: PlayHistoryEntry) {
+        self.pending = Some(pending);
+        self.position_ms = None;
+    }
+
+    /// Hands back the play in progress, e.g. on shutdown. It was stopped,
+    /// not skipped.
+    pub fn finish(&mut self) -> Option<PlayHistoryEntry> {
+        self.position_ms = None;
+        self.pending.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLL: Duration = Duration::from_secs(5);
+
+    fn skip_sequence() -> Vec<PlaybackState> {
+        let data = std::fs::read_to_string("sample_data/skip_sequence.json").unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn queue() -> Queue {
+        let data = std::fs::read_to_string("sample_data/queue.json").unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+
+    #[test]
+    fn test_player_wins_over_queue() {
+        // Player is on the second track while the queue still reports the first
+        let player = skip_sequence().remove(3);
+        let (listen, confidence) = reconcile(Some(&player), Some(&queue())).unwrap();
+        assert_eq!(listen.name(), "Dive In");
+        assert_eq!(confidence, Confidence::High);
+    }
+
+    #[test]
+    fn test_queue_fills_in_when_player_is_empty() {
+        let between_tracks = skip_sequence().remove(1);
+        let (listen, confidence) = reconcile(Some(&between_tracks), Some(&queue())).unwrap();
+        assert_eq!(listen.name(), "The Divine Zero");
+        assert_eq!(confidence, Confidence::Low);
+        assert!(reconcile(None, None).is_none());
+    }
+
+    /// The player `progress_ms` into the sample episode.
+    fn playing_episode(progress_ms: u32) -> PlaybackState {
+        let episode =
+            std::fs::read_to_string("sample_data/currently_playing_episode.json").unwrap();
+        let episode: serde_json::Value = serde_json::from_str(&episode).unwrap();
+        let state = std::fs::read_to_string("sample_data/playback_state.json").unwrap();
+        let mut state: serde_json::Value = serde_json::from_str(&state).unwrap();
+        state["item"] = episode["item"].clone();
+        state["currently_playing_type"] = "episode".into();
+        state["progress_ms"] = progress_ms.into();
+        serde_json::from_value(state).unwrap()
+    }
+
+    #[test]
+    fn test_episode_keeps_its_play_and_progress() {
+        let start = SystemTime::now();
+        let mut tracker = PlayTracker::new(POLL);
+        assert!(tracker
+            .observe(reconcile(Some(&playing_episode(60_000)), None), start)
+            .is_none());
+
+        // Paused for longer than the episode and picked up again
+        let later = start + Duration::from_secs(4 * 3600);
+        assert!(tracker
+            .observe(reconcile(Some(&playing_episode(900_000)), None), later)
+            .is_none());
+        // Skipping back doesn't lose how far it got
+        assert!(tracker
+            .observe(reconcile(Some(&playing_episode(300_000)), None), later)
+            .is_none());
+
+        let track = queue().get_current_track().unwrap();
+        let play = tracker
+            .observe(Some((track.into(), Confidence::High)), later)
+            .unwrap();
+        assert!(play.is_episode());
+        assert_eq!(play.track_name, "The Sound of Cities");
+        assert_eq!(play.played_at, start);
+        assert_eq!(play.progress_ms, Some(900_000));
+    }
+
+    #[test]
+    fn test_skip_position() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let next = skip_sequence().remove(3).playing.get_track_data().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+        tracker.note_position(1_000);
+
+        tracker.observe(Some((track.clone().into(), Confidence::High)), start);
+        tracker.note_position(30_000);
+        tracker.note_position(61_500);
+        let skipped = tracker
+            .observe(Some((next.clone().into(), Confidence::High)), start)
+            .unwrap();
+        assert_eq!(skipped.skipped_at_ms, Some(61_500));
+
+        // Last seen a poll before the end, it played through
+        tracker.note_position(next.duration_ms - 4_000);
+        let played = tracker
+            .observe(Some((track.into(), Confidence::High)), start)
+            .unwrap();
+        assert_eq!(played.skipped_at_ms, None);
+
+        // Stopping isn't skipping
+        tracker.note_position(20_000);
+        assert_eq!(tracker.finish().unwrap().skipped_at_ms, None);
+    }
+
+    #[test]
+    fn test_skip_tolerance_follows_the_poll_interval() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let next = skip_sequence().remove(3).playing.get_track_data().unwrap();
+        let last_seen = |tracker: &mut PlayTracker| {
+            tracker.observe(Some((track.clone().into(), Confidence::High)), start);
+            tracker.note_position(track.duration_ms - 25_000);
+            tracker
+                .observe(Some((next.clone().into(), Confidence::High)), start)
+                .unwrap()
+                .skipped_at_ms
+        };
+
+        // Polled every 30s, the last poll can be 25s before the end
+        assert_eq!(
+            last_seen(&mut PlayTracker::new(Duration::from_secs(30))),
+            None
+        );
+        assert!(last_seen(&mut PlayTracker::new(POLL)).is_some());
+    }
+
+    #[test]
+    fn test_low_then_high_is_one_upgraded_play() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+
+        assert!(tracker
+            .observe(Some((track.clone().into(), Confidence::Low)), start)
+            .is_none());
+        assert!(tracker
+            .observe(
+                Some((track.into(), Confidence::High)),
+                start + Duration::from_secs(5)
+            )
+            .is_none());
+
+        let play = tracker.finish().unwrap();
+        assert_eq!(play.confidence, Confidence::High);
+        assert_eq!(play.played_at, start);
+    }
+
+    #[test]
+    fn test_tags_stick_to_the_play_in_progress() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+        assert!(!tracker.tag_current("gym"));
+
+        tracker.observe(Some((track.clone().into(), Confidence::Low)), start);
+        assert!(tracker.tag_current("gym"));
+        assert!(tracker.tag_current("gym"));
+        // Upgrading the play keeps its tags
+        tracker.observe(Some((track.into(), Confidence::High)), start);
+        assert_eq!(tracker.finish().unwrap().tags, ["gym"]);
+    }
+
+    #[test]
+    fn test_play_keeps_the_device_it_started_on() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+        tracker.note_device("Pixel 7");
+        assert!(tracker.current().is_none());
+
+        tracker.observe(Some((track.clone().into(), Confidence::High)), start);
+        tracker.note_device("Pixel 7");
+        // Handed over to another device halfway through
+        tracker.observe(Some((track.into(), Confidence::High)), start);
+        tracker.note_device("Volvo XC40");
+        assert_eq!(tracker.finish().unwrap().device.as_deref(), Some("Pixel 7"));
+    }
+
+    #[test]
+    fn test_play_keeps_the_context_it_started_in() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+        tracker.observe(Some((track.into(), Confidence::High)), start);
+        tracker.note_context(PlayContext::Collection);
+        // Liked Songs ran out and autoplay took over
+        tracker.note_context(PlayContext::Autoplay);
+        assert_eq!(
+            tracker.finish().unwrap().context,
+            Some(PlayContext::Collection)
+        );
+    }
+
+    #[test]
+    fn test_stale_queue_does_not_double_count() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+
+        tracker.observe(Some((track.clone().into(), Confidence::High)), start);
+        tracker.observe(None, start + Duration::from_secs(60));
+        // Long after the track ended the queue still reports it
+        let later = start + Duration::from_secs(3600);
+        assert!(tracker
+            .observe(Some((track.into(), Confidence::Low)), later)
+            .is_none());
+
+        let next = skip_sequence().remove(3);
+        let (next_track, _) = reconcile(Some(&next), None).unwrap();
+        let completed = tracker
+            .observe(Some((next_track, Confidence::High)), later)
+            .unwrap();
+        assert_eq!(completed.track_name, "The Divine Zero");
+        assert_eq!(tracker.finish().unwrap().track_name, "Dive In");
+    }
+
+    #[test]
+    fn test_resumed_play_continues() {
+        let start = SystemTime::now();
+        let track = queue().get_current_track().unwrap();
+        let mut tracker = PlayTracker::new(POLL);
+        tracker.resume(PlayHistoryEntry::from_track(
+            &track,
+            start,
+            Confidence::High,
+        ));
+
+        // Still the same play after the restart
+        let later = start + Duration::from_secs(30);
+        assert!(tracker
+            .observe(Some((track.into(), Confidence::High)), later)
+            .is_none());
+        assert_eq!(tracker.current().unwrap().played_at, start);
+    }
+
+    #[test]
+    fn test_relinked_track_is_recorded_as_asked_for() {
+        let data = std::fs::read_to_string("sample_data/relinked_track.json").unwrap();
+        let track: Track = serde_json::from_str(&data).unwrap();
+        let start = SystemTime::now();
+        let mut tracker = PlayTracker::new(POLL);
+        tracker.observe(Some((track.clone().into(), Confidence::High)), start);
+        assert_eq!(
+            tracker.current().unwrap().track_id,
+            "2GnPNoTCTkDDvwSO3mdHa6"
+        );
+
+        // The same play as long as it is relinked the same way
+        let later = start + Duration::from_secs(30);
+        assert!(tracker
+            .observe(Some((track.into(), Confidence::High)), later)
+            .is_none());
+        assert_eq!(tracker.current().unwrap().played_at, start);
+    }
+}
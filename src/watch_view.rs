@@ -0,0 +1,292 @@
+use crate::notifications::NotificationSink;
+use crate::spotify_data::{Device, Episode, PlaybackState, Queue, Track};
+use crate::watcher::{WatchEvent, Watcher};
+
+use std::time::Instant;
+
+/// How many queued items the up next line shows
+pub const UP_NEXT_LEN: usize = 2;
+
+const EPISODE_STYLE: &str = "\x1b[3;35m";
+const RESET_STYLE: &str = "\x1b[0m";
+
+/// An item of the queue as the up next line shows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpNext {
+    Track { name: String, artists: String },
+    Episode { name: String, show: String },
+}
+
+impl UpNext {
+    /// None for queued items that are neither tracks nor episodes.
+    pub fn from_queue_item(item: &serde_json::Value) -> Option<UpNext> {
+        match item.get("type")?.as_str()? {
+            "track" => {
+                let track: Track = serde_json::from_value(item.clone()).ok()?;
+                let artists: Vec<&str> = track.artists.iter().map(|a| a.name.as_str()).collect();
+                Some(UpNext::Track {
+                    name: track.name,
+                    artists: artists.join(", "),
+                })
+            }
+            "episode" => {
+                let episode: Episode = serde_json::from_value(item.clone()).ok()?;
+                Some(UpNext::Episode {
+                    name: episode.name,
+                    show: episode.show.name,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn render(&self, color: bool) -> String {
+        match self {
+            UpNext::Track { name, artists } => format!("{name} - {artists}"),
+            UpNext::Episode { name, show } if color => {
+                format!("{EPISODE_STYLE}{name} from {show}{RESET_STYLE}")
+            }
+            UpNext::Episode { name, show } => format!("{name} from {show} (episode)"),
+        }
+    }
+}
+
+/// The `watch` loop's state: the [Watcher] plus the up next line. The
+/// player is polled every interval, the queue only after the item changed,
+/// so showing what's next costs one call per track rather than per poll.
+pub struct WatchView {
+    watcher: Watcher,
+    show_up_next: bool,
+    queue_due: bool,
+    up_next: Vec<UpNext>,
+    notifications: Vec<Box<dyn NotificationSink>>,
+}
+
+impl WatchView {
+    pub fn new(watcher: Watcher) -> WatchView {
+        WatchView {
+            watcher,
+            show_up_next: false,
+            queue_due: false,
+            up_next: Vec::new(),
+            notifications: Vec::new(),
+        }
+    }
+
+    /// Also keeps track of the next items of the queue.
+    pub fn with_up_next(mut self, show_up_next: bool) -> WatchView {
+        self.show_up_next = show_up_next;
+        self
+    }
+
+    /// Tells `sink` about every track that starts playing.
+    pub fn with_notifications(mut self, sink: Box<dyn NotificationSink>) -> WatchView {
+        self.notifications.push(sink);
+        self
+    }
+
+    pub fn needs_devices(&self, state: Option<&PlaybackState>) -> bool {
+        self.watcher.needs_devices(state)
+    }
+
+    /// Feeds the player's latest snapshot to the watcher. A new item makes
+    /// the queue due, a new track is handed to the notification sinks,
+    /// playback ending clears the up next line.
+    pub fn observe(
+        &mut self,
+        state: Option<PlaybackState>,
+        devices: Option<&[Device]>,
+        now: Instant,
+    ) -> Vec<WatchEvent> {
+        let events = self.watcher.observe_at(state, devices, now);
+        for event in &events {
+            if let WatchEvent::TrackChanged(track) = event {
+                for sink in &self.notifications {
+                    sink.notify(track);
+                }
+            }
+            match event {
+                WatchEvent::TrackChanged(_)
+                | WatchEvent::EpisodeChanged(_)
+                | WatchEvent::ChapterChanged(_) => self.queue_due = self.show_up_next,
+                WatchEvent::Stopped | WatchEvent::DeviceDisconnected(_) => {
+                    self.queue_due = false;
+                    self.up_next.clear();
+                }
+                WatchEvent::AdStarted | WatchEvent::Paused | WatchEvent::Resumed => {}
+            }
+        }
+        events
+    }
+
+    /// Whether the queue should be fetched before the next render.
+    pub fn queue_due(&self) -> bool {
+        self.queue_due
+    }
+
+    /// Takes the fetched queue, None when fetching it failed or the account
+    /// can't use it. The line then goes away until the next item.
+    pub fn queue_fetched(&mut self, queue: Option<&Queue>) {
+        self.queue_due = false;
+        self.up_next = queue
+            .map(|queue| {
+                queue
+                    .queue
+                    .iter()
+                    .filter_map(UpNext::from_queue_item)
+                    .take(UP_NEXT_LEN)
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+
+    pub fn up_next(&self) -> &[UpNext] {
+        &self.up_next
+    }
+}
+
+/// The up next line, None when there is nothing to show. Episodes are set
+/// apart from tracks, in color when allowed.
+pub fn render_up_next(up_next: &[UpNext], color: bool) -> Option<String> {
+    if up_next.is_empty() {
+        return None;
+    }
+    let items: Vec<String> = up_next.iter().map(|item| item.render(color)).collect();
+    Some(format!("Up next: {}", items.join(" · ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::load_sample;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    /// Keeps the ids of the tracks it was told about.
+    #[derive(Default, Clone)]
+    struct RecordingSink {
+        notified: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl NotificationSink for RecordingSink {
+        fn notify(&self, track: &Track) {
+            self.notified.lock().unwrap().push(track.id.clone());
+        }
+    }
+
+    fn playing_state() -> PlaybackState {
+        serde_json::from_str(&load_sample("playback_state.json")).unwrap()
+    }
+
+    fn queue() -> Queue {
+        serde_json::from_str(&load_sample("queue_mixed.json")).unwrap()
+    }
+
+    #[test]
+    fn test_queue_refreshed_on_item_change_only() {
+        let start = Instant::now();
+        let mut view = WatchView::new(Watcher::new()).with_up_next(true);
+        assert!(!view.queue_due());
+
+        view.observe(Some(playing_state()), None, start);
+        assert!(view.queue_due());
+        view.queue_fetched(Some(&queue()));
+        assert!(!view.queue_due());
+        assert_eq!(view.up_next().len(), UP_NEXT_LEN);
+
+        // Polls of the same track, paused or not, don't fetch it again
+        let mut paused = playing_state();
+        paused.playing.is_playing = false;
+        view.observe(Some(playing_state()), None, start);
+        view.observe(Some(paused), None, start);
+        assert!(!view.queue_due());
+
+        // A failed fetch drops the line until the next track
+        let mut next = playing_state();
+        next.playing.item.as_mut().unwrap()["id"] = "next".into();
+        view.observe(Some(next), None, start);
+        assert!(view.queue_due());
+        view.queue_fetched(None);
+        assert!(view.up_next().is_empty());
+
+        view.queue_fetched(Some(&queue()));
+        view.observe(None, Some(&[]), start);
+        assert!(view.up_next().is_empty());
+        assert!(!view.queue_due());
+
+        // Without the line the queue is never due
+        let mut view = WatchView::new(Watcher::new());
+        view.observe(Some(playing_state()), None, start);
+        assert!(!view.queue_due());
+    }
+
+    #[test]
+    fn test_notified_once_per_track_change() {
+        let start = Instant::now();
+        let sink = RecordingSink::default();
+        let watcher = Watcher::new().with_debounce(Duration::from_secs(5));
+        let mut view = WatchView::new(watcher).with_notifications(Box::new(sink.clone()));
+        let with_id = |id: &str| {
+            let mut state = playing_state();
+            state.playing.item.as_mut().unwrap()["id"] = id.into();
+            state
+        };
+
+        // Only once the track kept playing through the debounce
+        view.observe(Some(with_id("first")), None, start);
+        assert!(sink.notified.lock().unwrap().is_empty());
+        view.observe(Some(with_id("first")), None, start + Duration::from_secs(6));
+        assert_eq!(*sink.notified.lock().unwrap(), ["first"]);
+
+        // More polls, a pause and a resume of the same track aren't changes
+        let mut paused = with_id("first");
+        paused.playing.is_playing = false;
+        view.observe(Some(with_id("first")), None, start + Duration::from_secs(7));
+        view.observe(Some(paused), None, start + Duration::from_secs(8));
+        view.observe(Some(with_id("first")), None, start + Duration::from_secs(9));
+
+        // A track skipped past is never confirmed
+        view.observe(
+            Some(with_id("skipped")),
+            None,
+            start + Duration::from_secs(10),
+        );
+        view.observe(
+            Some(with_id("second")),
+            None,
+            start + Duration::from_secs(11),
+        );
+        view.observe(
+            Some(with_id("second")),
+            None,
+            start + Duration::from_secs(17),
+        );
+        assert_eq!(*sink.notified.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn test_render_up_next() {
+        let mut view = WatchView::new(Watcher::new());
+        view.queue_fetched(Some(&queue()));
+        assert_eq!(
+            view.up_next(),
+            [
+                UpNext::Track {
+                    name: "Dive In".to_string(),
+                    artists: "Pierce The Veil".to_string()
+                },
+                UpNext::Episode {
+                    name: "The Sound of Cities".to_string(),
+                    show: "Listening Room".to_string()
+                }
+            ]
+        );
+        assert_eq!(
+            render_up_next(view.up_next(), false).unwrap(),
+            "Up next: Dive In - Pierce The Veil · The Sound of Cities from Listening Room (episode)"
+        );
+        let colored = render_up_next(view.up_next(), true).unwrap();
+        assert!(colored.contains("\x1b[3;35mThe Sound of Cities from Listening Room\x1b[0m"));
+        assert_eq!(render_up_next(&[], true), None);
+    }
+}
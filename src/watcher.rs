@@ -0,0 +1,533 @@
+use crate::spotify_data::{
+    Chapter, Device, Episode, PlaybackState, PlaybackStatus, PlayingItem, Track,
+};
+
+use std::time::{Duration, Instant};
+
+/// How many consecutive polls without an item to sit through before calling
+/// it a stop.
+pub const DEFAULT_INDETERMINATE_LIMIT: u32 = 3;
+
+/// Something that changed between two polls of the player.
+#[derive(Debug)]
+pub enum WatchEvent {
+    TrackChanged(Track),
+    EpisodeChanged(Episode),
+    ChapterChanged(Chapter),
+    AdStarted,
+    Paused,
+    Resumed,
+    Stopped,
+    /// Playback ended because the device went away, not because the user stopped it.
+    DeviceDisconnected(Device),
+}
+
+/// What the watcher remembers from the previous poll.
+struct LastSeen {
+    item_id: Option<String>,
+    is_playing: bool,
+    device: Device,
+}
+
+/// A change of item held back until the item has been current for the
+/// debounce duration.
+struct PendingChange {
+    since: Instant,
+    event: WatchEvent,
+}
+
+/// Turns successive `/me/player` snapshots into [WatchEvent]s.
+/// It does no IO itself, the poll loop feeds it.
+pub struct Watcher {
+    last: Option<LastSeen>,
+    indeterminate_limit: u32,
+    indeterminate_polls: u32,
+    track_chapters: bool,
+    debounce: Duration,
+    pending: Option<PendingChange>,
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Watcher {
+            last: None,
+            indeterminate_limit: DEFAULT_INDETERMINATE_LIMIT,
+            indeterminate_polls: 0,
+            track_chapters: true,
+            debounce: Duration::ZERO,
+            pending: None,
+        }
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        Watcher::default()
+    }
+
+    /// Right after a skip Spotify briefly reports `item: null`, even with
+    /// `is_playing: true`, see [PlaybackStatus::Transitioning]. Those polls
+    /// are indeterminate: the previous state is held for up to `limit` of
+    /// them in a row before concluding playback stopped.
+    pub fn with_indeterminate_limit(mut self, limit: u32) -> Watcher {
+        self.indeterminate_limit = limit;
+        self
+    }
+
+    /// Audiobook chapters are reported like episodes by default. When off,
+    /// a chapter starting gives no event, pauses and resumes still do.
+    pub fn with_chapters(mut self, track_chapters: bool) -> Watcher {
+        self.track_chapters = track_chapters;
+        self
+    }
+
+    /// Only reports a change once the new item has been current for at
+    /// least `debounce`, so skipping through tracks gives no event for the
+    /// ones skipped past. The event comes with the first poll after that.
+    pub fn with_debounce(mut self, debounce: Duration) -> Watcher {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Spotify answers 204 both when music was stopped and when the device
+    /// went offline. Telling them apart needs the devices list, which is only
+    /// worth fetching when playback was going and the player just went silent.
+    pub fn needs_devices(&self, state: Option<&PlaybackState>) -> bool {
+        state.is_none() && self.last.as_ref().is_some_and(|last| last.is_playing)
+    }
+
+    /// Feeds the latest snapshot, `None` meaning the player returned 204.
+    /// `devices` should be provided whenever [Watcher::needs_devices] says so.
+    pub fn observe(
+        &mut self,
+        state: Option<PlaybackState>,
+        devices: Option<&[Device]>,
+    ) -> Vec<WatchEvent> {
+        self.observe_at(state, devices, Instant::now())
+    }
+
+    /// [Watcher::observe] for a snapshot polled at `now`.
+    pub fn observe_at(
+        &mut self,
+        state: Option<PlaybackState>,
+        devices: Option<&[Device]>,
+        now: Instant,
+    ) -> Vec<WatchEvent> {
+        let Some(state) = state else {
+            self.indeterminate_polls = 0;
+            self.pending = None;
+            return match self.last.take() {
+                None => vec![],
+                Some(last)
+                    if last.is_playing && devices.is_some_and(|d| !is_listed(&last.device, d)) =>
+                {
+                    vec![WatchEvent::DeviceDisconnected(last.device)]
+                }
+                Some(_) => vec![WatchEvent::Stopped],
+            };
+        };
+
+        let status = state.playing.status();
+        if matches!(
+            status,
+            PlaybackStatus::Transitioning | PlaybackStatus::Stopped
+        ) {
+            self.indeterminate_polls += 1;
+            if self.indeterminate_polls <= self.indeterminate_limit {
+                return vec![];
+            }
+            self.pending = None;
+            return match self.last.take() {
+                None => vec![],
+                Some(_) => vec![WatchEvent::Stopped],
+            };
+        }
+        self.indeterminate_polls = 0;
+
+        let item_id = state
+            .playing
+            .item
+            .as_ref()
+            .and_then(|item| item.get("id"))
+            .and_then(|id| id.as_str())
+            .map(String::from);
+        let is_playing = state.playing.is_playing;
+
+        let PlaybackState {
+            device, playing, ..
+        } = state;
+        let mut events = Vec::new();
+        match &self.last {
+            Some(last) if last.item_id == item_id => {
+                events.extend(take_due(&mut self.pending, self.debounce, now));
+                if last.is_playing && !is_playing {
+                    events.push(WatchEvent::Paused);
+                } else if !last.is_playing && is_playing {
+                    events.push(WatchEvent::Resumed);
+                }
+            }
+            _ => {
+                let change = match playing.into_playing_item() {
+                    Some(PlayingItem::Track(track)) => Some(WatchEvent::TrackChanged(track)),
+                    Some(PlayingItem::Episode(episode)) => {
+                        Some(WatchEvent::EpisodeChanged(episode))
+                    }
+                    Some(PlayingItem::Chapter(chapter)) if self.track_chapters => {
+                        Some(WatchEvent::ChapterChanged(chapter))
+                    }
+                    Some(PlayingItem::Chapter(_)) => None,
+                    Some(PlayingItem::Ad) => Some(WatchEvent::AdStarted),
+                    None => None,
+                };
+                // Whatever was pending was skipped past
+                self.pending = change.map(|event| PendingChange { since: now, event });
+                events.extend(take_due(&mut self.pending, self.debounce, now));
+            }
+        }
+
+        self.last = Some(LastSeen {
+            item_id,
+            is_playing,
+            device,
+        });
+        events
+    }
+}
+
+/// The pending change once its item has been current for `debounce`.
+fn take_due(
+    pending: &mut Option<PendingChange>,
+    debounce: Duration,
+    now: Instant,
+) -> Option<WatchEvent> {
+    pending
+        .take_if(|pending| now.saturating_duration_since(pending.since) >= debounce)
+        .map(|pending| pending.event)
+}
+
+/// Devices without an id (restricted ones) can only be matched by name.
+fn is_listed(device: &Device, devices: &[Device]) -> bool {
+    devices.iter().any(|d| match (&d.id, &device.id) {
+        (Some(a), Some(b)) => a == b,
+        _ => d.name == device.name,
+    })
+}
+
+/// Estimates the playback position between polls: the progress of the last
+/// poll plus the time since, while playing. Lets a progress bar tick
+/// smoothly while the player is only polled every few seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct PlaybackExtrapolator {
+    progress: Duration,
+    /// Length of the item, the estimate never goes past it
+    duration: Option<Duration>,
+    is_playing: bool,
+    fetched_at: Instant,
+}
+
+impl PlaybackExtrapolator {
+    pub fn new(
+        progress_ms: u32,
+        duration_ms: Option<u32>,
+        is_playing: bool,
+        fetched_at: Instant,
+    ) -> PlaybackExtrapolator {
+        PlaybackExtrapolator {
+            progress: Duration::from_millis(progress_ms as u64),
+            duration: duration_ms.map(|ms| Duration::from_millis(ms as u64)),
+            is_playing,
+            fetched_at,
+        }
+    }
+
+    /// From a state polled at `fetched_at`, None when it has no progress.
+    pub fn from_state(state: &PlaybackState, fetched_at: Instant) -> Option<PlaybackExtrapolator> {
+        let playing = &state.playing;
+        let duration_ms = playing
+            .item
+            .as_ref()
+            .and_then(|item| item.get("duration_ms")?.as_u64())
+            .and_then(|ms| u32::try_from(ms).ok());
+        Some(PlaybackExtrapolator::new(
+            playing.progress_ms?,
+            duration_ms,
+            playing.is_playing,
+            fetched_at,
+        ))
+    }
+
+    /// The estimated position at `now`.
+    pub fn progress_at(&self, now: Instant) -> Duration {
+        let progress = if self.is_playing {
+            self.progress + now.saturating_duration_since(self.fetched_at)
+        } else {
+            self.progress
+        };
+        match self.duration {
+            Some(duration) => progress.min(duration),
+            None => progress,
+        }
+    }
+
+    pub fn progress_ms_at(&self, now: Instant) -> u32 {
+        self.progress_at(now)
+            .as_millis()
+            .try_into()
+            .unwrap_or(u32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spotify_data::Devices;
+
+    fn playing_state() -> PlaybackState {
+        let data = std::fs::read_to_string("sample_data/playback_state.json").unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn skip_sequence() -> Vec<PlaybackState> {
+        let data = std::fs::read_to_string("sample_data/skip_sequence.json").unwrap();
+        serde_json::from_str(&data).unwrap()
+    }
+
+    fn chapter_state() -> PlaybackState {
+        let data = std::fs::read_to_string("sample_data/currently_playing_chapter.json").unwrap();
+        PlaybackState {
+            playing: serde_json::from_str(&data).unwrap(),
+            ..playing_state()
+        }
+    }
+
+    fn devices() -> Vec<Device> {
+        let data = std::fs::read_to_string("sample_data/devices.json").unwrap();
+        serde_json::from_str::<Devices>(&data).unwrap().devices
+    }
+
+    #[test]
+    fn test_device_disconnected() {
+        let mut watcher = Watcher::new();
+        let events = watcher.observe(Some(playing_state()), None);
+        assert!(matches!(events[..], [WatchEvent::TrackChanged(_)]));
+
+        assert!(watcher.needs_devices(None));
+        let events = watcher.observe(None, Some(&[]));
+        match &events[..] {
+            [WatchEvent::DeviceDisconnected(device)] => assert_eq!(device.name, "Kitchen speaker"),
+            other => panic!("expected a disconnect, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_stopped_while_device_still_around() {
+        let mut watcher = Watcher::new();
+        watcher.observe(Some(playing_state()), None);
+        let events = watcher.observe(None, Some(&devices()));
+        assert!(matches!(events[..], [WatchEvent::Stopped]));
+    }
+
+    #[test]
+    fn test_skip_does_not_stop_playback() {
+        let mut watcher = Watcher::new();
+        let events: Vec<WatchEvent> = skip_sequence()
+            .into_iter()
+            .flat_map(|state| watcher.observe(Some(state), None))
+            .collect();
+
+        match &events[..] {
+            [WatchEvent::TrackChanged(first), WatchEvent::TrackChanged(second)] => {
+                assert_eq!(first.name, "The Divine Zero");
+                assert_eq!(second.name, "Dive In");
+            }
+            other => panic!("expected two track changes, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_indeterminate_limit_concludes_stop() {
+        let mut watcher = Watcher::new().with_indeterminate_limit(1);
+        let events: Vec<WatchEvent> = skip_sequence()
+            .into_iter()
+            .flat_map(|state| watcher.observe(Some(state), None))
+            .collect();
+
+        assert!(matches!(
+            events[..],
+            [
+                WatchEvent::TrackChanged(_),
+                WatchEvent::Stopped,
+                WatchEvent::TrackChanged(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_no_item_is_held_playing_or_not() {
+        let mut watcher = Watcher::new().with_indeterminate_limit(2);
+        watcher.observe(Some(playing_state()), None);
+        let transition = skip_sequence().remove(1);
+        assert_eq!(transition.playing.status(), PlaybackStatus::Transitioning);
+        assert!(watcher.observe(Some(transition), None).is_empty());
+
+        let stopped = || {
+            let mut state = skip_sequence().remove(1);
+            state.playing.is_playing = false;
+            state
+        };
+        assert_eq!(stopped().playing.status(), PlaybackStatus::Stopped);
+        assert!(watcher.observe(Some(stopped()), None).is_empty());
+        let events = watcher.observe(Some(stopped()), None);
+        assert!(matches!(events[..], [WatchEvent::Stopped]));
+    }
+
+    /// The playing state with the track swapped for one named `name`.
+    fn track_state(name: &str) -> PlaybackState {
+        let mut state = playing_state();
+        let item = state.playing.item.as_mut().unwrap();
+        item["id"] = name.into();
+        item["name"] = name.into();
+        state
+    }
+
+    #[test]
+    fn test_debounce_drops_skipped_tracks() {
+        let start = Instant::now();
+        let mut watcher = Watcher::new().with_debounce(Duration::from_secs(10));
+        // Hunting for a track, then settling on the fourth one
+        let polls = [
+            (0, "first"),
+            (3, "second"),
+            (5, "third"),
+            (8, "stuck"),
+            (13, "stuck"),
+            (18, "stuck"),
+            (23, "stuck"),
+        ];
+        let mut fired = Vec::new();
+        for (secs, name) in polls {
+            let at = start + Duration::from_secs(secs);
+            for event in watcher.observe_at(Some(track_state(name)), None, at) {
+                fired.push((secs, event));
+            }
+        }
+
+        match &fired[..] {
+            [(18, WatchEvent::TrackChanged(track))] => assert_eq!(track.name, "stuck"),
+            other => panic!("expected one change for the stuck track, got {other:?}"),
+        }
+
+        // Pausing the pending track doesn't hold its change back
+        let mut paused = track_state("next");
+        paused.playing.is_playing = false;
+        watcher.observe_at(
+            Some(track_state("next")),
+            None,
+            start + Duration::from_secs(30),
+        );
+        let events = watcher.observe_at(Some(paused), None, start + Duration::from_secs(40));
+        assert!(matches!(
+            events[..],
+            [WatchEvent::TrackChanged(_), WatchEvent::Paused]
+        ));
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let mut watcher = Watcher::new();
+        watcher.observe(Some(playing_state()), None);
+
+        let mut paused = playing_state();
+        paused.playing.is_playing = false;
+        assert!(matches!(
+            watcher.observe(Some(paused), None)[..],
+            [WatchEvent::Paused]
+        ));
+        assert!(!watcher.needs_devices(None));
+        assert!(matches!(
+            watcher.observe(Some(playing_state()), None)[..],
+            [WatchEvent::Resumed]
+        ));
+    }
+
+    #[test]
+    fn test_chapters_like_episodes() {
+        let mut watcher = Watcher::new();
+        match &watcher.observe(Some(chapter_state()), None)[..] {
+            [WatchEvent::ChapterChanged(chapter)] => {
+                assert_eq!(chapter.audiobook.name, "A Wizard of Earthsea")
+            }
+            other => panic!("expected a chapter change, got {other:?}"),
+        }
+        assert!(matches!(
+            watcher.observe(Some(playing_state()), None)[..],
+            [WatchEvent::TrackChanged(_)]
+        ));
+    }
+
+    #[test]
+    fn test_untracked_chapters() {
+        let mut watcher = Watcher::new().with_chapters(false);
+        assert!(watcher.observe(Some(chapter_state()), None).is_empty());
+
+        let mut paused = chapter_state();
+        paused.playing.is_playing = false;
+        assert!(matches!(
+            watcher.observe(Some(paused), None)[..],
+            [WatchEvent::Paused]
+        ));
+    }
+
+    #[test]
+    fn test_extrapolates_while_playing() {
+        let fetched_at = Instant::now();
+        let state = playing_state();
+        let clock = PlaybackExtrapolator::from_state(&state, fetched_at).unwrap();
+        let progress_ms = state.playing.progress_ms.unwrap();
+        assert_eq!(clock.progress_ms_at(fetched_at), progress_ms);
+        assert_eq!(
+            clock.progress_ms_at(fetched_at + Duration::from_millis(1500)),
+            progress_ms + 1500
+        );
+        // A clock going backwards doesn't rewind the estimate
+        let earlier = fetched_at.checked_sub(Duration::from_secs(1)).unwrap();
+        assert_eq!(clock.progress_ms_at(earlier), progress_ms);
+    }
+
+    #[test]
+    fn test_paused_does_not_advance() {
+        let fetched_at = Instant::now();
+        let mut state = playing_state();
+        state.playing.is_playing = false;
+        let clock = PlaybackExtrapolator::from_state(&state, fetched_at).unwrap();
+        assert_eq!(
+            clock.progress_ms_at(fetched_at + Duration::from_secs(60)),
+            state.playing.progress_ms.unwrap()
+        );
+    }
+
+    #[test]
+    fn test_extrapolation_stops_at_the_end() {
+        let fetched_at = Instant::now();
+        let clock = PlaybackExtrapolator::new(179_000, Some(180_000), true, fetched_at);
+        assert_eq!(
+            clock.progress_ms_at(fetched_at + Duration::from_millis(500)),
+            179_500
+        );
+        assert_eq!(
+            clock.progress_ms_at(fetched_at + Duration::from_secs(5)),
+            180_000
+        );
+
+        // Without a known length there's nothing to clamp to
+        let clock = PlaybackExtrapolator::new(179_000, None, true, fetched_at);
+        assert_eq!(
+            clock.progress_ms_at(fetched_at + Duration::from_secs(5)),
+            184_000
+        );
+
+        let mut state = playing_state();
+        state.playing.progress_ms = None;
+        assert!(PlaybackExtrapolator::from_state(&state, fetched_at).is_none());
+    }
+}